@@ -5,7 +5,7 @@ use protoc_rust::Codegen;
 fn main() {
     Codegen::new()
         .out_dir("src/protos")
-        .inputs(&["src/protos/messages.proto"])
+        .inputs(["src/protos/messages.proto"])
         .include("src/protos")
         .run()
         .expect("Failed to run protoc_rust.");
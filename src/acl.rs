@@ -0,0 +1,211 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::RwLock;
+
+use crate::protos::messages;
+use crate::types::{NaiveError, Result};
+
+/// What a principal may do in a namespace, each role allowing everything the previous ones do.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum Role {
+    /// Read the keys, e.g. for analytics.
+    ReadOnly,
+    /// Also write the keys.
+    ReadWrite,
+    /// Also take the administrative actions, such as pausing jobs or changing the grants.
+    Admin,
+}
+
+impl Role {
+    pub fn to_message(self) -> messages::Role {
+        match self {
+            Role::ReadOnly => messages::Role::READ_ONLY,
+            Role::ReadWrite => messages::Role::READ_WRITE,
+            Role::Admin => messages::Role::ADMIN,
+        }
+    }
+
+    pub fn from_message(role: messages::Role) -> Self {
+        match role {
+            messages::Role::READ_ONLY => Role::ReadOnly,
+            messages::Role::READ_WRITE => Role::ReadWrite,
+            messages::Role::ADMIN => Role::Admin,
+        }
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Role::ReadOnly => "read-only",
+            Role::ReadWrite => "read-write",
+            Role::Admin => "admin",
+        })
+    }
+}
+
+impl FromStr for Role {
+    type Err = NaiveError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "read-only" => Ok(Role::ReadOnly),
+            "read-write" => Ok(Role::ReadWrite),
+            "admin" => Ok(Role::Admin),
+            _ => Err(NaiveError::InvalidData),
+        }
+    }
+}
+
+/// The role granted to a principal in a namespace.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Grant {
+    pub namespace: String,
+
+    /// Who the role is granted to, e.g. the IP address of a client.
+    pub principal: String,
+
+    pub role: Role,
+}
+
+impl Grant {
+    pub fn to_message(&self) -> messages::Grant {
+        let mut message = messages::Grant::new();
+        message.set_namespace(self.namespace.clone());
+        message.set_principal(self.principal.clone());
+        message.set_role(self.role.to_message());
+        message
+    }
+
+    pub fn from_message(mut message: messages::Grant) -> Self {
+        Self {
+            namespace: message.take_namespace(),
+            principal: message.take_principal(),
+            role: Role::from_message(message.get_role()),
+        }
+    }
+}
+
+/// The access control list of a server, telling the role of each principal in each namespace.
+///
+/// The principals without a grant in a namespace get the default role there.
+pub struct Acl {
+    default_role: Role,
+
+    /// The roles by principal by namespace.
+    grants: RwLock<BTreeMap<String, BTreeMap<String, Role>>>,
+}
+
+impl Acl {
+    pub fn new(default_role: Role, grants: impl IntoIterator<Item = Grant>) -> Self {
+        let mut grants_by_namespace = BTreeMap::<String, BTreeMap<String, Role>>::new();
+        for grant in grants {
+            grants_by_namespace
+                .entry(grant.namespace)
+                .or_default()
+                .insert(grant.principal, grant.role);
+        }
+        Self {
+            default_role,
+            grants: RwLock::new(grants_by_namespace),
+        }
+    }
+
+    pub fn role(&self, namespace: &str, principal: &str) -> Result<Role> {
+        Ok(self
+            .grants
+            .read()?
+            .get(namespace)
+            .and_then(|roles| roles.get(principal))
+            .copied()
+            .unwrap_or(self.default_role))
+    }
+
+    /// Grant the role to the principal in the namespace, replacing any role granted before.
+    pub fn grant(&self, grant: Grant) -> Result<()> {
+        self.grants
+            .write()?
+            .entry(grant.namespace)
+            .or_default()
+            .insert(grant.principal, grant.role);
+        Ok(())
+    }
+
+    /// Revoke the role granted to the principal in the namespace, returning it if any, so that
+    /// the principal gets the default role again.
+    pub fn revoke(&self, namespace: &str, principal: &str) -> Result<Option<Role>> {
+        let mut grants = self.grants.write()?;
+        let role = match grants.get_mut(namespace) {
+            Some(roles) => roles.remove(principal),
+            None => return Ok(None),
+        };
+        if grants.get(namespace).is_some_and(|roles| roles.is_empty()) {
+            grants.remove(namespace);
+        }
+        Ok(role)
+    }
+
+    /// The grants ordered by namespace and then by principal.
+    pub fn grants(&self) -> Result<Vec<Grant>> {
+        Ok(self
+            .grants
+            .read()?
+            .iter()
+            .flat_map(|(namespace, roles)| {
+                roles.iter().map(|(principal, role)| Grant {
+                    namespace: namespace.clone(),
+                    principal: principal.clone(),
+                    role: *role,
+                })
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_acl() {
+        let grant = |namespace: &str, principal: &str, role| Grant {
+            namespace: namespace.to_owned(),
+            principal: principal.to_owned(),
+            role,
+        };
+        let acl = Acl::new(
+            Role::ReadOnly,
+            vec![
+                grant("", "10.0.0.1", Role::Admin),
+                grant("logs", "10.0.0.2", Role::ReadWrite),
+            ],
+        );
+        assert_eq!(acl.role("", "10.0.0.1").unwrap(), Role::Admin);
+        assert_eq!(acl.role("", "10.0.0.2").unwrap(), Role::ReadOnly);
+        assert_eq!(acl.role("logs", "10.0.0.2").unwrap(), Role::ReadWrite);
+        assert!(Role::ReadOnly < Role::ReadWrite && Role::ReadWrite < Role::Admin);
+
+        acl.grant(grant("", "10.0.0.2", Role::ReadWrite)).unwrap();
+        assert_eq!(acl.role("", "10.0.0.2").unwrap(), Role::ReadWrite);
+        assert_eq!(
+            acl.revoke("logs", "10.0.0.2").unwrap(),
+            Some(Role::ReadWrite)
+        );
+        assert_eq!(acl.revoke("logs", "10.0.0.2").unwrap(), None);
+        assert_eq!(acl.role("logs", "10.0.0.2").unwrap(), Role::ReadOnly);
+        assert_eq!(
+            acl.grants().unwrap(),
+            vec![
+                grant("", "10.0.0.1", Role::Admin),
+                grant("", "10.0.0.2", Role::ReadWrite),
+            ]
+        );
+
+        for role in [Role::ReadOnly, Role::ReadWrite, Role::Admin] {
+            assert_eq!(role.to_string().parse::<Role>().unwrap(), role);
+            assert_eq!(Role::from_message(role.to_message()), role);
+        }
+        assert!("owner".parse::<Role>().is_err());
+    }
+}
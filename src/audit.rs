@@ -0,0 +1,222 @@
+use protobuf::Message;
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use crate::protos::messages;
+use crate::types::{NaiveError, Result};
+use crate::utils::{self, N_BYTES_CHUNK_LENGTH};
+
+/// The name of the audit log file in a data folder.
+const AUDIT_LOG_FILE_NAME: &str = "AUDIT";
+
+/// An administrative action recorded in the audit log.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AuditRecord {
+    /// When the action was taken, in milliseconds since the Unix epoch.
+    pub timestamp_ms: u64,
+
+    /// Who took the action, e.g. the address of a client.
+    pub principal: String,
+
+    pub action: String,
+
+    /// The parameters of the action by name, in the order given.
+    pub parameters: Vec<(String, String)>,
+}
+
+impl AuditRecord {
+    pub fn to_message(&self) -> messages::AuditRecord {
+        let mut message = messages::AuditRecord::new();
+        message.set_timestamp_ms(self.timestamp_ms);
+        message.set_principal(self.principal.clone());
+        message.set_action(self.action.clone());
+        message.set_parameters(
+            self.parameters
+                .iter()
+                .map(|(name, value)| {
+                    let mut parameter = messages::Parameter::new();
+                    parameter.set_name(name.clone());
+                    parameter.set_value(value.clone());
+                    parameter
+                })
+                .collect(),
+        );
+        message
+    }
+
+    pub fn from_message(mut message: messages::AuditRecord) -> Self {
+        Self {
+            timestamp_ms: message.get_timestamp_ms(),
+            principal: message.take_principal(),
+            action: message.take_action(),
+            parameters: message
+                .take_parameters()
+                .into_iter()
+                .map(|mut parameter| (parameter.take_name(), parameter.take_value()))
+                .collect(),
+        }
+    }
+}
+
+/// The log of the administrative actions taken on a data folder, for compliance.
+///
+/// The records are only ever appended and synced one by one, never rewritten, so that the log
+/// tells every action taken so far even after a crash.
+pub struct AuditLog {
+    file_path: PathBuf,
+
+    /// The file opened for appending, which also serializes the readers with the writers.
+    file: Mutex<File>,
+}
+
+impl AuditLog {
+    /// Open the audit log of the data folder, creating it if there is none yet.
+    pub fn open(folder_path: &Path) -> Result<Self> {
+        let file_path = folder_path.join(AUDIT_LOG_FILE_NAME);
+        let file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&file_path)?;
+
+        // A record cut short by a crash was never taken, and would hide the ones appended after.
+        let (_, num_bytes) = read_records(&file)?;
+        let file_size = file.metadata()?.len();
+        if num_bytes < file_size {
+            log::warn!(
+                "Dropped a partial record of {} bytes at the end of {}.",
+                file_size - num_bytes,
+                file_path.display()
+            );
+            file.set_len(num_bytes)?;
+            file.sync_all()?;
+        }
+        Ok(Self {
+            file_path,
+            file: Mutex::new(file),
+        })
+    }
+
+    /// Append an action taken now by the principal, and return the record once it is durable.
+    pub fn record(
+        &self,
+        principal: &str,
+        action: &str,
+        parameters: Vec<(String, String)>,
+    ) -> Result<AuditRecord> {
+        let record = AuditRecord {
+            timestamp_ms: SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map_or(0, |duration| duration.as_millis() as u64),
+            principal: principal.to_owned(),
+            action: action.to_owned(),
+            parameters,
+        };
+        // Write each record at once, so that it never interleaves with another one.
+        let mut bytes = Vec::new();
+        utils::write_message(&record.to_message(), &mut bytes)?;
+        let mut file = self.file.lock()?;
+        std::io::Write::write_all(&mut *file, &bytes)?;
+        file.sync_data()?;
+        Ok(record)
+    }
+
+    /// Read up to limit records starting from the offset-th one, in the order they were taken.
+    pub fn read(&self, offset: usize, limit: usize) -> Result<Vec<AuditRecord>> {
+        let file = self.file.lock()?;
+        let (records, _) = read_records(&file)?;
+        Ok(records.into_iter().skip(offset).take(limit).collect())
+    }
+
+    pub fn file_path(&self) -> &Path {
+        self.file_path.as_path()
+    }
+}
+
+/// Read the complete records from the start of the file, and the number of bytes they take.
+fn read_records(file: &File) -> Result<(Vec<AuditRecord>, u64)> {
+    let mut bytes = Vec::new();
+    let mut file_reader = BufReader::new(file);
+    std::io::Seek::rewind(&mut file_reader)?;
+    file_reader.read_to_end(&mut bytes)?;
+
+    let mut records = Vec::new();
+    let mut num_bytes = 0;
+    while bytes.len() - num_bytes >= N_BYTES_CHUNK_LENGTH {
+        let mut chunk_reader = &bytes[num_bytes..];
+        let mut chunk = Vec::new();
+        match utils::read_chunk(&mut chunk_reader, &mut chunk) {
+            Ok(chunk_length) => {
+                let message = messages::AuditRecord::parse_from_bytes(&chunk)?;
+                records.push(AuditRecord::from_message(message));
+                num_bytes += N_BYTES_CHUNK_LENGTH + chunk_length;
+            }
+            Err(NaiveError::IoError(error))
+                if error.kind() == std::io::ErrorKind::UnexpectedEof =>
+            {
+                break;
+            }
+            Err(error) => return Err(error),
+        }
+    }
+    Ok((records, num_bytes as u64))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AuditLog, AUDIT_LOG_FILE_NAME};
+    use std::io::Write;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_audit_log() {
+        const FOLDER_PATH: &str = "/tmp/naive_kv/test_audit_log/";
+
+        let folder_path = PathBuf::from(FOLDER_PATH);
+        let _ = std::fs::remove_dir_all(&folder_path);
+        std::fs::create_dir_all(&folder_path).unwrap();
+
+        let audit_log = AuditLog::open(&folder_path).unwrap();
+        assert!(audit_log.read(0, 10).unwrap().is_empty());
+        let start = audit_log
+            .record(
+                "admin",
+                "START",
+                vec![("port".to_owned(), "1024".to_owned())],
+            )
+            .unwrap();
+        let describe = audit_log
+            .record("127.0.0.1:5678", "DESCRIBE", Vec::new())
+            .unwrap();
+        assert_eq!(
+            audit_log.read(0, 10).unwrap(),
+            vec![start.clone(), describe.clone()]
+        );
+        assert_eq!(audit_log.read(1, 10).unwrap(), vec![describe.clone()]);
+        assert_eq!(audit_log.read(0, 1).unwrap(), vec![start.clone()]);
+        drop(audit_log);
+
+        // A partial record left by a crash is dropped on open, and the records go on after it.
+        std::fs::OpenOptions::new()
+            .append(true)
+            .open(folder_path.join(AUDIT_LOG_FILE_NAME))
+            .unwrap()
+            .write_all(&[0, 0, 1, 0, 42])
+            .unwrap();
+        let audit_log = AuditLog::open(&folder_path).unwrap();
+        let export = audit_log
+            .record(
+                "127.0.0.1:5678",
+                "EXPORT",
+                vec![("prefix".to_owned(), "user/".to_owned())],
+            )
+            .unwrap();
+        assert_eq!(
+            audit_log.read(0, 10).unwrap(),
+            vec![start, describe, export]
+        );
+    }
+}
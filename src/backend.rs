@@ -0,0 +1,750 @@
+use std::any::Any;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Debug;
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::SystemTime;
+
+use memmap2::Mmap;
+
+use crate::types::{NaiveError, Result};
+use crate::utils;
+
+/// A file opened through a backend, which stays readable even after it is removed from the
+/// backend, so that readers can pin the files they read.
+pub trait BackendFile: Read + Write + Seek + Send + Sync {
+    /// The current size of the file in bytes.
+    fn len(&self) -> Result<u64>;
+
+    fn is_empty(&self) -> Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    /// Make the written data durable.
+    fn sync(&self) -> Result<()>;
+
+    /// Read the bytes at the offset without moving the cursor, returning how many are read, which
+    /// are fewer than asked only at the end of the file. Many readers may share a handle this way
+    /// without taking turns on its cursor.
+    fn read_at(&self, buffer: &mut [u8], offset: u64) -> std::io::Result<usize>;
+}
+
+/// A cursor of its own over a file shared by many readers, which reads it through positional
+/// reads, leaving the cursor of the file alone.
+pub struct PositionalReader<'a> {
+    file: &'a dyn BackendFile,
+    offset: u64,
+}
+
+impl<'a> PositionalReader<'a> {
+    pub fn new(file: &'a dyn BackendFile, offset: u64) -> Self {
+        Self { file, offset }
+    }
+}
+
+impl Read for PositionalReader<'_> {
+    fn read(&mut self, buffer: &mut [u8]) -> std::io::Result<usize> {
+        let num_bytes = self.file.read_at(buffer, self.offset)?;
+        self.offset += num_bytes as u64;
+        Ok(num_bytes)
+    }
+}
+
+/// Where the files of a data folder are stored, e.g. the local file system or memory.
+pub trait Backend: Debug + Send + Sync {
+    fn create_dir_all(&self, folder_path: &Path) -> Result<()>;
+
+    /// The paths of the files directly in the folder, subdirectories excluded.
+    fn list_files(&self, folder_path: &Path) -> Result<Vec<PathBuf>>;
+
+    fn exists(&self, path: &Path) -> bool;
+
+    /// Open an existing file for reading.
+    fn open(&self, file_path: &Path) -> Result<Box<dyn BackendFile>>;
+
+    /// Open a file for reading and appending, creating it if it does not exist.
+    fn open_append(&self, file_path: &Path) -> Result<Box<dyn BackendFile>>;
+
+    /// Create a new file for reading and appending, failing if it already exists.
+    fn create_new(&self, file_path: &Path) -> Result<Box<dyn BackendFile>>;
+
+    /// Overwrite the bytes at the offset of an existing file and make them durable.
+    fn write_at(&self, file_path: &Path, offset: u64, bytes: &[u8]) -> Result<()>;
+
+    /// Replace the content of a file, creating it if it does not exist, so that a crash leaves
+    /// either the old or the new content behind.
+    fn replace(&self, file_path: &Path, bytes: &[u8]) -> Result<()>;
+
+    fn rename(&self, from_path: &Path, to_path: &Path) -> Result<()>;
+
+    /// Remove a file and return whether it existed.
+    fn remove_file(&self, file_path: &Path) -> Result<bool>;
+
+    /// Remove a folder and return whether it has been removed, which it is not unless it exists
+    /// and is empty.
+    fn remove_dir(&self, folder_path: &Path) -> Result<bool>;
+
+    /// Take the lock of the name on an existing folder until the lock drops, either shared with
+    /// the other shared holders, e.g. the replicas of a data folder, or exclusively, e.g. by the
+    /// primary. Fail with `NaiveError::FolderInUse` if it is held otherwise. The locks are
+    /// advisory, so they only keep out the ones taking them as well.
+    fn lock_folder(
+        &self,
+        folder_path: &Path,
+        lock_name: &str,
+        is_exclusive: bool,
+    ) -> Result<FolderLock>;
+
+    fn file_size(&self, file_path: &Path) -> Result<u64>;
+
+    fn modified_time(&self, file_path: &Path) -> Result<SystemTime>;
+
+    fn set_modified_time(&self, file_path: &Path, modified_time: SystemTime) -> Result<()>;
+
+    /// Copy the content of an existing file into a new one and make it durable, e.g. for a backup.
+    fn copy(&self, from_path: &Path, to_path: &Path) -> Result<()> {
+        let mut from_file = self.open(from_path)?;
+        let mut to_file = self.create_new(to_path)?;
+        std::io::copy(&mut from_file, &mut to_file)?;
+        to_file.sync()
+    }
+
+    /// Make a new file with the content of an existing one, e.g. for a checkpoint, which may share
+    /// the content with the original since both are never changed in place afterwards. The bytes
+    /// are copied unless the backend can do better.
+    fn link_or_copy(&self, from_path: &Path, to_path: &Path) -> Result<()> {
+        self.copy(from_path, to_path)
+    }
+
+    /// Map an existing file into memory for reading, e.g. a segment file, which must not shrink
+    /// while mapped, or None if the backend cannot, in which case the file is read as usual.
+    fn map(&self, _file_path: &Path) -> Result<Option<MappedFile>> {
+        Ok(None)
+    }
+}
+
+/// The bytes of a file mapped into memory through a backend, which stay readable until dropped
+/// even if the file is removed.
+pub type MappedFile = Box<dyn AsRef<[u8]> + Send + Sync>;
+
+/// A lock on a folder taken through a backend, which is released once dropped.
+pub struct FolderLock {
+    _holder: Box<dyn Any + Send + Sync>,
+}
+
+impl FolderLock {
+    fn new(holder: impl Any + Send + Sync) -> Self {
+        Self {
+            _holder: Box::new(holder),
+        }
+    }
+}
+
+impl Debug for FolderLock {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FolderLock").finish_non_exhaustive()
+    }
+}
+
+/// The local file system, which is the default backend.
+#[derive(Debug, Default)]
+pub struct LocalBackend;
+
+impl BackendFile for File {
+    fn len(&self) -> Result<u64> {
+        Ok(self.metadata()?.len())
+    }
+
+    fn sync(&self) -> Result<()> {
+        Ok(self.sync_data()?)
+    }
+
+    #[cfg(unix)]
+    fn read_at(&self, buffer: &mut [u8], offset: u64) -> std::io::Result<usize> {
+        std::os::unix::fs::FileExt::read_at(self, buffer, offset)
+    }
+
+    // The cursor moves on Windows, but the shared handles are only ever read by offset.
+    #[cfg(windows)]
+    fn read_at(&self, buffer: &mut [u8], offset: u64) -> std::io::Result<usize> {
+        std::os::windows::fs::FileExt::seek_read(self, buffer, offset)
+    }
+}
+
+impl Backend for LocalBackend {
+    fn create_dir_all(&self, folder_path: &Path) -> Result<()> {
+        Ok(std::fs::create_dir_all(folder_path)?)
+    }
+
+    fn list_files(&self, folder_path: &Path) -> Result<Vec<PathBuf>> {
+        let mut file_paths = Vec::new();
+        for dir_entry in std::fs::read_dir(folder_path)? {
+            let file_path = dir_entry?.path();
+            if file_path.is_file() {
+                file_paths.push(file_path);
+            }
+        }
+        Ok(file_paths)
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        path.exists()
+    }
+
+    fn open(&self, file_path: &Path) -> Result<Box<dyn BackendFile>> {
+        Ok(Box::new(File::open(file_path)?))
+    }
+
+    fn open_append(&self, file_path: &Path) -> Result<Box<dyn BackendFile>> {
+        Ok(Box::new(
+            OpenOptions::new()
+                .read(true)
+                .append(true)
+                .create(true)
+                .open(file_path)?,
+        ))
+    }
+
+    fn create_new(&self, file_path: &Path) -> Result<Box<dyn BackendFile>> {
+        Ok(Box::new(
+            OpenOptions::new()
+                .read(true)
+                .append(true)
+                .create_new(true)
+                .open(file_path)?,
+        ))
+    }
+
+    fn write_at(&self, file_path: &Path, offset: u64, bytes: &[u8]) -> Result<()> {
+        let mut file = OpenOptions::new().write(true).open(file_path)?;
+        file.seek(SeekFrom::Start(offset))?;
+        file.write_all(bytes)?;
+        file.sync_all()?;
+        Ok(())
+    }
+
+    fn replace(&self, file_path: &Path, bytes: &[u8]) -> Result<()> {
+        // Write into a temporary file and then rename it over the old one.
+        let temp_path = file_path.with_extension("tmp");
+        let mut temp_file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&temp_path)?;
+        temp_file.write_all(bytes)?;
+        temp_file.sync_all()?;
+        std::fs::rename(&temp_path, file_path)?;
+        if let Some(folder_path) = file_path.parent() {
+            File::open(folder_path)?.sync_all()?;
+        }
+        Ok(())
+    }
+
+    fn rename(&self, from_path: &Path, to_path: &Path) -> Result<()> {
+        Ok(std::fs::rename(from_path, to_path)?)
+    }
+
+    fn remove_file(&self, file_path: &Path) -> Result<bool> {
+        utils::try_remove_file(file_path)
+    }
+
+    fn remove_dir(&self, folder_path: &Path) -> Result<bool> {
+        match std::fs::remove_dir(folder_path) {
+            Ok(()) => Ok(true),
+            Err(error)
+                if matches!(
+                    error.kind(),
+                    std::io::ErrorKind::NotFound | std::io::ErrorKind::DirectoryNotEmpty
+                ) =>
+            {
+                Ok(false)
+            }
+            Err(error) => Err(error.into()),
+        }
+    }
+
+    fn lock_folder(
+        &self,
+        folder_path: &Path,
+        lock_name: &str,
+        is_exclusive: bool,
+    ) -> Result<FolderLock> {
+        // The lock is taken on a file of the name, which stays in place, since removing it would
+        // let a new holder lock another one.
+        let lock_file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(folder_path.join(lock_name))?;
+        let result = if is_exclusive {
+            lock_file.try_lock()
+        } else {
+            lock_file.try_lock_shared()
+        };
+        match result {
+            // The lock is released along with the file.
+            Ok(()) => Ok(FolderLock::new(lock_file)),
+            Err(TryLockError::WouldBlock) => Err(NaiveError::FolderInUse(folder_path.to_owned())),
+            Err(TryLockError::Error(error)) => Err(error.into()),
+        }
+    }
+
+    fn file_size(&self, file_path: &Path) -> Result<u64> {
+        Ok(std::fs::metadata(file_path)?.len())
+    }
+
+    fn modified_time(&self, file_path: &Path) -> Result<SystemTime> {
+        Ok(std::fs::metadata(file_path)?.modified()?)
+    }
+
+    fn set_modified_time(&self, file_path: &Path, modified_time: SystemTime) -> Result<()> {
+        Ok(OpenOptions::new()
+            .write(true)
+            .open(file_path)?
+            .set_modified(modified_time)?)
+    }
+
+    fn map(&self, file_path: &Path) -> Result<Option<MappedFile>> {
+        let file = File::open(file_path)?;
+        // SAFETY: The callers only map the files that are never truncated or rewritten in place
+        // but for a few bytes, e.g. the segment files, so the mapped bytes stay valid.
+        let mmap = unsafe { Mmap::map(&file)? };
+        Ok(Some(Box::new(mmap)))
+    }
+
+    fn link_or_copy(&self, from_path: &Path, to_path: &Path) -> Result<()> {
+        // Fall back to copying, e.g. across file systems.
+        if let Err(error) = std::fs::hard_link(from_path, to_path) {
+            log::debug!(
+                "Copying {} since it cannot be hard-linked: {}",
+                from_path.display(),
+                error
+            );
+            self.copy(from_path, to_path)?;
+        }
+        Ok(())
+    }
+}
+
+/// The content of a file in memory, shared by the backend and the open handles.
+#[derive(Debug)]
+struct MemoryFileData {
+    bytes: Vec<u8>,
+    modified_time: SystemTime,
+}
+
+type SharedFileData = Arc<RwLock<MemoryFileData>>;
+
+/// Keeps the files in memory, e.g. for tests, so that they vanish with the backend.
+#[derive(Debug, Default)]
+pub struct MemoryBackend {
+    state: Mutex<MemoryBackendState>,
+    folder_locks: Arc<Mutex<FolderLocks>>,
+}
+
+/// The number of shared holders of each folder lock by its path, i.e. the folder joined by the
+/// name, or -1 if it is held exclusively.
+type FolderLocks = BTreeMap<PathBuf, isize>;
+
+/// A folder lock of a memory backend, which is counted there until dropped.
+struct MemoryFolderLock {
+    folder_locks: Arc<Mutex<FolderLocks>>,
+    lock_path: PathBuf,
+}
+
+impl Drop for MemoryFolderLock {
+    fn drop(&mut self) {
+        if let Ok(mut folder_locks) = self.folder_locks.lock() {
+            match folder_locks.get_mut(&self.lock_path) {
+                Some(num_holders) if *num_holders > 1 => *num_holders -= 1,
+                _ => {
+                    folder_locks.remove(&self.lock_path);
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct MemoryBackendState {
+    folders: BTreeSet<PathBuf>,
+    files: BTreeMap<PathBuf, SharedFileData>,
+}
+
+impl MemoryBackend {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl MemoryBackendState {
+    fn file(&self, file_path: &Path) -> Result<SharedFileData> {
+        self.files
+            .get(file_path)
+            .cloned()
+            .ok_or_else(|| not_found(file_path))
+    }
+
+    /// Check that the folder of a new file exists, as the local file system would.
+    fn check_parent(&self, file_path: &Path) -> Result<()> {
+        match file_path.parent() {
+            Some(folder_path) if !self.folders.contains(folder_path) => Err(not_found(folder_path)),
+            _ => Ok(()),
+        }
+    }
+
+    fn create(&mut self, file_path: &Path) -> Result<SharedFileData> {
+        self.check_parent(file_path)?;
+        let file_data = Arc::new(RwLock::new(MemoryFileData {
+            bytes: Vec::new(),
+            modified_time: SystemTime::now(),
+        }));
+        self.files.insert(file_path.to_owned(), file_data.clone());
+        Ok(file_data)
+    }
+}
+
+impl Backend for MemoryBackend {
+    fn create_dir_all(&self, folder_path: &Path) -> Result<()> {
+        let mut state = self.state.lock()?;
+        for ancestor in folder_path.ancestors() {
+            state.folders.insert(ancestor.to_owned());
+        }
+        Ok(())
+    }
+
+    fn list_files(&self, folder_path: &Path) -> Result<Vec<PathBuf>> {
+        let state = self.state.lock()?;
+        if !state.folders.contains(folder_path) {
+            return Err(not_found(folder_path));
+        }
+        Ok(state
+            .files
+            .keys()
+            .filter(|file_path| file_path.parent() == Some(folder_path))
+            .cloned()
+            .collect())
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        self.state
+            .lock()
+            .map(|state| state.folders.contains(path) || state.files.contains_key(path))
+            .unwrap_or(false)
+    }
+
+    fn open(&self, file_path: &Path) -> Result<Box<dyn BackendFile>> {
+        let file_data = self.state.lock()?.file(file_path)?;
+        Ok(Box::new(MemoryFile::new(file_data, false)))
+    }
+
+    fn open_append(&self, file_path: &Path) -> Result<Box<dyn BackendFile>> {
+        let mut state = self.state.lock()?;
+        let file_data = match state.files.get(file_path) {
+            Some(file_data) => file_data.clone(),
+            None => state.create(file_path)?,
+        };
+        Ok(Box::new(MemoryFile::new(file_data, true)))
+    }
+
+    fn create_new(&self, file_path: &Path) -> Result<Box<dyn BackendFile>> {
+        let mut state = self.state.lock()?;
+        if state.files.contains_key(file_path) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                file_path.display().to_string(),
+            )
+            .into());
+        }
+        Ok(Box::new(MemoryFile::new(state.create(file_path)?, true)))
+    }
+
+    fn write_at(&self, file_path: &Path, offset: u64, bytes: &[u8]) -> Result<()> {
+        let file_data = self.state.lock()?.file(file_path)?;
+        let mut file_data = file_data.write()?;
+        let end = offset as usize + bytes.len();
+        if file_data.bytes.len() < end {
+            file_data.bytes.resize(end, 0u8);
+        }
+        file_data.bytes[offset as usize..end].copy_from_slice(bytes);
+        file_data.modified_time = SystemTime::now();
+        Ok(())
+    }
+
+    fn replace(&self, file_path: &Path, bytes: &[u8]) -> Result<()> {
+        let mut state = self.state.lock()?;
+        // Swap in new content rather than changing it, so that open handles keep the old one.
+        let file_data = state.create(file_path)?;
+        file_data.write()?.bytes = bytes.to_vec();
+        Ok(())
+    }
+
+    fn rename(&self, from_path: &Path, to_path: &Path) -> Result<()> {
+        let mut state = self.state.lock()?;
+        state.check_parent(to_path)?;
+        let file_data = state
+            .files
+            .remove(from_path)
+            .ok_or_else(|| not_found(from_path))?;
+        state.files.insert(to_path.to_owned(), file_data);
+        Ok(())
+    }
+
+    fn remove_file(&self, file_path: &Path) -> Result<bool> {
+        Ok(self.state.lock()?.files.remove(file_path).is_some())
+    }
+
+    fn remove_dir(&self, folder_path: &Path) -> Result<bool> {
+        let mut state = self.state.lock()?;
+        let is_empty = !state
+            .files
+            .keys()
+            .chain(state.folders.iter())
+            .any(|path| path.parent() == Some(folder_path));
+        Ok(is_empty && state.folders.remove(folder_path))
+    }
+
+    fn lock_folder(
+        &self,
+        folder_path: &Path,
+        lock_name: &str,
+        is_exclusive: bool,
+    ) -> Result<FolderLock> {
+        if !self.exists(folder_path) {
+            return Err(not_found(folder_path));
+        }
+        let lock_path = folder_path.join(lock_name);
+        let mut folder_locks = self.folder_locks.lock()?;
+        let num_holders = folder_locks.entry(lock_path.clone()).or_insert(0);
+        if *num_holders < 0 || (is_exclusive && *num_holders > 0) {
+            return Err(NaiveError::FolderInUse(folder_path.to_owned()));
+        }
+        *num_holders = if is_exclusive { -1 } else { *num_holders + 1 };
+        Ok(FolderLock::new(MemoryFolderLock {
+            folder_locks: self.folder_locks.clone(),
+            lock_path,
+        }))
+    }
+
+    fn file_size(&self, file_path: &Path) -> Result<u64> {
+        let file_data = self.state.lock()?.file(file_path)?;
+        let file_size = file_data.read()?.bytes.len() as u64;
+        Ok(file_size)
+    }
+
+    fn modified_time(&self, file_path: &Path) -> Result<SystemTime> {
+        let file_data = self.state.lock()?.file(file_path)?;
+        let modified_time = file_data.read()?.modified_time;
+        Ok(modified_time)
+    }
+
+    fn set_modified_time(&self, file_path: &Path, modified_time: SystemTime) -> Result<()> {
+        let file_data = self.state.lock()?.file(file_path)?;
+        file_data.write()?.modified_time = modified_time;
+        Ok(())
+    }
+}
+
+/// An open handle of a file in memory.
+struct MemoryFile {
+    file_data: SharedFileData,
+    position: u64,
+    is_writable: bool,
+}
+
+impl MemoryFile {
+    fn new(file_data: SharedFileData, is_writable: bool) -> Self {
+        Self {
+            file_data,
+            position: 0,
+            is_writable,
+        }
+    }
+}
+
+impl Read for MemoryFile {
+    fn read(&mut self, buffer: &mut [u8]) -> std::io::Result<usize> {
+        let num_bytes = self.read_at(buffer, self.position)?;
+        self.position += num_bytes as u64;
+        Ok(num_bytes)
+    }
+}
+
+impl Write for MemoryFile {
+    /// Append the bytes to the end of the file, like a file opened in the append mode.
+    fn write(&mut self, bytes: &[u8]) -> std::io::Result<usize> {
+        if !self.is_writable {
+            return Err(std::io::Error::from(std::io::ErrorKind::PermissionDenied));
+        }
+        let mut file_data = self.file_data.write().map_err(|_| lock_error())?;
+        file_data.bytes.extend_from_slice(bytes);
+        file_data.modified_time = SystemTime::now();
+        self.position = file_data.bytes.len() as u64;
+        Ok(bytes.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for MemoryFile {
+    fn seek(&mut self, position: SeekFrom) -> std::io::Result<u64> {
+        let len = self.file_data.read().map_err(|_| lock_error())?.bytes.len() as i64;
+        let position = match position {
+            SeekFrom::Start(offset) => offset as i64,
+            SeekFrom::End(offset) => len + offset,
+            SeekFrom::Current(offset) => self.position as i64 + offset,
+        };
+        if position < 0 {
+            return Err(std::io::Error::from(std::io::ErrorKind::InvalidInput));
+        }
+        self.position = position as u64;
+        Ok(self.position)
+    }
+}
+
+impl BackendFile for MemoryFile {
+    fn len(&self) -> Result<u64> {
+        Ok(self.file_data.read()?.bytes.len() as u64)
+    }
+
+    fn sync(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn read_at(&self, buffer: &mut [u8], offset: u64) -> std::io::Result<usize> {
+        let file_data = self.file_data.read().map_err(|_| lock_error())?;
+        let start = (offset as usize).min(file_data.bytes.len());
+        let num_bytes = buffer.len().min(file_data.bytes.len() - start);
+        buffer[..num_bytes].copy_from_slice(&file_data.bytes[start..start + num_bytes]);
+        Ok(num_bytes)
+    }
+}
+
+fn not_found(path: &Path) -> NaiveError {
+    std::io::Error::new(std::io::ErrorKind::NotFound, path.display().to_string()).into()
+}
+
+fn lock_error() -> std::io::Error {
+    std::io::Error::other("poisoned lock of a file in memory")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Backend, LocalBackend, MemoryBackend, PositionalReader};
+    use crate::types::NaiveError;
+    use std::io::{Read, Seek, SeekFrom, Write};
+    use std::path::Path;
+
+    #[test]
+    fn test_memory_backend() {
+        let backend = MemoryBackend::new();
+        let folder_path = Path::new("/memory/folder");
+        let file_path = folder_path.join("file");
+        assert!(backend.open_append(&file_path).is_err());
+        backend.create_dir_all(folder_path).unwrap();
+
+        let mut file = backend.open_append(&file_path).unwrap();
+        file.write_all(b"hello").unwrap();
+        assert!(backend.create_new(&file_path).is_err());
+        assert_eq!(
+            backend.list_files(folder_path).unwrap(),
+            vec![file_path.clone()]
+        );
+
+        // A handle keeps reading the file after it is removed.
+        let mut reader = backend.open(&file_path).unwrap();
+        assert!(backend.remove_file(&file_path).unwrap());
+        assert!(!backend.exists(&file_path));
+        file.write_all(b" world").unwrap();
+        let mut content = String::new();
+        reader.read_to_string(&mut content).unwrap();
+        assert_eq!(content, "hello world");
+
+        backend.replace(&file_path, b"old").unwrap();
+        let mut old_reader = backend.open(&file_path).unwrap();
+        backend.replace(&file_path, b"new").unwrap();
+        backend.write_at(&file_path, 0, b"N").unwrap();
+        let mut content = String::new();
+        old_reader.read_to_string(&mut content).unwrap();
+        assert_eq!(content, "old");
+        let mut reader = backend.open(&file_path).unwrap();
+        reader.seek(SeekFrom::Start(1)).unwrap();
+        let mut content = String::new();
+        reader.read_to_string(&mut content).unwrap();
+        assert_eq!(content, "ew");
+
+        // The positional reads leave the cursor alone and stop at the end of the file.
+        let mut buffer = [0u8; 4];
+        assert_eq!(reader.read_at(&mut buffer, 1).unwrap(), 2);
+        assert_eq!(&buffer[..2], b"ew");
+        assert_eq!(reader.read_at(&mut buffer, 5).unwrap(), 0);
+        reader.seek(SeekFrom::Start(0)).unwrap();
+        let mut content = String::new();
+        PositionalReader::new(reader.as_ref(), 2)
+            .read_to_string(&mut content)
+            .unwrap();
+        assert_eq!(content, "w");
+        assert_eq!(reader.stream_position().unwrap(), 0);
+        assert!(reader.write_all(b"x").is_err());
+
+        let renamed_path = folder_path.join("renamed");
+        backend.rename(&file_path, &renamed_path).unwrap();
+        assert!(backend.exists(&renamed_path));
+        assert!(backend.rename(&file_path, &renamed_path).is_err());
+
+        // A copy is a new file, which never replaces an existing one.
+        let copied_path = folder_path.join("copied");
+        backend.link_or_copy(&renamed_path, &copied_path).unwrap();
+        let mut content = String::new();
+        backend
+            .open(&copied_path)
+            .unwrap()
+            .read_to_string(&mut content)
+            .unwrap();
+        assert_eq!(content, "New");
+        assert!(backend.link_or_copy(&renamed_path, &copied_path).is_err());
+
+        // A folder is only removed once empty.
+        let subfolder_path = folder_path.join("subfolder");
+        backend.create_dir_all(&subfolder_path).unwrap();
+        assert!(!backend.remove_dir(folder_path).unwrap());
+        assert!(backend.remove_dir(&subfolder_path).unwrap());
+        assert!(!backend.remove_dir(&subfolder_path).unwrap());
+    }
+
+    #[test]
+    fn test_lock_folder() {
+        let folder_path = Path::new("/tmp/naive_kv/test_lock_folder");
+        let _ = std::fs::remove_dir_all(folder_path);
+        let local_backend = LocalBackend;
+        local_backend.create_dir_all(folder_path).unwrap();
+        let memory_backend = MemoryBackend::new();
+        memory_backend.create_dir_all(folder_path).unwrap();
+
+        for backend in [&local_backend as &dyn Backend, &memory_backend] {
+            let shared_lock = backend.lock_folder(folder_path, "SHARED", false).unwrap();
+            let other_shared_lock = backend.lock_folder(folder_path, "SHARED", false).unwrap();
+            assert!(matches!(
+                backend.lock_folder(folder_path, "SHARED", true),
+                Err(NaiveError::FolderInUse(_))
+            ));
+            drop(shared_lock);
+            drop(other_shared_lock);
+            let exclusive_lock = backend.lock_folder(folder_path, "SHARED", true).unwrap();
+            assert!(matches!(
+                backend.lock_folder(folder_path, "SHARED", false),
+                Err(NaiveError::FolderInUse(_))
+            ));
+            // The locks of other names are independent.
+            let other_lock = backend.lock_folder(folder_path, "OTHER", true).unwrap();
+            drop(exclusive_lock);
+            backend.lock_folder(folder_path, "SHARED", true).unwrap();
+            drop(other_lock);
+        }
+    }
+}
@@ -0,0 +1,455 @@
+use std::collections::{HashMap, HashSet};
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+
+use crate::backend::Backend;
+use crate::catalog::Catalog;
+use crate::manifest::Manifest;
+use crate::protos::messages;
+use crate::types::{NaiveError, Result};
+use crate::utils;
+
+/// The name of the file describing the backups in a backup folder.
+const BACKUP_CHAIN_FILE_NAME: &str = "BACKUPS";
+
+/// The subfolder of a backup folder holding the SSTables shared by its backups.
+const SHARED_FOLDER_NAME: &str = "shared";
+
+/// A backup in a backup folder.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BackupInfo {
+    pub backup_id: u64,
+    pub timestamp_ms: u64,
+
+    /// The previous backup of the folder, if any, whose SSTables are not copied again.
+    pub parent_id: Option<u64>,
+
+    /// The manifest of the data folder restored from the backup.
+    pub manifest: Manifest,
+
+    /// The number of the SSTables copied for the backup and their total size, the others being
+    /// shared with the previous backups.
+    pub num_new_sstables: usize,
+    pub new_sstable_bytes: u64,
+
+    /// The checksums of the blob files named by the manifest, in the same order.
+    pub blob_checksums: Vec<u32>,
+}
+
+impl BackupInfo {
+    fn to_message(&self) -> messages::Backup {
+        let mut backup = messages::Backup::new();
+        backup.set_backup_id(self.backup_id);
+        backup.set_timestamp_ms(self.timestamp_ms);
+        if let Some(parent_id) = self.parent_id {
+            backup.set_parent_id(parent_id);
+        }
+        backup.set_manifest(self.manifest.to_message());
+        backup.set_num_new_sstables(self.num_new_sstables as u64);
+        backup.set_new_sstable_bytes(self.new_sstable_bytes);
+        backup.set_blob_checksums(self.blob_checksums.clone());
+        backup
+    }
+
+    fn from_message(backup: &messages::Backup) -> Self {
+        Self {
+            backup_id: backup.get_backup_id(),
+            timestamp_ms: backup.get_timestamp_ms(),
+            parent_id: backup.has_parent_id().then(|| backup.get_parent_id()),
+            manifest: Manifest::from_message(backup.get_manifest()),
+            num_new_sstables: backup.get_num_new_sstables() as usize,
+            new_sstable_bytes: backup.get_new_sstable_bytes(),
+            blob_checksums: backup.get_blob_checksums().to_vec(),
+        }
+    }
+
+    /// The folder of the Memtable logs of the backup in the backup folder.
+    fn folder_path(&self, backup_path: &Path) -> PathBuf {
+        gen_backup_folder_path(backup_path, self.backup_id)
+    }
+}
+
+fn gen_backup_folder_path(backup_path: &Path, backup_id: u64) -> PathBuf {
+    backup_path.join(format!("backup_{}", backup_id))
+}
+
+/// The name of the copy of an SSTable or a blob file in the shared folder, qualified by its
+/// checksum, since a data folder restored and written again may reuse the name for other content.
+pub(crate) fn gen_shared_file_name(file_name: &str, checksum: u32) -> String {
+    format!("{:08x}_{}", checksum, file_name)
+}
+
+/// Copy a file into the shared folder unless a backup has copied it there already, through a
+/// temporary file renamed into place, so that the shared files are never overwritten and a copy
+/// cut short is never taken for a full one.
+fn share_file(backend: &dyn Backend, file_path: &Path, shared_file_path: &Path) -> Result<()> {
+    if backend.exists(shared_file_path) {
+        return Ok(());
+    }
+    let temp_file_path = utils::temp_file_path(shared_file_path);
+    backend.remove_file(&temp_file_path)?;
+    backend.copy(file_path, &temp_file_path)?;
+    backend.rename(&temp_file_path, shared_file_path)
+}
+
+/// The backups in a backup folder, from the oldest to the newest, rewritten as a whole and
+/// atomically replaced once a backup completes.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct BackupChain {
+    pub backups: Vec<BackupInfo>,
+}
+
+impl BackupChain {
+    /// Load the chain of the backup folder, which is empty if no backup is taken there yet.
+    pub fn load(backend: &dyn Backend, backup_path: &Path) -> Result<Self> {
+        let chain_path = backup_path.join(BACKUP_CHAIN_FILE_NAME);
+        if !backend.exists(&chain_path) {
+            return Ok(Self::default());
+        }
+        let mut file_reader = BufReader::new(backend.open(&chain_path)?);
+        let chain =
+            utils::read_message::<messages::BackupChain, _>(&mut file_reader)?.unwrap_or_default();
+        Ok(Self {
+            backups: chain
+                .get_backups()
+                .iter()
+                .map(BackupInfo::from_message)
+                .collect(),
+        })
+    }
+
+    fn save(&self, backend: &dyn Backend, backup_path: &Path) -> Result<()> {
+        let mut chain = messages::BackupChain::new();
+        chain.set_backups(self.backups.iter().map(BackupInfo::to_message).collect());
+        let mut bytes = Vec::new();
+        utils::write_message(&chain, &mut bytes)?;
+        backend.replace(&backup_path.join(BACKUP_CHAIN_FILE_NAME), &bytes)
+    }
+
+    pub fn get(&self, backup_id: u64) -> Option<&BackupInfo> {
+        self.backups
+            .iter()
+            .find(|backup| backup.backup_id == backup_id)
+    }
+
+    /// The ID for the next backup, after the ones taken so far.
+    pub fn next_backup_id(&self) -> u64 {
+        self.backups
+            .iter()
+            .map(|backup| backup.backup_id + 1)
+            .max()
+            .unwrap_or(1)
+    }
+}
+
+/// Back up a catalog into the backup folder incrementally under the ID.
+///
+/// The SSTables and the blob files they refer to are immutable, so the ones in a previous backup
+/// with the same name and checksum are shared with it, and only the others are copied into the
+/// shared folder, under their names qualified by their checksums. The Memtable logs are copied up
+/// to the last write into the folder of the backup, which is added to the chain last, so that a
+/// backup cut short is never restored.
+pub(crate) fn backup(
+    catalog: &RwLock<Catalog>,
+    backup_path: &Path,
+    backup_id: u64,
+) -> Result<BackupInfo> {
+    let mut pinned_files = Catalog::pin_files(catalog)?;
+    let backend = pinned_files.backend.clone();
+    let mut chain = BackupChain::load(backend.as_ref(), backup_path)?;
+    if chain.get(backup_id).is_some() {
+        return Err(NaiveError::InvalidOptions(format!(
+            "backup {} is in {} already",
+            backup_id,
+            backup_path.display()
+        )));
+    }
+    let backed_up_sstables = chain
+        .backups
+        .iter()
+        .flat_map(|backup| {
+            let manifest = &backup.manifest;
+            manifest
+                .sstable_names
+                .iter()
+                .cloned()
+                .zip(manifest.sstable_checksums.iter().copied())
+        })
+        .collect::<HashSet<_>>();
+    let backed_up_blobs = chain
+        .backups
+        .iter()
+        .flat_map(|backup| {
+            backup
+                .manifest
+                .blob_names
+                .iter()
+                .cloned()
+                .zip(backup.blob_checksums.iter().copied())
+        })
+        .collect::<HashSet<_>>();
+
+    let shared_path = backup_path.join(SHARED_FOLDER_NAME);
+    backend.create_dir_all(&shared_path)?;
+    let num_sstables = pinned_files.sstables.len();
+    let mut sstable_names = Vec::with_capacity(num_sstables);
+    let mut sstable_checksums = Vec::with_capacity(num_sstables);
+    let mut num_new_sstables = 0;
+    let mut new_sstable_bytes = 0;
+    for sstable in pinned_files.sstables.iter() {
+        let sstable_name = Catalog::file_name(sstable.file_path());
+        let checksum = sstable.checksum()?;
+        if !backed_up_sstables.contains(&(sstable_name.clone(), checksum)) {
+            let shared_sstable_path =
+                shared_path.join(gen_shared_file_name(&sstable_name, checksum));
+            share_file(backend.as_ref(), sstable.file_path(), &shared_sstable_path)?;
+            num_new_sstables += 1;
+            new_sstable_bytes += sstable.file_size() as u64;
+        }
+        sstable_names.push(sstable_name);
+        sstable_checksums.push(checksum);
+    }
+    let mut blob_checksums_by_name = HashMap::new();
+    for blob_file_path in pinned_files.blob_file_paths() {
+        let blob_name = Catalog::file_name(&blob_file_path);
+        let checksum = utils::checksum(&mut backend.open(&blob_file_path)?)?;
+        if !backed_up_blobs.contains(&(blob_name.clone(), checksum)) {
+            let shared_blob_path = shared_path.join(gen_shared_file_name(&blob_name, checksum));
+            share_file(backend.as_ref(), &blob_file_path, &shared_blob_path)?;
+        }
+        blob_checksums_by_name.insert(blob_name, checksum);
+    }
+
+    let folder_path = gen_backup_folder_path(backup_path, backup_id);
+    backend.create_dir_all(&folder_path)?;
+    for file_path in backend.list_files(&folder_path)? {
+        backend.remove_file(&file_path)?;
+    }
+    pinned_files.copy_logs(&folder_path)?;
+    let mut manifest = pinned_files.manifest;
+    manifest.sstable_names = sstable_names;
+    manifest.sstable_checksums = sstable_checksums;
+    manifest.blob_names = Catalog::blob_names(&pinned_files.sstables);
+    let blob_checksums = manifest
+        .blob_names
+        .iter()
+        .map(|blob_name| blob_checksums_by_name.get(blob_name).copied())
+        .collect::<Option<Vec<_>>>()
+        .ok_or(NaiveError::Unknown)?;
+
+    let backup = BackupInfo {
+        backup_id,
+        timestamp_ms: utils::now_ms(),
+        parent_id: chain.backups.last().map(|backup| backup.backup_id),
+        manifest,
+        num_new_sstables,
+        new_sstable_bytes,
+        blob_checksums,
+    };
+    chain.backups.push(backup.clone());
+    chain.save(backend.as_ref(), backup_path)?;
+    log::info!(
+        "Backed up {} SSTables, {} of which are new with {} bytes, into backup {} of {}.",
+        num_sstables,
+        num_new_sstables,
+        new_sstable_bytes,
+        backup_id,
+        backup_path.display()
+    );
+    Ok(backup)
+}
+
+/// Restore a backup of the backup folder into the target folder, which must hold no data folder
+/// yet, as `restore_files` does.
+pub(crate) fn restore(
+    backend: &dyn Backend,
+    backup_path: &Path,
+    backup: &BackupInfo,
+    target_path: &Path,
+) -> Result<()> {
+    let manifest = &backup.manifest;
+    if backup.blob_checksums.len() != manifest.blob_names.len() {
+        log::error!(
+            "Found {} checksums for {} blob files in backup {} of {}.",
+            backup.blob_checksums.len(),
+            manifest.blob_names.len(),
+            backup.backup_id,
+            backup_path.display()
+        );
+        return Err(NaiveError::InvalidData);
+    }
+    let shared_path = backup_path.join(SHARED_FOLDER_NAME);
+    let data_file_paths = manifest
+        .sstable_names
+        .iter()
+        .zip(manifest.sstable_checksums.iter())
+        .chain(manifest.blob_names.iter().zip(backup.blob_checksums.iter()))
+        .map(|(file_name, &checksum)| shared_path.join(gen_shared_file_name(file_name, checksum)))
+        .collect::<Vec<_>>();
+    restore_files(
+        backend,
+        manifest,
+        &data_file_paths,
+        &backup.folder_path(backup_path),
+        target_path,
+    )?;
+    log::info!(
+        "Restored backup {} of {} into {}.",
+        backup.backup_id,
+        backup_path.display(),
+        target_path.display()
+    );
+    Ok(())
+}
+
+/// Restore a checkpoint, or any data folder not in use, into the target folder, which must hold no
+/// data folder yet, as `restore_files` does. The column families are left to the caller.
+pub(crate) fn restore_checkpoint(
+    backend: &dyn Backend,
+    checkpoint_path: &Path,
+    target_path: &Path,
+) -> Result<Manifest> {
+    let manifest = Manifest::load(backend, checkpoint_path)?.ok_or_else(|| {
+        NaiveError::InvalidOptions(format!(
+            "found no backup or checkpoint in {}",
+            checkpoint_path.display()
+        ))
+    })?;
+    let data_file_paths = manifest
+        .sstable_names
+        .iter()
+        .chain(manifest.blob_names.iter())
+        .map(|file_name| checkpoint_path.join(file_name))
+        .collect::<Vec<_>>();
+    restore_files(
+        backend,
+        &manifest,
+        &data_file_paths,
+        checkpoint_path,
+        target_path,
+    )?;
+    log::info!(
+        "Restored checkpoint {} into {}.",
+        checkpoint_path.display(),
+        target_path.display()
+    );
+    Ok(manifest)
+}
+
+/// Copy the SSTables along with their blob files from their paths, in the order the manifest names
+/// them, and the Memtable logs from their folder into the target folder, and then the manifest
+/// itself.
+///
+/// The manifest is validated and the files are checked to be all there before anything is
+/// copied, and the copies of the SSTables are verified against their checksums before the
+/// manifest is written, so that the target only opens once restored in full. The files copied
+/// are removed again if the restore fails, so that it can be retried into the same folder. The
+/// files are copied rather than hard-linked, so that the backup stays intact whatever happens to
+/// them.
+fn restore_files(
+    backend: &dyn Backend,
+    manifest: &Manifest,
+    data_file_paths: &[PathBuf],
+    log_folder_path: &Path,
+    target_path: &Path,
+) -> Result<()> {
+    if manifest.sstable_checksums.len() != manifest.sstable_names.len() {
+        log::error!(
+            "Found {} checksums for {} SSTables in the manifest to restore.",
+            manifest.sstable_checksums.len(),
+            manifest.sstable_names.len()
+        );
+        return Err(NaiveError::InvalidData);
+    }
+    let log_names = manifest
+        .flushing_log_name
+        .iter()
+        .chain(manifest.active_log_name.iter())
+        .collect::<Vec<_>>();
+    let missing_paths = data_file_paths
+        .iter()
+        .cloned()
+        .chain(
+            log_names
+                .iter()
+                .map(|log_name| log_folder_path.join(log_name)),
+        )
+        .filter(|file_path| !backend.exists(file_path))
+        .collect::<Vec<_>>();
+    if !missing_paths.is_empty() {
+        return Err(NaiveError::CorruptBackup(missing_paths));
+    }
+    if Manifest::load(backend, target_path)?.is_some() {
+        return Err(NaiveError::InvalidOptions(format!(
+            "{} already holds a data folder",
+            target_path.display()
+        )));
+    }
+
+    backend.create_dir_all(target_path)?;
+    let mut copied_paths = Vec::new();
+    let result = (|| {
+        let source_paths = data_file_paths.iter().cloned().chain(
+            log_names
+                .iter()
+                .map(|log_name| log_folder_path.join(log_name)),
+        );
+        let file_names = manifest
+            .sstable_names
+            .iter()
+            .chain(manifest.blob_names.iter())
+            .chain(log_names.iter().copied());
+        for (source_path, file_name) in source_paths.zip(file_names) {
+            let target_file_path = target_path.join(file_name);
+            backend.copy(&source_path, &target_file_path)?;
+            copied_paths.push(target_file_path);
+        }
+        let bad_paths = manifest.verify_sstables(backend, target_path)?;
+        if !bad_paths.is_empty() {
+            return Err(NaiveError::CorruptBackup(bad_paths));
+        }
+        manifest.save(backend, target_path)
+    })();
+    if result.is_err() {
+        remove_files(backend, &copied_paths, target_path);
+    }
+    result
+}
+
+/// Undo a restore into the target folder that has completed, e.g. of the default column family
+/// once one of the others has failed to restore.
+pub(crate) fn unrestore(backend: &dyn Backend, manifest: &Manifest, target_path: &Path) {
+    let file_paths = manifest
+        .sstable_names
+        .iter()
+        .chain(manifest.blob_names.iter())
+        .chain(manifest.flushing_log_name.iter())
+        .chain(manifest.active_log_name.iter())
+        .map(|file_name| target_path.join(file_name))
+        .chain(std::iter::once(Manifest::gen_manifest_path(target_path)))
+        .collect::<Vec<_>>();
+    remove_files(backend, &file_paths, target_path);
+}
+
+/// Remove the files restored into the target folder, and then the folder itself unless anything
+/// else is left in it, logging rather than returning the errors, which would hide the one that
+/// failed the restore.
+fn remove_files(backend: &dyn Backend, file_paths: &[PathBuf], target_path: &Path) {
+    for file_path in file_paths {
+        if let Err(error) = backend.remove_file(file_path) {
+            log::error!(
+                "Failed to remove {} restored in part: {:?}",
+                file_path.display(),
+                error
+            );
+        }
+    }
+    if let Err(error) = backend.remove_dir(target_path) {
+        log::error!(
+            "Failed to remove {} restored in part: {:?}",
+            target_path.display(),
+            error
+        );
+    }
+}
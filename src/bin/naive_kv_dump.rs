@@ -0,0 +1,78 @@
+use log::info;
+use naive_kv::dump::DumpFormat;
+use naive_kv::logger;
+use naive_kv::options::Options;
+use naive_kv::types::Result;
+use naive_kv::NaiveKV;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::ops::Bound;
+
+const DEFAULT_FOLDER_PATH: &str = "/tmp/naive_kv/";
+
+/// Dump the key-value pairs of a data folder as JSON lines or CSV, e.g. for migrating the data
+/// elsewhere or debugging.
+///
+/// The data folder is opened as a replica, so the server may keep running on it meanwhile.
+fn main() -> Result<()> {
+    logger::init()?;
+    let flag_matches = clap::App::new("NaiveKV Dump")
+        .version(env!("CARGO_PKG_VERSION"))
+        .author(env!("CARGO_PKG_AUTHORS"))
+        .arg(
+            clap::Arg::with_name("folder_path")
+                .long("directory")
+                .takes_value(true)
+                .help("The directory for holding the storage"),
+        )
+        .arg(
+            clap::Arg::with_name("format")
+                .long("format")
+                .takes_value(true)
+                .possible_values(&["jsonl", "csv"])
+                .help("The format of the dump, JSON lines by default"),
+        )
+        .arg(
+            clap::Arg::with_name("start")
+                .long("start")
+                .takes_value(true)
+                .help("The first key to dump, inclusive"),
+        )
+        .arg(
+            clap::Arg::with_name("end")
+                .long("end")
+                .takes_value(true)
+                .help("The key to stop the dump at, exclusive"),
+        )
+        .arg(
+            clap::Arg::with_name("output_path")
+                .long("output")
+                .takes_value(true)
+                .help("The file to write the dump into, the standard output by default"),
+        )
+        .get_matches();
+
+    let folder_path = flag_matches
+        .value_of("folder_path")
+        .unwrap_or(DEFAULT_FOLDER_PATH);
+    let format = DumpFormat::from_name(flag_matches.value_of("format").unwrap_or("jsonl"))
+        .expect("Cannot parse format.");
+    let bound = |name, to_bound: fn(String) -> Bound<String>| {
+        flag_matches
+            .value_of(name)
+            .map_or(Bound::Unbounded, |key: &str| to_bound(key.to_owned()))
+    };
+    let range = (
+        bound("start", Bound::Included),
+        bound("end", Bound::Excluded),
+    );
+
+    let naive_kv = NaiveKV::open_replica(folder_path, Options::for_folder(folder_path)?)?;
+    let mut writer: Box<dyn Write> = match flag_matches.value_of("output_path") {
+        Some(output_path) => Box::new(BufWriter::new(File::create(output_path)?)),
+        None => Box::new(BufWriter::new(std::io::stdout().lock())),
+    };
+    let num_pairs = naive_kv.dump(range, format, &mut writer)?;
+    info!("Dumped {} keys from {}.", num_pairs, folder_path);
+    Ok(())
+}
@@ -0,0 +1,189 @@
+use log::info;
+use naive_kv::audit::AuditLog;
+use naive_kv::logger;
+use naive_kv::options::Options;
+use naive_kv::types::{Result, WriteBatch};
+use naive_kv::NaiveKV;
+use std::path::Path;
+
+const DEFAULT_FOLDER_PATH: &str = "/tmp/naive_kv/";
+const DEFAULT_BATCH_SIZE: &str = "1000";
+
+/// Load the key-value pairs of a JSON or TOML file into a data folder, e.g. to bootstrap an
+/// environment with its configuration and seed data at deploy time.
+///
+/// The file holds a single object or table whose values are strings, or numbers and booleans,
+/// which are stored as written. The server must not be running on the data folder meanwhile.
+fn main() -> Result<()> {
+    logger::init()?;
+    let flag_matches = clap::App::new("NaiveKV Seed")
+        .version(env!("CARGO_PKG_VERSION"))
+        .author(env!("CARGO_PKG_AUTHORS"))
+        .arg(
+            clap::Arg::with_name("folder_path")
+                .long("directory")
+                .takes_value(true)
+                .help("The directory for holding the storage"),
+        )
+        .arg(
+            clap::Arg::with_name("format")
+                .long("format")
+                .takes_value(true)
+                .possible_values(&["json", "toml"])
+                .help("The format of the file, told by its extension by default"),
+        )
+        .arg(
+            clap::Arg::with_name("batch_size")
+                .long("batch-size")
+                .takes_value(true)
+                .help("The number of keys written together in each batch"),
+        )
+        .arg(
+            clap::Arg::with_name("file_path")
+                .required(true)
+                .help("The JSON or TOML file of the key-value pairs"),
+        )
+        .get_matches();
+
+    let folder_path = flag_matches
+        .value_of("folder_path")
+        .unwrap_or(DEFAULT_FOLDER_PATH);
+    let batch_size = match flag_matches
+        .value_of("batch_size")
+        .unwrap_or(DEFAULT_BATCH_SIZE)
+        .parse::<usize>()
+    {
+        Ok(batch_size) if batch_size > 0 => batch_size,
+        _ => {
+            eprintln!("Cannot parse batch_size: expected a positive integer.");
+            std::process::exit(1);
+        }
+    };
+    let file_path = flag_matches.value_of("file_path").unwrap();
+    let format = flag_matches.value_of("format").unwrap_or_else(|| {
+        match Path::new(file_path)
+            .extension()
+            .and_then(|extension| extension.to_str())
+        {
+            Some("toml") => "toml",
+            _ => "json",
+        }
+    });
+
+    let contents = std::fs::read_to_string(file_path)?;
+    let pairs = match format {
+        "toml" => parse_toml(&contents),
+        _ => parse_json(&contents),
+    };
+    let pairs = match pairs {
+        Ok(pairs) => pairs,
+        Err(message) => {
+            eprintln!("Cannot load {}: {}", file_path, message);
+            std::process::exit(1);
+        }
+    };
+
+    // Keep the order of an existing data folder, which may not be the default one.
+    let naive_kv = NaiveKV::open(folder_path, Options::for_folder(folder_path)?)?;
+    let mut catalog_viewer = naive_kv.catalog_viewer()?;
+    for chunk in pairs.chunks(batch_size) {
+        let mut batch = WriteBatch::new();
+        for (key, value) in chunk {
+            batch.set(key.clone(), value.clone());
+        }
+        catalog_viewer.write(&batch)?;
+    }
+    // Make sure the keys are on the disk before saying so.
+    naive_kv.close()?;
+    info!("Loaded {} keys from {}.", pairs.len(), file_path);
+
+    // Keep the seeding on record along with the other admin actions on the data folder.
+    AuditLog::open(Path::new(folder_path))?.record(
+        &std::env::var("USER").unwrap_or_else(|_| "unknown".to_owned()),
+        "SEED",
+        vec![
+            ("file".to_owned(), file_path.to_owned()),
+            ("num_keys".to_owned(), pairs.len().to_string()),
+        ],
+    )?;
+    println!("Loaded {} keys into {}.", pairs.len(), folder_path);
+    Ok(())
+}
+
+fn parse_json(contents: &str) -> std::result::Result<Vec<(String, String)>, String> {
+    let object = match serde_json::from_str(contents).map_err(|error| error.to_string())? {
+        serde_json::Value::Object(object) => object,
+        _ => return Err("the top level is not an object".to_owned()),
+    };
+    object
+        .into_iter()
+        .map(|(key, value)| {
+            let value = match value {
+                serde_json::Value::String(string) => string,
+                serde_json::Value::Number(number) => number.to_string(),
+                serde_json::Value::Bool(boolean) => boolean.to_string(),
+                _ => return Err(format!("the value of {:?} is not a scalar", key)),
+            };
+            Ok((key, value))
+        })
+        .collect()
+}
+
+fn parse_toml(contents: &str) -> std::result::Result<Vec<(String, String)>, String> {
+    let table = contents
+        .parse::<toml::Table>()
+        .map_err(|error| error.to_string())?;
+    table
+        .into_iter()
+        .map(|(key, value)| {
+            let value = match value {
+                toml::Value::String(string) => string,
+                toml::Value::Integer(integer) => integer.to_string(),
+                toml::Value::Float(float) => float.to_string(),
+                toml::Value::Boolean(boolean) => boolean.to_string(),
+                _ => return Err(format!("the value of {:?} is not a scalar", key)),
+            };
+            Ok((key, value))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_json, parse_toml};
+
+    #[test]
+    fn test_parse_json() {
+        let pairs = parse_json(r#"{"name": "naive", "port": 8080, "debug": true}"#).unwrap();
+        assert_eq!(
+            pairs,
+            vec![
+                ("debug".to_owned(), "true".to_owned()),
+                ("name".to_owned(), "naive".to_owned()),
+                ("port".to_owned(), "8080".to_owned()),
+            ]
+        );
+        assert!(parse_json("[1, 2]").is_err());
+        assert!(parse_json(r#"{"nested": {"a": 1}}"#).is_err());
+        assert!(parse_json(r#"{"null": null}"#).is_err());
+        assert!(parse_json("{").is_err());
+    }
+
+    #[test]
+    fn test_parse_toml() {
+        let pairs =
+            parse_toml("name = \"naive\"\nport = 8080\nratio = 0.5\ndebug = false\n").unwrap();
+        assert_eq!(
+            pairs,
+            vec![
+                ("debug".to_owned(), "false".to_owned()),
+                ("name".to_owned(), "naive".to_owned()),
+                ("port".to_owned(), "8080".to_owned()),
+                ("ratio".to_owned(), "0.5".to_owned()),
+            ]
+        );
+        assert!(parse_toml("[table]\na = 1\n").is_err());
+        assert!(parse_toml("list = [1, 2]\n").is_err());
+        assert!(parse_toml("name = ").is_err());
+    }
+}
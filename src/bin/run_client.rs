@@ -1,12 +1,15 @@
-use clap;
+use naive_kv::acl::Role;
+use naive_kv::client::{Client, FailoverPolicy, ServerRole};
 use naive_kv::protos::messages;
-use naive_kv::types::Result;
-use naive_kv::utils;
+use naive_kv::types::{NaiveError, Result};
 use std::io::{stdin, stdout, BufRead, Write};
-use std::net::TcpStream;
+use std::net::ToSocketAddrs;
+use std::time::{Duration, SystemTime};
 
 const DEFAULT_SERVER_IP: &str = "127.0.0.1";
 const DEFAULT_SERVER_PORT: &str = "1024";
+const EXPORT_CHUNK_SIZE: u64 = 1000;
+const AUDIT_LOG_CHUNK_SIZE: u64 = 1000;
 
 fn main() -> Result<()> {
     let flag_matches = clap::App::new("NaiveKV Client")
@@ -24,6 +27,42 @@ fn main() -> Result<()> {
                 .takes_value(true)
                 .help("The port of the server"),
         )
+        .arg(
+            clap::Arg::with_name("standby_addresses")
+                .long("standby")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("The IP:PORT of a standby server to fail over to, repeatable"),
+        )
+        .arg(
+            clap::Arg::with_name("fail_over_writes")
+                .long("fail-over-writes")
+                .help("Promote a standby for the writes once the server fails"),
+        )
+        .arg(
+            clap::Arg::with_name("timeout_ms")
+                .long("timeout-ms")
+                .takes_value(true)
+                .help("The milliseconds the server may spend on each request"),
+        )
+        .arg(
+            clap::Arg::with_name("batch")
+                .long("batch")
+                .help("Send the requests at batch priority, e.g. for a bulk load"),
+        )
+        .arg(
+            clap::Arg::with_name("namespace")
+                .long("namespace")
+                .takes_value(true)
+                .help("The namespace, i.e. the column family, of the requests"),
+        )
+        .arg(
+            clap::Arg::with_name("timeout_s")
+                .long("timeout")
+                .takes_value(true)
+                .help("The seconds to wait for the server before giving up on a request"),
+        )
         .get_matches();
 
     let server_ip = flag_matches
@@ -32,9 +71,49 @@ fn main() -> Result<()> {
     let server_port = flag_matches
         .value_of("server_port")
         .unwrap_or(DEFAULT_SERVER_PORT);
+    let timeout_ms = flag_matches
+        .value_of("timeout_ms")
+        .map(|s| s.parse::<u64>().expect("Cannot parse timeout_ms."));
+    let timeout = flag_matches
+        .value_of("timeout_s")
+        .map(|s| Duration::from_secs_f64(s.parse::<f64>().expect("Cannot parse timeout_s.")));
 
     // TODO Decide whether to build the TCP connection once for all or for each single request.
-    let mut stream = TcpStream::connect(format!("{}:{}", server_ip, server_port))?;
+    let server_address = format!("{}:{}", server_ip, server_port);
+    let standby_addresses = flag_matches
+        .values_of("standby_addresses")
+        .into_iter()
+        .flatten()
+        .collect::<Vec<_>>();
+    let mut client = if !standby_addresses.is_empty() {
+        let resolve = |address: &str| {
+            address
+                .to_socket_addrs()
+                .ok()
+                .and_then(|mut addresses| addresses.next())
+                .expect("Cannot resolve the server address.")
+        };
+        let mut servers = vec![(resolve(&server_address), ServerRole::Primary)];
+        for standby_address in standby_addresses {
+            servers.push((resolve(standby_address), ServerRole::Standby));
+        }
+        let failover_policy = FailoverPolicy {
+            fail_over_writes: flag_matches.is_present("fail_over_writes"),
+            ..FailoverPolicy::default()
+        };
+        Client::connect_with_failover(servers, timeout, failover_policy)?
+    } else {
+        match timeout {
+            Some(timeout) => Client::connect_with_timeout(server_address, timeout)?,
+            None => Client::connect(server_address)?,
+        }
+    };
+    if flag_matches.is_present("batch") {
+        client = client.with_priority(messages::Priority::BATCH);
+    }
+    if let Some(namespace) = flag_matches.value_of("namespace") {
+        client = client.with_namespace(namespace);
+    }
 
     let stdin = stdin();
     let mut user_messages = stdin.lock().lines();
@@ -46,7 +125,6 @@ fn main() -> Result<()> {
         stdout().flush().unwrap();
         user_messages.next()
     };
-    let mut request_id = 1; // Cannot start from 0, otherwise the response would not be serialized.
     while let Some(command) = read_user_command() {
         let command = command?;
         let tokens = command
@@ -81,30 +159,215 @@ fn main() -> Result<()> {
             "get" => {
                 check_arguments!(tokens.len() - 1, 1);
                 let mut request = messages::Request::new();
-                request.set_id(request_id);
-                request_id += 1;
                 request.set_operation(messages::Operation::GET);
                 request.set_key(tokens[1].to_owned());
-                send_request(request, &mut stream);
+                send_request(request, timeout_ms, &mut client);
             }
             "set" => {
                 check_arguments!(tokens.len() - 1, 2);
                 let mut request = messages::Request::new();
-                request.set_id(request_id);
-                request_id += 1;
                 request.set_operation(messages::Operation::SET);
                 request.set_key(tokens[1].to_owned());
                 request.set_value(tokens[2].to_owned());
-                send_request(request, &mut stream);
+                send_request(request, timeout_ms, &mut client);
+            }
+            "setnx" | "setxx" | "getset" | "append" => {
+                check_arguments!(tokens.len() - 1, 2);
+                let mut request = messages::Request::new();
+                request.set_operation(match tokens[0] {
+                    "setnx" => messages::Operation::SET_NX,
+                    "setxx" => messages::Operation::SET_XX,
+                    "getset" => messages::Operation::GET_SET,
+                    _ => messages::Operation::APPEND,
+                });
+                request.set_key(tokens[1].to_owned());
+                request.set_value(tokens[2].to_owned());
+                send_request(request, timeout_ms, &mut client);
             }
             "remove" => {
                 check_arguments!(tokens.len() - 1, 1);
                 let mut request = messages::Request::new();
-                request.set_id(request_id);
-                request_id += 1;
                 request.set_operation(messages::Operation::REMOVE);
                 request.set_key(tokens[1].to_owned());
-                send_request(request, &mut stream);
+                send_request(request, timeout_ms, &mut client);
+            }
+            "incr" => {
+                check_arguments!(tokens.len() - 1, 2);
+                let delta = match tokens[2].parse::<i64>() {
+                    Ok(delta) => delta,
+                    Err(_) => {
+                        println!("Invalid Arguments: expect an integer delta.");
+                        continue;
+                    }
+                };
+                let mut request = messages::Request::new();
+                request.set_operation(messages::Operation::INCREMENT);
+                request.set_key(tokens[1].to_owned());
+                request.set_delta(delta);
+                send_request(request, timeout_ms, &mut client);
+            }
+            "mdel" => {
+                if tokens.len() < 2 {
+                    println!("Invalid Arguments: expect at least 1 but got 0.");
+                    continue;
+                }
+                let mut request = messages::Request::new();
+                request.set_operation(messages::Operation::MDEL);
+                request.set_keys(tokens[1..].iter().map(|&key| key.to_owned()).collect());
+                send_request(request, timeout_ms, &mut client);
+            }
+            "export" => {
+                check_arguments!(tokens.len() - 1, 1);
+                match client.export(tokens[1], EXPORT_CHUNK_SIZE) {
+                    Ok(entries) => {
+                        for (key, value) in entries.iter() {
+                            println!("  {}: {}", key, value);
+                        }
+                        println!("Exported {} keys.", entries.len());
+                    }
+                    Err(error) => {
+                        println!("Failed to export: {:?}.", error);
+                    }
+                }
+            }
+            "audit" => {
+                check_arguments!(tokens.len() - 1, 0);
+                match client.audit_log(AUDIT_LOG_CHUNK_SIZE) {
+                    Ok(records) => {
+                        for record in records.iter() {
+                            print!(
+                                "  [{} ms] {} by {}",
+                                record.timestamp_ms, record.action, record.principal
+                            );
+                            for (name, value) in record.parameters.iter() {
+                                print!(", {}={}", name, value);
+                            }
+                            println!();
+                        }
+                    }
+                    Err(error) => {
+                        println!("Failed to read the audit log: {:?}.", error);
+                    }
+                }
+            }
+            "time" => {
+                check_arguments!(tokens.len() - 1, 0);
+                match client.server_time() {
+                    Ok(server_time) => {
+                        let local_time_ms = SystemTime::now()
+                            .duration_since(SystemTime::UNIX_EPOCH)
+                            .map_or(0, |duration| duration.as_millis() as u64);
+                        println!(
+                            "  time (ms): {}, sequence no: {}, round trip: {:?}, offset (ms): {}",
+                            server_time.time_ms,
+                            server_time.sequence_no,
+                            server_time.round_trip,
+                            server_time.clock_offset_ms(local_time_ms)
+                        );
+                    }
+                    Err(error) => {
+                        println!("Failed to get the server time: {:?}.", error);
+                    }
+                }
+            }
+            "verify" => {
+                check_arguments!(tokens.len() - 1, 0);
+                match client.verify() {
+                    Ok(report) => {
+                        println!(
+                            "  {} keys and {} tombstones checked, {} problems found",
+                            report.num_keys, report.num_tombstones, report.num_problems
+                        );
+                        for problem in report.problems.iter() {
+                            println!("    {}", problem);
+                        }
+                    }
+                    Err(error) => {
+                        println!("Failed to verify: {:?}.", error);
+                    }
+                }
+            }
+            "stats" => {
+                check_arguments!(tokens.len() - 1, 0);
+                match client.stats_all() {
+                    Ok(namespace_stats) => {
+                        for (namespace, stats) in namespace_stats.iter() {
+                            println!("  namespace {:?}: {:?}", namespace, stats);
+                            println!(
+                                "    write amplification {:.2}, compaction backlog {} bytes",
+                                stats.write_amplification(),
+                                stats.compaction_backlog_bytes
+                            );
+                        }
+                    }
+                    Err(error) => {
+                        println!("Failed to get the stats: {:?}.", error);
+                    }
+                }
+            }
+            "jobs" => {
+                check_arguments!(tokens.len() - 1, 0);
+                let mut request = messages::Request::new();
+                request.set_operation(messages::Operation::LIST_JOBS);
+                send_request(request, timeout_ms, &mut client);
+            }
+            "pause" | "resume" => {
+                check_arguments!(tokens.len() - 1, 1);
+                let mut request = messages::Request::new();
+                request.set_operation(if tokens[0] == "pause" {
+                    messages::Operation::PAUSE_JOB
+                } else {
+                    messages::Operation::RESUME_JOB
+                });
+                request.set_key(tokens[1].to_owned());
+                send_request(request, timeout_ms, &mut client);
+            }
+            "grant" => {
+                check_arguments!(tokens.len() - 1, 2);
+                let role = match tokens[2].parse::<Role>() {
+                    Ok(role) => role,
+                    Err(_) => {
+                        println!("Invalid Arguments: expect read-only, read-write or admin.");
+                        continue;
+                    }
+                };
+                let mut request = messages::Request::new();
+                request.set_operation(messages::Operation::GRANT_ROLE);
+                request.set_key(tokens[1].to_owned());
+                request.set_role(role.to_message());
+                send_request(request, timeout_ms, &mut client);
+            }
+            "revoke" => {
+                check_arguments!(tokens.len() - 1, 1);
+                let mut request = messages::Request::new();
+                request.set_operation(messages::Operation::REVOKE_ROLE);
+                request.set_key(tokens[1].to_owned());
+                send_request(request, timeout_ms, &mut client);
+            }
+            "grants" => {
+                check_arguments!(tokens.len() - 1, 0);
+                let mut request = messages::Request::new();
+                request.set_operation(messages::Operation::LIST_GRANTS);
+                send_request(request, timeout_ms, &mut client);
+            }
+            "describe" => {
+                check_arguments!(tokens.len() - 1, 0);
+                let mut request = messages::Request::new();
+                request.set_operation(messages::Operation::DESCRIBE);
+                send_request(request, timeout_ms, &mut client);
+            }
+            "property" => {
+                check_arguments!(tokens.len() - 1, 1);
+                let mut request = messages::Request::new();
+                request.set_operation(messages::Operation::GET_PROPERTY);
+                request.set_key(tokens[1].to_owned());
+                send_request(request, timeout_ms, &mut client);
+            }
+            "plan" => {
+                check_arguments!(tokens.len() - 1, 0);
+                let mut request = messages::Request::new();
+                request.set_operation(messages::Operation::PLAN_COMPACTION);
+                send_request(request, timeout_ms, &mut client);
             }
             _ => {
                 println!("Command not found.");
@@ -114,39 +377,99 @@ fn main() -> Result<()> {
     Ok(())
 }
 
-fn send_request(request: messages::Request, stream: &mut TcpStream) {
-    match utils::write_message(&request, stream) {
-        Ok(()) => match utils::read_message::<messages::Response, TcpStream>(stream) {
-            Ok(response) => {
-                let response = response.unwrap_or(messages::Response::new());
-                if response.get_id() != request.get_id() {
-                    println!(
-                        "Invalid Response: expected id = {}, but got id = {}.",
-                        request.get_id(),
-                        response.get_id()
+fn send_request(mut request: messages::Request, timeout_ms: Option<u64>, client: &mut Client) {
+    if let Some(timeout_ms) = timeout_ms {
+        request.set_timeout_ms(timeout_ms);
+    }
+    match client.send(request) {
+        Ok(response) => {
+            print!("Status: {:?}", response.get_status());
+            if response.has_value() {
+                print!(", Value: {}", response.get_value());
+            }
+            if response.has_timestamp_ms() {
+                print!(", Timestamp (ms): {}", response.get_timestamp_ms());
+            }
+            if !response.get_statuses().is_empty() {
+                print!(", Statuses: {:?}", response.get_statuses());
+            }
+            if response.has_error() {
+                print!(", Error: {:?}", response.get_error());
+            }
+            println!();
+            for sstable in response.get_sstables() {
+                print!(
+                    "  gen {} (epoch {}): {}, {} bytes, {} entries",
+                    sstable.get_gen_no(),
+                    sstable.get_epoch_no(),
+                    sstable.get_file_path(),
+                    sstable.get_file_size(),
+                    sstable.get_num_entries()
+                );
+                if sstable.get_properties().get_num_deletions() > 0 {
+                    print!(
+                        " ({} deletions)",
+                        sstable.get_properties().get_num_deletions()
+                    );
+                }
+                if sstable.has_min_key() && sstable.has_max_key() {
+                    print!(
+                        ", keys [{}, {}]",
+                        sstable.get_min_key(),
+                        sstable.get_max_key()
                     );
                 }
-                print!("Status: {:?}", response.get_status());
-                if response.has_value() {
-                    print!(", Value: {}", response.get_value());
+                println!();
+            }
+            for job in response.get_jobs() {
+                print!(
+                    "  {}: every {} ms (+ up to {} ms), {}{} runs, {} failed, {} skipped",
+                    job.get_name(),
+                    job.get_interval_ms(),
+                    job.get_jitter_ms(),
+                    if job.get_is_paused() { "paused, " } else { "" },
+                    job.get_num_runs(),
+                    job.get_num_failures(),
+                    job.get_num_skipped()
+                );
+                if job.get_num_consecutive_failures() > 0 {
+                    print!(" ({} in a row)", job.get_num_consecutive_failures());
+                }
+                if job.get_is_running() {
+                    print!(", running");
+                } else if !job.get_is_paused() {
+                    print!(", next in {} ms", job.get_next_run_in_ms());
                 }
-                if response.has_error() {
-                    print!(", Error: {:?}", response.get_error());
+                if job.has_last_error() {
+                    print!(", last error: {}", job.get_last_error());
                 }
-                println!("");
+                println!();
             }
-            Err(error) => {
+            for grant in response.get_grants() {
                 println!(
-                    "Internal Error: failed to receive or deserialize the response: {:?}.",
-                    error
+                    "  {}: {}",
+                    grant.get_principal(),
+                    Role::from_message(grant.get_role())
                 );
             }
-        },
+            for plan in response.get_compaction_plans() {
+                print!(
+                    "  {:?} into gen {}: {} bytes",
+                    plan.get_kind(),
+                    plan.get_output_gen_no(),
+                    plan.get_estimated_output_size()
+                );
+                if plan.has_estimated_duration_ms() {
+                    print!(" in {} ms", plan.get_estimated_duration_ms());
+                }
+                println!(" from {}", plan.get_input_paths().join(", "));
+            }
+        }
+        Err(NaiveError::DeadlineExceeded) => {
+            println!("Timed out waiting for the server.");
+        }
         Err(error) => {
-            println!(
-                "Internal Error: failed to serialize or send the request: {:?}.",
-                error
-            );
+            println!("Internal Error: failed to send the request: {:?}.", error);
         }
     };
 }
@@ -156,7 +479,27 @@ fn print_help() {
     println!("Supported commands:");
     println!("  get [KEY]            Get the value for a key.");
     println!("  set [KEY] [VALUE]    Set the value for a key.");
+    println!("  setnx [KEY] [VALUE]  Set the value for a key only if it is missing.");
+    println!("  setxx [KEY] [VALUE]  Set the value for a key only if it has one.");
+    println!("  getset [KEY] [VALUE] Set the value for a key and show the old one.");
+    println!("  append [KEY] [VALUE] Append to the value for a key.");
     println!("  remove [KEY]         Remove a key.");
+    println!("  incr [KEY] [DELTA]   Add a delta, negative to subtract, to an integer value.");
+    println!("  mdel [KEY]...        Remove a list of keys at once.");
+    println!("  export [PREFIX]      Export the keys with the prefix from a snapshot.");
+    println!("  audit                Show the administrative actions on the server.");
+    println!("  describe             List the SSTables on the server.");
+    println!("  plan                 Show the next compactions on the server.");
+    println!("  stats                Show the engine stats of all the namespaces.");
+    println!("  property [NAME]      Show an engine property, e.g. naivekv.wal-bytes.");
+    println!("  verify               Cross-check the scans and the gets on the server.");
+    println!("  time                 Show the server clock and the last visible sequence no.");
+    println!("  jobs                 List the background jobs on the server.");
+    println!("  pause [JOB]          Pause a background job.");
+    println!("  resume [JOB]         Resume a paused background job.");
+    println!("  grant [IP] [ROLE]    Grant read-only, read-write or admin to a client.");
+    println!("  revoke [IP]          Revoke the role granted to a client.");
+    println!("  grants               List the roles granted to the clients.");
     println!("  exit                 Exit the interactive session.");
     println!("  help                 Display this help info.");
 }
@@ -1,27 +1,67 @@
-use clap;
 use log::info;
+use naive_kv::acl::{Acl, Grant, Role};
+use naive_kv::audit::AuditLog;
 use naive_kv::catalog::CatalogViewer;
+use naive_kv::client::Client;
+use naive_kv::compaction::CompactionKind;
+use naive_kv::fallback::{ArchiveFallback, ReadFallback, RemoteFallback};
+use naive_kv::io_scheduler::{self, IoPriority, IoScheduler, DEFAULT_MAX_BACKGROUND_WAIT_MS};
 use naive_kv::logger;
+use naive_kv::options::{
+    LogRecoveryMode, Options, DEFAULT_COLD_GENERATION_NO, DEFAULT_MEMTABLE_SHARDS,
+    DEFAULT_NUM_BACKGROUND_THREADS, DEFAULT_TRASH_RETENTION_S,
+};
 use naive_kv::protos::messages;
+use naive_kv::scheduler::JobStatus;
+use naive_kv::snapshot::{ScanIterator, Snapshot};
 use naive_kv::thread_pool::ThreadPool;
-use naive_kv::types::Result;
+use naive_kv::types::{NaiveError, Record, Result};
 use naive_kv::utils;
+use naive_kv::watch::ChangeEvent;
 use naive_kv::NaiveKV;
-use std::net::{SocketAddr, TcpListener, TcpStream};
-use std::thread;
-use std::time::Duration;
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::io::{ErrorKind, Write};
+use std::net::{IpAddr, SocketAddr, TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
 
 const DEFAULT_FOLDER_PATH: &str = "/tmp/naive_kv/";
 const DEFAULT_NUM_THREADS: usize = 8;
+const DEFAULT_MAX_FRAME_SIZE: usize = 4 << 20; // 4MB
 const DEFAULT_SOCKET_IP: &str = "127.0.0.1";
 const DEFAULT_SOCKET_PORT: &str = "1024";
+const BUSY_WRITE_TIMEOUT_MS: u64 = 100;
+const DEFAULT_MAX_QUEUED_BYTES: usize = 16 << 20; // 16MB
+const DEFAULT_IDLE_TIMEOUT_S: u64 = 300;
+const DEFAULT_CHUNK_SIZE: u64 = 1000;
+const MAX_CHUNK_SIZE: u64 = 100_000;
+const DEFAULT_EXPORT_LEASE_S: u64 = 600;
+const DEFAULT_MAX_EXPORTS: usize = 64;
+const DEFAULT_MAX_EXPORTS_PER_CLIENT: usize = 4;
+const DEFAULT_MAX_EXPORT_CHUNK_BYTES: usize = 4 << 20; // 4MB
+const EXPORT_REAP_INTERVAL_S: u64 = 10;
+const SHADOW_QUEUE_CAPACITY: usize = 10_000;
+const SHADOW_TIMEOUT_MS: u64 = 1000;
+const SHADOW_REPORT_INTERVAL_S: u64 = 60;
+const FALLBACK_TIMEOUT_MS: u64 = 1000;
+
+/// The namespace of the default column family, while every other namespace is the column family
+/// of its name.
+const DEFAULT_NAMESPACE: &str = "";
+
+/// Everyone is an admin unless told otherwise, as before there were roles.
+const DEFAULT_ROLE: &str = "admin";
 
 // TODO Create a config type to incorporate the following params.
-const MIN_RETRY_DELAY_MS: u64 = 100;
-const MAX_RETRY_TIMES: usize = 3;
-const MEMTABLE_COMPACTION_THRESHOLD: usize = 1 << 20; // 1MB
-const GENERATION_GEOMETRIC_RATIO: usize = 8;
-const COMPACTION_DAEMON_CYCLE_S: u64 = 1; // 1 sec
+const WRITE_POLL_INTERVAL_MS: u64 = 10;
+const FINAL_WRITE_TIMEOUT_MS: u64 = 1000;
+/// How often a watch with no key written sends an empty response, which finds out whether the
+/// client is still there.
+const WATCH_HEARTBEAT_INTERVAL_MS: u64 = 1000;
+const WATCH_WRITE_TIMEOUT_MS: u64 = 10_000;
 
 fn main() -> Result<()> {
     logger::init()?;
@@ -34,12 +74,203 @@ fn main() -> Result<()> {
                 .takes_value(true)
                 .help("The directory for holding the storage"),
         )
+        .arg(
+            clap::Arg::with_name("cold_folder_path")
+                .long("cold-directory")
+                .takes_value(true)
+                .help("The absolute path of a slower directory for the oldest SSTable generations"),
+        )
+        .arg(
+            clap::Arg::with_name("cold_generation_no")
+                .long("cold-generation")
+                .takes_value(true)
+                .help("The first SSTable generation stored in the cold directory"),
+        )
         .arg(
             clap::Arg::with_name("num_threads")
                 .long("workers")
                 .takes_value(true)
                 .help("The number of worker threads"),
         )
+        .arg(
+            clap::Arg::with_name("num_background_threads")
+                .long("background-workers")
+                .takes_value(true)
+                .help("The number of background threads for compaction"),
+        )
+        .arg(
+            clap::Arg::with_name("memtable_shards")
+                .long("memtable-shards")
+                .takes_value(true)
+                .help("The number of shards the writes to the Memtable are split into by key"),
+        )
+        .arg(
+            clap::Arg::with_name("repair")
+                .long("repair")
+                .help("Repair an inconsistent directory instead of failing on start"),
+        )
+        .arg(
+            clap::Arg::with_name("paranoid")
+                .long("paranoid")
+                .help("Verify every SSTable on start, failing on any corruption found"),
+        )
+        .arg(
+            clap::Arg::with_name("no_create")
+                .long("no-create")
+                .help("Fail on start unless the directory holds a store already"),
+        )
+        .arg(
+            clap::Arg::with_name("error_if_exists")
+                .long("error-if-exists")
+                .conflicts_with("no_create")
+                .help("Fail on start if the directory holds a store already"),
+        )
+        .arg(
+            clap::Arg::with_name("log_recovery_mode")
+                .long("log-recovery")
+                .takes_value(true)
+                .possible_values(&["fail-fast", "truncate", "skip"])
+                .help("What to do about the corrupted records in the Memtable logs on start"),
+        )
+        .arg(
+            clap::Arg::with_name("trash_retention_s")
+                .long("trash-retention")
+                .takes_value(true)
+                .help("The seconds to keep deprecated files in the trash, 0 for removing them"),
+        )
+        .arg(
+            clap::Arg::with_name("column_families")
+                .long("column-family")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("A column family to serve as a namespace, created if missing, repeatable"),
+        )
+        .arg(
+            clap::Arg::with_name("default_role")
+                .long("default-role")
+                .takes_value(true)
+                .possible_values(&["read-only", "read-write", "admin"])
+                .help("The role of the clients without a grant"),
+        )
+        .arg(
+            clap::Arg::with_name("grants")
+                .long("grant")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help(
+                    "Grant a role to a client IP address in a namespace, the default one if \
+                     omitted, e.g. 10.0.0.1=read-only or 10.0.0.1@meta=admin, repeatable",
+                ),
+        )
+        .arg(
+            clap::Arg::with_name("max_frame_size")
+                .long("max-frame-size")
+                .takes_value(true)
+                .help("The largest request in bytes, beyond which FRAME_TOO_LARGE is returned"),
+        )
+        .arg(
+            clap::Arg::with_name("max_queued_bytes")
+                .long("max-queued-bytes")
+                .takes_value(true)
+                .help("The most response bytes queued for a slow client before disconnecting it"),
+        )
+        .arg(
+            clap::Arg::with_name("idle_timeout_s")
+                .long("idle-timeout")
+                .takes_value(true)
+                .help(
+                    "The idle seconds before a client neither sending nor reading is disconnected",
+                ),
+        )
+        .arg(
+            clap::Arg::with_name("export_lease_s")
+                .long("export-lease")
+                .takes_value(true)
+                .help("The seconds an export without a chunk requested stays open"),
+        )
+        .arg(
+            clap::Arg::with_name("max_exports")
+                .long("max-exports")
+                .takes_value(true)
+                .help("The most exports open at once, each pinning a snapshot"),
+        )
+        .arg(
+            clap::Arg::with_name("max_exports_per_client")
+                .long("max-exports-per-client")
+                .takes_value(true)
+                .help("The most exports open at once by a client IP address"),
+        )
+        .arg(
+            clap::Arg::with_name("max_export_chunk_bytes")
+                .long("max-export-chunk-bytes")
+                .takes_value(true)
+                .help("The most bytes of entries in an export chunk, cutting it short if needed"),
+        )
+        .arg(
+            clap::Arg::with_name("max_generations")
+                .long("max-generations")
+                .takes_value(true)
+                .help("The cap on the number of SSTable generations, unlimited if not set"),
+        )
+        .arg(
+            clap::Arg::with_name("overload_action")
+                .long("overload-action")
+                .takes_value(true)
+                .possible_values(&["busy", "reset"])
+                .help("Whether to answer SERVER_BUSY or reset when all workers are busy"),
+        )
+        .arg(
+            clap::Arg::with_name("shadow_address")
+                .long("shadow")
+                .takes_value(true)
+                .value_name("IP:PORT")
+                .help("A secondary server to forward the writes to on a best-effort basis"),
+        )
+        .arg(
+            clap::Arg::with_name("fallback_address")
+                .long("fallback")
+                .takes_value(true)
+                .value_name("IP:PORT")
+                .conflicts_with("fallback_folder_path")
+                .help("A server to read the keys never written locally from"),
+        )
+        .arg(
+            clap::Arg::with_name("fallback_folder_path")
+                .long("fallback-directory")
+                .takes_value(true)
+                .help("A data folder, e.g. a backup, to read the keys never written locally from"),
+        )
+        .arg(
+            clap::Arg::with_name("backfill")
+                .long("backfill")
+                .help("Write the values read from the fallback locally"),
+        )
+        .arg(
+            clap::Arg::with_name("io_priority")
+                .long("io-priority")
+                .help("Let the reads and writes serving the clients preempt the background ones"),
+        )
+        .arg(
+            clap::Arg::with_name("mmap_reads")
+                .long("mmap")
+                .help("Read the SSTables through memory maps"),
+        )
+        .arg(
+            clap::Arg::with_name("background_io_limit")
+                .long("background-io-limit")
+                .takes_value(true)
+                .value_name("BYTES_PER_S")
+                .requires("io_priority")
+                .help("The cap on the bytes per second the background jobs read and write"),
+        )
+        .arg(
+            clap::Arg::with_name("batch_concurrency")
+                .long("batch-concurrency")
+                .takes_value(true)
+                .help("The most batch requests served at once, half the workers by default"),
+        )
         .arg(
             clap::Arg::with_name("socket_ip")
                 .long("ip")
@@ -57,10 +288,142 @@ fn main() -> Result<()> {
     let folder_path = flag_matches
         .value_of("folder_path")
         .unwrap_or(DEFAULT_FOLDER_PATH);
+    let cold_folder_path = flag_matches.value_of("cold_folder_path");
+    let cold_generation_no = flag_matches
+        .value_of("cold_generation_no")
+        .map(|s| {
+            s.parse::<usize>()
+                .expect("Cannot parse cold_generation_no.")
+        })
+        .unwrap_or(DEFAULT_COLD_GENERATION_NO);
     let num_threads = flag_matches
         .value_of("num_threads")
         .map(|s| s.parse::<usize>().expect("Cannot parse num_threads."))
         .unwrap_or(DEFAULT_NUM_THREADS);
+    let num_background_threads = flag_matches
+        .value_of("num_background_threads")
+        .map(|s| {
+            s.parse::<usize>()
+                .expect("Cannot parse num_background_threads.")
+        })
+        .unwrap_or(DEFAULT_NUM_BACKGROUND_THREADS);
+    let memtable_shards = flag_matches
+        .value_of("memtable_shards")
+        .map(|s| s.parse::<usize>().expect("Cannot parse memtable_shards."))
+        .unwrap_or(DEFAULT_MEMTABLE_SHARDS);
+    let repair_on_open = flag_matches.is_present("repair");
+    let paranoid_checks = flag_matches.is_present("paranoid");
+    let create_if_missing = !flag_matches.is_present("no_create");
+    let error_if_exists = flag_matches.is_present("error_if_exists");
+    let log_recovery_mode = match flag_matches.value_of("log_recovery_mode") {
+        Some("truncate") => LogRecoveryMode::TruncateAndContinue,
+        Some("skip") => LogRecoveryMode::SkipCorrupted,
+        _ => LogRecoveryMode::FailFast,
+    };
+    let trash_retention_s = flag_matches
+        .value_of("trash_retention_s")
+        .map(|s| s.parse::<u64>().expect("Cannot parse trash_retention_s."))
+        .unwrap_or(DEFAULT_TRASH_RETENTION_S);
+    let default_role = flag_matches
+        .value_of("default_role")
+        .unwrap_or(DEFAULT_ROLE)
+        .parse::<Role>()
+        .expect("Cannot parse default_role.");
+    let grants = flag_matches
+        .values_of("grants")
+        .into_iter()
+        .flatten()
+        .map(|s| {
+            let (principal, role) = s.split_once('=').expect("Cannot parse grants.");
+            let (principal, namespace) = principal
+                .split_once('@')
+                .unwrap_or((principal, DEFAULT_NAMESPACE));
+            Grant {
+                namespace: namespace.to_owned(),
+                principal: principal.to_owned(),
+                role: role.parse::<Role>().expect("Cannot parse grants."),
+            }
+        })
+        .collect::<Vec<_>>();
+    let max_frame_size = flag_matches
+        .value_of("max_frame_size")
+        .map(|s| s.parse::<usize>().expect("Cannot parse max_frame_size."))
+        .unwrap_or(DEFAULT_MAX_FRAME_SIZE);
+    let max_queued_bytes = flag_matches
+        .value_of("max_queued_bytes")
+        .map(|s| s.parse::<usize>().expect("Cannot parse max_queued_bytes."))
+        .unwrap_or(DEFAULT_MAX_QUEUED_BYTES);
+    let idle_timeout = Duration::from_secs(
+        flag_matches
+            .value_of("idle_timeout_s")
+            .map(|s| s.parse::<u64>().expect("Cannot parse idle_timeout_s."))
+            .unwrap_or(DEFAULT_IDLE_TIMEOUT_S),
+    );
+    let export_limits = ExportLimits {
+        lease: Duration::from_secs(
+            flag_matches
+                .value_of("export_lease_s")
+                .map(|s| s.parse::<u64>().expect("Cannot parse export_lease_s."))
+                .unwrap_or(DEFAULT_EXPORT_LEASE_S),
+        ),
+        max_exports: flag_matches
+            .value_of("max_exports")
+            .map(|s| s.parse::<usize>().expect("Cannot parse max_exports."))
+            .unwrap_or(DEFAULT_MAX_EXPORTS),
+        max_exports_per_client: flag_matches
+            .value_of("max_exports_per_client")
+            .map(|s| {
+                s.parse::<usize>()
+                    .expect("Cannot parse max_exports_per_client.")
+            })
+            .unwrap_or(DEFAULT_MAX_EXPORTS_PER_CLIENT),
+        max_chunk_bytes: flag_matches
+            .value_of("max_export_chunk_bytes")
+            .map(|s| {
+                s.parse::<usize>()
+                    .expect("Cannot parse max_export_chunk_bytes.")
+            })
+            .unwrap_or(DEFAULT_MAX_EXPORT_CHUNK_BYTES),
+    };
+    let max_generations = flag_matches
+        .value_of("max_generations")
+        .map(|s| s.parse::<usize>().expect("Cannot parse max_generations."));
+    let overload_action = match flag_matches.value_of("overload_action") {
+        Some("reset") => OverloadAction::Reset,
+        _ => OverloadAction::Busy,
+    };
+    let shadow_address = flag_matches.value_of("shadow_address");
+    let fallback_address = flag_matches.value_of("fallback_address");
+    let fallback_folder_path = flag_matches.value_of("fallback_folder_path");
+    let backfill_reads = flag_matches.is_present("backfill");
+    let read_fallback: Option<Arc<dyn ReadFallback>> =
+        match (fallback_address, fallback_folder_path) {
+            (Some(fallback_address), _) => Some(Arc::new(RemoteFallback::new(
+                fallback_address,
+                Duration::from_millis(FALLBACK_TIMEOUT_MS),
+            ))),
+            (None, Some(fallback_folder_path)) => Some(Arc::new(ArchiveFallback::open(
+                fallback_folder_path,
+                Options::default(),
+            )?)),
+            (None, None) => None,
+        };
+    let io_priority = flag_matches.is_present("io_priority");
+    let mmap_reads = flag_matches.is_present("mmap_reads");
+    let background_io_limit = flag_matches
+        .value_of("background_io_limit")
+        .map(|s| s.parse::<u64>().expect("Cannot parse background_io_limit."));
+    let io_scheduler = io_priority.then(|| {
+        Arc::new(IoScheduler::new(
+            Duration::from_millis(DEFAULT_MAX_BACKGROUND_WAIT_MS),
+            background_io_limit,
+        ))
+    });
+    let batch_concurrency = flag_matches
+        .value_of("batch_concurrency")
+        .map(|s| s.parse::<usize>().expect("Cannot parse batch_concurrency."))
+        .unwrap_or((num_threads / 2).max(1));
+    assert!(batch_concurrency > 0, "batch_concurrency must be positive.");
     let socket_ip = flag_matches
         .value_of("socket_ip")
         .unwrap_or(DEFAULT_SOCKET_IP);
@@ -68,127 +431,1998 @@ fn main() -> Result<()> {
         .value_of("socket_port")
         .unwrap_or(DEFAULT_SOCKET_PORT);
 
-    let naive_kv = NaiveKV::open(
-        folder_path,
-        MEMTABLE_COMPACTION_THRESHOLD,
-        GENERATION_GEOMETRIC_RATIO,
-        COMPACTION_DAEMON_CYCLE_S,
-    )?;
+    let options = Options {
+        num_background_threads,
+        memtable_shards,
+        create_if_missing,
+        error_if_exists,
+        repair_on_open,
+        paranoid_checks,
+        log_recovery_mode,
+        trash_retention_s,
+        max_generations,
+        cold_folder_path: cold_folder_path.map(PathBuf::from),
+        cold_generation_no,
+        read_fallback,
+        backfill_reads,
+        io_scheduler,
+        mmap_reads,
+        ..Options::default()
+    };
+    let naive_kv = Arc::new(NaiveKV::open(folder_path, options)?);
+    let column_families = flag_matches
+        .values_of("column_families")
+        .into_iter()
+        .flatten()
+        .collect::<Vec<_>>();
+    for name in column_families.iter() {
+        naive_kv.create_column_family(name)?;
+    }
     info!("Started the NaiveKV instance.");
 
+    // Record the configuration the server starts with, so that any change to it is on record.
+    let audit_log = AuditLog::open(Path::new(folder_path))?;
+    audit_log.record(
+        &std::env::var("USER").unwrap_or_else(|_| "unknown".to_owned()),
+        "START",
+        vec![
+            ("directory".to_owned(), folder_path.to_owned()),
+            (
+                "cold_directory".to_owned(),
+                format!("{:?}", cold_folder_path),
+            ),
+            ("cold_generation".to_owned(), cold_generation_no.to_string()),
+            ("workers".to_owned(), num_threads.to_string()),
+            (
+                "background_workers".to_owned(),
+                num_background_threads.to_string(),
+            ),
+            ("memtable_shards".to_owned(), memtable_shards.to_string()),
+            ("create".to_owned(), create_if_missing.to_string()),
+            ("error_if_exists".to_owned(), error_if_exists.to_string()),
+            ("repair".to_owned(), repair_on_open.to_string()),
+            ("paranoid".to_owned(), paranoid_checks.to_string()),
+            (
+                "log_recovery".to_owned(),
+                format!("{:?}", log_recovery_mode),
+            ),
+            ("trash_retention".to_owned(), trash_retention_s.to_string()),
+            ("column_families".to_owned(), column_families.join(",")),
+            ("default_role".to_owned(), default_role.to_string()),
+            (
+                "grants".to_owned(),
+                grants
+                    .iter()
+                    .map(|grant| format!("{}={}", grant.principal, grant.role))
+                    .collect::<Vec<_>>()
+                    .join(","),
+            ),
+            ("max_frame_size".to_owned(), max_frame_size.to_string()),
+            ("max_queued_bytes".to_owned(), max_queued_bytes.to_string()),
+            ("idle_timeout".to_owned(), format!("{:?}", idle_timeout)),
+            ("export_limits".to_owned(), format!("{:?}", export_limits)),
+            (
+                "max_generations".to_owned(),
+                format!("{:?}", max_generations),
+            ),
+            (
+                "overload_action".to_owned(),
+                format!("{:?}", overload_action),
+            ),
+            ("shadow".to_owned(), format!("{:?}", shadow_address)),
+            ("fallback".to_owned(), format!("{:?}", fallback_address)),
+            (
+                "fallback_directory".to_owned(),
+                format!("{:?}", fallback_folder_path),
+            ),
+            ("backfill".to_owned(), backfill_reads.to_string()),
+            ("io_priority".to_owned(), io_priority.to_string()),
+            (
+                "background_io_limit".to_owned(),
+                format!("{:?}", background_io_limit),
+            ),
+            (
+                "batch_concurrency".to_owned(),
+                batch_concurrency.to_string(),
+            ),
+            ("ip".to_owned(), socket_ip.to_owned()),
+            ("port".to_owned(), socket_port.to_owned()),
+        ],
+    )?;
+
     let servers = ThreadPool::new(num_threads);
     info!("Started the server threads.");
 
     let listener = TcpListener::bind(format!("{}:{}", socket_ip, socket_port))?;
     info!("Started the TCP listener.");
 
-    for stream in listener.incoming() {
-        if let Ok(stream) = stream {
-            let catalog_viewer = naive_kv.catalog_viewer()?;
-            servers.add_task(move || {
-                let _ = serve_client(catalog_viewer, stream);
-            })?;
+    let shadow = shadow_address
+        .map(|address| Shadow::start(address, &naive_kv))
+        .transpose()?;
+    let server_state = Arc::new(ServerState {
+        naive_kv,
+        exports: Exports::new(export_limits),
+        audit_log,
+        acl: Acl::new(default_role, grants),
+        shadow,
+        batch_lane: BatchLane::new(batch_concurrency),
+    });
+    {
+        // Reclaim the abandoned exports even while no export is requested.
+        let server_state = server_state.clone();
+        std::thread::spawn(move || loop {
+            std::thread::sleep(Duration::from_secs(EXPORT_REAP_INTERVAL_S));
+            if let Err(error) = server_state.exports.reclaim() {
+                log::error!("Failed to reclaim the abandoned exports: {:?}", error);
+            }
+        });
+    }
+    for stream in listener.incoming().flatten() {
+        // Keep a handle on the connection for turning it down if the task cannot be queued.
+        let rejected_stream = stream.try_clone()?;
+        let catalog_viewers = CatalogViewers::new(server_state.naive_kv.catalog_viewer()?);
+        let server_state = server_state.clone();
+        match servers.try_add_task(move || {
+            let _ = serve_client(
+                catalog_viewers,
+                &server_state,
+                stream,
+                max_frame_size,
+                max_queued_bytes,
+                idle_timeout,
+            );
+        }) {
+            Ok(()) => {}
+            Err(NaiveError::TaskQueueFull) => {
+                if let Err(error) = reject_client(rejected_stream, overload_action) {
+                    log::error!("Failed to turn down a connection: {:?}", error);
+                }
+            }
+            Err(error) => {
+                return Err(error);
+            }
         }
     }
     Ok(())
 }
 
-fn serve_client(mut catalog_viewer: CatalogViewer, mut stream: TcpStream) -> Result<()> {
-    let client_address = stream.peer_addr()?;
-    info!("Start serving client {}.", client_address);
-    loop {
-        let mut response = messages::Response::new();
-        match utils::read_message::<messages::Request, TcpStream>(&mut stream) {
-            Ok(Some(request)) => {
-                handle_request(
-                    &client_address,
-                    &mut catalog_viewer,
-                    &request,
-                    &mut response,
+/// The viewers of a connection by namespace, each opened on the first request in its namespace.
+struct CatalogViewers {
+    catalog_viewers: HashMap<String, CatalogViewer>,
+}
+
+impl CatalogViewers {
+    fn new(catalog_viewer: CatalogViewer) -> Self {
+        Self {
+            catalog_viewers: HashMap::from([(DEFAULT_NAMESPACE.to_owned(), catalog_viewer)]),
+        }
+    }
+
+    /// The viewer of the column family of the namespace, or ColumnFamilyNotFound if there is no
+    /// such column family.
+    fn get(&mut self, naive_kv: &NaiveKV, namespace: &str) -> Result<&mut CatalogViewer> {
+        match self.catalog_viewers.entry(namespace.to_owned()) {
+            Entry::Occupied(entry) => Ok(entry.into_mut()),
+            Entry::Vacant(entry) => Ok(entry.insert(naive_kv.catalog_viewer_for(namespace)?)),
+        }
+    }
+}
+
+/// The state shared by all the connections.
+struct ServerState {
+    naive_kv: Arc<NaiveKV>,
+    exports: Exports,
+    audit_log: AuditLog,
+
+    /// The roles of the clients by IP address, changeable by the admins until the server
+    /// restarts, when the flags apply again.
+    acl: Acl,
+
+    shadow: Option<Arc<Shadow>>,
+
+    batch_lane: BatchLane,
+}
+
+/// Keeps the requests of batch priority out of the way of the interactive ones, so that the bulk
+/// loaders sharing a server do not inflate the latency of the other clients.
+///
+/// At most so many batch requests are served at once, and the others are turned down as busy
+/// right away rather than holding up the workers of their connections, which a burst of them
+/// would otherwise take over. Their reads and writes go at the background I/O priority, which the
+/// foreground ones preempt if the server schedules its I/O.
+struct BatchLane {
+    max_running: usize,
+    num_running: AtomicUsize,
+}
+
+impl BatchLane {
+    fn new(max_running: usize) -> Self {
+        Self {
+            max_running,
+            num_running: AtomicUsize::new(0),
+        }
+    }
+
+    /// Serve a request at its priority, or return None if it is of batch priority and the lane is
+    /// full.
+    fn run<T>(&self, request: &messages::Request, serve: impl FnOnce() -> T) -> Option<T> {
+        if request.get_priority() != messages::Priority::BATCH {
+            return Some(serve());
+        }
+        self.num_running
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |num_running| {
+                (num_running < self.max_running).then_some(num_running + 1)
+            })
+            .ok()?;
+        let result = io_scheduler::with_priority(IoPriority::Background, serve);
+        self.num_running.fetch_sub(1, Ordering::SeqCst);
+        Some(result)
+    }
+}
+
+/// Forwards the writes applied locally to a secondary server, so that a new cluster can be
+/// validated under the production traffic before cutting over to it.
+///
+/// The writes are taken from a watch on all the keys of each namespace served, which sees those
+/// to each key in the order
+/// they are applied, and forwarded one by one in that order as the values the keys end up with,
+/// e.g. the sum of an INCREMENT rather than its delta, so that the secondary converges on the same
+/// values whatever it held before. Neither a TTL nor the atomicity of a batch is forwarded, though
+/// the expiry of a value is, as a removal.
+///
+/// The forwarding is best-effort: the writes are dropped while the queue is full and never
+/// retried once failed, so the secondary may drift from this server. So are the writes missed
+/// while the watch is disconnected for falling behind, after which the keys are watched again.
+/// The writes keep their namespaces, so the secondary must serve the same column families.
+struct Shadow {
+    address: String,
+
+    /// The writes waiting to be forwarded, along with when they were applied locally.
+    sender: crossbeam::channel::Sender<(messages::Request, Instant)>,
+
+    num_forwarded: AtomicU64,
+    num_failed: AtomicU64,
+    num_dropped: AtomicU64,
+
+    /// The times the watch has been disconnected for falling behind, each dropping an unknown
+    /// number of writes.
+    num_overflows: AtomicU64,
+
+    /// How long the last forwarded write took from being applied locally to being applied on the
+    /// secondary.
+    lag_ms: AtomicU64,
+}
+
+impl Shadow {
+    fn start(address: &str, naive_kv: &Arc<NaiveKV>) -> Result<Arc<Self>> {
+        let (sender, receiver) = crossbeam::channel::bounded(SHADOW_QUEUE_CAPACITY);
+        let shadow = Arc::new(Self {
+            address: address.to_owned(),
+            sender,
+            num_forwarded: AtomicU64::new(0),
+            num_failed: AtomicU64::new(0),
+            num_dropped: AtomicU64::new(0),
+            num_overflows: AtomicU64::new(0),
+            lag_ms: AtomicU64::new(0),
+        });
+        let mut namespaces = vec![DEFAULT_NAMESPACE.to_owned()];
+        namespaces.extend(naive_kv.column_family_names()?);
+        for namespace in namespaces {
+            let mut events = watch_namespace(naive_kv, &namespace)?;
+            let shadow = shadow.clone();
+            let naive_kv = naive_kv.clone();
+            std::thread::spawn(move || loop {
+                for event in events {
+                    shadow.forward(&namespace, &event);
+                }
+                // The watch has fallen behind and been disconnected.
+                shadow.num_overflows.fetch_add(1, Ordering::Relaxed);
+                log::warn!(
+                    "Fell behind forwarding the writes to {}, dropping some.",
+                    shadow.address
                 );
-            }
-            Ok(None) => {
-                break;
-            }
-            Err(error) => {
-                log::error!("Failed to receive or deserialize request: {:?}", error);
-                response.set_status(messages::Status::OPERATION_NOT_SUPPORTED);
-            }
+                events = match watch_namespace(&naive_kv, &namespace) {
+                    Ok(events) => events,
+                    Err(error) => {
+                        log::error!("Failed to watch the writes to forward: {:?}", error);
+                        return;
+                    }
+                };
+            });
         }
-        let mut retry_delay_ms = MIN_RETRY_DELAY_MS;
-        for _ in 0..MAX_RETRY_TIMES {
-            match utils::write_message(&response, &mut stream) {
-                Ok(()) => {
-                    break;
+        {
+            let shadow = shadow.clone();
+            std::thread::spawn(move || shadow.run(receiver));
+        }
+        info!("Started forwarding the writes to {}.", address);
+        Ok(shadow)
+    }
+
+    /// Queue a write applied locally in the namespace, without ever waiting for the secondary.
+    fn forward(&self, namespace: &str, event: &ChangeEvent) {
+        let request = match forwarded_request(namespace, event) {
+            Some(request) => request,
+            None => return,
+        };
+        if self.sender.try_send((request, Instant::now())).is_err() {
+            self.num_dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn run(&self, receiver: crossbeam::channel::Receiver<(messages::Request, Instant)>) {
+        let timeout = Duration::from_millis(SHADOW_TIMEOUT_MS);
+        let mut client = None;
+        let mut last_report = Instant::now();
+        for (request, applied_at) in receiver {
+            if client.is_none() {
+                client = Client::connect_with_timeout(self.address.as_str(), timeout)
+                    .map_err(|error| {
+                        log::warn!("Failed to connect to shadow {}: {:?}", self.address, error)
+                    })
+                    .ok();
+            }
+            match client.as_mut().map(|client| client.send(request)) {
+                Some(Ok(response)) if response.get_status() == messages::Status::OK => {
+                    self.num_forwarded.fetch_add(1, Ordering::Relaxed);
+                    self.lag_ms
+                        .store(applied_at.elapsed().as_millis() as u64, Ordering::Relaxed);
                 }
-                Err(error) => {
-                    log::error!("Failed to serialize or send response: {:?}", error);
+                Some(Ok(response)) => {
+                    log::warn!(
+                        "Shadow {} failed a write with {:?}: {}",
+                        self.address,
+                        response.get_status(),
+                        response.get_error()
+                    );
+                    self.num_failed.fetch_add(1, Ordering::Relaxed);
+                }
+                Some(Err(error)) => {
+                    log::warn!("Failed to forward a write to {}: {:?}", self.address, error);
+                    self.num_failed.fetch_add(1, Ordering::Relaxed);
+                    // Reconnect for the next write.
+                    client = None;
+                }
+                None => {
+                    self.num_failed.fetch_add(1, Ordering::Relaxed);
                 }
             }
-            thread::sleep(Duration::from_millis(retry_delay_ms));
-            retry_delay_ms *= 2;
+            if last_report.elapsed() >= Duration::from_secs(SHADOW_REPORT_INTERVAL_S) {
+                info!("Shadow stats: {:?}", self.stats());
+                last_report = Instant::now();
+            }
+        }
+    }
+
+    fn stats(&self) -> messages::ShadowStats {
+        let mut stats = messages::ShadowStats::new();
+        stats.set_address(self.address.clone());
+        stats.set_num_forwarded(self.num_forwarded.load(Ordering::Relaxed));
+        stats.set_num_failed(self.num_failed.load(Ordering::Relaxed));
+        stats.set_num_dropped(self.num_dropped.load(Ordering::Relaxed));
+        stats.set_num_overflows(self.num_overflows.load(Ordering::Relaxed));
+        stats.set_queue_length(self.sender.len() as u64);
+        stats.set_lag_ms(self.lag_ms.load(Ordering::Relaxed));
+        stats
+    }
+}
+
+/// Watch all the keys of the namespace, i.e. of the column family of the name.
+fn watch_namespace(
+    naive_kv: &NaiveKV,
+    namespace: &str,
+) -> Result<crossbeam::channel::Receiver<ChangeEvent>> {
+    if namespace == DEFAULT_NAMESPACE {
+        naive_kv.watch("")
+    } else {
+        naive_kv.catalog_viewer_for(namespace)?.watch("")
+    }
+}
+
+/// The request setting the key in the namespace of the secondary to what the write has left it
+/// with here, or None for a merge operand, which the server never writes.
+fn forwarded_request(namespace: &str, event: &ChangeEvent) -> Option<messages::Request> {
+    let mut request = messages::Request::new();
+    request.set_namespace(namespace.to_owned());
+    request.set_key(event.key.clone());
+    match &event.record {
+        Record::Value(value) | Record::ExpiringValue { value, .. } => {
+            request.set_operation(messages::Operation::SET);
+            request.set_value(value.clone());
+        }
+        Record::Deleted => request.set_operation(messages::Operation::REMOVE),
+        Record::Merge(_) => return None,
+    }
+    Some(request)
+}
+
+/// How to turn down a new connection when all the workers are busy and the task buffer is full.
+#[derive(Clone, Copy, Debug)]
+enum OverloadAction {
+    /// Respond with SERVER_BUSY and then close the connection.
+    Busy,
+    /// Reset the connection without any response.
+    Reset,
+}
+
+/// Turn down a connection right away, so that the accept loop never waits for the workers.
+fn reject_client(stream: TcpStream, overload_action: OverloadAction) -> Result<()> {
+    let client_address = stream.peer_addr()?;
+    log::warn!(
+        "Turned down client {} since all the workers are busy.",
+        client_address
+    );
+    match overload_action {
+        OverloadAction::Busy => {
+            let mut stream = stream;
+            let mut response = messages::Response::new();
+            response.set_status(messages::Status::SERVER_BUSY);
+            response.set_error("All the workers are busy.".to_owned());
+            // A slow client must not hold up the accept loop.
+            stream.set_write_timeout(Some(Duration::from_millis(BUSY_WRITE_TIMEOUT_MS)))?;
+            utils::write_message(&response, &mut stream)?;
+        }
+        OverloadAction::Reset => {
+            // Closing with a zero linger timeout sends a TCP reset instead of a FIN.
+            socket2::SockRef::from(&stream).set_linger(Some(Duration::ZERO))?;
         }
     }
-    info!("End serving client {}.", client_address);
     Ok(())
 }
 
-fn handle_request(
-    client_address: &SocketAddr,
-    catalog_viewer: &mut CatalogViewer,
-    request: &messages::Request,
-    response: &mut messages::Response,
-) {
-    let key = request.get_key();
-    response.set_status(messages::Status::OK);
-    response.set_id(request.get_id());
-    match request.get_operation() {
-        messages::Operation::GET => {
-            info!(
-                "CLIENT={} REQUEST_ID={} GET {}",
-                client_address,
-                request.get_id(),
-                key
-            );
-            match catalog_viewer.get(key) {
-                Ok(Some(value)) => {
-                    response.set_value(value);
-                }
-                Ok(None) => {
-                    response.set_status(messages::Status::KEY_NOT_FOUND);
-                }
-                Err(_) => {
-                    response.set_status(messages::Status::INTERNAL_ERROR);
-                }
-            }
+/// The responses waiting to be sent to a client, written without blocking so that a slow client
+/// never holds up its worker thread.
+struct ResponseQueue {
+    stream: TcpStream,
+
+    /// The framed responses not yet written, starting from the offset.
+    buffer: Vec<u8>,
+    offset: usize,
+}
+
+impl ResponseQueue {
+    fn new(stream: TcpStream) -> Self {
+        Self {
+            stream,
+            buffer: Vec::new(),
+            offset: 0,
         }
-        messages::Operation::SET => {
-            if !request.has_value() {
-                response.set_status(messages::Status::VALUE_MISSING);
-                return;
-            }
-            let value = request.get_value();
-            info!(
-                "CLIENT={} REQUEST_ID={} SET {} {}",
-                client_address,
-                request.get_id(),
-                key,
-                value
-            );
-            if let Err(_) = catalog_viewer.set(key.to_string(), value.to_string()) {
-                response.set_status(messages::Status::INTERNAL_ERROR);
-            }
+    }
+
+    fn push(&mut self, response: &messages::Response) -> Result<()> {
+        utils::write_message(response, &mut self.buffer)?;
+        Ok(())
+    }
+
+    /// The number of bytes not yet written.
+    fn len(&self) -> usize {
+        self.buffer.len() - self.offset
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Write as many queued bytes as the socket takes right now, in as few writes as possible.
+    fn flush(&mut self) -> Result<()> {
+        if self.is_empty() {
+            return Ok(());
         }
-        messages::Operation::REMOVE => {
-            info!(
-                "CLIENT={} REQUEST_ID={} REMOVE {}",
-                client_address,
-                request.get_id(),
-                key
-            );
-            if let Err(_) = catalog_viewer.remove(key.to_string()) {
-                response.set_status(messages::Status::INTERNAL_ERROR);
+        self.stream.set_nonblocking(true)?;
+        let result = self.write_until_blocked();
+        self.stream.set_nonblocking(false)?;
+        if self.is_empty() {
+            self.buffer.clear();
+            self.offset = 0;
+        }
+        result
+    }
+
+    fn write_until_blocked(&mut self) -> Result<()> {
+        while !self.is_empty() {
+            match self.stream.write(&self.buffer[self.offset..]) {
+                Ok(0) => return Err(std::io::Error::from(ErrorKind::WriteZero).into()),
+                Ok(num_bytes) => self.offset += num_bytes,
+                Err(error) if error.kind() == ErrorKind::WouldBlock => break,
+                Err(error) if error.kind() == ErrorKind::Interrupted => (),
+                Err(error) => return Err(error.into()),
             }
         }
+        Ok(())
+    }
+
+    /// Write out the remaining bytes once the client has no more requests, waiting a bounded time.
+    fn finish(mut self) -> Result<()> {
+        self.stream
+            .set_write_timeout(Some(Duration::from_millis(FINAL_WRITE_TIMEOUT_MS)))?;
+        self.stream.write_all(&self.buffer[self.offset..])?;
+        Ok(())
+    }
+}
+
+/// Wait for the next request from the client for a while, and tell whether it has come.
+fn poll_request(stream: &TcpStream, timeout: Duration) -> Result<bool> {
+    // A zero timeout would mean waiting forever.
+    stream.set_read_timeout(Some(timeout.max(Duration::from_millis(1))))?;
+    let result = stream.peek(&mut [0u8]);
+    stream.set_read_timeout(None)?;
+    match result {
+        // The end of the stream counts, to be read as such.
+        Ok(_) => Ok(true),
+        Err(error) if matches!(error.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+            Ok(false)
+        }
+        Err(error) => Err(error.into()),
+    }
+}
+
+/// Read the request the client has started sending, failing with a timeout if the whole frame has
+/// not arrived by then, so that a client stopping halfway cannot hold on to the worker.
+fn read_request(
+    stream: &TcpStream,
+    max_frame_size: usize,
+    timeout: Duration,
+) -> Result<Option<messages::Request>> {
+    let mut reader = DeadlineReader {
+        stream,
+        deadline: Instant::now() + timeout,
+    };
+    let result = utils::read_message_with_limit(&mut reader, max_frame_size);
+    stream.set_read_timeout(None)?;
+    result
+}
+
+/// Reads a stream until the deadline, after which each read fails with TimedOut.
+struct DeadlineReader<'a> {
+    stream: &'a TcpStream,
+    deadline: Instant,
+}
+
+impl std::io::Read for DeadlineReader<'_> {
+    fn read(&mut self, buffer: &mut [u8]) -> std::io::Result<usize> {
+        let timeout = self.deadline.saturating_duration_since(Instant::now());
+        if timeout.is_zero() {
+            return Err(ErrorKind::TimedOut.into());
+        }
+        self.stream.set_read_timeout(Some(timeout))?;
+        self.stream.read(buffer)
+    }
+}
+
+/// The caps on the exports, so that the clients leaking them cannot pin the snapshots, and the
+/// SSTable files under them, forever.
+#[derive(Debug)]
+struct ExportLimits {
+    /// How long an export stays open after its last chunk, renewed by each chunk requested.
+    lease: Duration,
+
+    max_exports: usize,
+    max_exports_per_client: usize,
+
+    /// The most bytes of keys and values in a chunk, which holds at least one entry all the same.
+    max_chunk_bytes: usize,
+}
+
+/// The exports in progress, shared by all the connections so that an export can resume on a new
+/// connection once the old one breaks.
+struct Exports {
+    limits: ExportLimits,
+    next_export_id: AtomicU64,
+    sessions: Mutex<HashMap<u64, ExportSession>>,
+}
+
+/// An export going through a snapshot, which stays consistent however long the export takes.
+struct ExportSession {
+    /// The IP address of the client which started the export.
+    client_ip: IpAddr,
+
+    prefix: String,
+    snapshot: Snapshot,
+
+    /// The scan of the snapshot, which has yielded the number of entries in the offset.
+    cursor: ScanIterator,
+    offset: u64,
+
+    /// Whether the last chunk has been sent, after which the export is kept only in case the
+    /// chunk gets lost, and no longer counts towards the caps.
+    is_finished: bool,
+
+    last_access: Instant,
+}
+
+impl Exports {
+    fn new(limits: ExportLimits) -> Self {
+        Self {
+            limits,
+            next_export_id: AtomicU64::new(0),
+            sessions: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Start an export of the keys with the prefix for the client, or return None if the client
+    /// or the server has too many exports open.
+    ///
+    /// The exports checked out for reading their chunks do not count, which lets the caps be
+    /// exceeded by the number of workers at most.
+    fn start(
+        &self,
+        catalog_viewer: &CatalogViewer,
+        client_ip: IpAddr,
+        prefix: &str,
+    ) -> Result<Option<(u64, ExportSession)>> {
+        {
+            let mut sessions = self.sessions.lock()?;
+            self.retain_leased(&mut sessions);
+            let open_sessions = sessions.values().filter(|session| !session.is_finished);
+            let (num_exports, num_client_exports) =
+                open_sessions.fold((0, 0), |(num_exports, num_client_exports), session| {
+                    (
+                        num_exports + 1,
+                        num_client_exports + usize::from(session.client_ip == client_ip),
+                    )
+                });
+            if num_exports >= self.limits.max_exports
+                || num_client_exports >= self.limits.max_exports_per_client
+            {
+                return Ok(None);
+            }
+        }
+        let snapshot = catalog_viewer.snapshot()?;
+        let cursor = snapshot.scan_prefix(prefix)?;
+        let export_id = self.next_export_id.fetch_add(1, Ordering::SeqCst) + 1;
+        let session = ExportSession {
+            client_ip,
+            prefix: prefix.to_owned(),
+            snapshot,
+            cursor,
+            offset: 0,
+            is_finished: false,
+            last_access: Instant::now(),
+        };
+        Ok(Some((export_id, session)))
+    }
+
+    /// Check out an export, so that other connections do not wait while it is read.
+    fn take(&self, export_id: u64) -> Result<Option<ExportSession>> {
+        let mut sessions = self.sessions.lock()?;
+        self.retain_leased(&mut sessions);
+        Ok(sessions.remove(&export_id))
+    }
+
+    /// Release the snapshots of the exports whose leases have run out.
+    fn reclaim(&self) -> Result<()> {
+        self.retain_leased(&mut *self.sessions.lock()?);
+        Ok(())
+    }
+
+    fn retain_leased(&self, sessions: &mut HashMap<u64, ExportSession>) {
+        let num_sessions = sessions.len();
+        sessions.retain(|_, session| session.last_access.elapsed() < self.limits.lease);
+        if sessions.len() < num_sessions {
+            info!(
+                "Reclaimed {} abandoned exports.",
+                num_sessions - sessions.len()
+            );
+        }
+    }
+
+    fn put_back(&self, export_id: u64, mut session: ExportSession) -> Result<()> {
+        session.last_access = Instant::now();
+        self.sessions.lock()?.insert(export_id, session);
+        Ok(())
+    }
+}
+
+/// Export the next chunk of a new or existing export starting from the requested offset.
+fn handle_export(
+    client_address: &SocketAddr,
+    catalog_viewer: &CatalogViewer,
+    exports: &Exports,
+    request: &messages::Request,
+    response: &mut messages::Response,
+) -> Result<()> {
+    let (export_id, mut session) = if request.has_export_id() {
+        let export_id = request.get_export_id();
+        match exports.take(export_id)? {
+            Some(session) => (export_id, session),
+            None => {
+                response.set_status(messages::Status::EXPORT_NOT_FOUND);
+                return Ok(());
+            }
+        }
+    } else {
+        match exports.start(catalog_viewer, client_address.ip(), request.get_key())? {
+            Some(started) => started,
+            None => {
+                response.set_status(messages::Status::TOO_MANY_EXPORTS);
+                return Ok(());
+            }
+        }
+    };
+    if request.get_offset() != session.offset {
+        // The client has missed a chunk, or moved on to a new connection, so scan the snapshot
+        // again up to the offset, where the same entries are as before.
+        session.cursor = session.snapshot.scan_prefix(&session.prefix)?;
+        session.offset = 0;
+        while session.offset < request.get_offset() {
+            match session.cursor.next() {
+                Some(entry) => {
+                    entry?;
+                    session.offset += 1;
+                }
+                None => break,
+            }
+        }
+    }
+
+    let limit = chunk_limit(request);
+    let mut entries = Vec::new();
+    let mut num_bytes = 0;
+    let mut is_last_chunk = false;
+    while (entries.len() as u64) < limit && num_bytes < exports.limits.max_chunk_bytes {
+        match session.cursor.next() {
+            Some(entry) => {
+                let (key, value) = entry?;
+                num_bytes += key.len() + value.len();
+                let mut entry = messages::Entry::new();
+                entry.set_key(key);
+                entry.set_value(value);
+                entries.push(entry);
+            }
+            None => {
+                is_last_chunk = true;
+                break;
+            }
+        }
+    }
+    response.set_export_id(export_id);
+    response.set_offset(session.offset);
+    response.set_is_last_chunk(is_last_chunk);
+    session.is_finished = is_last_chunk;
+    response.set_lease_ms(exports.limits.lease.as_millis() as u64);
+    session.offset += entries.len() as u64;
+    response.set_entries(entries.into());
+    // Keep even a finished export for a while, in case the last chunk gets lost.
+    exports.put_back(export_id, session)
+}
+
+/// Read the next chunk of the audit log starting from the requested offset.
+fn handle_audit_log(
+    audit_log: &AuditLog,
+    request: &messages::Request,
+    response: &mut messages::Response,
+) -> Result<()> {
+    let offset = request.get_offset() as usize;
+    let limit = chunk_limit(request) as usize;
+    // Read one more record to tell whether the chunk is the last one.
+    let mut records = audit_log.read(offset, limit + 1)?;
+    let is_last_chunk = records.len() <= limit;
+    records.truncate(limit);
+    response.set_offset(request.get_offset());
+    response.set_is_last_chunk(is_last_chunk);
+    response.set_audit_records(records.iter().map(|record| record.to_message()).collect());
+    Ok(())
+}
+
+/// The stats of the default namespace followed by those of the column families.
+fn all_namespace_stats(naive_kv: &NaiveKV) -> Result<Vec<messages::NamespaceStats>> {
+    let mut namespace_stats = vec![(DEFAULT_NAMESPACE.to_owned(), naive_kv.stats()?)];
+    for name in naive_kv.column_family_names()? {
+        let stats = naive_kv.catalog_viewer_for(&name)?.stats()?;
+        namespace_stats.push((name, stats));
+    }
+    Ok(namespace_stats
+        .into_iter()
+        .map(|(namespace, stats)| {
+            let mut message = messages::NamespaceStats::new();
+            message.set_namespace(namespace);
+            message.set_stats(stats.to_message());
+            message
+        })
+        .collect())
+}
+
+fn describe_job(status: JobStatus) -> messages::JobDescription {
+    let mut job = messages::JobDescription::new();
+    job.set_name(status.name);
+    job.set_interval_ms(status.schedule.interval.as_millis() as u64);
+    job.set_jitter_ms(status.schedule.jitter.as_millis() as u64);
+    job.set_is_paused(status.is_paused);
+    job.set_is_running(status.is_running);
+    job.set_num_runs(status.num_runs);
+    job.set_num_failures(status.num_failures);
+    job.set_num_consecutive_failures(status.num_consecutive_failures);
+    job.set_num_skipped(status.num_skipped);
+    if let Some(last_duration) = status.last_duration {
+        job.set_last_duration_ms(last_duration.as_millis() as u64);
+    }
+    if let Some(last_error) = status.last_error {
+        job.set_last_error(last_error);
+    }
+    job.set_next_run_in_ms(status.next_run_in.as_millis() as u64);
+    job
+}
+
+/// The most entries in the next chunk of an export or the audit log.
+fn chunk_limit(request: &messages::Request) -> u64 {
+    match request.get_limit() {
+        0 => DEFAULT_CHUNK_SIZE,
+        limit => limit.min(MAX_CHUNK_SIZE),
+    }
+}
+
+/// Record an administrative action of the client in the audit log, and refuse to take it if it
+/// cannot be recorded.
+fn audit(
+    audit_log: &AuditLog,
+    client_address: &SocketAddr,
+    action: &str,
+    parameters: Vec<(String, String)>,
+    response: &mut messages::Response,
+) -> bool {
+    match audit_log.record(&client_address.to_string(), action, parameters) {
+        Ok(_) => true,
+        Err(error) => {
+            log::error!("Failed to record {} in the audit log: {:?}", action, error);
+            response.set_status(messages::Status::INTERNAL_ERROR);
+            response.set_error(format!("{:?}", error));
+            false
+        }
+    }
+}
+
+/// The least role allowed to take the operation, or None for a batch, whose sub-requests are
+/// authorized one by one.
+fn required_role(operation: messages::Operation) -> Option<Role> {
+    match operation {
+        messages::Operation::GET
+        | messages::Operation::EXPORT
+        | messages::Operation::SERVER_TIME
+        | messages::Operation::WATCH => Some(Role::ReadOnly),
+        messages::Operation::SET
+        | messages::Operation::REMOVE
+        | messages::Operation::MDEL
+        | messages::Operation::INCREMENT
+        | messages::Operation::SET_NX
+        | messages::Operation::SET_XX
+        | messages::Operation::GET_SET
+        | messages::Operation::APPEND => Some(Role::ReadWrite),
+        messages::Operation::BATCH => None,
+        messages::Operation::DESCRIBE
+        | messages::Operation::PLAN_COMPACTION
+        | messages::Operation::AUDIT_LOG
+        | messages::Operation::LIST_JOBS
+        | messages::Operation::PAUSE_JOB
+        | messages::Operation::RESUME_JOB
+        | messages::Operation::STATS_ALL
+        | messages::Operation::GRANT_ROLE
+        | messages::Operation::REVOKE_ROLE
+        | messages::Operation::LIST_GRANTS
+        | messages::Operation::VERIFY
+        | messages::Operation::GET_PROPERTY => Some(Role::Admin),
+    }
+}
+
+/// Whether the operation acts on the engine as a whole rather than on the keys of a namespace.
+fn is_engine_wide(operation: messages::Operation) -> bool {
+    matches!(
+        operation,
+        messages::Operation::PAUSE_JOB
+            | messages::Operation::RESUME_JOB
+            | messages::Operation::LIST_JOBS
+            | messages::Operation::STATS_ALL
+            | messages::Operation::AUDIT_LOG
+            | messages::Operation::LIST_GRANTS
+    )
+}
+
+/// Check the role of the client in the namespace against the request, and refuse to take it if
+/// the role does not allow it. The requests on the engine as a whole are checked against the role
+/// in the default namespace instead, so that the admins of a namespace cannot reach the others.
+fn authorize(
+    client_address: &SocketAddr,
+    acl: &Acl,
+    request: &messages::Request,
+    namespace: &str,
+    response: &mut messages::Response,
+) -> bool {
+    let required_role = match required_role(request.get_operation()) {
+        Some(required_role) => required_role,
+        None => return true,
+    };
+    let namespace = if is_engine_wide(request.get_operation()) {
+        DEFAULT_NAMESPACE
+    } else {
+        namespace
+    };
+    match acl.role(namespace, &client_address.ip().to_string()) {
+        Ok(role) if role >= required_role => true,
+        Ok(role) => {
+            log::warn!(
+                "Denied {:?} to client {} with role {}.",
+                request.get_operation(),
+                client_address,
+                role
+            );
+            response.set_status(messages::Status::PERMISSION_DENIED);
+            response.set_error(format!(
+                "{:?} requires role {} but the client has role {}.",
+                request.get_operation(),
+                required_role,
+                role
+            ));
+            false
+        }
+        Err(error) => {
+            response.set_status(messages::Status::INTERNAL_ERROR);
+            response.set_error(format!("{:?}", error));
+            false
+        }
+    }
+}
+
+fn serve_client(
+    mut catalog_viewers: CatalogViewers,
+    server_state: &ServerState,
+    stream: TcpStream,
+    max_frame_size: usize,
+    max_queued_bytes: usize,
+    idle_timeout: Duration,
+) -> Result<()> {
+    let client_address = stream.peer_addr()?;
+    info!("Start serving client {}.", client_address);
+    let mut response_queue = ResponseQueue::new(stream.try_clone()?);
+    // When the client last sent a request or took some responses.
+    let mut last_active_time = Instant::now();
+    loop {
+        // Keep sending the queued responses while waiting for the next request, so that a client
+        // pipelining requests is served without reading its responses first.
+        let num_queued_bytes = response_queue.len();
+        response_queue.flush()?;
+        if response_queue.len() < num_queued_bytes {
+            last_active_time = Instant::now();
+        }
+        let poll_timeout = if response_queue.is_empty() {
+            idle_timeout.saturating_sub(last_active_time.elapsed())
+        } else {
+            Duration::from_millis(WRITE_POLL_INTERVAL_MS)
+        };
+        if !poll_request(&stream, poll_timeout)? {
+            if last_active_time.elapsed() >= idle_timeout {
+                log::warn!(
+                    "Disconnected client {} for idling over {:?}.",
+                    client_address,
+                    idle_timeout
+                );
+                return Ok(());
+            }
+            continue;
+        }
+        last_active_time = Instant::now();
+
+        let mut response = messages::Response::new();
+        match read_request(&stream, max_frame_size, idle_timeout) {
+            Ok(Some(request)) if request.get_operation() == messages::Operation::WATCH => {
+                response.set_id(request.get_id());
+                if authorize(
+                    &client_address,
+                    &server_state.acl,
+                    &request,
+                    request.get_namespace(),
+                    &mut response,
+                ) {
+                    // The responses queued go out before the connection turns into the stream.
+                    response_queue.finish()?;
+                    return serve_watch(
+                        &mut catalog_viewers,
+                        &server_state.naive_kv,
+                        stream,
+                        &client_address,
+                        &request,
+                    );
+                }
+            }
+            Ok(Some(request)) => {
+                // The deadline counts from when the request is received.
+                let deadline = request
+                    .has_timeout_ms()
+                    .then(|| Instant::now() + Duration::from_millis(request.get_timeout_ms()));
+                let served = server_state.batch_lane.run(&request, || {
+                    handle_request(
+                        &client_address,
+                        &mut catalog_viewers,
+                        server_state,
+                        &request,
+                        request.get_namespace(),
+                        deadline,
+                        &mut response,
+                    )
+                });
+                if served.is_none() {
+                    response.set_id(request.get_id());
+                    response.set_status(messages::Status::SERVER_BUSY);
+                    response.set_error(format!(
+                        "Already serving {} requests of batch priority.",
+                        server_state.batch_lane.max_running
+                    ));
+                }
+            }
+            Ok(None) => {
+                break;
+            }
+            Err(NaiveError::FrameTooLarge { length, max_length }) => {
+                // The frame has been skipped, so the session can go on with the next request.
+                log::error!(
+                    "Skipped a request of {} bytes from client {}.",
+                    length,
+                    client_address
+                );
+                response.set_status(messages::Status::FRAME_TOO_LARGE);
+                response.set_error(format!(
+                    "The request of {} bytes exceeds the limit of {} bytes.",
+                    length, max_length
+                ));
+            }
+            Err(NaiveError::IoError(error))
+                if matches!(error.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) =>
+            {
+                log::warn!(
+                    "Disconnected client {} for not finishing a request within {:?}.",
+                    client_address,
+                    idle_timeout
+                );
+                return Ok(());
+            }
+            Err(error) => {
+                log::error!("Failed to receive or deserialize request: {:?}", error);
+                response.set_status(messages::Status::OPERATION_NOT_SUPPORTED);
+            }
+        }
+        response_queue.push(&response)?;
+        if response_queue.len() > max_queued_bytes {
+            log::error!(
+                "Disconnected client {} for leaving {} bytes of responses unread.",
+                client_address,
+                response_queue.len()
+            );
+            return Ok(());
+        }
+    }
+    response_queue.finish()?;
+    info!("End serving client {}.", client_address);
+    Ok(())
+}
+
+/// Stream the keys written with the prefix of a WATCH request until the client disconnects, which
+/// holds on to the worker serving the connection like any other session.
+fn serve_watch(
+    catalog_viewers: &mut CatalogViewers,
+    naive_kv: &NaiveKV,
+    mut stream: TcpStream,
+    client_address: &SocketAddr,
+    request: &messages::Request,
+) -> Result<()> {
+    let prefix = request.get_key();
+    info!(
+        "CLIENT={} REQUEST_ID={} WATCH {}",
+        client_address,
+        request.get_id(),
+        prefix
+    );
+    stream.set_write_timeout(Some(Duration::from_millis(WATCH_WRITE_TIMEOUT_MS)))?;
+    let mut response = messages::Response::new();
+    response.set_id(request.get_id());
+    let events = match catalog_viewers
+        .get(naive_kv, request.get_namespace())
+        .and_then(|catalog_viewer| catalog_viewer.watch(prefix))
+    {
+        Ok(events) => events,
+        Err(error) => {
+            response.set_status(match error {
+                NaiveError::ColumnFamilyNotFound(_) => messages::Status::NAMESPACE_NOT_FOUND,
+                _ => messages::Status::INTERNAL_ERROR,
+            });
+            response.set_error(format!("{:?}", error));
+            utils::write_message(&response, &mut stream)?;
+            return Err(error);
+        }
+    };
+    utils::write_message(&response, &mut stream)?;
+    loop {
+        let mut response = messages::Response::new();
+        response.set_id(request.get_id());
+        match events.recv_timeout(Duration::from_millis(WATCH_HEARTBEAT_INTERVAL_MS)) {
+            Ok(event) => {
+                let entries = std::iter::once(event)
+                    .chain(events.try_iter())
+                    .map(|event| {
+                        let mut entry = messages::Entry::new();
+                        entry.set_key(event.key);
+                        entry
+                    })
+                    .collect::<Vec<_>>();
+                response.set_entries(entries.into());
+            }
+            Err(crossbeam::channel::RecvTimeoutError::Timeout) => {}
+            Err(crossbeam::channel::RecvTimeoutError::Disconnected) => {
+                log::warn!(
+                    "Ended the watch of client {} for falling behind the writes.",
+                    client_address
+                );
+                response.set_status(messages::Status::SERVER_BUSY);
+                response.set_error("The watch has fallen behind the writes.".to_owned());
+                utils::write_message(&response, &mut stream)?;
+                return Ok(());
+            }
+        }
+        utils::write_message(&response, &mut stream)?;
+    }
+}
+
+/// Serve the request in the namespace, which is that of the batch for a sub-request.
+fn handle_request(
+    client_address: &SocketAddr,
+    catalog_viewers: &mut CatalogViewers,
+    server_state: &ServerState,
+    request: &messages::Request,
+    namespace: &str,
+    deadline: Option<Instant>,
+    response: &mut messages::Response,
+) {
+    let audit_log = &server_state.audit_log;
+    let key = request.get_key();
+    response.set_status(messages::Status::OK);
+    response.set_id(request.get_id());
+    if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+        response.set_status(messages::Status::DEADLINE_EXCEEDED);
+        return;
+    }
+    if !authorize(
+        client_address,
+        &server_state.acl,
+        request,
+        namespace,
+        response,
+    ) {
+        return;
+    }
+    let catalog_viewer = match catalog_viewers.get(&server_state.naive_kv, namespace) {
+        Ok(catalog_viewer) => catalog_viewer,
+        Err(NaiveError::ColumnFamilyNotFound(_)) => {
+            response.set_status(messages::Status::NAMESPACE_NOT_FOUND);
+            response.set_error(format!("No column family is named {}.", namespace));
+            return;
+        }
+        Err(error) => {
+            response.set_status(messages::Status::INTERNAL_ERROR);
+            response.set_error(format!("{:?}", error));
+            return;
+        }
+    };
+    match request.get_operation() {
+        messages::Operation::GET => {
+            info!(
+                "CLIENT={} REQUEST_ID={} GET {}",
+                client_address,
+                request.get_id(),
+                key
+            );
+            match catalog_viewer.get_with_timestamp(key, deadline) {
+                Ok(Some((value, timestamp_ms))) => {
+                    response.set_value(value);
+                    if let Some(timestamp_ms) = timestamp_ms {
+                        response.set_timestamp_ms(timestamp_ms);
+                    }
+                }
+                Ok(None) => {
+                    response.set_status(messages::Status::KEY_NOT_FOUND);
+                }
+                Err(NaiveError::DeadlineExceeded) => {
+                    response.set_status(messages::Status::DEADLINE_EXCEEDED);
+                }
+                Err(NaiveError::Quarantined { file_path }) => {
+                    response.set_status(messages::Status::QUARANTINED);
+                    response.set_error(file_path.display().to_string());
+                }
+                Err(error) => {
+                    response.set_status(messages::Status::INTERNAL_ERROR);
+                    response.set_error(format!("{:?}", error));
+                }
+            }
+        }
+        messages::Operation::SET => {
+            if !request.has_value() {
+                response.set_status(messages::Status::VALUE_MISSING);
+                return;
+            }
+            let value = request.get_value();
+            info!(
+                "CLIENT={} REQUEST_ID={} SET {} {}",
+                client_address,
+                request.get_id(),
+                key,
+                value
+            );
+            if catalog_viewer
+                .set(key.to_string(), value.to_string())
+                .is_err()
+            {
+                response.set_status(messages::Status::INTERNAL_ERROR);
+            }
+        }
+        messages::Operation::SET_NX | messages::Operation::SET_XX => {
+            if !request.has_value() {
+                response.set_status(messages::Status::VALUE_MISSING);
+                return;
+            }
+            let value = request.get_value();
+            let operation = request.get_operation();
+            info!(
+                "CLIENT={} REQUEST_ID={} {:?} {} {}",
+                client_address,
+                request.get_id(),
+                operation,
+                key,
+                value
+            );
+            let result = if operation == messages::Operation::SET_NX {
+                catalog_viewer.set_nx(key.to_string(), value.to_string())
+            } else {
+                catalog_viewer.set_xx(key.to_string(), value.to_string())
+            };
+            match result {
+                Ok(Some(_)) => (),
+                Ok(None) => response.set_status(messages::Status::CONDITION_NOT_MET),
+                Err(error) => {
+                    response.set_status(messages::Status::INTERNAL_ERROR);
+                    response.set_error(format!("{:?}", error));
+                }
+            }
+        }
+        messages::Operation::GET_SET | messages::Operation::APPEND => {
+            if !request.has_value() {
+                response.set_status(messages::Status::VALUE_MISSING);
+                return;
+            }
+            let value = request.get_value();
+            let operation = request.get_operation();
+            info!(
+                "CLIENT={} REQUEST_ID={} {:?} {} {}",
+                client_address,
+                request.get_id(),
+                operation,
+                key,
+                value
+            );
+            let result = if operation == messages::Operation::GET_SET {
+                catalog_viewer.get_set(key.to_string(), value.to_string())
+            } else {
+                catalog_viewer
+                    .append(key.to_string(), value)
+                    .map(|new_len| Some(new_len.to_string()))
+            };
+            match result {
+                Ok(Some(value)) => response.set_value(value),
+                Ok(None) => (),
+                Err(error) => {
+                    response.set_status(messages::Status::INTERNAL_ERROR);
+                    response.set_error(format!("{:?}", error));
+                }
+            }
+        }
+        messages::Operation::REMOVE => {
+            info!(
+                "CLIENT={} REQUEST_ID={} REMOVE {}",
+                client_address,
+                request.get_id(),
+                key
+            );
+            if catalog_viewer.remove(key.to_string()).is_err() {
+                response.set_status(messages::Status::INTERNAL_ERROR);
+            }
+        }
+        messages::Operation::INCREMENT => {
+            let delta = request.get_delta();
+            info!(
+                "CLIENT={} REQUEST_ID={} INCREMENT {} {}",
+                client_address,
+                request.get_id(),
+                key,
+                delta
+            );
+            match catalog_viewer.increment(key.to_string(), delta) {
+                Ok(value) => response.set_value(value.to_string()),
+                Err(NaiveError::InvalidValue(message)) => {
+                    response.set_status(messages::Status::INVALID_VALUE);
+                    response.set_error(message);
+                }
+                Err(error) => {
+                    response.set_status(messages::Status::INTERNAL_ERROR);
+                    response.set_error(format!("{:?}", error));
+                }
+            }
+        }
+        messages::Operation::MDEL => {
+            let keys = request.get_keys();
+            info!(
+                "CLIENT={} REQUEST_ID={} MDEL ({} keys)",
+                client_address,
+                request.get_id(),
+                keys.len()
+            );
+            match catalog_viewer.remove_all(keys, deadline) {
+                Ok(were_present) => {
+                    let statuses = were_present
+                        .into_iter()
+                        .map(|was_present| match was_present {
+                            true => messages::Status::OK,
+                            false => messages::Status::KEY_NOT_FOUND,
+                        })
+                        .collect();
+                    response.set_statuses(statuses);
+                }
+                Err(NaiveError::DeadlineExceeded) => {
+                    // Nothing has been removed.
+                    response.set_status(messages::Status::DEADLINE_EXCEEDED);
+                }
+                Err(error) => {
+                    response.set_status(messages::Status::INTERNAL_ERROR);
+                    response.set_error(format!("{:?}", error));
+                    response.set_statuses(vec![messages::Status::INTERNAL_ERROR; keys.len()]);
+                }
+            }
+        }
+        messages::Operation::DESCRIBE => {
+            info!(
+                "CLIENT={} REQUEST_ID={} DESCRIBE",
+                client_address,
+                request.get_id()
+            );
+            if !audit(audit_log, client_address, "DESCRIBE", Vec::new(), response) {
+                return;
+            }
+            match catalog_viewer.describe() {
+                Ok(descriptions) => {
+                    let sstables = descriptions
+                        .into_iter()
+                        .map(|description| {
+                            let mut sstable = messages::SSTableDescription::new();
+                            sstable.set_file_path(description.file_path.display().to_string());
+                            sstable.set_gen_no(description.gen_no as u64);
+                            sstable.set_epoch_no(description.epoch_no);
+                            sstable.set_file_size(description.file_size as u64);
+                            if let Some((min_key, max_key)) = description.key_range {
+                                sstable.set_min_key(min_key);
+                                sstable.set_max_key(max_key);
+                            }
+                            sstable.set_num_entries(description.num_entries as u64);
+                            sstable.set_is_quarantined(description.is_quarantined);
+                            sstable.set_properties(description.properties.to_message());
+                            sstable
+                        })
+                        .collect();
+                    response.set_sstables(sstables);
+                }
+                Err(error) => {
+                    response.set_status(messages::Status::INTERNAL_ERROR);
+                    response.set_error(format!("{:?}", error));
+                }
+            }
+        }
+        messages::Operation::PLAN_COMPACTION => {
+            info!(
+                "CLIENT={} REQUEST_ID={} PLAN_COMPACTION",
+                client_address,
+                request.get_id()
+            );
+            if !audit(
+                audit_log,
+                client_address,
+                "PLAN_COMPACTION",
+                Vec::new(),
+                response,
+            ) {
+                return;
+            }
+            match catalog_viewer.plan_compaction() {
+                Ok(plans) => {
+                    let plans = plans
+                        .into_iter()
+                        .map(|plan| {
+                            let mut compaction_plan = messages::CompactionPlan::new();
+                            compaction_plan.set_kind(match plan.kind {
+                                CompactionKind::Flush => messages::CompactionKind::FLUSH,
+                                CompactionKind::Merge => messages::CompactionKind::MERGE,
+                                CompactionKind::MergeOldest => {
+                                    messages::CompactionKind::MERGE_OLDEST
+                                }
+                                CompactionKind::MinorMerge => messages::CompactionKind::MINOR_MERGE,
+                            });
+                            compaction_plan.set_input_paths(
+                                plan.inputs
+                                    .iter()
+                                    .map(|input| input.file_path.display().to_string())
+                                    .collect(),
+                            );
+                            compaction_plan.set_output_gen_no(plan.output_gen_no as u64);
+                            compaction_plan
+                                .set_estimated_output_size(plan.estimated_output_size as u64);
+                            if let Some(estimated_duration) = plan.estimated_duration {
+                                compaction_plan.set_estimated_duration_ms(
+                                    estimated_duration.as_millis() as u64,
+                                );
+                            }
+                            compaction_plan
+                        })
+                        .collect();
+                    response.set_compaction_plans(plans);
+                }
+                Err(error) => {
+                    response.set_status(messages::Status::INTERNAL_ERROR);
+                    response.set_error(format!("{:?}", error));
+                }
+            }
+        }
+        messages::Operation::BATCH => {
+            let requests = request.get_requests();
+            info!(
+                "CLIENT={} REQUEST_ID={} BATCH ({} requests)",
+                client_address,
+                request.get_id(),
+                requests.len()
+            );
+            let mut responses = Vec::with_capacity(requests.len());
+            for sub_request in requests.iter() {
+                let mut sub_response = messages::Response::new();
+                if sub_request.get_operation() == messages::Operation::BATCH {
+                    sub_response.set_id(sub_request.get_id());
+                    sub_response.set_status(messages::Status::OPERATION_NOT_SUPPORTED);
+                } else {
+                    // The sub-requests share the namespace and the deadline of the batch.
+                    handle_request(
+                        client_address,
+                        catalog_viewers,
+                        server_state,
+                        sub_request,
+                        namespace,
+                        deadline,
+                        &mut sub_response,
+                    );
+                }
+                responses.push(sub_response);
+            }
+            response.set_responses(responses.into());
+        }
+        messages::Operation::EXPORT => {
+            info!(
+                "CLIENT={} REQUEST_ID={} EXPORT {} FROM {}",
+                client_address,
+                request.get_id(),
+                key,
+                request.get_offset()
+            );
+            // Only the start of an export is recorded, not every chunk.
+            if !request.has_export_id()
+                && !audit(
+                    audit_log,
+                    client_address,
+                    "EXPORT",
+                    vec![("prefix".to_owned(), key.to_owned())],
+                    response,
+                )
+            {
+                return;
+            }
+            if let Err(error) = handle_export(
+                client_address,
+                catalog_viewer,
+                &server_state.exports,
+                request,
+                response,
+            ) {
+                response.set_status(messages::Status::INTERNAL_ERROR);
+                response.set_error(format!("{:?}", error));
+            }
+        }
+        messages::Operation::AUDIT_LOG => {
+            info!(
+                "CLIENT={} REQUEST_ID={} AUDIT_LOG FROM {}",
+                client_address,
+                request.get_id(),
+                request.get_offset()
+            );
+            // Only the first chunk of a read is recorded, like an export.
+            if request.get_offset() == 0
+                && !audit(audit_log, client_address, "AUDIT_LOG", Vec::new(), response)
+            {
+                return;
+            }
+            if let Err(error) = handle_audit_log(audit_log, request, response) {
+                response.set_status(messages::Status::INTERNAL_ERROR);
+                response.set_error(format!("{:?}", error));
+            }
+        }
+        messages::Operation::SERVER_TIME => {
+            info!(
+                "CLIENT={} REQUEST_ID={} SERVER_TIME",
+                client_address,
+                request.get_id()
+            );
+            match server_state.naive_kv.visible_sequence_no() {
+                Ok(sequence_no) => {
+                    response.set_sequence_no(sequence_no);
+                    response.set_server_time_ms(
+                        SystemTime::now()
+                            .duration_since(SystemTime::UNIX_EPOCH)
+                            .map_or(0, |duration| duration.as_millis() as u64),
+                    );
+                }
+                Err(error) => {
+                    response.set_status(messages::Status::INTERNAL_ERROR);
+                    response.set_error(format!("{:?}", error));
+                }
+            }
+        }
+        messages::Operation::VERIFY => {
+            info!(
+                "CLIENT={} REQUEST_ID={} VERIFY",
+                client_address,
+                request.get_id()
+            );
+            if !audit(audit_log, client_address, "VERIFY", Vec::new(), response) {
+                return;
+            }
+            match catalog_viewer
+                .snapshot()
+                .and_then(|snapshot| snapshot.verify())
+            {
+                Ok(report) => {
+                    response.set_consistency_report(report.to_message());
+                }
+                Err(error) => {
+                    response.set_status(messages::Status::INTERNAL_ERROR);
+                    response.set_error(format!("{:?}", error));
+                }
+            }
+        }
+        messages::Operation::WATCH => {
+            // Only taken as the request of a connection, not as a sub-request of a batch.
+            response.set_status(messages::Status::OPERATION_NOT_SUPPORTED);
+            response.set_error("A watch takes a connection of its own.".to_owned());
+        }
+        messages::Operation::GET_PROPERTY => {
+            // Polled by monitoring like the stats, so not audited either.
+            info!(
+                "CLIENT={} REQUEST_ID={} GET_PROPERTY {}",
+                client_address,
+                request.get_id(),
+                key
+            );
+            match catalog_viewer.property(key) {
+                Ok(Some(value)) => {
+                    response.set_value(value);
+                }
+                Ok(None) => {
+                    response.set_status(messages::Status::KEY_NOT_FOUND);
+                    response.set_error(format!("No property is named {}.", key));
+                }
+                Err(error) => {
+                    response.set_status(messages::Status::INTERNAL_ERROR);
+                    response.set_error(format!("{:?}", error));
+                }
+            }
+        }
+        messages::Operation::STATS_ALL => {
+            // Unlike the other admin ops, the stats are polled by monitoring, which would flood
+            // the audit log.
+            info!(
+                "CLIENT={} REQUEST_ID={} STATS_ALL",
+                client_address,
+                request.get_id()
+            );
+            match all_namespace_stats(&server_state.naive_kv) {
+                Ok(namespace_stats) => {
+                    response.set_namespace_stats(namespace_stats.into());
+                    if let Some(shadow) = server_state.shadow.as_ref() {
+                        response.set_shadow_stats(shadow.stats());
+                    }
+                }
+                Err(error) => {
+                    response.set_status(messages::Status::INTERNAL_ERROR);
+                    response.set_error(format!("{:?}", error));
+                }
+            }
+        }
+        messages::Operation::LIST_JOBS => {
+            info!(
+                "CLIENT={} REQUEST_ID={} LIST_JOBS",
+                client_address,
+                request.get_id()
+            );
+            if !audit(audit_log, client_address, "LIST_JOBS", Vec::new(), response) {
+                return;
+            }
+            match server_state.naive_kv.jobs() {
+                Ok(jobs) => {
+                    response.set_jobs(jobs.into_iter().map(describe_job).collect());
+                }
+                Err(error) => {
+                    response.set_status(messages::Status::INTERNAL_ERROR);
+                    response.set_error(format!("{:?}", error));
+                }
+            }
+        }
+        messages::Operation::PAUSE_JOB | messages::Operation::RESUME_JOB => {
+            let operation = request.get_operation();
+            info!(
+                "CLIENT={} REQUEST_ID={} {:?} {}",
+                client_address,
+                request.get_id(),
+                operation,
+                key
+            );
+            if !audit(
+                audit_log,
+                client_address,
+                &format!("{:?}", operation),
+                vec![("job".to_owned(), key.to_owned())],
+                response,
+            ) {
+                return;
+            }
+            let result = if operation == messages::Operation::PAUSE_JOB {
+                server_state.naive_kv.pause_job(key)
+            } else {
+                server_state.naive_kv.resume_job(key)
+            };
+            match result {
+                Ok(status) => {
+                    response.set_jobs(vec![describe_job(status)].into());
+                }
+                Err(NaiveError::JobNotFound(_)) => {
+                    response.set_status(messages::Status::JOB_NOT_FOUND);
+                }
+                Err(error) => {
+                    response.set_status(messages::Status::INTERNAL_ERROR);
+                    response.set_error(format!("{:?}", error));
+                }
+            }
+        }
+        messages::Operation::GRANT_ROLE => {
+            let role = Role::from_message(request.get_role());
+            info!(
+                "CLIENT={} REQUEST_ID={} GRANT_ROLE {} {}",
+                client_address,
+                request.get_id(),
+                key,
+                role
+            );
+            if !audit(
+                audit_log,
+                client_address,
+                "GRANT_ROLE",
+                vec![
+                    ("namespace".to_owned(), namespace.to_owned()),
+                    ("principal".to_owned(), key.to_owned()),
+                    ("role".to_owned(), role.to_string()),
+                ],
+                response,
+            ) {
+                return;
+            }
+            let grant = Grant {
+                namespace: namespace.to_owned(),
+                principal: key.to_owned(),
+                role,
+            };
+            let message = grant.to_message();
+            match server_state.acl.grant(grant) {
+                Ok(()) => {
+                    response.set_grants(vec![message].into());
+                }
+                Err(error) => {
+                    response.set_status(messages::Status::INTERNAL_ERROR);
+                    response.set_error(format!("{:?}", error));
+                }
+            }
+        }
+        messages::Operation::REVOKE_ROLE => {
+            info!(
+                "CLIENT={} REQUEST_ID={} REVOKE_ROLE {}",
+                client_address,
+                request.get_id(),
+                key
+            );
+            if !audit(
+                audit_log,
+                client_address,
+                "REVOKE_ROLE",
+                vec![
+                    ("namespace".to_owned(), namespace.to_owned()),
+                    ("principal".to_owned(), key.to_owned()),
+                ],
+                response,
+            ) {
+                return;
+            }
+            match server_state.acl.revoke(namespace, key) {
+                Ok(Some(role)) => {
+                    let grant = Grant {
+                        namespace: namespace.to_owned(),
+                        principal: key.to_owned(),
+                        role,
+                    };
+                    response.set_grants(vec![grant.to_message()].into());
+                }
+                Ok(None) => {
+                    response.set_status(messages::Status::KEY_NOT_FOUND);
+                }
+                Err(error) => {
+                    response.set_status(messages::Status::INTERNAL_ERROR);
+                    response.set_error(format!("{:?}", error));
+                }
+            }
+        }
+        messages::Operation::LIST_GRANTS => {
+            info!(
+                "CLIENT={} REQUEST_ID={} LIST_GRANTS",
+                client_address,
+                request.get_id()
+            );
+            if !audit(
+                audit_log,
+                client_address,
+                "LIST_GRANTS",
+                Vec::new(),
+                response,
+            ) {
+                return;
+            }
+            match server_state.acl.grants() {
+                Ok(grants) => {
+                    response.set_grants(grants.iter().map(Grant::to_message).collect());
+                }
+                Err(error) => {
+                    response.set_status(messages::Status::INTERNAL_ERROR);
+                    response.set_error(format!("{:?}", error));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use naive_kv::options::Options;
+
+    #[test]
+    fn test_batch_lane() {
+        let batch_lane = BatchLane::new(1);
+        let mut batch_request = messages::Request::new();
+        batch_request.set_priority(messages::Priority::BATCH);
+        let interactive_request = messages::Request::new();
+
+        // A batch request takes the lane until it is done serving.
+        let (started_sender, started_receiver) = crossbeam::channel::bounded(0);
+        let (done_sender, done_receiver) = crossbeam::channel::bounded::<()>(0);
+        std::thread::scope(|scope| {
+            let running = scope.spawn(|| {
+                batch_lane.run(&batch_request, || {
+                    started_sender.send(()).unwrap();
+                    done_receiver.recv().unwrap();
+                })
+            });
+            started_receiver.recv().unwrap();
+
+            // Another batch request is turned down without blocking, unlike an interactive one.
+            assert_eq!(batch_lane.run(&batch_request, || ()), None);
+            assert_eq!(batch_lane.run(&interactive_request, || 1), Some(1));
+
+            done_sender.send(()).unwrap();
+            assert_eq!(running.join().unwrap(), Some(()));
+        });
+        assert_eq!(batch_lane.run(&batch_request, || 2), Some(2));
+    }
+
+    #[test]
+    fn test_read_request() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        let timeout = Duration::from_millis(100);
+
+        // A whole frame is read as usual.
+        let mut request = messages::Request::new();
+        request.set_key("key".to_owned());
+        utils::write_message(&request, &mut client).unwrap();
+        let received = read_request(&stream, usize::MAX, timeout).unwrap().unwrap();
+        assert_eq!(received.get_key(), "key");
+
+        // A frame cut short times out instead of blocking forever.
+        let mut frame = Vec::new();
+        utils::write_message(&request, &mut frame).unwrap();
+        client.write_all(&frame[..frame.len() - 1]).unwrap();
+        let start_time = Instant::now();
+        match read_request(&stream, usize::MAX, timeout) {
+            Err(NaiveError::IoError(error)) => assert!(matches!(
+                error.kind(),
+                ErrorKind::WouldBlock | ErrorKind::TimedOut
+            )),
+            result => panic!("Unexpected result of read_request: {:?}", result.err()),
+        }
+        assert!(start_time.elapsed() >= timeout);
+    }
+
+    #[test]
+    fn test_authorize() {
+        let client_address = "127.0.0.1:1234".parse::<SocketAddr>().unwrap();
+        let acl = Acl::new(
+            Role::ReadOnly,
+            [Grant {
+                namespace: "meta".to_owned(),
+                principal: client_address.ip().to_string(),
+                role: Role::Admin,
+            }],
+        );
+        let is_authorized = |operation: messages::Operation, namespace: &str| {
+            let mut request = messages::Request::new();
+            request.set_operation(operation);
+            request.set_namespace(namespace.to_owned());
+            let mut response = messages::Response::new();
+            let is_authorized =
+                authorize(&client_address, &acl, &request, namespace, &mut response);
+            assert_eq!(
+                is_authorized,
+                response.get_status() != messages::Status::PERMISSION_DENIED
+            );
+            is_authorized
+        };
+
+        // The admin of a namespace administers it, but not the engine as a whole.
+        assert!(is_authorized(messages::Operation::SET, "meta"));
+        assert!(is_authorized(messages::Operation::GET_PROPERTY, "meta"));
+        assert!(is_authorized(messages::Operation::GRANT_ROLE, "meta"));
+        assert!(!is_authorized(messages::Operation::SET, DEFAULT_NAMESPACE));
+        for operation in [
+            messages::Operation::PAUSE_JOB,
+            messages::Operation::RESUME_JOB,
+            messages::Operation::LIST_JOBS,
+            messages::Operation::STATS_ALL,
+            messages::Operation::AUDIT_LOG,
+            messages::Operation::LIST_GRANTS,
+        ] {
+            assert!(!is_authorized(operation, "meta"));
+        }
+
+        // The admin of the default namespace does.
+        acl.grant(Grant {
+            namespace: DEFAULT_NAMESPACE.to_owned(),
+            principal: client_address.ip().to_string(),
+            role: Role::Admin,
+        })
+        .unwrap();
+        assert!(is_authorized(messages::Operation::PAUSE_JOB, "meta"));
+        assert!(is_authorized(
+            messages::Operation::LIST_GRANTS,
+            DEFAULT_NAMESPACE
+        ));
+    }
+
+    #[test]
+    fn test_catalog_viewers() {
+        const FOLDER_PATH: &str = "/tmp/naive_kv/test_catalog_viewers/";
+
+        let _ = std::fs::remove_dir_all(FOLDER_PATH);
+        let naive_kv = NaiveKV::open(FOLDER_PATH, Options::default()).unwrap();
+        naive_kv.create_column_family("meta").unwrap();
+        let mut catalog_viewers = CatalogViewers::new(naive_kv.catalog_viewer().unwrap());
+
+        // Each namespace reads and writes the column family of its name.
+        catalog_viewers
+            .get(&naive_kv, DEFAULT_NAMESPACE)
+            .unwrap()
+            .set("key".to_owned(), "data".to_owned())
+            .unwrap();
+        catalog_viewers
+            .get(&naive_kv, "meta")
+            .unwrap()
+            .set("key".to_owned(), "meta".to_owned())
+            .unwrap();
+        assert_eq!(
+            naive_kv.catalog_viewer().unwrap().get("key").unwrap(),
+            Some("data".to_owned())
+        );
+        assert_eq!(
+            naive_kv
+                .catalog_viewer_for("meta")
+                .unwrap()
+                .get("key")
+                .unwrap(),
+            Some("meta".to_owned())
+        );
+        assert!(matches!(
+            catalog_viewers.get(&naive_kv, "missing"),
+            Err(NaiveError::ColumnFamilyNotFound(_))
+        ));
+
+        // The stats list the default namespace first.
+        let namespaces = all_namespace_stats(&naive_kv)
+            .unwrap()
+            .iter()
+            .map(|namespace_stats| namespace_stats.get_namespace().to_owned())
+            .collect::<Vec<_>>();
+        assert_eq!(namespaces, vec![DEFAULT_NAMESPACE, "meta"]);
+    }
+
+    #[test]
+    fn test_forwarded_requests() {
+        const FOLDER_PATH: &str = "/tmp/naive_kv/test_forwarded_requests/";
+        const NUM_THREADS: i64 = 4;
+        const NUM_INCREMENTS: i64 = 50;
+
+        let _ = std::fs::remove_dir_all(FOLDER_PATH);
+        let naive_kv = NaiveKV::open(FOLDER_PATH, Options::default()).unwrap();
+        naive_kv.create_column_family("meta").unwrap();
+        let events = watch_namespace(&naive_kv, DEFAULT_NAMESPACE).unwrap();
+        let meta_events = watch_namespace(&naive_kv, "meta").unwrap();
+        std::thread::scope(|scope| {
+            for _ in 0..NUM_THREADS {
+                let mut catalog_viewer = naive_kv.catalog_viewer().unwrap();
+                scope.spawn(move || {
+                    for _ in 0..NUM_INCREMENTS {
+                        catalog_viewer.increment("counter".to_owned(), 1).unwrap();
+                        catalog_viewer.append("log".to_owned(), "x").unwrap();
+                    }
+                });
+            }
+        });
+        naive_kv
+            .catalog_viewer()
+            .unwrap()
+            .remove("log".to_owned())
+            .unwrap();
+
+        // Each key is set to the values it has taken, in the order it has taken them.
+        let requests = events
+            .try_iter()
+            .filter_map(|event| forwarded_request(DEFAULT_NAMESPACE, &event))
+            .collect::<Vec<_>>();
+        let values = |key: &str| {
+            requests
+                .iter()
+                .filter(|request| request.get_key() == key)
+                .map(|request| (request.get_operation(), request.get_value().to_owned()))
+                .collect::<Vec<_>>()
+        };
+        let num_writes = NUM_THREADS * NUM_INCREMENTS;
+        assert_eq!(
+            values("counter"),
+            (1..=num_writes)
+                .map(|value| (messages::Operation::SET, value.to_string()))
+                .collect::<Vec<_>>()
+        );
+        let mut expected_log_values = (1..=num_writes as usize)
+            .map(|len| (messages::Operation::SET, "x".repeat(len)))
+            .collect::<Vec<_>>();
+        expected_log_values.push((messages::Operation::REMOVE, String::new()));
+        assert_eq!(values("log"), expected_log_values);
+        assert!(requests
+            .iter()
+            .all(|request| request.get_namespace() == DEFAULT_NAMESPACE));
+
+        // The writes to a column family are forwarded to its namespace.
+        naive_kv
+            .catalog_viewer_for("meta")
+            .unwrap()
+            .set("version".to_owned(), "1".to_owned())
+            .unwrap();
+        let meta_requests = meta_events
+            .try_iter()
+            .filter_map(|event| forwarded_request("meta", &event))
+            .collect::<Vec<_>>();
+        assert_eq!(meta_requests.len(), 1);
+        assert_eq!(meta_requests[0].get_namespace(), "meta");
+        assert_eq!(meta_requests[0].get_key(), "version");
+        assert!(events.try_iter().next().is_none());
     }
 }
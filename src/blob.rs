@@ -0,0 +1,162 @@
+use std::io::{BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+use rand::{thread_rng, Rng};
+
+use crate::backend::{Backend, BackendFile, PositionalReader};
+use crate::options::Options;
+use crate::protos::messages::BlobReference;
+use crate::types::{NaiveError, Result};
+
+const BLOB_FILE_PREFIX: &str = "blob_";
+const BLOB_FILE_SUFFIX: &str = ".blob";
+
+/// The folders to look for the blob files of the segment files in the folder. The blob files are
+/// written into the first one, which is the cold folder if configured, since the large values are
+/// read far less often than the keys, and the folder itself comes next for the copies of the data
+/// folder, which hold all the files together.
+pub(crate) fn blob_folder_paths(folder_path: &Path, options: &Options) -> Vec<PathBuf> {
+    let mut folder_paths = Vec::with_capacity(2);
+    folder_paths.extend(options.cold_folder_path.clone());
+    if folder_paths.first().map(PathBuf::as_path) != Some(folder_path) {
+        folder_paths.push(folder_path.to_path_buf());
+    }
+    folder_paths
+}
+
+pub(crate) fn blob_file_name(file_no: u64) -> String {
+    format!("{}{}{}", BLOB_FILE_PREFIX, file_no, BLOB_FILE_SUFFIX)
+}
+
+/// The number of the blob file at the path, or None if it is not a blob file.
+pub(crate) fn blob_file_no(file_path: &Path) -> Option<u64> {
+    file_path
+        .file_name()?
+        .to_str()?
+        .strip_prefix(BLOB_FILE_PREFIX)?
+        .strip_suffix(BLOB_FILE_SUFFIX)?
+        .parse()
+        .ok()
+}
+
+/// The path of the blob file in the first of the folders holding it, or in the first folder if
+/// none does, e.g. once a data folder with a cold folder is copied into a single folder.
+pub(crate) fn locate_blob_file(
+    backend: &dyn Backend,
+    folder_paths: &[PathBuf],
+    file_no: u64,
+) -> PathBuf {
+    let file_name = blob_file_name(file_no);
+    folder_paths
+        .iter()
+        .map(|folder_path| folder_path.join(&file_name))
+        .find(|file_path| backend.exists(file_path))
+        .unwrap_or_else(|| folder_paths[0].join(&file_name))
+}
+
+/// Read the value the reference points to out of the blob file at the path, verifying its
+/// checksum. The file is read by offset, so that the reads may share a handle on it.
+pub(crate) fn read_blob(
+    blob_file: &dyn BackendFile,
+    file_path: &Path,
+    blob: &BlobReference,
+) -> Result<String> {
+    let mut bytes = vec![0u8; blob.get_length() as usize];
+    PositionalReader::new(blob_file, blob.get_offset()).read_exact(&mut bytes)?;
+    if crc32fast::hash(&bytes) != blob.get_checksum() {
+        log::error!(
+            "Checksum mismatch in {} at offset {}.",
+            file_path.display(),
+            blob.get_offset()
+        );
+        return Err(NaiveError::Corruption {
+            file_path: file_path.to_owned(),
+            offset: blob.get_offset(),
+        });
+    }
+    String::from_utf8(bytes).map_err(|_| NaiveError::InvalidData)
+}
+
+/// Appends the values separated from a segment file to a new blob file, which is never changed
+/// once finished.
+pub(crate) struct BlobWriter {
+    file_no: u64,
+    file_writer: BufWriter<Box<dyn BackendFile>>,
+    offset: u64,
+}
+
+impl BlobWriter {
+    pub(crate) fn create(folder_path: &Path, backend: &dyn Backend) -> Result<Self> {
+        let file_no = thread_rng().gen::<u64>();
+        let file_path = folder_path.join(blob_file_name(file_no));
+        log::info!("Going to create blob file {}.", file_path.display());
+        Ok(Self {
+            file_no,
+            file_writer: BufWriter::new(backend.create_new(&file_path)?),
+            offset: 0,
+        })
+    }
+
+    /// Append the value and return the reference to it.
+    pub(crate) fn append(&mut self, value: &str) -> Result<BlobReference> {
+        let bytes = value.as_bytes();
+        self.file_writer.write_all(bytes)?;
+        let mut blob = BlobReference::new();
+        blob.set_file_no(self.file_no);
+        blob.set_offset(self.offset);
+        blob.set_length(bytes.len() as u64);
+        blob.set_checksum(crc32fast::hash(bytes));
+        self.offset += bytes.len() as u64;
+        Ok(blob)
+    }
+
+    /// Write out the values appended and sync the blob file, returning its number.
+    pub(crate) fn finish(self) -> Result<u64> {
+        self.file_writer.into_inner()?.sync()?;
+        Ok(self.file_no)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::LocalBackend;
+
+    #[test]
+    fn test_blob_file() {
+        const FOLDER_PATH: &str = "/tmp/naive_kv/test_blob_file/";
+
+        let _ = std::fs::remove_dir_all(FOLDER_PATH);
+        std::fs::create_dir_all(FOLDER_PATH).unwrap();
+        let folder_paths = [PathBuf::from("/tmp/naive_kv/missing/"), FOLDER_PATH.into()];
+        let mut blob_writer = BlobWriter::create(&folder_paths[1], &LocalBackend).unwrap();
+        let blobs = ["first", "", "third"]
+            .iter()
+            .map(|value| blob_writer.append(value).unwrap())
+            .collect::<Vec<_>>();
+        let file_no = blob_writer.finish().unwrap();
+
+        // The blob file is found in the folder holding it.
+        let file_path = locate_blob_file(&LocalBackend, &folder_paths, file_no);
+        assert_eq!(file_path, folder_paths[1].join(blob_file_name(file_no)));
+        assert_eq!(blob_file_no(&file_path), Some(file_no));
+        assert_eq!(blob_file_no(Path::new("gen_0_1.sst")), None);
+        let blob_file = LocalBackend.open(&file_path).unwrap();
+        for (blob, value) in blobs.iter().zip(["first", "", "third"]) {
+            assert_eq!(blob.get_file_no(), file_no);
+            assert_eq!(
+                read_blob(blob_file.as_ref(), &file_path, blob).unwrap(),
+                value
+            );
+        }
+
+        // A value changed in the blob file fails its checksum.
+        LocalBackend
+            .write_at(&file_path, blobs[2].get_offset(), b"T")
+            .unwrap();
+        assert!(matches!(
+            read_blob(blob_file.as_ref(), &file_path, &blobs[2]),
+            Err(NaiveError::Corruption { offset, .. }) if offset == blobs[2].get_offset()
+        ));
+    }
+}
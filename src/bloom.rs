@@ -0,0 +1,71 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+/// A Bloom filter over strings, which never gives false negatives.
+pub struct BloomFilter {
+    /// The bit array.
+    bits: Vec<u64>,
+
+    /// The number of bits probed for each string.
+    num_probes: usize,
+}
+
+impl BloomFilter {
+    pub fn new(num_keys: usize, bits_per_key: usize) -> Self {
+        let num_words = (num_keys * bits_per_key)
+            .div_ceil(u64::BITS as usize)
+            .max(1);
+        // About ln(2) * bits_per_key probes minimize the false positive rate.
+        let num_probes = (bits_per_key * 69 / 100).clamp(1, 30);
+        Self {
+            bits: vec![0; num_words],
+            num_probes,
+        }
+    }
+
+    pub fn insert(&mut self, key: &str) {
+        for bit in self.probes(key) {
+            self.bits[bit >> 6] |= 1 << (bit & 63);
+        }
+    }
+
+    /// Whether the string may have been inserted.
+    pub fn may_contain(&self, key: &str) -> bool {
+        self.probes(key)
+            .all(|bit| self.bits[bit >> 6] & (1 << (bit & 63)) != 0)
+    }
+
+    /// Derive the probed bits from a single hash with double hashing.
+    fn probes(&self, key: &str) -> impl Iterator<Item = usize> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        let hash = hasher.finish();
+        let delta = hash.rotate_left(32) | 1;
+        let num_bits = (self.bits.len() * u64::BITS as usize) as u64;
+        (0..self.num_probes as u64)
+            .map(move |i| (hash.wrapping_add(i.wrapping_mul(delta)) % num_bits) as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bloom_filter() {
+        const NUM_KEYS: usize = 10000;
+        const BITS_PER_KEY: usize = 10;
+
+        let mut bloom_filter = BloomFilter::new(NUM_KEYS, BITS_PER_KEY);
+        for num in 0..NUM_KEYS {
+            bloom_filter.insert(&format!("key{}", num));
+        }
+        for num in 0..NUM_KEYS {
+            assert!(bloom_filter.may_contain(&format!("key{}", num)));
+        }
+        let num_false_positives = (NUM_KEYS..2 * NUM_KEYS)
+            .filter(|num| bloom_filter.may_contain(&format!("key{}", num)))
+            .count();
+        assert!(num_false_positives < NUM_KEYS / 50);
+    }
+}
@@ -1,38 +1,185 @@
+use crossbeam::channel::Receiver;
+use protobuf::Message;
+use rand::distributions::{Distribution, WeightedIndex};
 use rand::{thread_rng, Rng};
-use std::path::PathBuf;
-use std::sync::{Arc, RwLock};
+use std::cmp::Reverse;
+use std::collections::HashSet;
+use std::io::{Read, Seek, SeekFrom};
+use std::ops::RangeBounds;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock, Weak};
+use std::time::{Duration, Instant};
 
-use crate::memtable::Memtable;
+use crate::backend::{Backend, BackendFile, FolderLock};
+use crate::blob;
+use crate::compaction::{self, CompactionPlan};
+use crate::comparator::Comparator;
+use crate::listener::{
+    self, CompactionFailureEvent, EventListener, QuarantineEvent, RepairEvent, SoftLimitEvent,
+};
+use crate::manifest::Manifest;
+use crate::memtable::{self, Memtable, MemtableWriter};
+use crate::merge;
+use crate::options::Options;
+use crate::sequencer::Sequencer;
+use crate::snapshot::{
+    EntryIterator, RawRecord, RecordSource, ScanIterator, ScanOptions, Snapshot,
+};
 use crate::sstable::{SSTable, SSTableView};
-use crate::types::{NaiveError, Result};
+use crate::stats::{
+    self, CompactionStats, RangeEstimate, ReadAmplificationRecorder, ReplayStats,
+    SSTableDescription, SoftLimitStats, Stats,
+};
+use crate::trash::Trash;
+use crate::types::{NaiveError, Record, RecordKind, Result, TimedRecord, WriteBatch, WriteReceipt};
+use crate::utils;
+use crate::watch::{ChangeEvent, Watchers};
 
 pub struct Catalog {
     /// The absolute path of the data folder.
     pub folder_path: PathBuf,
 
+    /// The options the storage engine is opened with.
+    pub options: Options,
+
+    /// Where the writes take their sequence numbers from, shared with each Memtable, and which
+    /// tells up to which one they are visible to the readers.
+    sequencer: Arc<Sequencer>,
+
     /// The in-memory active data for both read and write.
-    pub memtable: Arc<RwLock<Memtable>>,
+    pub memtable: Arc<Memtable>,
 
     /// The read-only backup of the Memtable during compaction.
     pub ro_memtable: Option<Arc<Memtable>>,
 
-    /// Read-only on-disk data in increasing generations.
+    /// Read-only on-disk data in increasing generations, each of which is partitioned by key
+    /// range into SSTables in increasing order of their keys.
     pub sstables: Vec<Arc<SSTable>>,
+
+    /// Deprecated SSTables, whose files are removed once the last view or iterator drops.
+    obsolete_sstables: Vec<Weak<SSTable>>,
+
+    /// The SSTables retired within the epoch retention window, kept for the historical views.
+    retained_sstables: Vec<RetainedSSTable>,
+
+    /// The oldest epoch whose SSTables are all still live or retained.
+    oldest_retained_epoch_no: u64,
+
+    /// The numbers of the blob files referred to by the SSTables as of the last collection of
+    /// the blob files, which are removed once no SSTable refers to them any longer.
+    blob_file_nos: HashSet<u64>,
+
+    /// How replaying the Memtable log went on open.
+    log_replay: ReplayStats,
+
+    /// The number of layers touched by the point lookups so far.
+    read_amplification: ReadAmplificationRecorder,
+
+    /// The compactions run so far, whose throughput is used for planning the next ones.
+    compaction_stats: Mutex<CompactionStats>,
+
+    /// The durable state of the data folder, updated by the flushes.
+    manifest: Mutex<Manifest>,
+
+    /// The soft limits crossed so far.
+    soft_limit_stats: Mutex<SoftLimitStats>,
+
+    /// The number of bytes the writes have appended to the Memtable logs so far.
+    user_bytes: AtomicUsize,
+
+    /// Whether the catalog follows a data folder written by another catalog and rejects writes.
+    is_replica: bool,
+
+    /// The watches on the keys, handed over from each Memtable to the next.
+    watchers: Arc<Watchers>,
+
+    /// The lock on the data folder, exclusive to a primary or shared by the replicas. This goes
+    /// last, so that it is released after the files close.
+    _folder_lock: FolderLock,
+}
+
+/// An SSTable retired by a compaction, which stays readable as of the epochs before it for the
+/// epoch retention window.
+struct RetainedSSTable {
+    sstable: Arc<SSTable>,
+
+    /// The epoch of the compaction that retired it.
+    retired_epoch_no: u64,
+
+    retired_at: Instant,
+}
+
+/// The lock a primary holds on its data folder exclusively, so that no other one opens it, e.g. in
+/// another process, and corrupts its Memtable log and its SSTables.
+const LOCK_NAME: &str = "LOCK";
+
+/// The lock the replicas of a data folder share, which keeps it from being destroyed.
+const REPLICA_LOCK_NAME: &str = "REPLICA_LOCK";
+
+/// The number of times a replica tries to catch up before giving up till the next refresh.
+const MAX_REFRESH_ATTEMPTS: usize = 3;
+
+/// An unflushed Memtable log found by a replica in the data folder of its primary.
+enum ReplicaLog {
+    /// The log of the current read-write Memtable of the replica.
+    ReadWrite(Option<(u64, u64)>),
+    /// The log of the current read-only Memtable of the replica.
+    ReadOnly(Arc<Memtable>),
+    /// A log the replica has not seen before.
+    Opened(Box<Memtable>),
+}
+
+impl ReplicaLog {
+    fn sequence_range(&self) -> Option<(u64, u64)> {
+        match self {
+            ReplicaLog::ReadWrite(sequence_range) => *sequence_range,
+            ReplicaLog::ReadOnly(memtable) => memtable.sequence_range(),
+            ReplicaLog::Opened(memtable) => memtable.sequence_range(),
+        }
+    }
 }
 
 impl Catalog {
-    pub fn open(folder_path: PathBuf) -> Result<Self> {
-        std::fs::create_dir_all(folder_path.as_path())?;
+    pub fn open(folder_path: PathBuf, options: Options) -> Result<Self> {
+        if !options.create_if_missing && !options.backend.exists(&folder_path) {
+            return Err(NaiveError::FolderNotFound(folder_path));
+        }
+        options.backend.create_dir_all(&folder_path)?;
+        let folder_lock = options.backend.lock_folder(&folder_path, LOCK_NAME, true)?;
+        // Tell the existing data folders apart once locked, so that no other catalog is creating
+        // one in the meantime.
+        let holds_data = Self::holds_data(&folder_path, &options)?;
+        if !holds_data && !options.create_if_missing {
+            // Leave the folder as found, removing the lock file while still holding the lock.
+            options.backend.remove_file(&folder_path.join(LOCK_NAME))?;
+            return Err(NaiveError::FolderNotFound(folder_path));
+        }
+        if holds_data && options.error_if_exists {
+            return Err(NaiveError::FolderExists(folder_path));
+        }
+        let manifest = Manifest::load(options.backend.as_ref(), &folder_path)?.unwrap_or_default();
+        Self::check_comparator(&manifest, &options)?;
 
         let ro_memtable = None;
         let mut sstables = Vec::new();
 
         let mut memtable_paths = Vec::new();
-        for dir_entry in std::fs::read_dir(folder_path.as_path())? {
-            let file_path = dir_entry?.path();
-            if !file_path.as_path().is_file() {
-                continue;
+        let mut blob_file_nos = HashSet::new();
+        let mut file_paths = options.backend.list_files(&folder_path)?;
+        if let Some(cold_folder_path) = options.cold_folder_path.as_ref() {
+            // Only SSTables and blob files are ever stored in the cold folder.
+            options.backend.create_dir_all(cold_folder_path)?;
+            for file_path in options.backend.list_files(cold_folder_path)? {
+                if file_path
+                    .extension()
+                    .is_some_and(|extension| extension == "sst" || extension == "blob")
+                {
+                    file_paths.push(file_path);
+                }
             }
+        }
+        for file_path in file_paths {
             let file_name = file_path
                 .as_path()
                 .file_name()
@@ -40,25 +187,108 @@ impl Catalog {
                 .to_str()
                 .unwrap_or("");
             if file_name.ends_with(".sst") {
-                sstables.push(Arc::new(SSTable::open(file_path)?));
+                // The SSTables found are all live as of the last epoch recorded.
+                sstables.push(SSTable::open_at_epoch(
+                    file_path,
+                    manifest.last_epoch_no,
+                    &options,
+                )?);
             } else if file_name.starts_with("memtable_") && file_name.ends_with(".log") {
                 memtable_paths.push(file_path);
+            } else if let Some(file_no) = blob::blob_file_no(&file_path) {
+                blob_file_nos.insert(file_no);
             }
         }
         log::info!("Successfully generated SSTables.");
 
-        if memtable_paths.len() > 1 {
+        // Tell the Memtable logs apart by the manifest, if it records them.
+        // Spare reading the SSTables through for their checksums, which are verified separately.
+        for (file_path, checksum) in manifest.sstable_paths(&folder_path) {
+            if let (Some(sstable), Some(checksum)) = (
+                sstables
+                    .iter()
+                    .find(|sstable| sstable.file_path() == file_path),
+                checksum,
+            ) {
+                sstable.set_checksum(checksum);
+            }
+        }
+        let (memtable_paths, stray_memtable_paths) = if manifest.active_log_name.is_some() {
+            Self::classify_memtable_logs(&manifest, memtable_paths, &options)?
+        } else {
+            (Vec::new(), memtable_paths)
+        };
+        let num_memtable_logs = memtable_paths.len() + stray_memtable_paths.len();
+        if num_memtable_logs > 1 && !stray_memtable_paths.is_empty() && !options.repair_on_open {
             log::error!("Found multiple Memtable logs:");
-            for memtable_path in memtable_paths {
+            for memtable_path in memtable_paths.iter().chain(stray_memtable_paths.iter()) {
                 log::error!("  {}", memtable_path.display());
             }
             return Err(NaiveError::InvalidData);
         }
 
-        sstables.sort_by(|a, b| a.gen_no().partial_cmp(&b.gen_no()).unwrap());
-        for gen_no in 0..sstables.len() {
-            let sstable = &sstables[gen_no];
+        // The SSTables of the same generation recorded in the manifest are its partitions, which
+        // come first so that they stay together.
+        let recorded_paths = manifest
+            .sstable_paths(&folder_path)
+            .into_iter()
+            .map(|(file_path, _)| file_path)
+            .collect::<HashSet<_>>();
+        let is_recorded = |sstable: &SSTable| recorded_paths.contains(sstable.file_path());
+        if options.repair_on_open {
+            // Among SSTables of the same generation otherwise, the newest one comes first since
+            // it is merged from the others.
+            let mut keyed_sstables = Vec::with_capacity(sstables.len());
+            for sstable in sstables {
+                let modified_time = options.backend.modified_time(sstable.file_path())?;
+                keyed_sstables.push((
+                    (
+                        sstable.gen_no(),
+                        !is_recorded(&sstable),
+                        Reverse(modified_time),
+                    ),
+                    sstable,
+                ));
+            }
+            keyed_sstables.sort_by_key(|(key, _)| *key);
+            sstables = keyed_sstables
+                .into_iter()
+                .map(|(_, sstable)| sstable)
+                .collect();
+        } else {
+            sstables.sort_by_key(|sstable| (sstable.gen_no(), !is_recorded(sstable)));
+        }
+        let mut num_generations = 0;
+        let mut last_sstable = None::<(usize, bool)>;
+        for sstable in sstables.iter_mut() {
+            let is_partition = last_sstable.is_some_and(|(last_gen_no, was_recorded)| {
+                was_recorded && last_gen_no == sstable.gen_no() && is_recorded(sstable)
+            });
+            last_sstable = Some((sstable.gen_no(), is_recorded(sstable)));
+            if !is_partition {
+                num_generations += 1;
+            }
+            let gen_no = num_generations - 1;
             if gen_no != sstable.gen_no() {
+                if options.repair_on_open {
+                    let old_gen_no = sstable.gen_no();
+                    sstable.renumber(gen_no)?;
+                    log::warn!(
+                        "Renumbered {} from generation {} to {}.",
+                        sstable.file_path().display(),
+                        old_gen_no,
+                        gen_no
+                    );
+                    listener::notify_repair(
+                        &options,
+                        RepairEvent::RenumberedSSTable {
+                            file_path: sstable.file_path().to_owned(),
+                            old_gen_no,
+                            new_gen_no: gen_no,
+                        },
+                    );
+                    continue;
+                }
                 log::error!(
                     "Expect generation {}, found {} which is generation {}.",
                     gen_no,
@@ -68,101 +298,2265 @@ impl Catalog {
                 return Err(NaiveError::InvalidData);
             }
         }
+        let mut sstables = sstables.into_iter().map(Arc::new).collect::<Vec<_>>();
+        Self::sort_sstables(&mut sstables, options.comparator);
 
         // If no Memtable log is found, create a new one.
-        let memtable = Arc::new(RwLock::new(Memtable::open(
-            memtable_paths
-                .pop()
-                .unwrap_or(Self::gen_memtable_path(&folder_path)),
-        )?));
+        let mut memtable = if num_memtable_logs <= 1 {
+            Memtable::open(
+                memtable_paths
+                    .into_iter()
+                    .chain(stray_memtable_paths)
+                    .next()
+                    .unwrap_or(Self::gen_memtable_path(&folder_path)),
+                &options,
+            )?
+        } else if stray_memtable_paths.is_empty() {
+            // A flush was cut short, so the logs in the manifest are replayed in their order.
+            log::warn!("Resuming the flush of {}.", memtable_paths[0].display());
+            Self::merge_memtable_logs(&folder_path, &memtable_paths, &options)?
+        } else {
+            // Without knowing the order of the logs, guess it by their modified times.
+            let mut keyed_log_paths = Vec::with_capacity(num_memtable_logs);
+            for log_path in memtable_paths.into_iter().chain(stray_memtable_paths) {
+                let modified_time = options.backend.modified_time(&log_path)?;
+                keyed_log_paths.push((modified_time, log_path));
+            }
+            keyed_log_paths.sort();
+            let log_paths = keyed_log_paths
+                .into_iter()
+                .map(|(_, log_path)| log_path)
+                .collect::<Vec<_>>();
+            let memtable = Self::merge_memtable_logs(&folder_path, &log_paths, &options)?;
+            log::warn!(
+                "Merged {} Memtable logs into {}.",
+                log_paths.len(),
+                memtable.log_path().display()
+            );
+            listener::notify_repair(
+                &options,
+                RepairEvent::MergedMemtableLogs {
+                    log_paths,
+                    merged_log_path: memtable.log_path().to_owned(),
+                },
+            );
+            memtable
+        };
+        let log_replay = memtable.replay_stats().clone();
+
+        // The Memtable log should pick up right after the last flushed write.
+        let last_flushed_sequence_no = manifest.last_flushed_sequence_no;
+        let mut last_sequence_no = last_flushed_sequence_no;
+        if let Some((first_logged_sequence_no, last_logged_sequence_no)) = memtable.sequence_range()
+        {
+            if first_logged_sequence_no > last_flushed_sequence_no + 1 {
+                log::warn!(
+                    "The writes from sequence number {} to {} are missing, since the last flushed \
+                     one is {} but the Memtable log starts from {}.",
+                    last_flushed_sequence_no + 1,
+                    first_logged_sequence_no - 1,
+                    last_flushed_sequence_no,
+                    first_logged_sequence_no
+                );
+                if options.fail_on_sequence_gap {
+                    return Err(NaiveError::SequenceGap {
+                        last_flushed_sequence_no,
+                        first_logged_sequence_no,
+                    });
+                }
+            }
+            last_sequence_no = last_sequence_no.max(last_logged_sequence_no);
+        }
+        let watchers = Arc::new(Watchers::new(options.watch_capacity));
+        memtable.set_watchers(watchers.clone());
+        // The log is in the order of the sequence numbers, so the writes replayed from it make up
+        // a contiguous range, unless the recovery mode skips the corrupted ones.
+        let sequencer = Arc::new(Sequencer::new(last_sequence_no));
+        memtable.set_sequencer(sequencer.clone());
+        let memtable = Arc::new(memtable);
         log::info!("Successfully generated an Memtable.");
 
-        Ok(Self {
+        let mut catalog = Self {
             folder_path,
+            options,
+            sequencer,
             memtable,
             ro_memtable,
             sstables,
+            obsolete_sstables: Vec::new(),
+            retained_sstables: Vec::new(),
+            oldest_retained_epoch_no: manifest.last_epoch_no,
+            blob_file_nos,
+            log_replay,
+            read_amplification: ReadAmplificationRecorder::default(),
+            compaction_stats: Mutex::new(CompactionStats::default()),
+            manifest: Mutex::new(manifest),
+            soft_limit_stats: Mutex::new(SoftLimitStats::default()),
+            user_bytes: AtomicUsize::new(0),
+            is_replica: false,
+            watchers,
+            _folder_lock: folder_lock,
+        };
+        // Publish the SSTables found, which may have been renumbered, and the Memtable log, which
+        // may have been merged, for the replicas and the next recovery.
+        let active_log_name = Self::file_name(catalog.memtable.log_path());
+        let comparator_name = catalog.options.comparator.name().to_owned();
+        catalog.update_manifest(|manifest| {
+            manifest.active_log_name = Some(active_log_name);
+            manifest.flushing_log_name = None;
+            manifest.comparator_name = Some(comparator_name);
+        })?;
+        // The blob files written by the compactions cut short are referred to by no SSTable.
+        catalog.collect_blob_garbage()?;
+        Ok(catalog)
+    }
+
+    /// The watches on the keys, to be carried over to each new Memtable.
+    pub(crate) fn watchers(&self) -> &Arc<Watchers> {
+        &self.watchers
+    }
+
+    /// The names of the column families created in the data folder besides the default one.
+    pub fn column_family_names(&self) -> Result<Vec<String>> {
+        Ok(self.manifest.lock()?.column_family_names.clone())
+    }
+
+    /// Record a new column family in the manifest, once its subfolder is in place.
+    pub fn record_column_family(&self, name: &str) -> Result<()> {
+        self.update_manifest(|manifest| {
+            if !manifest
+                .column_family_names
+                .iter()
+                .any(|other| other == name)
+            {
+                manifest.column_family_names.push(name.to_owned());
+            }
+        })
+    }
+
+    /// Whether the folder holds a data folder, i.e. a manifest, or the data files of a data folder
+    /// predating the manifest.
+    fn holds_data(folder_path: &Path, options: &Options) -> Result<bool> {
+        if options
+            .backend
+            .exists(&Manifest::gen_manifest_path(folder_path))
+        {
+            return Ok(true);
+        }
+        Ok(options
+            .backend
+            .list_files(folder_path)?
+            .iter()
+            .any(|file_path| Self::is_data_file(file_path)))
+    }
+
+    /// Whether the file is one of the data files a data folder writes, i.e. an SSTable, a blob file
+    /// or a Memtable log, as opposed to any other file that happens to be in the folder.
+    fn is_data_file(file_path: &Path) -> bool {
+        file_path
+            .file_name()
+            .and_then(|file_name| file_name.to_str())
+            .is_some_and(|file_name| {
+                file_name.ends_with(".sst")
+                    || file_name.ends_with(".blob")
+                    || (file_name.starts_with("memtable_") && file_name.ends_with(".log"))
+            })
+    }
+
+    /// Refuse to open a data folder ordered by another comparator than the one in the options.
+    /// The folders predating the record are checked by their SSTables instead.
+    fn check_comparator(manifest: &Manifest, options: &Options) -> Result<()> {
+        match manifest.comparator_name.as_deref() {
+            Some(comparator_name) if comparator_name != options.comparator.name() => {
+                Err(NaiveError::ComparatorMismatch(format!(
+                    "the data folder is ordered by {} but opened with {}",
+                    comparator_name,
+                    options.comparator.name()
+                )))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Open a read-only replica of a data folder in use by another catalog, e.g. in a sidecar
+    /// process, which catches up with the primary on refresh_replica.
+    pub fn open_replica(folder_path: PathBuf, options: Options) -> Result<Self> {
+        let folder_lock = options
+            .backend
+            .lock_folder(&folder_path, REPLICA_LOCK_NAME, false)?;
+        for attempt in 1..=MAX_REFRESH_ATTEMPTS {
+            let manifest = Self::load_replica_manifest(&folder_path, &options)?;
+            Self::check_comparator(&manifest, &options)?;
+            let result = Self::scan_replica(&folder_path, &options, &manifest, None, None, &[]);
+            let (sstables, mut replica_logs) =
+                match Self::check_replica_scan(&folder_path, &options, &manifest, result, attempt)?
+                {
+                    Some(scan) => scan,
+                    None => continue,
+                };
+
+            let mut memtable = match replica_logs.pop() {
+                Some(ReplicaLog::Opened(memtable)) => *memtable,
+                _ => {
+                    log::error!("Found no Memtable log in {}.", folder_path.display());
+                    return Err(NaiveError::InvalidData);
+                }
+            };
+            let mut ro_memtable = match replica_logs.pop() {
+                Some(ReplicaLog::Opened(memtable)) => Some(*memtable),
+                _ => None,
+            };
+            let last_sequence_no =
+                Self::last_replica_sequence_no(&manifest, &memtable, ro_memtable.as_ref());
+            let sequencer = Arc::new(Sequencer::new(last_sequence_no));
+            memtable.set_sequencer(sequencer.clone());
+            if let Some(ro_memtable) = ro_memtable.as_mut() {
+                ro_memtable.set_sequencer(sequencer.clone());
+            }
+            log::info!(
+                "Opened a replica of {} as of sequence number {}.",
+                folder_path.display(),
+                last_sequence_no
+            );
+            return Ok(Self {
+                folder_path,
+                options,
+                sequencer,
+                memtable: Arc::new(memtable),
+                ro_memtable: ro_memtable.map(Arc::new),
+                sstables,
+                obsolete_sstables: Vec::new(),
+                retained_sstables: Vec::new(),
+                oldest_retained_epoch_no: 0,
+                blob_file_nos: HashSet::new(),
+                log_replay: ReplayStats::default(),
+                read_amplification: ReadAmplificationRecorder::default(),
+                compaction_stats: Mutex::new(CompactionStats::default()),
+                manifest: Mutex::new(manifest),
+                soft_limit_stats: Mutex::new(SoftLimitStats::default()),
+                user_bytes: AtomicUsize::new(0),
+                is_replica: true,
+                watchers: Arc::default(),
+                _folder_lock: folder_lock,
+            });
+        }
+        log::error!(
+            "Failed to open a replica of {}, since its manifest keeps changing.",
+            folder_path.display()
+        );
+        Err(NaiveError::Unknown)
+    }
+
+    /// Catch up a replica with the SSTables in the manifest and the writes appended to the
+    /// Memtable logs since the last refresh.
+    ///
+    /// The folder is scanned without locking the catalog, and the scan is only taken if the
+    /// manifest stays the same throughout, since the primary publishes its SSTables there before
+    /// removing any file they replace.
+    pub fn refresh_replica(catalog: &RwLock<Catalog>) -> Result<()> {
+        let (folder_path, options) = {
+            let catalog = catalog.read()?;
+            if !catalog.is_replica {
+                return Err(NaiveError::InvalidData);
+            }
+            (catalog.folder_path.clone(), catalog.options.clone())
+        };
+        for attempt in 1..=MAX_REFRESH_ATTEMPTS {
+            let manifest = Self::load_replica_manifest(&folder_path, &options)?;
+            let (memtable, ro_memtable, old_sstables) = {
+                let catalog = catalog.read()?;
+                (
+                    catalog.memtable.clone(),
+                    catalog.ro_memtable.clone(),
+                    catalog.sstables.clone(),
+                )
+            };
+            let result = Self::scan_replica(
+                &folder_path,
+                &options,
+                &manifest,
+                Some(&memtable),
+                ro_memtable.as_ref(),
+                &old_sstables,
+            );
+            let (sstables, mut replica_logs) =
+                match Self::check_replica_scan(&folder_path, &options, &manifest, result, attempt)?
+                {
+                    Some(scan) => scan,
+                    None => continue,
+                };
+
+            // The Memtables opened show the reads only the writes published below.
+            let sequencer = catalog.read()?.sequencer.clone();
+            for replica_log in replica_logs.iter_mut() {
+                if let ReplicaLog::Opened(memtable) = replica_log {
+                    memtable.set_sequencer(sequencer.clone());
+                }
+            }
+
+            // Lock the catalog for a short duration.
+            let mut catalog = catalog.write()?;
+            let old_memtable = match replica_logs.pop() {
+                Some(ReplicaLog::Opened(new_memtable)) => Some(std::mem::replace(
+                    &mut catalog.memtable,
+                    Arc::new(*new_memtable),
+                )),
+                Some(ReplicaLog::ReadWrite(_)) => None,
+                _ => {
+                    log::error!("Found no Memtable log in {}.", folder_path.display());
+                    return Err(NaiveError::InvalidData);
+                }
+            };
+            catalog.ro_memtable = match replica_logs.pop() {
+                Some(ReplicaLog::ReadWrite(_)) => old_memtable,
+                Some(ReplicaLog::ReadOnly(memtable)) => Some(memtable),
+                Some(ReplicaLog::Opened(memtable)) => Some(Arc::new(*memtable)),
+                None => None,
+            };
+            catalog.sstables = sstables;
+            let last_sequence_no = Self::last_replica_sequence_no(
+                &manifest,
+                &catalog.memtable,
+                catalog.ro_memtable.as_deref(),
+            );
+            *catalog.manifest.lock()? = manifest;
+            catalog.sequencer.commit(last_sequence_no);
+            catalog.sequencer.publish(last_sequence_no)?;
+            return Ok(());
+        }
+        log::warn!(
+            "Gave up refreshing the replica of {} for now, since its manifest keeps changing.",
+            folder_path.display()
+        );
+        Ok(())
+    }
+
+    fn load_replica_manifest(folder_path: &Path, options: &Options) -> Result<Manifest> {
+        Manifest::load(options.backend.as_ref(), folder_path)?.ok_or_else(|| {
+            log::error!("Found no manifest in {}.", folder_path.display());
+            NaiveError::InvalidData
+        })
+    }
+
+    /// Open the SSTables in the manifest and the Memtable logs not yet flushed, the latter from
+    /// the oldest to the newest, reusing the ones the replica already has.
+    fn scan_replica(
+        folder_path: &Path,
+        options: &Options,
+        manifest: &Manifest,
+        memtable: Option<&Arc<Memtable>>,
+        ro_memtable: Option<&Arc<Memtable>>,
+        old_sstables: &[Arc<SSTable>],
+    ) -> Result<(Vec<Arc<SSTable>>, Vec<ReplicaLog>)> {
+        // List the logs before tailing the current one, which is complete if a newer one shows up.
+        let mut log_paths = Vec::new();
+        for file_path in options.backend.list_files(folder_path)? {
+            let file_name = file_path.file_name().and_then(|name| name.to_str());
+            if file_name.is_some_and(|name| name.starts_with("memtable_") && name.ends_with(".log"))
+            {
+                log_paths.push(file_path);
+            }
+        }
+
+        let mut replica_logs = Vec::with_capacity(log_paths.len());
+        for log_path in log_paths {
+            if let Some(memtable) = memtable {
+                if memtable.log_path() == log_path {
+                    memtable.tail()?;
+                    replica_logs.push(ReplicaLog::ReadWrite(memtable.sequence_range()));
+                    continue;
+                }
+            }
+            if let Some(ro_memtable) = ro_memtable {
+                if ro_memtable.log_path() == log_path {
+                    replica_logs.push(ReplicaLog::ReadOnly(ro_memtable.clone()));
+                    continue;
+                }
+            }
+            match Memtable::open_read_only(log_path, options) {
+                Ok(memtable) => replica_logs.push(ReplicaLog::Opened(Box::new(memtable))),
+                // The log has been flushed and removed since listed.
+                Err(NaiveError::IoError(error)) if error.kind() == std::io::ErrorKind::NotFound => {
+                }
+                Err(error) => return Err(error),
+            }
+        }
+        // Skip the flushed logs, and put the empty ones after the others.
+        replica_logs.retain(|replica_log| {
+            replica_log
+                .sequence_range()
+                .is_none_or(|(_, max)| max > manifest.last_flushed_sequence_no)
+        });
+        replica_logs.sort_by_key(|replica_log| {
+            replica_log
+                .sequence_range()
+                .map_or((1, 0), |(min, _)| (0, min))
+        });
+        if replica_logs.len() > 2 {
+            log::error!(
+                "Found {} unflushed Memtable logs in {}.",
+                replica_logs.len(),
+                folder_path.display()
+            );
+            return Err(NaiveError::InvalidData);
+        }
+
+        let mut epoch_no = old_sstables
+            .iter()
+            .map(|sstable| sstable.epoch_no())
+            .max()
+            .unwrap_or(0);
+        let mut sstables = Vec::with_capacity(manifest.sstable_names.len());
+        for sstable_name in manifest.sstable_names.iter() {
+            let file_path = folder_path.join(sstable_name);
+            let sstable = match old_sstables
+                .iter()
+                .find(|sstable| sstable.file_path() == file_path)
+            {
+                Some(sstable) => sstable.clone(),
+                None => {
+                    // A new epoch lets the viewers tell the SSTable from the one it replaces.
+                    epoch_no += 1;
+                    Arc::new(SSTable::open_at_epoch(file_path, epoch_no, options)?)
+                }
+            };
+            // Either the next partition of the last generation or the first of the next one.
+            let num_generations = sstables
+                .last()
+                .map_or(0, |sstable: &Arc<SSTable>| sstable.gen_no() + 1);
+            if sstable.gen_no() + 1 != num_generations && sstable.gen_no() != num_generations {
+                log::error!(
+                    "Expect generation {}, found {} which is generation {}.",
+                    num_generations,
+                    sstable.file_path().display(),
+                    sstable.gen_no()
+                );
+                return Err(NaiveError::InvalidData);
+            }
+            sstables.push(sstable);
+        }
+        Ok((sstables, replica_logs))
+    }
+
+    /// Take the scan of a replica if the manifest has stayed the same, or return None to scan
+    /// again if the primary has changed it meanwhile.
+    fn check_replica_scan<T>(
+        folder_path: &Path,
+        options: &Options,
+        manifest: &Manifest,
+        result: Result<T>,
+        attempt: usize,
+    ) -> Result<Option<T>> {
+        if Manifest::load(options.backend.as_ref(), folder_path)?.as_ref() == Some(manifest) {
+            return result.map(Some);
+        }
+        log::info!(
+            "The manifest of {} changed during attempt {} to catch up.",
+            folder_path.display(),
+            attempt
+        );
+        Ok(None)
+    }
+
+    /// The sequence number of the last write a replica has, which is the largest one in the
+    /// Memtable logs tailed so far, since each log is written in the order of the sequence numbers
+    /// and tailed up to a complete command, and follows the writes flushed before it.
+    fn last_replica_sequence_no(
+        manifest: &Manifest,
+        memtable: &Memtable,
+        ro_memtable: Option<&Memtable>,
+    ) -> u64 {
+        [
+            memtable.sequence_range(),
+            ro_memtable.and_then(Memtable::sequence_range),
+        ]
+        .into_iter()
+        .flatten()
+        .map(|(_, max)| max)
+        .fold(manifest.last_flushed_sequence_no, u64::max)
+    }
+
+    /// Replay the Memtable logs, from the oldest to the newest, into a new Memtable and then
+    /// remove them.
+    fn merge_memtable_logs(
+        folder_path: &Path,
+        log_paths: &[PathBuf],
+        options: &Options,
+    ) -> Result<Memtable> {
+        let merged_log_path = Self::gen_memtable_path(folder_path);
+        let memtable = Memtable::open(merged_log_path.clone(), options)?;
+        let mut stray_memtables = Vec::with_capacity(log_paths.len());
+        for log_path in log_paths.iter() {
+            let stray_memtable = Memtable::open(log_path.clone(), options)?;
+            let mut batch = WriteBatch::new();
+            for (key, record) in stray_memtable.iter() {
+                match record {
+                    Record::Value(value) => batch.set(key, value),
+                    Record::ExpiringValue {
+                        value,
+                        expires_at_ms,
+                    } => batch.set_expiring(key, value, expires_at_ms),
+                    Record::Deleted => batch.remove(key),
+                    Record::Merge(operands) => {
+                        for operand in operands {
+                            batch.merge(key.clone(), operand);
+                        }
+                    }
+                }
+            }
+            // Stamp the first batch with the smallest sequence number and the others with the
+            // largest ones, so that the merged log spans the same sequence range.
+            let sequence_no = match stray_memtable.sequence_range() {
+                Some((min_sequence_no, _)) if stray_memtables.is_empty() => min_sequence_no,
+                Some((_, max_sequence_no)) => max_sequence_no,
+                None => 0,
+            };
+            memtable.write_batch(&batch, sequence_no)?;
+            stray_memtables.push(stray_memtable);
+        }
+        // Remove the stray logs only after all of them are merged.
+        for stray_memtable in stray_memtables {
+            stray_memtable.deprecate()?;
+        }
+        Ok(memtable)
+    }
+
+    /// Split the Memtable logs into the ones in the manifest, the flushing one before the active
+    /// one, and the stray ones. The stray logs that are empty or whose writes have all been
+    /// flushed, e.g. left behind by a crash around a flush, are removed instead.
+    fn classify_memtable_logs(
+        manifest: &Manifest,
+        log_paths: Vec<PathBuf>,
+        options: &Options,
+    ) -> Result<(Vec<PathBuf>, Vec<PathBuf>)> {
+        let mut flushing_log_path = None;
+        let mut active_log_path = None;
+        let mut stray_log_paths = Vec::new();
+        for log_path in log_paths {
+            let log_name = log_path
+                .file_name()
+                .map(|file_name| file_name.to_string_lossy().into_owned());
+            if log_name.is_some() && log_name == manifest.active_log_name {
+                active_log_path = Some(log_path);
+            } else if log_name.is_some() && log_name == manifest.flushing_log_name {
+                flushing_log_path = Some(log_path);
+            } else {
+                let stray_memtable = Memtable::open(log_path.clone(), options)?;
+                let is_flushed = match stray_memtable.sequence_range() {
+                    Some((_, last_sequence_no)) => {
+                        last_sequence_no <= manifest.last_flushed_sequence_no
+                    }
+                    None => stray_memtable.iter().next().is_none(),
+                };
+                if is_flushed {
+                    log::warn!("Removing flushed Memtable log {}.", log_path.display());
+                    stray_memtable.deprecate()?;
+                } else {
+                    stray_log_paths.push(log_path);
+                }
+            }
+        }
+        let log_paths = flushing_log_path
+            .into_iter()
+            .chain(active_log_path)
+            .collect();
+        Ok((log_paths, stray_log_paths))
+    }
+
+    /// Lock an existing data folder against both a primary and the replicas, failing with
+    /// FolderInUse if any has it open.
+    pub(crate) fn lock_for_destroy(
+        folder_path: &Path,
+        options: &Options,
+    ) -> Result<Vec<FolderLock>> {
+        [LOCK_NAME, REPLICA_LOCK_NAME]
+            .into_iter()
+            .map(|lock_name| options.backend.lock_folder(folder_path, lock_name, true))
+            .collect()
+    }
+
+    /// Remove the files of a data folder the engine owns, i.e. the SSTables, the Memtable logs and
+    /// the manifest, along with the ones in the trash and in the cold folder, and then the folders
+    /// once empty, returning the number of files removed. Any other file is left in place, and so
+    /// is the folder holding it.
+    ///
+    /// The manifest goes last, so that a destroy cut short can run again. The caller holds the
+    /// locks of `lock_for_destroy` throughout.
+    pub(crate) fn destroy(folder_path: &Path, options: &Options) -> Result<usize> {
+        let backend = options.backend.as_ref();
+        let folder_paths = std::iter::once(folder_path)
+            .chain(options.cold_folder_path.as_deref())
+            .collect::<Vec<_>>();
+        let mut num_files = 0;
+        for folder_path in folder_paths.iter() {
+            let trash_path = Trash::gen_trash_path(folder_path);
+            for path in [trash_path.as_path(), folder_path] {
+                if !backend.exists(path) {
+                    continue;
+                }
+                for file_path in backend.list_files(path)? {
+                    if Self::is_data_file(&file_path) && backend.remove_file(&file_path)? {
+                        num_files += 1;
+                    }
+                }
+            }
+            backend.remove_dir(&trash_path)?;
+        }
+        let manifest_path = Manifest::gen_manifest_path(folder_path);
+        for file_path in [manifest_path.with_extension("tmp"), manifest_path] {
+            if backend.remove_file(&file_path)? {
+                num_files += 1;
+            }
+        }
+        // The lock files go along with the folder, while the caller still holds the locks.
+        for lock_name in [LOCK_NAME, REPLICA_LOCK_NAME] {
+            backend.remove_file(&folder_path.join(lock_name))?;
+        }
+        for folder_path in folder_paths.iter().rev() {
+            backend.remove_dir(folder_path)?;
+        }
+        Ok(num_files)
+    }
+
+    /// The number of generations, each of which holds at least one SSTable.
+    pub fn num_generations(&self) -> usize {
+        self.sstables
+            .last()
+            .map_or(0, |sstable| sstable.gen_no() + 1)
+    }
+
+    /// The SSTables of the generation in increasing order of their keys.
+    pub fn generation(&self, gen_no: usize) -> &[Arc<SSTable>] {
+        let start = self
+            .sstables
+            .partition_point(|sstable| sstable.gen_no() < gen_no);
+        let end = self
+            .sstables
+            .partition_point(|sstable| sstable.gen_no() <= gen_no);
+        &self.sstables[start..end]
+    }
+
+    /// Replace the SSTables with the ones a compaction creates, retiring the old ones, and keep
+    /// the SSTables in order. The manifest is left for the caller to update.
+    pub fn replace_sstables(
+        &mut self,
+        old_sstables: &[Arc<SSTable>],
+        new_sstables: Vec<SSTable>,
+        epoch_no: u64,
+    ) -> Result<()> {
+        self.sstables.retain(|sstable| {
+            !old_sstables
+                .iter()
+                .any(|old_sstable| Arc::ptr_eq(old_sstable, sstable))
+        });
+        for old_sstable in old_sstables {
+            self.retire_sstable(old_sstable, epoch_no)?;
+        }
+        self.sstables.extend(new_sstables.into_iter().map(Arc::new));
+        Self::sort_sstables(&mut self.sstables, self.options.comparator);
+        Ok(())
+    }
+
+    /// Sort the SSTables in increasing generations and then keys, with the empty ones first.
+    fn sort_sstables(sstables: &mut [Arc<SSTable>], comparator: &dyn Comparator) {
+        sstables.sort_by(|a, b| {
+            a.gen_no()
+                .cmp(&b.gen_no())
+                .then_with(|| match (a.key_range(), b.key_range()) {
+                    (Some((a_min_key, _)), Some((b_min_key, _))) => {
+                        comparator.compare(a_min_key, b_min_key)
+                    }
+                    (a_key_range, b_key_range) => a_key_range.is_some().cmp(&b_key_range.is_some()),
+                })
+        });
+    }
+
+    /// Deprecate an SSTable replaced by compaction and keep track of it while it is pinned.
+    pub fn retire_sstable(&mut self, sstable: &Arc<SSTable>, epoch_no: u64) -> Result<()> {
+        sstable.deprecate()?;
+        self.obsolete_sstables
+            .retain(|sstable| sstable.strong_count() > 0);
+        self.obsolete_sstables.push(Arc::downgrade(sstable));
+        if self.options.epoch_retention_s > 0 {
+            self.retained_sstables.push(RetainedSSTable {
+                sstable: sstable.clone(),
+                retired_epoch_no: epoch_no,
+                retired_at: Instant::now(),
+            });
+        } else {
+            self.oldest_retained_epoch_no = self.oldest_retained_epoch_no.max(epoch_no);
+        }
+        Ok(())
+    }
+
+    /// Remove the blob files that no SSTable refers to any longer, be it live or pinned, e.g. once
+    /// a compaction replaces the SSTables referring to them, and return their number. The blob
+    /// files written since the last collection are left alone until an SSTable refers to them.
+    pub(crate) fn collect_blob_garbage(&mut self) -> Result<usize> {
+        let referenced_file_nos = self
+            .sstables
+            .iter()
+            .cloned()
+            .chain(self.obsolete_sstables.iter().filter_map(Weak::upgrade))
+            .flat_map(|sstable| sstable.blob_file_nos().to_vec())
+            .collect::<HashSet<_>>();
+        let backend = self.options.backend.as_ref();
+        let folder_paths = blob::blob_folder_paths(&self.folder_path, &self.options);
+        let trash = Trash::new(&self.options);
+        let mut num_files = 0;
+        for &file_no in self.blob_file_nos.difference(&referenced_file_nos) {
+            let file_path = blob::locate_blob_file(backend, &folder_paths, file_no);
+            if backend.exists(&file_path) {
+                Trash::discard(backend, trash.as_ref(), &file_path)?;
+                num_files += 1;
+            }
+        }
+        if num_files > 0 {
+            log::info!("Removed {} blob files no longer referred to.", num_files);
+        }
+        self.blob_file_nos = referenced_file_nos;
+        Ok(num_files)
+    }
+
+    /// Release the retired SSTables kept beyond the epoch retention window, so that their files
+    /// are removed once no view or iterator pins them, and return the number released.
+    pub fn expire_retained_sstables(&mut self) -> usize {
+        let retention = Duration::from_secs(self.options.epoch_retention_s);
+        let num_sstables = self.retained_sstables.len();
+        let mut oldest_retained_epoch_no = self.oldest_retained_epoch_no;
+        self.retained_sstables.retain(|retained_sstable| {
+            let is_expired = retained_sstable.retired_at.elapsed() >= retention;
+            if is_expired {
+                oldest_retained_epoch_no =
+                    oldest_retained_epoch_no.max(retained_sstable.retired_epoch_no);
+            }
+            !is_expired
+        });
+        self.oldest_retained_epoch_no = oldest_retained_epoch_no;
+        num_sstables - self.retained_sstables.len()
+    }
+
+    /// The oldest epoch the historical views can be taken at.
+    pub fn oldest_retained_epoch_no(&self) -> u64 {
+        self.oldest_retained_epoch_no
+    }
+
+    /// The epoch of the SSTables installed last, as recorded in the manifest.
+    pub fn last_epoch_no(&self) -> Result<u64> {
+        Ok(self.manifest.lock()?.last_epoch_no)
+    }
+
+    /// The SSTables as of the epoch in increasing generations, i.e. the ones generated by then and
+    /// not retired by then, or None if any of them has been released. Since each compaction keeps
+    /// the data of its inputs, the data of an SSTable may show up in an older generation as well,
+    /// e.g. when a flush overlaps with a merge, which makes no difference to the reads.
+    pub fn sstables_at_epoch(&self, epoch_no: u64) -> Option<Vec<Arc<SSTable>>> {
+        if epoch_no < self.oldest_retained_epoch_no {
+            return None;
+        }
+        let mut sstables = self
+            .sstables
+            .iter()
+            .filter(|sstable| sstable.epoch_no() <= epoch_no)
+            .chain(
+                self.retained_sstables
+                    .iter()
+                    .filter(|retained_sstable| {
+                        retained_sstable.sstable.epoch_no() <= epoch_no
+                            && epoch_no < retained_sstable.retired_epoch_no
+                    })
+                    .map(|retained_sstable| &retained_sstable.sstable),
+            )
+            .cloned()
+            .collect::<Vec<_>>();
+        sstables.sort_by_key(|sstable| (sstable.gen_no(), Reverse(sstable.epoch_no())));
+        Some(sstables)
+    }
+
+    /// Place an SSTable built elsewhere as the new oldest generation, once it is numbered so,
+    /// unless its keys overlap with the ones of the Memtables or of any other SSTable, in which
+    /// case its records, stamped before any write, would be out of order. The segment file is
+    /// discarded if the SSTable is not placed.
+    pub(crate) fn install_sstable(&mut self, sstable: SSTable) -> Result<()> {
+        if let Err(error) = self.check_installable(&sstable) {
+            sstable.deprecate()?;
+            return Err(error);
+        }
+        let num_generations = self.num_generations();
+        self.sstables.push(Arc::new(sstable));
+        debug_assert_eq!(self.num_generations(), num_generations + 1);
+        self.record_sstables()
+    }
+
+    fn check_installable(&self, sstable: &SSTable) -> Result<()> {
+        if self.is_replica {
+            return Err(NaiveError::ReadOnly);
+        }
+        if sstable.gen_no() != self.num_generations() {
+            return Err(NaiveError::InvalidOptions(format!(
+                "{} is numbered generation {} rather than {}",
+                sstable.file_path().display(),
+                sstable.gen_no(),
+                self.num_generations()
+            )));
+        }
+        let (min_key, max_key) = match sstable.key_range() {
+            Some(key_range) => key_range,
+            None => return Ok(()),
+        };
+        let comparator = self.options.comparator;
+        let range = min_key.to_owned()..=max_key.to_owned();
+        let overlaps = self.memtable.range(&range).next().is_some()
+            || self
+                .ro_memtable
+                .as_ref()
+                .is_some_and(|ro_memtable| ro_memtable.range(&range).next().is_some())
+            || self.sstables.iter().any(|other| {
+                other
+                    .key_range()
+                    .is_some_and(|(other_min_key, other_max_key)| {
+                        comparator.compare(other_min_key, max_key).is_le()
+                            && comparator.compare(min_key, other_max_key).is_le()
+                    })
+            });
+        if overlaps {
+            return Err(NaiveError::InvalidOptions(format!(
+                "the keys of {} overlap with the ones stored",
+                sstable.file_path().display()
+            )));
+        }
+        Ok(())
+    }
+
+    /// Write a consistent copy of the data folder into the target folder, which opens like any
+    /// data folder, e.g. as a backup. The SSTables and the blob files of the cold folder are copied
+    /// into the target folder as well.
+    ///
+    /// The catalog is only locked for pinning the SSTables and the Memtable logs as of now. The
+    /// SSTables and their blob files are then hard-linked if the backend allows, or copied
+    /// otherwise, and the logs are copied up to the last write before the pinning, with the
+    /// manifest written last.
+    pub fn checkpoint(catalog: &RwLock<Catalog>, target_path: &Path) -> Result<()> {
+        let mut pinned_files = Self::pin_files(catalog)?;
+        let backend = pinned_files.backend.clone();
+        if Manifest::load(backend.as_ref(), target_path)?.is_some() {
+            return Err(NaiveError::InvalidOptions(format!(
+                "{} already holds a data folder",
+                target_path.display()
+            )));
+        }
+        backend.create_dir_all(target_path)?;
+        let num_sstables = pinned_files.sstables.len();
+        let mut sstable_names = Vec::with_capacity(num_sstables);
+        let mut sstable_checksums = Vec::with_capacity(num_sstables);
+        for sstable in pinned_files.sstables.iter() {
+            let sstable_name = Self::file_name(sstable.file_path());
+            backend.link_or_copy(sstable.file_path(), &target_path.join(&sstable_name))?;
+            sstable_names.push(sstable_name);
+            sstable_checksums.push(sstable.checksum()?);
+        }
+        for blob_file_path in pinned_files.blob_file_paths() {
+            let blob_name = Self::file_name(&blob_file_path);
+            backend.link_or_copy(&blob_file_path, &target_path.join(blob_name))?;
+        }
+        let num_logs = pinned_files.copy_logs(target_path)?;
+        let manifest = &mut pinned_files.manifest;
+        manifest.sstable_names = sstable_names;
+        manifest.sstable_checksums = sstable_checksums;
+        manifest.blob_names = Self::blob_names(&pinned_files.sstables);
+        manifest.save(backend.as_ref(), target_path)?;
+        log::info!(
+            "Checkpointed {} SSTables and {} Memtable logs into {}.",
+            num_sstables,
+            num_logs,
+            target_path.display()
+        );
+        Ok(())
+    }
+
+    /// Pin the SSTables and the Memtable logs as of now, e.g. for a checkpoint or a backup, only
+    /// locking the catalog meanwhile.
+    pub(crate) fn pin_files(catalog: &RwLock<Catalog>) -> Result<PinnedFiles> {
+        let catalog = catalog.read()?;
+        // The read-only Memtable, if any, is being flushed, and its log comes first.
+        let mut logs = Vec::new();
+        if let Some(ro_memtable) = catalog.ro_memtable.as_ref() {
+            let (log_file, log_size) = ro_memtable.pin_log()?;
+            logs.push((Self::file_name(ro_memtable.log_path()), log_file, log_size));
+        }
+        let (log_file, log_size) = catalog.memtable.pin_log()?;
+        logs.push((
+            Self::file_name(catalog.memtable.log_path()),
+            log_file,
+            log_size,
+        ));
+        let manifest = catalog.manifest.lock()?.clone();
+        Ok(PinnedFiles {
+            backend: catalog.options.backend.clone(),
+            manifest,
+            sstables: catalog.sstables.clone(),
+            logs,
         })
     }
 
-    pub fn gen_memtable_path(folder_path: &PathBuf) -> PathBuf {
-        let mut path_buf = folder_path.clone();
+    /// Record in the manifest that the current Memtable is about to be flushed and a new one with
+    /// the log at the path is to take the writes, which must happen before the swap.
+    pub fn record_memtable_swap(&self, new_log_path: &Path) -> Result<()> {
+        let flushing_log_name = Self::file_name(self.memtable.log_path());
+        let active_log_name = Self::file_name(new_log_path);
+        self.update_manifest(|manifest| {
+            manifest.flushing_log_name = Some(flushing_log_name);
+            manifest.active_log_name = Some(active_log_name);
+        })
+    }
+
+    /// Record in the manifest that the writes up to the sequence number have been flushed into
+    /// the current SSTables, which completes the flush in progress.
+    pub fn record_flush(&self, sequence_no: u64) -> Result<()> {
+        self.update_manifest(|manifest| {
+            manifest.last_flushed_sequence_no = manifest.last_flushed_sequence_no.max(sequence_no);
+            manifest.flushing_log_name = None;
+        })
+    }
+
+    /// The file name of a Memtable log or a segment file in the folder.
+    pub(crate) fn file_name(file_path: &Path) -> String {
+        file_path
+            .file_name()
+            .map(|file_name| file_name.to_string_lossy().into_owned())
+            .unwrap_or_default()
+    }
+
+    /// The file names of the blob files the SSTables refer to, in increasing order.
+    pub(crate) fn blob_names(sstables: &[Arc<SSTable>]) -> Vec<String> {
+        let mut blob_file_nos = sstables
+            .iter()
+            .flat_map(|sstable| sstable.blob_file_nos().iter().copied())
+            .collect::<Vec<_>>();
+        blob_file_nos.sort_unstable();
+        blob_file_nos.dedup();
+        blob_file_nos
+            .into_iter()
+            .map(blob::blob_file_name)
+            .collect()
+    }
+
+    /// Record the current SSTables in the manifest, which must happen before the files of the
+    /// SSTables they replace are removed.
+    pub fn record_sstables(&self) -> Result<()> {
+        self.update_manifest(|_| ())
+    }
+
+    /// Update the manifest along with the current SSTables, and save it if anything changes.
+    fn update_manifest(&self, update: impl FnOnce(&mut Manifest)) -> Result<()> {
+        let mut manifest = self.manifest.lock()?;
+        let mut new_manifest = manifest.clone();
+        update(&mut new_manifest);
+        new_manifest.sstable_names = self
+            .sstables
+            .iter()
+            .map(|sstable| {
+                // The SSTables outside the data folder, i.e. in the cold folder, are recorded by
+                // their absolute paths.
+                let file_path = sstable.file_path();
+                file_path
+                    .strip_prefix(&self.folder_path)
+                    .unwrap_or(file_path)
+                    .to_string_lossy()
+                    .into_owned()
+            })
+            .collect();
+        new_manifest.sstable_checksums = self
+            .sstables
+            .iter()
+            .map(|sstable| sstable.checksum())
+            .collect::<Result<_>>()?;
+        new_manifest.blob_names = Self::blob_names(&self.sstables);
+        new_manifest.last_epoch_no = self
+            .sstables
+            .iter()
+            .map(|sstable| sstable.epoch_no())
+            .fold(new_manifest.last_epoch_no, u64::max);
+        if new_manifest != *manifest {
+            new_manifest.save(self.options.backend.as_ref(), &self.folder_path)?;
+            *manifest = new_manifest;
+        }
+        Ok(())
+    }
+
+    /// Sync the Memtable log to the disk, unless the catalog is a replica's, which never writes it.
+    pub(crate) fn sync_log(&self) -> Result<()> {
+        if self.is_replica {
+            return Ok(());
+        }
+        self.memtable.sync()
+    }
+
+    /// The sequence number of the last write visible to the reads.
+    pub fn visible_sequence_no(&self) -> Result<u64> {
+        Ok(self.sequencer.visible_sequence_no())
+    }
+
+    /// Block until the writes up to the sequence number are visible.
+    fn wait_until_visible(&self, sequence_no: u64) -> Result<()> {
+        self.sequencer.wait_until_visible(sequence_no)
+    }
+
+    /// Where the writes take their sequence numbers from, to be shared with each new Memtable.
+    pub(crate) fn sequencer(&self) -> &Arc<Sequencer> {
+        &self.sequencer
+    }
+
+    pub fn stats(&self) -> Stats {
+        let mut stats = Stats {
+            log_replay: self.log_replay.clone(),
+            read_amplification: self.read_amplification.snapshot(),
+            compaction: self.compaction_stats(),
+            soft_limits: self
+                .soft_limit_stats
+                .lock()
+                .map(|soft_limit_stats| soft_limit_stats.clone())
+                .unwrap_or_default(),
+            user_bytes: self.user_bytes.load(Ordering::SeqCst),
+            compaction_backlog_bytes: self.compaction_backlog().unwrap_or_default(),
+            ..Stats::default()
+        };
+        for sstable in self.obsolete_sstables.iter().filter_map(Weak::upgrade) {
+            stats.num_pinned_obsolete_files += 1;
+            stats.pinned_obsolete_bytes += sstable.file_size();
+        }
+        stats
+    }
+
+    pub fn compaction_stats(&self) -> CompactionStats {
+        self.compaction_stats
+            .lock()
+            .map(|compaction_stats| compaction_stats.clone())
+            .unwrap_or_default()
+    }
+
+    /// Account for a compaction that has written a segment file of the given size.
+    pub fn record_compaction(&self, num_bytes: usize, duration: Duration) -> Result<()> {
+        let mut compaction_stats = self.compaction_stats.lock()?;
+        compaction_stats.num_compactions += 1;
+        compaction_stats.num_bytes += num_bytes;
+        compaction_stats.duration += duration;
+        Ok(())
+    }
+
+    /// Warn about a soft limit crossed in the log and the stats, returning the event listeners to
+    /// notify once the caller has released its locks, so that a slow listener holds up no write.
+    pub fn record_soft_limit(&self, event: &SoftLimitEvent) -> Result<Vec<Arc<dyn EventListener>>> {
+        log::warn!("Crossed a soft limit: {:?}", event);
+        let mut soft_limit_stats = self.soft_limit_stats.lock()?;
+        match event {
+            SoftLimitEvent::MemtableNearlyFull { .. } => {
+                soft_limit_stats.num_memtable_nearly_full += 1
+            }
+            SoftLimitEvent::FlushBehind { .. } => soft_limit_stats.num_flush_behind += 1,
+            SoftLimitEvent::CompactionBacklog { .. } => {
+                soft_limit_stats.num_compaction_backlog += 1
+            }
+        }
+        Ok(self.options.event_listeners.clone())
+    }
+
+    /// Report a failed compaction in the log and the stats, returning the event listeners to
+    /// notify once the caller has released its locks.
+    pub fn record_compaction_failure(
+        &self,
+        event: &CompactionFailureEvent,
+    ) -> Result<Vec<Arc<dyn EventListener>>> {
+        log::error!("Failed a compaction: {:?}", event);
+        self.compaction_stats.lock()?.num_failures += 1;
+        Ok(self.options.event_listeners.clone())
+    }
+
+    /// Count a read from the SSTable towards its quarantine if it has failed with an I/O error or
+    /// corrupted data, and notify the event listeners once it is quarantined.
+    fn check_sstable_read<T>(&self, sstable: &SSTable, result: Result<T>) -> Result<T> {
+        let is_error = matches!(
+            result,
+            Err(NaiveError::IoError(_)
+                | NaiveError::Corruption { .. }
+                | NaiveError::InvalidData
+                | NaiveError::ProtobufError)
+        );
+        if sstable.record_read(is_error, self.options.sstable_error_threshold) {
+            let event = QuarantineEvent {
+                file_path: sstable.file_path().to_path_buf(),
+                gen_no: sstable.gen_no(),
+                num_errors: sstable.num_read_errors(),
+                last_error: format!("{:?}", result.as_ref().err()),
+            };
+            log::error!("Quarantined an SSTable: {:?}", event);
+            for event_listener in self.options.event_listeners.iter() {
+                event_listener.on_quarantine(&event);
+            }
+        }
+        result
+    }
+
+    /// The number of bytes the pending compactions would write.
+    pub fn compaction_backlog(&self) -> Result<usize> {
+        Ok(self
+            .plan_compaction()?
+            .iter()
+            .map(|plan| plan.estimated_output_size)
+            .sum())
+    }
+
+    /// The current value of the property named as in `stats::PROPERTY_NAMES`, or None if there
+    /// is no such property.
+    pub fn property(&self, name: &str) -> Result<Option<String>> {
+        let value = match name {
+            stats::PROPERTY_MEMTABLE_BYTES => {
+                let mut num_bytes = self.memtable.data_size();
+                if let Some(ro_memtable) = self.ro_memtable.as_ref() {
+                    num_bytes += ro_memtable.data_size();
+                }
+                num_bytes.to_string()
+            }
+            stats::PROPERTY_WAL_BYTES => {
+                let backend = self.options.backend.as_ref();
+                let mut num_bytes = backend.file_size(self.memtable.log_path())?;
+                if let Some(ro_memtable) = self.ro_memtable.as_ref() {
+                    num_bytes += backend.file_size(ro_memtable.log_path())?;
+                }
+                num_bytes.to_string()
+            }
+            stats::PROPERTY_NUM_GENERATIONS => self.num_generations().to_string(),
+            stats::PROPERTY_GENERATION_BYTES => (0..self.num_generations())
+                .map(|gen_no| {
+                    self.generation(gen_no)
+                        .iter()
+                        .map(|sstable| sstable.file_size())
+                        .sum::<usize>()
+                        .to_string()
+                })
+                .collect::<Vec<_>>()
+                .join(","),
+            stats::PROPERTY_NUM_PENDING_COMPACTIONS => self.plan_compaction()?.len().to_string(),
+            stats::PROPERTY_COMPACTION_BACKLOG_BYTES => self.compaction_backlog()?.to_string(),
+            stats::PROPERTY_NUM_DELETIONS => self
+                .sstables
+                .iter()
+                .map(|sstable| sstable.properties().num_deletions)
+                .sum::<usize>()
+                .to_string(),
+            stats::PROPERTY_TOTAL_DISK_BYTES => self.disk_usage()?.to_string(),
+            _ => return Ok(None),
+        };
+        Ok(Some(value))
+    }
+
+    /// The number of bytes of the files in the data folder and the cold folder, and in their
+    /// trash. The files removed while being listed, e.g. by a compaction, are skipped.
+    fn disk_usage(&self) -> Result<u64> {
+        let backend = self.options.backend.as_ref();
+        let mut folder_paths = vec![self.folder_path.clone()];
+        folder_paths.extend(self.options.cold_folder_path.clone());
+        let mut num_bytes = 0;
+        for folder_path in folder_paths {
+            for folder_path in [Trash::gen_trash_path(&folder_path), folder_path] {
+                if !backend.exists(&folder_path) {
+                    continue;
+                }
+                for file_path in backend.list_files(&folder_path)? {
+                    num_bytes += backend.file_size(&file_path).unwrap_or(0);
+                }
+            }
+        }
+        Ok(num_bytes)
+    }
+
+    /// Plan the flush and the merge that would run if the compaction daemon woke up now.
+    pub fn plan_compaction(&self) -> Result<Vec<CompactionPlan>> {
+        let mut plans = Vec::new();
+        plans.extend(compaction::plan_flush(self)?);
+        plans.extend(compaction::plan_merge(self));
+        Ok(plans)
+    }
+
+    /// Estimate the records with keys in [start, end) without scanning, by counting those in the
+    /// Memtables and locating the range in the SSTable indexes.
+    pub fn estimate_range(&self, start: &str, end: &str) -> Result<RangeEstimate> {
+        self.estimate(&(start.to_owned()..end.to_owned()))
+    }
+
+    /// Estimate the records with keys in the range like `estimate_range`.
+    pub fn estimate<R: RangeBounds<String>>(&self, range: &R) -> Result<RangeEstimate> {
+        let mut estimate = RangeEstimate::default();
+        if self.options.comparator.is_empty_range(range) {
+            return Ok(estimate);
+        }
+        let mut add_memtable = |memtable: &Memtable| {
+            for (key, record) in memtable.range(range) {
+                estimate.num_keys += 1;
+                estimate.num_bytes += key.len() + record.len();
+            }
+        };
+        add_memtable(&self.memtable);
+        if let Some(ro_memtable) = self.ro_memtable.as_ref() {
+            add_memtable(ro_memtable);
+        }
+        for sstable in self.sstables.iter() {
+            let sstable_estimate = sstable.estimate(range);
+            estimate.num_keys += sstable_estimate.num_keys;
+            estimate.num_bytes += sstable_estimate.num_bytes;
+        }
+        Ok(estimate)
+    }
+
+    /// Describe the live SSTables in increasing generations.
+    pub fn describe_sstables(&self) -> Vec<SSTableDescription> {
+        self.sstables
+            .iter()
+            .map(|sstable| SSTableDescription {
+                file_path: sstable.file_path().to_path_buf(),
+                gen_no: sstable.gen_no(),
+                epoch_no: sstable.epoch_no(),
+                file_size: sstable.file_size(),
+                key_range: sstable
+                    .key_range()
+                    .map(|(min_key, max_key)| (min_key.to_owned(), max_key.to_owned())),
+                num_entries: sstable.num_entries(),
+                is_quarantined: sstable.is_quarantined(),
+                properties: sstable.properties().clone(),
+            })
+            .collect()
+    }
+
+    pub fn gen_memtable_path(folder_path: &Path) -> PathBuf {
+        let mut path_buf = folder_path.to_path_buf();
         let mut rng = thread_rng();
         path_buf.push(format!("memtable_{}.log", rng.gen::<u64>()));
         path_buf
     }
 
-    pub fn gen_sstable_path(folder_path: &PathBuf, gen_no: usize) -> PathBuf {
-        let mut path_buf = folder_path.clone();
+    /// The folder for the SSTables of the generation, which is the cold folder for the oldest
+    /// generations if configured.
+    pub fn sstable_folder_path(&self, gen_no: usize) -> &Path {
+        match self.options.cold_folder_path.as_ref() {
+            Some(cold_folder_path) if gen_no >= self.options.cold_generation_no => cold_folder_path,
+            _ => &self.folder_path,
+        }
+    }
+
+    pub fn gen_sstable_path(folder_path: &Path, gen_no: usize) -> PathBuf {
+        let mut path_buf = folder_path.to_path_buf();
         let mut rng = thread_rng();
         path_buf.push(format!("gen_{}_{}.sst", gen_no, rng.gen::<u64>()));
         path_buf
     }
 }
 
+/// The files of a catalog pinned as of a point in time, which stay readable however the catalog
+/// changes afterwards.
+pub(crate) struct PinnedFiles {
+    pub backend: Arc<dyn Backend>,
+
+    /// The manifest as of the pinning, to be pointed at the copies of the files.
+    pub manifest: Manifest,
+
+    pub sstables: Vec<Arc<SSTable>>,
+
+    /// The name, a handle and the size as of the pinning of each Memtable log, the one being
+    /// flushed first if any.
+    logs: Vec<(String, Box<dyn BackendFile>, u64)>,
+}
+
+impl PinnedFiles {
+    /// The paths of the blob files the SSTables refer to, each once.
+    pub fn blob_file_paths(&self) -> Vec<PathBuf> {
+        let mut blob_file_paths = self
+            .sstables
+            .iter()
+            .flat_map(|sstable| sstable.blob_file_paths())
+            .collect::<Vec<_>>();
+        blob_file_paths.sort();
+        blob_file_paths.dedup();
+        blob_file_paths
+    }
+
+    /// Copy the Memtable logs into the folder up to the pinning, naming the copies in the manifest,
+    /// and return their number.
+    pub fn copy_logs(&mut self, folder_path: &Path) -> Result<usize> {
+        let num_logs = self.logs.len();
+        let mut log_names = Vec::with_capacity(num_logs);
+        for (log_name, mut log_file, log_size) in std::mem::take(&mut self.logs) {
+            let mut target_file = self.backend.create_new(&folder_path.join(&log_name))?;
+            log_file.seek(SeekFrom::Start(0))?;
+            std::io::copy(&mut log_file.take(log_size), &mut target_file)?;
+            target_file.sync()?;
+            log_names.push(log_name);
+        }
+        self.manifest.active_log_name = log_names.pop();
+        self.manifest.flushing_log_name = log_names.pop();
+        Ok(num_logs)
+    }
+}
+
+/// A reader and writer of the catalog, owned by a single thread.
+///
+/// A viewer always observes its own prior writes. Reads wait until the last write of the viewer
+/// is visible, and flushes move data from the read-write Memtable to the read-only one and then
+/// to an SSTable under the catalog write lock, so the data is in exactly one of these layers
+/// whenever a reader looks.
 pub struct CatalogViewer {
     /// The underlying Catalog.
     catalog: Arc<RwLock<Catalog>>,
 
-    /// The SSTable views of the last synced epoch.
-    sstable_views: Vec<SSTableView>,
+    /// The SSTable views of the last synced epoch, indexed by generation and opened on demand.
+    sstable_views: Vec<Option<SSTableView>>,
+
+    /// The sequence number of the last write by this viewer.
+    last_sequence_no: u64,
 }
 
 impl CatalogViewer {
     pub fn new(catalog: Arc<RwLock<Catalog>>) -> Result<CatalogViewer> {
-        let mut sstable_views = Vec::new();
-        {
-            let catalog = catalog.read()?;
-            sstable_views.reserve(catalog.sstables.len());
-            for sstable in &catalog.sstables {
-                sstable_views.push(SSTableView::new(sstable.clone())?);
-            }
-        }
+        let sstable_views = Vec::new();
         Ok(Self {
             catalog,
             sstable_views,
+            last_sequence_no: 0,
         })
     }
 
+    /// The sequence number of the last write by this viewer, or zero if there is none.
+    pub fn last_sequence_no(&self) -> u64 {
+        self.last_sequence_no
+    }
+
     pub fn get(&mut self, key: &str) -> Result<Option<String>> {
-        let catalog = self.catalog.read()?;
+        self.get_with_deadline(key, None)
+    }
 
-        // Step 1. Try to read the read-write Memtable.
-        if let Some(record) = catalog.memtable.read()?.get(key)? {
-            return record.into();
+    /// Get the value of the key, giving up with DeadlineExceeded once the deadline, if any, passes
+    /// before the remaining SSTables are looked up.
+    pub fn get_with_deadline(
+        &mut self,
+        key: &str,
+        deadline: Option<Instant>,
+    ) -> Result<Option<String>> {
+        Ok(self
+            .get_with_timestamp(key, deadline)?
+            .map(|(value, _)| value))
+    }
+
+    /// Get the value of the key along with when it was last written, in milliseconds since the
+    /// Unix epoch, which is unknown for the records written before the timestamps were kept, or
+    /// for the values from the read fallback.
+    pub fn get_with_timestamp(
+        &mut self,
+        key: &str,
+        deadline: Option<Instant>,
+    ) -> Result<Option<(String, Option<u64>)>> {
+        let record = {
+            let catalog = self.catalog.read()?;
+            catalog.wait_until_visible(self.last_sequence_no)?;
+
+            // Step 1. Try to read the read-write Memtable.
+            let memtable_record = catalog.memtable.get_timed(key)?;
+            Self::lookup_below(
+                &catalog,
+                memtable_record,
+                &mut self.sstable_views,
+                key,
+                deadline,
+            )?
+        };
+        match record {
+            Some(TimedRecord {
+                record: Record::Value(value) | Record::ExpiringValue { value, .. },
+                timestamp_ms,
+                ..
+            }) => Ok(Some((value, timestamp_ms))),
+            // The lookup has applied any merge operands and expired the value if due.
+            Some(TimedRecord {
+                record: Record::Deleted | Record::Merge(_),
+                ..
+            }) => Ok(None),
+            None => Ok(self.get_from_fallback(key)?.map(|value| (value, None))),
         }
+    }
 
-        // Step 2. Try to read the read-only Memtable if it exists.
-        if let Some(memtable) = catalog.ro_memtable.as_ref() {
-            if let Some(record) = memtable.get(key)? {
-                return record.into();
+    /// Whether the key has a value as of now, which stops at its newest record without decoding
+    /// the value, e.g. to check for large values. The SSTables ruled out by their key ranges or
+    /// prefix Bloom filters are skipped like for the gets.
+    ///
+    /// Merge operands on top fall back to a full get, since whether they leave a value depends on
+    /// the records under them.
+    pub fn contains_key(&mut self, key: &str) -> Result<bool> {
+        let kind = {
+            let catalog = self.catalog.read()?;
+            catalog.wait_until_visible(self.last_sequence_no)?;
+            Self::lookup_kind(&catalog, &mut self.sstable_views, key)?
+        };
+        match kind.map(|kind| kind.is_present(utils::now_ms())) {
+            Some(Some(is_present)) => Ok(is_present),
+            Some(None) => Ok(self.get(key)?.is_some()),
+            None => Ok(self.get_from_fallback(key)?.is_some()),
+        }
+    }
+
+    /// Find the kind of the newest record of the key, from the read-write Memtable down, and count
+    /// the layers touched on the way like `lookup_below`.
+    fn lookup_kind(
+        catalog: &Catalog,
+        sstable_views: &mut Vec<Option<SSTableView>>,
+        key: &str,
+    ) -> Result<Option<RecordKind>> {
+        let mut num_layers = 1;
+        let mut kind = catalog.memtable.get_kind(key)?;
+        'lookup: {
+            if kind.is_some() {
+                break 'lookup;
+            }
+            if let Some(memtable) = catalog.ro_memtable.as_ref() {
+                num_layers += 1;
+                kind = memtable.get_kind(key)?;
+                if kind.is_some() {
+                    break 'lookup;
+                }
+            }
+            for (position, sstable) in catalog.sstables.iter().enumerate() {
+                if !sstable.may_contain(key) {
+                    continue;
+                }
+                if sstable.is_quarantined() {
+                    return Err(NaiveError::Quarantined {
+                        file_path: sstable.file_path().to_path_buf(),
+                    });
+                }
+                num_layers += 1;
+                let result = Self::sstable_view(sstable_views, position, sstable)
+                    .and_then(|sstable_view| sstable_view.get_kind(key));
+                kind = catalog.check_sstable_read(sstable, result)?;
+                if kind.is_some() {
+                    break 'lookup;
+                }
             }
         }
+        catalog.read_amplification.record(num_layers);
+        Ok(kind)
+    }
+
+    /// Get the value of a key never written locally from the read fallback, if any, and backfill
+    /// it as the options say.
+    fn get_from_fallback(&mut self, key: &str) -> Result<Option<String>> {
+        let (read_fallback, backfill_reads) = {
+            let catalog = self.catalog.read()?;
+            (
+                catalog.options.read_fallback.clone(),
+                catalog.options.backfill_reads && !catalog.is_replica,
+            )
+        };
+        let value = match read_fallback {
+            Some(read_fallback) => read_fallback.get(key)?,
+            None => None,
+        };
+        if let (Some(value), true) = (value.as_ref(), backfill_reads) {
+            // The views are moved out for the lookup, which runs while the viewer is borrowed.
+            let mut sstable_views = std::mem::take(&mut self.sstable_views);
+            let key_hashes = [memtable::key_hash(key)];
+            let result = self.try_write_to_memtable(&key_hashes, |catalog, memtable| {
+                // A write to the key since the lookup wins over the value from the fallback.
+                let memtable_record = memtable.get_latest_timed(key)?;
+                if Self::lookup_below(catalog, memtable_record, &mut sstable_views, key, None)?
+                    .is_some()
+                {
+                    return Ok(None);
+                }
+                memtable.set(key.to_owned(), value.clone()).map(Some)
+            });
+            self.sstable_views = sstable_views;
+            result?;
+        }
+        Ok(value)
+    }
 
-        // Step 3. Try to read the SSTableView's in sequence.
-        for (gen_no, sstable) in catalog.sstables.iter().enumerate() {
-            // SSTableView's are updated on demand.
-            if self.sstable_views.len() == gen_no {
-                self.sstable_views.push(SSTableView::new(sstable.clone())?);
-            } else if self.sstable_views[gen_no].epoch_no() != sstable.epoch_no() {
-                self.sstable_views[gen_no] = SSTableView::new(sstable.clone())?;
+    /// Look up the key in the read-only Memtable and then the SSTables, unless the read-write
+    /// Memtable already has the record, and count the layers touched on the way. The merge
+    /// operands found are applied to the older records, so the record returned is never a merge,
+    /// and an expired value is returned as a deletion.
+    fn lookup_below(
+        catalog: &Catalog,
+        memtable_record: Option<TimedRecord>,
+        sstable_views: &mut Vec<Option<SSTableView>>,
+        key: &str,
+        deadline: Option<Instant>,
+    ) -> Result<Option<TimedRecord>> {
+        let merge_operator = catalog.options.merge_operator.as_deref();
+        let is_final = |record: &Option<TimedRecord>| {
+            record
+                .as_ref()
+                .is_some_and(|record| !matches!(record.record, Record::Merge(_)))
+        };
+        let stack = |record: Option<TimedRecord>, older_record| match record {
+            Some(record) => merge::stack_timed(key, record, older_record, merge_operator),
+            None => Ok(older_record),
+        };
+
+        // Count the layers touched, skipped SSTables excluded, on the way to the record.
+        let mut num_layers = 1;
+        let mut record = memtable_record;
+        'lookup: {
+            if is_final(&record) {
+                break 'lookup;
             }
-            if let Some(record) = self.sstable_views[gen_no].get(key)? {
-                return record.into();
+
+            // Step 2. Try to read the read-only Memtable if it exists.
+            if let Some(memtable) = catalog.ro_memtable.as_ref() {
+                num_layers += 1;
+                if let Some(older_record) = memtable.get_timed(key)? {
+                    record = Some(stack(record, older_record)?);
+                    if is_final(&record) {
+                        break 'lookup;
+                    }
+                }
+            }
+
+            // Step 3. Try to read the SSTableView's in sequence.
+            for (position, sstable) in catalog.sstables.iter().enumerate() {
+                // Skip the SSTable without opening its view if the key is out of its range.
+                if !sstable.may_contain(key) {
+                    continue;
+                }
+                if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                    return Err(NaiveError::DeadlineExceeded);
+                }
+                // Fail fast rather than read an SSTable known to be broken, which might hold
+                // the key.
+                if sstable.is_quarantined() {
+                    return Err(NaiveError::Quarantined {
+                        file_path: sstable.file_path().to_path_buf(),
+                    });
+                }
+                num_layers += 1;
+                let result = Self::sstable_view(sstable_views, position, sstable)
+                    .and_then(|sstable_view| sstable_view.get(key));
+                if let Some(older_record) = catalog.check_sstable_read(sstable, result)? {
+                    record = Some(stack(record, older_record)?);
+                    if is_final(&record) {
+                        break 'lookup;
+                    }
+                }
+            }
+        }
+        catalog.read_amplification.record(num_layers);
+        let now_ms = utils::now_ms();
+        record
+            .map(|record| {
+                let mut record = merge::resolve_timed(key, record, merge_operator)?;
+                record.record = record.record.expire(now_ms);
+                Ok(record)
+            })
+            .transpose()
+    }
+
+    /// Get the record of the key, deletions included, as an encoded `Command` along with where
+    /// it was found. The bytes of an SSTable record are forwarded as stored, while a record still
+    /// in a Memtable is encoded on the fly, since only its log keeps it encoded.
+    ///
+    /// The newest record is returned alone, so merge operands come back unapplied.
+    pub fn get_raw(&mut self, key: &str) -> Result<Option<RawRecord>> {
+        let catalog = self.catalog.read()?;
+        catalog.wait_until_visible(self.last_sequence_no)?;
+        let encode = |record: TimedRecord, source: RecordSource| -> Result<RawRecord> {
+            let command = record.record.to_stamped_command(
+                key.to_owned(),
+                record.sequence_no,
+                record.timestamp_ms,
+                catalog.options.checksum_records,
+            );
+            Ok(RawRecord {
+                bytes: command.write_to_bytes()?,
+                source,
+                location: None,
+            })
+        };
+
+        if let Some(record) = catalog.memtable.get_timed(key)? {
+            return encode(record, RecordSource::Memtable).map(Some);
+        }
+        if let Some(memtable) = catalog.ro_memtable.as_ref() {
+            if let Some(record) = memtable.get_timed(key)? {
+                return encode(record, RecordSource::ReadOnlyMemtable).map(Some);
+            }
+        }
+        for (position, sstable) in catalog.sstables.iter().enumerate() {
+            if !sstable.may_contain(key) {
+                continue;
+            }
+            if let Some((bytes, offset)) =
+                Self::sstable_view(&mut self.sstable_views, position, sstable)?.get_raw(key)?
+            {
+                return Ok(Some(RawRecord {
+                    bytes,
+                    source: RecordSource::SSTable {
+                        gen_no: sstable.gen_no(),
+                    },
+                    location: Some((sstable.file_path().to_path_buf(), offset)),
+                }));
             }
         }
         Ok(None)
     }
 
-    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+    /// Sample up to n keys, roughly uniformly at random with replacement, from the SSTables by
+    /// picking random chunks through their indexes rather than scanning them. The keys still in
+    /// the Memtables are not sampled, and neither are the deleted ones, so fewer keys may return.
+    pub fn sample_keys(&mut self, n: usize) -> Result<Vec<String>> {
         let catalog = self.catalog.read()?;
-        let result = catalog.memtable.write()?.set(key, value);
-        result
+        let num_entries = catalog
+            .sstables
+            .iter()
+            .map(|sstable| sstable.num_entries())
+            .collect::<Vec<_>>();
+        let sstable_distribution = match WeightedIndex::new(&num_entries) {
+            Ok(sstable_distribution) => sstable_distribution,
+            Err(_) => return Ok(Vec::new()), // All the SSTables are empty.
+        };
+        let mut rng = thread_rng();
+        let mut keys = Vec::with_capacity(n);
+        for _ in 0..n {
+            let position = sstable_distribution.sample(&mut rng);
+            let sstable = &catalog.sstables[position];
+            if let Some(key) = Self::sstable_view(&mut self.sstable_views, position, sstable)?
+                .sample_key(&mut rng)?
+            {
+                keys.push(key);
+            }
+        }
+        Ok(keys)
+    }
+
+    /// Get the view of the SSTable at the position among the live ones, which is updated on
+    /// demand.
+    fn sstable_view<'a>(
+        sstable_views: &'a mut Vec<Option<SSTableView>>,
+        position: usize,
+        sstable: &Arc<SSTable>,
+    ) -> Result<&'a mut SSTableView> {
+        if sstable_views.len() <= position {
+            sstable_views.resize_with(position + 1, || None);
+        }
+        let sstable_view = &mut sstable_views[position];
+        if sstable_view
+            .as_ref()
+            .is_none_or(|view| !view.is_of(sstable))
+        {
+            *sstable_view = Some(SSTableView::new(sstable.clone())?);
+        }
+        Ok(sstable_view.as_mut().unwrap())
+    }
+
+    /// Take a snapshot of the data as of now, which may be scanned many times.
+    pub fn snapshot(&self) -> Result<Snapshot> {
+        let catalog = self.catalog.read()?;
+        catalog.wait_until_visible(self.last_sequence_no)?;
+        Snapshot::new(&catalog)
+    }
+
+    /// Iterate over the key-value pairs in the range as of now, unaffected by later writes and
+    /// compactions.
+    pub fn scan<R: RangeBounds<String>>(&self, range: R) -> Result<ScanIterator> {
+        let catalog = self.catalog.read()?;
+        catalog.wait_until_visible(self.last_sequence_no)?;
+        Snapshot::new(&catalog)?.scan(range)
+    }
+
+    /// Iterate over the records in the range as of now, optionally including the deletions.
+    pub fn scan_entries<R: RangeBounds<String>>(
+        &self,
+        range: R,
+        options: &ScanOptions,
+    ) -> Result<EntryIterator> {
+        let catalog = self.catalog.read()?;
+        catalog.wait_until_visible(self.last_sequence_no)?;
+        Snapshot::new(&catalog)?.scan_entries(range, options)
     }
 
-    pub fn remove(&mut self, key: String) -> Result<()> {
+    /// Iterate over the key-value pairs whose keys start with the prefix as of now.
+    pub fn scan_prefix(&self, prefix: &str) -> Result<ScanIterator> {
         let catalog = self.catalog.read()?;
-        let result = catalog.memtable.write()?.remove(key);
+        catalog.wait_until_visible(self.last_sequence_no)?;
+        Snapshot::new(&catalog)?.scan_prefix(prefix)
+    }
+
+    /// Describe the live SSTables in increasing generations.
+    pub fn describe(&self) -> Result<Vec<SSTableDescription>> {
+        Ok(self.catalog.read()?.describe_sstables())
+    }
+
+    /// Estimate the number of keys and bytes in [start, end), e.g. for choosing between a scan
+    /// and point lookups. Keys overwritten across layers are counted more than once.
+    pub fn estimate_range(&self, start: &str, end: &str) -> Result<RangeEstimate> {
+        self.catalog.read()?.estimate_range(start, end)
+    }
+
+    /// The approximate number of bytes the keys in the range take up on disk and in memory, e.g.
+    /// for monitoring the growth of a prefix or planning where to split a shard. Keys overwritten
+    /// or deleted but not yet compacted away are counted as well.
+    pub fn approximate_size<R: RangeBounds<String>>(&self, range: R) -> Result<usize> {
+        Ok(self.catalog.read()?.estimate(&range)?.num_bytes)
+    }
+
+    /// The approximate number of keys, counted from the SSTable indexes and the Memtables without
+    /// scanning, like `approximate_size`.
+    pub fn approximate_key_count(&self) -> Result<usize> {
+        Ok(self.catalog.read()?.estimate(&(..))?.num_keys)
+    }
+
+    /// Plan the next compactions without running them.
+    pub fn plan_compaction(&self) -> Result<Vec<CompactionPlan>> {
+        self.catalog.read()?.plan_compaction()
+    }
+
+    /// The current value of the property of the name, e.g. `stats::PROPERTY_WAL_BYTES`, or None
+    /// if there is no such property.
+    pub fn property(&self, name: &str) -> Result<Option<String>> {
+        self.catalog.read()?.property(name)
+    }
+
+    pub fn stats(&self) -> Result<Stats> {
+        Ok(self.catalog.read()?.stats())
+    }
+
+    /// Watch the keys starting with the prefix like `NaiveKV::watch`, but in the column family of
+    /// the viewer.
+    pub fn watch(&self, prefix: &str) -> Result<Receiver<ChangeEvent>> {
+        self.catalog.read()?.watchers().add(prefix)
+    }
+
+    pub fn set(&mut self, key: String, value: String) -> Result<WriteReceipt> {
+        self.write_to_memtable(&[memtable::key_hash(&key)], |_, memtable| {
+            memtable.set(key, value)
+        })
+    }
+
+    pub fn remove(&mut self, key: String) -> Result<WriteReceipt> {
+        self.write_to_memtable(&[memtable::key_hash(&key)], |_, memtable| {
+            memtable.remove(key)
+        })
+    }
+
+    pub fn write(&mut self, batch: &WriteBatch) -> Result<WriteReceipt> {
+        self.write_to_memtable(&batch.key_hashes(), |_, memtable| {
+            memtable.write_batch(batch)
+        })
+    }
+
+    /// Remove the keys in a single batch and tell which of them had a value until then.
+    ///
+    /// The keys are looked up and removed under their Memtable shards like `update` does, so that
+    /// no other write to them can come in between. Nothing is removed if the lookups run past the
+    /// deadline.
+    pub fn remove_all(&mut self, keys: &[String], deadline: Option<Instant>) -> Result<Vec<bool>> {
+        // The read fallback is not consulted under the Memtable shards, but ahead of them, in
+        // case the keys turn out never written locally.
+        let fallback_hits = if self.catalog.read()?.options.read_fallback.is_some() {
+            keys.iter()
+                .map(|key| Ok(self.get_with_deadline(key, deadline)?.is_some()))
+                .collect::<Result<Vec<_>>>()?
+        } else {
+            vec![false; keys.len()]
+        };
+        let mut batch = WriteBatch::new();
+        for key in keys.iter() {
+            batch.remove(key.clone());
+        }
+        let mut were_present = Vec::with_capacity(keys.len());
+        // The views are moved out for the lookups, which run while the viewer is borrowed.
+        let mut sstable_views = std::mem::take(&mut self.sstable_views);
+        let result = self.write_to_memtable(&batch.key_hashes(), |catalog, memtable| {
+            for (key, fallback_hit) in keys.iter().zip(fallback_hits) {
+                let memtable_record = memtable.get_latest_timed(key)?;
+                let record = Self::lookup_below(
+                    catalog,
+                    memtable_record,
+                    &mut sstable_views,
+                    key,
+                    deadline,
+                )?;
+                were_present.push(match record.map(|timed_record| timed_record.record) {
+                    Some(Record::Value(_) | Record::ExpiringValue { .. }) => true,
+                    Some(Record::Deleted | Record::Merge(_)) => false,
+                    None => fallback_hit,
+                });
+            }
+            memtable.write_batch(&batch)
+        });
+        self.sstable_views = sstable_views;
+        result?;
+        Ok(were_present)
+    }
+
+    /// Set a value for the key which counts as missing once the TTL has passed, until it is
+    /// overwritten. The expired values are dropped when compacted into the oldest generation.
+    pub fn set_with_ttl(
+        &mut self,
+        key: String,
+        value: String,
+        ttl: Duration,
+    ) -> Result<WriteReceipt> {
+        let expires_at_ms = utils::now_ms().saturating_add(ttl.as_millis() as u64);
+        self.write_to_memtable(&[memtable::key_hash(&key)], |_, memtable| {
+            memtable.set_expiring(key, value, expires_at_ms)
+        })
+    }
+
+    /// Write a merge operand for the key, which the merge operator in the options applies to the
+    /// value on reads and compactions.
+    pub fn merge(&mut self, key: String, operand: String) -> Result<WriteReceipt> {
+        self.write_to_memtable(&[memtable::key_hash(&key)], |_, memtable| {
+            memtable.merge(key, operand)
+        })
+    }
+
+    /// Add the delta to the integer value of the key, taking a missing key as zero, and return
+    /// the new value, which expires along with the old one, if at all.
+    ///
+    /// The value is read and written under the Memtable shard of the key like `update` does.
+    pub fn increment(&mut self, key: String, delta: i64) -> Result<i64> {
+        let mut new_value = 0;
+        self.update(key, |old_value| {
+            let (old_value, expires_at_ms) = old_value.unwrap_or_else(|| ("0".to_owned(), None));
+            let old_value = old_value.parse::<i64>().map_err(|_| {
+                NaiveError::InvalidValue(format!("{:?} is not an integer", old_value))
+            })?;
+            new_value = old_value.checked_add(delta).ok_or_else(|| {
+                NaiveError::InvalidValue(format!("{} + {} overflows", old_value, delta))
+            })?;
+            Ok(Some((new_value.to_string(), expires_at_ms)))
+        })?;
+        Ok(new_value)
+    }
+
+    /// Set the value only if the key is missing, e.g. to take a lock or to claim a name, and
+    /// return None without writing anything if the key has a value.
+    ///
+    /// The key is looked up and written under the Memtable shard of the key like `update` does.
+    pub fn set_nx(&mut self, key: String, value: String) -> Result<Option<WriteReceipt>> {
+        self.set_if(key, value, false)
+    }
+
+    /// Set the value only if the key has one, e.g. to update a record without bringing it back
+    /// once removed, and return None without writing anything if the key is missing.
+    ///
+    /// The key is looked up and written under the Memtable shard of the key like `update` does.
+    pub fn set_xx(&mut self, key: String, value: String) -> Result<Option<WriteReceipt>> {
+        self.set_if(key, value, true)
+    }
+
+    /// Set the value if the key has a value exactly when it should, dropping any TTL of the old
+    /// value like `set` does.
+    fn set_if(
+        &mut self,
+        key: String,
+        value: String,
+        is_present: bool,
+    ) -> Result<Option<WriteReceipt>> {
+        self.update(key, |old_value| {
+            Ok((old_value.is_some() == is_present).then_some((value, None)))
+        })
+    }
+
+    /// Set the value and return the one it replaces, if any, e.g. to take a counter and reset it
+    /// at once. Any TTL of the old value is dropped like `set` does.
+    ///
+    /// The value is read and written under the Memtable shard of the key like `update` does.
+    pub fn get_set(&mut self, key: String, value: String) -> Result<Option<String>> {
+        let mut old_value = None;
+        self.update(key, |value_read| {
+            old_value = value_read.map(|(value_read, _)| value_read);
+            Ok(Some((value, None)))
+        })?;
+        Ok(old_value)
+    }
+
+    /// Append the suffix to the value of the key, taking a missing key as empty, and return the
+    /// length of the new value in bytes. The new value is logged as a single record and expires
+    /// along with the old one, if at all.
+    ///
+    /// The value is read and written under the Memtable shard of the key like `update` does, so
+    /// that the concurrent appends never lose one another, unlike a get followed by a set.
+    pub fn append(&mut self, key: String, suffix: &str) -> Result<usize> {
+        let mut new_len = 0;
+        self.update(key, |old_value| {
+            let (mut value, expires_at_ms) = old_value.unwrap_or_default();
+            value.push_str(suffix);
+            new_len = value.len();
+            Ok(Some((value, expires_at_ms)))
+        })?;
+        Ok(new_len)
+    }
+
+    /// Look up the value of the key, along with when it expires if ever, and set the value the
+    /// update makes of it, if any, expiring as the update says.
+    ///
+    /// The value is read and written under the Memtable shard of the key, so that no other write
+    /// to the key can come in between, at the cost of blocking the writes to the keys of the shard
+    /// during the lookup.
+    fn update(
+        &mut self,
+        key: String,
+        update: impl FnOnce(Option<(String, Option<u64>)>) -> Result<Option<(String, Option<u64>)>>,
+    ) -> Result<Option<WriteReceipt>> {
+        // The read fallback is not consulted under the Memtable shard, but ahead of it, in case the
+        // key turns out never written locally.
+        let fallback_value = if self.catalog.read()?.options.read_fallback.is_some() {
+            self.get(&key)?
+        } else {
+            None
+        };
+        // The views are moved out for the lookup, which runs while the viewer is borrowed.
+        let mut sstable_views = std::mem::take(&mut self.sstable_views);
+        let key_hashes = [memtable::key_hash(&key)];
+        let result = self.try_write_to_memtable(&key_hashes, |catalog, memtable| {
+            let memtable_record = memtable.get_latest_timed(&key)?;
+            let record =
+                Self::lookup_below(catalog, memtable_record, &mut sstable_views, &key, None)?;
+            let old_value = match record.map(|timed_record| timed_record.record) {
+                Some(Record::Value(value)) => Some((value, None)),
+                Some(Record::ExpiringValue {
+                    value,
+                    expires_at_ms,
+                }) => Some((value, Some(expires_at_ms))),
+                Some(Record::Deleted | Record::Merge(_)) => None,
+                None => fallback_value.map(|value| (value, None)),
+            };
+            match update(old_value)? {
+                Some((value, Some(expires_at_ms))) => {
+                    memtable.set_expiring(key, value, expires_at_ms).map(Some)
+                }
+                Some((value, None)) => memtable.set(key, value).map(Some),
+                None => Ok(None),
+            }
+        });
+        self.sstable_views = sstable_views;
         result
     }
+
+    fn write_to_memtable(
+        &mut self,
+        key_hashes: &[u64],
+        write: impl FnOnce(&Catalog, &mut MemtableWriter) -> Result<usize>,
+    ) -> Result<WriteReceipt> {
+        self.try_write_to_memtable(key_hashes, |catalog, memtable| {
+            write(catalog, memtable).map(Some)
+        })
+        .map(Option::unwrap)
+    }
+
+    /// Write to the Memtable like `write_to_memtable`, unless the write gives up with None, in
+    /// which case no sequence number is taken.
+    fn try_write_to_memtable(
+        &mut self,
+        key_hashes: &[u64],
+        write: impl FnOnce(&Catalog, &mut MemtableWriter) -> Result<Option<usize>>,
+    ) -> Result<Option<WriteReceipt>> {
+        let mut soft_limit_events = Vec::new();
+        let mut event_listeners = Vec::new();
+        let receipt = {
+            let catalog = self.catalog.read()?;
+            if catalog.is_replica {
+                return Err(NaiveError::ReadOnly);
+            }
+            let mut memtable = catalog.memtable.writer(key_hashes)?;
+            let threshold = catalog.options.memtable_compaction_threshold;
+            let old_data_size = memtable.data_size();
+
+            // The sequence number is taken as the write is appended to the log, and is published
+            // only after the writes before it are applied as well.
+            let wal_bytes = match write(&catalog, &mut memtable)? {
+                Some(wal_bytes) => wal_bytes,
+                None => return Ok(None),
+            };
+            let sequence_no = memtable.sequence_no().ok_or(NaiveError::Unknown)?;
+            catalog.user_bytes.fetch_add(wal_bytes, Ordering::SeqCst);
+            self.last_sequence_no = sequence_no;
+
+            let data_size = memtable.data_size();
+            let soft_limit =
+                (threshold as f64 * catalog.options.memtable_soft_limit_ratio) as usize;
+            if old_data_size < soft_limit && data_size >= soft_limit {
+                soft_limit_events.push(SoftLimitEvent::MemtableNearlyFull {
+                    data_size,
+                    threshold,
+                });
+            }
+            let flush_triggered = old_data_size < threshold && data_size >= threshold;
+            if flush_triggered && catalog.ro_memtable.is_some() {
+                soft_limit_events.push(SoftLimitEvent::FlushBehind {
+                    data_size,
+                    threshold,
+                });
+            }
+            for event in soft_limit_events.iter() {
+                event_listeners = catalog.record_soft_limit(event)?;
+            }
+            WriteReceipt {
+                sequence_no,
+                wal_bytes,
+                flush_triggered,
+            }
+        };
+
+        // The Memtable writer and the catalog are released by now.
+        for event in soft_limit_events.iter() {
+            listener::notify_soft_limit(&event_listeners, event);
+        }
+        Ok(Some(receipt))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::LocalBackend;
+    use crate::utils;
+    use std::time::{Duration, SystemTime};
+
+    #[derive(Debug, Default)]
+    struct RepairRecorder {
+        events: Mutex<Vec<RepairEvent>>,
+    }
+
+    impl EventListener for RepairRecorder {
+        fn on_repair(&self, event: &RepairEvent) {
+            self.events.lock().unwrap().push(event.clone());
+        }
+    }
+
+    /// Write a file into the folder as if it were last modified the given seconds after the epoch.
+    fn touch(file_path: &Path, secs: u64) {
+        let file = std::fs::File::options()
+            .write(true)
+            .open(file_path)
+            .unwrap();
+        file.set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(secs))
+            .unwrap();
+    }
+
+    #[test]
+    fn test_repair_on_open() {
+        let folder_path = PathBuf::from("/tmp/naive_kv/test_repair_on_open/");
+        let scratch_log_path = PathBuf::from("/tmp/naive_kv/test_repair_on_open.log");
+        let linked_path = PathBuf::from("/tmp/naive_kv/test_repair_on_open.sst");
+        let _ = std::fs::remove_dir_all(&folder_path);
+        let _ = std::fs::remove_file(&linked_path);
+        std::fs::create_dir_all(&folder_path).unwrap();
+        let options = Options::default();
+
+        // Two SSTables of generation 0 as if a compaction crashed, and a gap before generation 3.
+        let mut sstable_paths = Vec::new();
+        for (gen_no, writes, secs) in [
+            (0, vec![("a", "old")], 100),
+            (0, vec![("a", "new"), ("b", "1")], 200),
+            (3, vec![("c", "3")], 50),
+        ] {
+            utils::try_remove_file(&scratch_log_path).unwrap();
+            let memtable = Memtable::open(scratch_log_path.clone(), &options).unwrap();
+            for (key, value) in writes {
+                memtable.set(key.to_owned(), value.to_owned(), 0).unwrap();
+            }
+            memtable.deprecate().unwrap();
+            let sstable_path = Catalog::gen_sstable_path(&folder_path, gen_no);
+            SSTable::create(
+                sstable_path.clone(),
+                Some(&memtable),
+                &[],
+                true,
+                gen_no,
+                1,
+                &options,
+            )
+            .unwrap();
+            touch(&sstable_path, secs);
+            sstable_paths.push(sstable_path);
+        }
+
+        // Two Memtable logs, the newer one overwriting the older one.
+        let mut log_paths = Vec::new();
+        for (writes, secs) in [
+            (vec![("d", Some("1")), ("e", Some("1"))], 300),
+            (vec![("d", Some("2")), ("e", None)], 400),
+        ] {
+            let log_path = Catalog::gen_memtable_path(&folder_path);
+            let memtable = Memtable::open(log_path.clone(), &options).unwrap();
+            for (key, value) in writes {
+                match value {
+                    Some(value) => memtable.set(key.to_owned(), value.to_owned(), 0).unwrap(),
+                    None => memtable.remove(key.to_owned(), 0).unwrap(),
+                };
+            }
+            drop(memtable);
+            touch(&log_path, secs);
+            log_paths.push(log_path);
+        }
+
+        assert!(matches!(
+            Catalog::open(folder_path.clone(), options.clone()),
+            Err(NaiveError::InvalidData)
+        ));
+
+        // A checkpoint sharing an SSTable to be renumbered through a hard link.
+        std::fs::hard_link(&sstable_paths[0], &linked_path).unwrap();
+        let linked_bytes = std::fs::read(&linked_path).unwrap();
+
+        let recorder = Arc::new(RepairRecorder::default());
+        let options = Options {
+            repair_on_open: true,
+            event_listeners: vec![recorder.clone()],
+            ..options
+        };
+        let catalog = Catalog::open(folder_path.clone(), options.clone()).unwrap();
+        let gen_nos = catalog
+            .sstables
+            .iter()
+            .map(|sstable| (sstable.file_path().to_owned(), sstable.gen_no()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            gen_nos,
+            vec![
+                (sstable_paths[1].clone(), 0),
+                (sstable_paths[0].clone(), 1),
+                (sstable_paths[2].clone(), 2),
+            ]
+        );
+        let events = recorder.events.lock().unwrap().clone();
+        assert_eq!(events.len(), 3);
+        assert_eq!(
+            events[0],
+            RepairEvent::RenumberedSSTable {
+                file_path: sstable_paths[0].clone(),
+                old_gen_no: 0,
+                new_gen_no: 1,
+            }
+        );
+        assert_eq!(
+            events[1],
+            RepairEvent::RenumberedSSTable {
+                file_path: sstable_paths[2].clone(),
+                old_gen_no: 3,
+                new_gen_no: 2,
+            }
+        );
+        assert!(matches!(
+            &events[2],
+            RepairEvent::MergedMemtableLogs { log_paths: merged, .. } if *merged == log_paths
+        ));
+        assert!(log_paths.iter().all(|log_path| !log_path.exists()));
+        assert_eq!(std::fs::read(&linked_path).unwrap(), linked_bytes);
+
+        let mut catalog_viewer = CatalogViewer::new(Arc::new(RwLock::new(catalog))).unwrap();
+        assert_eq!(catalog_viewer.get("a").unwrap(), Some("new".to_owned()));
+        assert_eq!(catalog_viewer.get("b").unwrap(), Some("1".to_owned()));
+        assert_eq!(catalog_viewer.get("c").unwrap(), Some("3".to_owned()));
+        assert_eq!(catalog_viewer.get("d").unwrap(), Some("2".to_owned()));
+        assert_eq!(catalog_viewer.get("e").unwrap(), None);
+        drop(catalog_viewer);
+
+        // The repaired folder opens without repair.
+        let options = Options {
+            repair_on_open: false,
+            ..options
+        };
+        assert!(Catalog::open(folder_path, options).is_ok());
+    }
+
+    #[test]
+    fn test_resume_flush_on_open() {
+        let folder_path = PathBuf::from("/tmp/naive_kv/test_resume_flush_on_open/");
+        let _ = std::fs::remove_dir_all(&folder_path);
+        std::fs::create_dir_all(&folder_path).unwrap();
+
+        // A crash in the middle of a flush leaves the flushing log, the active log and an empty
+        // log created for the next swap.
+        let options = Options::default();
+        let flushing_log_path = Catalog::gen_memtable_path(&folder_path);
+        let flushing_memtable = Memtable::open(flushing_log_path.clone(), &options).unwrap();
+        flushing_memtable
+            .set("a".to_owned(), "1".to_owned(), 1)
+            .unwrap();
+        flushing_memtable
+            .set("b".to_owned(), "1".to_owned(), 2)
+            .unwrap();
+        let active_log_path = Catalog::gen_memtable_path(&folder_path);
+        let active_memtable = Memtable::open(active_log_path.clone(), &options).unwrap();
+        active_memtable
+            .set("a".to_owned(), "2".to_owned(), 3)
+            .unwrap();
+        let empty_log_path = Catalog::gen_memtable_path(&folder_path);
+        drop(Memtable::open(empty_log_path.clone(), &options).unwrap());
+        drop(flushing_memtable);
+        drop(active_memtable);
+        Manifest {
+            active_log_name: Some(Catalog::file_name(&active_log_path)),
+            flushing_log_name: Some(Catalog::file_name(&flushing_log_path)),
+            ..Manifest::default()
+        }
+        .save(&LocalBackend, &folder_path)
+        .unwrap();
+
+        // The logs are replayed in the recorded order without any repair.
+        let catalog = Catalog::open(folder_path.clone(), options).unwrap();
+        let log_path = catalog.memtable.log_path().to_owned();
+        let mut catalog_viewer = CatalogViewer::new(Arc::new(RwLock::new(catalog))).unwrap();
+        assert_eq!(catalog_viewer.get("a").unwrap(), Some("2".to_owned()));
+        assert_eq!(catalog_viewer.get("b").unwrap(), Some("1".to_owned()));
+        for old_log_path in [&flushing_log_path, &active_log_path, &empty_log_path] {
+            assert!(!old_log_path.exists());
+        }
+        let manifest = Manifest::load(&LocalBackend, &folder_path)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            manifest.active_log_name,
+            Some(Catalog::file_name(&log_path))
+        );
+        assert_eq!(manifest.flushing_log_name, None);
+    }
+
+    #[test]
+    fn test_sequence_gap_on_open() {
+        let folder_path = PathBuf::from("/tmp/naive_kv/test_sequence_gap_on_open/");
+        let _ = std::fs::remove_dir_all(&folder_path);
+        std::fs::create_dir_all(&folder_path).unwrap();
+
+        // The log starts from sequence number 5 while only up to 2 has been flushed.
+        let log_path = Catalog::gen_memtable_path(&folder_path);
+        let memtable = Memtable::open(log_path, &Options::default()).unwrap();
+        memtable.set("a".to_owned(), "1".to_owned(), 5).unwrap();
+        memtable.set("b".to_owned(), "2".to_owned(), 6).unwrap();
+        drop(memtable);
+        Manifest {
+            last_flushed_sequence_no: 2,
+            ..Manifest::default()
+        }
+        .save(&LocalBackend, &folder_path)
+        .unwrap();
+
+        let options = Options {
+            fail_on_sequence_gap: true,
+            ..Options::default()
+        };
+        assert!(matches!(
+            Catalog::open(folder_path.clone(), options),
+            Err(NaiveError::SequenceGap {
+                last_flushed_sequence_no: 2,
+                first_logged_sequence_no: 5,
+            })
+        ));
+
+        // Without failing, the sequence numbers continue after the logged ones.
+        let catalog = Catalog::open(folder_path, Options::default()).unwrap();
+        let mut catalog_viewer = CatalogViewer::new(Arc::new(RwLock::new(catalog))).unwrap();
+        assert_eq!(catalog_viewer.get("b").unwrap(), Some("2".to_owned()));
+        let receipt = catalog_viewer.set("c".to_owned(), "3".to_owned()).unwrap();
+        assert_eq!(receipt.sequence_no, 7);
+    }
 }
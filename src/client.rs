@@ -0,0 +1,1338 @@
+use std::collections::HashMap;
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+
+use crate::audit::AuditRecord;
+use crate::protos::messages::{Entry, Operation, Priority, Request, Response, Status};
+use crate::stats::{ConsistencyReport, Stats};
+use crate::types::{NaiveError, Result};
+use crate::utils;
+
+/// How long the watch of a near cache goes without any response, heartbeats included, before
+/// the server is taken as gone.
+const WATCH_TIMEOUT_MS: u64 = 10_000;
+/// How long to wait before watching again once the watch of a near cache is lost.
+const WATCH_RETRY_INTERVAL_MS: u64 = 1000;
+
+/// The role of a server among the ones a client can fail over between.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ServerRole {
+    /// The server taking the writes.
+    Primary,
+    /// A server following the primary, which may lag behind it.
+    Standby,
+}
+
+/// Which requests a client retries on the next server once the current one fails, i.e. cannot
+/// be connected to, drops the connection or times out.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FailoverPolicy {
+    /// Retry the gets, and the exports not yet started, on the other servers.
+    pub fail_over_reads: bool,
+
+    /// Promote the next reachable server to primary for the writes once the primary fails. This
+    /// is only safe once the standby has taken over on the server side too, and a write whose
+    /// response was lost may be applied twice.
+    pub fail_over_writes: bool,
+}
+
+impl Default for FailoverPolicy {
+    fn default() -> Self {
+        Self {
+            fail_over_reads: true,
+            fail_over_writes: false,
+        }
+    }
+}
+
+/// A connection to a NaiveKV server, or to one of several servers failing over to each other.
+pub struct Client {
+    /// The servers in the order to fail over in.
+    servers: Vec<(SocketAddr, ServerRole)>,
+
+    /// The server connected to, or to reconnect to.
+    server_index: usize,
+
+    /// The server taking the writes, which the client promotes a standby to on failover.
+    primary_index: usize,
+
+    failover_policy: FailoverPolicy,
+
+    /// The connection to the server, or None after a failed request until the next one
+    /// reconnects.
+    stream: Option<TcpStream>,
+
+    /// How long to wait for each connection, write and response, or forever if None.
+    timeout: Option<Duration>,
+
+    /// The id of the next request, starting from 1 so that responses are never empty.
+    next_request_id: u64,
+
+    /// The GET results kept locally, if enabled, shared with the thread watching the server for
+    /// the keys to drop.
+    near_cache: Option<Arc<Mutex<NearCache>>>,
+
+    /// The priority of the requests not setting their own, or None to leave it to the server.
+    priority: Option<Priority>,
+
+    /// The namespace of the requests not setting their own, or empty for the default one.
+    namespace: String,
+}
+
+impl Client {
+    pub fn connect<A: ToSocketAddrs>(address: A) -> Result<Self> {
+        let stream = TcpStream::connect(address)?;
+        Ok(Self::new(
+            vec![(stream.peer_addr()?, ServerRole::Primary)],
+            0,
+            Some(stream),
+            None,
+        ))
+    }
+
+    /// Connect to the server and fail a request with DeadlineExceeded once connecting, sending or
+    /// receiving stalls for longer than the timeout, instead of waiting forever for a hung server.
+    ///
+    /// A late response cannot be told apart from the next one, so the connection is dropped on
+    /// a timeout and the next request reconnects.
+    pub fn connect_with_timeout<A: ToSocketAddrs>(address: A, timeout: Duration) -> Result<Self> {
+        let mut last_error = None;
+        for address in address.to_socket_addrs()? {
+            match connect_stream(address, Some(timeout)) {
+                Ok(stream) => {
+                    return Ok(Self::new(
+                        vec![(address, ServerRole::Primary)],
+                        0,
+                        Some(stream),
+                        Some(timeout),
+                    ))
+                }
+                Err(error) => last_error = Some(error),
+            }
+        }
+        Err(last_error.unwrap_or(NaiveError::InvalidData))
+    }
+
+    /// Connect to the first reachable one of the servers, starting from the primary, and retry
+    /// the requests on the others once it fails as the policy allows, so that the application
+    /// survives the loss of a server. Any writes still go to the primary until it fails.
+    pub fn connect_with_failover(
+        servers: Vec<(SocketAddr, ServerRole)>,
+        timeout: Option<Duration>,
+        failover_policy: FailoverPolicy,
+    ) -> Result<Self> {
+        let primary_index = match servers
+            .iter()
+            .position(|(_, role)| *role == ServerRole::Primary)
+        {
+            Some(primary_index) => primary_index,
+            None => {
+                log::error!("Found no primary among {} servers.", servers.len());
+                return Err(NaiveError::InvalidData);
+            }
+        };
+        let mut client = Self::new(servers, primary_index, None, timeout);
+        client.failover_policy = failover_policy;
+        let mut last_error = None;
+        for _ in 0..client.servers.len() {
+            match connect_stream(client.servers[client.server_index].0, timeout) {
+                Ok(stream) => {
+                    client.stream = Some(stream);
+                    return Ok(client);
+                }
+                Err(error) => last_error = Some(error),
+            }
+            client.server_index = (client.server_index + 1) % client.servers.len();
+        }
+        Err(last_error.unwrap_or(NaiveError::InvalidData))
+    }
+
+    fn new(
+        servers: Vec<(SocketAddr, ServerRole)>,
+        primary_index: usize,
+        stream: Option<TcpStream>,
+        timeout: Option<Duration>,
+    ) -> Self {
+        Self {
+            servers,
+            server_index: primary_index,
+            primary_index,
+            failover_policy: FailoverPolicy::default(),
+            stream,
+            timeout,
+            next_request_id: 1,
+            near_cache: None,
+            priority: None,
+            namespace: String::new(),
+        }
+    }
+
+    /// The server connected to, or to reconnect to on the next request.
+    pub fn server_address(&self) -> SocketAddr {
+        self.servers[self.server_index].0
+    }
+
+    /// The server the writes go to, which may be a promoted standby.
+    pub fn primary_address(&self) -> SocketAddr {
+        self.servers[self.primary_index].0
+    }
+
+    /// Keep up to the given number of GET results locally, each for no longer than the TTL.
+    ///
+    /// The writes through this client invalidate the cached keys right away, but the writes by
+    /// the other clients only show up once the cached results expire, or once `invalidate` is
+    /// called for their keys, unless the cache is watched as by `with_watched_near_cache`.
+    pub fn with_near_cache(mut self, capacity: usize, ttl: Duration) -> Self {
+        self.near_cache = Some(Arc::new(Mutex::new(NearCache::new(capacity, ttl))));
+        self
+    }
+
+    /// Keep the GET results locally like `with_near_cache`, and watch the server connected to
+    /// for the writes by all the clients, which drop their keys from the cache as they are
+    /// applied, give or take the network delay.
+    ///
+    /// The watch takes a connection of its own, followed by a thread for as long as the client
+    /// lives. While the watch is lost, e.g. for falling behind the writes, nothing is cached, and
+    /// the cache starts over empty once the keys are watched again.
+    pub fn with_watched_near_cache(mut self, capacity: usize, ttl: Duration) -> Result<Self> {
+        let address = self.server_address();
+        let namespace = self.namespace.clone();
+        let stream = connect_watch(address, &namespace)?;
+        let near_cache = Arc::new(Mutex::new(NearCache::new(capacity, ttl)));
+        let watched_cache = Arc::downgrade(&near_cache);
+        std::thread::spawn(move || follow_watch(address, &namespace, stream, watched_cache));
+        self.near_cache = Some(near_cache);
+        Ok(self)
+    }
+
+    /// Send the requests at the priority unless they set their own, e.g. BATCH for a bulk loader
+    /// to stay out of the way of the interactive clients of the server. A request of batch
+    /// priority fails with SERVER_BUSY while the server is serving as many as it allows, to be
+    /// retried after a pause.
+    pub fn with_priority(mut self, priority: Priority) -> Self {
+        self.priority = Some(priority);
+        self
+    }
+
+    /// Send the requests in the namespace, i.e. the column family of the name on the server,
+    /// unless they set their own. A watched near cache watches the namespace set before it.
+    pub fn with_namespace(mut self, namespace: &str) -> Self {
+        self.namespace = namespace.to_owned();
+        self
+    }
+
+    /// Drop the cached result of a key, e.g. upon learning that it has changed.
+    pub fn invalidate(&mut self, key: &str) {
+        if let Some(near_cache) = self.near_cache.as_ref() {
+            if let Ok(mut near_cache) = near_cache.lock() {
+                near_cache.entries.remove(key);
+            }
+        }
+    }
+
+    /// Send a request with a new id and wait for its response.
+    pub fn send(&mut self, mut request: Request) -> Result<Response> {
+        if let Some(near_cache) = self.near_cache.as_ref() {
+            let mut near_cache = near_cache.lock()?;
+            for key in written_keys(&request) {
+                near_cache.entries.remove(key);
+            }
+        }
+        request.set_id(self.next_request_id);
+        self.next_request_id += 1;
+        if let (Some(priority), false) = (self.priority, request.has_priority()) {
+            request.set_priority(priority);
+        }
+        if request.get_namespace().is_empty() {
+            request.set_namespace(self.namespace.clone());
+        }
+        let response = self.exchange_with_failover(&request)?;
+        if matches!(
+            response.get_status(),
+            Status::FRAME_TOO_LARGE | Status::SERVER_BUSY
+        ) {
+            // The server cannot tell the id of a request it has skipped or not even read.
+            return Err(into_error(response));
+        }
+        if response.get_id() != request.get_id() {
+            log::error!(
+                "Expected the response to request {} but got {}.",
+                request.get_id(),
+                response.get_id()
+            );
+            return Err(NaiveError::InvalidData);
+        }
+        Ok(response)
+    }
+
+    pub fn get(&mut self, key: &str) -> Result<Option<String>> {
+        let mut generation = None;
+        if let Some(near_cache) = self.near_cache.as_ref() {
+            let mut near_cache = near_cache.lock()?;
+            if let Some(value) = near_cache.get(key) {
+                return Ok(value);
+            }
+            generation = Some(near_cache.generation);
+        }
+        let value = into_result(self.send(get_request(key))?)?;
+        if let Some(near_cache) = self.near_cache.as_ref() {
+            let mut near_cache = near_cache.lock()?;
+            if generation == Some(near_cache.generation) {
+                near_cache.insert(key.to_owned(), value.clone());
+            }
+        }
+        Ok(value)
+    }
+
+    /// Get the value of the key along with when it was last written, in milliseconds since the
+    /// Unix epoch, if the server knows.
+    pub fn get_with_timestamp(&mut self, key: &str) -> Result<Option<(String, Option<u64>)>> {
+        let response = self.send(get_request(key))?;
+        let timestamp_ms = response
+            .has_timestamp_ms()
+            .then(|| response.get_timestamp_ms());
+        Ok(into_result(response)?.map(|value| (value, timestamp_ms)))
+    }
+
+    pub fn set(&mut self, key: &str, value: &str) -> Result<()> {
+        into_result(self.send(set_request(key, value))?).map(|_| ())
+    }
+
+    pub fn remove(&mut self, key: &str) -> Result<()> {
+        into_result(self.send(remove_request(key))?).map(|_| ())
+    }
+
+    /// Set the value only if the key is missing on the server, and return whether it was set.
+    pub fn set_nx(&mut self, key: &str, value: &str) -> Result<bool> {
+        self.set_if(Operation::SET_NX, key, value)
+    }
+
+    /// Set the value only if the key has one on the server, and return whether it was set.
+    pub fn set_xx(&mut self, key: &str, value: &str) -> Result<bool> {
+        self.set_if(Operation::SET_XX, key, value)
+    }
+
+    /// Set the value on the server and return the one it replaces, if any.
+    pub fn get_set(&mut self, key: &str, value: &str) -> Result<Option<String>> {
+        let mut request = set_request(key, value);
+        request.set_operation(Operation::GET_SET);
+        into_result(self.send(request)?)
+    }
+
+    /// Append the suffix to the value of the key on the server, taking a missing key as empty,
+    /// and return the length of the new value in bytes.
+    pub fn append(&mut self, key: &str, suffix: &str) -> Result<usize> {
+        let mut request = set_request(key, suffix);
+        request.set_operation(Operation::APPEND);
+        into_result(self.send(request)?)?
+            .and_then(|new_len| new_len.parse().ok())
+            .ok_or(NaiveError::InvalidData)
+    }
+
+    fn set_if(&mut self, operation: Operation, key: &str, value: &str) -> Result<bool> {
+        let mut request = set_request(key, value);
+        request.set_operation(operation);
+        let response = self.send(request)?;
+        if response.get_status() == Status::CONDITION_NOT_MET {
+            return Ok(false);
+        }
+        into_result(response).map(|_| true)
+    }
+
+    /// Add the delta to the integer value of the key on the server, taking a missing key as zero,
+    /// and return the new value.
+    pub fn increment(&mut self, key: &str, delta: i64) -> Result<i64> {
+        let mut request = Request::new();
+        request.set_operation(Operation::INCREMENT);
+        request.set_key(key.to_owned());
+        request.set_delta(delta);
+        into_result(self.send(request)?)?
+            .and_then(|value| value.parse().ok())
+            .ok_or(NaiveError::InvalidData)
+    }
+
+    /// Fetch a chunk of an export of the keys with the prefix, starting a new export from a new
+    /// snapshot if no export id is given. An export can resume from any offset, even on another
+    /// connection, until it expires on the server.
+    pub fn export_chunk(
+        &mut self,
+        prefix: &str,
+        export_id: Option<u64>,
+        offset: u64,
+        limit: u64,
+    ) -> Result<ExportChunk> {
+        let mut request = Request::new();
+        request.set_operation(Operation::EXPORT);
+        request.set_key(prefix.to_owned());
+        if let Some(export_id) = export_id {
+            request.set_export_id(export_id);
+        }
+        request.set_offset(offset);
+        request.set_limit(limit);
+        let mut response = self.send(request)?;
+        if response.get_status() != Status::OK {
+            return Err(into_error(response));
+        }
+        if response.get_offset() != offset {
+            log::error!(
+                "Expected the export chunk at offset {} but got {}.",
+                offset,
+                response.get_offset()
+            );
+            return Err(NaiveError::InvalidData);
+        }
+        Ok(ExportChunk {
+            export_id: response.get_export_id(),
+            entries: response
+                .take_entries()
+                .into_iter()
+                .map(|mut entry| (entry.take_key(), entry.take_value()))
+                .collect(),
+            is_last_chunk: response.get_is_last_chunk(),
+            lease: response
+                .has_lease_ms()
+                .then(|| Duration::from_millis(response.get_lease_ms())),
+        })
+    }
+
+    /// Export all the key-value pairs with the prefix from a consistent snapshot, chunk by chunk.
+    pub fn export(&mut self, prefix: &str, chunk_size: u64) -> Result<Vec<(String, String)>> {
+        let mut entries = Vec::new();
+        let mut export_id = None;
+        loop {
+            let chunk = self.export_chunk(prefix, export_id, entries.len() as u64, chunk_size)?;
+            export_id = Some(chunk.export_id);
+            entries.extend(chunk.entries);
+            if chunk.is_last_chunk {
+                return Ok(entries);
+            }
+        }
+    }
+
+    /// Read the whole audit log of the administrative actions on the server, chunk by chunk.
+    pub fn audit_log(&mut self, chunk_size: u64) -> Result<Vec<AuditRecord>> {
+        let mut records = Vec::new();
+        loop {
+            let mut request = Request::new();
+            request.set_operation(Operation::AUDIT_LOG);
+            request.set_offset(records.len() as u64);
+            request.set_limit(chunk_size);
+            let mut response = self.send(request)?;
+            if response.get_status() != Status::OK {
+                return Err(into_error(response));
+            }
+            records.extend(
+                response
+                    .take_audit_records()
+                    .into_iter()
+                    .map(AuditRecord::from_message),
+            );
+            if response.get_is_last_chunk() {
+                return Ok(records);
+            }
+        }
+    }
+
+    /// Have the server check the consistency of a snapshot of its data, which reads every key.
+    pub fn verify(&mut self) -> Result<ConsistencyReport> {
+        let mut request = Request::new();
+        request.set_operation(Operation::VERIFY);
+        let response = self.send(request)?;
+        if response.get_status() != Status::OK {
+            return Err(into_error(response));
+        }
+        Ok(ConsistencyReport::from_message(
+            response.get_consistency_report(),
+        ))
+    }
+
+    /// Get the current value of the engine property of the name on the server, e.g.
+    /// `stats::PROPERTY_WAL_BYTES`, or None if there is no such property.
+    pub fn property(&mut self, name: &str) -> Result<Option<String>> {
+        let mut request = Request::new();
+        request.set_operation(Operation::GET_PROPERTY);
+        request.set_key(name.to_owned());
+        into_result(self.send(request)?)
+    }
+
+    /// Get the engine stats of all the namespaces on the server in a single request, by namespace.
+    pub fn stats_all(&mut self) -> Result<Vec<(String, Stats)>> {
+        let mut request = Request::new();
+        request.set_operation(Operation::STATS_ALL);
+        let mut response = self.send(request)?;
+        if response.get_status() != Status::OK {
+            return Err(into_error(response));
+        }
+        Ok(response
+            .take_namespace_stats()
+            .into_iter()
+            .map(|mut namespace_stats| {
+                (
+                    namespace_stats.take_namespace(),
+                    Stats::from_message(namespace_stats.get_stats()),
+                )
+            })
+            .collect())
+    }
+
+    /// Get the wall clock and the last visible sequence number of the server, along with the
+    /// round trip taken, which bounds how far the clock was read from the middle of it.
+    pub fn server_time(&mut self) -> Result<ServerTime> {
+        let mut request = Request::new();
+        request.set_operation(Operation::SERVER_TIME);
+        let start_time = Instant::now();
+        let response = self.send(request)?;
+        let round_trip = start_time.elapsed();
+        if response.get_status() != Status::OK {
+            return Err(into_error(response));
+        }
+        if !response.has_server_time_ms() || !response.has_sequence_no() {
+            return Err(NaiveError::InvalidData);
+        }
+        Ok(ServerTime {
+            time_ms: response.get_server_time_ms(),
+            sequence_no: response.get_sequence_no(),
+            round_trip,
+        })
+    }
+
+    /// Exchange the request with the current server, or with the primary for a write, and retry
+    /// it on the next servers in turn while they fail, as far as the policy allows.
+    fn exchange_with_failover(&mut self, request: &Request) -> Result<Response> {
+        let is_write = !written_keys(request).is_empty();
+        if is_write && self.server_index != self.primary_index {
+            // The reads may have failed over to a standby, but the writes stay on the primary.
+            self.stream = None;
+            self.server_index = self.primary_index;
+        }
+        let can_fail_over = if is_write {
+            self.failover_policy.fail_over_writes
+        } else {
+            self.failover_policy.fail_over_reads && is_failover_read(request)
+        };
+        let mut result = self.exchange(request);
+        if !can_fail_over {
+            return result;
+        }
+        for _ in 1..self.servers.len() {
+            let error = match result {
+                Ok(response) => return Ok(response),
+                Err(error) => error,
+            };
+            let failed_address = self.server_address();
+            self.server_index = (self.server_index + 1) % self.servers.len();
+            if is_write {
+                self.primary_index = self.server_index;
+            }
+            log::warn!(
+                "Failing over from {} to {} ({:?} standby) after {:?}.",
+                failed_address,
+                self.server_address(),
+                self.servers[self.server_index].1,
+                error
+            );
+            result = self.exchange(request);
+        }
+        result
+    }
+
+    fn exchange(&mut self, request: &Request) -> Result<Response> {
+        let stream = match self.stream.as_mut() {
+            Some(stream) => stream,
+            None => self
+                .stream
+                .insert(connect_stream(self.server_address(), self.timeout)?),
+        };
+        match exchange(request, stream) {
+            Ok(response) => Ok(response),
+            Err(error) => {
+                // The stream may be left in the middle of a message, so start over next time.
+                self.stream = None;
+                Err(error)
+            }
+        }
+    }
+
+    /// Start queueing operations to be submitted in a single batch request.
+    pub fn pipeline(&mut self) -> Pipeline<'_> {
+        Pipeline {
+            client: self,
+            requests: Vec::new(),
+        }
+    }
+}
+
+/// Operations queued locally until they are submitted together.
+pub struct Pipeline<'a> {
+    client: &'a mut Client,
+    requests: Vec<Request>,
+}
+
+impl Pipeline<'_> {
+    pub fn get(&mut self, key: &str) -> &mut Self {
+        self.requests.push(get_request(key));
+        self
+    }
+
+    pub fn set(&mut self, key: &str, value: &str) -> &mut Self {
+        self.requests.push(set_request(key, value));
+        self
+    }
+
+    pub fn remove(&mut self, key: &str) -> &mut Self {
+        self.requests.push(remove_request(key));
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.requests.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.requests.is_empty()
+    }
+
+    /// Submit the queued operations and return their results in order, i.e. the value for each
+    /// get and None for each set or remove. The operations are applied one by one rather than
+    /// atomically, so some may fail while others succeed.
+    pub fn submit(self) -> Result<Vec<Result<Option<String>>>> {
+        let num_requests = self.requests.len();
+        let mut request = Request::new();
+        request.set_operation(Operation::BATCH);
+        request.set_requests(self.requests.into());
+        let mut response = self.client.send(request)?;
+        if response.get_status() != Status::OK {
+            return Err(into_error(response));
+        }
+        if response.get_responses().len() != num_requests {
+            log::error!(
+                "Expected {} responses in the batch but got {}.",
+                num_requests,
+                response.get_responses().len()
+            );
+            return Err(NaiveError::InvalidData);
+        }
+        Ok(response
+            .take_responses()
+            .into_iter()
+            .map(into_result)
+            .collect())
+    }
+}
+
+/// A chunk of the key-value pairs in an export.
+#[derive(Clone, Debug)]
+pub struct ExportChunk {
+    /// The id for resuming the export.
+    pub export_id: u64,
+
+    pub entries: Vec<(String, String)>,
+
+    /// Whether the export has no more entries after this chunk.
+    pub is_last_chunk: bool,
+
+    /// How long the server keeps the export open for the next chunk, if it says.
+    pub lease: Option<Duration>,
+}
+
+/// The clock and the progress of a server as of a SERVER_TIME request.
+#[derive(Clone, Debug)]
+pub struct ServerTime {
+    /// The wall clock of the server in milliseconds since the Unix epoch.
+    pub time_ms: u64,
+
+    /// The sequence number of the last write visible on the server when the clock was read.
+    pub sequence_no: u64,
+
+    /// The time between sending the request and receiving the response.
+    pub round_trip: Duration,
+}
+
+impl ServerTime {
+    /// How far the server clock is ahead of the local one, assuming it was read halfway through
+    /// the round trip, which is off by half the round trip at most.
+    pub fn clock_offset_ms(&self, local_time_ms: u64) -> i64 {
+        let midpoint_ms = local_time_ms as i64 - (self.round_trip.as_millis() / 2) as i64;
+        self.time_ms as i64 - midpoint_ms
+    }
+}
+
+/// The GET results kept by a client, including the absent keys.
+pub(crate) struct NearCache {
+    capacity: usize,
+    ttl: Duration,
+
+    /// The value of each key, if any, and when it expires.
+    entries: HashMap<String, (Option<String>, Instant)>,
+
+    /// Whether the results are kept, which they are not while the writes to them cannot be seen.
+    is_enabled: bool,
+
+    /// Bumped whenever the keys written elsewhere are dropped, so that a result read before a
+    /// write, but received after its key was dropped, is not kept.
+    generation: u64,
+}
+
+impl NearCache {
+    pub(crate) fn new(capacity: usize, ttl: Duration) -> Self {
+        Self {
+            capacity,
+            ttl,
+            entries: HashMap::new(),
+            is_enabled: true,
+            generation: 0,
+        }
+    }
+
+    /// Keep the results or stop keeping them, dropping those kept so far in either case.
+    fn set_enabled(&mut self, is_enabled: bool) {
+        self.entries.clear();
+        self.is_enabled = is_enabled;
+        self.generation += 1;
+    }
+
+    /// Drop the keys written elsewhere.
+    fn invalidate_all<'a>(&mut self, keys: impl Iterator<Item = &'a str>) {
+        for key in keys {
+            self.entries.remove(key);
+        }
+        self.generation += 1;
+    }
+
+    /// Look up the unexpired result of a key.
+    pub(crate) fn get(&mut self, key: &str) -> Option<Option<String>> {
+        let (value, expiry) = self.entries.get(key)?;
+        if Instant::now() >= *expiry {
+            self.entries.remove(key);
+            return None;
+        }
+        Some(value.clone())
+    }
+
+    pub(crate) fn insert(&mut self, key: String, value: Option<String>) {
+        if self.capacity == 0 || !self.is_enabled {
+            return;
+        }
+        if self.entries.len() >= self.capacity && !self.entries.contains_key(&key) {
+            // Make room by evicting the entry closest to expiring.
+            if let Some(oldest_key) = self
+                .entries
+                .iter()
+                .min_by_key(|(_, (_, expiry))| *expiry)
+                .map(|(key, _)| key.clone())
+            {
+                self.entries.remove(&oldest_key);
+            }
+        }
+        self.entries.insert(key, (value, Instant::now() + self.ttl));
+    }
+}
+
+/// Watch all the keys on the server, and wait for the watch to be acknowledged, after which the
+/// writes it has missed have all been applied.
+fn connect_watch(address: SocketAddr, namespace: &str) -> Result<TcpStream> {
+    let mut stream = connect_stream(address, Some(Duration::from_millis(WATCH_TIMEOUT_MS)))?;
+    let mut request = Request::new();
+    request.set_id(1);
+    request.set_operation(Operation::WATCH);
+    request.set_namespace(namespace.to_owned());
+    exchange(&request, &mut stream).and_then(into_result)?;
+    Ok(stream)
+}
+
+/// Drop the keys written from the near cache as the watch lists them, until the client drops the
+/// cache, watching again whenever the watch is lost.
+fn follow_watch(
+    address: SocketAddr,
+    namespace: &str,
+    mut stream: TcpStream,
+    near_cache: Weak<Mutex<NearCache>>,
+) {
+    loop {
+        let error = loop {
+            let response = match utils::read_message::<Response, _>(&mut stream) {
+                Ok(Some(response)) if response.get_status() == Status::OK => response,
+                Ok(Some(response)) => break into_error(response),
+                Ok(None) => break NaiveError::InvalidData,
+                Err(error) => break error,
+            };
+            // Even a heartbeat listing no keys finds out whether the client is still there.
+            if !update_near_cache(&near_cache, |near_cache| {
+                near_cache.invalidate_all(response.get_entries().iter().map(Entry::get_key))
+            }) {
+                return;
+            }
+        };
+        log::warn!("Lost the watch on server {}: {:?}", address, error);
+        if !update_near_cache(&near_cache, |near_cache| near_cache.set_enabled(false)) {
+            return;
+        }
+        stream = loop {
+            std::thread::sleep(Duration::from_millis(WATCH_RETRY_INTERVAL_MS));
+            if near_cache.strong_count() == 0 {
+                return;
+            }
+            match connect_watch(address, namespace) {
+                Ok(stream) => break stream,
+                Err(error) => log::warn!("Failed to watch server {}: {:?}", address, error),
+            }
+        };
+        // The writes missed were applied before the watch was acknowledged, so the results read
+        // from here on reflect them.
+        if !update_near_cache(&near_cache, |near_cache| near_cache.set_enabled(true)) {
+            return;
+        }
+    }
+}
+
+/// Apply the update to the near cache, and tell whether the client still has it.
+fn update_near_cache(
+    near_cache: &Weak<Mutex<NearCache>>,
+    update: impl FnOnce(&mut NearCache),
+) -> bool {
+    let Some(near_cache) = near_cache.upgrade() else {
+        return false;
+    };
+    let Ok(mut near_cache) = near_cache.lock() else {
+        return false;
+    };
+    update(&mut near_cache);
+    true
+}
+
+fn connect_stream(address: SocketAddr, timeout: Option<Duration>) -> Result<TcpStream> {
+    let stream = match timeout {
+        Some(timeout) => TcpStream::connect_timeout(&address, timeout)?,
+        None => TcpStream::connect(address)?,
+    };
+    stream.set_read_timeout(timeout)?;
+    stream.set_write_timeout(timeout)?;
+    Ok(stream)
+}
+
+/// Write a request and read its response, turning the socket timeouts into DeadlineExceeded.
+fn exchange(request: &Request, stream: &mut TcpStream) -> Result<Response> {
+    let result = utils::write_message(request, stream).and_then(|_| {
+        utils::read_message::<Response, TcpStream>(stream)?.ok_or(NaiveError::InvalidData)
+    });
+    match result {
+        Err(NaiveError::IoError(error))
+            if matches!(
+                error.kind(),
+                std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+            ) =>
+        {
+            Err(NaiveError::DeadlineExceeded)
+        }
+        result => result,
+    }
+}
+
+/// Whether a request only reads data that any server has, so that it can be retried on another
+/// one, unlike the exports in progress, which only live on the server that started them.
+fn is_failover_read(request: &Request) -> bool {
+    match request.get_operation() {
+        Operation::GET => true,
+        Operation::EXPORT => !request.has_export_id(),
+        Operation::BATCH => request.get_requests().iter().all(is_failover_read),
+        _ => false,
+    }
+}
+
+/// The keys a request may change, including those in the sub-requests of a batch.
+fn written_keys(request: &Request) -> Vec<&str> {
+    match request.get_operation() {
+        Operation::SET
+        | Operation::REMOVE
+        | Operation::INCREMENT
+        | Operation::SET_NX
+        | Operation::SET_XX
+        | Operation::GET_SET
+        | Operation::APPEND => vec![request.get_key()],
+        Operation::MDEL => request.get_keys().iter().map(String::as_str).collect(),
+        Operation::BATCH => request
+            .get_requests()
+            .iter()
+            .flat_map(written_keys)
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+fn get_request(key: &str) -> Request {
+    let mut request = Request::new();
+    request.set_operation(Operation::GET);
+    request.set_key(key.to_owned());
+    request
+}
+
+fn set_request(key: &str, value: &str) -> Request {
+    let mut request = Request::new();
+    request.set_operation(Operation::SET);
+    request.set_key(key.to_owned());
+    request.set_value(value.to_owned());
+    request
+}
+
+fn remove_request(key: &str) -> Request {
+    let mut request = Request::new();
+    request.set_operation(Operation::REMOVE);
+    request.set_key(key.to_owned());
+    request
+}
+
+/// Convert a response into the value if any, treating KEY_NOT_FOUND as no value.
+fn into_result(mut response: Response) -> Result<Option<String>> {
+    match response.get_status() {
+        Status::OK => Ok(response.has_value().then(|| response.take_value())),
+        Status::KEY_NOT_FOUND => Ok(None),
+        _ => Err(into_error(response)),
+    }
+}
+
+fn into_error(mut response: Response) -> NaiveError {
+    NaiveError::ServerError {
+        status: response.get_status(),
+        message: response.has_error().then(|| response.take_error()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{get_request, Client, FailoverPolicy, ServerRole};
+    use crate::protos::messages::{Entry, Operation, Priority, Request, Response, Status};
+    use crate::types::NaiveError;
+    use crate::utils;
+    use std::net::TcpListener;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[test]
+    fn test_pipeline() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+
+        // A fake server expecting a batch of a set, a get and a remove.
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let request = utils::read_message::<Request, _>(&mut stream)
+                .unwrap()
+                .unwrap();
+            assert_eq!(request.get_operation(), Operation::BATCH);
+            let operations = request
+                .get_requests()
+                .iter()
+                .map(Request::get_operation)
+                .collect::<Vec<_>>();
+            assert_eq!(
+                operations,
+                vec![Operation::SET, Operation::GET, Operation::REMOVE]
+            );
+
+            let mut response = Response::new();
+            response.set_id(request.get_id());
+            let mut set_response = Response::new();
+            set_response.set_status(Status::OK);
+            let mut get_response = Response::new();
+            get_response.set_value("value".to_owned());
+            let mut remove_response = Response::new();
+            remove_response.set_status(Status::INTERNAL_ERROR);
+            response.set_responses(vec![set_response, get_response, remove_response].into());
+            utils::write_message(&response, &mut stream).unwrap();
+        });
+
+        let mut client = Client::connect(address).unwrap();
+        let mut pipeline = client.pipeline();
+        pipeline.set("key", "value").get("key").remove("key");
+        assert_eq!(pipeline.len(), 3);
+        let results = pipeline.submit().unwrap();
+        server.join().unwrap();
+
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[0].as_ref().unwrap(), &None);
+        assert_eq!(results[1].as_ref().unwrap(), &Some("value".to_owned()));
+        assert!(matches!(
+            results[2],
+            Err(NaiveError::ServerError {
+                status: Status::INTERNAL_ERROR,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_server_time() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+
+        // A fake server with its clock at 10s, taking 100ms to respond.
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let request = utils::read_message::<Request, _>(&mut stream)
+                .unwrap()
+                .unwrap();
+            assert_eq!(request.get_operation(), Operation::SERVER_TIME);
+            std::thread::sleep(Duration::from_millis(100));
+            let mut response = Response::new();
+            response.set_id(request.get_id());
+            response.set_server_time_ms(10_000);
+            response.set_sequence_no(7);
+            utils::write_message(&response, &mut stream).unwrap();
+        });
+
+        let mut client = Client::connect(address).unwrap();
+        let server_time = client.server_time().unwrap();
+        server.join().unwrap();
+        assert_eq!(server_time.time_ms, 10_000);
+        assert_eq!(server_time.sequence_no, 7);
+        assert!(server_time.round_trip >= Duration::from_millis(100));
+
+        // The server clock was read halfway through the round trip.
+        let half_round_trip_ms = (server_time.round_trip.as_millis() / 2) as u64;
+        assert_eq!(server_time.clock_offset_ms(10_000 + half_round_trip_ms), 0);
+        assert_eq!(
+            server_time.clock_offset_ms(9_000 + half_round_trip_ms),
+            1_000
+        );
+    }
+
+    #[test]
+    fn test_priority() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+
+        // A fake server echoing the priorities of the requests in the values.
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            while let Some(request) = utils::read_message::<Request, _>(&mut stream).unwrap() {
+                let mut response = Response::new();
+                response.set_id(request.get_id());
+                response.set_value(format!("{:?}", request.get_priority()));
+                utils::write_message(&response, &mut stream).unwrap();
+            }
+        });
+
+        let mut client = Client::connect(address)
+            .unwrap()
+            .with_priority(Priority::BATCH);
+        assert_eq!(client.get("key").unwrap(), Some("BATCH".to_owned()));
+
+        // A request setting its own priority keeps it.
+        let mut request = get_request("key");
+        request.set_priority(Priority::INTERACTIVE);
+        assert_eq!(client.send(request).unwrap().get_value(), "INTERACTIVE");
+        drop(client);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_namespace() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+
+        // A fake server echoing the namespaces of the requests in the values.
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            while let Some(request) = utils::read_message::<Request, _>(&mut stream).unwrap() {
+                let mut response = Response::new();
+                response.set_id(request.get_id());
+                response.set_value(request.get_namespace().to_owned());
+                utils::write_message(&response, &mut stream).unwrap();
+            }
+        });
+
+        let mut client = Client::connect(address).unwrap();
+        assert_eq!(client.get("key").unwrap(), Some(String::new()));
+        let mut client = client.with_namespace("meta");
+        assert_eq!(client.get("key").unwrap(), Some("meta".to_owned()));
+
+        // A request setting its own namespace keeps it.
+        let mut request = get_request("key");
+        request.set_namespace("data".to_owned());
+        assert_eq!(client.send(request).unwrap().get_value(), "data");
+        drop(client);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_near_cache() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+
+        // A fake server counting the requests, which returns the number of sets so far.
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut num_requests = 0;
+            let mut num_sets = 0;
+            while let Some(request) = utils::read_message::<Request, _>(&mut stream).unwrap() {
+                num_requests += 1;
+                let mut response = Response::new();
+                response.set_id(request.get_id());
+                match request.get_operation() {
+                    Operation::GET if num_sets == 0 => response.set_status(Status::KEY_NOT_FOUND),
+                    Operation::GET => response.set_value(num_sets.to_string()),
+                    _ => num_sets += 1,
+                }
+                utils::write_message(&response, &mut stream).unwrap();
+            }
+            num_requests
+        });
+
+        let mut client = Client::connect(address)
+            .unwrap()
+            .with_near_cache(1, Duration::from_millis(200));
+        assert_eq!(client.get("key").unwrap(), None);
+        assert_eq!(client.get("key").unwrap(), None);
+
+        // The writes through the client invalidate the key.
+        client.set("key", "1").unwrap();
+        assert_eq!(client.get("key").unwrap(), Some("1".to_owned()));
+        assert_eq!(client.get("key").unwrap(), Some("1".to_owned()));
+
+        // The cache only holds a single key.
+        assert_eq!(client.get("other").unwrap(), Some("1".to_owned()));
+        assert_eq!(client.get("key").unwrap(), Some("1".to_owned()));
+
+        // The cached results expire.
+        std::thread::sleep(Duration::from_millis(300));
+        assert_eq!(client.get("key").unwrap(), Some("1".to_owned()));
+        drop(client);
+        assert_eq!(server.join().unwrap(), 6);
+    }
+
+    #[test]
+    fn test_watched_near_cache() {
+        // The responses to the watch carry the id of its request, so that none is empty.
+        let watch_response = || {
+            let mut response = Response::new();
+            response.set_id(1);
+            response
+        };
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let version = Arc::new(AtomicU64::new(0));
+        let num_gets = Arc::new(AtomicU64::new(0));
+        let (watch_sender, watch_receiver) = crossbeam::channel::unbounded::<Response>();
+        let (rewatch_sender, rewatch_receiver) = crossbeam::channel::bounded(0);
+
+        // A fake server getting the version of every key, whose watch streams the responses sent
+        // by the test.
+        let server = {
+            let version = version.clone();
+            let num_gets = num_gets.clone();
+            std::thread::spawn(move || {
+                let (mut stream, _) = listener.accept().unwrap();
+                std::thread::spawn(move || {
+                    while let Some(request) =
+                        utils::read_message::<Request, _>(&mut stream).unwrap()
+                    {
+                        assert_eq!(request.get_operation(), Operation::GET);
+                        num_gets.fetch_add(1, Ordering::SeqCst);
+                        let mut response = Response::new();
+                        response.set_id(request.get_id());
+                        response.set_value(version.load(Ordering::SeqCst).to_string());
+                        utils::write_message(&response, &mut stream).unwrap();
+                    }
+                });
+                let accept_watch = || {
+                    let (mut stream, _) = listener.accept().unwrap();
+                    let request = utils::read_message::<Request, _>(&mut stream)
+                        .unwrap()
+                        .unwrap();
+                    assert_eq!(request.get_operation(), Operation::WATCH);
+                    stream
+                };
+                let mut stream = accept_watch();
+                utils::write_message(&watch_response(), &mut stream).unwrap();
+                for response in watch_receiver.iter() {
+                    utils::write_message(&response, &mut stream).unwrap();
+                    if response.get_status() == Status::SERVER_BUSY {
+                        stream = accept_watch();
+                        rewatch_sender.send(()).unwrap();
+                    }
+                }
+            })
+        };
+
+        let mut client = Client::connect(address)
+            .unwrap()
+            .with_watched_near_cache(10, Duration::from_secs(60))
+            .unwrap();
+        assert_eq!(client.get("key").unwrap(), Some("0".to_owned()));
+        assert_eq!(client.get("key").unwrap(), Some("0".to_owned()));
+        assert_eq!(num_gets.load(Ordering::SeqCst), 1);
+
+        // A write by another client drops the key once the watch lists it.
+        version.store(1, Ordering::SeqCst);
+        let mut entry = Entry::new();
+        entry.set_key("key".to_owned());
+        let mut response = watch_response();
+        response.set_entries(vec![entry].into());
+        watch_sender.send(response).unwrap();
+        while client.get("key").unwrap() != Some("1".to_owned()) {
+            std::thread::yield_now();
+        }
+        assert_eq!(num_gets.load(Ordering::SeqCst), 2);
+
+        // Nothing is cached from losing the watch until it is acknowledged again.
+        let mut response = watch_response();
+        response.set_status(Status::SERVER_BUSY);
+        watch_sender.send(response).unwrap();
+        rewatch_receiver.recv().unwrap();
+        assert_eq!(client.get("key").unwrap(), Some("1".to_owned()));
+        assert_eq!(client.get("key").unwrap(), Some("1".to_owned()));
+        assert_eq!(num_gets.load(Ordering::SeqCst), 4);
+
+        watch_sender.send(watch_response()).unwrap();
+        drop(watch_sender);
+        drop(client);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_export() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+
+        // A fake server exporting five keys in chunks of two.
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            while let Some(request) = utils::read_message::<Request, _>(&mut stream).unwrap() {
+                assert_eq!(request.get_operation(), Operation::EXPORT);
+                assert_eq!(request.get_key(), "prefix");
+                assert_eq!(request.has_export_id(), request.get_offset() > 0);
+                let mut response = Response::new();
+                response.set_id(request.get_id());
+                response.set_export_id(7);
+                response.set_offset(request.get_offset());
+                let entries = (request.get_offset()..5)
+                    .take(request.get_limit() as usize)
+                    .map(|num| {
+                        let mut entry = Entry::new();
+                        entry.set_key(format!("prefix{}", num));
+                        entry.set_value(num.to_string());
+                        entry
+                    })
+                    .collect::<Vec<_>>();
+                response.set_is_last_chunk(request.get_offset() + entries.len() as u64 == 5);
+                response.set_entries(entries.into());
+                response.set_lease_ms(60_000);
+                utils::write_message(&response, &mut stream).unwrap();
+            }
+        });
+
+        let mut client = Client::connect(address).unwrap();
+        let entries = client.export("prefix", 2).unwrap();
+        assert_eq!(
+            entries,
+            (0..5)
+                .map(|num| (format!("prefix{}", num), num.to_string()))
+                .collect::<Vec<_>>()
+        );
+        let chunk = client.export_chunk("prefix", None, 0, 2).unwrap();
+        assert_eq!(chunk.lease, Some(Duration::from_secs(60)));
+        drop(client);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+
+        // A fake server hanging on the first connection and answering on the second one.
+        let server = std::thread::spawn(move || {
+            let (mut hung_stream, _) = listener.accept().unwrap();
+            utils::read_message::<Request, _>(&mut hung_stream)
+                .unwrap()
+                .unwrap();
+            let (mut stream, _) = listener.accept().unwrap();
+            let request = utils::read_message::<Request, _>(&mut stream)
+                .unwrap()
+                .unwrap();
+            let mut response = Response::new();
+            response.set_id(request.get_id());
+            response.set_value("value".to_owned());
+            utils::write_message(&response, &mut stream).unwrap();
+        });
+
+        let mut client = Client::connect_with_timeout(address, Duration::from_millis(100)).unwrap();
+        assert!(matches!(
+            client.get("key"),
+            Err(NaiveError::DeadlineExceeded)
+        ));
+        assert_eq!(client.get("key").unwrap(), Some("value".to_owned()));
+        drop(client);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_failover() {
+        let primary_listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let primary_address = primary_listener.local_addr().unwrap();
+        let standby_listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let standby_address = standby_listener.local_addr().unwrap();
+
+        // A fake primary answering a single request before going down for good.
+        let primary = std::thread::spawn(move || {
+            let (mut stream, _) = primary_listener.accept().unwrap();
+            let request = utils::read_message::<Request, _>(&mut stream)
+                .unwrap()
+                .unwrap();
+            let mut response = Response::new();
+            response.set_id(request.get_id());
+            response.set_value("primary".to_owned());
+            utils::write_message(&response, &mut stream).unwrap();
+        });
+
+        // A fake standby serving three connections one after the other, counting the sets. The
+        // second client connects to it first and then reconnects once it fails over the writes.
+        let standby = std::thread::spawn(move || {
+            let mut num_sets = 0;
+            for _ in 0..3 {
+                let (mut stream, _) = standby_listener.accept().unwrap();
+                while let Some(request) = utils::read_message::<Request, _>(&mut stream).unwrap() {
+                    let mut response = Response::new();
+                    response.set_id(request.get_id());
+                    match request.get_operation() {
+                        Operation::GET => response.set_value("standby".to_owned()),
+                        _ => num_sets += 1,
+                    }
+                    utils::write_message(&response, &mut stream).unwrap();
+                }
+            }
+            num_sets
+        });
+
+        let servers = vec![
+            (primary_address, ServerRole::Primary),
+            (standby_address, ServerRole::Standby),
+        ];
+        let mut client =
+            Client::connect_with_failover(servers.clone(), None, FailoverPolicy::default())
+                .unwrap();
+        assert_eq!(client.get("key").unwrap(), Some("primary".to_owned()));
+        primary.join().unwrap();
+
+        // The reads fail over to the standby, but the writes do not by default.
+        assert_eq!(client.get("key").unwrap(), Some("standby".to_owned()));
+        assert_eq!(client.server_address(), standby_address);
+        assert!(client.set("key", "value").is_err());
+        assert_eq!(client.primary_address(), primary_address);
+        drop(client);
+
+        // Failing over the writes promotes the standby.
+        let failover_policy = FailoverPolicy {
+            fail_over_reads: true,
+            fail_over_writes: true,
+        };
+        let mut client = Client::connect_with_failover(servers, None, failover_policy).unwrap();
+        client.set("key", "value").unwrap();
+        assert_eq!(client.primary_address(), standby_address);
+        client.set("key", "value").unwrap();
+        drop(client);
+        assert_eq!(standby.join().unwrap(), 2);
+    }
+}
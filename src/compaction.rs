@@ -0,0 +1,294 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::catalog::Catalog;
+use crate::comparator::Comparator;
+use crate::sstable::SSTable;
+use crate::types::Result;
+
+/// The partitions below this fraction of the partition size count as small for a minor merge.
+const MINOR_MERGE_SIZE_RATIO: usize = 4;
+
+/// The kind of work a compaction does.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CompactionKind {
+    /// Flush the Memtable into generation 0.
+    Flush,
+
+    /// Merge a generation that exceeds its size limit into the partitions of the next generation
+    /// its keys overlap with.
+    Merge,
+
+    /// Merge the two oldest generations into one while there are more than allowed.
+    MergeOldest,
+
+    /// Merge the small partitions in a row of a generation into the same generation.
+    MinorMerge,
+}
+
+/// A Memtable log or a segment file that a compaction reads.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CompactionInput {
+    /// The path of the Memtable log or the segment file.
+    pub file_path: PathBuf,
+
+    /// The generation number of the SSTable, or None for the Memtable.
+    pub gen_no: Option<usize>,
+
+    /// The data size of the Memtable or the size of the segment file in bytes.
+    pub size: usize,
+}
+
+/// What a compaction would do if it ran now.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CompactionPlan {
+    pub kind: CompactionKind,
+
+    /// The inputs from the newest to the oldest.
+    pub inputs: Vec<CompactionInput>,
+
+    /// The generation number of the SSTable to create.
+    pub output_gen_no: usize,
+
+    /// The output size in bytes, assuming no key is overwritten or deleted by a newer input.
+    pub estimated_output_size: usize,
+
+    /// The estimated time taken at the historical compaction throughput, or None if no compaction
+    /// has run since the engine was opened.
+    pub estimated_duration: Option<Duration>,
+}
+
+impl CompactionPlan {
+    fn new(catalog: &Catalog, kind: CompactionKind, inputs: Vec<CompactionInput>) -> Self {
+        let output_gen_no = match kind {
+            CompactionKind::Flush => 0,
+            CompactionKind::Merge => inputs[0].gen_no.unwrap() + 1,
+            CompactionKind::MergeOldest | CompactionKind::MinorMerge => inputs[0].gen_no.unwrap(),
+        };
+        let estimated_output_size = inputs.iter().map(|input| input.size).sum();
+        let compaction_stats = catalog.compaction_stats();
+        let estimated_duration = (compaction_stats.num_bytes > 0).then(|| {
+            Duration::from_secs_f64(
+                estimated_output_size as f64 / compaction_stats.bytes_per_second(),
+            )
+        });
+        Self {
+            kind,
+            inputs,
+            output_gen_no,
+            estimated_output_size,
+            estimated_duration,
+        }
+    }
+}
+
+/// Plan the flush of the read-only Memtable left by a failed flush, if any, or otherwise of the
+/// Memtable, if it has reached the threshold.
+pub(crate) fn plan_flush(catalog: &Catalog) -> Result<Option<CompactionPlan>> {
+    let memtable = match catalog.ro_memtable.as_ref() {
+        Some(ro_memtable) => ro_memtable,
+        None if catalog.memtable.data_size() < catalog.options.memtable_compaction_threshold => {
+            return Ok(None)
+        }
+        None => &catalog.memtable,
+    };
+    let mut inputs = vec![CompactionInput {
+        file_path: memtable.log_path().to_path_buf(),
+        gen_no: None,
+        size: memtable.data_size(),
+    }];
+    inputs.extend(catalog.generation(0).iter().map(sstable_input));
+    Ok(Some(CompactionPlan::new(
+        catalog,
+        CompactionKind::Flush,
+        inputs,
+    )))
+}
+
+/// Plan the merge of the oldest generations if there are too many of them, or otherwise the merge
+/// of the youngest generation that exceeds its size limit, or otherwise a minor merge of the small
+/// partitions of a generation.
+pub(crate) fn plan_merge(catalog: &Catalog) -> Option<CompactionPlan> {
+    let options = &catalog.options;
+
+    // Never merge the generation 0 away, which belongs to the flushes.
+    let max_generations = options.max_generations.map_or(usize::MAX, |max| max.max(2));
+    let num_generations = catalog.num_generations();
+    if num_generations > max_generations {
+        // There might be too many generations from before the option was set.
+        let inputs = (num_generations - 2..num_generations)
+            .flat_map(|gen_no| catalog.generation(gen_no))
+            .map(sstable_input)
+            .collect();
+        return Some(CompactionPlan::new(
+            catalog,
+            CompactionKind::MergeOldest,
+            inputs,
+        ));
+    }
+
+    // The last generation allowed keeps growing instead of moving on.
+    let generation_geometric_ratio = options.generation_geometric_ratio;
+    let mut size_limit = options.memtable_compaction_threshold * generation_geometric_ratio;
+    let gen_no = (0..num_generations.min(max_generations - 1)).find(|&gen_no| {
+        let gen_size = catalog
+            .generation(gen_no)
+            .iter()
+            .map(|sstable| sstable.file_size())
+            .sum::<usize>();
+        let is_full = gen_size >= size_limit;
+        size_limit *= generation_geometric_ratio;
+        is_full
+    });
+    let gen_no = match gen_no {
+        Some(gen_no) => gen_no,
+        None => return plan_minor_merge(catalog),
+    };
+    let sstables = catalog.generation(gen_no);
+    let mut inputs = sstables.iter().map(sstable_input).collect::<Vec<_>>();
+    // Only the partitions of the next generation within the key range of the merged one are
+    // rewritten, along with the empty ones, and the others stay as they are.
+    let key_range = key_range(sstables, options.comparator);
+    inputs.extend(
+        catalog
+            .generation(gen_no + 1)
+            .iter()
+            .filter(|sstable| {
+                options.generation_partition_size == 0
+                    || match (sstable.key_range(), key_range) {
+                        (Some((min_key, max_key)), Some((other_min_key, other_max_key))) => {
+                            options.comparator.compare(min_key, other_max_key).is_le()
+                                && options.comparator.compare(other_min_key, max_key).is_le()
+                        }
+                        (None, _) => true,
+                        (Some(_), None) => false,
+                    }
+            })
+            .map(sstable_input),
+    );
+    Some(CompactionPlan::new(catalog, CompactionKind::Merge, inputs))
+}
+
+/// Plan the merge of the first run of small partitions long enough in the youngest generation that
+/// has one, which rewrites them into the same generation.
+///
+/// Only the partitions next to each other in key order make up a run, so that the merged ones
+/// cover a key range that no other partition of the generation overlaps with. The empty SSTables
+/// are skipped, which the gets skip too.
+fn plan_minor_merge(catalog: &Catalog) -> Option<CompactionPlan> {
+    let options = &catalog.options;
+    let min_files = options.minor_merge_min_files;
+    if min_files == 0 || options.generation_partition_size == 0 {
+        return None;
+    }
+    let max_small_size = options.generation_partition_size / MINOR_MERGE_SIZE_RATIO;
+    // Generation 0 is a single SSTable.
+    (1..catalog.num_generations()).find_map(|gen_no| {
+        let mut run = Vec::new();
+        for sstable in catalog.generation(gen_no) {
+            if sstable.key_range().is_none() {
+                continue;
+            }
+            if data_size(sstable) < max_small_size {
+                run.push(sstable_input(sstable));
+            } else if run.len() >= min_files {
+                break;
+            } else {
+                run.clear();
+            }
+        }
+        (run.len() >= min_files)
+            .then(|| CompactionPlan::new(catalog, CompactionKind::MinorMerge, run))
+    })
+}
+
+/// The smallest and the largest keys of the SSTables, or None if they are all empty.
+fn key_range<'a>(
+    sstables: &'a [Arc<SSTable>],
+    comparator: &dyn Comparator,
+) -> Option<(&'a str, &'a str)> {
+    sstables
+        .iter()
+        .filter_map(|sstable| sstable.key_range())
+        .reduce(|(min_key, max_key), (other_min_key, other_max_key)| {
+            (
+                if comparator.compare(other_min_key, min_key).is_lt() {
+                    other_min_key
+                } else {
+                    min_key
+                },
+                if comparator.compare(other_max_key, max_key).is_gt() {
+                    other_max_key
+                } else {
+                    max_key
+                },
+            )
+        })
+}
+
+/// The number of bytes of keys and values, as the partitions are cut by.
+fn data_size(sstable: &SSTable) -> usize {
+    let properties = sstable.properties();
+    properties.raw_key_bytes + properties.raw_value_bytes
+}
+
+fn sstable_input(sstable: &Arc<SSTable>) -> CompactionInput {
+    CompactionInput {
+        file_path: sstable.file_path().to_path_buf(),
+        gen_no: Some(sstable.gen_no()),
+        size: sstable.file_size(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CompactionKind, CompactionPlan};
+    use crate::catalog::Catalog;
+    use crate::options::Options;
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    #[test]
+    fn test_plan_compaction() {
+        const FOLDER_PATH: &str = "/tmp/naive_kv/test_plan_compaction/";
+
+        let _ = std::fs::remove_dir_all(FOLDER_PATH);
+        let options = Options {
+            memtable_compaction_threshold: 256,
+            ..Options::default()
+        };
+        let catalog = Catalog::open(PathBuf::from(FOLDER_PATH), options).unwrap();
+        assert!(catalog.plan_compaction().unwrap().is_empty());
+
+        for num in 0..100 {
+            catalog
+                .memtable
+                .set(num.to_string(), num.to_string(), num + 1)
+                .unwrap();
+        }
+        let plans = catalog.plan_compaction().unwrap();
+        assert_eq!(plans.len(), 1);
+        let CompactionPlan {
+            kind,
+            inputs,
+            output_gen_no,
+            estimated_output_size,
+            estimated_duration,
+        } = &plans[0];
+        assert_eq!(*kind, CompactionKind::Flush);
+        assert_eq!(inputs.len(), 1);
+        assert_eq!(inputs[0].gen_no, None);
+        assert_eq!(*output_gen_no, 0);
+        assert_eq!(*estimated_output_size, catalog.memtable.data_size());
+        assert_eq!(*estimated_duration, None);
+
+        // The estimate follows the historical throughput.
+        catalog
+            .record_compaction(*estimated_output_size * 2, Duration::from_secs(2))
+            .unwrap();
+        let plans = catalog.plan_compaction().unwrap();
+        let estimated_duration = plans[0].estimated_duration.unwrap();
+        assert!(estimated_duration.abs_diff(Duration::from_secs(1)) < Duration::from_millis(1));
+    }
+}
@@ -0,0 +1,235 @@
+//! The order of keys, which every layer of the engine consults through the same comparator in
+//! the options: the Memtable, the merges into the SSTables and their indexes, the scans and the
+//! range locks. Scans yield the keys in strictly increasing order under it, and so do the
+//! SSTables hold them.
+//!
+//! The order is part of the data folder. The manifest records the name of the comparator, and
+//! opening the folder with another one fails with ComparatorMismatch, as does opening an SSTable
+//! whose keys are out of its order.
+
+use std::cmp::Ordering;
+use std::fmt::Debug;
+use std::ops::{Bound, RangeBounds};
+
+/// Defines the order of keys, which must stay the same across restarts of a data folder.
+///
+/// The order must be total and consistent, i.e. a key compares equal only to itself, and
+/// reversing the arguments reverses the result.
+pub trait Comparator: Debug + Send + Sync {
+    fn compare(&self, a: &str, b: &str) -> Ordering;
+
+    /// The name recorded in the manifest, which must change whenever the order does, and only
+    /// then, since a data folder fails to open under another name.
+    fn name(&self) -> &str;
+
+    /// Whether the keys starting with any string come together right from that string, which
+    /// lets prefix scans seek to the prefix and stop at the first key without it.
+    fn keeps_prefixes_contiguous(&self) -> bool {
+        false
+    }
+}
+
+impl dyn Comparator {
+    /// Whether the key falls into the range under this order.
+    pub fn contains<R: RangeBounds<String>>(&self, range: &R, key: &str) -> bool {
+        self.is_after_start(range.start_bound(), key) && self.is_before_end(range.end_bound(), key)
+    }
+
+    /// Whether the key is not before the start bound under this order.
+    pub fn is_after_start(&self, start: Bound<&String>, key: &str) -> bool {
+        match start {
+            Bound::Included(start) => self.compare(key, start) != Ordering::Less,
+            Bound::Excluded(start) => self.compare(key, start) == Ordering::Greater,
+            Bound::Unbounded => true,
+        }
+    }
+
+    /// Whether the key is not beyond the end bound under this order.
+    pub fn is_before_end(&self, end: Bound<&String>, key: &str) -> bool {
+        match end {
+            Bound::Included(end) => self.compare(key, end) != Ordering::Greater,
+            Bound::Excluded(end) => self.compare(key, end) == Ordering::Less,
+            Bound::Unbounded => true,
+        }
+    }
+
+    /// Whether no key can fall into the range under this order.
+    pub fn is_empty_range<R: RangeBounds<String>>(&self, range: &R) -> bool {
+        match (range.start_bound(), range.end_bound()) {
+            (Bound::Included(start), Bound::Included(end)) => {
+                self.compare(start, end) == Ordering::Greater
+            }
+            (Bound::Included(start) | Bound::Excluded(start), Bound::Excluded(end))
+            | (Bound::Excluded(start), Bound::Included(end)) => {
+                self.compare(start, end) != Ordering::Less
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Order keys byte-wise, which is how Rust strings are ordered.
+#[derive(Debug)]
+pub struct BytewiseComparator;
+
+impl Comparator for BytewiseComparator {
+    fn compare(&self, a: &str, b: &str) -> Ordering {
+        a.cmp(b)
+    }
+
+    fn name(&self) -> &str {
+        "naive_kv.BytewiseComparator"
+    }
+
+    fn keeps_prefixes_contiguous(&self) -> bool {
+        true
+    }
+}
+
+/// Order the embedded numbers in keys by their values, e.g. "user_2" comes before "user_10".
+#[derive(Debug)]
+pub struct NumericComparator;
+
+impl Comparator for NumericComparator {
+    fn compare(&self, a: &str, b: &str) -> Ordering {
+        let (mut a, mut b) = (a.as_bytes(), b.as_bytes());
+        loop {
+            match (a.first(), b.first()) {
+                (None, None) => return Ordering::Equal,
+                (None, Some(_)) => return Ordering::Less,
+                (Some(_), None) => return Ordering::Greater,
+                (Some(x), Some(y)) if x.is_ascii_digit() && y.is_ascii_digit() => {
+                    let (a_digits, a_rest) = split_digits(a);
+                    let (b_digits, b_rest) = split_digits(b);
+                    let a_number = trim_leading_zeros(a_digits);
+                    let b_number = trim_leading_zeros(b_digits);
+                    // Compare the values, and then the leading zeros to keep the order total.
+                    let ordering = a_number
+                        .len()
+                        .cmp(&b_number.len())
+                        .then_with(|| a_number.cmp(b_number))
+                        .then_with(|| a_digits.len().cmp(&b_digits.len()));
+                    if ordering != Ordering::Equal {
+                        return ordering;
+                    }
+                    a = a_rest;
+                    b = b_rest;
+                }
+                (Some(x), Some(y)) => {
+                    if x != y {
+                        return x.cmp(y);
+                    }
+                    a = &a[1..];
+                    b = &b[1..];
+                }
+            }
+        }
+    }
+
+    fn name(&self) -> &str {
+        "naive_kv.NumericComparator"
+    }
+}
+
+/// Look up a comparator of the engine by its name, e.g. the one recorded in a manifest.
+pub fn builtin_comparator(name: &str) -> Option<&'static dyn Comparator> {
+    [
+        &BytewiseComparator as &'static dyn Comparator,
+        &NumericComparator,
+    ]
+    .into_iter()
+    .find(|comparator| comparator.name() == name)
+}
+
+fn split_digits(bytes: &[u8]) -> (&[u8], &[u8]) {
+    let len = bytes
+        .iter()
+        .take_while(|byte| byte.is_ascii_digit())
+        .count();
+    bytes.split_at(len)
+}
+
+fn trim_leading_zeros(digits: &[u8]) -> &[u8] {
+    let len = digits.iter().take_while(|&&byte| byte == b'0').count();
+    &digits[len..]
+}
+
+/// A key in an ordered container, ordered by the comparator it carries.
+#[derive(Clone, Debug)]
+pub struct OrderedKey {
+    key: String,
+    comparator: &'static dyn Comparator,
+}
+
+impl OrderedKey {
+    pub fn new(key: String, comparator: &'static dyn Comparator) -> Self {
+        Self { key, comparator }
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.key
+    }
+
+    pub fn into_string(self) -> String {
+        self.key
+    }
+
+    /// Convert the bounds of a range of strings into those of OrderedKey's.
+    pub fn bounds<R: RangeBounds<String>>(
+        range: &R,
+        comparator: &'static dyn Comparator,
+    ) -> (Bound<OrderedKey>, Bound<OrderedKey>) {
+        let convert = |bound: Bound<&String>| bound.map(|key| Self::new(key.clone(), comparator));
+        (convert(range.start_bound()), convert(range.end_bound()))
+    }
+}
+
+impl PartialEq for OrderedKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for OrderedKey {}
+
+impl PartialOrd for OrderedKey {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for OrderedKey {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.comparator.compare(&self.key, &other.key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_numeric_comparator() {
+        let comparator = NumericComparator;
+        let mut keys = vec![
+            "a10", "a2", "a02", "b", "a", "10", "9", "a2b", "a2a", "a1_5",
+        ];
+        keys.sort_by(|a, b| comparator.compare(a, b));
+        assert_eq!(
+            keys,
+            vec!["9", "10", "a", "a1_5", "a2", "a2a", "a2b", "a02", "a10", "b"]
+        );
+        for a in keys.iter() {
+            for b in keys.iter() {
+                assert_eq!(comparator.compare(a, b) == Ordering::Equal, a == b);
+                assert_eq!(comparator.compare(a, b), comparator.compare(b, a).reverse());
+            }
+        }
+    }
+
+    #[test]
+    fn test_name() {
+        assert_eq!(BytewiseComparator.name(), "naive_kv.BytewiseComparator");
+        assert_eq!(NumericComparator.name(), "naive_kv.NumericComparator");
+    }
+}
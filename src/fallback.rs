@@ -0,0 +1,186 @@
+use std::fmt::{self, Debug};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::catalog::CatalogViewer;
+use crate::client::{Client, NearCache};
+use crate::options::Options;
+use crate::types::Result;
+use crate::NaiveKV;
+
+/// The most connections to a remote server kept open while no get uses them.
+const MAX_IDLE_CLIENTS: usize = 8;
+
+/// The most keys remembered as absent from a remote server, and for how long.
+const ABSENT_KEYS_CAPACITY: usize = 10_000;
+const ABSENT_KEYS_TTL_S: u64 = 60;
+
+/// A secondary source for the keys never written locally, e.g. an archive restored from a backup
+/// or the cluster the data is migrating from lazily.
+pub trait ReadFallback: Debug + Send + Sync {
+    /// Get the value of the key, or None if the fallback lacks it too.
+    fn get(&self, key: &str) -> Result<Option<String>>;
+}
+
+/// Read through a data folder, e.g. restored from a backup, which is opened as a replica so that
+/// it is never written.
+pub struct ArchiveFallback {
+    folder_path: PathBuf,
+
+    /// Kept open for the viewer.
+    _naive_kv: NaiveKV,
+
+    catalog_viewer: Mutex<CatalogViewer>,
+}
+
+impl ArchiveFallback {
+    pub fn open(folder_path: impl AsRef<Path>, options: Options) -> Result<Self> {
+        let folder_path = folder_path.as_ref().to_path_buf();
+        let naive_kv = NaiveKV::open_replica(folder_path.clone(), options)?;
+        let catalog_viewer = Mutex::new(naive_kv.catalog_viewer()?);
+        Ok(Self {
+            folder_path,
+            _naive_kv: naive_kv,
+            catalog_viewer,
+        })
+    }
+}
+
+impl Debug for ArchiveFallback {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ArchiveFallback")
+            .field("folder_path", &self.folder_path)
+            .finish()
+    }
+}
+
+impl ReadFallback for ArchiveFallback {
+    fn get(&self, key: &str) -> Result<Option<String>> {
+        self.catalog_viewer.lock()?.get(key)
+    }
+}
+
+/// Read through a remote server, e.g. of the cluster the data is migrating from.
+///
+/// The connections are only opened once the gets need them, so that the remote server need not
+/// be up for this one to start, and are pooled for the concurrent gets, each dropped after a
+/// failure. The keys the remote server lacks are remembered for a while, so that the misses of
+/// the keys never written anywhere do not each take a round trip.
+pub struct RemoteFallback {
+    address: String,
+    timeout: Duration,
+
+    /// The connections not in use by any get.
+    idle_clients: Mutex<Vec<Client>>,
+
+    /// The keys found absent, along with when they are to be asked for again.
+    absent_keys: Mutex<NearCache>,
+}
+
+impl RemoteFallback {
+    pub fn new(address: &str, timeout: Duration) -> Self {
+        Self {
+            address: address.to_owned(),
+            timeout,
+            idle_clients: Mutex::new(Vec::new()),
+            absent_keys: Mutex::new(NearCache::new(
+                ABSENT_KEYS_CAPACITY,
+                Duration::from_secs(ABSENT_KEYS_TTL_S),
+            )),
+        }
+    }
+}
+
+impl Debug for RemoteFallback {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RemoteFallback")
+            .field("address", &self.address)
+            .finish()
+    }
+}
+
+impl ReadFallback for RemoteFallback {
+    fn get(&self, key: &str) -> Result<Option<String>> {
+        if let Some(None) = self.absent_keys.lock()?.get(key) {
+            return Ok(None);
+        }
+        let idle_client = self.idle_clients.lock()?.pop();
+        let mut client = match idle_client {
+            Some(client) => client,
+            None => Client::connect_with_timeout(self.address.as_str(), self.timeout)?,
+        };
+        // A failed connection is dropped, and the next get opens a new one.
+        let value = client.get(key)?;
+        {
+            let mut idle_clients = self.idle_clients.lock()?;
+            if idle_clients.len() < MAX_IDLE_CLIENTS {
+                idle_clients.push(client);
+            }
+        }
+        if value.is_none() {
+            self.absent_keys.lock()?.insert(key.to_owned(), None);
+        }
+        Ok(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protos::messages::{Request, Response, Status};
+    use crate::utils;
+    use std::net::TcpListener;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn test_remote_fallback() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let fallback = RemoteFallback::new(&address, Duration::from_secs(5));
+
+        // Nothing is connected before the first get.
+        listener.set_nonblocking(true).unwrap();
+        assert!(listener.accept().is_err());
+        listener.set_nonblocking(false).unwrap();
+
+        // A fake server holding a single key, which breaks its first connection after a get.
+        let num_gets = AtomicUsize::new(0);
+        std::thread::scope(|scope| {
+            let (listener, num_gets) = (&listener, &num_gets);
+            scope.spawn(move || {
+                for (num_connections, stream) in listener.incoming().take(2).enumerate() {
+                    let mut stream = stream.unwrap();
+                    while let Some(request) =
+                        utils::read_message::<Request, _>(&mut stream).unwrap()
+                    {
+                        num_gets.fetch_add(1, Ordering::SeqCst);
+                        let mut response = Response::new();
+                        response.set_id(request.get_id());
+                        match request.get_key() {
+                            "key" => response.set_value("value".to_owned()),
+                            _ => response.set_status(Status::KEY_NOT_FOUND),
+                        }
+                        utils::write_message(&response, &mut stream).unwrap();
+                        if num_connections == 0 && request.get_key() == "broken" {
+                            break;
+                        }
+                    }
+                }
+            });
+
+            // The connection is reused, and the absent key is only asked for once.
+            assert_eq!(fallback.get("key").unwrap(), Some("value".to_owned()));
+            assert_eq!(fallback.get("absent").unwrap(), None);
+            assert_eq!(fallback.get("absent").unwrap(), None);
+            assert_eq!(num_gets.load(Ordering::SeqCst), 2);
+
+            // A broken connection fails a get, after which a new one is opened.
+            assert_eq!(fallback.get("broken").unwrap(), None);
+            assert!(fallback.get("key").is_err());
+            assert_eq!(fallback.get("key").unwrap(), Some("value".to_owned()));
+            assert_eq!(num_gets.load(Ordering::SeqCst), 4);
+            fallback.idle_clients.lock().unwrap().clear();
+        });
+    }
+}
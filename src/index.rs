@@ -0,0 +1,328 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::ops::Bound;
+use std::sync::{Arc, Mutex};
+
+use crate::comparator::OrderedKey;
+use crate::types::Result;
+
+/// The file offsets of the chunks by their first keys.
+pub(crate) type ChunkIndex = BTreeMap<OrderedKey, u64>;
+
+/// The sparse index of an SSTable split into partitions of consecutive chunks, which a top-level
+/// index locates by their first keys. Only the partitions in use stay in memory, up to a number
+/// of them, and the others are loaded again from the segment file on demand.
+pub(crate) struct PartitionedIndex {
+    /// The partitions in increasing order of their first keys.
+    partitions: Vec<(OrderedKey, IndexPartition)>,
+
+    /// The chunks of the only partition, which is never evicted.
+    pinned: Option<Arc<ChunkIndex>>,
+
+    /// The partitions loaded, from the most to the least recently used.
+    resident: Mutex<VecDeque<(usize, Arc<ChunkIndex>)>>,
+
+    /// The number of partitions to keep in memory at most.
+    max_resident: usize,
+}
+
+/// Where a partition of the index lies in the segment file.
+struct IndexPartition {
+    /// The offset of the first chunk.
+    offset: u64,
+
+    /// The offset right after the last chunk.
+    end_offset: u64,
+
+    /// The number of chunks before the partition.
+    first_chunk_no: usize,
+
+    num_chunks: usize,
+}
+
+impl PartitionedIndex {
+    /// Split the chunks ending at the given offset into partitions of the given number of chunks,
+    /// or keep them in a single one if zero.
+    pub(crate) fn new(
+        chunks: ChunkIndex,
+        end_offset: u64,
+        partition_size: usize,
+        max_resident: usize,
+    ) -> Self {
+        let partition_size = if partition_size == 0 {
+            chunks.len().max(1)
+        } else {
+            partition_size
+        };
+        let mut partitions = Vec::<(OrderedKey, IndexPartition)>::new();
+        for (chunk_no, (key, &offset)) in chunks.iter().enumerate() {
+            if chunk_no % partition_size == 0 {
+                if let Some((_, partition)) = partitions.last_mut() {
+                    partition.end_offset = offset;
+                }
+                partitions.push((
+                    key.clone(),
+                    IndexPartition {
+                        offset,
+                        end_offset,
+                        first_chunk_no: chunk_no,
+                        num_chunks: 0,
+                    },
+                ));
+            }
+            partitions.last_mut().unwrap().1.num_chunks += 1;
+        }
+        let pinned = (partitions.len() == 1).then(|| Arc::new(chunks));
+        Self {
+            partitions,
+            pinned,
+            resident: Mutex::new(VecDeque::new()),
+            max_resident,
+        }
+    }
+
+    /// The first key of the first chunk.
+    #[cfg(test)]
+    pub(crate) fn first_key(&self) -> Option<&OrderedKey> {
+        self.partitions.first().map(|(key, _)| key)
+    }
+
+    pub(crate) fn num_chunks(&self) -> usize {
+        self.partitions.last().map_or(0, |(_, partition)| {
+            partition.first_chunk_no + partition.num_chunks
+        })
+    }
+
+    /// The number of partitions, of which a single one is never evicted.
+    pub(crate) fn num_partitions(&self) -> usize {
+        self.partitions.len()
+    }
+
+    /// The number of partitions in memory.
+    pub(crate) fn num_resident_partitions(&self) -> usize {
+        match self.pinned {
+            Some(_) => 1,
+            None => self.resident.lock().map_or(0, |resident| resident.len()),
+        }
+    }
+
+    /// The offset of the last chunk whose first key is not greater than the key, or None if the
+    /// key precedes all the chunks. The load reads the chunks between two offsets of the file.
+    pub(crate) fn floor(
+        &self,
+        key: &OrderedKey,
+        load: impl FnOnce(u64, u64) -> Result<ChunkIndex>,
+    ) -> Result<Option<u64>> {
+        let partition_no = match self
+            .partitions
+            .partition_point(|(first_key, _)| first_key <= key)
+        {
+            0 => return Ok(None),
+            partition_no => partition_no - 1,
+        };
+        let chunks = self.partition(partition_no, load)?;
+        Ok(chunks.range(..=key).next_back().map(|(_, &offset)| offset))
+    }
+
+    /// The offset of the first chunk whose first key comes after the key, or at it if inclusive,
+    /// or None if there is no such chunk.
+    pub(crate) fn ceiling(
+        &self,
+        key: &OrderedKey,
+        is_inclusive: bool,
+        load: impl FnOnce(u64, u64) -> Result<ChunkIndex>,
+    ) -> Result<Option<u64>> {
+        let partition_no = self.partition_after(key, is_inclusive);
+        if partition_no > 0 {
+            let chunks = self.partition(partition_no - 1, load)?;
+            if let Some((_, &offset)) = chunks.range(Self::lower_bound(key, is_inclusive)).next() {
+                return Ok(Some(offset));
+            }
+        }
+        Ok(self
+            .partitions
+            .get(partition_no)
+            .map(|(_, partition)| partition.offset))
+    }
+
+    /// Like `ceiling`, but without loading any partition, so that the offset is only as precise
+    /// as the partitions unless the one holding the key is in memory.
+    pub(crate) fn ceiling_resident(&self, key: &OrderedKey, is_inclusive: bool) -> Option<u64> {
+        let partition_no = self.partition_after(key, is_inclusive);
+        if partition_no > 0 {
+            if let Some(chunks) = self.resident_partition(partition_no - 1) {
+                if let Some((_, &offset)) =
+                    chunks.range(Self::lower_bound(key, is_inclusive)).next()
+                {
+                    return Some(offset);
+                }
+            }
+        }
+        self.partitions
+            .get(partition_no)
+            .map(|(_, partition)| partition.offset)
+    }
+
+    /// The offset of the chunk numbered from zero, or None if there are not as many chunks.
+    pub(crate) fn nth(
+        &self,
+        chunk_no: usize,
+        load: impl FnOnce(u64, u64) -> Result<ChunkIndex>,
+    ) -> Result<Option<u64>> {
+        let partition_no = self
+            .partitions
+            .partition_point(|(_, partition)| partition.first_chunk_no <= chunk_no);
+        if partition_no == 0 || chunk_no >= self.num_chunks() {
+            return Ok(None);
+        }
+        let first_chunk_no = self.partitions[partition_no - 1].1.first_chunk_no;
+        let chunks = self.partition(partition_no - 1, load)?;
+        Ok(chunks.values().nth(chunk_no - first_chunk_no).copied())
+    }
+
+    /// The number of partitions starting before the key, or at it too if the key is excluded, the
+    /// last of which holds the chunk sought unless it starts the next partition.
+    fn partition_after(&self, key: &OrderedKey, is_inclusive: bool) -> usize {
+        self.partitions.partition_point(|(first_key, _)| {
+            if is_inclusive {
+                first_key < key
+            } else {
+                first_key <= key
+            }
+        })
+    }
+
+    fn lower_bound(
+        key: &OrderedKey,
+        is_inclusive: bool,
+    ) -> (Bound<&OrderedKey>, Bound<&OrderedKey>) {
+        if is_inclusive {
+            (Bound::Included(key), Bound::Unbounded)
+        } else {
+            (Bound::Excluded(key), Bound::Unbounded)
+        }
+    }
+
+    fn resident_partition(&self, partition_no: usize) -> Option<Arc<ChunkIndex>> {
+        if let Some(chunks) = self.pinned.as_ref() {
+            return Some(chunks.clone());
+        }
+        let resident = self.resident.lock().ok()?;
+        resident
+            .iter()
+            .find(|(resident_no, _)| *resident_no == partition_no)
+            .map(|(_, chunks)| chunks.clone())
+    }
+
+    /// Get the chunks of the partition, loading them if not in memory, which evicts the least
+    /// recently used partition if there are too many.
+    fn partition(
+        &self,
+        partition_no: usize,
+        load: impl FnOnce(u64, u64) -> Result<ChunkIndex>,
+    ) -> Result<Arc<ChunkIndex>> {
+        if let Some(chunks) = self.pinned.as_ref() {
+            return Ok(chunks.clone());
+        }
+        {
+            let mut resident = self.resident.lock()?;
+            if let Some(position) = resident
+                .iter()
+                .position(|(resident_no, _)| *resident_no == partition_no)
+            {
+                let entry = resident.remove(position).unwrap();
+                let chunks = entry.1.clone();
+                resident.push_front(entry);
+                return Ok(chunks);
+            }
+        }
+
+        // Load the partition without holding the lock, so that the lookups of the others go on.
+        let partition = &self.partitions[partition_no].1;
+        let chunks = Arc::new(load(partition.offset, partition.end_offset)?);
+        let mut resident = self.resident.lock()?;
+        if !resident
+            .iter()
+            .any(|(resident_no, _)| *resident_no == partition_no)
+        {
+            resident.push_front((partition_no, chunks.clone()));
+            resident.truncate(self.max_resident);
+        }
+        Ok(chunks)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::comparator::BytewiseComparator;
+
+    #[test]
+    fn test_partitioned_index() {
+        const NUM_CHUNKS: usize = 100;
+        const CHUNK_SIZE: u64 = 10;
+
+        let key =
+            |chunk_no: usize| OrderedKey::new(format!("key{:03}", chunk_no), &BytewiseComparator);
+        let chunks = (0..NUM_CHUNKS)
+            .map(|chunk_no| (key(chunk_no), chunk_no as u64 * CHUNK_SIZE))
+            .collect::<ChunkIndex>();
+        let end_offset = NUM_CHUNKS as u64 * CHUNK_SIZE;
+        // Load the partitions out of the chunks, counting the loads.
+        let num_loads = std::cell::Cell::new(0);
+        let load = |offset: u64, end_offset: u64| {
+            num_loads.set(num_loads.get() + 1);
+            Ok(chunks
+                .iter()
+                .filter(|(_, &chunk_offset)| offset <= chunk_offset && chunk_offset < end_offset)
+                .map(|(key, &offset)| (key.clone(), offset))
+                .collect())
+        };
+
+        let index = PartitionedIndex::new(chunks.clone(), end_offset, 16, 2);
+        assert_eq!(index.num_chunks(), NUM_CHUNKS);
+        assert_eq!(index.num_partitions(), 7);
+        assert_eq!(index.num_resident_partitions(), 0);
+        assert_eq!(index.first_key(), Some(&key(0)));
+
+        let before = OrderedKey::new("a".to_owned(), &BytewiseComparator);
+        let after = OrderedKey::new("z".to_owned(), &BytewiseComparator);
+        assert_eq!(index.floor(&before, load).unwrap(), None);
+        assert_eq!(index.floor(&after, load).unwrap(), Some(990));
+        for chunk_no in 0..NUM_CHUNKS {
+            let offset = chunk_no as u64 * CHUNK_SIZE;
+            assert_eq!(index.floor(&key(chunk_no), load).unwrap(), Some(offset));
+            assert_eq!(
+                index.ceiling(&key(chunk_no), true, load).unwrap(),
+                Some(offset)
+            );
+            assert_eq!(
+                index.ceiling(&key(chunk_no), false, load).unwrap(),
+                (chunk_no + 1 < NUM_CHUNKS).then_some(offset + CHUNK_SIZE)
+            );
+            assert_eq!(index.nth(chunk_no, load).unwrap(), Some(offset));
+        }
+        assert_eq!(index.nth(NUM_CHUNKS, load).unwrap(), None);
+        assert_eq!(index.ceiling(&before, false, load).unwrap(), Some(0));
+        assert_eq!(index.ceiling(&after, true, load).unwrap(), None);
+
+        // Only the two partitions used last stay in memory.
+        assert_eq!(index.num_resident_partitions(), 2);
+        let num_loads_before = num_loads.get();
+        index.floor(&key(99), load).unwrap();
+        index.floor(&key(85), load).unwrap();
+        assert_eq!(num_loads.get(), num_loads_before);
+        index.floor(&key(0), load).unwrap();
+        assert_eq!(num_loads.get(), num_loads_before + 1);
+        assert_eq!(index.num_resident_partitions(), 2);
+
+        // Without loading, the offsets are exact for the partitions in memory only.
+        assert_eq!(index.ceiling_resident(&key(3), true), Some(30));
+        assert_eq!(index.ceiling_resident(&key(20), true), Some(320));
+
+        // A single partition is kept in memory for good.
+        let index = PartitionedIndex::new(chunks, end_offset, 0, 2);
+        assert_eq!(index.num_partitions(), 1);
+        assert_eq!(index.num_resident_partitions(), 1);
+        assert_eq!(index.ceiling_resident(&key(20), true), Some(200));
+    }
+}
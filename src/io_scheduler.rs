@@ -0,0 +1,501 @@
+use std::cell::Cell;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::backend::{Backend, BackendFile, FolderLock, MappedFile};
+use crate::types::{NaiveError, Result};
+
+pub const DEFAULT_MAX_BACKGROUND_WAIT_MS: u64 = 5;
+
+/// The most bytes a background read goes through at a time, so that the foreground I/O preempts a
+/// large read ahead between its chunks rather than only before it.
+const MAX_BACKGROUND_CHUNK_SIZE: usize = 64 << 10; // 64KB
+
+/// Whose I/O a thread is doing, which tells the I/O scheduler what may be held back.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum IoPriority {
+    /// Serving the clients, e.g. gets and the writes to the Memtable logs.
+    Foreground,
+    /// Maintenance, e.g. flushes, merges and the backups run as background jobs.
+    Background,
+}
+
+thread_local! {
+    static PRIORITY: Cell<IoPriority> = const { Cell::new(IoPriority::Foreground) };
+}
+
+/// The priority of the I/O done by the current thread, which is foreground unless set otherwise.
+pub fn current_priority() -> IoPriority {
+    PRIORITY.with(Cell::get)
+}
+
+/// Run a function with the I/O done by the current thread at the priority, restoring the previous
+/// one afterwards.
+pub fn with_priority<T>(priority: IoPriority, function: impl FnOnce() -> T) -> T {
+    struct Restore(IoPriority);
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            PRIORITY.with(|cell| cell.set(self.0));
+        }
+    }
+    let _restore = Restore(PRIORITY.with(|cell| cell.replace(priority)));
+    function()
+}
+
+/// A snapshot of what the I/O scheduler has done.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct IoStats {
+    pub num_foreground_ops: u64,
+    pub num_background_ops: u64,
+    pub background_bytes: u64,
+
+    /// The total time the background I/O has been held back, for the foreground I/O in flight
+    /// or by the rate limit.
+    pub background_delay: Duration,
+}
+
+#[derive(Debug)]
+struct IoState {
+    /// The bytes the background I/O may go through right away, negative once it runs ahead of the
+    /// rate limit.
+    tokens: f64,
+    last_refill: Instant,
+
+    /// Until when the background I/O goes on without waiting for the foreground one, once a
+    /// background read or write has waited out the bound, so that a steady foreground load holds
+    /// up a compaction by at most half rather than by the bound for each of its chunks.
+    background_pass_until: Instant,
+
+    stats: IoStats,
+}
+
+impl IoState {
+    /// Refill the token bucket for the time passed, holding at most a second worth of bytes, and
+    /// take the bytes out of it, returning how long to sleep off the debt if any.
+    fn take_tokens(&mut self, now: Instant, num_bytes: usize, bytes_per_s: u64) -> Duration {
+        let refill = now.duration_since(self.last_refill).as_secs_f64() * bytes_per_s as f64;
+        self.tokens = (self.tokens + refill).min(bytes_per_s as f64) - num_bytes as f64;
+        self.last_refill = now;
+        if self.tokens < 0.0 {
+            Duration::from_secs_f64(-self.tokens / bytes_per_s.max(1) as f64)
+        } else {
+            Duration::ZERO
+        }
+    }
+}
+
+/// Lets the foreground I/O preempt the background one on the same disk, to smooth out the latency
+/// of the clients during heavy compactions or backups.
+///
+/// Each background read or write waits for the foreground ones in flight to finish, but never
+/// longer than a bound, after which the background I/O goes on unhindered for as long so that
+/// the compactions do not starve, and then takes its bytes out of a token bucket if the
+/// background I/O is rate-limited. The foreground I/O never waits, and only touches the lock to
+/// wake up the background I/O waiting once the last foreground read or write in flight finishes.
+///
+/// A scheduler can be shared by the data folders on the same disk.
+#[derive(Debug)]
+pub struct IoScheduler {
+    /// The most time each background read or write waits for the foreground ones.
+    max_background_wait: Duration,
+
+    /// If set, the background I/O is limited to this number of bytes per second on average.
+    background_bytes_per_s: Option<u64>,
+
+    /// The number of foreground reads and writes in flight.
+    num_foreground: AtomicUsize,
+    num_foreground_ops: AtomicU64,
+
+    /// The number of background reads and writes waiting for the foreground ones to finish.
+    num_background_waiting: AtomicUsize,
+
+    state: Mutex<IoState>,
+    foreground_done: Condvar,
+}
+
+impl IoScheduler {
+    pub fn new(max_background_wait: Duration, background_bytes_per_s: Option<u64>) -> Self {
+        let now = Instant::now();
+        Self {
+            max_background_wait,
+            background_bytes_per_s,
+            num_foreground: AtomicUsize::new(0),
+            num_foreground_ops: AtomicU64::new(0),
+            num_background_waiting: AtomicUsize::new(0),
+            state: Mutex::new(IoState {
+                tokens: 0.0,
+                last_refill: now,
+                background_pass_until: now,
+                stats: IoStats::default(),
+            }),
+            foreground_done: Condvar::new(),
+        }
+    }
+
+    pub fn stats(&self) -> Result<IoStats> {
+        Ok(IoStats {
+            num_foreground_ops: self.num_foreground_ops.load(Ordering::Relaxed),
+            ..self.state.lock()?.stats.clone()
+        })
+    }
+
+    /// Run a read or a write of about the number of bytes at the priority of the current thread.
+    fn schedule<T>(&self, num_bytes: usize, io: impl FnOnce() -> T) -> Result<T> {
+        match current_priority() {
+            IoPriority::Foreground => {
+                self.num_foreground.fetch_add(1, Ordering::SeqCst);
+                self.num_foreground_ops.fetch_add(1, Ordering::Relaxed);
+                let result = io();
+                // Either this sees a background read or write waiting, or it sees nothing in
+                // flight before it starts to wait.
+                if self.num_foreground.fetch_sub(1, Ordering::SeqCst) == 1
+                    && self.num_background_waiting.load(Ordering::SeqCst) > 0
+                {
+                    let _state = self.state.lock()?;
+                    self.foreground_done.notify_all();
+                }
+                Ok(result)
+            }
+            IoPriority::Background => {
+                self.wait_for_background(num_bytes)?;
+                Ok(io())
+            }
+        }
+    }
+
+    fn wait_for_background(&self, num_bytes: usize) -> Result<()> {
+        let start_time = Instant::now();
+        let mut state = self.state.lock()?;
+        if start_time >= state.background_pass_until {
+            self.num_background_waiting.fetch_add(1, Ordering::SeqCst);
+            let wait_result =
+                self.foreground_done
+                    .wait_timeout_while(state, self.max_background_wait, |_| {
+                        self.num_foreground.load(Ordering::SeqCst) > 0
+                    });
+            self.num_background_waiting.fetch_sub(1, Ordering::SeqCst);
+            let timeout_result;
+            (state, timeout_result) = wait_result.map_err(|_| NaiveError::MutexLockError)?;
+            if timeout_result.timed_out() {
+                state.background_pass_until = Instant::now() + self.max_background_wait;
+            }
+        }
+
+        let throttle = match self.background_bytes_per_s {
+            Some(bytes_per_s) => state.take_tokens(Instant::now(), num_bytes, bytes_per_s),
+            None => Duration::ZERO,
+        };
+        state.stats.num_background_ops += 1;
+        state.stats.background_bytes += num_bytes as u64;
+        state.stats.background_delay += start_time.elapsed() + throttle;
+        drop(state);
+
+        // Sleep off the debt without holding the lock, so that the other background I/O goes on.
+        if !throttle.is_zero() {
+            std::thread::sleep(throttle);
+        }
+        Ok(())
+    }
+}
+
+/// A backend whose file reads and writes go through an I/O scheduler.
+#[derive(Debug)]
+pub struct ScheduledBackend {
+    backend: Arc<dyn Backend>,
+    io_scheduler: Arc<IoScheduler>,
+}
+
+impl ScheduledBackend {
+    pub fn new(backend: Arc<dyn Backend>, io_scheduler: Arc<IoScheduler>) -> Self {
+        Self {
+            backend,
+            io_scheduler,
+        }
+    }
+
+    fn wrap(&self, file: Box<dyn BackendFile>) -> Box<dyn BackendFile> {
+        Box::new(ScheduledFile {
+            file,
+            io_scheduler: self.io_scheduler.clone(),
+        })
+    }
+}
+
+impl Backend for ScheduledBackend {
+    fn create_dir_all(&self, folder_path: &Path) -> Result<()> {
+        self.backend.create_dir_all(folder_path)
+    }
+
+    fn list_files(&self, folder_path: &Path) -> Result<Vec<PathBuf>> {
+        self.backend.list_files(folder_path)
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        self.backend.exists(path)
+    }
+
+    fn open(&self, file_path: &Path) -> Result<Box<dyn BackendFile>> {
+        Ok(self.wrap(self.backend.open(file_path)?))
+    }
+
+    fn open_append(&self, file_path: &Path) -> Result<Box<dyn BackendFile>> {
+        Ok(self.wrap(self.backend.open_append(file_path)?))
+    }
+
+    fn create_new(&self, file_path: &Path) -> Result<Box<dyn BackendFile>> {
+        Ok(self.wrap(self.backend.create_new(file_path)?))
+    }
+
+    fn write_at(&self, file_path: &Path, offset: u64, bytes: &[u8]) -> Result<()> {
+        self.io_scheduler.schedule(bytes.len(), || {
+            self.backend.write_at(file_path, offset, bytes)
+        })?
+    }
+
+    fn replace(&self, file_path: &Path, bytes: &[u8]) -> Result<()> {
+        self.io_scheduler
+            .schedule(bytes.len(), || self.backend.replace(file_path, bytes))?
+    }
+
+    fn rename(&self, from_path: &Path, to_path: &Path) -> Result<()> {
+        self.backend.rename(from_path, to_path)
+    }
+
+    fn remove_file(&self, file_path: &Path) -> Result<bool> {
+        self.backend.remove_file(file_path)
+    }
+
+    fn remove_dir(&self, folder_path: &Path) -> Result<bool> {
+        self.backend.remove_dir(folder_path)
+    }
+
+    fn file_size(&self, file_path: &Path) -> Result<u64> {
+        self.backend.file_size(file_path)
+    }
+
+    fn modified_time(&self, file_path: &Path) -> Result<SystemTime> {
+        self.backend.modified_time(file_path)
+    }
+
+    fn set_modified_time(&self, file_path: &Path, modified_time: SystemTime) -> Result<()> {
+        self.backend.set_modified_time(file_path, modified_time)
+    }
+
+    fn link_or_copy(&self, from_path: &Path, to_path: &Path) -> Result<()> {
+        // Keep the hard links of the backend underneath, which move no bytes at all.
+        self.backend.link_or_copy(from_path, to_path)
+    }
+
+    fn lock_folder(
+        &self,
+        folder_path: &Path,
+        lock_name: &str,
+        is_exclusive: bool,
+    ) -> Result<FolderLock> {
+        self.backend
+            .lock_folder(folder_path, lock_name, is_exclusive)
+    }
+
+    fn map(&self, file_path: &Path) -> Result<Option<MappedFile>> {
+        // The reads through a map are page faults out of sight of the scheduler, but only the
+        // gets serving the clients take them.
+        self.backend.map(file_path)
+    }
+}
+
+/// A file of a scheduled backend.
+struct ScheduledFile {
+    file: Box<dyn BackendFile>,
+    io_scheduler: Arc<IoScheduler>,
+}
+
+impl Read for ScheduledFile {
+    fn read(&mut self, buffer: &mut [u8]) -> std::io::Result<usize> {
+        let file = &mut self.file;
+        self.io_scheduler
+            .schedule(buffer.len(), || file.read(buffer))
+            .map_err(io_error)?
+    }
+}
+
+impl Write for ScheduledFile {
+    fn write(&mut self, bytes: &[u8]) -> std::io::Result<usize> {
+        let file = &mut self.file;
+        self.io_scheduler
+            .schedule(bytes.len(), || file.write(bytes))
+            .map_err(io_error)?
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl Seek for ScheduledFile {
+    fn seek(&mut self, position: SeekFrom) -> std::io::Result<u64> {
+        self.file.seek(position)
+    }
+}
+
+impl BackendFile for ScheduledFile {
+    fn len(&self) -> Result<u64> {
+        self.file.len()
+    }
+
+    fn sync(&self) -> Result<()> {
+        self.file.sync()
+    }
+
+    fn read_at(&self, buffer: &mut [u8], offset: u64) -> std::io::Result<usize> {
+        if current_priority() == IoPriority::Foreground {
+            return self
+                .io_scheduler
+                .schedule(buffer.len(), || self.file.read_at(buffer, offset))
+                .map_err(io_error)?;
+        }
+        let mut num_bytes = 0;
+        for chunk in buffer.chunks_mut(MAX_BACKGROUND_CHUNK_SIZE) {
+            let chunk_offset = offset + num_bytes as u64;
+            let num_chunk_bytes = self
+                .io_scheduler
+                .schedule(chunk.len(), || self.file.read_at(chunk, chunk_offset))
+                .map_err(io_error)??;
+            num_bytes += num_chunk_bytes;
+            if num_chunk_bytes < chunk.len() {
+                break;
+            }
+        }
+        Ok(num_bytes)
+    }
+}
+
+fn io_error(error: NaiveError) -> std::io::Error {
+    std::io::Error::other(format!("failed to schedule I/O: {:?}", error))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::MemoryBackend;
+
+    #[test]
+    fn test_io_scheduler() {
+        let io_scheduler = Arc::new(IoScheduler::new(Duration::from_secs(3600), None));
+        let backend = ScheduledBackend::new(Arc::new(MemoryBackend::new()), io_scheduler.clone());
+        let file_path = Path::new("/memory/file");
+        backend.create_dir_all(Path::new("/memory")).unwrap();
+        backend
+            .create_new(file_path)
+            .unwrap()
+            .write_all(b"data")
+            .unwrap();
+        assert_eq!(io_scheduler.stats().unwrap().num_foreground_ops, 1);
+
+        // The background reads wait for a foreground write in flight to finish.
+        let done = Mutex::new(Vec::new());
+        let (release_sender, release_receiver) = crossbeam::channel::bounded::<()>(0);
+        std::thread::scope(|scope| {
+            let (io_scheduler, done) = (&io_scheduler, &done);
+            let (started_sender, started_receiver) = crossbeam::channel::bounded(0);
+            scope.spawn(move || {
+                io_scheduler
+                    .schedule(0, || {
+                        started_sender.send(()).unwrap();
+                        release_receiver.recv().unwrap();
+                        done.lock().unwrap().push(IoPriority::Foreground);
+                    })
+                    .unwrap()
+            });
+            started_receiver.recv().unwrap();
+            let background = scope.spawn(|| {
+                let mut buffer = Vec::new();
+                with_priority(IoPriority::Background, || {
+                    backend.open(file_path).unwrap().read_to_end(&mut buffer)
+                })
+                .unwrap();
+                done.lock().unwrap().push(IoPriority::Background);
+                assert_eq!(current_priority(), IoPriority::Foreground);
+                buffer
+            });
+            while io_scheduler.num_background_waiting.load(Ordering::SeqCst) == 0 {
+                std::thread::yield_now();
+            }
+            release_sender.send(()).unwrap();
+            assert_eq!(background.join().unwrap(), b"data");
+        });
+        assert_eq!(
+            *done.lock().unwrap(),
+            vec![IoPriority::Foreground, IoPriority::Background]
+        );
+        let stats = io_scheduler.stats().unwrap();
+        assert_eq!(stats.num_foreground_ops, 2);
+        assert!(stats.num_background_ops > 0);
+
+        // A large background read goes through in chunks, each scheduled on its own.
+        let large_file_path = Path::new("/memory/large_file");
+        let file_size = 3 * MAX_BACKGROUND_CHUNK_SIZE + 100;
+        backend
+            .create_new(large_file_path)
+            .unwrap()
+            .write_all(&vec![1u8; file_size])
+            .unwrap();
+        let num_background_ops = io_scheduler.stats().unwrap().num_background_ops;
+        let mut buffer = vec![0u8; file_size + 100];
+        let num_bytes = with_priority(IoPriority::Background, || {
+            backend
+                .open(large_file_path)
+                .unwrap()
+                .read_at(&mut buffer, 0)
+        })
+        .unwrap();
+        assert_eq!(num_bytes, file_size);
+        assert_eq!(
+            io_scheduler.stats().unwrap().num_background_ops,
+            num_background_ops + 4
+        );
+
+        // Without a bound to wait, the background I/O goes on past the foreground one.
+        let io_scheduler = IoScheduler::new(Duration::ZERO, None);
+        io_scheduler.num_foreground.fetch_add(1, Ordering::SeqCst);
+        with_priority(IoPriority::Background, || {
+            io_scheduler.schedule(1000, || ()).unwrap();
+        });
+        assert_eq!(io_scheduler.stats().unwrap().num_background_ops, 1);
+    }
+
+    #[test]
+    fn test_io_token_bucket() {
+        const BYTES_PER_S: u64 = 10_000;
+
+        let start_time = Instant::now();
+        let mut state = IoState {
+            tokens: 0.0,
+            last_refill: start_time,
+            background_pass_until: start_time,
+            stats: IoStats::default(),
+        };
+
+        // The debt is slept off at the rate.
+        let throttle = state.take_tokens(start_time, 1000, BYTES_PER_S);
+        assert_eq!(throttle, Duration::from_millis(100));
+        let now = start_time + throttle;
+        assert_eq!(
+            state.take_tokens(now, 1000, BYTES_PER_S),
+            Duration::from_millis(100)
+        );
+
+        // The bucket holds at most a second worth of bytes after a long pause.
+        let now = now + Duration::from_secs(10);
+        assert_eq!(
+            state.take_tokens(now, BYTES_PER_S as usize, BYTES_PER_S),
+            Duration::ZERO
+        );
+        assert_eq!(
+            state.take_tokens(now, 500, BYTES_PER_S),
+            Duration::from_millis(50)
+        );
+    }
+}
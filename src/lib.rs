@@ -1,161 +1,1215 @@
+pub mod acl;
+pub mod audit;
+pub mod backend;
+pub mod backup;
+mod blob;
+mod bloom;
 pub mod catalog;
+pub mod client;
+pub mod compaction;
+pub mod comparator;
+pub mod dump;
+pub mod fallback;
+mod index;
+pub mod io_scheduler;
+pub mod listener;
 pub mod logger;
+pub mod manifest;
 mod memtable;
+pub mod merge;
+pub mod options;
+pub mod prefix;
 pub mod protos;
+pub mod range_lock;
+mod readahead;
+pub mod scheduler;
+mod sequencer;
+pub mod snapshot;
 mod sstable;
+pub mod stats;
 pub mod thread_pool;
+pub mod trash;
+pub mod typed;
 pub mod types;
 pub mod utils;
+pub mod watch;
 
-use std::path::PathBuf;
-use std::sync::{Arc, Mutex, RwLock};
-use std::thread;
-use std::time::Duration;
+use crossbeam::channel::Receiver;
+use std::collections::BTreeMap;
+use std::ops::RangeBounds;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Instant;
 
+use crate::backend::Backend;
+use crate::backup::{BackupChain, BackupInfo};
 use crate::catalog::{Catalog, CatalogViewer};
+use crate::compaction::{CompactionKind, CompactionPlan};
+use crate::dump::DumpFormat;
+use crate::listener::{CompactionFailureEvent, SoftLimitEvent};
+use crate::manifest::Manifest;
 use crate::memtable::Memtable;
+use crate::options::Options;
+use crate::range_lock::{LockMode, RangeLockGuard, RangeLocks};
+use crate::scheduler::{JobSchedule, JobStatus, Scheduler};
+use crate::snapshot::Snapshot;
 use crate::sstable::SSTable;
-use crate::types::Result;
+use crate::stats::{ConsistencyReport, SSTableDescription, Stats};
+use crate::trash::Trash;
+use crate::types::{NaiveError, Result};
+use crate::watch::ChangeEvent;
+
+pub use crate::sstable::SSTableBuilder;
+
+/// The names of the background jobs of the engine.
+pub const FLUSH_JOB: &str = "flush";
+pub const MERGE_JOB: &str = "merge";
+pub const PURGE_TRASH_JOB: &str = "purge_trash";
+pub const CHECK_COMPACTION_BACKLOG_JOB: &str = "check_compaction_backlog";
+pub const REFRESH_REPLICA_JOB: &str = "refresh_replica";
+pub const EXPIRE_EPOCHS_JOB: &str = "expire_epochs";
+pub const EXPIRE_KEYS_JOB: &str = "expire_keys";
 
 /// The facade of the storage engine.
 pub struct NaiveKV {
-    /// The catalog of the data files.
+    /// The catalog of the data files of the default column family.
+    catalog: Arc<RwLock<Catalog>>,
+
+    /// The column families other than the default one, which the background jobs go through
+    /// along with it.
+    column_families: ColumnFamilies,
+
+    /// The default column family, which the others are created after.
+    default_column_family: Arc<ColumnFamily>,
+
+    /// The background jobs, such as flushes and merges, or refreshes of the catalog of a replica.
+    scheduler: Scheduler,
+
+    /// The key ranges locked by the bulk operations.
+    range_locks: Arc<RangeLocks>,
+
+    /// The epoch of the SSTables generated last, by a flush, a merge or an ingestion.
+    epoch_no: Arc<AtomicU64>,
+}
+
+type ColumnFamilies = Arc<RwLock<BTreeMap<String, Arc<ColumnFamily>>>>;
+
+/// A keyspace of the data folder with its own Memtable and SSTables, e.g. for the metadata of an
+/// application apart from its data. The default one is the data folder itself, and each other one
+/// is in a subfolder of it.
+struct ColumnFamily {
+    folder_path: PathBuf,
+    options: Options,
     catalog: Arc<RwLock<Catalog>>,
 
-    /// The compaction daemon.
-    daemon: Option<thread::JoinHandle<Result<()>>>,
+    /// Whether the compaction backlog was beyond the soft limit at the last check.
+    is_backlogged: AtomicBool,
+
+    /// Held shared by the flushes and the merges, and exclusively by an ingestion, so that no
+    /// generation moves while an SSTable is placed below the others.
+    compaction_lock: RwLock<()>,
+}
+
+impl ColumnFamily {
+    fn new(folder_path: PathBuf, options: Options, catalog: Catalog) -> Self {
+        Self {
+            folder_path,
+            options,
+            catalog: Arc::new(RwLock::new(catalog)),
+            is_backlogged: AtomicBool::new(false),
+            compaction_lock: RwLock::new(()),
+        }
+    }
+
+    /// The subfolder of a named column family, along with its options, whose cold folder is
+    /// a subfolder of the one of the data folder as well. The read fallback only serves the
+    /// default column family.
+    fn locate(folder_path: &Path, options: &Options, name: &str) -> Result<(PathBuf, Options)> {
+        let is_valid = !name.is_empty()
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+        if !is_valid {
+            return Err(NaiveError::InvalidOptions(format!(
+                "column family name {:?} must be non-empty and alphanumeric",
+                name
+            )));
+        }
+        let subfolder_name = format!("cf_{}", name);
+        let options = Options {
+            cold_folder_path: options
+                .cold_folder_path
+                .as_ref()
+                .map(|cold_folder_path| cold_folder_path.join(&subfolder_name)),
+            read_fallback: None,
+            // The subfolders come and go with the column families rather than the options.
+            create_if_missing: true,
+            error_if_exists: false,
+            ..options.clone()
+        };
+        Ok((folder_path.join(subfolder_name), options))
+    }
 
-    /// The shared flag for telling daemon to stop.
-    stop_flag: Arc<Mutex<bool>>,
+    /// Run a job on each column family, the default one first, returning the first error after
+    /// going through all of them, so that a failing one does not hold back the others.
+    fn for_each(
+        default_column_family: &Arc<ColumnFamily>,
+        column_families: &ColumnFamilies,
+        job: impl Fn(&ColumnFamily) -> Result<()>,
+    ) -> Result<()> {
+        let column_families = std::iter::once(default_column_family.clone())
+            .chain(column_families.read()?.values().cloned())
+            .collect::<Vec<_>>();
+        let mut result = Ok(());
+        for column_family in column_families.iter() {
+            result = result.and(job(column_family));
+        }
+        result
+    }
 }
 
 impl NaiveKV {
-    pub fn open(
+    /// Open the data folder, creating it if missing unless told otherwise by create_if_missing and
+    /// error_if_exists in the options. No other NaiveKV may open it, even in another process,
+    /// until this one and its catalog viewers drop, though replicas may.
+    pub fn open(folder_path: impl Into<PathBuf>, options: Options) -> Result<Self> {
+        options.validate()?;
+        let options = options.with_scheduled_backend();
+        let folder_path = folder_path.into();
+        let catalog = Catalog::open(folder_path.clone(), options.clone())?;
+        let mut last_epoch_no = catalog.last_epoch_no()?;
+        let mut column_families = BTreeMap::new();
+        for name in catalog.column_family_names()? {
+            let (cf_folder_path, cf_options) = ColumnFamily::locate(&folder_path, &options, &name)?;
+            let cf_catalog = Catalog::open(cf_folder_path.clone(), cf_options.clone())?;
+            last_epoch_no = last_epoch_no.max(cf_catalog.last_epoch_no()?);
+            column_families.insert(
+                name,
+                Arc::new(ColumnFamily::new(cf_folder_path, cf_options, cf_catalog)),
+            );
+        }
+        let default_column_family =
+            Arc::new(ColumnFamily::new(folder_path, options.clone(), catalog));
+        let column_families = Arc::new(RwLock::new(column_families));
+
+        // Background work runs on a dedicated pool, separate from the ones serving clients.
+        let scheduler = Scheduler::new(options.num_background_threads);
+        let range_locks = Arc::new(RangeLocks::new(options.comparator));
+
+        // Each flush, merge or ingestion installs SSTables of a new epoch, carrying on from the
+        // epochs of the last time the data folder was open.
+        let epoch_no = Arc::new(AtomicU64::new(last_epoch_no));
+
+        // Flushes and merges each run one at a time, but independently of each other, so that a
+        // long merge never holds back the flushes. Each run goes through all the column families.
+        {
+            let default_column_family = default_column_family.clone();
+            let column_families = column_families.clone();
+            let epoch_no = epoch_no.clone();
+            scheduler.register(FLUSH_JOB, options.job_schedule(FLUSH_JOB), move || {
+                ColumnFamily::for_each(&default_column_family, &column_families, |cf| {
+                    let _compaction_guard = cf.compaction_lock.read()?;
+                    let result = Self::flush(&cf.catalog, &epoch_no, &cf.options);
+                    Self::check_compaction(&cf.catalog, FLUSH_JOB, result)
+                })
+            })?;
+        }
+        {
+            let default_column_family = default_column_family.clone();
+            let column_families = column_families.clone();
+            let epoch_no = epoch_no.clone();
+            scheduler.register(MERGE_JOB, options.job_schedule(MERGE_JOB), move || {
+                ColumnFamily::for_each(&default_column_family, &column_families, |cf| {
+                    let _compaction_guard = cf.compaction_lock.read()?;
+                    let result = Self::merge(&cf.catalog, &epoch_no, &cf.options);
+                    Self::check_compaction(&cf.catalog, MERGE_JOB, result)
+                })
+            })?;
+        }
+        // Expire the files in the trash even if nothing gets compacted.
+        if let Some(trash) = Trash::new(&options) {
+            let default_column_family = default_column_family.clone();
+            let column_families = column_families.clone();
+            scheduler.register(
+                PURGE_TRASH_JOB,
+                options.job_schedule(PURGE_TRASH_JOB),
+                move || {
+                    ColumnFamily::for_each(&default_column_family, &column_families, |cf| {
+                        trash.purge(&cf.folder_path)?;
+                        match cf.options.cold_folder_path.as_ref() {
+                            Some(cold_folder_path) => trash.purge(cold_folder_path),
+                            None => Ok(()),
+                        }
+                    })
+                },
+            )?;
+        }
+        // Release the SSTables retired long enough ago even if nothing gets compacted.
+        if options.epoch_retention_s > 0 {
+            let default_column_family = default_column_family.clone();
+            let column_families = column_families.clone();
+            scheduler.register(
+                EXPIRE_EPOCHS_JOB,
+                options.job_schedule(EXPIRE_EPOCHS_JOB),
+                move || {
+                    ColumnFamily::for_each(&default_column_family, &column_families, |cf| {
+                        let num_sstables = cf.catalog.write()?.expire_retained_sstables();
+                        if num_sstables > 0 {
+                            log::info!(
+                                "Released {} SSTables retired beyond the epoch retention in {}.",
+                                num_sstables,
+                                cf.folder_path.display()
+                            );
+                        }
+                        Ok(())
+                    })
+                },
+            )?;
+        }
+        // Send the expiry of the values watched, which the reads stop seeing without any write.
+        {
+            let default_column_family = default_column_family.clone();
+            let column_families = column_families.clone();
+            scheduler.register(
+                EXPIRE_KEYS_JOB,
+                options.job_schedule(EXPIRE_KEYS_JOB),
+                move || {
+                    ColumnFamily::for_each(&default_column_family, &column_families, |cf| {
+                        let watchers = cf.catalog.read()?.watchers().clone();
+                        watchers.expire(utils::now_ms())?;
+                        Ok(())
+                    })
+                },
+            )?;
+        }
+        {
+            let default_column_family = default_column_family.clone();
+            let column_families = column_families.clone();
+            scheduler.register(
+                CHECK_COMPACTION_BACKLOG_JOB,
+                options.job_schedule(CHECK_COMPACTION_BACKLOG_JOB),
+                move || {
+                    ColumnFamily::for_each(&default_column_family, &column_families, |cf| {
+                        Self::check_compaction_backlog(&cf.catalog, &cf.options, &cf.is_backlogged)
+                    })
+                },
+            )?;
+        }
+        Ok(Self {
+            catalog: default_column_family.catalog.clone(),
+            column_families,
+            default_column_family,
+            scheduler,
+            range_locks,
+            epoch_no,
+        })
+    }
+
+    /// Verify the SSTables of a data folder, or of a copy of it such as a backup, against the
+    /// checksums in its manifest without opening it, returning the paths of the ones missing or
+    /// changed, so that a broken backup is found before it is needed.
+    pub fn verify_sstables(
         folder_path: impl Into<PathBuf>,
-        memtable_compaction_threshold: usize,
-        generation_geometric_ratio: usize,
-        compaction_daemon_cycle_s: u64,
-    ) -> Result<Self> {
-        let catalog = Arc::new(RwLock::new(Catalog::open(folder_path.into())?));
-        let catalog_copy = catalog.clone();
-
-        let stop_flag = Arc::new(Mutex::new(false));
-        let stop_flag_copy = stop_flag.clone();
-
-        let daemon = Some(thread::spawn(move || {
-            let mut epoch_no = 0;
-            while !*stop_flag_copy.lock()? {
-                thread::sleep(Duration::from_secs(compaction_daemon_cycle_s));
-                Self::compact(
-                    &*catalog_copy,
-                    &mut epoch_no,
-                    memtable_compaction_threshold,
-                    generation_geometric_ratio,
-                )?;
+        options: &Options,
+    ) -> Result<Vec<PathBuf>> {
+        let folder_path = folder_path.into();
+        let backend = options.backend.as_ref();
+        match Manifest::load(backend, &folder_path)? {
+            Some(manifest) => manifest.verify_sstables(backend, &folder_path),
+            None => {
+                log::error!("Found no manifest in {}.", folder_path.display());
+                Err(NaiveError::InvalidData)
             }
-            Ok(())
-        }));
+        }
+    }
+
+    /// Destroy a data folder, removing only the files the engine owns in it and in the subfolder of
+    /// each column family, as `Catalog::destroy` does, rather than the folder as a whole along
+    /// with whatever else is in it, e.g. the audit log.
+    ///
+    /// Fail with FolderInUse if any NaiveKV, even a replica in another process, has it open.
+    pub fn destroy(folder_path: impl Into<PathBuf>, options: &Options) -> Result<()> {
+        let folder_path = folder_path.into();
+        let backend = options.backend.as_ref();
+        if !backend.exists(&folder_path) {
+            return Ok(());
+        }
+        // Keep the data folder locked until the column families, which open along with it, are
+        // gone as well.
+        let _folder_locks = Catalog::lock_for_destroy(&folder_path, options)?;
+        let manifest = Manifest::load(backend, &folder_path)?.unwrap_or_default();
+        let mut num_files = 0;
+        for name in manifest.column_family_names.iter() {
+            let (cf_folder_path, cf_options) = ColumnFamily::locate(&folder_path, options, name)?;
+            if backend.exists(&cf_folder_path) {
+                let _cf_folder_locks = Catalog::lock_for_destroy(&cf_folder_path, &cf_options)?;
+                num_files += Catalog::destroy(&cf_folder_path, &cf_options)?;
+            }
+        }
+        num_files += Catalog::destroy(&folder_path, options)?;
+        log::info!(
+            "Destroyed {} by removing {} files.",
+            folder_path.display(),
+            num_files
+        );
+        Ok(())
+    }
+
+    /// Open a read-only replica of a data folder in use by another NaiveKV, possibly in another
+    /// process, which follows the writes by refreshing its catalog from the manifest and the
+    /// Memtable logs periodically.
+    ///
+    /// Reads may fail when racing a compaction on the primary that removes the files they are
+    /// about to open, in which case the next refresh picks up the replacements.
+    ///
+    /// The replica follows the column families created by the time it is opened.
+    pub fn open_replica(folder_path: impl Into<PathBuf>, options: Options) -> Result<Self> {
+        options.validate()?;
+        let options = options.with_scheduled_backend();
+        let folder_path = folder_path.into();
+        let catalog = Catalog::open_replica(folder_path.clone(), options.clone())?;
+        let mut column_families = BTreeMap::new();
+        for name in catalog.column_family_names()? {
+            let (cf_folder_path, cf_options) = ColumnFamily::locate(&folder_path, &options, &name)?;
+            let cf_catalog = Catalog::open_replica(cf_folder_path.clone(), cf_options.clone())?;
+            column_families.insert(
+                name,
+                Arc::new(ColumnFamily::new(cf_folder_path, cf_options, cf_catalog)),
+            );
+        }
+        let default_column_family =
+            Arc::new(ColumnFamily::new(folder_path, options.clone(), catalog));
+        let column_families = Arc::new(RwLock::new(column_families));
+
+        let scheduler = Scheduler::new(options.num_background_threads);
+        {
+            let default_column_family = default_column_family.clone();
+            let column_families = column_families.clone();
+            scheduler.register(
+                REFRESH_REPLICA_JOB,
+                options.job_schedule(REFRESH_REPLICA_JOB),
+                move || {
+                    ColumnFamily::for_each(&default_column_family, &column_families, |cf| {
+                        Catalog::refresh_replica(&cf.catalog)
+                    })
+                },
+            )?;
+        }
         Ok(Self {
-            catalog,
-            daemon,
-            stop_flag,
+            catalog: default_column_family.catalog.clone(),
+            column_families,
+            default_column_family,
+            scheduler,
+            range_locks: Arc::new(RangeLocks::new(options.comparator)),
+            epoch_no: Arc::default(),
         })
     }
 
+    /// Catch up a replica with its primary right away instead of waiting for the next refresh.
+    pub fn refresh(&self) -> Result<()> {
+        ColumnFamily::for_each(
+            &self.default_column_family,
+            &self.column_families,
+            |column_family| Catalog::refresh_replica(&column_family.catalog),
+        )
+    }
+
+    /// Stop the background jobs, waiting for the runs in progress, and sync the Memtable logs to
+    /// the disk, which dropping the engine does without telling how it went.
+    ///
+    /// Fails with the error of the job scheduler if it has stopped on its own, or otherwise with
+    /// the errors of all the failed runs of the jobs, e.g. flushes or merges, even those followed
+    /// by successful runs, once the logs are synced all the same.
+    pub fn close(mut self) -> Result<()> {
+        let scheduler_result = self.scheduler.stop();
+        ColumnFamily::for_each(
+            &self.default_column_family,
+            &self.column_families,
+            |column_family| column_family.catalog.read()?.sync_log(),
+        )?;
+        scheduler_result?;
+        let errors: Vec<_> = self
+            .scheduler
+            .jobs()?
+            .into_iter()
+            .flat_map(|job| {
+                let name = job.name;
+                job.errors
+                    .into_iter()
+                    .map(move |error| (name.clone(), error))
+            })
+            .collect();
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(NaiveError::JobFailed { errors })
+        }
+    }
+
+    /// Run a job of the application periodically along with the background jobs of the engine,
+    /// e.g. a backup.
+    pub fn register_job(
+        &self,
+        name: &str,
+        schedule: JobSchedule,
+        function: impl Fn() -> Result<()> + Send + Sync + 'static,
+    ) -> Result<()> {
+        self.scheduler.register(name, schedule, function)
+    }
+
+    /// Describe the background jobs in the order they were registered.
+    pub fn jobs(&self) -> Result<Vec<JobStatus>> {
+        self.scheduler.jobs()
+    }
+
+    /// Stop running a background job until it is resumed, e.g. merges during peak hours.
+    pub fn pause_job(&self, name: &str) -> Result<JobStatus> {
+        self.scheduler.pause(name)
+    }
+
+    pub fn resume_job(&self, name: &str) -> Result<JobStatus> {
+        self.scheduler.resume(name)
+    }
+
+    /// Change how often a background job runs.
+    pub fn reschedule_job(&self, name: &str, schedule: JobSchedule) -> Result<JobStatus> {
+        self.scheduler.reschedule(name, schedule)
+    }
+
+    /// Lock the key range for a bulk operation, e.g. exclusively for an import or a range
+    /// deletion, or shared for a backup, giving up with DeadlineExceeded once the deadline, if
+    /// any, passes. The lock is released once the guard drops.
+    ///
+    /// The locks only keep the bulk operations on overlapping ranges from running at once, while
+    /// the point reads and writes go on regardless.
+    pub fn lock_range<R: RangeBounds<String>>(
+        &self,
+        range: R,
+        mode: LockMode,
+        deadline: Option<Instant>,
+    ) -> Result<RangeLockGuard> {
+        self.range_locks.lock(range, mode, deadline)
+    }
+
     pub fn catalog_viewer(&self) -> Result<CatalogViewer> {
         CatalogViewer::new(self.catalog.clone())
     }
 
-    fn compact(
+    /// Create a column family, or do nothing if it exists already. Its name is made of ASCII
+    /// letters, digits, underscores and hyphens.
+    pub fn create_column_family(&self, name: &str) -> Result<()> {
+        let mut column_families = self.column_families.write()?;
+        if column_families.contains_key(name) {
+            return Ok(());
+        }
+        let default_column_family = &self.default_column_family;
+        let (folder_path, options) = ColumnFamily::locate(
+            &default_column_family.folder_path,
+            &default_column_family.options,
+            name,
+        )?;
+        let catalog = Catalog::open(folder_path.clone(), options.clone())?;
+        // Record the column family only once its subfolder is in place, so that it is found on
+        // the next open.
+        self.catalog.read()?.record_column_family(name)?;
+        column_families.insert(
+            name.to_owned(),
+            Arc::new(ColumnFamily::new(folder_path, options, catalog)),
+        );
+        log::info!("Created column family {}.", name);
+        Ok(())
+    }
+
+    /// The names of the column families other than the default one.
+    pub fn column_family_names(&self) -> Result<Vec<String>> {
+        Ok(self.column_families.read()?.keys().cloned().collect())
+    }
+
+    /// A viewer of a column family created before, which reads and writes it apart from the
+    /// others. A write batch never spans column families.
+    pub fn catalog_viewer_for(&self, name: &str) -> Result<CatalogViewer> {
+        match self.column_families.read()?.get(name) {
+            Some(column_family) => CatalogViewer::new(column_family.catalog.clone()),
+            None => Err(NaiveError::ColumnFamilyNotFound(name.to_owned())),
+        }
+    }
+
+    /// Take a snapshot of the data as of now, which pins the Memtables and SSTables so that the
+    /// reads through it are unaffected by later writes and compactions.
+    pub fn snapshot(&self) -> Result<Snapshot> {
+        Snapshot::new(&*self.catalog.read()?)
+    }
+
+    /// The epoch of the SSTables installed last, by a flush, a merge or an ingestion, which carries
+    /// on across the restarts of the data folder. The historical views reach no further back than
+    /// the epoch the data folder was opened at.
+    pub fn epoch_no(&self) -> u64 {
+        self.epoch_no.load(Ordering::SeqCst)
+    }
+
+    /// Open a historical view of the data as of the epoch, which reads only the SSTables live then
+    /// and skips the Memtables, e.g. for looking into the data before a bad write got flushed.
+    ///
+    /// Fail with EpochNotRetained unless the epoch is at most the current one and the SSTables
+    /// retired since are still kept, for which see `Options::epoch_retention_s`.
+    pub fn snapshot_at_epoch(&self, epoch_no: u64) -> Result<Snapshot> {
+        let catalog = self.catalog.read()?;
+        let latest_epoch_no = self.epoch_no();
+        match catalog.sstables_at_epoch(epoch_no) {
+            Some(sstables) if epoch_no <= latest_epoch_no => {
+                Ok(Snapshot::of_sstables(&catalog, sstables))
+            }
+            _ => Err(NaiveError::EpochNotRetained {
+                epoch_no,
+                oldest_epoch_no: catalog.oldest_retained_epoch_no(),
+                latest_epoch_no,
+            }),
+        }
+    }
+
+    /// Write a consistent copy of the data folder into the target folder, which opens like any
+    /// data folder, e.g. as a backup, as `Catalog::checkpoint` does. Each other column family is
+    /// then copied into its subfolder of the target on its own, so the copy is only consistent
+    /// within each column family.
+    pub fn checkpoint(&self, target_path: impl Into<PathBuf>) -> Result<()> {
+        let target_path = target_path.into();
+        Catalog::checkpoint(&self.catalog, &target_path)?;
+        let options = &self.default_column_family.options;
+        for (name, column_family) in self.column_families.read()?.iter() {
+            let (folder_path, _) = ColumnFamily::locate(&target_path, options, name)?;
+            Catalog::checkpoint(&column_family.catalog, &folder_path)?;
+        }
+        Ok(())
+    }
+
+    /// Back up the data folder into the backup folder incrementally, copying only the SSTables not
+    /// backed up there before along with the Memtable logs, and return the backup of the default
+    /// column family. Each other column family is backed up into its subfolder of the backup
+    /// folder under the same ID.
+    pub fn backup(&self, backup_path: impl Into<PathBuf>) -> Result<BackupInfo> {
+        let backup_path = backup_path.into();
+        let options = &self.default_column_family.options;
+        let backup_id = BackupChain::load(options.backend.as_ref(), &backup_path)?.next_backup_id();
+        let backup = backup::backup(&self.catalog, &backup_path, backup_id)?;
+        for (name, column_family) in self.column_families.read()?.iter() {
+            let (folder_path, _) = ColumnFamily::locate(&backup_path, options, name)?;
+            backup::backup(&column_family.catalog, &folder_path, backup_id)?;
+        }
+        Ok(backup)
+    }
+
+    /// The backups of the default column family in the backup folder, from the oldest to the
+    /// newest.
+    pub fn list_backups(
+        backup_path: impl Into<PathBuf>,
+        options: &Options,
+    ) -> Result<Vec<BackupInfo>> {
+        Ok(BackupChain::load(options.backend.as_ref(), &backup_path.into())?.backups)
+    }
+
+    /// Restore a backup of the backup folder, along with the column families backed up with it,
+    /// into the target folder, which then opens like any data folder. The files are verified as
+    /// `NaiveKV::restore` does.
+    pub fn restore_backup(
+        backup_path: impl Into<PathBuf>,
+        backup_id: u64,
+        target_path: impl Into<PathBuf>,
+        options: &Options,
+    ) -> Result<()> {
+        let backup_path = backup_path.into();
+        let target_path = target_path.into();
+        let backend = options.backend.as_ref();
+        let chain = BackupChain::load(backend, &backup_path)?;
+        let backup = chain.get(backup_id).ok_or_else(|| {
+            NaiveError::InvalidOptions(format!(
+                "found no backup {} in {}",
+                backup_id,
+                backup_path.display()
+            ))
+        })?;
+        backup::restore(backend, &backup_path, backup, &target_path)?;
+        let mut restored = vec![(backup.manifest.clone(), target_path.clone())];
+        let result = (|| {
+            for name in backup.manifest.column_family_names.iter() {
+                let (column_family_backup_path, _) =
+                    ColumnFamily::locate(&backup_path, options, name)?;
+                let (column_family_path, _) = ColumnFamily::locate(&target_path, options, name)?;
+                let column_family_chain = BackupChain::load(backend, &column_family_backup_path)?;
+                // The backup of a column family is missing if it failed after the default one.
+                if let Some(column_family_backup) = column_family_chain.get(backup_id) {
+                    backup::restore(
+                        backend,
+                        &column_family_backup_path,
+                        column_family_backup,
+                        &column_family_path,
+                    )?;
+                    restored.push((column_family_backup.manifest.clone(), column_family_path));
+                }
+            }
+            Ok(())
+        })();
+        if result.is_err() {
+            Self::unrestore(backend, &restored);
+        }
+        result
+    }
+
+    /// Restore the latest backup of the backup folder, or a checkpoint if the folder holds one
+    /// instead, into the target folder and open it, so that recovery is never a matter of copying
+    /// files by hand. The files are checked against the manifest before being copied, and the
+    /// SSTables verified against their checksums before the target is opened, failing with
+    /// CorruptBackup otherwise, once the files restored so far are removed again.
+    pub fn restore(
+        backup_path: impl Into<PathBuf>,
+        target_path: impl Into<PathBuf>,
+        options: Options,
+    ) -> Result<Self> {
+        let backup_path = backup_path.into();
+        let target_path = target_path.into();
+        let backend = options.backend.clone();
+        match BackupChain::load(backend.as_ref(), &backup_path)?
+            .backups
+            .last()
+        {
+            Some(backup) => {
+                Self::restore_backup(&backup_path, backup.backup_id, &target_path, &options)?
+            }
+            None => {
+                let manifest =
+                    backup::restore_checkpoint(backend.as_ref(), &backup_path, &target_path)?;
+                let mut restored = vec![(manifest.clone(), target_path.clone())];
+                let result = (|| -> Result<()> {
+                    for name in manifest.column_family_names.iter() {
+                        let (checkpoint_path, _) =
+                            ColumnFamily::locate(&backup_path, &options, name)?;
+                        let (column_family_path, _) =
+                            ColumnFamily::locate(&target_path, &options, name)?;
+                        let column_family_manifest = backup::restore_checkpoint(
+                            backend.as_ref(),
+                            &checkpoint_path,
+                            &column_family_path,
+                        )?;
+                        restored.push((column_family_manifest, column_family_path));
+                    }
+                    Ok(())
+                })();
+                if result.is_err() {
+                    Self::unrestore(backend.as_ref(), &restored);
+                }
+                result?;
+            }
+        }
+        Self::open(target_path, options)
+    }
+
+    /// Remove the data folders restored so far, the column families before the default one whose
+    /// folder holds theirs, once restoring another has failed, so that the target is left as
+    /// found rather than opening with some of the column families missing.
+    fn unrestore(backend: &dyn Backend, restored: &[(Manifest, PathBuf)]) {
+        for (manifest, folder_path) in restored.iter().rev() {
+            backup::unrestore(backend, manifest, folder_path);
+        }
+    }
+
+    /// Watch the keys of the default column family starting with the prefix, receiving each
+    /// value set or removal once it is applied to the Memtable, e.g. to invalidate a cache
+    /// without polling. A replica, which takes no writes, never sends any event.
+    ///
+    /// The events are buffered until received, up to `Options::watch_capacity`, so drop the
+    /// receiver once done with it. A receiver falling further behind has its channel disconnected
+    /// once drained, and has to watch again, having missed some writes. The ingested SSTables send
+    /// no event, and the expiring values written while watched send one more once they expire, as
+    /// swept by the expire_keys job.
+    pub fn watch(&self, prefix: &str) -> Result<Receiver<ChangeEvent>> {
+        self.catalog.read()?.watchers().add(prefix)
+    }
+
+    /// Write the key-value pairs of the default column family in the range, as of now, in the
+    /// format, e.g. for migrating the data or debugging, and return the number of pairs written.
+    pub fn dump<R: RangeBounds<String>>(
+        &self,
+        range: R,
+        format: DumpFormat,
+        writer: &mut impl std::io::Write,
+    ) -> Result<usize> {
+        dump::dump(&self.snapshot()?, range, format, writer)
+    }
+
+    /// Install a segment file written by an `SSTableBuilder` into the default column family as its
+    /// oldest generation, e.g. for a bulk load that bypasses the Memtable and its log, and return
+    /// the number of records ingested.
+    ///
+    /// The file is copied into the data folder, so it may be removed afterwards, and the keys
+    /// become readable all at once. Its keys must not overlap with any stored, even deleted, so
+    /// ingest into a fresh range, e.g. under a new prefix.
+    pub fn ingest(&self, file_path: impl Into<PathBuf>) -> Result<usize> {
+        let file_path = file_path.into();
+        let column_family = &self.default_column_family;
+        let options = &column_family.options;
+        // No flush or merge may move the generations until the SSTable is placed.
+        let _compaction_guard = column_family.compaction_lock.write()?;
+        let (gen_no, sstable_path) = {
+            let catalog = self.catalog.read()?;
+            let gen_no = catalog.num_generations();
+            let sstable_path =
+                Catalog::gen_sstable_path(catalog.sstable_folder_path(gen_no), gen_no);
+            (gen_no, sstable_path)
+        };
+        options.backend.copy(&file_path, &sstable_path)?;
+        let sstable = SSTable::open(sstable_path.clone(), options)
+            .and_then(|mut sstable| sstable.renumber(gen_no).map(|()| sstable));
+        let mut sstable = match sstable {
+            Ok(sstable) => sstable,
+            Err(error) => {
+                // Leave no stray segment file behind for the next open to trip over.
+                options.backend.remove_file(&sstable_path)?;
+                return Err(error);
+            }
+        };
+        let num_entries = sstable.num_entries();
+        let mut catalog = self.catalog.write()?;
+        // A new epoch lets the viewers tell the SSTable from any that was of the generation before.
+        sstable.set_epoch_no(self.epoch_no.fetch_add(1, Ordering::SeqCst) + 1);
+        catalog.install_sstable(sstable)?;
+        drop(catalog);
+        log::info!(
+            "Ingested {} records from {} as generation {}.",
+            num_entries,
+            file_path.display(),
+            gen_no
+        );
+        Ok(num_entries)
+    }
+
+    /// Check the consistency of a snapshot of the data, e.g. to gain confidence after an incident
+    /// or an upgrade, as `Snapshot::verify` does.
+    pub fn verify(&self) -> Result<ConsistencyReport> {
+        let start_time = Instant::now();
+        let report = self.snapshot()?.verify()?;
+        log::info!(
+            "Verified {} keys and {} tombstones in {:?} with {} problems found.",
+            report.num_keys,
+            report.num_tombstones,
+            start_time.elapsed(),
+            report.num_problems
+        );
+        Ok(report)
+    }
+
+    /// The sequence number of the last write visible to the reads, which never goes backwards, e.g.
+    /// for the clients to tell whether a server has caught up with a write.
+    pub fn visible_sequence_no(&self) -> Result<u64> {
+        self.catalog.read()?.visible_sequence_no()
+    }
+
+    pub fn stats(&self) -> Result<Stats> {
+        Ok(self.catalog.read()?.stats())
+    }
+
+    /// Describe the live SSTables in increasing generations.
+    pub fn describe(&self) -> Result<Vec<SSTableDescription>> {
+        Ok(self.catalog.read()?.describe_sstables())
+    }
+
+    /// The current value of a property of the internals of the default column family, named as
+    /// in `stats::PROPERTY_NAMES`, e.g. for monitoring, or None if there is no such property.
+    pub fn property(&self, name: &str) -> Result<Option<String>> {
+        self.catalog.read()?.property(name)
+    }
+
+    /// Plan the next flush and merge without running them, e.g. for scheduling heavy merges.
+    pub fn plan_compaction(&self) -> Result<Vec<CompactionPlan>> {
+        self.catalog.read()?.plan_compaction()
+    }
+
+    /// Read the parts of the SSTables holding the hot key ranges ahead of the first requests,
+    /// e.g. right after a restart, so that they do not wait on a cold disk, returning the number
+    /// of bytes read. A single key is warmed up with a range like `key.clone()..=key`.
+    ///
+    /// The indexes and the prefix filters of the SSTables are already in memory once opened.
+    pub fn warm_up<R: RangeBounds<String>>(&self, ranges: &[R]) -> Result<usize> {
+        // The SSTables are pinned, so the files stay readable without locking the catalog.
+        let sstables = self.catalog.read()?.sstables.clone();
+        let mut num_bytes = 0;
+        for sstable in sstables.iter() {
+            num_bytes += sstable.warm_up(ranges)?;
+        }
+        log::info!(
+            "Warmed up {} key ranges by reading {} bytes.",
+            ranges.len(),
+            num_bytes
+        );
+        Ok(num_bytes)
+    }
+
+    /// Warn once each time the compaction backlog grows beyond the soft limit.
+    fn check_compaction_backlog(
+        catalog: &RwLock<Catalog>,
+        options: &Options,
+        is_backlogged: &AtomicBool,
+    ) -> Result<()> {
+        let num_bytes = catalog.read()?.compaction_backlog()?;
+        let soft_limit = options.compaction_backlog_soft_limit;
+        if !is_backlogged.load(Ordering::SeqCst) && num_bytes > soft_limit {
+            let event = SoftLimitEvent::CompactionBacklog {
+                num_bytes,
+                soft_limit,
+            };
+            // The catalog is released before the event listeners are called.
+            let event_listeners = catalog.read()?.record_soft_limit(&event)?;
+            listener::notify_soft_limit(&event_listeners, &event);
+        }
+        is_backlogged.store(num_bytes > soft_limit, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Report a failed flush or merge through the stats and the event listeners, and pass the
+    /// error on for the job scheduler to back off from retrying it.
+    fn check_compaction(
         catalog: &RwLock<Catalog>,
-        epoch_no: &mut u64,
-        memtable_compaction_threshold: usize,
-        generation_geometric_ratio: usize,
+        job_name: &str,
+        result: Result<()>,
     ) -> Result<()> {
+        if let Err(error) = result.as_ref() {
+            let catalog = catalog.read()?;
+            let event = CompactionFailureEvent {
+                job_name: job_name.to_owned(),
+                folder_path: catalog.folder_path.clone(),
+                error: format!("{:?}", error),
+            };
+            let event_listeners = catalog.record_compaction_failure(&event)?;
+            // The catalog is released before the event listeners are called.
+            drop(catalog);
+            for event_listener in event_listeners.iter() {
+                event_listener.on_compaction_failure(&event);
+            }
+        }
+        result
+    }
+
+    /// Flush the Memtable, once it reaches the threshold, into generation 0 by merging it with
+    /// the current SSTable of generation 0.
+    ///
+    /// The writes are only blocked while the Memtables are swapped. The merge iterates over the
+    /// read-only Memtable, which no write can reach any more, without holding any lock.
+    ///
+    /// The Memtables never queue up behind a flush: the read-write one keeps taking the writes
+    /// beyond the threshold until the next flush, so that a burst of writes, with the overwrites
+    /// already folded in memory, makes a single SSTable of generation 0 rather than many small
+    /// ones. Likewise the logs left by a crash are merged in memory on open.
+    fn flush(catalog: &RwLock<Catalog>, epoch_no: &AtomicU64, options: &Options) -> Result<()> {
+        // Create the log of the new Memtable before locking anything, unless the read-only one of
+        // a failed flush is still pending, which is flushed first instead. Only this job swaps the
+        // Memtables of a primary, and the Memtable never shrinks in between, so the plan holds.
+        let rw_memtable = {
+            let catalog = catalog.read()?;
+            if compaction::plan_flush(&catalog)?.is_none() {
+                return Ok(());
+            }
+            if catalog.ro_memtable.is_some() {
+                None
+            } else {
+                let mut memtable =
+                    Memtable::open(Catalog::gen_memtable_path(&catalog.folder_path), options)?;
+                memtable.set_watchers(catalog.watchers().clone());
+                memtable.set_sequencer(catalog.sequencer().clone());
+                Some(memtable)
+            }
+        };
+
         let ro_memtable;
+        let sstables;
+        let is_bottom;
         let sstable_path;
-        let mut sstables = Vec::new();
-        let mut gen_no = 0; // The generation number of the new SSTable.
         {
             // Lock the catalog for a short duration.
             let mut catalog = catalog.write()?;
-            {
-                let mut memtable = catalog.memtable.write()?;
-                if memtable.data_size() < memtable_compaction_threshold {
-                    return Ok(());
+            ro_memtable = match rw_memtable {
+                Some(rw_memtable) => {
+                    // Record which log is which before the new Memtable takes any write, so that
+                    // recovery need not guess.
+                    if let Err(error) = catalog.record_memtable_swap(rw_memtable.log_path()) {
+                        rw_memtable.deprecate()?;
+                        return Err(error);
+                    }
+                    // Replace the current read-write Memtable with the new one, and move the old
+                    // one into the read-only stage.
+                    let ro_memtable =
+                        std::mem::replace(&mut catalog.memtable, Arc::new(rw_memtable));
+                    catalog.ro_memtable = Some(ro_memtable.clone());
+                    ro_memtable
                 }
-                *epoch_no += 1;
-
-                // Create a new Memtable to replace the current read-write Memtable.
-                let mut rw_memtable =
-                    Memtable::open(Catalog::gen_memtable_path(&catalog.folder_path))?;
-                std::mem::swap(&mut rw_memtable, &mut *memtable);
-                ro_memtable = Arc::new(rw_memtable);
-            }
-            // Move the old read-write Memtable into the read-only stage.
-            catalog.ro_memtable = Some(ro_memtable.clone());
-
-            // Copy pointers to the SSTables that should be merged.
-            let mut size = ro_memtable.data_size();
-            let mut size_threshold = memtable_compaction_threshold * generation_geometric_ratio;
-            for sstable in &catalog.sstables {
-                sstables.push(sstable.clone());
-                size += sstable.file_size();
-                if size < size_threshold {
-                    break;
-                }
-                gen_no += 1;
-                size_threshold *= generation_geometric_ratio;
-            }
-            sstable_path = Catalog::gen_sstable_path(&catalog.folder_path, sstables.len());
+                None => catalog.ro_memtable.clone().unwrap(),
+            };
+
+            sstables = catalog.generation(0).to_vec();
+            is_bottom = catalog.num_generations() <= 1;
+            sstable_path = Catalog::gen_sstable_path(&catalog.folder_path, 0);
         }
 
-        // Do the merge without locking the catalog.
-        let sstable = SSTable::create(sstable_path, &ro_memtable, &sstables, gen_no, *epoch_no)?;
+        // Do the merge without locking the catalog. The SSTable takes its epoch once installed.
+        let start_time = Instant::now();
+        let mut sstable = SSTable::create(
+            sstable_path,
+            Some(&ro_memtable),
+            &sstables,
+            is_bottom,
+            0,
+            0,
+            options,
+        )?;
 
         {
             // Lock the catalog again for a short duration.
             let mut catalog = catalog.write()?;
+            catalog.record_compaction(sstable.file_size(), start_time.elapsed())?;
+            let epoch_no = epoch_no.fetch_add(1, Ordering::SeqCst) + 1;
+            sstable.set_epoch_no(epoch_no);
+
+            // Place the new SSTable of generation 0. A merge in the meantime may have emptied the
+            // generation, but never put anything newer into it: merges never write generation 0,
+            // which only ever holds the single SSTable of the last flush, and so it is replaced
+            // as a whole.
+            let old_sstables = catalog.generation(0).to_vec();
+            catalog.sstables.retain(|sstable| sstable.gen_no() != 0);
+            catalog.sstables.insert(0, Arc::new(sstable));
 
-            // Remove the read-only Memtable.
+            // Remove the read-only Memtable and the old SSTable, only after the manifest no
+            // longer needs them.
+            let last_sequence_no = ro_memtable
+                .sequence_range()
+                .map_or(0, |(_, last_sequence_no)| last_sequence_no);
+            catalog.record_flush(last_sequence_no)?;
             catalog.ro_memtable.as_ref().unwrap().deprecate()?;
             catalog.ro_memtable = None;
-
-            // Place the merge-to SSTable.
-            if gen_no == catalog.sstables.len() {
-                catalog.sstables.push(Arc::new(sstable));
-            } else {
-                catalog.sstables[gen_no].deprecate()?;
-                catalog.sstables[gen_no] = Arc::new(sstable);
-            }
-            // Replace the merge-from SSTables with empty ones.
-            for i in 0..gen_no {
-                catalog.sstables[i].deprecate()?;
-                let sstable_path = Catalog::gen_sstable_path(&catalog.folder_path, i);
-                catalog.sstables[i] = Arc::new(SSTable::create_empty(sstable_path, i, *epoch_no)?);
+            for old_sstable in old_sstables {
+                catalog.retire_sstable(&old_sstable, epoch_no)?;
             }
+            catalog.collect_blob_garbage()?;
         }
         Ok(())
     }
-}
 
-impl Drop for NaiveKV {
-    fn drop(&mut self) {
-        *self.stop_flag.lock().unwrap() = true;
-        if let Some(daemon) = self.daemon.take() {
-            let _ = daemon
-                .join()
-                .expect("Failed to join the compaction daemon.");
+    /// Merge the youngest generation that exceeds its size limit into the partitions of the next
+    /// generation its keys overlap with, or merge the two oldest generations into one while there
+    /// are more than allowed, or otherwise merge the small partitions of a generation together.
+    fn merge(catalog: &RwLock<Catalog>, epoch_no: &AtomicU64, options: &Options) -> Result<()> {
+        let plan;
+        let sstables;
+        let is_bottom;
+        let sstable_folder_path;
+        {
+            let catalog = catalog.read()?;
+            plan = match compaction::plan_merge(&catalog) {
+                Some(plan) => plan,
+                None => return Ok(()),
+            };
+            // The inputs come in increasing generations, i.e. from the newest to the oldest.
+            sstables = catalog
+                .sstables
+                .iter()
+                .filter(|sstable| {
+                    plan.inputs
+                        .iter()
+                        .any(|input| input.file_path == sstable.file_path())
+                })
+                .cloned()
+                .collect::<Vec<_>>();
+            // Only merges push the data into older generations, and they run one at a time. The
+            // partitions of the output generation left out hold none of the keys merged.
+            is_bottom = plan.kind == CompactionKind::MergeOldest
+                || plan.output_gen_no + 1 >= catalog.num_generations();
+            sstable_folder_path = catalog
+                .sstable_folder_path(plan.output_gen_no)
+                .to_path_buf();
+        }
+
+        // Do the merge without locking the catalog. The SSTables take their epoch once installed.
+        let start_time = Instant::now();
+        let output_gen_no = plan.output_gen_no;
+        let mut new_sstables = SSTable::create_partitioned(
+            || Catalog::gen_sstable_path(&sstable_folder_path, output_gen_no),
+            None,
+            &sstables,
+            is_bottom,
+            output_gen_no,
+            0,
+            options.generation_partition_size,
+            options,
+        )?;
+
+        {
+            // Lock the catalog for a short duration.
+            let mut catalog = catalog.write()?;
+            let num_bytes = new_sstables.iter().map(|sstable| sstable.file_size()).sum();
+            catalog.record_compaction(num_bytes, start_time.elapsed())?;
+            let epoch_no = epoch_no.fetch_add(1, Ordering::SeqCst) + 1;
+            for new_sstable in new_sstables.iter_mut() {
+                new_sstable.set_epoch_no(epoch_no);
+            }
+
+            if plan.kind == CompactionKind::MergeOldest {
+                // Replace the two oldest generations with the merged one.
+                catalog.replace_sstables(&sstables, new_sstables, epoch_no)?;
+                catalog.record_sstables()?;
+                catalog.collect_blob_garbage()?;
+                log::info!(
+                    "Merged the two oldest generations into generation {}.",
+                    output_gen_no
+                );
+                return Ok(());
+            }
+            if plan.kind == CompactionKind::MinorMerge {
+                // Nothing but merges replaces the partitions beyond generation 0.
+                catalog.replace_sstables(&sstables, new_sstables, epoch_no)?;
+                catalog.record_sstables()?;
+                catalog.collect_blob_garbage()?;
+                log::info!(
+                    "Merged {} small partitions of generation {}.",
+                    sstables.len(),
+                    output_gen_no
+                );
+                return Ok(());
+            }
+
+            // Replace the merge-to partitions, which only merges can replace.
+            let gen_no = output_gen_no - 1;
+            let (merged_sstables, mut old_sstables): (Vec<_>, Vec<_>) = sstables
+                .into_iter()
+                .partition(|sstable| sstable.gen_no() == gen_no);
+
+            // Replace the merge-from generation with an empty SSTable, unless a flush has replaced
+            // it with a newer one that also contains its data.
+            if catalog.generation(gen_no).iter().all(|sstable| {
+                merged_sstables
+                    .iter()
+                    .any(|merged_sstable| Arc::ptr_eq(merged_sstable, sstable))
+            }) {
+                let sstable_path =
+                    Catalog::gen_sstable_path(catalog.sstable_folder_path(gen_no), gen_no);
+                new_sstables.push(SSTable::create_empty(
+                    sstable_path,
+                    gen_no,
+                    epoch_no,
+                    options,
+                )?);
+                old_sstables.extend(merged_sstables);
+            }
+            catalog.replace_sstables(&old_sstables, new_sstables, epoch_no)?;
+            catalog.record_sstables()?;
+            catalog.collect_blob_garbage()?;
         }
+        Ok(())
     }
 }
 
 #[cfg(test)]
 #[allow(unused_assignments)]
 mod tests {
-    use super::NaiveKV;
+    use super::{
+        backup, compaction, BackupChain, ColumnFamily, CompactionKind, NaiveKV, SSTableBuilder,
+        EXPIRE_KEYS_JOB, FLUSH_JOB, MERGE_JOB,
+    };
+    use crate::backend::{
+        Backend, BackendFile, FolderLock, LocalBackend, MappedFile, MemoryBackend,
+    };
+    use crate::catalog::CatalogViewer;
+    use crate::comparator::NumericComparator;
+    use crate::fallback::ArchiveFallback;
+    use crate::listener::{CompactionFailureEvent, EventListener, QuarantineEvent, SoftLimitEvent};
     use crate::logger;
+    use crate::manifest::Manifest;
+    use crate::merge::StringAppendOperator;
+    use crate::options::Options;
+    use crate::prefix::DelimiterPrefixExtractor;
+    use crate::protos::messages::Command;
+    use crate::scheduler::JobSchedule;
+    use crate::snapshot::{RawRecord, RecordSource, ScanOptions, TombstoneVisibility};
+    use crate::stats::{self, SSTableDescription};
     use crate::thread_pool::ThreadPool;
+    use crate::types::{NaiveError, Record, Result, WriteBatch};
+    use protobuf::Message;
+    use std::path::{Path, PathBuf};
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    /// Pause the flushes and the merges, so that a test compacts exactly when it calls `compact`.
+    fn pause_compactions(naive_kv: &NaiveKV) {
+        naive_kv.pause_job(FLUSH_JOB).unwrap();
+        naive_kv.pause_job(MERGE_JOB).unwrap();
+    }
+
+    /// Flush each column family whose Memtable has reached the threshold, and merge until no merge
+    /// is due, as the background jobs would over a few runs.
+    fn compact(naive_kv: &NaiveKV) {
+        let epoch_no = &naive_kv.epoch_no;
+        ColumnFamily::for_each(
+            &naive_kv.default_column_family,
+            &naive_kv.column_families,
+            |column_family| {
+                let catalog = &column_family.catalog;
+                NaiveKV::flush(catalog, epoch_no, &column_family.options)?;
+                while compaction::plan_merge(&*catalog.read()?).is_some() {
+                    NaiveKV::merge(catalog, epoch_no, &column_family.options)?;
+                }
+                Ok(())
+            },
+        )
+        .unwrap();
+    }
+
+    /// The options of the fixture below, whose Memtable is flushed after a few keys.
+    fn fixture_options() -> Options {
+        Options {
+            memtable_compaction_threshold: 256,
+            ..Options::default()
+        }
+    }
+
+    /// Open a fresh data folder with the compactions paused and a "meta" column family.
+    fn open_paused(folder_path: &str, options: Options) -> NaiveKV {
+        let _ = std::fs::remove_dir_all(folder_path);
+        let naive_kv = NaiveKV::open(folder_path, options).unwrap();
+        pause_compactions(&naive_kv);
+        naive_kv.create_column_family("meta").unwrap();
+        naive_kv
+    }
+
+    /// Set `key0` to `key19`, enough to fill the Memtable with the fixture options.
+    fn set_keys(catalog_viewer: &mut CatalogViewer) {
+        for i in 0..20 {
+            catalog_viewer
+                .set(format!("key{}", i), format!("value{}", i).repeat(4))
+                .unwrap();
+        }
+    }
+
+    /// Open the fixture with the keys set and flushed into the default column family.
+    fn open_with_keys(folder_path: &str, options: Options) -> (NaiveKV, CatalogViewer) {
+        let naive_kv = open_paused(folder_path, options);
+        let mut catalog_viewer = naive_kv.catalog_viewer().unwrap();
+        set_keys(&mut catalog_viewer);
+        compact(&naive_kv);
+        (naive_kv, catalog_viewer)
+    }
+
+    /// Wait until the background job has completed at least so many runs.
+    fn wait_for_runs(naive_kv: &NaiveKV, job_name: &str, num_runs: u64) {
+        let job_runs = || {
+            naive_kv
+                .jobs()
+                .unwrap()
+                .into_iter()
+                .find(|job| job.name == job_name)
+                .unwrap()
+                .num_runs
+        };
+        while job_runs() < num_runs {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
 
     #[test]
     fn test_naive_kv() {
@@ -165,19 +1219,23 @@ mod tests {
         const MEMTABLE_COMPACTION_THRESHOLD: usize = 1024; // 1 KB
         const GENERATION_GEOMETRIC_RATIO: usize = 8;
         const COMPACTION_DAEMON_CYCLE_S: u64 = 1;
+        const NUM_BACKGROUND_THREADS: usize = 2;
 
         logger::init().unwrap();
 
         let _ = std::fs::remove_dir_all(FOLDER_PATH);
 
+        let options = Options {
+            memtable_compaction_threshold: MEMTABLE_COMPACTION_THRESHOLD,
+            generation_geometric_ratio: GENERATION_GEOMETRIC_RATIO,
+            compaction_daemon_cycle_s: COMPACTION_DAEMON_CYCLE_S,
+            num_background_threads: NUM_BACKGROUND_THREADS,
+            ..Options::default()
+        };
+
         let mut naive_kv = Some(
-            NaiveKV::open(
-                FOLDER_PATH,
-                MEMTABLE_COMPACTION_THRESHOLD,
-                GENERATION_GEOMETRIC_RATIO,
-                COMPACTION_DAEMON_CYCLE_S,
-            )
-            .expect("Failed to create the NaiveKV instance."),
+            NaiveKV::open(FOLDER_PATH, options.clone())
+                .expect("Failed to create the NaiveKV instance."),
         );
 
         // Write initial values.
@@ -230,13 +1288,8 @@ mod tests {
         // Restart from disk files.
         naive_kv = None;
         naive_kv = Some(
-            NaiveKV::open(
-                FOLDER_PATH,
-                MEMTABLE_COMPACTION_THRESHOLD,
-                GENERATION_GEOMETRIC_RATIO,
-                COMPACTION_DAEMON_CYCLE_S,
-            )
-            .expect("Failed to restart the NaiveKV instance"),
+            NaiveKV::open(FOLDER_PATH, options.clone())
+                .expect("Failed to restart the NaiveKV instance"),
         );
         let mut catalog_viewer = naive_kv.as_ref().unwrap().catalog_viewer().unwrap();
         for num in 0..MAX_NUMBER {
@@ -246,4 +1299,3202 @@ mod tests {
             assert_eq!(val, Some(num_plus_one_str));
         }
     }
+
+    #[test]
+    fn test_write_receipt() {
+        const FOLDER_PATH: &str = "/tmp/naive_kv/test_write_receipt/";
+        const MEMTABLE_COMPACTION_THRESHOLD: usize = 64;
+
+        let _ = std::fs::remove_dir_all(FOLDER_PATH);
+        let options = Options {
+            memtable_compaction_threshold: MEMTABLE_COMPACTION_THRESHOLD,
+            ..Options::default()
+        };
+        let naive_kv = NaiveKV::open(FOLDER_PATH, options).unwrap();
+        pause_compactions(&naive_kv);
+        let mut catalog_viewer = naive_kv.catalog_viewer().unwrap();
+
+        let receipt = catalog_viewer.set("a".to_owned(), "1".to_owned()).unwrap();
+        assert_eq!(receipt.sequence_no, 1);
+        assert!(receipt.wal_bytes > 0);
+        assert!(!receipt.flush_triggered);
+
+        let mut batch = WriteBatch::new();
+        batch.remove("a".to_owned());
+        batch.set("b".to_owned(), "x".repeat(MEMTABLE_COMPACTION_THRESHOLD));
+        let batch_receipt = catalog_viewer.write(&batch).unwrap();
+        assert_eq!(batch_receipt.sequence_no, 2);
+        assert_eq!(naive_kv.visible_sequence_no().unwrap(), 2);
+        assert!(batch_receipt.wal_bytes > receipt.wal_bytes);
+        assert!(batch_receipt.flush_triggered);
+
+        assert_eq!(catalog_viewer.get("a").unwrap(), None);
+        assert_eq!(
+            catalog_viewer.get("b").unwrap(),
+            Some("x".repeat(MEMTABLE_COMPACTION_THRESHOLD))
+        );
+
+        // The flush rewrites what the writes appended to the log.
+        compact(&naive_kv);
+        let stats = naive_kv.stats().unwrap();
+        assert_eq!(
+            stats.user_bytes,
+            receipt.wal_bytes + batch_receipt.wal_bytes
+        );
+        assert!(stats.compaction.num_bytes > 0);
+        assert!(stats.write_amplification() > 1.0);
+    }
+
+    #[test]
+    fn test_increment() {
+        const FOLDER_PATH: &str = "/tmp/naive_kv/test_increment/";
+        const NUM_THREADS: usize = 4;
+        const NUM_INCREMENTS: usize = 100;
+
+        let _ = std::fs::remove_dir_all(FOLDER_PATH);
+        let options = Options {
+            memtable_compaction_threshold: 64,
+            ..Options::default()
+        };
+        let naive_kv = NaiveKV::open(FOLDER_PATH, options.clone()).unwrap();
+        pause_compactions(&naive_kv);
+        let mut catalog_viewer = naive_kv.catalog_viewer().unwrap();
+
+        // A missing key counts from zero, and a flushed value is read from the SSTables.
+        assert_eq!(catalog_viewer.increment("a".to_owned(), -2).unwrap(), -2);
+        catalog_viewer.set("b".to_owned(), "x".repeat(64)).unwrap();
+        catalog_viewer.set("c".to_owned(), "40".to_owned()).unwrap();
+        compact(&naive_kv);
+        assert_eq!(catalog_viewer.increment("c".to_owned(), 2).unwrap(), 42);
+        assert!(matches!(
+            catalog_viewer.increment("b".to_owned(), 1),
+            Err(NaiveError::InvalidValue(_))
+        ));
+        catalog_viewer
+            .set("c".to_owned(), i64::MAX.to_string())
+            .unwrap();
+        assert!(matches!(
+            catalog_viewer.increment("c".to_owned(), 1),
+            Err(NaiveError::InvalidValue(_))
+        ));
+        assert_eq!(catalog_viewer.get("c").unwrap(), Some(i64::MAX.to_string()));
+
+        // No increment gets lost among the concurrent ones.
+        let threads: Vec<_> = (0..NUM_THREADS)
+            .map(|_| {
+                let mut catalog_viewer = naive_kv.catalog_viewer().unwrap();
+                std::thread::spawn(move || {
+                    for _ in 0..NUM_INCREMENTS {
+                        catalog_viewer.increment("a".to_owned(), 1).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        let expected = (NUM_THREADS * NUM_INCREMENTS) as i64 - 2;
+        assert_eq!(catalog_viewer.get("a").unwrap(), Some(expected.to_string()));
+
+        // The results are logged like any other write.
+        drop(catalog_viewer);
+        drop(naive_kv);
+        let naive_kv = NaiveKV::open(FOLDER_PATH, options).unwrap();
+        let mut catalog_viewer = naive_kv.catalog_viewer().unwrap();
+        assert_eq!(catalog_viewer.get("a").unwrap(), Some(expected.to_string()));
+    }
+
+    #[test]
+    fn test_memtable_shards() {
+        const FOLDER_PATH: &str = "/tmp/naive_kv/test_memtable_shards/";
+        const NUM_THREADS: usize = 4;
+        const NUM_WRITES: usize = 100;
+
+        let _ = std::fs::remove_dir_all(FOLDER_PATH);
+        let options = Options {
+            memtable_shards: 4,
+            ..Options::default()
+        };
+        let naive_kv = NaiveKV::open(FOLDER_PATH, options.clone()).unwrap();
+
+        // The concurrent writes take distinct sequence numbers, all visible once they are done.
+        let threads: Vec<_> = (0..NUM_THREADS)
+            .map(|num| {
+                let mut catalog_viewer = naive_kv.catalog_viewer().unwrap();
+                std::thread::spawn(move || {
+                    (0..NUM_WRITES)
+                        .map(|write_no| {
+                            let key = format!("{}_{}", num, write_no);
+                            let receipt = catalog_viewer.set(key, write_no.to_string()).unwrap();
+                            receipt.sequence_no
+                        })
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+        let mut sequence_nos = threads
+            .into_iter()
+            .flat_map(|thread| thread.join().unwrap())
+            .collect::<Vec<_>>();
+        sequence_nos.sort_unstable();
+        let num_writes = (NUM_THREADS * NUM_WRITES) as u64;
+        assert_eq!(sequence_nos, (1..=num_writes).collect::<Vec<_>>());
+        assert_eq!(naive_kv.visible_sequence_no().unwrap(), num_writes);
+
+        // A failed write gives its sequence number back once no other write is in flight.
+        let mut catalog_viewer = naive_kv.catalog_viewer().unwrap();
+        assert!(catalog_viewer
+            .merge("0_0".to_owned(), "1".to_owned())
+            .is_err());
+        let receipt = catalog_viewer.set("a".to_owned(), "1".to_owned()).unwrap();
+        assert_eq!(receipt.sequence_no, num_writes + 1);
+
+        // The writes of all the shards are replayed from the log.
+        drop(catalog_viewer);
+        drop(naive_kv);
+        let naive_kv = NaiveKV::open(FOLDER_PATH, options).unwrap();
+        let mut catalog_viewer = naive_kv.catalog_viewer().unwrap();
+        for num in 0..NUM_THREADS {
+            for write_no in 0..NUM_WRITES {
+                let key = format!("{}_{}", num, write_no);
+                assert_eq!(
+                    catalog_viewer.get(&key).unwrap(),
+                    Some(write_no.to_string())
+                );
+            }
+        }
+        assert_eq!(naive_kv.visible_sequence_no().unwrap(), num_writes + 1);
+    }
+
+    #[test]
+    fn test_set_nx_and_xx() {
+        const FOLDER_PATH: &str = "/tmp/naive_kv/test_set_nx_and_xx/";
+        const NUM_THREADS: usize = 4;
+
+        let _ = std::fs::remove_dir_all(FOLDER_PATH);
+        let options = Options {
+            memtable_compaction_threshold: 64,
+            ..Options::default()
+        };
+        let naive_kv = NaiveKV::open(FOLDER_PATH, options).unwrap();
+        pause_compactions(&naive_kv);
+        let mut catalog_viewer = naive_kv.catalog_viewer().unwrap();
+
+        // Nothing is written unless the condition holds, even for a flushed value.
+        assert!(catalog_viewer
+            .set_xx("a".to_owned(), "1".to_owned())
+            .unwrap()
+            .is_none());
+        assert!(catalog_viewer
+            .set_nx("a".to_owned(), "x".repeat(64))
+            .unwrap()
+            .is_some());
+        compact(&naive_kv);
+        assert!(catalog_viewer
+            .set_nx("a".to_owned(), "2".to_owned())
+            .unwrap()
+            .is_none());
+        assert_eq!(catalog_viewer.get("a").unwrap(), Some("x".repeat(64)));
+        assert!(catalog_viewer
+            .set_xx("a".to_owned(), "3".to_owned())
+            .unwrap()
+            .is_some());
+        assert_eq!(catalog_viewer.get("a").unwrap(), Some("3".to_owned()));
+
+        // A removed key counts as missing.
+        catalog_viewer.remove("a".to_owned()).unwrap();
+        assert!(catalog_viewer
+            .set_xx("a".to_owned(), "4".to_owned())
+            .unwrap()
+            .is_none());
+        assert_eq!(catalog_viewer.get("a").unwrap(), None);
+
+        // Exactly one of the concurrent claims wins.
+        let threads: Vec<_> = (0..NUM_THREADS)
+            .map(|i| {
+                let mut catalog_viewer = naive_kv.catalog_viewer().unwrap();
+                std::thread::spawn(move || {
+                    catalog_viewer
+                        .set_nx("lock".to_owned(), i.to_string())
+                        .unwrap()
+                        .is_some()
+                })
+            })
+            .collect();
+        let num_winners = threads
+            .into_iter()
+            .map(|thread| thread.join().unwrap())
+            .filter(|&won| won)
+            .count();
+        assert_eq!(num_winners, 1);
+    }
+
+    #[test]
+    fn test_get_set_and_append() {
+        const FOLDER_PATH: &str = "/tmp/naive_kv/test_get_set_and_append/";
+        const NUM_THREADS: usize = 4;
+        const NUM_APPENDS: usize = 50;
+
+        let _ = std::fs::remove_dir_all(FOLDER_PATH);
+        let options = Options {
+            memtable_compaction_threshold: 64,
+            ..Options::default()
+        };
+        let naive_kv = NaiveKV::open(FOLDER_PATH, options).unwrap();
+        pause_compactions(&naive_kv);
+        let mut catalog_viewer = naive_kv.catalog_viewer().unwrap();
+
+        // The old value is returned even once flushed.
+        assert_eq!(
+            catalog_viewer
+                .get_set("a".to_owned(), "x".repeat(64))
+                .unwrap(),
+            None
+        );
+        compact(&naive_kv);
+        assert_eq!(
+            catalog_viewer
+                .get_set("a".to_owned(), "1".to_owned())
+                .unwrap(),
+            Some("x".repeat(64))
+        );
+        assert_eq!(catalog_viewer.get("a").unwrap(), Some("1".to_owned()));
+
+        // A missing key is appended to as empty, and an expiring value keeps its TTL.
+        assert_eq!(catalog_viewer.append("b".to_owned(), "ab").unwrap(), 2);
+        catalog_viewer
+            .set_with_ttl(
+                "c".to_owned(),
+                "ab".to_owned(),
+                std::time::Duration::from_millis(200),
+            )
+            .unwrap();
+        assert_eq!(catalog_viewer.append("c".to_owned(), "cd").unwrap(), 4);
+        assert_eq!(catalog_viewer.get("c").unwrap(), Some("abcd".to_owned()));
+        std::thread::sleep(std::time::Duration::from_millis(300));
+        assert_eq!(catalog_viewer.get("c").unwrap(), None);
+
+        // No append gets lost among the concurrent ones.
+        let threads: Vec<_> = (0..NUM_THREADS)
+            .map(|_| {
+                let mut catalog_viewer = naive_kv.catalog_viewer().unwrap();
+                std::thread::spawn(move || {
+                    for _ in 0..NUM_APPENDS {
+                        catalog_viewer.append("b".to_owned(), "c").unwrap();
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(
+            catalog_viewer.get("b").unwrap().unwrap().len(),
+            2 + NUM_THREADS * NUM_APPENDS
+        );
+    }
+
+    #[test]
+    fn test_remove_all() {
+        const FOLDER_PATH: &str = "/tmp/naive_kv/test_remove_all/";
+        const NUM_THREADS: usize = 4;
+        const NUM_KEYS: usize = 20;
+
+        let _ = std::fs::remove_dir_all(FOLDER_PATH);
+        let options = Options {
+            memtable_compaction_threshold: 64,
+            ..Options::default()
+        };
+        let naive_kv = NaiveKV::open(FOLDER_PATH, options.clone()).unwrap();
+        naive_kv.pause_job(FLUSH_JOB).unwrap();
+        naive_kv.pause_job(MERGE_JOB).unwrap();
+        let mut catalog_viewer = naive_kv.catalog_viewer().unwrap();
+        let keys = |keys: &[&str]| keys.iter().map(|&key| key.to_owned()).collect::<Vec<_>>();
+
+        catalog_viewer.set("a".to_owned(), "x".repeat(64)).unwrap();
+        catalog_viewer.set("b".to_owned(), "1".to_owned()).unwrap();
+        catalog_viewer.remove("b".to_owned()).unwrap();
+        NaiveKV::flush(&naive_kv.catalog, &naive_kv.epoch_no, &options).unwrap();
+        catalog_viewer.set("c".to_owned(), "2".to_owned()).unwrap();
+
+        // Nothing is removed once the lookups run past the deadline.
+        assert!(matches!(
+            catalog_viewer.remove_all(&keys(&["c", "a"]), Some(Instant::now())),
+            Err(NaiveError::DeadlineExceeded)
+        ));
+        assert_eq!(catalog_viewer.get("c").unwrap(), Some("2".to_owned()));
+
+        // The keys are found even once flushed, and removed all at once.
+        assert_eq!(
+            catalog_viewer
+                .remove_all(&keys(&["a", "b", "c", "d"]), None)
+                .unwrap(),
+            vec![true, false, true, false]
+        );
+        for key in ["a", "b", "c", "d"] {
+            assert_eq!(catalog_viewer.get(key).unwrap(), None);
+        }
+
+        // Exactly one of the concurrent removals finds each key.
+        let keys = (0..NUM_KEYS).map(|num| num.to_string()).collect::<Vec<_>>();
+        for key in keys.iter() {
+            catalog_viewer.set(key.clone(), key.clone()).unwrap();
+        }
+        let threads: Vec<_> = (0..NUM_THREADS)
+            .map(|_| {
+                let mut catalog_viewer = naive_kv.catalog_viewer().unwrap();
+                let keys = keys.clone();
+                std::thread::spawn(move || catalog_viewer.remove_all(&keys, None).unwrap())
+            })
+            .collect();
+        let mut num_removals = vec![0; NUM_KEYS];
+        for thread in threads {
+            for (num, was_present) in thread.join().unwrap().into_iter().enumerate() {
+                num_removals[num] += was_present as usize;
+            }
+        }
+        assert_eq!(num_removals, vec![1; NUM_KEYS]);
+    }
+
+    #[test]
+    fn test_merge_operator() {
+        const FOLDER_PATH: &str = "/tmp/naive_kv/test_merge_operator/";
+
+        let _ = std::fs::remove_dir_all(FOLDER_PATH);
+        let options = Options {
+            memtable_compaction_threshold: 64,
+            merge_operator: Some(Arc::new(StringAppendOperator {
+                delimiter: ",".to_owned(),
+            })),
+            ..Options::default()
+        };
+        let naive_kv = NaiveKV::open(FOLDER_PATH, options.clone()).unwrap();
+        pause_compactions(&naive_kv);
+        let mut catalog_viewer = naive_kv.catalog_viewer().unwrap();
+        let expect = |catalog_viewer: &mut CatalogViewer, expected: &[(&str, &str)]| {
+            for &(key, value) in expected {
+                assert_eq!(catalog_viewer.get(key).unwrap(), Some(value.to_owned()));
+            }
+            let actual = catalog_viewer
+                .scan("a".to_owned()..="c".to_owned())
+                .unwrap()
+                .collect::<Result<Vec<_>>>()
+                .unwrap();
+            let expected = expected
+                .iter()
+                .map(|&(key, value)| (key.to_owned(), value.to_owned()))
+                .collect::<Vec<_>>();
+            assert_eq!(actual, expected);
+        };
+
+        // The operands apply to a missing key, a value and a deletion alike.
+        catalog_viewer
+            .merge("a".to_owned(), "1".to_owned())
+            .unwrap();
+        catalog_viewer.set("b".to_owned(), "x".to_owned()).unwrap();
+        catalog_viewer
+            .merge("b".to_owned(), "y".to_owned())
+            .unwrap();
+        catalog_viewer.set("c".to_owned(), "z".to_owned()).unwrap();
+        catalog_viewer.remove("c".to_owned()).unwrap();
+        catalog_viewer
+            .merge("c".to_owned(), "w".to_owned())
+            .unwrap();
+        expect(&mut catalog_viewer, &[("a", "1"), ("b", "x,y"), ("c", "w")]);
+
+        // The operands written after a flush pile up on the flushed records.
+        catalog_viewer.set("d".to_owned(), "-".repeat(64)).unwrap();
+        compact(&naive_kv);
+        catalog_viewer
+            .merge("a".to_owned(), "2".to_owned())
+            .unwrap();
+        catalog_viewer
+            .merge("b".to_owned(), "z".to_owned())
+            .unwrap();
+        expect(
+            &mut catalog_viewer,
+            &[("a", "1,2"), ("b", "x,y,z"), ("c", "w")],
+        );
+
+        // The merged values survive a restart.
+        drop(catalog_viewer);
+        drop(naive_kv);
+        let naive_kv = NaiveKV::open(FOLDER_PATH, options).unwrap();
+        let mut catalog_viewer = naive_kv.catalog_viewer().unwrap();
+        catalog_viewer
+            .merge("c".to_owned(), "v".to_owned())
+            .unwrap();
+        expect(
+            &mut catalog_viewer,
+            &[("a", "1,2"), ("b", "x,y,z"), ("c", "w,v")],
+        );
+
+        // No operand is written without a merge operator to apply it.
+        drop(catalog_viewer);
+        drop(naive_kv);
+        let naive_kv = NaiveKV::open(FOLDER_PATH, Options::default()).unwrap();
+        let mut catalog_viewer = naive_kv.catalog_viewer().unwrap();
+        assert!(matches!(
+            catalog_viewer.merge("e".to_owned(), "u".to_owned()),
+            Err(NaiveError::InvalidOptions(_))
+        ));
+    }
+
+    #[test]
+    fn test_ttl() {
+        const FOLDER_PATH: &str = "/tmp/naive_kv/test_ttl/";
+
+        let _ = std::fs::remove_dir_all(FOLDER_PATH);
+        let options = Options {
+            memtable_compaction_threshold: 64,
+            merge_operator: Some(Arc::new(StringAppendOperator {
+                delimiter: ",".to_owned(),
+            })),
+            ..Options::default()
+        };
+        let naive_kv = NaiveKV::open(FOLDER_PATH, options.clone()).unwrap();
+        pause_compactions(&naive_kv);
+        let mut catalog_viewer = naive_kv.catalog_viewer().unwrap();
+        let short_ttl = Duration::from_millis(300);
+        let long_ttl = Duration::from_secs(3600);
+        let entries = |catalog_viewer: &CatalogViewer| {
+            let options = ScanOptions {
+                tombstones: TombstoneVisibility::Include,
+            };
+            catalog_viewer
+                .scan_entries(.., &options)
+                .unwrap()
+                .map(|entry| {
+                    let entry = entry.unwrap();
+                    (entry.key, entry.record)
+                })
+                .collect::<Vec<_>>()
+        };
+
+        catalog_viewer
+            .set_with_ttl("a".to_owned(), "1".to_owned(), short_ttl)
+            .unwrap();
+        catalog_viewer
+            .set_with_ttl("b".to_owned(), "2".to_owned(), long_ttl)
+            .unwrap();
+        catalog_viewer
+            .set_with_ttl("c".to_owned(), "3".to_owned(), short_ttl)
+            .unwrap();
+        assert_eq!(catalog_viewer.get("a").unwrap(), Some("1".to_owned()));
+
+        // The expired values count as deletions.
+        std::thread::sleep(short_ttl);
+        assert_eq!(catalog_viewer.get("a").unwrap(), None);
+        assert_eq!(catalog_viewer.get("b").unwrap(), Some("2".to_owned()));
+        let actual = catalog_viewer
+            .scan(..)
+            .unwrap()
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(actual, vec![("b".to_owned(), "2".to_owned())]);
+        assert_eq!(
+            entries(&catalog_viewer)[0],
+            ("a".to_owned(), Record::Deleted)
+        );
+
+        // A merge operand written after the expiration applies to nothing.
+        catalog_viewer
+            .merge("c".to_owned(), "x".to_owned())
+            .unwrap();
+        assert_eq!(catalog_viewer.get("c").unwrap(), Some("x".to_owned()));
+
+        // The flush into the bottom generation drops the expired values.
+        catalog_viewer.set("d".to_owned(), "-".repeat(64)).unwrap();
+        compact(&naive_kv);
+        let keys = entries(&catalog_viewer)
+            .into_iter()
+            .map(|(key, _)| key)
+            .collect::<Vec<_>>();
+        assert_eq!(keys, vec!["b", "c", "d"]);
+
+        // The expiration survives a restart.
+        drop(catalog_viewer);
+        drop(naive_kv);
+        let naive_kv = NaiveKV::open(FOLDER_PATH, options).unwrap();
+        let mut catalog_viewer = naive_kv.catalog_viewer().unwrap();
+        assert_eq!(catalog_viewer.get("b").unwrap(), Some("2".to_owned()));
+        assert!(matches!(
+            entries(&catalog_viewer)[0].1,
+            Record::ExpiringValue { .. }
+        ));
+    }
+
+    #[test]
+    fn test_scan_snapshot() {
+        const FOLDER_PATH: &str = "/tmp/naive_kv/test_scan_snapshot/";
+        const MAX_NUMBER: usize = 2000;
+
+        let _ = std::fs::remove_dir_all(FOLDER_PATH);
+        let options = Options {
+            memtable_compaction_threshold: 1024, // 1 KB
+            ..Options::default()
+        };
+        let naive_kv = NaiveKV::open(FOLDER_PATH, options).unwrap();
+        pause_compactions(&naive_kv);
+        let mut catalog_viewer = naive_kv.catalog_viewer().unwrap();
+        let key_of = |num: usize| format!("{:05}", num);
+
+        for num in 0..MAX_NUMBER {
+            catalog_viewer.set(key_of(num), num.to_string()).unwrap();
+        }
+        compact(&naive_kv);
+        for num in (0..MAX_NUMBER).step_by(2) {
+            catalog_viewer.remove(key_of(num)).unwrap();
+        }
+
+        let scan_iter = catalog_viewer
+            .scan(key_of(100)..key_of(MAX_NUMBER - 100))
+            .unwrap();
+
+        // Overwrite everything and let compactions swap the generations during the scan.
+        for num in 0..MAX_NUMBER {
+            catalog_viewer.set(key_of(num), "new".to_owned()).unwrap();
+        }
+        compact(&naive_kv);
+
+        let expected = (100..MAX_NUMBER - 100)
+            .filter(|num| num % 2 == 1)
+            .map(|num| (key_of(num), num.to_string()))
+            .collect::<Vec<_>>();
+        let actual = scan_iter.collect::<Result<Vec<_>>>().unwrap();
+        assert_eq!(actual, expected);
+
+        let scan_iter = catalog_viewer.scan(..).unwrap();
+        assert!(scan_iter
+            .map(|entry| entry.unwrap().1)
+            .all(|value| value == "new"));
+
+        // A snapshot iterates over all the pairs in a for loop.
+        let snapshot = catalog_viewer.snapshot().unwrap();
+        let mut num_pairs = 0;
+        for pair in &snapshot {
+            assert_eq!(pair.unwrap(), (key_of(num_pairs), "new".to_owned()));
+            num_pairs += 1;
+        }
+        assert_eq!(num_pairs, MAX_NUMBER);
+    }
+
+    #[test]
+    fn test_snapshot_get() {
+        const FOLDER_PATH: &str = "/tmp/naive_kv/test_snapshot_get/";
+        const MAX_NUMBER: usize = 500;
+
+        let _ = std::fs::remove_dir_all(FOLDER_PATH);
+        let options = Options {
+            memtable_compaction_threshold: 1024, // 1 KB
+            ..Options::default()
+        };
+        let naive_kv = NaiveKV::open(FOLDER_PATH, options).unwrap();
+        pause_compactions(&naive_kv);
+        let mut catalog_viewer = naive_kv.catalog_viewer().unwrap();
+        for num in 0..MAX_NUMBER {
+            catalog_viewer
+                .set(num.to_string(), num.to_string())
+                .unwrap();
+        }
+        compact(&naive_kv);
+        let snapshot = naive_kv.snapshot().unwrap();
+
+        // Overwrite or remove everything and let compactions replace the SSTables.
+        for num in 0..MAX_NUMBER {
+            match num % 2 {
+                0 => catalog_viewer.remove(num.to_string()).unwrap(),
+                _ => catalog_viewer
+                    .set(num.to_string(), "new".to_owned())
+                    .unwrap(),
+            };
+        }
+        catalog_viewer
+            .set("new".to_owned(), "new".to_owned())
+            .unwrap();
+        compact(&naive_kv);
+
+        for num in 0..MAX_NUMBER {
+            assert_eq!(
+                snapshot.get(&num.to_string()).unwrap(),
+                Some(num.to_string())
+            );
+        }
+        assert_eq!(snapshot.get("new").unwrap(), None);
+        assert_eq!(catalog_viewer.get("0").unwrap(), None);
+        assert_eq!(catalog_viewer.get("1").unwrap(), Some("new".to_owned()));
+    }
+
+    #[test]
+    fn test_read_fallback() {
+        const ARCHIVE_PATH: &str = "/tmp/naive_kv/test_read_fallback/archive/";
+        const FOLDER_PATH: &str = "/tmp/naive_kv/test_read_fallback/local/";
+
+        let _ = std::fs::remove_dir_all("/tmp/naive_kv/test_read_fallback/");
+        {
+            let naive_kv = NaiveKV::open(ARCHIVE_PATH, Options::default()).unwrap();
+            let mut catalog_viewer = naive_kv.catalog_viewer().unwrap();
+            for key in ["a", "b", "c", "n"] {
+                catalog_viewer
+                    .set(key.to_owned(), format!("{}0", key))
+                    .unwrap();
+            }
+            catalog_viewer.set("n".to_owned(), "10".to_owned()).unwrap();
+        }
+        let options = Options {
+            read_fallback: Some(Arc::new(
+                ArchiveFallback::open(ARCHIVE_PATH, Options::default()).unwrap(),
+            )),
+            backfill_reads: true,
+            ..Options::default()
+        };
+        let naive_kv = NaiveKV::open(FOLDER_PATH, options).unwrap();
+        let mut catalog_viewer = naive_kv.catalog_viewer().unwrap();
+        catalog_viewer.set("a".to_owned(), "a1".to_owned()).unwrap();
+        catalog_viewer.remove("b".to_owned()).unwrap();
+
+        // Only the keys never written locally fall back.
+        assert_eq!(catalog_viewer.get("a").unwrap(), Some("a1".to_owned()));
+        assert_eq!(catalog_viewer.get("b").unwrap(), None);
+        assert_eq!(catalog_viewer.get("d").unwrap(), None);
+        assert!(catalog_viewer.get_raw("c").unwrap().is_none());
+        assert_eq!(
+            catalog_viewer.get_with_timestamp("c", None).unwrap(),
+            Some(("c0".to_owned(), None))
+        );
+
+        // The value found is backfilled, and an increment starts from the value in the fallback.
+        assert!(catalog_viewer.get_raw("c").unwrap().is_some());
+        assert_eq!(catalog_viewer.increment("n".to_owned(), 5).unwrap(), 15);
+        assert_eq!(catalog_viewer.get("n").unwrap(), Some("15".to_owned()));
+    }
+
+    #[test]
+    fn test_numeric_comparator() {
+        const FOLDER_PATH: &str = "/tmp/naive_kv/test_numeric_comparator/";
+        const MAX_NUMBER: usize = 500;
+
+        let _ = std::fs::remove_dir_all(FOLDER_PATH);
+        let options = Options {
+            memtable_compaction_threshold: 1024, // 1 KB
+            comparator: &NumericComparator,
+            ..Options::default()
+        };
+        let key_of = |num: usize| format!("key{}", num);
+        {
+            let naive_kv = NaiveKV::open(FOLDER_PATH, options.clone()).unwrap();
+            pause_compactions(&naive_kv);
+            let mut catalog_viewer = naive_kv.catalog_viewer().unwrap();
+            for num in (0..MAX_NUMBER).rev() {
+                catalog_viewer.set(key_of(num), num.to_string()).unwrap();
+            }
+            compact(&naive_kv);
+        }
+
+        // Restart from disk files and scan in the numeric order.
+        let naive_kv = NaiveKV::open(FOLDER_PATH, options).unwrap();
+        let mut catalog_viewer = naive_kv.catalog_viewer().unwrap();
+        assert_eq!(catalog_viewer.get("key42").unwrap(), Some("42".to_owned()));
+        let actual = catalog_viewer
+            .scan(key_of(9)..key_of(100))
+            .unwrap()
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        let expected = (9..100)
+            .map(|num| (key_of(num), num.to_string()))
+            .collect::<Vec<_>>();
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn test_column_families() {
+        const FOLDER_PATH: &str = "/tmp/naive_kv/test_column_families/";
+        const MAX_NUMBER: usize = 200;
+
+        let _ = std::fs::remove_dir_all(FOLDER_PATH);
+        let options = Options {
+            memtable_compaction_threshold: 1024, // 1 KB
+            ..Options::default()
+        };
+        {
+            let naive_kv = NaiveKV::open(FOLDER_PATH, options.clone()).unwrap();
+            pause_compactions(&naive_kv);
+            assert!(matches!(
+                naive_kv.catalog_viewer_for("metadata"),
+                Err(NaiveError::ColumnFamilyNotFound(_))
+            ));
+            assert!(matches!(
+                naive_kv.create_column_family("../metadata"),
+                Err(NaiveError::InvalidOptions(_))
+            ));
+            naive_kv.create_column_family("metadata").unwrap();
+            naive_kv.create_column_family("metadata").unwrap();
+
+            let mut data_viewer = naive_kv.catalog_viewer().unwrap();
+            let mut metadata_viewer = naive_kv.catalog_viewer_for("metadata").unwrap();
+            for num in 0..MAX_NUMBER {
+                data_viewer
+                    .set(num.to_string(), format!("data{}", num))
+                    .unwrap();
+                metadata_viewer
+                    .set(num.to_string(), format!("metadata{}", num))
+                    .unwrap();
+            }
+            metadata_viewer.remove("0".to_owned()).unwrap();
+            compact(&naive_kv);
+        }
+
+        // The column families are flushed alike and found again on open.
+        assert!(
+            std::fs::read_dir(Path::new(FOLDER_PATH).join("cf_metadata"))
+                .unwrap()
+                .any(|entry| entry
+                    .unwrap()
+                    .path()
+                    .extension()
+                    .is_some_and(|e| e == "sst"))
+        );
+        let naive_kv = NaiveKV::open(FOLDER_PATH, options).unwrap();
+        assert_eq!(
+            naive_kv.column_family_names().unwrap(),
+            vec!["metadata".to_owned()]
+        );
+        let mut data_viewer = naive_kv.catalog_viewer().unwrap();
+        let mut metadata_viewer = naive_kv.catalog_viewer_for("metadata").unwrap();
+        assert_eq!(data_viewer.get("0").unwrap(), Some("data0".to_owned()));
+        assert_eq!(metadata_viewer.get("0").unwrap(), None);
+        for num in 1..MAX_NUMBER {
+            assert_eq!(
+                data_viewer.get(&num.to_string()).unwrap(),
+                Some(format!("data{}", num))
+            );
+            assert_eq!(
+                metadata_viewer.get(&num.to_string()).unwrap(),
+                Some(format!("metadata{}", num))
+            );
+        }
+    }
+
+    #[test]
+    fn test_comparator_mismatch() {
+        const FOLDER_PATH: &str = "/tmp/naive_kv/test_comparator_mismatch/";
+        const MAX_NUMBER: usize = 200;
+
+        let _ = std::fs::remove_dir_all(FOLDER_PATH);
+        let options = Options {
+            memtable_compaction_threshold: 1024, // 1 KB
+            comparator: &NumericComparator,
+            ..Options::default()
+        };
+        {
+            let naive_kv = NaiveKV::open(FOLDER_PATH, options.clone()).unwrap();
+            pause_compactions(&naive_kv);
+            let mut catalog_viewer = naive_kv.catalog_viewer().unwrap();
+            for num in 0..MAX_NUMBER {
+                catalog_viewer
+                    .set(format!("key{}", num), num.to_string())
+                    .unwrap();
+            }
+            compact(&naive_kv);
+        }
+        let bytewise_options = Options {
+            memtable_compaction_threshold: 1024, // 1 KB
+            ..Options::default()
+        };
+        assert!(matches!(
+            NaiveKV::open(FOLDER_PATH, bytewise_options.clone()),
+            Err(NaiveError::ComparatorMismatch(_))
+        ));
+        assert!(matches!(
+            NaiveKV::open_replica(FOLDER_PATH, bytewise_options.clone()),
+            Err(NaiveError::ComparatorMismatch(_))
+        ));
+
+        // Without the record of the comparator, the SSTables tell the order apart.
+        let mut manifest = Manifest::load(&LocalBackend, Path::new(FOLDER_PATH))
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            manifest.comparator_name.as_deref(),
+            Some("naive_kv.NumericComparator")
+        );
+        manifest.comparator_name = None;
+        manifest
+            .save(&LocalBackend, Path::new(FOLDER_PATH))
+            .unwrap();
+        assert!(matches!(
+            NaiveKV::open(FOLDER_PATH, bytewise_options),
+            Err(NaiveError::ComparatorMismatch(_))
+        ));
+
+        let naive_kv = NaiveKV::open(FOLDER_PATH, options).unwrap();
+        let mut catalog_viewer = naive_kv.catalog_viewer().unwrap();
+        assert_eq!(catalog_viewer.get("key42").unwrap(), Some("42".to_owned()));
+    }
+
+    #[test]
+    fn test_scan_prefix() {
+        const FOLDER_PATH: &str = "/tmp/naive_kv/test_scan_prefix/";
+        const NUM_ENTITIES: usize = 200;
+
+        let _ = std::fs::remove_dir_all(FOLDER_PATH);
+        let options = Options {
+            memtable_compaction_threshold: 1024, // 1 KB
+            prefix_extractor: Some(&DelimiterPrefixExtractor { delimiter: ':' }),
+            ..Options::default()
+        };
+        let naive_kv = NaiveKV::open(FOLDER_PATH, options).unwrap();
+        pause_compactions(&naive_kv);
+        let mut catalog_viewer = naive_kv.catalog_viewer().unwrap();
+        for num in 0..NUM_ENTITIES {
+            catalog_viewer
+                .set(format!("user{}:name", num), format!("name{}", num))
+                .unwrap();
+        }
+        compact(&naive_kv);
+        for num in 0..NUM_ENTITIES {
+            catalog_viewer
+                .set(format!("user{}:email", num), format!("email{}", num))
+                .unwrap();
+        }
+        catalog_viewer.remove("user7:name".to_owned()).unwrap();
+
+        let scan_prefix = |prefix: &str| {
+            catalog_viewer
+                .scan_prefix(prefix)
+                .unwrap()
+                .collect::<Result<Vec<_>>>()
+                .unwrap()
+        };
+        assert_eq!(
+            scan_prefix("user42:"),
+            vec![
+                ("user42:email".to_owned(), "email42".to_owned()),
+                ("user42:name".to_owned(), "name42".to_owned()),
+            ]
+        );
+        assert_eq!(
+            scan_prefix("user7:"),
+            vec![("user7:email".to_owned(), "email7".to_owned())]
+        );
+        assert_eq!(scan_prefix("user42:n").len(), 1);
+        assert_eq!(scan_prefix("user19").len(), 2 * 11);
+        assert!(scan_prefix("user1000:").is_empty());
+    }
+
+    #[test]
+    fn test_read_your_writes() {
+        const FOLDER_PATH: &str = "/tmp/naive_kv/test_read_your_writes/";
+        const NUM_THREADS: usize = 4;
+        const NUM_ROUNDS: usize = 3;
+        const NUM_KEYS: usize = 500;
+
+        let _ = std::fs::remove_dir_all(FOLDER_PATH);
+        let options = Options {
+            memtable_compaction_threshold: 256,
+            ..Options::default()
+        };
+        let naive_kv = NaiveKV::open(FOLDER_PATH, options).unwrap();
+
+        // Each thread keeps rewriting its own keys while flushes move them across the layers.
+        let servers = ThreadPool::new(NUM_THREADS);
+        for thread_no in 0..NUM_THREADS {
+            let mut catalog_viewer = naive_kv.catalog_viewer().unwrap();
+            servers
+                .add_task(move || {
+                    for round in 0..NUM_ROUNDS {
+                        for num in 0..NUM_KEYS {
+                            let key = format!("{}_{}", thread_no, num);
+                            let value = format!("{}_{}", round, num);
+                            let receipt = catalog_viewer.set(key.clone(), value.clone()).unwrap();
+                            assert_eq!(catalog_viewer.last_sequence_no(), receipt.sequence_no);
+                            assert_eq!(catalog_viewer.get(&key).unwrap(), Some(value));
+                        }
+                        std::thread::sleep(std::time::Duration::from_millis(500));
+                    }
+                })
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn test_flush_and_merge() {
+        const FOLDER_PATH: &str = "/tmp/naive_kv/test_flush_and_merge/";
+        const NUM_ROUNDS: usize = 4;
+        const NUM_KEYS: usize = 200;
+
+        let _ = std::fs::remove_dir_all(FOLDER_PATH);
+        let options = Options {
+            memtable_compaction_threshold: 256,
+            generation_geometric_ratio: 2,
+            ..Options::default()
+        };
+        let naive_kv = NaiveKV::open(FOLDER_PATH, options.clone()).unwrap();
+        pause_compactions(&naive_kv);
+
+        // Keep flushing into generation 0 while the merges push the data to older generations.
+        let mut catalog_viewer = naive_kv.catalog_viewer().unwrap();
+        for round in 0..NUM_ROUNDS {
+            for num in 0..NUM_KEYS {
+                catalog_viewer
+                    .set(num.to_string(), format!("{}_{}", round, num))
+                    .unwrap();
+            }
+            compact(&naive_kv);
+        }
+        assert!(naive_kv.catalog.read().unwrap().sstables.len() > 1);
+        for num in 0..NUM_KEYS {
+            let value = format!("{}_{}", NUM_ROUNDS - 1, num);
+            assert_eq!(catalog_viewer.get(&num.to_string()).unwrap(), Some(value));
+        }
+
+        // Nothing gets lost across a restart.
+        drop(catalog_viewer);
+        drop(naive_kv);
+        let naive_kv = NaiveKV::open(FOLDER_PATH, options).unwrap();
+        let mut catalog_viewer = naive_kv.catalog_viewer().unwrap();
+        for num in 0..NUM_KEYS {
+            let value = format!("{}_{}", NUM_ROUNDS - 1, num);
+            assert_eq!(catalog_viewer.get(&num.to_string()).unwrap(), Some(value));
+        }
+
+        // The sequence numbers continue from the flushed writes.
+        let receipt = catalog_viewer.set("0".to_owned(), "0".to_owned()).unwrap();
+        assert_eq!(receipt.sequence_no, (NUM_ROUNDS * NUM_KEYS) as u64 + 1);
+    }
+
+    #[test]
+    fn test_partitioned_generations() {
+        const FOLDER_PATH: &str = "/tmp/naive_kv/test_partitioned_generations/";
+        const NUM_ROUNDS: usize = 8;
+        const NUM_KEYS: usize = 50;
+
+        let _ = std::fs::remove_dir_all(FOLDER_PATH);
+        let options = Options {
+            memtable_compaction_threshold: 256,
+            generation_partition_size: 512,
+            ..Options::default()
+        };
+        let naive_kv = NaiveKV::open(FOLDER_PATH, options.clone()).unwrap();
+        naive_kv.pause_job(FLUSH_JOB).unwrap();
+        naive_kv.pause_job(MERGE_JOB).unwrap();
+        let catalog = &naive_kv.catalog;
+        let epoch_no = &naive_kv.epoch_no;
+
+        // Each round writes keys after the ones before, and compacts until no merge is due.
+        let mut catalog_viewer = naive_kv.catalog_viewer().unwrap();
+        let key = |round: usize, num: usize| format!("{:02}_{:03}", round, num);
+        for round in 0..NUM_ROUNDS {
+            for num in 0..NUM_KEYS {
+                catalog_viewer
+                    .set(key(round, num), num.to_string())
+                    .unwrap();
+            }
+            NaiveKV::flush(catalog, epoch_no, &options).unwrap();
+            while compaction::plan_merge(&catalog.read().unwrap()).is_some() {
+                NaiveKV::merge(catalog, epoch_no, &options).unwrap();
+            }
+        }
+
+        // The partitions of each generation are in order without overlapping.
+        let descriptions = naive_kv.describe().unwrap();
+        for pair in descriptions.windows(2) {
+            if let (Some((_, max_key)), Some((min_key, _))) =
+                (&pair[0].key_range, &pair[1].key_range)
+            {
+                assert!(pair[0].gen_no < pair[1].gen_no || max_key < min_key);
+            }
+        }
+        let num_generations = descriptions.last().unwrap().gen_no + 1;
+        assert!(descriptions.len() > num_generations);
+
+        // The merges into a generation leave the partitions out of the keys merged untouched.
+        assert!((1..num_generations).any(|gen_no| {
+            let epoch_nos = descriptions
+                .iter()
+                .filter(|description| {
+                    description.gen_no == gen_no && description.key_range.is_some()
+                })
+                .map(|description| description.epoch_no)
+                .collect::<Vec<_>>();
+            epoch_nos.windows(2).any(|pair| pair[0] != pair[1])
+        }));
+
+        let check = |catalog_viewer: &mut CatalogViewer| {
+            for round in 0..NUM_ROUNDS {
+                for num in 0..NUM_KEYS {
+                    assert_eq!(
+                        catalog_viewer.get(&key(round, num)).unwrap(),
+                        Some(num.to_string())
+                    );
+                }
+            }
+            let num_pairs = catalog_viewer.scan(..).unwrap().count();
+            assert_eq!(num_pairs, NUM_ROUNDS * NUM_KEYS);
+        };
+        check(&mut catalog_viewer);
+
+        // The partitions are told apart from the stale SSTables across a restart.
+        drop(catalog_viewer);
+        drop(naive_kv);
+        let naive_kv = NaiveKV::open(FOLDER_PATH, options).unwrap();
+        let file_paths = |descriptions: Vec<SSTableDescription>| {
+            descriptions
+                .into_iter()
+                .map(|description| (description.gen_no, description.file_path))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            file_paths(naive_kv.describe().unwrap()),
+            file_paths(descriptions)
+        );
+        check(&mut naive_kv.catalog_viewer().unwrap());
+    }
+
+    #[test]
+    fn test_minor_merge() {
+        const FOLDER_PATH: &str = "/tmp/naive_kv/test_minor_merge/";
+        const NUM_ROUNDS: usize = 12;
+        const NUM_KEYS: usize = 30;
+        const PARTITION_SIZE: usize = 4096;
+
+        let _ = std::fs::remove_dir_all(FOLDER_PATH);
+        let options = Options {
+            memtable_compaction_threshold: 256,
+            generation_partition_size: PARTITION_SIZE,
+            minor_merge_min_files: 0,
+            ..Options::default()
+        };
+        let naive_kv = NaiveKV::open(FOLDER_PATH, options.clone()).unwrap();
+        naive_kv.pause_job(FLUSH_JOB).unwrap();
+        naive_kv.pause_job(MERGE_JOB).unwrap();
+
+        // Each round writes keys after the ones before, and each merge leaves them in a small
+        // partition of their own.
+        let mut catalog_viewer = naive_kv.catalog_viewer().unwrap();
+        let key = |round: usize, num: usize| format!("{:02}_{:03}", round, num);
+        for round in 0..NUM_ROUNDS {
+            for num in 0..NUM_KEYS {
+                catalog_viewer
+                    .set(key(round, num), num.to_string())
+                    .unwrap();
+            }
+            NaiveKV::flush(&naive_kv.catalog, &naive_kv.epoch_no, &options).unwrap();
+            while compaction::plan_merge(&naive_kv.catalog.read().unwrap()).is_some() {
+                NaiveKV::merge(&naive_kv.catalog, &naive_kv.epoch_no, &options).unwrap();
+            }
+        }
+        drop(catalog_viewer);
+        drop(naive_kv);
+        let num_small_partitions = |descriptions: &[SSTableDescription]| {
+            descriptions
+                .iter()
+                .filter(|description| {
+                    description.gen_no > 0
+                        && description.key_range.is_some()
+                        && description.properties.raw_key_bytes
+                            + description.properties.raw_value_bytes
+                            < PARTITION_SIZE / 4
+                })
+                .count()
+        };
+
+        // The small partitions are merged once enough of them are in a row.
+        let options = Options {
+            minor_merge_min_files: 2,
+            ..options
+        };
+        let naive_kv = NaiveKV::open(FOLDER_PATH, options.clone()).unwrap();
+        naive_kv.pause_job(FLUSH_JOB).unwrap();
+        naive_kv.pause_job(MERGE_JOB).unwrap();
+        assert!(num_small_partitions(&naive_kv.describe().unwrap()) >= 2);
+        let plans = naive_kv.plan_compaction().unwrap();
+        assert_eq!(plans.len(), 1);
+        assert_eq!(plans[0].kind, CompactionKind::MinorMerge);
+        assert!(plans[0].inputs.len() >= 2);
+        while compaction::plan_merge(&naive_kv.catalog.read().unwrap()).is_some() {
+            NaiveKV::merge(&naive_kv.catalog, &naive_kv.epoch_no, &options).unwrap();
+        }
+        let descriptions = naive_kv.describe().unwrap();
+        assert_eq!(num_small_partitions(&descriptions), 0);
+        for pair in descriptions.windows(2) {
+            if let (Some((_, max_key)), Some((min_key, _))) =
+                (&pair[0].key_range, &pair[1].key_range)
+            {
+                assert!(pair[0].gen_no < pair[1].gen_no || max_key < min_key);
+            }
+        }
+
+        let mut catalog_viewer = naive_kv.catalog_viewer().unwrap();
+        for round in 0..NUM_ROUNDS {
+            for num in 0..NUM_KEYS {
+                assert_eq!(
+                    catalog_viewer.get(&key(round, num)).unwrap(),
+                    Some(num.to_string())
+                );
+            }
+        }
+        assert_eq!(
+            catalog_viewer.scan(..).unwrap().count(),
+            NUM_ROUNDS * NUM_KEYS
+        );
+    }
+
+    #[test]
+    fn test_blob_separation() {
+        const FOLDER_PATH: &str = "/tmp/naive_kv/test_blob_separation/";
+        const CHECKPOINT_PATH: &str = "/tmp/naive_kv/test_blob_separation_checkpoint/";
+        const BACKUP_PATH: &str = "/tmp/naive_kv/test_blob_separation_backup/";
+        const RESTORE_PATH: &str = "/tmp/naive_kv/test_blob_separation_restore/";
+        const NUM_ROUNDS: usize = 6;
+        const NUM_KEYS: usize = 20;
+
+        for folder_path in [FOLDER_PATH, CHECKPOINT_PATH, BACKUP_PATH, RESTORE_PATH] {
+            let _ = std::fs::remove_dir_all(folder_path);
+        }
+        let options = Options {
+            memtable_compaction_threshold: 256,
+            generation_geometric_ratio: 2,
+            min_blob_size: 64,
+            merge_operator: Some(Arc::new(StringAppendOperator {
+                delimiter: ",".to_owned(),
+            })),
+            ..Options::default()
+        };
+        let naive_kv = NaiveKV::open(FOLDER_PATH, options.clone()).unwrap();
+        naive_kv.pause_job(FLUSH_JOB).unwrap();
+        naive_kv.pause_job(MERGE_JOB).unwrap();
+        let catalog = &naive_kv.catalog;
+        let epoch_no = &naive_kv.epoch_no;
+        let blob_names = || {
+            let mut blob_names = std::fs::read_dir(FOLDER_PATH)
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+                .filter(|file_name| file_name.ends_with(".blob"))
+                .collect::<Vec<_>>();
+            blob_names.sort();
+            blob_names
+        };
+
+        // The large values written first are never overwritten, unlike the ones of the later
+        // rounds, and the small values stay in the segment files.
+        let large_value =
+            |round: usize, num: usize| format!("{}_{}_{}", round, num, "v".repeat(64));
+        let mut catalog_viewer = naive_kv.catalog_viewer().unwrap();
+        for num in 0..NUM_KEYS {
+            catalog_viewer
+                .set(format!("first_{}", num), large_value(0, num))
+                .unwrap();
+        }
+        catalog_viewer
+            .set("merged".to_owned(), large_value(0, 0))
+            .unwrap();
+        NaiveKV::flush(catalog, epoch_no, &options).unwrap();
+        let first_blob_names = blob_names();
+        assert_eq!(first_blob_names.len(), 1);
+
+        let mut seen_blob_names = first_blob_names.clone();
+        for round in 1..=NUM_ROUNDS {
+            for num in 0..NUM_KEYS {
+                catalog_viewer
+                    .set(format!("later_{}", num), large_value(round, num))
+                    .unwrap();
+                catalog_viewer
+                    .set(format!("small_{}", num), round.to_string())
+                    .unwrap();
+            }
+            catalog_viewer
+                .merge("merged".to_owned(), round.to_string())
+                .unwrap();
+            NaiveKV::flush(catalog, epoch_no, &options).unwrap();
+            while compaction::plan_merge(&catalog.read().unwrap()).is_some() {
+                NaiveKV::merge(catalog, epoch_no, &options).unwrap();
+            }
+            seen_blob_names.extend(blob_names());
+        }
+        seen_blob_names.sort();
+        seen_blob_names.dedup();
+
+        let check = |catalog_viewer: &mut CatalogViewer| {
+            for num in 0..NUM_KEYS {
+                assert_eq!(
+                    catalog_viewer.get(&format!("first_{}", num)).unwrap(),
+                    Some(large_value(0, num))
+                );
+                assert_eq!(
+                    catalog_viewer.get(&format!("later_{}", num)).unwrap(),
+                    Some(large_value(NUM_ROUNDS, num))
+                );
+                assert_eq!(
+                    catalog_viewer.get(&format!("small_{}", num)).unwrap(),
+                    Some(NUM_ROUNDS.to_string())
+                );
+            }
+            let operands = (1..=NUM_ROUNDS).map(|round| round.to_string());
+            assert_eq!(
+                catalog_viewer.get("merged").unwrap(),
+                Some(
+                    std::iter::once(large_value(0, 0))
+                        .chain(operands)
+                        .collect::<Vec<_>>()
+                        .join(",")
+                )
+            );
+            let num_pairs = catalog_viewer.scan(..).unwrap().count();
+            assert_eq!(num_pairs, 3 * NUM_KEYS + 1);
+        };
+        check(&mut catalog_viewer);
+
+        // The merges carry the values of the first round over rather than writing them again,
+        // while the blob files of the values overwritten are removed once no SSTable refers to
+        // them, even a pinned one.
+        drop(catalog_viewer);
+        catalog.write().unwrap().collect_blob_garbage().unwrap();
+        let live_blob_names = blob_names();
+        assert!(live_blob_names.contains(&first_blob_names[0]));
+        assert!(live_blob_names.len() < seen_blob_names.len());
+        let mut manifest = Manifest::load(&LocalBackend, Path::new(FOLDER_PATH))
+            .unwrap()
+            .unwrap();
+        manifest.blob_names.sort();
+        assert_eq!(manifest.blob_names, live_blob_names);
+
+        // The copies of the data folder take the blob files along.
+        naive_kv.checkpoint(CHECKPOINT_PATH).unwrap();
+        check(
+            &mut NaiveKV::open(CHECKPOINT_PATH, options.clone())
+                .unwrap()
+                .catalog_viewer()
+                .unwrap(),
+        );
+        naive_kv.backup(BACKUP_PATH).unwrap();
+        check(
+            &mut NaiveKV::restore(BACKUP_PATH, RESTORE_PATH, options.clone())
+                .unwrap()
+                .catalog_viewer()
+                .unwrap(),
+        );
+
+        // A blob file no SSTable refers to is removed on open, e.g. after a merge cut short.
+        drop(naive_kv);
+        let stray_blob_path = Path::new(FOLDER_PATH).join("blob_1.blob");
+        std::fs::write(&stray_blob_path, "stray").unwrap();
+        let naive_kv = NaiveKV::open(FOLDER_PATH, options).unwrap();
+        assert!(!stray_blob_path.exists());
+        assert_eq!(blob_names(), live_blob_names);
+        check(&mut naive_kv.catalog_viewer().unwrap());
+    }
+
+    #[test]
+    fn test_blob_garbage_collection() {
+        const FOLDER_PATH: &str = "/tmp/naive_kv/test_blob_garbage_collection/";
+        const NUM_KEYS: usize = 20;
+        const NUM_OVERWRITTEN: usize = 15;
+
+        let _ = std::fs::remove_dir_all(FOLDER_PATH);
+        let options = Options {
+            memtable_compaction_threshold: 64,
+            min_blob_size: 64,
+            ..Options::default()
+        };
+        let naive_kv = NaiveKV::open(FOLDER_PATH, options.clone()).unwrap();
+        naive_kv.pause_job(FLUSH_JOB).unwrap();
+        naive_kv.pause_job(MERGE_JOB).unwrap();
+        let catalog = &naive_kv.catalog;
+        let epoch_no = &naive_kv.epoch_no;
+        let blob_names = || {
+            let mut blob_names = std::fs::read_dir(FOLDER_PATH)
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+                .filter(|file_name| file_name.ends_with(".blob"))
+                .collect::<Vec<_>>();
+            blob_names.sort();
+            blob_names
+        };
+        let large_value =
+            |round: usize, num: usize| format!("{}_{}_{}", round, num, "v".repeat(64));
+        let mut catalog_viewer = naive_kv.catalog_viewer().unwrap();
+        for num in 0..NUM_KEYS {
+            catalog_viewer
+                .set(format!("key_{}", num), large_value(0, num))
+                .unwrap();
+        }
+        NaiveKV::flush(catalog, epoch_no, &options).unwrap();
+        let first_blob_names = blob_names();
+        assert_eq!(first_blob_names.len(), 1);
+
+        // The bottom flush dropping most of the values of a blob file carries the rest over.
+        for num in 0..NUM_OVERWRITTEN {
+            catalog_viewer
+                .set(format!("key_{}", num), large_value(1, num))
+                .unwrap();
+        }
+        NaiveKV::flush(catalog, epoch_no, &options).unwrap();
+        catalog.write().unwrap().collect_blob_garbage().unwrap();
+        assert!(blob_names().contains(&first_blob_names[0]));
+
+        // The next one finds the first blob file mostly dead and stores its live values anew.
+        catalog_viewer
+            .set("other".to_owned(), "v".repeat(64))
+            .unwrap();
+        NaiveKV::flush(catalog, epoch_no, &options).unwrap();
+        catalog.write().unwrap().collect_blob_garbage().unwrap();
+        assert!(!blob_names().contains(&first_blob_names[0]));
+        for num in 0..NUM_KEYS {
+            let round = if num < NUM_OVERWRITTEN { 1 } else { 0 };
+            assert_eq!(
+                catalog_viewer.get(&format!("key_{}", num)).unwrap(),
+                Some(large_value(round, num))
+            );
+        }
+    }
+
+    #[test]
+    fn test_cold_generations() {
+        const FOLDER_PATH: &str = "/tmp/naive_kv/test_cold_generations/";
+        const COLD_FOLDER_PATH: &str = "/tmp/naive_kv/test_cold_generations_cold/";
+        const NUM_ROUNDS: usize = 4;
+        const NUM_KEYS: usize = 200;
+
+        let _ = std::fs::remove_dir_all(FOLDER_PATH);
+        let _ = std::fs::remove_dir_all(COLD_FOLDER_PATH);
+        let options = Options {
+            memtable_compaction_threshold: 256,
+            generation_geometric_ratio: 2,
+            cold_folder_path: Some(COLD_FOLDER_PATH.into()),
+            cold_generation_no: 1,
+            ..Options::default()
+        };
+        let naive_kv = NaiveKV::open(FOLDER_PATH, options.clone()).unwrap();
+        pause_compactions(&naive_kv);
+        let mut catalog_viewer = naive_kv.catalog_viewer().unwrap();
+        for round in 0..NUM_ROUNDS {
+            for num in 0..NUM_KEYS {
+                catalog_viewer
+                    .set(num.to_string(), format!("{}_{}", round, num))
+                    .unwrap();
+            }
+            compact(&naive_kv);
+        }
+
+        // Only generation 0 stays in the data folder.
+        let check_locations = |naive_kv: &NaiveKV| {
+            let sstables = naive_kv.describe().unwrap();
+            assert!(sstables.len() > 1);
+            for sstable in sstables {
+                let folder_path = if sstable.gen_no == 0 {
+                    FOLDER_PATH
+                } else {
+                    COLD_FOLDER_PATH
+                };
+                assert!(sstable.file_path.starts_with(folder_path));
+            }
+        };
+        check_locations(&naive_kv);
+
+        // The cold SSTables are found again after a restart.
+        drop(catalog_viewer);
+        drop(naive_kv);
+        let naive_kv = NaiveKV::open(FOLDER_PATH, options).unwrap();
+        check_locations(&naive_kv);
+        let mut catalog_viewer = naive_kv.catalog_viewer().unwrap();
+        for num in 0..NUM_KEYS {
+            let value = format!("{}_{}", NUM_ROUNDS - 1, num);
+            assert_eq!(catalog_viewer.get(&num.to_string()).unwrap(), Some(value));
+        }
+    }
+
+    #[test]
+    fn test_memory_backend() {
+        const FOLDER_PATH: &str = "/tmp/naive_kv/test_memory_backend/";
+        const NUM_KEYS: usize = 200;
+
+        let _ = std::fs::remove_dir_all(FOLDER_PATH);
+        let options = Options {
+            memtable_compaction_threshold: 256,
+            backend: Arc::new(MemoryBackend::new()),
+            ..Options::default()
+        };
+        let naive_kv = NaiveKV::open(FOLDER_PATH, options.clone()).unwrap();
+        pause_compactions(&naive_kv);
+        let mut catalog_viewer = naive_kv.catalog_viewer().unwrap();
+        for num in 0..NUM_KEYS {
+            catalog_viewer
+                .set(num.to_string(), num.to_string())
+                .unwrap();
+        }
+        compact(&naive_kv);
+        assert!(!naive_kv.catalog.read().unwrap().sstables.is_empty());
+
+        // The data survives a restart on the same backend without touching the local disk.
+        drop(catalog_viewer);
+        drop(naive_kv);
+        let naive_kv = NaiveKV::open(FOLDER_PATH, options).unwrap();
+        let mut catalog_viewer = naive_kv.catalog_viewer().unwrap();
+        for num in 0..NUM_KEYS {
+            assert_eq!(
+                catalog_viewer.get(&num.to_string()).unwrap(),
+                Some(num.to_string())
+            );
+        }
+        assert!(!std::path::Path::new(FOLDER_PATH).exists());
+    }
+
+    #[test]
+    fn test_approximate_size() {
+        const FOLDER_PATH: &str = "/tmp/naive_kv/test_approximate_size/";
+        const NUM_KEYS: usize = 1000;
+
+        let _ = std::fs::remove_dir_all(FOLDER_PATH);
+        let options = Options {
+            memtable_compaction_threshold: 4096,
+            ..Options::default()
+        };
+        let naive_kv = NaiveKV::open(FOLDER_PATH, options).unwrap();
+        pause_compactions(&naive_kv);
+        let mut catalog_viewer = naive_kv.catalog_viewer().unwrap();
+        for num in 0..NUM_KEYS {
+            catalog_viewer
+                .set(format!("key{:03}", num), format!("value{:03}", num))
+                .unwrap();
+        }
+        assert_eq!(catalog_viewer.approximate_key_count().unwrap(), NUM_KEYS);
+
+        // The distinct keys are counted exactly wherever they are, and the sizes add up.
+        compact(&naive_kv);
+        assert!(!naive_kv.catalog.read().unwrap().sstables.is_empty());
+        assert_eq!(catalog_viewer.approximate_key_count().unwrap(), NUM_KEYS);
+        let total_size = catalog_viewer.approximate_size(..).unwrap();
+        let lower_size = catalog_viewer
+            .approximate_size(..="key499".to_owned())
+            .unwrap();
+        let upper_size = catalog_viewer
+            .approximate_size("key500".to_owned()..)
+            .unwrap();
+        assert!(lower_size > 0 && upper_size > 0);
+        assert!(lower_size + upper_size <= total_size);
+        assert!(lower_size.abs_diff(upper_size) < total_size / 4);
+        assert_eq!(
+            catalog_viewer
+                .approximate_size("key500".to_owned().."key500".to_owned())
+                .unwrap(),
+            0
+        );
+    }
+
+    #[test]
+    fn test_warm_up() {
+        const FOLDER_PATH: &str = "/tmp/naive_kv/test_warm_up/";
+        const NUM_KEYS: usize = 200;
+
+        let _ = std::fs::remove_dir_all(FOLDER_PATH);
+        let options = Options {
+            memtable_compaction_threshold: 256,
+            ..Options::default()
+        };
+        let naive_kv = NaiveKV::open(FOLDER_PATH, options.clone()).unwrap();
+        pause_compactions(&naive_kv);
+        let mut catalog_viewer = naive_kv.catalog_viewer().unwrap();
+        for num in 0..NUM_KEYS {
+            catalog_viewer
+                .set(format!("{:03}", num), num.to_string())
+                .unwrap();
+        }
+        compact(&naive_kv);
+        drop(catalog_viewer);
+        drop(naive_kv);
+
+        // Each SSTable is read but for its generation number at most once.
+        let naive_kv = NaiveKV::open(FOLDER_PATH, options).unwrap();
+        let sstables = naive_kv.describe().unwrap();
+        let num_bytes = sstables
+            .iter()
+            .map(|sstable| sstable.file_size - 4)
+            .sum::<usize>();
+        assert!(num_bytes > 0);
+        assert_eq!(naive_kv.warm_up(&[..]).unwrap(), num_bytes);
+        let hot_range = "050".to_owned().."100".to_owned();
+        let num_hot_bytes = naive_kv.warm_up(std::slice::from_ref(&hot_range)).unwrap();
+        assert!(num_hot_bytes > 0 && num_hot_bytes < num_bytes);
+        assert_eq!(
+            naive_kv.warm_up(&[hot_range.clone(), hot_range]).unwrap(),
+            num_hot_bytes
+        );
+    }
+
+    #[test]
+    fn test_verify_sstables() {
+        const FOLDER_PATH: &str = "/tmp/naive_kv/test_verify_sstables/";
+        const NUM_KEYS: usize = 200;
+
+        let _ = std::fs::remove_dir_all(FOLDER_PATH);
+        let options = Options {
+            memtable_compaction_threshold: 256,
+            ..Options::default()
+        };
+        let naive_kv = NaiveKV::open(FOLDER_PATH, options.clone()).unwrap();
+        pause_compactions(&naive_kv);
+        let mut catalog_viewer = naive_kv.catalog_viewer().unwrap();
+        for num in 0..NUM_KEYS {
+            catalog_viewer
+                .set(num.to_string(), num.to_string())
+                .unwrap();
+        }
+        compact(&naive_kv);
+        drop(catalog_viewer);
+        drop(naive_kv);
+        assert!(NaiveKV::verify_sstables(FOLDER_PATH, &options)
+            .unwrap()
+            .is_empty());
+
+        // The checksums survive a restart, which takes them from the manifest.
+        drop(NaiveKV::open(FOLDER_PATH, options.clone()).unwrap());
+        assert!(NaiveKV::verify_sstables(FOLDER_PATH, &options)
+            .unwrap()
+            .is_empty());
+
+        // A flipped bit is caught, and so is a missing file.
+        let manifest = Manifest::load(&LocalBackend, Path::new(FOLDER_PATH))
+            .unwrap()
+            .unwrap();
+        let (file_path, _) = &manifest.sstable_paths(Path::new(FOLDER_PATH))[0];
+        let mut bytes = std::fs::read(file_path).unwrap();
+        let last_byte = bytes.len() - 1;
+        bytes[last_byte] ^= 1;
+        std::fs::write(file_path, bytes).unwrap();
+        assert_eq!(
+            NaiveKV::verify_sstables(FOLDER_PATH, &options).unwrap(),
+            vec![file_path.clone()]
+        );
+        std::fs::remove_file(file_path).unwrap();
+        assert_eq!(
+            NaiveKV::verify_sstables(FOLDER_PATH, &options).unwrap(),
+            vec![file_path.clone()]
+        );
+    }
+
+    #[test]
+    fn test_replica() {
+        const FOLDER_PATH: &str = "/tmp/naive_kv/test_replica/";
+        const NUM_ROUNDS: usize = 4;
+        const NUM_KEYS: usize = 200;
+
+        let _ = std::fs::remove_dir_all(FOLDER_PATH);
+        let options = Options {
+            memtable_compaction_threshold: 256,
+            generation_geometric_ratio: 2,
+            ..Options::default()
+        };
+        let naive_kv = NaiveKV::open(FOLDER_PATH, options.clone()).unwrap();
+        pause_compactions(&naive_kv);
+        let mut catalog_viewer = naive_kv.catalog_viewer().unwrap();
+        catalog_viewer
+            .set("key".to_owned(), "value".to_owned())
+            .unwrap();
+
+        let replica = NaiveKV::open_replica(FOLDER_PATH, options).unwrap();
+        let mut replica_viewer = replica.catalog_viewer().unwrap();
+        assert_eq!(replica_viewer.get("key").unwrap(), Some("value".to_owned()));
+        assert!(matches!(
+            replica_viewer.set("key".to_owned(), "other".to_owned()),
+            Err(NaiveError::ReadOnly)
+        ));
+
+        // The replica keeps up with the writes through the flushes and merges of the primary.
+        for round in 0..NUM_ROUNDS {
+            for num in 0..NUM_KEYS {
+                catalog_viewer
+                    .set(num.to_string(), format!("{}_{}", round, num))
+                    .unwrap();
+            }
+            catalog_viewer.remove("key".to_owned()).unwrap();
+            replica.refresh().unwrap();
+            for num in 0..NUM_KEYS {
+                let value = format!("{}_{}", round, num);
+                assert_eq!(replica_viewer.get(&num.to_string()).unwrap(), Some(value));
+            }
+            assert_eq!(replica_viewer.get("key").unwrap(), None);
+            compact(&naive_kv);
+        }
+        replica.refresh().unwrap();
+        assert!(replica.catalog.read().unwrap().sstables.len() > 1);
+        let file_paths = |naive_kv: &NaiveKV| {
+            naive_kv
+                .describe()
+                .unwrap()
+                .into_iter()
+                .map(|description| description.file_path)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(file_paths(&replica), file_paths(&naive_kv));
+        for num in 0..NUM_KEYS {
+            let value = format!("{}_{}", NUM_ROUNDS - 1, num);
+            assert_eq!(replica_viewer.get(&num.to_string()).unwrap(), Some(value));
+        }
+    }
+
+    #[test]
+    fn test_background_jobs() {
+        const FOLDER_PATH: &str = "/tmp/naive_kv/test_background_jobs/";
+        const NUM_KEYS: usize = 200;
+
+        let _ = std::fs::remove_dir_all(FOLDER_PATH);
+        let merge_schedule = JobSchedule {
+            interval: Duration::from_secs(3600),
+            jitter: Duration::from_secs(60),
+        };
+        let options = Options {
+            memtable_compaction_threshold: 256,
+            job_schedules: [(MERGE_JOB.to_owned(), merge_schedule)].into(),
+            ..Options::default()
+        };
+        let naive_kv = NaiveKV::open(FOLDER_PATH, options).unwrap();
+        let num_runs = Arc::new(AtomicUsize::new(0));
+        let num_runs_copy = num_runs.clone();
+        naive_kv
+            .register_job(
+                "count",
+                JobSchedule::every(Duration::from_millis(100)),
+                move || {
+                    num_runs_copy.fetch_add(1, Ordering::SeqCst);
+                    Ok(())
+                },
+            )
+            .unwrap();
+        let jobs = naive_kv.jobs().unwrap();
+        let job_names = jobs.iter().map(|job| job.name.as_str()).collect::<Vec<_>>();
+        assert_eq!(
+            job_names,
+            vec![
+                FLUSH_JOB,
+                MERGE_JOB,
+                EXPIRE_KEYS_JOB,
+                "check_compaction_backlog",
+                "count"
+            ]
+        );
+        assert_eq!(jobs[1].schedule, merge_schedule);
+
+        // Nothing gets flushed while the flushes are paused, however often the other jobs run.
+        assert!(naive_kv.pause_job(FLUSH_JOB).unwrap().is_paused);
+        let mut catalog_viewer = naive_kv.catalog_viewer().unwrap();
+        for num in 0..NUM_KEYS {
+            catalog_viewer
+                .set(num.to_string(), num.to_string())
+                .unwrap();
+        }
+        wait_for_runs(&naive_kv, "count", 3);
+        assert!(naive_kv.describe().unwrap().is_empty());
+        assert_eq!(naive_kv.jobs().unwrap()[0].num_runs, 0);
+
+        naive_kv.resume_job(FLUSH_JOB).unwrap();
+        wait_for_runs(&naive_kv, FLUSH_JOB, 1);
+        assert_eq!(naive_kv.describe().unwrap().len(), 1);
+        assert!(num_runs.load(Ordering::SeqCst) >= 3);
+        assert!(matches!(
+            naive_kv.pause_job("missing"),
+            Err(NaiveError::JobNotFound(_))
+        ));
+    }
+
+    #[test]
+    fn test_read_amplification() {
+        const FOLDER_PATH: &str = "/tmp/naive_kv/test_read_amplification/";
+        const NUM_ROUNDS: usize = 4;
+        const NUM_KEYS: usize = 200;
+        const MAX_GENERATIONS: usize = 2;
+
+        let _ = std::fs::remove_dir_all(FOLDER_PATH);
+        let options = Options {
+            memtable_compaction_threshold: 256,
+            generation_geometric_ratio: 2,
+            max_generations: Some(MAX_GENERATIONS),
+            ..Options::default()
+        };
+        let naive_kv = NaiveKV::open(FOLDER_PATH, options).unwrap();
+        pause_compactions(&naive_kv);
+
+        let mut catalog_viewer = naive_kv.catalog_viewer().unwrap();
+        for round in 0..NUM_ROUNDS {
+            for num in 0..NUM_KEYS {
+                catalog_viewer
+                    .set(num.to_string(), format!("{}_{}", round, num))
+                    .unwrap();
+            }
+            compact(&naive_kv);
+        }
+        assert!(naive_kv.catalog.read().unwrap().sstables.len() <= MAX_GENERATIONS);
+
+        for num in 0..NUM_KEYS {
+            let value = format!("{}_{}", NUM_ROUNDS - 1, num);
+            assert_eq!(catalog_viewer.get(&num.to_string()).unwrap(), Some(value));
+        }
+        let read_amplification = naive_kv.stats().unwrap().read_amplification;
+        assert_eq!(read_amplification.num_lookups(), NUM_KEYS as u64);
+        // At most both Memtables and all the generations.
+        assert!(read_amplification.max() <= 2 + MAX_GENERATIONS);
+        assert!(read_amplification.mean() >= 1.0);
+    }
+
+    #[test]
+    fn test_get_with_deadline() {
+        const FOLDER_PATH: &str = "/tmp/naive_kv/test_get_with_deadline/";
+        const NUM_KEYS: usize = 100;
+
+        let _ = std::fs::remove_dir_all(FOLDER_PATH);
+        let options = Options {
+            memtable_compaction_threshold: 256,
+            ..Options::default()
+        };
+        let naive_kv = NaiveKV::open(FOLDER_PATH, options).unwrap();
+        pause_compactions(&naive_kv);
+        let mut catalog_viewer = naive_kv.catalog_viewer().unwrap();
+        for num in 0..NUM_KEYS {
+            catalog_viewer
+                .set(num.to_string(), num.to_string())
+                .unwrap();
+        }
+        // Flush the keys into an SSTable.
+        compact(&naive_kv);
+        catalog_viewer
+            .set("new".to_owned(), "value".to_owned())
+            .unwrap();
+
+        // The Memtable is always read, but no SSTable after the deadline.
+        let deadline = Some(std::time::Instant::now());
+        assert_eq!(
+            catalog_viewer.get_with_deadline("new", deadline).unwrap(),
+            Some("value".to_owned())
+        );
+        assert!(matches!(
+            catalog_viewer.get_with_deadline("0", deadline),
+            Err(NaiveError::DeadlineExceeded)
+        ));
+        assert_eq!(
+            catalog_viewer.get_with_deadline("0", None).unwrap(),
+            Some("0".to_owned())
+        );
+    }
+
+    #[test]
+    fn test_sample_keys() {
+        const FOLDER_PATH: &str = "/tmp/naive_kv/test_sample_keys/";
+        const NUM_KEYS: usize = 1000;
+        const NUM_SAMPLES: usize = 100;
+
+        let _ = std::fs::remove_dir_all(FOLDER_PATH);
+        let options = Options {
+            memtable_compaction_threshold: 1024,
+            ..Options::default()
+        };
+        let naive_kv = NaiveKV::open(FOLDER_PATH, options).unwrap();
+        pause_compactions(&naive_kv);
+        let mut catalog_viewer = naive_kv.catalog_viewer().unwrap();
+        assert!(catalog_viewer.sample_keys(NUM_SAMPLES).unwrap().is_empty());
+
+        for num in 0..NUM_KEYS {
+            catalog_viewer
+                .set(num.to_string(), num.to_string())
+                .unwrap();
+        }
+        // Flush the keys into an SSTable.
+        compact(&naive_kv);
+
+        let keys = catalog_viewer.sample_keys(NUM_SAMPLES).unwrap();
+        assert_eq!(keys.len(), NUM_SAMPLES);
+        for key in keys.iter() {
+            assert!(key.parse::<usize>().unwrap() < NUM_KEYS);
+        }
+        // The sample is not stuck on a few keys.
+        let distinct_keys = keys.iter().collect::<std::collections::BTreeSet<_>>();
+        assert!(distinct_keys.len() > NUM_SAMPLES / 2);
+    }
+
+    #[test]
+    fn test_scan_tombstones() {
+        const FOLDER_PATH: &str = "/tmp/naive_kv/test_scan_tombstones/";
+        const MAX_NUMBER: usize = 200;
+
+        let _ = std::fs::remove_dir_all(FOLDER_PATH);
+        let options = Options {
+            memtable_compaction_threshold: 1024, // 1 KB
+            ..Options::default()
+        };
+        let naive_kv = NaiveKV::open(FOLDER_PATH, options).unwrap();
+        pause_compactions(&naive_kv);
+        let mut catalog_viewer = naive_kv.catalog_viewer().unwrap();
+        let key_of = |num: usize| format!("{:05}", num);
+
+        // Delete half of the keys once they are flushed.
+        for num in 0..MAX_NUMBER {
+            catalog_viewer.set(key_of(num), num.to_string()).unwrap();
+        }
+        compact(&naive_kv);
+        for num in (0..MAX_NUMBER).step_by(2) {
+            catalog_viewer.remove(key_of(num)).unwrap();
+        }
+
+        let scan_entries = |tombstones| {
+            let options = ScanOptions { tombstones };
+            catalog_viewer
+                .scan_entries(.., &options)
+                .unwrap()
+                .collect::<Result<Vec<_>>>()
+                .unwrap()
+        };
+        let entries = scan_entries(TombstoneVisibility::Skip);
+        assert_eq!(entries.len(), MAX_NUMBER / 2);
+        assert!(entries
+            .iter()
+            .all(|entry| matches!(entry.record, Record::Value(_))));
+
+        let entries = scan_entries(TombstoneVisibility::Include);
+        assert_eq!(entries.len(), MAX_NUMBER);
+        for (num, entry) in entries.into_iter().enumerate() {
+            assert_eq!(entry.key, key_of(num));
+            if num % 2 == 0 {
+                assert_eq!(entry.record, Record::Deleted);
+                assert_eq!(entry.source, RecordSource::Memtable);
+            } else {
+                assert_eq!(entry.record, Record::Value(num.to_string()));
+                assert_eq!(entry.source, RecordSource::SSTable { gen_no: 0 });
+            }
+        }
+    }
+
+    #[test]
+    fn test_write_timestamps() {
+        const FOLDER_PATH: &str = "/tmp/naive_kv/test_write_timestamps/";
+        let _ = std::fs::remove_dir_all(FOLDER_PATH);
+        let options = Options {
+            memtable_compaction_threshold: 16,
+            ..Options::default()
+        };
+        let naive_kv = NaiveKV::open(FOLDER_PATH, options).unwrap();
+        pause_compactions(&naive_kv);
+        let mut catalog_viewer = naive_kv.catalog_viewer().unwrap();
+        let now_ms = || {
+            std::time::SystemTime::now()
+                .duration_since(std::time::SystemTime::UNIX_EPOCH)
+                .unwrap()
+                .as_millis() as u64
+        };
+
+        let start_ms = now_ms();
+        catalog_viewer
+            .set("key".to_owned(), "x".repeat(16))
+            .unwrap();
+        let end_ms = now_ms();
+        let (_, timestamp_ms) = catalog_viewer
+            .get_with_timestamp("key", None)
+            .unwrap()
+            .unwrap();
+        let timestamp_ms = timestamp_ms.unwrap();
+        assert!((start_ms..=end_ms).contains(&timestamp_ms));
+
+        // The timestamp survives the flush into an SSTable.
+        compact(&naive_kv);
+        assert!(naive_kv
+            .catalog
+            .read()
+            .unwrap()
+            .memtable
+            .get("key")
+            .unwrap()
+            .is_none());
+        assert_eq!(
+            catalog_viewer.get_with_timestamp("key", None).unwrap(),
+            Some(("x".repeat(16), Some(timestamp_ms)))
+        );
+
+        // A deleted key has no timestamp to show.
+        catalog_viewer.remove("key".to_owned()).unwrap();
+        assert_eq!(
+            catalog_viewer.get_with_timestamp("key", None).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_get_raw() {
+        const FOLDER_PATH: &str = "/tmp/naive_kv/test_get_raw/";
+        let _ = std::fs::remove_dir_all(FOLDER_PATH);
+        let options = Options {
+            memtable_compaction_threshold: 16,
+            checksum_records: true,
+            ..Options::default()
+        };
+        let naive_kv = NaiveKV::open(FOLDER_PATH, options).unwrap();
+        pause_compactions(&naive_kv);
+        let mut catalog_viewer = naive_kv.catalog_viewer().unwrap();
+        assert_eq!(catalog_viewer.get_raw("key").unwrap(), None);
+
+        let decode = |raw_record: &RawRecord| {
+            let command = Command::parse_from_bytes(&raw_record.bytes).unwrap();
+            assert!(command.has_checksum());
+            (
+                command.get_key().to_owned(),
+                Record::from_command(&command).unwrap(),
+            )
+        };
+        catalog_viewer
+            .set("key".to_owned(), "x".repeat(16))
+            .unwrap();
+        let raw_record = catalog_viewer.get_raw("key").unwrap().unwrap();
+        assert_eq!(raw_record.source, RecordSource::Memtable);
+        assert_eq!(raw_record.location, None);
+        assert_eq!(
+            decode(&raw_record),
+            ("key".to_owned(), Record::Value("x".repeat(16)))
+        );
+
+        // The record in the SSTable is the same, but read as stored.
+        compact(&naive_kv);
+        let sstable_record = catalog_viewer.get_raw("key").unwrap().unwrap();
+        assert_eq!(sstable_record.source, RecordSource::SSTable { gen_no: 0 });
+        assert!(sstable_record.location.is_some());
+        assert_eq!(sstable_record.bytes, raw_record.bytes);
+
+        // Deletions show up as well.
+        catalog_viewer.remove("key".to_owned()).unwrap();
+        let raw_record = catalog_viewer.get_raw("key").unwrap().unwrap();
+        assert_eq!(decode(&raw_record), ("key".to_owned(), Record::Deleted));
+    }
+
+    #[derive(Debug, Default)]
+    struct QuarantineRecorder {
+        events: std::sync::Mutex<Vec<QuarantineEvent>>,
+    }
+
+    impl EventListener for QuarantineRecorder {
+        fn on_quarantine(&self, event: &QuarantineEvent) {
+            self.events.lock().unwrap().push(event.clone());
+        }
+    }
+
+    #[test]
+    fn test_quarantine() {
+        const FOLDER_PATH: &str = "/tmp/naive_kv/test_quarantine/";
+        let _ = std::fs::remove_dir_all(FOLDER_PATH);
+        let recorder = Arc::new(QuarantineRecorder::default());
+        let options = Options {
+            memtable_compaction_threshold: 4096,
+            checksum_records: true,
+            sstable_error_threshold: 2,
+            event_listeners: vec![recorder.clone()],
+            ..Options::default()
+        };
+        let naive_kv = NaiveKV::open(FOLDER_PATH, options).unwrap();
+        pause_compactions(&naive_kv);
+        let mut catalog_viewer = naive_kv.catalog_viewer().unwrap();
+        for num in 0..100 {
+            catalog_viewer
+                .set(format!("key{:03}", num), format!("value{:03}", num))
+                .unwrap();
+        }
+        catalog_viewer
+            .set("pad".to_owned(), "-".repeat(4096))
+            .unwrap();
+        compact(&naive_kv);
+        let descriptions = naive_kv.describe().unwrap();
+        assert_eq!(descriptions.len(), 1);
+        let sstable_path = descriptions[0].file_path.clone();
+
+        // Flip a byte of a value without breaking the encoding.
+        let mut bytes = std::fs::read(&sstable_path).unwrap();
+        let value_offset = bytes
+            .windows(8)
+            .position(|window| window == b"value042")
+            .unwrap();
+        bytes[value_offset + 7] = b'3';
+        std::fs::write(&sstable_path, &bytes).unwrap();
+
+        // The errors in a row quarantine the SSTable, while a successful read resets the count.
+        let is_corruption = |result| matches!(result, Err(NaiveError::Corruption { .. }));
+        assert!(is_corruption(catalog_viewer.get("key042")));
+        assert_eq!(
+            catalog_viewer.get("key041").unwrap(),
+            Some("value041".to_owned())
+        );
+        assert!(is_corruption(catalog_viewer.get("key042")));
+        assert!(recorder.events.lock().unwrap().is_empty());
+        assert!(is_corruption(catalog_viewer.get("key042")));
+        {
+            let events = recorder.events.lock().unwrap();
+            assert_eq!(events.len(), 1);
+            assert_eq!(events[0].file_path, sstable_path);
+            assert_eq!(events[0].num_errors, 2);
+        }
+        assert!(naive_kv.describe().unwrap()[0].is_quarantined);
+
+        // The gets needing the SSTable fail fast, while the others go on.
+        assert!(matches!(
+            catalog_viewer.get("key041"),
+            Err(NaiveError::Quarantined { file_path }) if file_path == sstable_path
+        ));
+        catalog_viewer
+            .set("key042".to_owned(), "new".to_owned())
+            .unwrap();
+        assert_eq!(
+            catalog_viewer.get("key042").unwrap(),
+            Some("new".to_owned())
+        );
+        assert_eq!(recorder.events.lock().unwrap().len(), 1);
+    }
+
+    #[derive(Debug, Default)]
+    struct CompactionFailureRecorder {
+        events: std::sync::Mutex<Vec<CompactionFailureEvent>>,
+    }
+
+    impl EventListener for CompactionFailureRecorder {
+        fn on_compaction_failure(&self, event: &CompactionFailureEvent) {
+            self.events.lock().unwrap().push(event.clone());
+        }
+    }
+
+    #[test]
+    fn test_compaction_failure() {
+        const FOLDER_PATH: &str = "/tmp/naive_kv/test_compaction_failure/";
+
+        let _ = std::fs::remove_dir_all(FOLDER_PATH);
+        let recorder = Arc::new(CompactionFailureRecorder::default());
+        let options = Options {
+            event_listeners: vec![recorder.clone()],
+            ..Options::default()
+        };
+        let naive_kv = NaiveKV::open(FOLDER_PATH, options).unwrap();
+
+        // A failed compaction is counted and reported, and its error is passed on.
+        let result =
+            NaiveKV::check_compaction(&naive_kv.catalog, MERGE_JOB, Err(NaiveError::Unknown));
+        assert!(matches!(result, Err(NaiveError::Unknown)));
+        assert!(NaiveKV::check_compaction(&naive_kv.catalog, FLUSH_JOB, Ok(())).is_ok());
+        assert_eq!(naive_kv.stats().unwrap().compaction.num_failures, 1);
+        assert_eq!(
+            *recorder.events.lock().unwrap(),
+            vec![CompactionFailureEvent {
+                job_name: MERGE_JOB.to_owned(),
+                folder_path: Path::new(FOLDER_PATH).to_path_buf(),
+                error: "Unknown".to_owned(),
+            }]
+        );
+        naive_kv.close().unwrap();
+    }
+
+    /// The local file system, which fails to create any segment file while told to.
+    #[derive(Debug, Default)]
+    struct FailingBackend {
+        fails_sstables: AtomicBool,
+    }
+
+    impl Backend for FailingBackend {
+        fn create_dir_all(&self, folder_path: &Path) -> Result<()> {
+            LocalBackend.create_dir_all(folder_path)
+        }
+
+        fn list_files(&self, folder_path: &Path) -> Result<Vec<PathBuf>> {
+            LocalBackend.list_files(folder_path)
+        }
+
+        fn exists(&self, path: &Path) -> bool {
+            LocalBackend.exists(path)
+        }
+
+        fn open(&self, file_path: &Path) -> Result<Box<dyn BackendFile>> {
+            LocalBackend.open(file_path)
+        }
+
+        fn open_append(&self, file_path: &Path) -> Result<Box<dyn BackendFile>> {
+            LocalBackend.open_append(file_path)
+        }
+
+        fn create_new(&self, file_path: &Path) -> Result<Box<dyn BackendFile>> {
+            let is_sstable = file_path
+                .extension()
+                .is_some_and(|extension| extension == "sst");
+            if is_sstable && self.fails_sstables.load(Ordering::SeqCst) {
+                return Err(NaiveError::Unknown);
+            }
+            LocalBackend.create_new(file_path)
+        }
+
+        fn write_at(&self, file_path: &Path, offset: u64, bytes: &[u8]) -> Result<()> {
+            LocalBackend.write_at(file_path, offset, bytes)
+        }
+
+        fn replace(&self, file_path: &Path, bytes: &[u8]) -> Result<()> {
+            LocalBackend.replace(file_path, bytes)
+        }
+
+        fn rename(&self, from_path: &Path, to_path: &Path) -> Result<()> {
+            LocalBackend.rename(from_path, to_path)
+        }
+
+        fn remove_file(&self, file_path: &Path) -> Result<bool> {
+            LocalBackend.remove_file(file_path)
+        }
+
+        fn remove_dir(&self, folder_path: &Path) -> Result<bool> {
+            LocalBackend.remove_dir(folder_path)
+        }
+
+        fn lock_folder(
+            &self,
+            folder_path: &Path,
+            lock_name: &str,
+            is_exclusive: bool,
+        ) -> Result<FolderLock> {
+            LocalBackend.lock_folder(folder_path, lock_name, is_exclusive)
+        }
+
+        fn file_size(&self, file_path: &Path) -> Result<u64> {
+            LocalBackend.file_size(file_path)
+        }
+
+        fn modified_time(&self, file_path: &Path) -> Result<std::time::SystemTime> {
+            LocalBackend.modified_time(file_path)
+        }
+
+        fn set_modified_time(
+            &self,
+            file_path: &Path,
+            modified_time: std::time::SystemTime,
+        ) -> Result<()> {
+            LocalBackend.set_modified_time(file_path, modified_time)
+        }
+
+        fn map(&self, file_path: &Path) -> Result<Option<MappedFile>> {
+            LocalBackend.map(file_path)
+        }
+    }
+
+    #[test]
+    fn test_failed_flush() {
+        const FOLDER_PATH: &str = "/tmp/naive_kv/test_failed_flush/";
+
+        let _ = std::fs::remove_dir_all(FOLDER_PATH);
+        let backend = Arc::new(FailingBackend::default());
+        let options = Options {
+            memtable_compaction_threshold: 64,
+            backend: backend.clone(),
+            ..Options::default()
+        };
+        let naive_kv = NaiveKV::open(FOLDER_PATH, options.clone()).unwrap();
+        pause_compactions(&naive_kv);
+        let mut catalog_viewer = naive_kv.catalog_viewer().unwrap();
+        let flush = |naive_kv: &NaiveKV| {
+            let options = &naive_kv.default_column_family.options;
+            NaiveKV::flush(&naive_kv.catalog, &naive_kv.epoch_no, options)
+        };
+
+        // A failed flush leaves its Memtable pending, and the flushes retry it rather than swap
+        // in another Memtable on top, however full the new one gets.
+        backend.fails_sstables.store(true, Ordering::SeqCst);
+        catalog_viewer.set("a".to_owned(), "x".repeat(64)).unwrap();
+        assert!(flush(&naive_kv).is_err());
+        let ro_log_path = {
+            let catalog = naive_kv.catalog.read().unwrap();
+            catalog
+                .ro_memtable
+                .as_ref()
+                .unwrap()
+                .log_path()
+                .to_path_buf()
+        };
+        catalog_viewer.set("b".to_owned(), "y".repeat(64)).unwrap();
+        assert!(flush(&naive_kv).is_err());
+        {
+            let catalog = naive_kv.catalog.read().unwrap();
+            assert_eq!(
+                catalog.ro_memtable.as_ref().unwrap().log_path(),
+                ro_log_path
+            );
+            assert_eq!(
+                compaction::plan_flush(&catalog).unwrap().unwrap().inputs[0].file_path,
+                ro_log_path
+            );
+        }
+        assert_eq!(catalog_viewer.get("a").unwrap(), Some("x".repeat(64)));
+        assert_eq!(catalog_viewer.get("b").unwrap(), Some("y".repeat(64)));
+
+        // Once the flushes succeed again, the pending Memtable goes first and nothing is lost.
+        backend.fails_sstables.store(false, Ordering::SeqCst);
+        flush(&naive_kv).unwrap();
+        assert!(naive_kv.catalog.read().unwrap().ro_memtable.is_none());
+        assert!(!Path::new(&ro_log_path).exists());
+        flush(&naive_kv).unwrap();
+        assert_eq!(naive_kv.stats().unwrap().compaction.num_compactions, 2);
+        drop(catalog_viewer);
+        drop(naive_kv);
+
+        let naive_kv = NaiveKV::open(FOLDER_PATH, options).unwrap();
+        let mut catalog_viewer = naive_kv.catalog_viewer().unwrap();
+        assert_eq!(catalog_viewer.get("a").unwrap(), Some("x".repeat(64)));
+        assert_eq!(catalog_viewer.get("b").unwrap(), Some("y".repeat(64)));
+    }
+
+    #[derive(Debug, Default)]
+    struct SoftLimitRecorder {
+        events: std::sync::Mutex<Vec<SoftLimitEvent>>,
+    }
+
+    impl EventListener for SoftLimitRecorder {
+        fn on_soft_limit(&self, event: &SoftLimitEvent) {
+            self.events.lock().unwrap().push(event.clone());
+        }
+    }
+
+    #[test]
+    fn test_soft_limits() {
+        const FOLDER_PATH: &str = "/tmp/naive_kv/test_soft_limits/";
+        let _ = std::fs::remove_dir_all(FOLDER_PATH);
+        let recorder = std::sync::Arc::new(SoftLimitRecorder::default());
+        let options = Options {
+            memtable_compaction_threshold: 1000,
+            compaction_backlog_soft_limit: 0,
+            event_listeners: vec![recorder.clone()],
+            ..Options::default()
+        };
+        let naive_kv = NaiveKV::open(FOLDER_PATH, options).unwrap();
+        // Keep the flush pending, since the backlog is checked independently of the flushes.
+        pause_compactions(&naive_kv);
+        let mut catalog_viewer = naive_kv.catalog_viewer().unwrap();
+
+        // The warning comes once on the way to the threshold, before the flush is triggered.
+        let mut num = 0;
+        while !catalog_viewer
+            .set(num.to_string(), num.to_string())
+            .unwrap()
+            .flush_triggered
+        {
+            num += 1;
+        }
+        {
+            let events = recorder.events.lock().unwrap();
+            assert_eq!(events.len(), 1);
+            assert!(matches!(
+                events[0],
+                SoftLimitEvent::MemtableNearlyFull { data_size, threshold: 1000 }
+                    if (800..1000).contains(&data_size)
+            ));
+        }
+
+        // The backlog check sees the pending flush beyond the limit.
+        let column_family = &naive_kv.default_column_family;
+        NaiveKV::check_compaction_backlog(
+            &column_family.catalog,
+            &column_family.options,
+            &column_family.is_backlogged,
+        )
+        .unwrap();
+        let events = recorder.events.lock().unwrap().clone();
+        assert!(matches!(
+            events[1..],
+            [SoftLimitEvent::CompactionBacklog { soft_limit: 0, .. }]
+        ));
+        let stats = naive_kv.stats().unwrap();
+        assert_eq!(stats.soft_limits.num_memtable_nearly_full, 1);
+        assert_eq!(stats.soft_limits.num_flush_behind, 0);
+        assert_eq!(stats.soft_limits.num_compaction_backlog, 1);
+        assert!(stats.compaction_backlog_bytes > 0);
+    }
+
+    /// Writes a key of its own on each soft limit crossed.
+    #[derive(Default)]
+    struct SoftLimitWriter {
+        catalog_viewer: std::sync::Mutex<Option<CatalogViewer>>,
+    }
+
+    impl std::fmt::Debug for SoftLimitWriter {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            f.debug_struct("SoftLimitWriter").finish()
+        }
+    }
+
+    impl EventListener for SoftLimitWriter {
+        fn on_soft_limit(&self, _event: &SoftLimitEvent) {
+            if let Some(catalog_viewer) = self.catalog_viewer.lock().unwrap().as_mut() {
+                catalog_viewer
+                    .set("listener".to_owned(), "called".to_owned())
+                    .unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn test_soft_limit_listener_writes() {
+        const FOLDER_PATH: &str = "/tmp/naive_kv/test_soft_limit_listener_writes/";
+        let _ = std::fs::remove_dir_all(FOLDER_PATH);
+        let writer = std::sync::Arc::new(SoftLimitWriter::default());
+        let options = Options {
+            memtable_compaction_threshold: 1000,
+            event_listeners: vec![writer.clone()],
+            ..Options::default()
+        };
+        let naive_kv = NaiveKV::open(FOLDER_PATH, options).unwrap();
+        naive_kv.pause_job(FLUSH_JOB).unwrap();
+        *writer.catalog_viewer.lock().unwrap() = Some(naive_kv.catalog_viewer().unwrap());
+
+        // The listener is called once the write has released the Memtable, so it can write too.
+        let mut catalog_viewer = naive_kv.catalog_viewer().unwrap();
+        let mut num = 0;
+        while catalog_viewer.get("listener").unwrap().is_none() {
+            catalog_viewer
+                .set(num.to_string(), num.to_string())
+                .unwrap();
+            num += 1;
+        }
+        assert_eq!(
+            naive_kv
+                .stats()
+                .unwrap()
+                .soft_limits
+                .num_memtable_nearly_full,
+            1
+        );
+        // Break the cycle through the catalog of the listener.
+        writer.catalog_viewer.lock().unwrap().take();
+    }
+
+    #[test]
+    fn test_flush_after_burst() {
+        const FOLDER_PATH: &str = "/tmp/naive_kv/test_flush_after_burst/";
+        const NUM_KEYS: usize = 200;
+        const NUM_ROUNDS: usize = 10;
+        const THRESHOLD: usize = 500;
+
+        let _ = std::fs::remove_dir_all(FOLDER_PATH);
+        let options = Options {
+            memtable_compaction_threshold: THRESHOLD,
+            ..Options::default()
+        };
+        let naive_kv = NaiveKV::open(FOLDER_PATH, options).unwrap();
+        // Hold up the flush, and keep generation 0 where the flush puts it.
+        pause_compactions(&naive_kv);
+        let mut catalog_viewer = naive_kv.catalog_viewer().unwrap();
+
+        // A burst overwriting the keys goes far beyond the threshold while the flush is held up.
+        for round in 0..NUM_ROUNDS {
+            for num in 0..NUM_KEYS {
+                catalog_viewer
+                    .set(num.to_string(), format!("{}-{}", num, round))
+                    .unwrap();
+            }
+        }
+        let data_size = naive_kv.catalog.read().unwrap().memtable.data_size();
+        assert!(data_size > 2 * THRESHOLD);
+
+        // A single flush writes the burst with the overwrites folded into one SSTable.
+        let options = &naive_kv.default_column_family.options;
+        NaiveKV::flush(&naive_kv.catalog, &naive_kv.epoch_no, options).unwrap();
+        let descriptions = naive_kv.describe().unwrap();
+        assert_eq!(descriptions.len(), 1);
+        assert_eq!(descriptions[0].gen_no, 0);
+        assert_eq!(descriptions[0].num_entries, NUM_KEYS);
+        assert_eq!(naive_kv.stats().unwrap().compaction.num_compactions, 1);
+        assert_eq!(
+            catalog_viewer.get("7").unwrap(),
+            Some(format!("7-{}", NUM_ROUNDS - 1))
+        );
+    }
+
+    #[test]
+    fn test_close() {
+        const FOLDER_PATH: &str = "/tmp/naive_kv/test_close/";
+
+        let _ = std::fs::remove_dir_all(FOLDER_PATH);
+        let naive_kv = NaiveKV::open(FOLDER_PATH, Options::default()).unwrap();
+        naive_kv
+            .catalog_viewer()
+            .unwrap()
+            .set("key".to_owned(), "value".to_owned())
+            .unwrap();
+        naive_kv.close().unwrap();
+        let naive_kv = NaiveKV::open(FOLDER_PATH, Options::default()).unwrap();
+        assert_eq!(
+            naive_kv.catalog_viewer().unwrap().get("key").unwrap(),
+            Some("value".to_owned())
+        );
+
+        // The failures of a job are all surfaced on close, even if a later run succeeds.
+        let num_runs = Arc::new(AtomicUsize::new(0));
+        let num_runs_copy = num_runs.clone();
+        naive_kv
+            .register_job(
+                "failing",
+                JobSchedule::every(Duration::from_millis(10)),
+                move || match num_runs_copy.fetch_add(1, Ordering::SeqCst) {
+                    0 => Err(NaiveError::InvalidData),
+                    1 => Err(NaiveError::TaskQueueFull),
+                    _ => Ok(()),
+                },
+            )
+            .unwrap();
+        while num_runs.load(Ordering::SeqCst) < 3 {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        match naive_kv.close() {
+            Err(NaiveError::JobFailed { errors }) => assert_eq!(
+                errors,
+                vec![
+                    ("failing".to_owned(), "InvalidData".to_owned()),
+                    ("failing".to_owned(), "TaskQueueFull".to_owned()),
+                ]
+            ),
+            result => panic!("Unexpected result of close: {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_checkpoint() {
+        const FOLDER_PATH: &str = "/tmp/naive_kv/test_checkpoint/";
+        const TARGET_PATH: &str = "/tmp/naive_kv/test_checkpoint_target/";
+
+        let _ = std::fs::remove_dir_all(TARGET_PATH);
+        let options = fixture_options();
+        let (naive_kv, mut catalog_viewer) = open_with_keys(FOLDER_PATH, options.clone());
+        assert!(!naive_kv.describe().unwrap().is_empty());
+        catalog_viewer.remove("key0".to_owned()).unwrap();
+        catalog_viewer
+            .set("unflushed".to_owned(), "value".to_owned())
+            .unwrap();
+        naive_kv
+            .catalog_viewer_for("meta")
+            .unwrap()
+            .set("version".to_owned(), "1".to_owned())
+            .unwrap();
+        naive_kv.checkpoint(TARGET_PATH).unwrap();
+        catalog_viewer
+            .set("later".to_owned(), "value".to_owned())
+            .unwrap();
+
+        // The copy holds the flushed and the unflushed writes before the checkpoint only.
+        let checkpoint = NaiveKV::open(TARGET_PATH, options).unwrap();
+        let mut checkpoint_viewer = checkpoint.catalog_viewer().unwrap();
+        assert_eq!(checkpoint_viewer.get("key0").unwrap(), None);
+        assert_eq!(
+            checkpoint_viewer.get("key19").unwrap(),
+            Some("value19".repeat(4))
+        );
+        assert_eq!(
+            checkpoint_viewer.get("unflushed").unwrap(),
+            Some("value".to_owned())
+        );
+        assert_eq!(checkpoint_viewer.get("later").unwrap(), None);
+        assert_eq!(
+            checkpoint
+                .catalog_viewer_for("meta")
+                .unwrap()
+                .get("version")
+                .unwrap(),
+            Some("1".to_owned())
+        );
+        assert!(NaiveKV::verify_sstables(TARGET_PATH, &Options::default())
+            .unwrap()
+            .is_empty());
+
+        // A data folder is never overwritten.
+        assert!(matches!(
+            naive_kv.checkpoint(TARGET_PATH),
+            Err(NaiveError::InvalidOptions(_))
+        ));
+    }
+
+    #[test]
+    fn test_backup() {
+        const FOLDER_PATH: &str = "/tmp/naive_kv/test_backup/";
+        const BACKUP_PATH: &str = "/tmp/naive_kv/test_backup_backups/";
+        const RESTORE_PATH: &str = "/tmp/naive_kv/test_backup_restored/";
+
+        let _ = std::fs::remove_dir_all(BACKUP_PATH);
+        let _ = std::fs::remove_dir_all(RESTORE_PATH);
+        let options = fixture_options();
+        let (naive_kv, mut catalog_viewer) = open_with_keys(FOLDER_PATH, options.clone());
+        naive_kv
+            .catalog_viewer_for("meta")
+            .unwrap()
+            .set("version".to_owned(), "1".to_owned())
+            .unwrap();
+        let first_backup = naive_kv.backup(BACKUP_PATH).unwrap();
+        assert_eq!(first_backup.backup_id, 1);
+        assert_eq!(first_backup.parent_id, None);
+        assert!(first_backup.num_new_sstables > 0);
+
+        // Without a flush in between, the next backup shares all the SSTables.
+        catalog_viewer.remove("key0".to_owned()).unwrap();
+        let second_backup = naive_kv.backup(BACKUP_PATH).unwrap();
+        assert_eq!(second_backup.backup_id, 2);
+        assert_eq!(second_backup.parent_id, Some(1));
+        assert_eq!(second_backup.num_new_sstables, 0);
+        assert_eq!(
+            second_backup.manifest.sstable_names,
+            first_backup.manifest.sstable_names
+        );
+        assert_eq!(
+            NaiveKV::list_backups(BACKUP_PATH, &options).unwrap(),
+            vec![first_backup, second_backup]
+        );
+
+        // Each backup restores as of when it was taken.
+        for (backup_id, value) in [(1, Some("value0".repeat(4))), (2, None)] {
+            let _ = std::fs::remove_dir_all(RESTORE_PATH);
+            NaiveKV::restore_backup(BACKUP_PATH, backup_id, RESTORE_PATH, &options).unwrap();
+            let restored = NaiveKV::open(RESTORE_PATH, options.clone()).unwrap();
+            let mut restored_viewer = restored.catalog_viewer().unwrap();
+            assert_eq!(restored_viewer.get("key0").unwrap(), value);
+            assert_eq!(
+                restored_viewer.get("key19").unwrap(),
+                Some("value19".repeat(4))
+            );
+            assert_eq!(
+                restored
+                    .catalog_viewer_for("meta")
+                    .unwrap()
+                    .get("version")
+                    .unwrap(),
+                Some("1".to_owned())
+            );
+        }
+        assert!(matches!(
+            NaiveKV::restore_backup(BACKUP_PATH, 3, RESTORE_PATH, &options),
+            Err(NaiveError::InvalidOptions(_))
+        ));
+        drop(catalog_viewer);
+        drop(naive_kv);
+
+        // The backups of a data folder started over share the backup folder with the earlier ones,
+        // whose copies in the shared folder are never overwritten.
+        const FILE_PATH: &str = "/tmp/naive_kv/test_backup.sst";
+        let mut backup_ids = Vec::new();
+        for value in ["first", "second"] {
+            let _ = std::fs::remove_dir_all(FOLDER_PATH);
+            let _ = std::fs::remove_file(FILE_PATH);
+            let mut builder = SSTableBuilder::new(FILE_PATH, &options).unwrap();
+            builder.add("bulk".to_owned(), value.to_owned()).unwrap();
+            builder.finish().unwrap();
+            let naive_kv = NaiveKV::open(FOLDER_PATH, options.clone()).unwrap();
+            naive_kv.ingest(FILE_PATH).unwrap();
+            let backup = naive_kv.backup(BACKUP_PATH).unwrap();
+            assert_eq!(backup.num_new_sstables, 1);
+            backup_ids.push(backup.backup_id);
+        }
+        for (backup_id, value) in backup_ids.into_iter().zip(["first", "second"]) {
+            let _ = std::fs::remove_dir_all(RESTORE_PATH);
+            NaiveKV::restore_backup(BACKUP_PATH, backup_id, RESTORE_PATH, &options).unwrap();
+            let restored = NaiveKV::open(RESTORE_PATH, options.clone()).unwrap();
+            assert_eq!(
+                restored.catalog_viewer().unwrap().get("bulk").unwrap(),
+                Some(value.to_owned())
+            );
+        }
+    }
+
+    #[test]
+    fn test_restore() {
+        const FOLDER_PATH: &str = "/tmp/naive_kv/test_restore/";
+        const CHECKPOINT_PATH: &str = "/tmp/naive_kv/test_restore_checkpoint/";
+        const BACKUP_PATH: &str = "/tmp/naive_kv/test_restore_backups/";
+        const RESTORE_PATH: &str = "/tmp/naive_kv/test_restore_restored/";
+
+        for folder_path in [CHECKPOINT_PATH, BACKUP_PATH, RESTORE_PATH] {
+            let _ = std::fs::remove_dir_all(folder_path);
+        }
+        let options = fixture_options();
+        let (naive_kv, _) = open_with_keys(FOLDER_PATH, options.clone());
+        naive_kv
+            .catalog_viewer_for("meta")
+            .unwrap()
+            .set("version".to_owned(), "1".to_owned())
+            .unwrap();
+        naive_kv.checkpoint(CHECKPOINT_PATH).unwrap();
+        let backup = naive_kv.backup(BACKUP_PATH).unwrap();
+        assert!(!backup.manifest.sstable_names.is_empty());
+
+        // Both a checkpoint and the latest backup of a backup folder restore and open.
+        for source_path in [CHECKPOINT_PATH, BACKUP_PATH] {
+            let _ = std::fs::remove_dir_all(RESTORE_PATH);
+            let restored = NaiveKV::restore(source_path, RESTORE_PATH, options.clone()).unwrap();
+            assert_eq!(
+                restored.catalog_viewer().unwrap().get("key7").unwrap(),
+                Some("value7".repeat(4))
+            );
+            assert_eq!(
+                restored
+                    .catalog_viewer_for("meta")
+                    .unwrap()
+                    .get("version")
+                    .unwrap(),
+                Some("1".to_owned())
+            );
+        }
+
+        // A damaged SSTable is caught before the target is opened.
+        let shared_path = Path::new(BACKUP_PATH)
+            .join("shared")
+            .join(backup::gen_shared_file_name(
+                &backup.manifest.sstable_names[0],
+                backup.manifest.sstable_checksums[0],
+            ));
+        let shared_bytes = std::fs::read(&shared_path).unwrap();
+        let mut bytes = shared_bytes.clone();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0xff;
+        std::fs::write(&shared_path, bytes).unwrap();
+        let _ = std::fs::remove_dir_all(RESTORE_PATH);
+        assert!(matches!(
+            NaiveKV::restore(BACKUP_PATH, RESTORE_PATH, options.clone()),
+            Err(NaiveError::CorruptBackup(bad_paths)) if bad_paths.len() == 1
+        ));
+        // The files copied are removed, so that the restore can be retried.
+        assert!(!Path::new(RESTORE_PATH).exists());
+
+        // So is the broken backup of a column family, and the default one restored meanwhile is
+        // removed as well.
+        std::fs::write(&shared_path, &shared_bytes).unwrap();
+        let column_family_backup_path = Path::new(BACKUP_PATH).join("cf_meta");
+        let column_family_backup = BackupChain::load(&LocalBackend, &column_family_backup_path)
+            .unwrap()
+            .get(backup.backup_id)
+            .unwrap()
+            .clone();
+        let log_path = column_family_backup_path
+            .join(format!("backup_{}", backup.backup_id))
+            .join(column_family_backup.manifest.active_log_name.unwrap());
+        std::fs::remove_file(&log_path).unwrap();
+        match NaiveKV::restore(BACKUP_PATH, RESTORE_PATH, options.clone()) {
+            Err(NaiveError::CorruptBackup(bad_paths)) => assert_eq!(bad_paths, vec![log_path]),
+            result => panic!("Unexpected result of restore: {:?}", result.err()),
+        }
+        assert!(!Path::new(RESTORE_PATH).exists());
+
+        // So is a missing one, before anything is copied.
+        std::fs::remove_file(&shared_path).unwrap();
+        let _ = std::fs::remove_dir_all(RESTORE_PATH);
+        match NaiveKV::restore(BACKUP_PATH, RESTORE_PATH, options.clone()) {
+            Err(NaiveError::CorruptBackup(bad_paths)) => assert_eq!(bad_paths, vec![shared_path]),
+            result => panic!("Unexpected result of restore: {:?}", result.err()),
+        }
+        assert!(!Path::new(RESTORE_PATH).exists());
+        assert!(matches!(
+            NaiveKV::restore(RESTORE_PATH, FOLDER_PATH, options),
+            Err(NaiveError::InvalidOptions(_))
+        ));
+    }
+
+    #[test]
+    fn test_watch() {
+        const FOLDER_PATH: &str = "/tmp/naive_kv/test_watch/";
+
+        let _ = std::fs::remove_dir_all(FOLDER_PATH);
+        let options = Options {
+            memtable_compaction_threshold: 256,
+            ..Options::default()
+        };
+        let naive_kv = NaiveKV::open(FOLDER_PATH, options).unwrap();
+        pause_compactions(&naive_kv);
+        let mut catalog_viewer = naive_kv.catalog_viewer().unwrap();
+        catalog_viewer
+            .set("user0".to_owned(), "before".to_owned())
+            .unwrap();
+        let receiver = naive_kv.watch("user").unwrap();
+        catalog_viewer
+            .set("user1".to_owned(), "alice".to_owned())
+            .unwrap();
+        catalog_viewer
+            .set("order1".to_owned(), "book".to_owned())
+            .unwrap();
+        catalog_viewer.remove("user1".to_owned()).unwrap();
+        let events = receiver.try_iter().collect::<Vec<_>>();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].key, "user1");
+        assert_eq!(events[0].record, Record::Value("alice".to_owned()));
+        assert_eq!(events[1].record, Record::Deleted);
+        assert!(events[0].sequence_no < events[1].sequence_no);
+
+        // The watch carries over to the Memtables after a flush.
+        for i in 0..20 {
+            catalog_viewer
+                .set(format!("order{}", i), "x".repeat(32))
+                .unwrap();
+        }
+        compact(&naive_kv);
+        assert!(naive_kv.stats().unwrap().compaction.num_compactions > 0);
+        catalog_viewer
+            .set("user2".to_owned(), "bob".to_owned())
+            .unwrap();
+        let events = receiver.try_iter().collect::<Vec<_>>();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].key, "user2");
+
+        // An expiring value sends one more event once swept after it expires.
+        catalog_viewer
+            .set_with_ttl(
+                "user3".to_owned(),
+                "carol".to_owned(),
+                Duration::from_secs(60),
+            )
+            .unwrap();
+        let events = receiver.try_iter().collect::<Vec<_>>();
+        let expires_at_ms = match events[0].record {
+            Record::ExpiringValue { expires_at_ms, .. } => expires_at_ms,
+            _ => unreachable!(),
+        };
+        let watchers = naive_kv.catalog.read().unwrap().watchers().clone();
+        assert_eq!(watchers.expire(expires_at_ms - 1).unwrap(), 0);
+        assert_eq!(watchers.expire(expires_at_ms).unwrap(), 1);
+        let events = receiver.try_iter().collect::<Vec<_>>();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].key, "user3");
+        assert_eq!(events[0].record, Record::Deleted);
+        assert!(events[0].is_expiry);
+    }
+
+    #[test]
+    fn test_verify() {
+        const FOLDER_PATH: &str = "/tmp/naive_kv/test_verify/";
+        const NUM_KEYS: usize = 200;
+
+        let _ = std::fs::remove_dir_all(FOLDER_PATH);
+        let options = Options {
+            memtable_compaction_threshold: 512,
+            merge_operator: Some(Arc::new(StringAppendOperator {
+                delimiter: ",".to_owned(),
+            })),
+            ..Options::default()
+        };
+        let naive_kv = NaiveKV::open(FOLDER_PATH, options).unwrap();
+        pause_compactions(&naive_kv);
+        let mut catalog_viewer = naive_kv.catalog_viewer().unwrap();
+        for num in 0..NUM_KEYS {
+            catalog_viewer
+                .set(format!("key{:03}", num), num.to_string())
+                .unwrap();
+        }
+        compact(&naive_kv);
+
+        // Shadow the flushed records with deletions, overwrites and merges across the layers.
+        for num in (0..NUM_KEYS).step_by(4) {
+            catalog_viewer.remove(format!("key{:03}", num)).unwrap();
+            catalog_viewer
+                .set(format!("key{:03}", num + 1), "overwritten".to_owned())
+                .unwrap();
+            catalog_viewer
+                .merge(format!("key{:03}", num + 2), "merged".to_owned())
+                .unwrap();
+        }
+        let report = naive_kv.verify().unwrap();
+        assert!(report.is_consistent(), "{:?}", report);
+        assert_eq!(report.num_keys, NUM_KEYS * 3 / 4);
+        assert_eq!(report.num_tombstones, NUM_KEYS / 4);
+        assert!(report.problems.is_empty());
+    }
+
+    #[test]
+    fn test_contains_key() {
+        const FOLDER_PATH: &str = "/tmp/naive_kv/test_contains_key/";
+
+        let _ = std::fs::remove_dir_all(FOLDER_PATH);
+        let options = Options {
+            memtable_compaction_threshold: 64,
+            merge_operator: Some(Arc::new(StringAppendOperator {
+                delimiter: ",".to_owned(),
+            })),
+            ..Options::default()
+        };
+        let naive_kv = NaiveKV::open(FOLDER_PATH, options).unwrap();
+        pause_compactions(&naive_kv);
+        let mut catalog_viewer = naive_kv.catalog_viewer().unwrap();
+        let short_ttl = Duration::from_millis(300);
+        catalog_viewer
+            .set("large".to_owned(), "x".repeat(1 << 16))
+            .unwrap();
+        catalog_viewer
+            .set("small".to_owned(), "1".to_owned())
+            .unwrap();
+        catalog_viewer
+            .set("removed".to_owned(), "2".to_owned())
+            .unwrap();
+        catalog_viewer.remove("removed".to_owned()).unwrap();
+        catalog_viewer
+            .set_with_ttl("expiring".to_owned(), "3".to_owned(), short_ttl)
+            .unwrap();
+        catalog_viewer
+            .merge("merged".to_owned(), "a".to_owned())
+            .unwrap();
+        let check = |catalog_viewer: &mut CatalogViewer, expected: &[(&str, bool)]| {
+            for &(key, is_present) in expected {
+                assert_eq!(catalog_viewer.contains_key(key).unwrap(), is_present);
+                assert_eq!(catalog_viewer.get(key).unwrap().is_some(), is_present);
+            }
+        };
+        check(
+            &mut catalog_viewer,
+            &[
+                ("large", true),
+                ("small", true),
+                ("removed", false),
+                ("expiring", true),
+                ("merged", true),
+                ("missing", false),
+            ],
+        );
+
+        // The same holds once the records are flushed to an SSTable and have expired.
+        std::thread::sleep(short_ttl);
+        compact(&naive_kv);
+        assert!(!naive_kv.describe().unwrap().is_empty());
+        check(
+            &mut catalog_viewer,
+            &[
+                ("large", true),
+                ("small", true),
+                ("removed", false),
+                ("expiring", false),
+                ("merged", true),
+                ("missing", false),
+            ],
+        );
+
+        // The newer records in the Memtable win, and the merge operands on top of a deletion
+        // leave a value.
+        catalog_viewer.remove("large".to_owned()).unwrap();
+        catalog_viewer
+            .merge("removed".to_owned(), "b".to_owned())
+            .unwrap();
+        check(&mut catalog_viewer, &[("large", false), ("removed", true)]);
+    }
+
+    #[test]
+    fn test_ingest() {
+        const FOLDER_PATH: &str = "/tmp/naive_kv/test_ingest/";
+        const FILE_PATH: &str = "/tmp/naive_kv/test_ingest.sst";
+
+        let _ = std::fs::remove_dir_all(FOLDER_PATH);
+        let naive_kv = NaiveKV::open(FOLDER_PATH, Options::default()).unwrap();
+        let mut catalog_viewer = naive_kv.catalog_viewer().unwrap();
+        catalog_viewer
+            .set("user1".to_owned(), "alice".to_owned())
+            .unwrap();
+
+        let build = |keys: &mut dyn Iterator<Item = String>| {
+            let _ = std::fs::remove_file(FILE_PATH);
+            let mut builder = SSTableBuilder::new(FILE_PATH, &Options::default()).unwrap();
+            for key in keys {
+                builder
+                    .add(key.clone(), format!("value of {}", key))
+                    .unwrap();
+            }
+            builder.finish().unwrap()
+        };
+        // Make sure this spans over multiple chunks.
+        assert_eq!(build(&mut (0..1000).map(|i| format!("bulk{:04}", i))), 1000);
+        assert_eq!(naive_kv.ingest(FILE_PATH).unwrap(), 1000);
+        assert_eq!(naive_kv.describe().unwrap().len(), 1);
+        assert_eq!(
+            catalog_viewer.get("bulk0500").unwrap(),
+            Some("value of bulk0500".to_owned())
+        );
+        assert_eq!(
+            catalog_viewer.get("user1").unwrap(),
+            Some("alice".to_owned())
+        );
+
+        // The writes through the engine supersede the ingested records.
+        catalog_viewer
+            .set("bulk0001".to_owned(), "new".to_owned())
+            .unwrap();
+        assert_eq!(
+            catalog_viewer.get("bulk0001").unwrap(),
+            Some("new".to_owned())
+        );
+
+        // The keys may overlap with neither the SSTables nor the Memtable.
+        for keys in [&["bulk0999", "bulk1000"], &["user0", "user2"]] {
+            build(&mut keys.iter().map(|key| key.to_string()));
+            assert!(matches!(
+                naive_kv.ingest(FILE_PATH),
+                Err(NaiveError::InvalidOptions(_))
+            ));
+        }
+        assert_eq!(naive_kv.describe().unwrap().len(), 1);
+        assert_eq!(catalog_viewer.get("bulk1000").unwrap(), None);
+
+        // The keys must be added in order.
+        let _ = std::fs::remove_file(FILE_PATH);
+        let mut builder = SSTableBuilder::new(FILE_PATH, &Options::default()).unwrap();
+        builder.add("b".to_owned(), "1".to_owned()).unwrap();
+        assert!(matches!(
+            builder.add("a".to_owned(), "2".to_owned()),
+            Err(NaiveError::ComparatorMismatch(_))
+        ));
+
+        // The ingested SSTable is recorded in the manifest.
+        drop(catalog_viewer);
+        naive_kv.close().unwrap();
+        let naive_kv = NaiveKV::open(FOLDER_PATH, Options::default()).unwrap();
+        let mut catalog_viewer = naive_kv.catalog_viewer().unwrap();
+        assert_eq!(
+            catalog_viewer.get("bulk0999").unwrap(),
+            Some("value of bulk0999".to_owned())
+        );
+        assert_eq!(
+            catalog_viewer.get("bulk0001").unwrap(),
+            Some("new".to_owned())
+        );
+    }
+
+    #[test]
+    fn test_folder_lock() {
+        const FOLDER_PATH: &str = "/tmp/naive_kv/test_folder_lock/";
+
+        let _ = std::fs::remove_dir_all(FOLDER_PATH);
+        let naive_kv = NaiveKV::open(FOLDER_PATH, Options::default()).unwrap();
+        let mut catalog_viewer = naive_kv.catalog_viewer().unwrap();
+        catalog_viewer
+            .set("key".to_owned(), "value".to_owned())
+            .unwrap();
+        assert!(matches!(
+            NaiveKV::open(FOLDER_PATH, Options::default()),
+            Err(NaiveError::FolderInUse(_))
+        ));
+        // The replicas follow the primary meanwhile.
+        let replica = NaiveKV::open_replica(FOLDER_PATH, Options::default()).unwrap();
+        drop(replica);
+
+        // The lock is held until the last catalog viewer drops.
+        drop(naive_kv);
+        assert!(matches!(
+            NaiveKV::open(FOLDER_PATH, Options::default()),
+            Err(NaiveError::FolderInUse(_))
+        ));
+        drop(catalog_viewer);
+        let naive_kv = NaiveKV::open(FOLDER_PATH, Options::default()).unwrap();
+        assert_eq!(
+            naive_kv.catalog_viewer().unwrap().get("key").unwrap(),
+            Some("value".to_owned())
+        );
+    }
+
+    #[test]
+    fn test_open_modes() {
+        const FOLDER_PATH: &str = "/tmp/naive_kv/test_open_modes/";
+
+        let _ = std::fs::remove_dir_all(FOLDER_PATH);
+        let attach_options = Options {
+            create_if_missing: false,
+            ..Options::default()
+        };
+        let bootstrap_options = Options {
+            error_if_exists: true,
+            ..Options::default()
+        };
+        assert!(matches!(
+            NaiveKV::open(FOLDER_PATH, attach_options.clone()),
+            Err(NaiveError::FolderNotFound(_))
+        ));
+        assert!(!Path::new(FOLDER_PATH).exists());
+        // An empty folder holds no data folder either, and is left empty.
+        std::fs::create_dir_all(FOLDER_PATH).unwrap();
+        assert!(matches!(
+            NaiveKV::open(FOLDER_PATH, attach_options.clone()),
+            Err(NaiveError::FolderNotFound(_))
+        ));
+        assert_eq!(std::fs::read_dir(FOLDER_PATH).unwrap().count(), 0);
+
+        let naive_kv = NaiveKV::open(FOLDER_PATH, bootstrap_options.clone()).unwrap();
+        naive_kv.create_column_family("meta").unwrap();
+        naive_kv
+            .catalog_viewer()
+            .unwrap()
+            .set("key".to_owned(), "value".to_owned())
+            .unwrap();
+        drop(naive_kv);
+
+        assert!(matches!(
+            NaiveKV::open(FOLDER_PATH, bootstrap_options),
+            Err(NaiveError::FolderExists(_))
+        ));
+        let naive_kv = NaiveKV::open(FOLDER_PATH, attach_options).unwrap();
+        assert_eq!(naive_kv.column_family_names().unwrap(), vec!["meta"]);
+        assert_eq!(
+            naive_kv.catalog_viewer().unwrap().get("key").unwrap(),
+            Some("value".to_owned())
+        );
+    }
+
+    #[test]
+    fn test_property() {
+        const FOLDER_PATH: &str = "/tmp/naive_kv/test_property/";
+
+        // The compactions are paused, so that the pending one stays pending until compacted.
+        let naive_kv = open_paused(FOLDER_PATH, fixture_options());
+        let property = |name: &str| naive_kv.property(name).unwrap().unwrap();
+        assert_eq!(property(stats::PROPERTY_MEMTABLE_BYTES), "0");
+        assert_eq!(property(stats::PROPERTY_NUM_GENERATIONS), "0");
+        assert_eq!(property(stats::PROPERTY_GENERATION_BYTES), "");
+        assert_eq!(naive_kv.property("naivekv.unknown").unwrap(), None);
+
+        set_keys(&mut naive_kv.catalog_viewer().unwrap());
+        assert!(
+            property(stats::PROPERTY_MEMTABLE_BYTES)
+                .parse::<usize>()
+                .unwrap()
+                > 0
+        );
+        assert!(property(stats::PROPERTY_WAL_BYTES).parse::<u64>().unwrap() > 0);
+        assert_eq!(property(stats::PROPERTY_NUM_PENDING_COMPACTIONS), "1");
+        assert!(
+            property(stats::PROPERTY_COMPACTION_BACKLOG_BYTES)
+                .parse::<usize>()
+                .unwrap()
+                > 0
+        );
+
+        compact(&naive_kv);
+        assert_eq!(property(stats::PROPERTY_NUM_PENDING_COMPACTIONS), "0");
+        assert_eq!(property(stats::PROPERTY_NUM_GENERATIONS), "1");
+        let generation_bytes = property(stats::PROPERTY_GENERATION_BYTES)
+            .parse::<u64>()
+            .unwrap();
+        assert_eq!(
+            generation_bytes,
+            naive_kv.describe().unwrap()[0].file_size as u64
+        );
+        assert!(
+            property(stats::PROPERTY_TOTAL_DISK_BYTES)
+                .parse::<u64>()
+                .unwrap()
+                > generation_bytes
+        );
+        for name in stats::PROPERTY_NAMES {
+            assert!(naive_kv.property(name).unwrap().is_some());
+        }
+    }
+
+    #[test]
+    fn test_snapshot_at_epoch() {
+        const FOLDER_PATH: &str = "/tmp/naive_kv/test_snapshot_at_epoch/";
+
+        let options = Options {
+            epoch_retention_s: 3600,
+            ..fixture_options()
+        };
+        let (naive_kv, mut catalog_viewer) = open_with_keys(FOLDER_PATH, options.clone());
+        let old_epoch_no = naive_kv.epoch_no();
+        assert!(old_epoch_no > 0);
+        for i in 0..20 {
+            catalog_viewer
+                .set(format!("key{}", i), format!("new_value{}", i).repeat(4))
+                .unwrap();
+        }
+        compact(&naive_kv);
+        let new_epoch_no = naive_kv.epoch_no();
+        assert!(new_epoch_no > old_epoch_no);
+        catalog_viewer
+            .set("unflushed".to_owned(), "value".to_owned())
+            .unwrap();
+
+        let get_at_epoch = |epoch_no, key| {
+            naive_kv
+                .snapshot_at_epoch(epoch_no)
+                .unwrap()
+                .get(key)
+                .unwrap()
+        };
+        assert_eq!(get_at_epoch(0, "key0"), None);
+        assert_eq!(get_at_epoch(old_epoch_no, "key0"), Some("value0".repeat(4)));
+        let snapshot = naive_kv.snapshot_at_epoch(new_epoch_no).unwrap();
+        assert_eq!(snapshot.get("key0").unwrap(), Some("new_value0".repeat(4)));
+        // The Memtables are skipped.
+        assert_eq!(snapshot.get("unflushed").unwrap(), None);
+        assert!(matches!(
+            naive_kv.snapshot_at_epoch(new_epoch_no + 1),
+            Err(NaiveError::EpochNotRetained { .. })
+        ));
+        // The retired SSTables stay pinned by the views meanwhile.
+        drop(snapshot);
+        drop(catalog_viewer);
+        drop(naive_kv);
+
+        // The epochs carry on once reopened, but the ones before are gone. Without the
+        // retention, so are the epochs before the last compaction.
+        let options = Options {
+            epoch_retention_s: 0,
+            ..options
+        };
+        let naive_kv = NaiveKV::open(FOLDER_PATH, options).unwrap();
+        pause_compactions(&naive_kv);
+        let reopened_epoch_no = naive_kv.epoch_no();
+        assert!(reopened_epoch_no >= new_epoch_no);
+        let snapshot = naive_kv.snapshot_at_epoch(reopened_epoch_no).unwrap();
+        assert_eq!(snapshot.get("key0").unwrap(), Some("new_value0".repeat(4)));
+        assert!(matches!(
+            naive_kv.snapshot_at_epoch(old_epoch_no),
+            Err(NaiveError::EpochNotRetained { .. })
+        ));
+        let mut catalog_viewer = naive_kv.catalog_viewer().unwrap();
+        for i in 0..20 {
+            catalog_viewer
+                .set(format!("key{}", i), format!("value{}", i).repeat(4))
+                .unwrap();
+        }
+        compact(&naive_kv);
+        assert!(naive_kv.epoch_no() > reopened_epoch_no);
+        assert!(matches!(
+            naive_kv.snapshot_at_epoch(reopened_epoch_no),
+            Err(NaiveError::EpochNotRetained { .. })
+        ));
+        let snapshot = naive_kv.snapshot_at_epoch(naive_kv.epoch_no()).unwrap();
+        assert_eq!(snapshot.get("key0").unwrap(), Some("value0".repeat(4)));
+    }
+
+    #[test]
+    fn test_destroy() {
+        const FOLDER_PATH: &str = "/tmp/naive_kv/test_destroy/";
+
+        let options = Options {
+            trash_retention_s: 3600,
+            ..fixture_options()
+        };
+        let (naive_kv, catalog_viewer) = open_with_keys(FOLDER_PATH, options.clone());
+        naive_kv
+            .catalog_viewer_for("meta")
+            .unwrap()
+            .set("version".to_owned(), "1".to_owned())
+            .unwrap();
+        assert!(!naive_kv.describe().unwrap().is_empty());
+        let notes_path = Path::new(FOLDER_PATH).join("notes.txt");
+        std::fs::write(&notes_path, "not the engine's").unwrap();
+
+        // Nothing is removed while any instance has the data folder open.
+        let replica = NaiveKV::open_replica(FOLDER_PATH, options.clone()).unwrap();
+        drop(catalog_viewer);
+        drop(naive_kv);
+        assert!(matches!(
+            NaiveKV::destroy(FOLDER_PATH, &options),
+            Err(NaiveError::FolderInUse(_))
+        ));
+        drop(replica);
+
+        // Only the files of the engine are removed, along with the folders left empty.
+        NaiveKV::destroy(FOLDER_PATH, &options).unwrap();
+        let file_names = std::fs::read_dir(FOLDER_PATH)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(file_names, vec!["notes.txt".to_owned()]);
+        let naive_kv = NaiveKV::open(FOLDER_PATH, options.clone()).unwrap();
+        assert_eq!(
+            naive_kv.catalog_viewer().unwrap().get("key1").unwrap(),
+            None
+        );
+        assert!(naive_kv.column_family_names().unwrap().is_empty());
+        drop(naive_kv);
+
+        std::fs::remove_file(&notes_path).unwrap();
+        NaiveKV::destroy(FOLDER_PATH, &options).unwrap();
+        assert!(!Path::new(FOLDER_PATH).exists());
+        NaiveKV::destroy(FOLDER_PATH, &options).unwrap();
+    }
 }
@@ -0,0 +1,104 @@
+use std::fmt::Debug;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use crate::options::Options;
+
+/// Receives notifications about the storage engine, e.g. for logging or alerting.
+///
+/// All the methods do nothing by default, so that a listener only overrides the events it needs.
+pub trait EventListener: Debug + Send + Sync {
+    /// Called for each change made by `Options::repair_on_open` to the data folder, and for each
+    /// record dropped by `Options::log_recovery_mode`.
+    fn on_repair(&self, _event: &RepairEvent) {}
+
+    /// Called when a soft limit is crossed, as an early warning before the writes stall.
+    fn on_soft_limit(&self, _event: &SoftLimitEvent) {}
+
+    /// Called when an SSTable is quarantined after too many read errors in a row.
+    fn on_quarantine(&self, _event: &QuarantineEvent) {}
+
+    /// Called when a flush or a merge by the compaction daemon fails, before it backs off from
+    /// retrying.
+    fn on_compaction_failure(&self, _event: &CompactionFailureEvent) {}
+}
+
+/// A change made to the data folder to make it consistent again.
+#[derive(Clone, Debug, PartialEq)]
+pub enum RepairEvent {
+    /// The generation number of an SSTable was rewritten to close a gap or resolve a duplicate.
+    RenumberedSSTable {
+        file_path: PathBuf,
+        old_gen_no: usize,
+        new_gen_no: usize,
+    },
+
+    /// Stray Memtable logs were replayed, from the oldest to the newest, into a new one.
+    MergedMemtableLogs {
+        log_paths: Vec<PathBuf>,
+        merged_log_path: PathBuf,
+    },
+
+    /// A Memtable log was cut off at the offset, dropping the bytes after it.
+    TruncatedMemtableLog {
+        log_path: PathBuf,
+        offset: u64,
+        num_bytes_dropped: u64,
+    },
+
+    /// A corrupted record of a Memtable log, located by the offset of its message, was skipped.
+    SkippedLogRecord { log_path: PathBuf, offset: u64 },
+}
+
+pub(crate) fn notify_repair(options: &Options, event: RepairEvent) {
+    for event_listener in options.event_listeners.iter() {
+        event_listener.on_repair(&event);
+    }
+}
+
+/// A soft limit crossed by the storage engine, reported once each time it is crossed.
+#[derive(Clone, Debug, PartialEq)]
+pub enum SoftLimitEvent {
+    /// The Memtable reached `Options::memtable_soft_limit_ratio` of the compaction threshold.
+    MemtableNearlyFull { data_size: usize, threshold: usize },
+
+    /// The Memtable reached the compaction threshold while the previous one is still being
+    /// flushed, so the unflushed Memtables are queuing up.
+    FlushBehind { data_size: usize, threshold: usize },
+
+    /// The pending compactions would write more than `Options::compaction_backlog_soft_limit`.
+    CompactionBacklog { num_bytes: usize, soft_limit: usize },
+}
+
+pub(crate) fn notify_soft_limit(
+    event_listeners: &[Arc<dyn EventListener>],
+    event: &SoftLimitEvent,
+) {
+    for event_listener in event_listeners.iter() {
+        event_listener.on_soft_limit(event);
+    }
+}
+
+/// An SSTable quarantined after too many read errors in a row, whose file needs a closer look.
+#[derive(Clone, Debug, PartialEq)]
+pub struct QuarantineEvent {
+    pub file_path: PathBuf,
+    pub gen_no: usize,
+    pub num_errors: usize,
+
+    /// The last read error, as debug-formatted.
+    pub last_error: String,
+}
+
+/// A flush or a merge failed in the background, whose data stays where it was until a retry.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CompactionFailureEvent {
+    /// The background job that ran it, i.e. `FLUSH_JOB` or `MERGE_JOB`.
+    pub job_name: String,
+
+    /// The data folder of the column family compacted.
+    pub folder_path: PathBuf,
+
+    /// The error, as debug-formatted.
+    pub error: String,
+}
@@ -0,0 +1,203 @@
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+
+use crate::backend::Backend;
+use crate::protos::messages;
+use crate::types::Result;
+use crate::utils;
+
+/// The name of the manifest file in a data folder.
+const MANIFEST_FILE_NAME: &str = "MANIFEST";
+
+/// The durable state of a data folder beyond what its data files tell, rewritten as a whole and
+/// atomically replaced on every change.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Manifest {
+    /// The largest sequence number that has been flushed into the SSTables.
+    pub last_flushed_sequence_no: u64,
+
+    /// The file names of the live SSTables in increasing generations, so that readers of the
+    /// folder can tell them from the ones being written or removed. The ones in the cold folder
+    /// are recorded by their absolute paths instead.
+    pub sstable_names: Vec<String>,
+
+    /// The file name of the log taking the writes, or None if the folder predates this field.
+    pub active_log_name: Option<String>,
+
+    /// The file name of the log whose Memtable is being flushed, if a flush is in progress, so
+    /// that recovery can replay it before the active one.
+    pub flushing_log_name: Option<String>,
+
+    /// The CRC32 checksums of the whole segment files of the live SSTables, in the order of
+    /// sstable_names, or empty if the folder predates this field.
+    pub sstable_checksums: Vec<u32>,
+
+    /// The name of the comparator ordering the data folder, or None if the folder predates this
+    /// field.
+    pub comparator_name: Option<String>,
+
+    /// The names of the column families created in the data folder besides the default one,
+    /// which is the folder itself.
+    pub column_family_names: Vec<String>,
+
+    /// The file names of the blob files the live SSTables refer to, so that the copies of the
+    /// folder take them along.
+    pub blob_names: Vec<String>,
+
+    /// The epoch of the SSTables installed last, which the epochs carry on from once the data
+    /// folder is opened again.
+    pub last_epoch_no: u64,
+}
+
+impl Manifest {
+    /// Load the manifest of the data folder, or None if there is none yet.
+    pub fn load(backend: &dyn Backend, folder_path: &Path) -> Result<Option<Self>> {
+        let manifest_path = Self::gen_manifest_path(folder_path);
+        if !backend.exists(&manifest_path) {
+            return Ok(None);
+        }
+        let mut file_reader = BufReader::new(backend.open(&manifest_path)?);
+        // An all-default manifest is serialized as an empty message.
+        let manifest =
+            utils::read_message::<messages::Manifest, _>(&mut file_reader)?.unwrap_or_default();
+        Ok(Some(Self::from_message(&manifest)))
+    }
+
+    pub(crate) fn from_message(manifest: &messages::Manifest) -> Self {
+        Self {
+            last_flushed_sequence_no: manifest.get_last_flushed_sequence_no(),
+            sstable_names: manifest.get_sstable_names().to_vec(),
+            active_log_name: manifest
+                .has_active_log_name()
+                .then(|| manifest.get_active_log_name().to_owned()),
+            flushing_log_name: manifest
+                .has_flushing_log_name()
+                .then(|| manifest.get_flushing_log_name().to_owned()),
+            sstable_checksums: manifest.get_sstable_checksums().to_vec(),
+            comparator_name: manifest
+                .has_comparator_name()
+                .then(|| manifest.get_comparator_name().to_owned()),
+            column_family_names: manifest.get_column_family_names().to_vec(),
+            blob_names: manifest.get_blob_names().to_vec(),
+            last_epoch_no: manifest.get_last_epoch_no(),
+        }
+    }
+
+    /// Replace the manifest as a whole, so that a crash leaves either the old or the new manifest
+    /// behind.
+    pub fn save(&self, backend: &dyn Backend, folder_path: &Path) -> Result<()> {
+        let mut bytes = Vec::new();
+        utils::write_message(&self.to_message(), &mut bytes)?;
+        backend.replace(&Self::gen_manifest_path(folder_path), &bytes)
+    }
+
+    pub(crate) fn to_message(&self) -> messages::Manifest {
+        let mut manifest = messages::Manifest::new();
+        manifest.set_last_flushed_sequence_no(self.last_flushed_sequence_no);
+        manifest.set_sstable_names(self.sstable_names.clone().into());
+        if let Some(active_log_name) = self.active_log_name.as_ref() {
+            manifest.set_active_log_name(active_log_name.clone());
+        }
+        if let Some(flushing_log_name) = self.flushing_log_name.as_ref() {
+            manifest.set_flushing_log_name(flushing_log_name.clone());
+        }
+        manifest.set_sstable_checksums(self.sstable_checksums.clone());
+        if let Some(comparator_name) = self.comparator_name.as_ref() {
+            manifest.set_comparator_name(comparator_name.clone());
+        }
+        manifest.set_column_family_names(self.column_family_names.clone().into());
+        manifest.set_blob_names(self.blob_names.clone().into());
+        manifest.set_last_epoch_no(self.last_epoch_no);
+        manifest
+    }
+
+    /// The path of each live SSTable of the data folder along with its recorded checksum, if
+    /// any.
+    pub fn sstable_paths(&self, folder_path: &Path) -> Vec<(PathBuf, Option<u32>)> {
+        self.sstable_names
+            .iter()
+            .enumerate()
+            .map(|(i, sstable_name)| {
+                (
+                    folder_path.join(sstable_name),
+                    self.sstable_checksums.get(i).copied(),
+                )
+            })
+            .collect()
+    }
+
+    /// Verify the live SSTables of the data folder, or of a copy of it such as a backup, against
+    /// their recorded checksums, returning the paths of the ones missing or changed, e.g. by a
+    /// truncated copy or bit rot. The SSTables recorded without a checksum are not verified, and
+    /// the ones in the cold folder are looked up at their absolute paths.
+    pub fn verify_sstables(
+        &self,
+        backend: &dyn Backend,
+        folder_path: &Path,
+    ) -> Result<Vec<PathBuf>> {
+        let mut bad_paths = Vec::new();
+        for (file_path, checksum) in self.sstable_paths(folder_path) {
+            let checksum = match checksum {
+                Some(checksum) => checksum,
+                None => continue,
+            };
+            if !backend.exists(&file_path) {
+                log::error!("Found no SSTable {}.", file_path.display());
+                bad_paths.push(file_path);
+                continue;
+            }
+            let actual_checksum = utils::checksum(&mut backend.open(&file_path)?)?;
+            if actual_checksum != checksum {
+                log::error!(
+                    "Expect checksum {:08x} of {}, found {:08x}.",
+                    checksum,
+                    file_path.display(),
+                    actual_checksum
+                );
+                bad_paths.push(file_path);
+            }
+        }
+        Ok(bad_paths)
+    }
+
+    pub fn gen_manifest_path(folder_path: &Path) -> PathBuf {
+        folder_path.join(MANIFEST_FILE_NAME)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Manifest;
+    use crate::backend::LocalBackend;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_manifest() {
+        const FOLDER_PATH: &str = "/tmp/naive_kv/test_manifest/";
+
+        let folder_path = PathBuf::from(FOLDER_PATH);
+        let _ = std::fs::remove_dir_all(&folder_path);
+        std::fs::create_dir_all(&folder_path).unwrap();
+        assert_eq!(Manifest::load(&LocalBackend, &folder_path).unwrap(), None);
+
+        let mut manifest = Manifest::default();
+        manifest.save(&LocalBackend, &folder_path).unwrap();
+        assert_eq!(
+            Manifest::load(&LocalBackend, &folder_path).unwrap(),
+            Some(manifest.clone())
+        );
+
+        manifest.last_flushed_sequence_no = 42;
+        manifest.sstable_names = vec!["gen_0_1.sst".to_owned(), "gen_1_2.sst".to_owned()];
+        manifest.active_log_name = Some("memtable_2.log".to_owned());
+        manifest.flushing_log_name = Some("memtable_1.log".to_owned());
+        manifest.sstable_checksums = vec![1, 2];
+        manifest.comparator_name = Some("naive_kv.BytewiseComparator".to_owned());
+        manifest.column_family_names = vec!["metadata".to_owned()];
+        manifest.save(&LocalBackend, &folder_path).unwrap();
+        assert_eq!(
+            Manifest::load(&LocalBackend, &folder_path).unwrap(),
+            Some(manifest)
+        );
+    }
+}
@@ -1,93 +1,644 @@
-use std::collections::{btree_map, BTreeMap};
-use std::fs::{File, OpenOptions};
-use std::io::{BufReader, BufWriter};
-use std::path::PathBuf;
-use std::sync::Mutex;
+use crossbeam::channel::unbounded;
+use crossbeam::epoch::{self, Atomic, Owned};
+use crossbeam_skiplist::SkipMap;
+use protobuf::Message;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::BTreeMap;
+use std::hash::{Hash, Hasher};
+use std::io::{BufReader, Read, Seek, SeekFrom, Write};
+use std::ops::RangeBounds;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Instant;
 
+use crate::backend::{Backend, BackendFile};
+use crate::comparator::{Comparator, OrderedKey};
+use crate::listener::{self, RepairEvent};
+use crate::merge::{self, MergeOperator};
+use crate::options::{LogRecoveryMode, Options};
 use crate::protos::messages::{Command, CommandType};
-use crate::types::{Record, Result};
-use crate::utils;
+use crate::sequencer::Sequencer;
+use crate::stats::ReplayStats;
+use crate::thread_pool::ThreadPool;
+use crate::trash::Trash;
+use crate::types::{self, NaiveError, Record, RecordKind, Result, TimedRecord, WriteBatch};
+use crate::utils::{self, N_BYTES_CHUNK_LENGTH};
+use crate::watch::{ChangeEvent, Watchers};
+
+/// The in-memory data ordered by the configured comparator, which the readers go through without
+/// any lock even while it is written.
+pub type MemtableData = SkipMap<OrderedKey, MemtableEntry>;
+
+/// A copy of the in-memory data, e.g. for a snapshot, which later writes leave alone.
+pub type FrozenMemtableData = BTreeMap<OrderedKey, TimedRecord>;
+
+/// The number of log chunks decoded by a single task during the replay.
+const REPLAY_BATCH_SIZE: usize = 1024;
+
+/// The key-record pairs, with their sequence numbers, decoded from a batch of log chunks.
+#[derive(Default)]
+struct DecodedBatch {
+    records: Vec<(String, TimedRecord, u64)>,
+
+    /// The corrupted records skipped, reported once the batch is applied.
+    skipped_records: Vec<RepairEvent>,
+
+    /// The offset of the first corrupted chunk, if the batch is cut off at it.
+    truncated_offset: Option<u64>,
+}
+
+/// The records of a key, the newest first, which the readers pick from by the visible sequence
+/// number, so that a write shows up only along with the writes before it.
+#[derive(Clone)]
+pub struct VersionedRecord {
+    record: Arc<TimedRecord>,
+
+    /// The older record of the key, if still readable.
+    older: Option<Arc<VersionedRecord>>,
+
+    /// Whether the older records are dropped, as no reader is left as of their sequence numbers.
+    is_truncated: bool,
+}
+
+impl VersionedRecord {
+    /// The newest record as of the sequence number, or the oldest one kept if the ones before it
+    /// are dropped, which is visible all the same.
+    fn read_at(&self, sequence_no: u64) -> Option<&TimedRecord> {
+        let mut version = self;
+        loop {
+            if version.record.sequence_no <= sequence_no {
+                return Some(&version.record);
+            }
+            match version.older.as_deref() {
+                Some(older) => version = older,
+                None => return version.is_truncated.then_some(&version.record),
+            }
+        }
+    }
+
+    /// Keep the records readable as of the sequence number or later, dropping the ones older than
+    /// the newest record as of it.
+    fn truncate(&self, sequence_no: u64) -> Self {
+        if self.record.sequence_no <= sequence_no {
+            return Self {
+                record: self.record.clone(),
+                older: None,
+                is_truncated: self.is_truncated || self.older.is_some(),
+            };
+        }
+        Self {
+            record: self.record.clone(),
+            older: self
+                .older
+                .as_deref()
+                .map(|older| Arc::new(older.truncate(sequence_no))),
+            is_truncated: self.is_truncated,
+        }
+    }
+}
+
+/// The records of a key in the data, which a write replaces in place rather than inserting the
+/// key anew, since the skiplist removes the old entry of a key before linking the new one, and a
+/// reader in between would miss the key.
+pub struct MemtableEntry {
+    versions: Atomic<VersionedRecord>,
+}
+
+impl MemtableEntry {
+    fn new(versioned_record: VersionedRecord) -> Self {
+        Self {
+            versions: Atomic::new(versioned_record),
+        }
+    }
+
+    /// Read the records of the key, which stay alive throughout even if replaced meanwhile.
+    fn read<T>(&self, read: impl FnOnce(&VersionedRecord) -> T) -> T {
+        let guard = epoch::pin();
+        let versions = self.versions.load(Ordering::Acquire, &guard);
+        // SAFETY: The records are never null, and the ones replaced are only destroyed once all
+        // the guards pinned before the replacement are dropped.
+        read(unsafe { versions.deref() })
+    }
+
+    /// Replace the records of the key, which only one writer of the key at a time may do.
+    fn replace(&self, versioned_record: VersionedRecord) {
+        let guard = epoch::pin();
+        let old_versions =
+            self.versions
+                .swap(Owned::new(versioned_record), Ordering::AcqRel, &guard);
+        // SAFETY: The old records are only reachable through the guards pinned before the swap.
+        unsafe { guard.defer_destroy(old_versions) };
+    }
+}
+
+impl Drop for MemtableEntry {
+    fn drop(&mut self) {
+        // SAFETY: The entry drops once no reader of the skiplist refers to it any longer.
+        unsafe {
+            drop(
+                self.versions
+                    .load(Ordering::Relaxed, epoch::unprotected())
+                    .into_owned(),
+            );
+        }
+    }
+}
 
 pub struct Memtable {
     /// The in-memory data.
-    data: BTreeMap<String, Record>,
+    data: MemtableData,
+
+    /// The order of keys.
+    comparator: &'static dyn Comparator,
+
+    /// What combines the merge operands with the values, if any.
+    merge_operator: Option<Arc<dyn MergeOperator>>,
+
+    /// Whether to write a checksum with each command in the log.
+    checksum_records: bool,
+
+    /// How replaying the log went when the Memtable was opened.
+    replay_stats: ReplayStats,
 
     /// The heuristic size of the in-memory data, used for triggering compaction.
-    data_size: usize,
+    data_size: AtomicUsize,
+
+    /// The smallest and the largest sequence numbers in the log.
+    sequence_range: SequenceRange,
 
     /// The path of the write-ahead log.
     log_path: PathBuf,
 
-    /// The write-ahead log writer.
-    log_writer: BufWriter<File>,
+    /// The write-ahead log, which the writers append to one at a time.
+    log: Mutex<MemtableLog>,
+
+    /// The turns of writing the keys hashed to each shard, so that the writes of the keys in
+    /// different shards are applied at once. A writer applies its write to the data before
+    /// letting the next one of its shards in, so that the data of each key follows the log order.
+    shards: Vec<Mutex<()>>,
+
+    /// The number of bytes of the log read or written so far, from which tail picks up.
+    log_offset: AtomicU64,
+
+    /// Where the writes take their sequence numbers from and the reads learn which ones are
+    /// visible, or None if the writes are stamped by the caller and visible once applied.
+    sequencer: Option<Arc<Sequencer>>,
 
     /// Whether the Memtable is deprecated.
     is_deprecated: Mutex<bool>,
+
+    /// Where the log goes once the Memtable is deprecated, if not removed right away.
+    trash: Option<Trash>,
+
+    /// Where the log is stored.
+    backend: Arc<dyn Backend>,
+
+    /// Notified of each write once applied, but not of the records replayed from the log.
+    watchers: Arc<Watchers>,
+}
+
+/// The write-ahead log of a Memtable.
+struct MemtableLog {
+    /// The handle of the log, which is appended to, or read from if the Memtable is read-only.
+    log_file: Box<dyn BackendFile>,
+
+    /// The buffer each write is encoded into, so that it is appended to the log whole. The lock
+    /// of the log is held throughout the append, which may take more than one system call.
+    buffer: Vec<u8>,
+}
+
+impl MemtableLog {
+    fn new(log_file: Box<dyn BackendFile>) -> Mutex<Self> {
+        Mutex::new(Self {
+            log_file,
+            buffer: Vec::new(),
+        })
+    }
+}
+
+/// The hash of a key, which tells the shard of a Memtable taking the writes of the key.
+pub fn key_hash(key: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// The smallest and the largest sequence numbers in a log, which the commands stamped with one
+/// extend as they are applied.
+struct SequenceRange {
+    min_sequence_no: AtomicU64,
+    max_sequence_no: AtomicU64,
+}
+
+impl SequenceRange {
+    fn new() -> Self {
+        Self {
+            min_sequence_no: AtomicU64::new(u64::MAX),
+            max_sequence_no: AtomicU64::new(0),
+        }
+    }
+
+    /// Take a sequence number into the range, unless it is zero, i.e. not stamped.
+    fn extend(&self, sequence_no: u64) {
+        if sequence_no == 0 {
+            return;
+        }
+        self.min_sequence_no
+            .fetch_min(sequence_no, Ordering::SeqCst);
+        self.max_sequence_no
+            .fetch_max(sequence_no, Ordering::SeqCst);
+    }
+
+    /// The range, or None if no command is stamped with a sequence number.
+    fn get(&self) -> Option<(u64, u64)> {
+        let max_sequence_no = self.max_sequence_no.load(Ordering::SeqCst);
+        (max_sequence_no > 0)
+            .then(|| (self.min_sequence_no.load(Ordering::SeqCst), max_sequence_no))
+    }
 }
 
 impl Memtable {
-    pub fn open(log_path: PathBuf) -> Result<Self> {
+    pub fn open(log_path: PathBuf, options: &Options) -> Result<Self> {
         log::info!("Going to open Memtable log file {}.", log_path.display());
 
-        let mut data = BTreeMap::new();
-        let mut data_size = 0;
+        let comparator = options.comparator;
+        let data = MemtableData::new();
+        let data_size = AtomicUsize::new(0);
+        let sequence_range = SequenceRange::new();
 
-        let log_file = OpenOptions::new()
-            .read(true)
-            .append(true)
-            .create(true)
-            .open(log_path.as_path())?;
+        let log_file = options.backend.open_append(&log_path)?;
 
         // Redo the commands in the log to recover the in-memory data.
         let mut log_reader = BufReader::new(log_file);
-        while let Some(command) = utils::read_message::<Command, BufReader<File>>(&mut log_reader)?
-        {
-            apply_command_to_data(&command, &mut data, &mut data_size)?;
+        let replay_stats = if !log_reader.get_ref().is_empty()? {
+            replay_log(
+                &mut log_reader,
+                &log_path,
+                options,
+                &data,
+                &data_size,
+                &sequence_range,
+            )?
+        } else {
+            ReplayStats::default()
+        };
+        let log_offset = replay_stats.num_bytes as u64;
+        let mut log_file = log_reader.into_inner();
+        if options.log_recovery_mode != LogRecoveryMode::FailFast && log_offset < log_file.len()? {
+            log_file = truncate_log(log_file, &log_path, log_offset, options)?;
         }
-        let log_writer = BufWriter::new(log_reader.into_inner());
 
         let is_deprecated = Mutex::new(false);
 
         Ok(Memtable {
             data,
+            comparator,
+            merge_operator: options.merge_operator.clone(),
+            checksum_records: options.checksum_records,
+            replay_stats,
             data_size,
+            sequence_range,
             log_path,
-            log_writer,
+            log: MemtableLog::new(log_file),
+            shards: Self::new_shards(options),
+            log_offset: AtomicU64::new(log_offset),
+            sequencer: None,
             is_deprecated,
+            trash: Trash::new(options),
+            backend: options.backend.clone(),
+            watchers: Arc::default(),
         })
     }
 
+    /// Open the log of a Memtable written by another process, which is never written here and
+    /// kept up to date by tail instead.
+    pub fn open_read_only(log_path: PathBuf, options: &Options) -> Result<Self> {
+        log::info!(
+            "Going to open Memtable log file {} read-only.",
+            log_path.display()
+        );
+
+        let log_file = options.backend.open(&log_path)?;
+        let memtable = Memtable {
+            data: MemtableData::new(),
+            comparator: options.comparator,
+            merge_operator: options.merge_operator.clone(),
+            checksum_records: options.checksum_records,
+            replay_stats: ReplayStats::default(),
+            data_size: AtomicUsize::new(0),
+            sequence_range: SequenceRange::new(),
+            log_path,
+            log: MemtableLog::new(log_file),
+            shards: Self::new_shards(options),
+            log_offset: AtomicU64::new(0),
+            sequencer: None,
+            is_deprecated: Mutex::new(false),
+            trash: None,
+            backend: options.backend.clone(),
+            watchers: Arc::default(),
+        };
+        memtable.tail()?;
+        Ok(memtable)
+    }
+
+    fn new_shards(options: &Options) -> Vec<Mutex<()>> {
+        (0..options.memtable_shards.max(1))
+            .map(|_| Mutex::new(()))
+            .collect()
+    }
+
+    /// Apply the commands appended to the log since it was last read and return the number of
+    /// them. A command still being written is left for the next time.
+    ///
+    /// The log is written in the order of the sequence numbers, so the commands applied so far
+    /// always make up a contiguous range of them.
+    pub fn tail(&self) -> Result<usize> {
+        let mut log = self.log.lock()?;
+        let log_offset = self.log_offset.load(Ordering::SeqCst);
+        // Read through the handle, which keeps the log readable even after it is removed.
+        let log_file = &mut log.log_file;
+        log_file.seek(SeekFrom::Start(log_offset))?;
+        let mut bytes = Vec::new();
+        log_file.read_to_end(&mut bytes)?;
+
+        let mut chunks = Vec::new();
+        let mut num_bytes = 0;
+        while bytes.len() - num_bytes >= N_BYTES_CHUNK_LENGTH {
+            let mut chunk_reader = &bytes[num_bytes..];
+            let mut chunk = Vec::new();
+            match utils::read_chunk(&mut chunk_reader, &mut chunk) {
+                Ok(chunk_length) => {
+                    let offset = log_offset + (num_bytes + N_BYTES_CHUNK_LENGTH) as u64;
+                    chunks.push((offset, chunk));
+                    num_bytes += N_BYTES_CHUNK_LENGTH + chunk_length;
+                }
+                Err(NaiveError::IoError(error))
+                    if error.kind() == std::io::ErrorKind::UnexpectedEof =>
+                {
+                    break;
+                }
+                Err(error) => return Err(error),
+            }
+        }
+
+        let num_records = chunks.len();
+        let batch = decode_chunks(chunks, &self.log_path, LogRecoveryMode::FailFast)?;
+        let readable_sequence_no = self.readable_sequence_no()?;
+        for (key, timed_record, sequence_no) in batch.records {
+            apply_record_to_data(
+                key,
+                timed_record,
+                &self.data,
+                &self.data_size,
+                self.comparator,
+                self.merge_operator.as_deref(),
+                readable_sequence_no,
+            )?;
+            self.sequence_range.extend(sequence_no);
+        }
+        self.log_offset
+            .fetch_add(num_bytes as u64, Ordering::SeqCst);
+        Ok(num_records)
+    }
+
     pub fn get(&self, key: &str) -> Result<Option<Record>> {
-        Ok(self.data.get(key).map(|record| (*record).clone()))
+        Ok(self.get_timed(key)?.map(|timed_record| timed_record.record))
+    }
+
+    /// Get the record of the key visible as of now along with when it was written.
+    pub fn get_timed(&self, key: &str) -> Result<Option<TimedRecord>> {
+        let key = OrderedKey::new(key.to_owned(), self.comparator);
+        let sequence_no = self.visible_sequence_no();
+        Ok(self.data.get(&key).and_then(|entry| {
+            entry
+                .value()
+                .read(|versions| versions.read_at(sequence_no).cloned())
+        }))
+    }
+
+    /// Sync the log to the disk.
+    pub fn sync(&self) -> Result<()> {
+        self.log.lock()?.log_file.sync()
+    }
+
+    /// Pin the log as of now, e.g. for a checkpoint, returning a handle that stays readable after
+    /// the log is removed along with the number of bytes written so far. Each write is flushed to
+    /// the file as it is made, so the bytes up to there are complete while no write is appending.
+    pub(crate) fn pin_log(&self) -> Result<(Box<dyn BackendFile>, u64)> {
+        let log = self.log.lock()?;
+        let log_offset = self.log_offset.load(Ordering::SeqCst);
+        let log_file = self.backend.open(&self.log_path)?;
+        drop(log);
+        Ok((log_file, log_offset))
+    }
+
+    /// Tell what the record of the key visible as of now is without cloning its value.
+    pub(crate) fn get_kind(&self, key: &str) -> Result<Option<RecordKind>> {
+        let key = OrderedKey::new(key.to_owned(), self.comparator);
+        let sequence_no = self.visible_sequence_no();
+        Ok(self.data.get(&key).and_then(|entry| {
+            entry.value().read(|versions| {
+                versions
+                    .read_at(sequence_no)
+                    .map(|timed_record| timed_record.record.kind())
+            })
+        }))
+    }
+
+    /// Take the turns of writing the keys of the hashes, which last until the writer drops, e.g.
+    /// to look up a key and write it with no other write to it in between. The shards of the keys
+    /// are taken in order, so that the writers of overlapping keys never wait for each other in a
+    /// cycle, while the writes of the other keys and the reads go on all the while.
+    ///
+    /// Each write takes its sequence number from the sequencer, if any, as it is appended to the
+    /// log, or goes unstamped otherwise.
+    pub fn writer(&self, key_hashes: &[u64]) -> Result<MemtableWriter<'_>> {
+        self.writer_stamped(key_hashes, None)
+    }
+
+    /// Take the turns of writing the keys like `writer`, but stamp the writes with the given
+    /// sequence number instead, e.g. to carry the writes over from another log.
+    pub fn writer_at(&self, key_hashes: &[u64], sequence_no: u64) -> Result<MemtableWriter<'_>> {
+        self.writer_stamped(key_hashes, Some(sequence_no))
+    }
+
+    fn writer_stamped(&self, key_hashes: &[u64], stamp: Option<u64>) -> Result<MemtableWriter<'_>> {
+        let mut shard_nos = key_hashes
+            .iter()
+            .map(|key_hash| self.shard_no(*key_hash))
+            .collect::<Vec<_>>();
+        shard_nos.sort_unstable();
+        shard_nos.dedup();
+        let mut turns = Vec::with_capacity(shard_nos.len());
+        for shard_no in shard_nos.iter() {
+            turns.push(self.shards[*shard_no].lock()?);
+        }
+        Ok(MemtableWriter {
+            memtable: self,
+            shard_nos,
+            _turns: turns,
+            stamp,
+            sequence_no: None,
+        })
+    }
+
+    fn shard_no(&self, key_hash: u64) -> usize {
+        (key_hash % self.shards.len() as u64) as usize
+    }
+
+    /// The number of shards taking the writes.
+    pub fn num_shards(&self) -> usize {
+        self.shards.len()
+    }
+
+    /// Set the value for a key and return the number of bytes written to the log.
+    pub fn set(&self, key: String, value: String, sequence_no: u64) -> Result<usize> {
+        self.writer_at(&[key_hash(&key)], sequence_no)?
+            .set(key, value)
+    }
+
+    /// Set a value for a key which counts as missing from the given time, and return the number
+    /// of bytes written to the log.
+    pub fn set_expiring(
+        &self,
+        key: String,
+        value: String,
+        expires_at_ms: u64,
+        sequence_no: u64,
+    ) -> Result<usize> {
+        self.writer_at(&[key_hash(&key)], sequence_no)?
+            .set_expiring(key, value, expires_at_ms)
     }
 
-    pub fn set(&mut self, key: String, value: String) -> Result<()> {
-        // Write the log before updating the in-memory data.
-        let mut command = Command::new();
-        command.set_key(key.clone());
-        command.set_command_type(CommandType::SET_VALUE);
-        command.set_value(value);
-        utils::write_message(&command, &mut self.log_writer)?;
+    /// Remove a key and return the number of bytes written to the log.
+    pub fn remove(&self, key: String, sequence_no: u64) -> Result<usize> {
+        self.writer_at(&[key_hash(&key)], sequence_no)?.remove(key)
+    }
+
+    /// Write a merge operand for a key and return the number of bytes written to the log.
+    pub fn merge(&self, key: String, operand: String, sequence_no: u64) -> Result<usize> {
+        self.writer_at(&[key_hash(&key)], sequence_no)?
+            .merge(key, operand)
+    }
+
+    /// Apply a batch of writes in order, all stamped with the same sequence number, and return
+    /// the number of bytes written to the log.
+    pub fn write_batch(&self, batch: &WriteBatch, sequence_no: u64) -> Result<usize> {
+        self.writer_at(&batch.key_hashes(), sequence_no)?
+            .write_batch(batch)
+    }
+
+    /// Iterate over the records visible as of now, each copied out as it is reached, since the
+    /// writes meanwhile may replace it.
+    pub fn iter(&self) -> impl Iterator<Item = (String, Record)> + '_ {
+        self.iter_timed()
+            .map(|(key, timed_record)| (key, timed_record.record))
+    }
+
+    /// Iterate over the records along with when they were written.
+    pub fn iter_timed(&self) -> impl Iterator<Item = (String, TimedRecord)> + '_ {
+        let sequence_no = self.visible_sequence_no();
+        self.data.iter().filter_map(move |entry| {
+            entry.value().read(|versions| {
+                versions
+                    .read_at(sequence_no)
+                    .map(|timed_record| (entry.key().as_str().to_owned(), timed_record.clone()))
+            })
+        })
+    }
 
-        apply_command_to_data(&command, &mut self.data, &mut self.data_size)
+    pub fn range<R: RangeBounds<String>>(
+        &self,
+        range: &R,
+    ) -> impl Iterator<Item = (String, Record)> + '_ {
+        self.range_timed(range)
+            .map(|(key, timed_record)| (key, timed_record.record))
     }
 
-    pub fn remove(&mut self, key: String) -> Result<()> {
-        // Write the log before updating the in-memory data.
-        let mut command = Command::new();
-        command.set_key(key.clone());
-        command.set_command_type(CommandType::DELETE);
-        utils::write_message(&command, &mut self.log_writer)?;
+    /// Iterate over the records in the range along with when they were written.
+    pub fn range_timed<R: RangeBounds<String>>(
+        &self,
+        range: &R,
+    ) -> impl Iterator<Item = (String, TimedRecord)> + '_ {
+        let bounds = OrderedKey::bounds(range, self.comparator);
+        let sequence_no = self.visible_sequence_no();
+        self.data.range(bounds).filter_map(move |entry| {
+            entry.value().read(|versions| {
+                versions
+                    .read_at(sequence_no)
+                    .map(|timed_record| (entry.key().as_str().to_owned(), timed_record.clone()))
+            })
+        })
+    }
 
-        apply_command_to_data(&command, &mut self.data, &mut self.data_size)
+    /// Copy the in-memory data as of the visible sequence number, which is not affected by later
+    /// writes to the Memtable. The sequence number is pinned throughout, so that the writes
+    /// meanwhile keep the records visible as of it.
+    pub fn freeze(&self) -> Result<FrozenMemtableData> {
+        let pin = self
+            .sequencer
+            .as_ref()
+            .map(|sequencer| sequencer.pin())
+            .transpose()?;
+        let sequence_no = pin.as_ref().map_or(u64::MAX, |pin| pin.sequence_no());
+        Ok(self
+            .data
+            .iter()
+            .filter_map(|entry| {
+                entry.value().read(|versions| {
+                    versions
+                        .read_at(sequence_no)
+                        .map(|timed_record| (entry.key().clone(), timed_record.clone()))
+                })
+            })
+            .collect())
     }
 
-    pub fn iter(&self) -> btree_map::Iter<'_, String, Record> {
-        self.data.iter()
+    pub fn comparator(&self) -> &'static dyn Comparator {
+        self.comparator
     }
 
     pub fn data_size(&self) -> usize {
-        self.data_size
+        self.data_size.load(Ordering::SeqCst)
+    }
+
+    pub fn log_path(&self) -> &Path {
+        self.log_path.as_path()
+    }
+
+    pub fn replay_stats(&self) -> &ReplayStats {
+        &self.replay_stats
+    }
+
+    /// Notify the watches of the catalog of the writes from now on.
+    pub(crate) fn set_watchers(&mut self, watchers: Arc<Watchers>) {
+        self.watchers = watchers;
+    }
+
+    /// Take the sequence numbers of the writes from the sequencer of the catalog, and show the
+    /// reads only the writes it has made visible, from now on.
+    pub(crate) fn set_sequencer(&mut self, sequencer: Arc<Sequencer>) {
+        self.sequencer = Some(sequencer);
+    }
+
+    /// The sequence number the reads are taken as of.
+    fn visible_sequence_no(&self) -> u64 {
+        self.sequencer
+            .as_ref()
+            .map_or(u64::MAX, |sequencer| sequencer.visible_sequence_no())
+    }
+
+    /// The sequence number as of which the readers may still read, whose records the writes keep.
+    fn readable_sequence_no(&self) -> Result<u64> {
+        self.sequencer.as_ref().map_or(Ok(u64::MAX), |sequencer| {
+            sequencer.oldest_readable_sequence_no()
+        })
+    }
+
+    /// The smallest and the largest sequence numbers in the log, if any.
+    pub fn sequence_range(&self) -> Option<(u64, u64)> {
+        self.sequence_range.get()
     }
 
     /// This is called by the compaction daemon once the Memtable is merged into an SSTable.
@@ -98,42 +649,541 @@ impl Memtable {
     }
 }
 
+/// The turns of a writer of a Memtable on the shards of its keys, during which the other writers
+/// of the shards wait while the readers go on.
+pub struct MemtableWriter<'a> {
+    memtable: &'a Memtable,
+    shard_nos: Vec<usize>,
+    _turns: Vec<MutexGuard<'a, ()>>,
+
+    /// The sequence number to stamp the writes with, or None to take one for each write.
+    stamp: Option<u64>,
+
+    /// The sequence number of the last write, if any.
+    sequence_no: Option<u64>,
+}
+
+impl MemtableWriter<'_> {
+    /// Set the value for a key and return the number of bytes written to the log.
+    pub fn set(&mut self, key: String, value: String) -> Result<usize> {
+        self.write(vec![(key, Record::Value(value))])
+    }
+
+    /// Set a value for a key which counts as missing from the given time, and return the number
+    /// of bytes written to the log.
+    pub fn set_expiring(
+        &mut self,
+        key: String,
+        value: String,
+        expires_at_ms: u64,
+    ) -> Result<usize> {
+        let record = Record::ExpiringValue {
+            value,
+            expires_at_ms,
+        };
+        self.write(vec![(key, record)])
+    }
+
+    /// Remove a key and return the number of bytes written to the log.
+    pub fn remove(&mut self, key: String) -> Result<usize> {
+        self.write(vec![(key, Record::Deleted)])
+    }
+
+    /// Write a merge operand for a key and return the number of bytes written to the log.
+    pub fn merge(&mut self, key: String, operand: String) -> Result<usize> {
+        self.write(vec![(key, Record::Merge(vec![operand]))])
+    }
+
+    /// Apply a batch of writes in order, all stamped with the same sequence number and appended
+    /// to the log at once, and return the number of bytes written to the log.
+    pub fn write_batch(&mut self, batch: &WriteBatch) -> Result<usize> {
+        self.write(
+            batch
+                .iter()
+                .map(|(key, record)| (key.clone(), record.clone()))
+                .collect(),
+        )
+    }
+
+    /// The sequence number of the last write, if any.
+    pub fn sequence_no(&self) -> Option<u64> {
+        self.sequence_no
+    }
+
+    /// Get the newest record of a key the writer holds the turn of, even if not yet visible, e.g.
+    /// to write a new value made of it.
+    pub fn get_latest_timed(&self, key: &str) -> Result<Option<TimedRecord>> {
+        let key = OrderedKey::new(key.to_owned(), self.memtable.comparator);
+        Ok(self.memtable.data.get(&key).map(|entry| {
+            entry
+                .value()
+                .read(|versions| TimedRecord::clone(&versions.record))
+        }))
+    }
+
+    fn write(&mut self, records: Vec<(String, Record)>) -> Result<usize> {
+        // Refuse the merge operands up front, which could not be applied after being logged.
+        if self.memtable.merge_operator.is_none()
+            && records
+                .iter()
+                .any(|(_, record)| matches!(record, Record::Merge(_)))
+        {
+            return Err(NaiveError::InvalidOptions(
+                "merge operands cannot be written without a merge operator".to_owned(),
+            ));
+        }
+        for (key, _) in records.iter() {
+            if !self
+                .shard_nos
+                .contains(&self.memtable.shard_no(key_hash(key)))
+            {
+                log::error!("The writer does not hold the shard of the key to write.");
+                return Err(NaiveError::Unknown);
+            }
+        }
+        let timestamp_ms = utils::now_ms();
+
+        // Write the log before updating the in-memory data. The sequence number is taken under
+        // the lock of the log, so that the log is in the order of the sequence numbers.
+        let sequencer = match self.stamp {
+            Some(_) => None,
+            None => self.memtable.sequencer.as_deref(),
+        };
+        let (sequence_no, commands, num_bytes) = {
+            let mut log = self.memtable.log.lock()?;
+            let sequence_no = match (self.stamp, sequencer) {
+                (Some(sequence_no), _) => sequence_no,
+                (None, Some(sequencer)) => sequencer.take()?,
+                (None, None) => 0,
+            };
+            match self.append(&mut log, &records, sequence_no, timestamp_ms) {
+                Ok((commands, num_bytes)) => (sequence_no, commands, num_bytes),
+                Err(error) => {
+                    if let Some(sequencer) = sequencer {
+                        sequencer.give_back(sequence_no)?;
+                    }
+                    return Err(error);
+                }
+            }
+        };
+        self.memtable.sequence_range.extend(sequence_no);
+        self.sequence_no = Some(sequence_no);
+
+        // Apply the write, which the readers only see once the sequencer makes it visible.
+        let mut events = Vec::new();
+        let result = self
+            .memtable
+            .readable_sequence_no()
+            .and_then(|readable_sequence_no| {
+                for (command, (_, record)) in commands.into_iter().zip(records) {
+                    if self.memtable.watchers.is_watching(command.get_key()) {
+                        events.push(ChangeEvent {
+                            key: command.get_key().to_owned(),
+                            record: record.clone(),
+                            sequence_no,
+                            is_expiry: false,
+                        });
+                    }
+                    apply_record_to_data(
+                        command.get_key().to_owned(),
+                        TimedRecord {
+                            record,
+                            timestamp_ms: Some(timestamp_ms),
+                            sequence_no,
+                        },
+                        &self.memtable.data,
+                        &self.memtable.data_size,
+                        self.memtable.comparator,
+                        self.memtable.merge_operator.as_deref(),
+                        readable_sequence_no,
+                    )?;
+                }
+                Ok(())
+            });
+        // The write is in the log either way, so the ones after it must not wait for it.
+        if let Some(sequencer) = sequencer {
+            sequencer.finish(sequence_no)?;
+        }
+        result?;
+        for event in events {
+            self.memtable.watchers.notify(&event);
+        }
+        Ok(num_bytes)
+    }
+
+    /// Encode the records into the commands stamped with the sequence number and append them to
+    /// the log by a single write.
+    fn append(
+        &self,
+        log: &mut MemtableLog,
+        records: &[(String, Record)],
+        sequence_no: u64,
+        timestamp_ms: u64,
+    ) -> Result<(Vec<Command>, usize)> {
+        log.buffer.clear();
+        let checksum_records = self.memtable.checksum_records;
+        let mut commands = records
+            .iter()
+            .map(|(key, record)| {
+                record.to_stamped_command(
+                    key.clone(),
+                    sequence_no,
+                    Some(timestamp_ms),
+                    checksum_records,
+                )
+            })
+            .collect::<Vec<_>>();
+        // A batch goes in a single record, which a crash leaves either whole or torn, so that the
+        // replay never applies a part of it.
+        let num_bytes = match commands.as_slice() {
+            [] => 0,
+            [command] => utils::write_message(command, &mut log.buffer)?,
+            _ => {
+                let mut batch =
+                    types::batch_command(commands, sequence_no, timestamp_ms, checksum_records);
+                let num_bytes = utils::write_message(&batch, &mut log.buffer)?;
+                commands = batch.take_batch().into_vec();
+                num_bytes
+            }
+        };
+        log.log_file.write_all(&log.buffer)?;
+        self.memtable
+            .log_offset
+            .fetch_add(num_bytes as u64, Ordering::SeqCst);
+        Ok((commands, num_bytes))
+    }
+}
+
+impl std::ops::Deref for MemtableWriter<'_> {
+    type Target = Memtable;
+
+    fn deref(&self) -> &Memtable {
+        self.memtable
+    }
+}
+
 impl Drop for Memtable {
     fn drop(&mut self) {
-        // If is_deprecated is set, remove the write-ahead log on drop.
-        let is_deprecated = self
-            .is_deprecated
-            .lock()
-            .expect("Failed to lock the mutex for Memtable::is_deprecated");
-        if *is_deprecated {
+        // If is_deprecated is set, remove the write-ahead log on drop. A log failing to go is
+        // left behind for the next open to sort out, rather than panicking in a drop.
+        let is_deprecated = match self.is_deprecated.lock() {
+            Ok(is_deprecated) => *is_deprecated,
+            Err(poisoned) => *poisoned.into_inner(),
+        };
+        if is_deprecated {
             let log_path = self.log_path.as_path();
-            utils::try_remove_file(log_path).expect(&format!(
-                "Failed to delete Memtable log {}",
-                log_path.display()
-            ));
+            if let Err(error) = Trash::discard(self.backend.as_ref(), self.trash.as_ref(), log_path)
+            {
+                log::error!(
+                    "Failed to delete Memtable log {}: {:?}",
+                    log_path.display(),
+                    error
+                );
+            }
+        }
+    }
+}
+
+/// Replay the log by decoding its chunks in batches on a thread pool, while applying the decoded
+/// batches to the data in the log order.
+fn replay_log(
+    log_reader: &mut impl Read,
+    log_path: &Path,
+    options: &Options,
+    data: &MemtableData,
+    data_size: &AtomicUsize,
+    sequence_range: &SequenceRange,
+) -> Result<ReplayStats> {
+    let start_time = Instant::now();
+    let mut replay_stats = ReplayStats::default();
+    let decoders = ThreadPool::new(options.num_background_threads.max(1));
+    let (sender, receiver) = unbounded();
+    let mut pending_batches = BTreeMap::new();
+    let mut num_batches = 0;
+    let mut num_applied_batches = 0;
+    // Where the log is cut off at the first corrupted chunk, if the recovery mode truncates it.
+    let mut truncated_offset = None;
+
+    let mut offset = 0;
+    let mut is_end_of_log = false;
+    while !is_end_of_log {
+        // Read a batch of raw chunks, which is cheap compared to decoding them.
+        let mut chunks = Vec::with_capacity(REPLAY_BATCH_SIZE);
+        while chunks.len() < REPLAY_BATCH_SIZE {
+            let mut chunk = Vec::new();
+            let num_bytes = match utils::read_chunk(log_reader, &mut chunk) {
+                Ok(num_bytes) => num_bytes,
+                // A chunk torn at the end of the log, e.g. by a crash, is left for the truncation.
+                Err(NaiveError::IoError(error))
+                    if error.kind() == std::io::ErrorKind::UnexpectedEof
+                        && options.log_recovery_mode != LogRecoveryMode::FailFast =>
+                {
+                    log::error!(
+                        "Found a torn chunk in {} at offset {}.",
+                        log_path.display(),
+                        offset
+                    );
+                    0
+                }
+                Err(error) => return Err(error),
+            };
+            if num_bytes == 0 {
+                is_end_of_log = true;
+                break;
+            }
+            chunks.push((offset + N_BYTES_CHUNK_LENGTH as u64, chunk));
+            offset += (N_BYTES_CHUNK_LENGTH + num_bytes) as u64;
+        }
+        if chunks.is_empty() {
+            break;
+        }
+        replay_stats.num_records += chunks.len();
+
+        let batch_no = num_batches;
+        num_batches += 1;
+        let sender = sender.clone();
+        let log_path = log_path.to_owned();
+        let recovery_mode = options.log_recovery_mode;
+        decoders.add_task(move || {
+            let _ = sender.send((batch_no, decode_chunks(chunks, &log_path, recovery_mode)));
+        })?;
+
+        // Apply whatever is already decoded without waiting.
+        while let Ok((batch_no, batch)) = receiver.try_recv() {
+            pending_batches.insert(batch_no, batch);
+        }
+        apply_decoded_batches(
+            &mut pending_batches,
+            &mut num_applied_batches,
+            &mut truncated_offset,
+            data,
+            data_size,
+            sequence_range,
+            options,
+        )?;
+    }
+
+    // Wait for the remaining batches.
+    drop(sender);
+    while num_applied_batches < num_batches {
+        // The channel only disconnects early if a decoding task panics.
+        let (batch_no, batch) = receiver.recv().map_err(|_| NaiveError::Unknown)?;
+        pending_batches.insert(batch_no, batch);
+        apply_decoded_batches(
+            &mut pending_batches,
+            &mut num_applied_batches,
+            &mut truncated_offset,
+            data,
+            data_size,
+            sequence_range,
+            options,
+        )?;
+    }
+
+    replay_stats.num_bytes = truncated_offset.unwrap_or(offset) as usize;
+    replay_stats.duration = start_time.elapsed();
+    log::info!(
+        "Replayed {} records ({} bytes) from {} in {:?} ({:.2} MB/s).",
+        replay_stats.num_records,
+        replay_stats.num_bytes,
+        log_path.display(),
+        replay_stats.duration,
+        replay_stats.bytes_per_second() / (1 << 20) as f64
+    );
+    Ok(replay_stats)
+}
+
+/// Decode a batch of log chunks, located by the offsets of their messages, failing on the first
+/// corrupted one, or skipping it or cutting the batch off at it as the recovery mode says.
+fn decode_chunks(
+    chunks: Vec<(u64, Vec<u8>)>,
+    log_path: &Path,
+    recovery_mode: LogRecoveryMode,
+) -> Result<DecodedBatch> {
+    let mut batch = DecodedBatch {
+        records: Vec::with_capacity(chunks.len()),
+        ..DecodedBatch::default()
+    };
+    for (offset, chunk) in chunks {
+        let decoded = Command::parse_from_bytes(&chunk)
+            .map_err(NaiveError::from)
+            .and_then(|command| decode_command(command, log_path, offset));
+        let records = match (decoded, recovery_mode) {
+            (Ok(decoded), _) => decoded,
+            (Err(error), LogRecoveryMode::FailFast) => return Err(error),
+            (Err(error), LogRecoveryMode::SkipCorrupted) => {
+                log::error!(
+                    "Skipped a corrupted record in {} at offset {}: {:?}",
+                    log_path.display(),
+                    offset,
+                    error
+                );
+                batch.skipped_records.push(RepairEvent::SkippedLogRecord {
+                    log_path: log_path.to_owned(),
+                    offset,
+                });
+                continue;
+            }
+            (Err(error), LogRecoveryMode::TruncateAndContinue) => {
+                log::error!(
+                    "Found a corrupted record in {} at offset {}: {:?}",
+                    log_path.display(),
+                    offset,
+                    error
+                );
+                batch.truncated_offset = Some(offset - N_BYTES_CHUNK_LENGTH as u64);
+                break;
+            }
+        };
+        batch.records.extend(records);
+    }
+    Ok(batch)
+}
+
+/// Decode the records of a log command, i.e. a single write or a whole batch, failing on any of
+/// them corrupted so that a batch is applied all or none.
+fn decode_command(
+    command: Command,
+    log_path: &Path,
+    offset: u64,
+) -> Result<Vec<(String, TimedRecord, u64)>> {
+    let decode = |command: &Command| {
+        Ok((
+            command.get_key().to_owned(),
+            TimedRecord::from_checked_command(command, log_path, offset)?,
+            command.get_sequence_no(),
+        ))
+    };
+    if command.get_command_type() != CommandType::BATCH {
+        return Ok(vec![decode(&command)?]);
+    }
+    types::verify_command(&command, log_path, offset)?;
+    if command.get_batch().is_empty() {
+        return Err(NaiveError::InvalidData);
+    }
+    command.get_batch().iter().map(decode).collect()
+}
+
+/// Cut the log off at the offset, replacing it as a whole so that a crash leaves either the old
+/// or the new log behind, and reopen it for appending.
+fn truncate_log(
+    mut log_file: Box<dyn BackendFile>,
+    log_path: &Path,
+    offset: u64,
+    options: &Options,
+) -> Result<Box<dyn BackendFile>> {
+    let num_bytes_dropped = log_file.len()? - offset;
+    let mut bytes = vec![0u8; offset as usize];
+    log_file.seek(SeekFrom::Start(0))?;
+    log_file.read_exact(&mut bytes)?;
+    drop(log_file);
+    options.backend.replace(log_path, &bytes)?;
+    log::warn!(
+        "Truncated {} at offset {}, dropping {} bytes.",
+        log_path.display(),
+        offset,
+        num_bytes_dropped
+    );
+    listener::notify_repair(
+        options,
+        RepairEvent::TruncatedMemtableLog {
+            log_path: log_path.to_owned(),
+            offset,
+            num_bytes_dropped,
+        },
+    );
+    options.backend.open_append(log_path)
+}
+
+/// Apply the consecutive decoded batches following the ones already applied.
+///
+/// Once a batch is cut off at a corrupted chunk, the batches after it are dropped.
+fn apply_decoded_batches(
+    pending_batches: &mut BTreeMap<usize, Result<DecodedBatch>>,
+    num_applied_batches: &mut usize,
+    truncated_offset: &mut Option<u64>,
+    data: &MemtableData,
+    data_size: &AtomicUsize,
+    sequence_range: &SequenceRange,
+    options: &Options,
+) -> Result<()> {
+    while let Some(batch) = pending_batches.remove(num_applied_batches) {
+        *num_applied_batches += 1;
+        if truncated_offset.is_some() {
+            continue;
+        }
+        let batch = batch?;
+        *truncated_offset = batch.truncated_offset;
+        for event in batch.skipped_records {
+            listener::notify_repair(options, event);
+        }
+        for (key, timed_record, sequence_no) in batch.records {
+            apply_record_to_data(
+                key,
+                timed_record,
+                data,
+                data_size,
+                options.comparator,
+                options.merge_operator.as_deref(),
+                u64::MAX,
+            )?;
+            sequence_range.extend(sequence_no);
         }
     }
+    Ok(())
 }
 
-fn apply_command_to_data(
-    command: &Command,
-    data: &mut BTreeMap<String, Record>,
-    data_size: &mut usize,
+/// Apply a record to the data, which only one writer of the key at a time may do, while the
+/// readers see the old record of the key until the new one is visible. The old records readable
+/// as of the given sequence number or later are kept for the readers.
+fn apply_record_to_data(
+    key: String,
+    timed_record: TimedRecord,
+    data: &MemtableData,
+    data_size: &AtomicUsize,
+    comparator: &'static dyn Comparator,
+    merge_operator: Option<&dyn MergeOperator>,
+    readable_sequence_no: u64,
 ) -> Result<()> {
-    let record = Record::from_command(command)?;
-    if let Some(ref mut record_mut) = data.get_mut(command.get_key()) {
-        // Replace the old record with the new one.
-        *data_size -= record_mut.len();
-        *data_size += record.len();
-        let _ = std::mem::replace(*record_mut, record);
+    let key = OrderedKey::new(key, comparator);
+    let sequence_no = timed_record.sequence_no;
+    if let Some(entry) = data.get(&key) {
+        // Replace the old record with the new one, or apply the merge operands to it.
+        let versioned_record = entry.value().read(|old_versions| {
+            let timed_record = match timed_record.record {
+                Record::Merge(_) => merge::stack_timed(
+                    key.as_str(),
+                    timed_record,
+                    TimedRecord::clone(&old_versions.record),
+                    merge_operator,
+                )?,
+                _ => timed_record,
+            };
+            data_size.fetch_sub(old_versions.record.record.len(), Ordering::SeqCst);
+            data_size.fetch_add(timed_record.record.len(), Ordering::SeqCst);
+            let older = (sequence_no > readable_sequence_no)
+                .then(|| Arc::new(old_versions.truncate(readable_sequence_no)));
+            Ok::<_, NaiveError>(VersionedRecord {
+                record: Arc::new(timed_record),
+                is_truncated: older.is_none(),
+                older,
+            })
+        })?;
+        entry.value().replace(versioned_record);
     } else {
         // Insert the key-record pair.
         // Note that even in the case of deletion we cannot simply remove the key from the data,
         // otherwise we cannot overwrite its existence in the SSTables.
-        let key = command.get_key().to_owned();
-        let record = Record::Value(command.get_value().to_owned());
-        *data_size += key.len() + record.len();
-        data.insert(key, record);
+        data_size.fetch_add(
+            key.as_str().len() + timed_record.record.len(),
+            Ordering::SeqCst,
+        );
+        let versioned_record = VersionedRecord {
+            record: Arc::new(timed_record),
+            older: None,
+            is_truncated: false,
+        };
+        data.insert(key, MemtableEntry::new(versioned_record));
     }
     Ok(())
 }
@@ -148,10 +1198,12 @@ mod tests {
         let log_path = PathBuf::from("/tmp/test_memtable.log");
         utils::try_remove_file(&log_path).unwrap();
 
-        let mut memtable = Memtable::open(log_path.clone()).unwrap();
+        let memtable = Memtable::open(log_path.clone(), &Options::default()).unwrap();
         for num in 0..=MAX_NUMBER {
             let num_str = num.to_string();
-            memtable.set(num_str.clone(), num_str.clone()).unwrap();
+            memtable
+                .set(num_str.clone(), num_str.clone(), num as u64 + 1)
+                .unwrap();
         }
         for num in 0..=MAX_NUMBER {
             let num_str = num.to_string();
@@ -166,7 +1218,9 @@ mod tests {
         // Remove all the odd numbers.
         for num in (1..=MAX_NUMBER).step_by(2) {
             let num_str = num.to_string();
-            memtable.remove(num_str).unwrap();
+            memtable
+                .remove(num_str, (MAX_NUMBER + num) as u64 + 1)
+                .unwrap();
         }
         for num in 0..=MAX_NUMBER {
             let num_str = num.to_string();
@@ -179,7 +1233,7 @@ mod tests {
         }
 
         // Restart from the disk.
-        let memtable = Memtable::open(log_path.clone()).unwrap();
+        let memtable = Memtable::open(log_path.clone(), &Options::default()).unwrap();
         memtable.deprecate().unwrap();
         for num in 0..=MAX_NUMBER {
             let num_str = num.to_string();
@@ -191,4 +1245,536 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn test_memtable_concurrent_reads() {
+        const NUM_WRITES: u64 = 1000;
+        let log_path = PathBuf::from("/tmp/test_memtable_concurrent_reads.log");
+        utils::try_remove_file(&log_path).unwrap();
+
+        let memtable = Arc::new(Memtable::open(log_path.clone(), &Options::default()).unwrap());
+        memtable.set("key".to_owned(), "0".to_owned(), 1).unwrap();
+
+        // The reads go on while a writer holds its turn.
+        let mut writer = memtable.writer_at(&[key_hash("key")], 2).unwrap();
+        let reader = {
+            let memtable = memtable.clone();
+            std::thread::spawn(move || memtable.get("key").unwrap())
+        };
+        assert_eq!(reader.join().unwrap(), Some(Record::Value("0".to_owned())));
+        writer.set("key".to_owned(), "1".to_owned()).unwrap();
+        drop(writer);
+
+        // The readers see each key either before or after a write, never in between.
+        let writers = (0..2)
+            .map(|num| {
+                let memtable = memtable.clone();
+                std::thread::spawn(move || {
+                    for sequence_no in 0..NUM_WRITES {
+                        let key = format!("{}_{}", num, sequence_no % 10);
+                        let value = sequence_no.to_string();
+                        memtable.set(key, value, sequence_no + 3).unwrap();
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for _ in 0..NUM_WRITES {
+            for (key, record) in memtable.iter() {
+                assert!(matches!(record, Record::Value(ref value) if value.parse::<u64>().is_ok()));
+                assert!(memtable.get(&key).unwrap().is_some());
+            }
+        }
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        assert_eq!(memtable.iter().count(), 21);
+        assert_eq!(memtable.sequence_range(), Some((1, NUM_WRITES + 2)));
+        memtable.sync().unwrap();
+        let replayed = Memtable::open(log_path, &Options::default()).unwrap();
+        assert!(replayed.freeze().unwrap() == memtable.freeze().unwrap());
+    }
+
+    #[test]
+    fn test_memtable_shards() {
+        const NUM_THREADS: u64 = 4;
+        const NUM_WRITES: u64 = 1000;
+        let options = Options {
+            memtable_shards: 4,
+            ..Options::default()
+        };
+        let log_path = PathBuf::from("/tmp/test_memtable_shards.log");
+        utils::try_remove_file(&log_path).unwrap();
+
+        let memtable = Arc::new(Memtable::open(log_path.clone(), &options).unwrap());
+        assert_eq!(memtable.num_shards(), 4);
+
+        // A writer holding the shard of a key blocks none of the writes to the other shards, and
+        // refuses to write the keys of the shards it does not hold.
+        let other_key = (0..)
+            .map(|num| num.to_string())
+            .find(|key| key_hash(key) % 4 != key_hash("key") % 4)
+            .unwrap();
+        let mut writer = memtable.writer_at(&[key_hash("key")], 1).unwrap();
+        writer.set("key".to_owned(), "0".to_owned()).unwrap();
+        {
+            let memtable = memtable.clone();
+            let other_key = other_key.clone();
+            std::thread::spawn(move || memtable.set(other_key, "0".to_owned(), 2).unwrap())
+                .join()
+                .unwrap();
+        }
+        assert!(writer.set(other_key.clone(), "1".to_owned()).is_err());
+        drop(writer);
+
+        // A batch takes the shards of all its keys.
+        let mut batch = WriteBatch::new();
+        batch.set("key".to_owned(), "1".to_owned());
+        batch.remove(other_key);
+        memtable.write_batch(&batch, 3).unwrap();
+
+        // The writes to different keys go on at once, each key keeping its own order.
+        let writers = (0..NUM_THREADS)
+            .map(|num| {
+                let memtable = memtable.clone();
+                std::thread::spawn(move || {
+                    for write_no in 0..NUM_WRITES {
+                        let key = format!("{}_{}", num, write_no % 10);
+                        let sequence_no = 4 + write_no * NUM_THREADS + num;
+                        memtable
+                            .set(key, write_no.to_string(), sequence_no)
+                            .unwrap();
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        for num in 0..NUM_THREADS {
+            for write_no in NUM_WRITES - 10..NUM_WRITES {
+                let key = format!("{}_{}", num, write_no % 10);
+                assert_eq!(
+                    memtable.get(&key).unwrap(),
+                    Some(Record::Value(write_no.to_string()))
+                );
+            }
+        }
+        assert_eq!(
+            memtable.sequence_range(),
+            Some((1, 3 + NUM_WRITES * NUM_THREADS))
+        );
+
+        // The commands of all the shards are replayed whole.
+        memtable.sync().unwrap();
+        let replayed = Memtable::open(log_path, &options).unwrap();
+        replayed.deprecate().unwrap();
+        assert!(replayed.freeze().unwrap() == memtable.freeze().unwrap());
+        assert_eq!(replayed.data_size(), memtable.data_size());
+        assert_eq!(
+            replayed.replay_stats().num_bytes as u64,
+            memtable.log_offset.load(Ordering::SeqCst)
+        );
+        assert_eq!(replayed.sequence_range(), memtable.sequence_range());
+    }
+
+    #[test]
+    fn test_memtable_visibility() {
+        let log_path = PathBuf::from("/tmp/test_memtable_visibility.log");
+        utils::try_remove_file(&log_path).unwrap();
+        let sequencer = Arc::new(Sequencer::new(0));
+        let mut memtable = Memtable::open(log_path, &Options::default()).unwrap();
+        memtable.set_sequencer(sequencer.clone());
+        memtable.deprecate().unwrap();
+        let mut writer = memtable.writer(&[key_hash("a")]).unwrap();
+        writer.set("a".to_owned(), "1".to_owned()).unwrap();
+        drop(writer);
+
+        // A write still in flight hides the ones after it, down to a frozen copy.
+        let in_flight_sequence_no = sequencer.take().unwrap();
+        let mut writer = memtable.writer(&[key_hash("a"), key_hash("b")]).unwrap();
+        writer.set("a".to_owned(), "2".to_owned()).unwrap();
+        writer.set("b".to_owned(), "2".to_owned()).unwrap();
+        assert_eq!(writer.sequence_no(), Some(in_flight_sequence_no + 2));
+        assert_eq!(
+            writer.get_latest_timed("a").unwrap().unwrap().record,
+            Record::Value("2".to_owned())
+        );
+        drop(writer);
+        assert_eq!(
+            memtable.get("a").unwrap(),
+            Some(Record::Value("1".to_owned()))
+        );
+        assert_eq!(memtable.get("b").unwrap(), None);
+        assert_eq!(memtable.iter().count(), 1);
+        let frozen_data = memtable.freeze().unwrap();
+        assert_eq!(frozen_data.len(), 1);
+
+        sequencer.finish(in_flight_sequence_no).unwrap();
+        assert_eq!(
+            memtable.get("a").unwrap(),
+            Some(Record::Value("2".to_owned()))
+        );
+        assert_eq!(
+            memtable.get("b").unwrap(),
+            Some(Record::Value("2".to_owned()))
+        );
+        assert_eq!(memtable.freeze().unwrap().len(), 2);
+        assert_eq!(frozen_data.len(), 1);
+    }
+
+    #[test]
+    fn test_memtable_batch_reads() {
+        const NUM_KEYS: usize = 10;
+        const NUM_BATCHES: usize = 300;
+        let options = Options {
+            memtable_shards: 4,
+            ..Options::default()
+        };
+        let log_path = PathBuf::from("/tmp/test_memtable_batch_reads.log");
+        utils::try_remove_file(&log_path).unwrap();
+        let mut memtable = Memtable::open(log_path, &options).unwrap();
+        memtable.set_sequencer(Arc::new(Sequencer::new(0)));
+        memtable.deprecate().unwrap();
+        let memtable = Arc::new(memtable);
+
+        // Each batch sets all the keys to its number, while the single writes in between keep
+        // some batches in flight behind them.
+        let writers = (0..2)
+            .map(|num| {
+                let memtable = memtable.clone();
+                std::thread::spawn(move || {
+                    for batch_no in 0..NUM_BATCHES {
+                        let mut batch = WriteBatch::new();
+                        if num == 0 {
+                            for key_no in 0..NUM_KEYS {
+                                batch.set(key_no.to_string(), batch_no.to_string());
+                            }
+                        } else {
+                            batch.set("other".to_owned(), batch_no.to_string());
+                        }
+                        let mut writer = memtable.writer(&batch.key_hashes()).unwrap();
+                        writer.write_batch(&batch).unwrap();
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+
+        // The reads see each batch either whole or not at all.
+        while writers.iter().any(|writer| !writer.is_finished()) {
+            let values = memtable
+                .freeze()
+                .unwrap()
+                .into_iter()
+                .filter(|(key, _)| key.as_str() != "other")
+                .map(|(_, timed_record)| timed_record.record)
+                .collect::<Vec<_>>();
+            assert!(values.is_empty() || values.len() == NUM_KEYS);
+            assert!(values.iter().all(|value| *value == values[0]));
+        }
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        assert_eq!(
+            memtable.get("0").unwrap(),
+            Some(Record::Value((NUM_BATCHES - 1).to_string()))
+        );
+    }
+
+    #[test]
+    fn test_memtable_log_order() {
+        const NUM_THREADS: u64 = 4;
+        const NUM_WRITES: u64 = 500;
+        let options = Options {
+            memtable_shards: 4,
+            ..Options::default()
+        };
+        let log_path = PathBuf::from("/tmp/test_memtable_log_order.log");
+        utils::try_remove_file(&log_path).unwrap();
+
+        // Each write goes to a key of its own, so a replica has as many keys as the writes up to
+        // the last sequence number it has if and only if it misses none of them.
+        let mut memtable = Memtable::open(log_path.clone(), &options).unwrap();
+        memtable.set_sequencer(Arc::new(Sequencer::new(0)));
+        let memtable = Arc::new(memtable);
+        let writers = (0..NUM_THREADS)
+            .map(|num| {
+                let memtable = memtable.clone();
+                std::thread::spawn(move || {
+                    for write_no in 0..NUM_WRITES {
+                        let key = format!("{}_{}", num, write_no);
+                        let mut writer = memtable.writer(&[key_hash(&key)]).unwrap();
+                        writer.set(key, write_no.to_string()).unwrap();
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        let replica = Memtable::open_read_only(log_path.clone(), &options).unwrap();
+        while writers.iter().any(|writer| !writer.is_finished()) {
+            replica.tail().unwrap();
+            let last_sequence_no = replica.sequence_range().map_or(0, |(_, max)| max);
+            assert_eq!(replica.iter().count() as u64, last_sequence_no);
+        }
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        replica.tail().unwrap();
+        assert_eq!(
+            replica.sequence_range(),
+            Some((1, NUM_THREADS * NUM_WRITES))
+        );
+
+        // A crash at any point of the log leaves the writes up to some sequence number.
+        memtable.sync().unwrap();
+        let bytes = std::fs::read(&log_path).unwrap();
+        let crashed_log_path = PathBuf::from("/tmp/test_memtable_log_order_crashed.log");
+        let options = Options {
+            log_recovery_mode: LogRecoveryMode::TruncateAndContinue,
+            ..options
+        };
+        for num_bytes in (0..bytes.len()).step_by(bytes.len() / 20 + 1) {
+            std::fs::write(&crashed_log_path, &bytes[..num_bytes]).unwrap();
+            let replayed = Memtable::open(crashed_log_path.clone(), &options).unwrap();
+            let last_sequence_no = replayed.sequence_range().map_or(0, |(_, max)| max);
+            assert_eq!(replayed.iter().count() as u64, last_sequence_no);
+            replayed.deprecate().unwrap();
+        }
+        memtable.deprecate().unwrap();
+    }
+
+    #[test]
+    fn test_memtable_replay() {
+        const NUM_WRITES: usize = 10 * REPLAY_BATCH_SIZE + 1; // Spread over many batches.
+
+        let options = Options {
+            num_background_threads: 4,
+            ..Options::default()
+        };
+        let log_path = PathBuf::from("/tmp/test_memtable_replay.log");
+        utils::try_remove_file(&log_path).unwrap();
+        let memtable = Memtable::open(log_path.clone(), &options).unwrap();
+        assert_eq!(memtable.replay_stats().num_records, 0);
+        let mut num_bytes = 0;
+        for num in 0..NUM_WRITES {
+            // Later batches overwrite earlier ones, so they must be applied in order.
+            let key = (num % 100).to_string();
+            num_bytes += match num % 7 {
+                0 => memtable.remove(key, num as u64 + 1).unwrap(),
+                _ => memtable.set(key, num.to_string(), num as u64 + 1).unwrap(),
+            };
+        }
+        let expected_data = memtable.freeze().unwrap();
+        let expected_data_size = memtable.data_size();
+        drop(memtable);
+
+        let memtable = Memtable::open(log_path, &options).unwrap();
+        memtable.deprecate().unwrap();
+        assert!(memtable.freeze().unwrap() == expected_data);
+        assert_eq!(memtable.data_size(), expected_data_size);
+        assert_eq!(memtable.replay_stats().num_records, NUM_WRITES);
+        assert_eq!(memtable.replay_stats().num_bytes, num_bytes);
+        assert_eq!(memtable.sequence_range(), Some((1, NUM_WRITES as u64)));
+    }
+
+    #[test]
+    fn test_memtable_tail() {
+        use std::io::Write;
+
+        let options = Options::default();
+        let log_path = PathBuf::from("/tmp/test_memtable_tail.log");
+        utils::try_remove_file(&log_path).unwrap();
+        let memtable = Memtable::open(log_path.clone(), &options).unwrap();
+        memtable.set("a".to_owned(), "1".to_owned(), 1).unwrap();
+
+        let replica = Memtable::open_read_only(log_path.clone(), &options).unwrap();
+        assert_eq!(
+            replica.get("a").unwrap(),
+            Some(Record::Value("1".to_owned()))
+        );
+        assert_eq!(replica.tail().unwrap(), 0);
+
+        memtable.set("b".to_owned(), "2".to_owned(), 2).unwrap();
+        memtable.remove("a".to_owned(), 3).unwrap();
+        assert_eq!(replica.tail().unwrap(), 2);
+        assert!(replica.freeze().unwrap() == memtable.freeze().unwrap());
+        assert_eq!(replica.data_size(), memtable.data_size());
+        assert_eq!(replica.sequence_range(), Some((1, 3)));
+
+        // A command partially written so far is left for the next time.
+        let command =
+            Record::Value("3".to_owned()).to_stamped_command("c".to_owned(), 4, None, true);
+        let mut bytes = Vec::new();
+        utils::write_message(&command, &mut bytes).unwrap();
+        let mut log_file = std::fs::OpenOptions::new()
+            .append(true)
+            .open(&log_path)
+            .unwrap();
+        log_file.write_all(&bytes[..bytes.len() - 1]).unwrap();
+        assert_eq!(replica.tail().unwrap(), 0);
+        log_file.write_all(&bytes[bytes.len() - 1..]).unwrap();
+        assert_eq!(replica.tail().unwrap(), 1);
+        assert_eq!(
+            replica.get("c").unwrap(),
+            Some(Record::Value("3".to_owned()))
+        );
+        drop(replica);
+        memtable.deprecate().unwrap();
+    }
+
+    #[test]
+    fn test_write_batch_replay() {
+        let options = Options::default();
+        let log_path = PathBuf::from("/tmp/test_write_batch_replay.log");
+        utils::try_remove_file(&log_path).unwrap();
+        let memtable = Memtable::open(log_path.clone(), &options).unwrap();
+        memtable.set("a".to_owned(), "1".to_owned(), 1).unwrap();
+        let replica = Memtable::open_read_only(log_path.clone(), &options).unwrap();
+        let logged_len = std::fs::metadata(&log_path).unwrap().len() as usize;
+
+        let mut batch = WriteBatch::new();
+        batch.set("b".to_owned(), "2".to_owned());
+        batch.remove("a".to_owned());
+        batch.set("c".to_owned(), "3".to_owned());
+        memtable.write_batch(&batch, 2).unwrap();
+        let expected_data = memtable.freeze().unwrap();
+        drop(memtable);
+
+        // The batch is tailed as a whole, being a single command in the log.
+        assert_eq!(replica.tail().unwrap(), 1);
+        assert!(replica.freeze().unwrap() == expected_data);
+        drop(replica);
+
+        // The batch is replayed as a whole.
+        let bytes = std::fs::read(&log_path).unwrap();
+        let memtable = Memtable::open(log_path.clone(), &options).unwrap();
+        assert!(memtable.freeze().unwrap() == expected_data);
+        assert_eq!(memtable.sequence_range(), Some((1, 2)));
+        drop(memtable);
+
+        // None of a batch torn in the middle is replayed.
+        let options = Options {
+            log_recovery_mode: LogRecoveryMode::TruncateAndContinue,
+            ..Options::default()
+        };
+        for torn_len in [logged_len + N_BYTES_CHUNK_LENGTH + 1, bytes.len() - 1] {
+            std::fs::write(&log_path, &bytes[..torn_len]).unwrap();
+            let memtable = Memtable::open(log_path.clone(), &options).unwrap();
+            assert_eq!(
+                memtable.get("a").unwrap(),
+                Some(Record::Value("1".to_owned()))
+            );
+            assert_eq!(memtable.get("b").unwrap(), None);
+            assert_eq!(memtable.get("c").unwrap(), None);
+            assert_eq!(memtable.sequence_range(), Some((1, 1)));
+            drop(memtable);
+        }
+        utils::try_remove_file(&log_path).unwrap();
+    }
+
+    #[derive(Debug, Default)]
+    struct RepairRecorder {
+        events: Mutex<Vec<RepairEvent>>,
+    }
+
+    impl crate::listener::EventListener for RepairRecorder {
+        fn on_repair(&self, event: &RepairEvent) {
+            self.events.lock().unwrap().push(event.clone());
+        }
+    }
+
+    #[test]
+    fn test_log_recovery_mode() {
+        let log_path = PathBuf::from("/tmp/test_memtable_recovery.log");
+
+        // Three records, the second of which is corrupted, and then a torn one.
+        let mut bytes = Vec::new();
+        let mut ends = Vec::new();
+        for (sequence_no, (key, value)) in [("a", "one"), ("b", "two"), ("c", "three")]
+            .into_iter()
+            .enumerate()
+        {
+            let command = Record::Value(value.to_owned()).to_stamped_command(
+                key.to_owned(),
+                sequence_no as u64 + 1,
+                None,
+                true,
+            );
+            utils::write_message(&command, &mut bytes).unwrap();
+            ends.push(bytes.len());
+        }
+        let corrupted_at = ends[0] + find(&bytes[ends[0]..ends[1]], b"two").unwrap();
+        bytes[corrupted_at] = b'T';
+        let torn_at = bytes.len();
+        let mut torn_bytes = Vec::new();
+        let command = Record::Deleted.to_stamped_command("a".to_owned(), 4, None, true);
+        utils::write_message(&command, &mut torn_bytes).unwrap();
+        bytes.extend_from_slice(&torn_bytes[..torn_bytes.len() - 1]);
+
+        let open = |recovery_mode| {
+            std::fs::write(&log_path, &bytes).unwrap();
+            let recorder = Arc::new(RepairRecorder::default());
+            let options = Options {
+                log_recovery_mode: recovery_mode,
+                event_listeners: vec![recorder.clone()],
+                ..Options::default()
+            };
+            let result = Memtable::open(log_path.clone(), &options);
+            let events = recorder.events.lock().unwrap().clone();
+            (result, events)
+        };
+        let value = |value: &str| Some(Record::Value(value.to_owned()));
+
+        let (result, events) = open(LogRecoveryMode::FailFast);
+        assert!(result.is_err());
+        assert!(events.is_empty());
+        assert_eq!(std::fs::read(&log_path).unwrap(), bytes);
+
+        // The log is cut off at the corrupted record, and new writes follow the ones kept.
+        let (result, events) = open(LogRecoveryMode::TruncateAndContinue);
+        let memtable = result.unwrap();
+        assert_eq!(memtable.get("a").unwrap(), value("one"));
+        assert_eq!(memtable.get("b").unwrap(), None);
+        assert_eq!(memtable.get("c").unwrap(), None);
+        assert_eq!(
+            events,
+            vec![RepairEvent::TruncatedMemtableLog {
+                log_path: log_path.clone(),
+                offset: ends[0] as u64,
+                num_bytes_dropped: (bytes.len() - ends[0]) as u64,
+            }]
+        );
+        memtable.set("d".to_owned(), "four".to_owned(), 4).unwrap();
+        drop(memtable);
+        let memtable = Memtable::open(log_path.clone(), &Options::default()).unwrap();
+        assert_eq!(memtable.get("d").unwrap(), value("four"));
+        drop(memtable);
+
+        // Only the corrupted record and the torn one are dropped.
+        let (result, events) = open(LogRecoveryMode::SkipCorrupted);
+        let memtable = result.unwrap();
+        assert_eq!(memtable.get("a").unwrap(), value("one"));
+        assert_eq!(memtable.get("b").unwrap(), None);
+        assert_eq!(memtable.get("c").unwrap(), value("three"));
+        assert_eq!(
+            events,
+            vec![
+                RepairEvent::SkippedLogRecord {
+                    log_path: log_path.clone(),
+                    offset: (ends[0] + N_BYTES_CHUNK_LENGTH) as u64,
+                },
+                RepairEvent::TruncatedMemtableLog {
+                    log_path: log_path.clone(),
+                    offset: torn_at as u64,
+                    num_bytes_dropped: (bytes.len() - torn_at) as u64,
+                },
+            ]
+        );
+        assert_eq!(std::fs::read(&log_path).unwrap(), &bytes[..torn_at]);
+        memtable.deprecate().unwrap();
+    }
+
+    fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+        haystack
+            .windows(needle.len())
+            .position(|window| window == needle)
+    }
 }
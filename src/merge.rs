@@ -0,0 +1,199 @@
+use std::fmt::Debug;
+
+use crate::types::{NaiveError, Record, Result, TimedRecord};
+
+/// Combines the merge operands written to a key with its value, so that appends or counters can
+/// be written blindly instead of reading the value first.
+///
+/// The operands pile up until a read or a compaction meets the value under them, or finds none.
+pub trait MergeOperator: Debug + Send + Sync {
+    /// Apply the operands, from the oldest to the newest, to the existing value of the key, which
+    /// is None if the key is absent or deleted.
+    fn full_merge(&self, key: &str, existing_value: Option<&str>, operands: &[String]) -> String;
+}
+
+/// Append each operand to the value, separated by the delimiter.
+#[derive(Debug)]
+pub struct StringAppendOperator {
+    pub delimiter: String,
+}
+
+impl MergeOperator for StringAppendOperator {
+    fn full_merge(&self, _key: &str, existing_value: Option<&str>, operands: &[String]) -> String {
+        existing_value
+            .into_iter()
+            .chain(operands.iter().map(String::as_str))
+            .collect::<Vec<_>>()
+            .join(&self.delimiter)
+    }
+}
+
+/// Put a record of the key on top of the older one, applying its merge operands, if any, to the
+/// older value or deletion, or piling them on top of the older operands.
+///
+/// The merged value expires along with the older one.
+pub(crate) fn stack(
+    key: &str,
+    newer: Record,
+    older: Record,
+    merge_operator: Option<&dyn MergeOperator>,
+) -> Result<Record> {
+    let operands = match newer {
+        Record::Merge(operands) => operands,
+        record => return Ok(record),
+    };
+    Ok(match older {
+        Record::Merge(mut older_operands) => {
+            older_operands.extend(operands);
+            Record::Merge(older_operands)
+        }
+        Record::Value(value) => {
+            Record::Value(full_merge(merge_operator, key, Some(&value), &operands)?)
+        }
+        Record::ExpiringValue {
+            value,
+            expires_at_ms,
+        } => Record::ExpiringValue {
+            value: full_merge(merge_operator, key, Some(&value), &operands)?,
+            expires_at_ms,
+        },
+        Record::Deleted => Record::Value(full_merge(merge_operator, key, None, &operands)?),
+    })
+}
+
+/// Apply the merge operands of a record, if any, to nothing, once no older record of the key is
+/// left.
+pub(crate) fn resolve(
+    key: &str,
+    record: Record,
+    merge_operator: Option<&dyn MergeOperator>,
+) -> Result<Record> {
+    match record {
+        Record::Merge(operands) => Ok(Record::Value(full_merge(
+            merge_operator,
+            key,
+            None,
+            &operands,
+        )?)),
+        record => Ok(record),
+    }
+}
+
+/// Stack a timed record on top of the older one like `stack`, keeping the time and the sequence
+/// number of the newer one.
+///
+/// The older value counts as missing if it had expired by the time of the newer write, so that
+/// the result does not depend on when or where the operands get applied.
+pub(crate) fn stack_timed(
+    key: &str,
+    newer: TimedRecord,
+    older: TimedRecord,
+    merge_operator: Option<&dyn MergeOperator>,
+) -> Result<TimedRecord> {
+    let older_record = match newer.timestamp_ms {
+        Some(timestamp_ms) => older.record.expire(timestamp_ms),
+        None => older.record,
+    };
+    Ok(TimedRecord {
+        record: stack(key, newer.record, older_record, merge_operator)?,
+        timestamp_ms: newer.timestamp_ms,
+        sequence_no: newer.sequence_no,
+    })
+}
+
+/// Resolve a timed record like `resolve`, keeping its time and its sequence number.
+pub(crate) fn resolve_timed(
+    key: &str,
+    timed_record: TimedRecord,
+    merge_operator: Option<&dyn MergeOperator>,
+) -> Result<TimedRecord> {
+    Ok(TimedRecord {
+        record: resolve(key, timed_record.record, merge_operator)?,
+        timestamp_ms: timed_record.timestamp_ms,
+        sequence_no: timed_record.sequence_no,
+    })
+}
+
+fn full_merge(
+    merge_operator: Option<&dyn MergeOperator>,
+    key: &str,
+    existing_value: Option<&str>,
+    operands: &[String],
+) -> Result<String> {
+    match merge_operator {
+        Some(merge_operator) => Ok(merge_operator.full_merge(key, existing_value, operands)),
+        None => Err(NaiveError::InvalidOptions(format!(
+            "merge operands of key {:?} found without a merge operator",
+            key
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stack() {
+        let merge_operator = StringAppendOperator {
+            delimiter: ",".to_owned(),
+        };
+        let operands = |operands: &[&str]| {
+            Record::Merge(operands.iter().map(|&operand| operand.to_owned()).collect())
+        };
+        let value = |value: &str| Record::Value(value.to_owned());
+        let stack = |newer, older| stack("key", newer, older, Some(&merge_operator)).unwrap();
+
+        // The operands pile up on top of each other until they meet a value or a deletion.
+        let record = stack(operands(&["c"]), operands(&["a", "b"]));
+        assert_eq!(record, operands(&["a", "b", "c"]));
+        assert_eq!(stack(record.clone(), value("x")), value("x,a,b,c"));
+        assert_eq!(stack(record.clone(), Record::Deleted), value("a,b,c"));
+        assert_eq!(
+            resolve("key", record.clone(), Some(&merge_operator)).unwrap(),
+            value("a,b,c")
+        );
+
+        // A value or a deletion hides whatever is under it.
+        assert_eq!(stack(Record::Deleted, record.clone()), Record::Deleted);
+        assert_eq!(stack(value("y"), record.clone()), value("y"));
+
+        // The operands cannot be applied without a merge operator.
+        assert!(matches!(
+            resolve("key", record, None),
+            Err(NaiveError::InvalidOptions(_))
+        ));
+
+        // The newer record keeps its time and its sequence number.
+        let timed = |record, timestamp_ms| TimedRecord {
+            record,
+            timestamp_ms: Some(timestamp_ms),
+            sequence_no: timestamp_ms,
+        };
+        assert_eq!(
+            stack_timed(
+                "key",
+                timed(operands(&["b"]), 2),
+                timed(value("a"), 1),
+                Some(&merge_operator)
+            )
+            .unwrap(),
+            timed(value("a,b"), 2)
+        );
+
+        // The older value counts as missing once it has expired by the time of the newer write.
+        let expiring = |value: &str, expires_at_ms| Record::ExpiringValue {
+            value: value.to_owned(),
+            expires_at_ms,
+        };
+        let stack_timed = |newer, older| stack_timed("key", newer, older, Some(&merge_operator));
+        assert_eq!(
+            stack_timed(timed(operands(&["b"]), 2), timed(expiring("a", 3), 1)).unwrap(),
+            timed(expiring("a,b", 3), 2)
+        );
+        assert_eq!(
+            stack_timed(timed(operands(&["b"]), 3), timed(expiring("a", 3), 1)).unwrap(),
+            timed(value("b"), 3)
+        );
+    }
+}
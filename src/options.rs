@@ -0,0 +1,515 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::backend::{Backend, LocalBackend};
+use crate::comparator::{self, BytewiseComparator, Comparator};
+use crate::fallback::ReadFallback;
+use crate::io_scheduler::{IoScheduler, ScheduledBackend};
+use crate::listener::EventListener;
+use crate::manifest::Manifest;
+use crate::merge::MergeOperator;
+use crate::prefix::PrefixExtractor;
+use crate::scheduler::JobSchedule;
+use crate::types::{NaiveError, Result};
+
+// The defaults of the options, shared with the flags of the server.
+pub const DEFAULT_MEMTABLE_COMPACTION_THRESHOLD: usize = 1 << 20; // 1MB
+pub const DEFAULT_MEMTABLE_SOFT_LIMIT_RATIO: f64 = 0.8;
+pub const DEFAULT_MEMTABLE_SHARDS: usize = 1;
+pub const DEFAULT_COMPACTION_BACKLOG_SOFT_LIMIT: usize = 64 << 20; // 64MB
+pub const DEFAULT_GENERATION_GEOMETRIC_RATIO: usize = 8;
+pub const DEFAULT_COMPACTION_DAEMON_CYCLE_S: u64 = 1;
+pub const DEFAULT_NUM_BACKGROUND_THREADS: usize = 2;
+pub const DEFAULT_BLOOM_FILTER_BITS_PER_KEY: usize = 10;
+pub const DEFAULT_TRASH_RETENTION_S: u64 = 0;
+pub const DEFAULT_TRASH_SIZE_CAP: usize = 1 << 30; // 1GB
+pub const DEFAULT_COLD_GENERATION_NO: usize = 3;
+pub const DEFAULT_SSTABLE_ERROR_THRESHOLD: usize = 3;
+pub const DEFAULT_INLINE_VALUES_CAPACITY: usize = 1 << 20; // 1MB
+pub const DEFAULT_KEY_RESTART_INTERVAL: usize = 1;
+pub const DEFAULT_INDEX_PARTITION_SIZE: usize = 0;
+pub const DEFAULT_MAX_RESIDENT_INDEX_PARTITIONS: usize = 16;
+pub const DEFAULT_READAHEAD_SIZE: usize = 256 << 10; // 256KB
+pub const DEFAULT_MIN_BLOB_LIVE_RATIO: f64 = 0.5;
+pub const DEFAULT_WATCH_CAPACITY: usize = 10_000;
+pub const DEFAULT_MINOR_MERGE_MIN_FILES: usize = 4;
+
+/// What to do about the corrupted records found while replaying a Memtable log on open.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum LogRecoveryMode {
+    /// Refuse to open, so that nothing is lost without a human looking at the log.
+    #[default]
+    FailFast,
+
+    /// Drop the first corrupted record and everything after it from the log, e.g. a write torn by
+    /// a crash, and open with the records before it.
+    TruncateAndContinue,
+
+    /// Drop each corrupted record alone and go on with the ones after it, trading consistency for
+    /// availability, e.g. on a dev box. A torn write at the end is dropped like the truncation.
+    SkipCorrupted,
+}
+
+/// The tunable parameters of the storage engine.
+#[derive(Clone, Debug)]
+pub struct Options {
+    /// Compact the Memtable once its data size reaches this number of bytes.
+    pub memtable_compaction_threshold: usize,
+
+    /// Warn the event listeners once the Memtable reaches this fraction of the compaction
+    /// threshold.
+    pub memtable_soft_limit_ratio: f64,
+
+    /// The number of shards the writes to the Memtable are split into by the hashes of their
+    /// keys, each taking its writes in turns, so that the writes of keys in different shards are
+    /// applied at once. The writes still append to the log one at a time, taking their sequence
+    /// numbers in the log order, and the readers only see them once the ones before are applied.
+    /// This relies on the comparator telling apart any two different keys.
+    pub memtable_shards: usize,
+
+    /// Warn the event listeners once the pending compactions would write more than this number
+    /// of bytes.
+    pub compaction_backlog_soft_limit: usize,
+
+    /// The ratio between the size thresholds of two adjacent generations.
+    pub generation_geometric_ratio: usize,
+
+    /// The interval in seconds between two runs of each background job, unless overridden.
+    pub compaction_daemon_cycle_s: u64,
+
+    /// The schedules of the background jobs by name, e.g. "merge", overriding the default one.
+    pub job_schedules: HashMap<String, JobSchedule>,
+
+    /// If set, the number of generations is capped to bound the read amplification: the last
+    /// generation allowed grows without limit, and any generations beyond it are merged into it.
+    /// Values below 2 are treated as 2.
+    pub max_generations: Option<usize>,
+
+    /// If positive, the merges split each generation they write, other than generation 0, into
+    /// SSTables of about this number of bytes of keys and values, partitioned by key range, so
+    /// that a merge only rewrites the partitions of the next generation its keys overlap with.
+    /// Zero keeps each generation in a single SSTable.
+    pub generation_partition_size: usize,
+
+    /// If positive, once this many partitions in a row of a generation each hold less than a
+    /// quarter of `generation_partition_size`, e.g. the remainders of the merges into it, they
+    /// are merged together in place, so that the gets do not probe ever more small files. Zero
+    /// leaves them alone.
+    pub minor_merge_min_files: usize,
+
+    /// The number of threads dedicated to background work such as compaction.
+    pub num_background_threads: usize,
+
+    /// The order of keys, which must not change once the data folder is created.
+    pub comparator: &'static dyn Comparator,
+
+    /// If set, the merge operands written by `CatalogViewer::merge` are combined with the values
+    /// by it. It must be set to read a data folder with any merge operands in it.
+    pub merge_operator: Option<Arc<dyn MergeOperator>>,
+
+    /// If set, each SSTable keeps a Bloom filter of the key prefixes for skipping it in prefix
+    /// scans.
+    pub prefix_extractor: Option<&'static dyn PrefixExtractor>,
+
+    /// If set, the gets and the increments of the keys never written locally to the default
+    /// column family fall back to it, e.g. for migrating the data lazily from another cluster.
+    /// The deleted or expired keys do not fall back, and neither do the scans or the snapshots.
+    pub read_fallback: Option<Arc<dyn ReadFallback>>,
+
+    /// Whether to write the values found by the read fallback locally, so that each key is fetched
+    /// from it at most once. Replicas never do.
+    pub backfill_reads: bool,
+
+    /// The number of bits per distinct prefix in the prefix Bloom filters.
+    pub bloom_filter_bits_per_key: usize,
+
+    /// If positive, each SSTable keeps the records of the values up to this number of bytes, and of
+    /// the deletions, in memory along with its index, so that the gets of them never read the
+    /// segment file.
+    pub inline_value_size: usize,
+
+    /// The number of bytes of the keys and the values each SSTable keeps in memory at most, taken
+    /// by the smallest keys first.
+    pub inline_values_capacity: usize,
+
+    /// Whether the gets read the segment files through memory maps, if the backend can map them,
+    /// rather than through a file cursor of each viewer, so that they neither open the files nor
+    /// seek in them.
+    pub mmap_reads: bool,
+
+    /// The most bytes a sequential read of a segment file, e.g. by a merge or a scan, reads ahead
+    /// at once. The reads ahead start small and double as long as the reads stay sequential.
+    pub readahead_size: usize,
+
+    /// Every this number of keys in a chunk of a segment file, starting from the first one, is a
+    /// restart point stored in full, while each key in between is stored as the suffix after the
+    /// prefix it shares with the key before it, which shrinks the chunks of keys with long common
+    /// prefixes. One stores all the keys in full, which the engines before the format version 2
+    /// of the segment files can read as well.
+    pub key_restart_interval: usize,
+
+    /// The number of chunks in each partition of the index of an SSTable, or zero to keep the
+    /// whole index in memory. The index of an SSTable with more chunks is split into partitions,
+    /// which are loaded from the segment file on demand by reading through their chunks again, so
+    /// this only pays off for the SSTables whose indexes outgrow the memory.
+    pub index_partition_size: usize,
+
+    /// The number of index partitions each SSTable keeps in memory at most, the least recently
+    /// used ones being dropped first.
+    pub max_resident_index_partitions: usize,
+
+    /// If positive, the flushes and the merges store the values of at least this number of bytes
+    /// in blob files rather than in the segment files, which only keep references to them, so
+    /// that the merges carry the references along instead of rewriting the values. A blob file
+    /// is removed once no SSTable refers to it any more.
+    pub min_blob_size: usize,
+
+    /// A bottom merge reads back the values it carries from a blob file of which its inputs refer
+    /// to less than this ratio of the bytes, and stores them anew, so that a blob file holding
+    /// mostly values overwritten or removed is freed. Zero carries all the values over.
+    pub min_blob_live_ratio: f64,
+
+    /// Whether to store a checksum with each record written, which is verified on reads.
+    pub checksum_records: bool,
+
+    /// Whether to verify each SSTable through `SSTable::verify` on open and fail the open with
+    /// Corruption on any problem found, rather than on the first read of it. This reads every
+    /// segment file through, which slows down the opens.
+    pub paranoid_checks: bool,
+
+    /// Quarantine an SSTable once this number of reads from it in a row fail with I/O errors or
+    /// corrupted data, so that the gets needing it fail fast, or never if zero. The quarantine
+    /// lasts until the data folder is reopened.
+    pub sstable_error_threshold: usize,
+
+    /// Whether to create the data folder on open if it holds no data folder yet. Otherwise the open
+    /// fails with FolderNotFound, e.g. on a mistyped path rather than starting empty.
+    pub create_if_missing: bool,
+
+    /// Whether to fail the open with FolderExists if the folder holds a data folder already, e.g.
+    /// to bootstrap a new store without attaching to an old one by mistake.
+    pub error_if_exists: bool,
+
+    /// Whether to repair an inconsistent data folder on open rather than failing, e.g. after a
+    /// crash in the middle of a compaction.
+    pub repair_on_open: bool,
+
+    /// What to do about the corrupted records in the Memtable logs on open. Each record dropped
+    /// is reported to the event listeners as a repair.
+    pub log_recovery_mode: LogRecoveryMode,
+
+    /// Whether to refuse to open a data folder whose Memtable log does not pick up where the
+    /// flushed data left off, which suggests lost files, rather than just logging a warning.
+    pub fail_on_sequence_gap: bool,
+
+    /// The listeners notified of the events in the storage engine.
+    pub event_listeners: Vec<Arc<dyn EventListener>>,
+
+    /// The most events a watch buffers until received, beyond which it is disconnected rather
+    /// than hold up the writes or grow without bound.
+    pub watch_capacity: usize,
+
+    /// How long in seconds the deprecated files are kept in the `trash/` subdirectory, or zero to
+    /// remove them right away.
+    pub trash_retention_s: u64,
+
+    /// The oldest files in the trash are removed once its total size exceeds this number of bytes.
+    pub trash_size_cap: usize,
+
+    /// How long in seconds the SSTables retired by the compactions, along with their files, are
+    /// kept for the historical views of `NaiveKV::snapshot_at_epoch`, or zero not to keep them.
+    pub epoch_retention_s: u64,
+
+    /// Where the files of the data folder are stored, the local file system by default.
+    pub backend: Arc<dyn Backend>,
+
+    /// If set, the file reads and writes of the engine go through it, so that the ones of the
+    /// background jobs, e.g. compactions, give way to the ones serving the clients.
+    pub io_scheduler: Option<Arc<IoScheduler>>,
+
+    /// If set, an absolute path to a second, cheaper and slower directory for the SSTables of the
+    /// oldest generations, e.g. on an HDD while the data folder is on an NVMe drive.
+    pub cold_folder_path: Option<PathBuf>,
+
+    /// The first generation written into the cold folder. An SSTable stays where it is until a
+    /// compaction rewrites it, so changing this takes effect gradually.
+    pub cold_generation_no: usize,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            memtable_compaction_threshold: DEFAULT_MEMTABLE_COMPACTION_THRESHOLD,
+            memtable_soft_limit_ratio: DEFAULT_MEMTABLE_SOFT_LIMIT_RATIO,
+            memtable_shards: DEFAULT_MEMTABLE_SHARDS,
+            compaction_backlog_soft_limit: DEFAULT_COMPACTION_BACKLOG_SOFT_LIMIT,
+            generation_geometric_ratio: DEFAULT_GENERATION_GEOMETRIC_RATIO,
+            compaction_daemon_cycle_s: DEFAULT_COMPACTION_DAEMON_CYCLE_S,
+            job_schedules: HashMap::new(),
+            max_generations: None,
+            generation_partition_size: 0,
+            minor_merge_min_files: DEFAULT_MINOR_MERGE_MIN_FILES,
+            num_background_threads: DEFAULT_NUM_BACKGROUND_THREADS,
+            comparator: &BytewiseComparator,
+            merge_operator: None,
+            prefix_extractor: None,
+            read_fallback: None,
+            backfill_reads: false,
+            bloom_filter_bits_per_key: DEFAULT_BLOOM_FILTER_BITS_PER_KEY,
+            inline_value_size: 0,
+            inline_values_capacity: DEFAULT_INLINE_VALUES_CAPACITY,
+            mmap_reads: false,
+            readahead_size: DEFAULT_READAHEAD_SIZE,
+            key_restart_interval: DEFAULT_KEY_RESTART_INTERVAL,
+            index_partition_size: DEFAULT_INDEX_PARTITION_SIZE,
+            max_resident_index_partitions: DEFAULT_MAX_RESIDENT_INDEX_PARTITIONS,
+            min_blob_size: 0,
+            min_blob_live_ratio: DEFAULT_MIN_BLOB_LIVE_RATIO,
+            checksum_records: false,
+            paranoid_checks: false,
+            sstable_error_threshold: DEFAULT_SSTABLE_ERROR_THRESHOLD,
+            create_if_missing: true,
+            error_if_exists: false,
+            repair_on_open: false,
+            log_recovery_mode: LogRecoveryMode::default(),
+            fail_on_sequence_gap: false,
+            event_listeners: Vec::new(),
+            watch_capacity: DEFAULT_WATCH_CAPACITY,
+            trash_retention_s: DEFAULT_TRASH_RETENTION_S,
+            trash_size_cap: DEFAULT_TRASH_SIZE_CAP,
+            epoch_retention_s: 0,
+            backend: Arc::new(LocalBackend),
+            io_scheduler: None,
+            cold_folder_path: None,
+            cold_generation_no: DEFAULT_COLD_GENERATION_NO,
+        }
+    }
+}
+
+impl Options {
+    /// Check the combinations of the options that the engine cannot work with, so that they fail
+    /// on open with a description rather than obscurely later on.
+    pub fn validate(&self) -> Result<()> {
+        let check = |is_valid: bool, description: String| {
+            if is_valid {
+                Ok(())
+            } else {
+                Err(NaiveError::InvalidOptions(description))
+            }
+        };
+        check(
+            self.memtable_compaction_threshold > 0,
+            "memtable_compaction_threshold must be positive".to_owned(),
+        )?;
+        check(
+            self.memtable_soft_limit_ratio > 0.0 && self.memtable_soft_limit_ratio <= 1.0,
+            format!(
+                "memtable_soft_limit_ratio must be in (0, 1] but is {}",
+                self.memtable_soft_limit_ratio
+            ),
+        )?;
+        check(
+            (0.0..=1.0).contains(&self.min_blob_live_ratio),
+            format!(
+                "min_blob_live_ratio must be in [0, 1] but is {}",
+                self.min_blob_live_ratio
+            ),
+        )?;
+        check(
+            self.memtable_shards > 0,
+            "memtable_shards must be positive".to_owned(),
+        )?;
+        check(
+            self.watch_capacity > 0,
+            "watch_capacity must be positive".to_owned(),
+        )?;
+        check(
+            self.generation_geometric_ratio >= 2,
+            format!(
+                "generation_geometric_ratio must be at least 2 but is {}",
+                self.generation_geometric_ratio
+            ),
+        )?;
+        check(
+            self.minor_merge_min_files != 1,
+            "minor_merge_min_files must not be 1".to_owned(),
+        )?;
+        check(
+            self.compaction_daemon_cycle_s > 0,
+            "compaction_daemon_cycle_s must be positive".to_owned(),
+        )?;
+        for (name, schedule) in self.job_schedules.iter() {
+            check(
+                !schedule.interval.is_zero(),
+                format!("the interval of job {} must be positive", name),
+            )?;
+        }
+        check(
+            self.num_background_threads > 0,
+            "num_background_threads must be positive".to_owned(),
+        )?;
+        check(
+            self.key_restart_interval > 0,
+            "key_restart_interval must be positive".to_owned(),
+        )?;
+        check(
+            self.index_partition_size == 0 || self.max_resident_index_partitions > 0,
+            "max_resident_index_partitions must be positive with a partitioned index".to_owned(),
+        )?;
+        check(
+            self.prefix_extractor.is_none() || self.bloom_filter_bits_per_key > 0,
+            "bloom_filter_bits_per_key must be positive with a prefix extractor".to_owned(),
+        )?;
+        if let Some(cold_folder_path) = self.cold_folder_path.as_ref() {
+            check(
+                cold_folder_path.is_absolute(),
+                format!(
+                    "cold_folder_path must be absolute but is {}",
+                    cold_folder_path.display()
+                ),
+            )?;
+            // Generation 0 is rewritten on every flush.
+            check(
+                self.cold_generation_no > 0,
+                "cold_generation_no must be positive with a cold folder".to_owned(),
+            )?;
+        }
+        Ok(())
+    }
+
+    /// The options with the backend wrapped to go through the I/O scheduler, if any.
+    pub(crate) fn with_scheduled_backend(self) -> Self {
+        match self.io_scheduler.clone() {
+            Some(io_scheduler) => Self {
+                backend: Arc::new(ScheduledBackend::new(self.backend.clone(), io_scheduler)),
+                ..self
+            },
+            None => self,
+        }
+    }
+
+    /// The default options with the comparator a data folder is recorded to be ordered by, for
+    /// the tools opening data folders they have not created. The folders without the record keep
+    /// the default comparator, which their SSTables are checked against on open.
+    pub fn for_folder(folder_path: impl AsRef<Path>) -> Result<Self> {
+        let mut options = Self::default();
+        let manifest = Manifest::load(options.backend.as_ref(), folder_path.as_ref())?;
+        if let Some(comparator_name) = manifest.and_then(|manifest| manifest.comparator_name) {
+            options.comparator =
+                comparator::builtin_comparator(&comparator_name).ok_or_else(|| {
+                    NaiveError::ComparatorMismatch(format!(
+                        "the data folder is ordered by {} which is not built in",
+                        comparator_name
+                    ))
+                })?;
+        }
+        Ok(options)
+    }
+
+    /// The schedule of the background job with the name.
+    pub fn job_schedule(&self, name: &str) -> JobSchedule {
+        self.job_schedules.get(name).copied().unwrap_or_else(|| {
+            JobSchedule::every(Duration::from_secs(self.compaction_daemon_cycle_s))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Options;
+    use crate::backend::LocalBackend;
+    use crate::comparator::NumericComparator;
+    use crate::manifest::Manifest;
+    use crate::scheduler::JobSchedule;
+    use crate::types::NaiveError;
+    use crate::NaiveKV;
+    use std::path::Path;
+    use std::time::Duration;
+
+    #[test]
+    fn test_validate() {
+        assert!(Options::default().validate().is_ok());
+
+        let invalid_options = vec![
+            Options {
+                generation_geometric_ratio: 1,
+                ..Options::default()
+            },
+            Options {
+                memtable_soft_limit_ratio: 1.5,
+                ..Options::default()
+            },
+            Options {
+                min_blob_live_ratio: -0.5,
+                ..Options::default()
+            },
+            Options {
+                compaction_daemon_cycle_s: 0,
+                ..Options::default()
+            },
+            Options {
+                watch_capacity: 0,
+                ..Options::default()
+            },
+            Options {
+                minor_merge_min_files: 1,
+                ..Options::default()
+            },
+            Options {
+                job_schedules: vec![("merge".to_owned(), JobSchedule::every(Duration::ZERO))]
+                    .into_iter()
+                    .collect(),
+                ..Options::default()
+            },
+            Options {
+                cold_folder_path: Some("cold".into()),
+                ..Options::default()
+            },
+            Options {
+                cold_folder_path: Some("/tmp/naive_kv/cold".into()),
+                cold_generation_no: 0,
+                ..Options::default()
+            },
+        ];
+        for options in invalid_options {
+            assert!(matches!(
+                options.validate(),
+                Err(NaiveError::InvalidOptions(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_for_folder() {
+        const FOLDER_PATH: &str = "/tmp/naive_kv/test_options_for_folder/";
+
+        let _ = std::fs::remove_dir_all(FOLDER_PATH);
+        let options = Options::for_folder(FOLDER_PATH).unwrap();
+        assert_eq!(options.comparator.name(), "naive_kv.BytewiseComparator");
+
+        let options = Options {
+            comparator: &NumericComparator,
+            ..Options::default()
+        };
+        NaiveKV::open(FOLDER_PATH, options)
+            .unwrap()
+            .close()
+            .unwrap();
+        let options = Options::for_folder(FOLDER_PATH).unwrap();
+        assert_eq!(options.comparator.name(), "naive_kv.NumericComparator");
+
+        let mut manifest = Manifest::load(&LocalBackend, Path::new(FOLDER_PATH))
+            .unwrap()
+            .unwrap();
+        manifest.comparator_name = Some("custom".to_owned());
+        manifest
+            .save(&LocalBackend, Path::new(FOLDER_PATH))
+            .unwrap();
+        assert!(matches!(
+            Options::for_folder(FOLDER_PATH),
+            Err(NaiveError::ComparatorMismatch(_))
+        ));
+    }
+}
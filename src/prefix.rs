@@ -0,0 +1,59 @@
+use std::fmt::Debug;
+
+/// Extracts the prefixes of keys for the prefix Bloom filters of SSTables.
+///
+/// Every key starting with a string in the domain must have the same prefix as that string, so
+/// that the filters can also answer whether any key starts with that string.
+pub trait PrefixExtractor: Debug + Send + Sync {
+    /// The prefix of the key, or None if the key is out of the domain of this extractor.
+    fn prefix<'a>(&self, key: &'a str) -> Option<&'a str>;
+}
+
+/// Take the first few characters as the prefix, ignoring the keys shorter than that.
+#[derive(Debug)]
+pub struct FixedPrefixExtractor {
+    pub len: usize,
+}
+
+impl PrefixExtractor for FixedPrefixExtractor {
+    fn prefix<'a>(&self, key: &'a str) -> Option<&'a str> {
+        match key.char_indices().nth(self.len) {
+            Some((end, _)) => Some(&key[..end]),
+            None if key.chars().count() == self.len => Some(key),
+            None => None,
+        }
+    }
+}
+
+/// Take everything up to and including the first delimiter as the prefix, e.g. "user42:" out of
+/// "user42:email", ignoring the keys without the delimiter.
+#[derive(Debug)]
+pub struct DelimiterPrefixExtractor {
+    pub delimiter: char,
+}
+
+impl PrefixExtractor for DelimiterPrefixExtractor {
+    fn prefix<'a>(&self, key: &'a str) -> Option<&'a str> {
+        key.find(self.delimiter)
+            .map(|pos| &key[..pos + self.delimiter.len_utf8()])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prefix_extractors() {
+        let fixed = FixedPrefixExtractor { len: 3 };
+        assert_eq!(fixed.prefix("abcd"), Some("abc"));
+        assert_eq!(fixed.prefix("abc"), Some("abc"));
+        assert_eq!(fixed.prefix("ab"), None);
+        assert_eq!(fixed.prefix("äöüß"), Some("äöü"));
+
+        let delimited = DelimiterPrefixExtractor { delimiter: ':' };
+        assert_eq!(delimited.prefix("user42:email"), Some("user42:"));
+        assert_eq!(delimited.prefix("user42:"), Some("user42:"));
+        assert_eq!(delimited.prefix("user42"), None);
+    }
+}
@@ -1 +1,2 @@
+#[allow(warnings)]
 pub mod messages;
@@ -0,0 +1,207 @@
+use std::ops::{Bound, RangeBounds};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Instant;
+
+use crate::comparator::Comparator;
+use crate::types::{NaiveError, Result};
+
+/// How a range lock shares its key range with the other locks.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum LockMode {
+    /// Shared with the other shared locks, e.g. for a backup reading a prefix.
+    Shared,
+    /// Held alone, e.g. for a bulk import or a range deletion rewriting the range.
+    Exclusive,
+}
+
+/// A lock over a key range, requested or held.
+#[derive(Debug)]
+struct RangeLock {
+    id: u64,
+    start: Bound<String>,
+    end: Bound<String>,
+    mode: LockMode,
+    is_held: bool,
+}
+
+#[derive(Debug, Default)]
+struct LockTable {
+    next_id: u64,
+
+    /// The locks held and the requests waiting, in the order they were requested.
+    locks: Vec<RangeLock>,
+}
+
+/// Advisory locks over key ranges, which let the bulk operations on overlapping ranges take
+/// turns without freezing all the writes. Point writes never take them.
+///
+/// The requests are granted in order, so that an exclusive lock does not starve behind a stream
+/// of shared locks overlapping it.
+#[derive(Debug)]
+pub struct RangeLocks {
+    comparator: &'static dyn Comparator,
+    table: Mutex<LockTable>,
+    released: Condvar,
+}
+
+impl RangeLocks {
+    pub fn new(comparator: &'static dyn Comparator) -> Self {
+        Self {
+            comparator,
+            table: Mutex::new(LockTable::default()),
+            released: Condvar::new(),
+        }
+    }
+
+    /// Lock the key range in the mode, waiting until no overlapping lock held or requested before
+    /// conflicts with it, or giving up with DeadlineExceeded once the deadline, if any, passes.
+    ///
+    /// The lock is released once the guard drops.
+    pub fn lock<R: RangeBounds<String>>(
+        self: &Arc<Self>,
+        range: R,
+        mode: LockMode,
+        deadline: Option<Instant>,
+    ) -> Result<RangeLockGuard> {
+        let mut table = self.table.lock()?;
+        let id = table.next_id;
+        table.next_id += 1;
+        table.locks.push(RangeLock {
+            id,
+            start: range.start_bound().cloned(),
+            end: range.end_bound().cloned(),
+            mode,
+            is_held: false,
+        });
+        loop {
+            let index = table.locks.iter().position(|lock| lock.id == id).unwrap();
+            if self.can_hold(&table.locks, index) {
+                table.locks[index].is_held = true;
+                return Ok(RangeLockGuard {
+                    range_locks: self.clone(),
+                    id,
+                });
+            }
+            table = match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        table.locks.remove(index);
+                        // The requests queued behind this one may go ahead now.
+                        self.released.notify_all();
+                        return Err(NaiveError::DeadlineExceeded);
+                    }
+                    self.released
+                        .wait_timeout(table, deadline - now)
+                        .map_err(|_| NaiveError::MutexLockError)?
+                        .0
+                }
+                None => self.released.wait(table)?,
+            };
+        }
+    }
+
+    /// Whether the lock at the index conflicts with none of the locks held or requested before.
+    fn can_hold(&self, locks: &[RangeLock], index: usize) -> bool {
+        let lock = &locks[index];
+        locks
+            .iter()
+            .enumerate()
+            .filter(|&(other_index, other)| other_index < index || other.is_held)
+            .all(|(other_index, other)| other_index == index || !self.conflicts(lock, other))
+    }
+
+    fn conflicts(&self, a: &RangeLock, b: &RangeLock) -> bool {
+        if a.mode == LockMode::Shared && b.mode == LockMode::Shared {
+            return false;
+        }
+        let is_empty = |start: &Bound<String>, end: &Bound<String>| {
+            self.comparator
+                .is_empty_range(&(start.as_ref(), end.as_ref()))
+        };
+        // Two ranges overlap unless one of them ends before the other starts.
+        !is_empty(&a.start, &a.end)
+            && !is_empty(&b.start, &b.end)
+            && !is_empty(&a.start, &b.end)
+            && !is_empty(&b.start, &a.end)
+    }
+
+    fn release(&self, id: u64) -> Result<()> {
+        self.table.lock()?.locks.retain(|lock| lock.id != id);
+        self.released.notify_all();
+        Ok(())
+    }
+}
+
+/// A range lock held until dropped.
+#[derive(Debug)]
+pub struct RangeLockGuard {
+    range_locks: Arc<RangeLocks>,
+    id: u64,
+}
+
+impl Drop for RangeLockGuard {
+    fn drop(&mut self) {
+        if let Err(error) = self.range_locks.release(self.id) {
+            log::error!("Failed to release range lock {}: {:?}", self.id, error);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::comparator::BytewiseComparator;
+    use std::time::Duration;
+
+    #[test]
+    fn test_range_locks() {
+        let range_locks = Arc::new(RangeLocks::new(&BytewiseComparator));
+        let soon = || Some(Instant::now() + Duration::from_millis(50));
+        let key = |key: &str| key.to_owned();
+
+        // The shared locks overlap each other, but not an exclusive one.
+        let shared = range_locks
+            .lock(key("a")..key("m"), LockMode::Shared, None)
+            .unwrap();
+        let _other_shared = range_locks
+            .lock(key("k")..key("z"), LockMode::Shared, None)
+            .unwrap();
+        assert!(matches!(
+            range_locks.lock(key("l")..=key("l"), LockMode::Exclusive, soon()),
+            Err(NaiveError::DeadlineExceeded)
+        ));
+
+        // The ranges touching at an excluded bound do not overlap.
+        let _adjacent = range_locks
+            .lock(key("z").., LockMode::Exclusive, None)
+            .unwrap();
+        assert!(matches!(
+            range_locks.lock(..=key("a"), LockMode::Exclusive, soon()),
+            Err(NaiveError::DeadlineExceeded)
+        ));
+
+        // A waiting exclusive lock holds back the shared locks requested after it.
+        let waiter = {
+            let range_locks = range_locks.clone();
+            std::thread::spawn(move || {
+                range_locks
+                    .lock(key("b")..key("c"), LockMode::Exclusive, None)
+                    .map(|_| ())
+            })
+        };
+        // Wait for the exclusive request to queue up behind the three locks held.
+        while range_locks.table.lock().unwrap().locks.len() < 4 {
+            std::thread::yield_now();
+        }
+        assert!(matches!(
+            range_locks.lock(key("b")..key("c"), LockMode::Shared, soon()),
+            Err(NaiveError::DeadlineExceeded)
+        ));
+        drop(shared);
+        waiter.join().unwrap().unwrap();
+        assert!(range_locks
+            .lock(key("b")..key("c"), LockMode::Shared, soon())
+            .is_ok());
+    }
+}
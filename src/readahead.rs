@@ -0,0 +1,176 @@
+use std::io::Read;
+
+use crate::backend::BackendFile;
+
+/// The number of bytes a read ahead starts with, and goes back to after a seek.
+const INITIAL_READAHEAD_SIZE: usize = 8 << 10; // 8KB
+
+/// A cursor over a file read by offset, which reads the bytes ahead of it into a buffer, twice as
+/// many each time the reads pick up right where the buffer ends, up to the maximum, so that a scan
+/// through the file takes ever fewer and larger reads, while a seek goes back to small ones.
+pub(crate) struct Readahead {
+    /// The bytes read ahead.
+    buffer: Vec<u8>,
+
+    /// The offset of the buffer in the file.
+    buffer_offset: u64,
+
+    /// The offset of the cursor in the file.
+    position: u64,
+
+    /// The number of bytes to read ahead next time.
+    readahead_size: usize,
+
+    /// The most bytes to read ahead at once.
+    max_readahead_size: usize,
+}
+
+impl Readahead {
+    pub(crate) fn new(position: u64, max_readahead_size: usize) -> Self {
+        let max_readahead_size = max_readahead_size.max(1);
+        Self {
+            buffer: Vec::new(),
+            buffer_offset: position,
+            position,
+            readahead_size: INITIAL_READAHEAD_SIZE.min(max_readahead_size),
+            max_readahead_size,
+        }
+    }
+
+    pub(crate) fn position(&self) -> u64 {
+        self.position
+    }
+
+    /// Move the cursor, keeping the bytes read ahead for when it lands among them.
+    pub(crate) fn seek(&mut self, position: u64) {
+        self.position = position;
+    }
+
+    /// A reader of the file from the cursor on, which moves the cursor along.
+    pub(crate) fn reader<'a>(&'a mut self, file: &'a dyn BackendFile) -> ReadaheadReader<'a> {
+        ReadaheadReader {
+            readahead: self,
+            file,
+        }
+    }
+
+    fn buffer_end(&self) -> u64 {
+        self.buffer_offset + self.buffer.len() as u64
+    }
+
+    /// Read ahead from the cursor, further than last time if the cursor is where the buffer ends.
+    fn fill(&mut self, file: &dyn BackendFile) -> std::io::Result<()> {
+        self.readahead_size = if self.position == self.buffer_end() && !self.buffer.is_empty() {
+            (self.readahead_size * 2).min(self.max_readahead_size)
+        } else {
+            INITIAL_READAHEAD_SIZE.min(self.max_readahead_size)
+        };
+        self.buffer.resize(self.readahead_size, 0u8);
+        let num_bytes = file.read_at(&mut self.buffer, self.position)?;
+        self.buffer.truncate(num_bytes);
+        self.buffer_offset = self.position;
+        Ok(())
+    }
+}
+
+/// Reads a file through the buffer of a `Readahead`.
+pub(crate) struct ReadaheadReader<'a> {
+    readahead: &'a mut Readahead,
+    file: &'a dyn BackendFile,
+}
+
+impl Read for ReadaheadReader<'_> {
+    fn read(&mut self, buffer: &mut [u8]) -> std::io::Result<usize> {
+        let readahead = &mut *self.readahead;
+        if readahead.position < readahead.buffer_offset
+            || readahead.position >= readahead.buffer_end()
+        {
+            // A read at least as large as the last read ahead skips the buffer.
+            if buffer.len() >= readahead.readahead_size {
+                let num_bytes = self.file.read_at(buffer, readahead.position)?;
+                readahead.position += num_bytes as u64;
+                return Ok(num_bytes);
+            }
+            readahead.fill(self.file)?;
+        }
+        let start = (readahead.position - readahead.buffer_offset) as usize;
+        let num_bytes = buffer.len().min(readahead.buffer.len() - start);
+        buffer[..num_bytes].copy_from_slice(&readahead.buffer[start..start + num_bytes]);
+        readahead.position += num_bytes as u64;
+        Ok(num_bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::{Backend, MemoryBackend};
+    use std::io::Write;
+    use std::path::Path;
+
+    #[test]
+    fn test_readahead() {
+        const MAX_READAHEAD_SIZE: usize = 64 << 10;
+
+        let backend = MemoryBackend::new();
+        backend.create_dir_all(Path::new("/memory")).unwrap();
+        let file_path = Path::new("/memory/file");
+        let bytes = (0..1 << 20).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        backend
+            .create_new(file_path)
+            .unwrap()
+            .write_all(&bytes)
+            .unwrap();
+        let file = backend.open(file_path).unwrap();
+
+        // The reads ahead grow while the reads stay sequential.
+        let mut readahead = Readahead::new(4, MAX_READAHEAD_SIZE);
+        let mut buffer = [0u8; 100];
+        let mut readahead_sizes = Vec::new();
+        while readahead.position() + 100 <= 200 << 10 {
+            let offset = readahead.position() as usize;
+            readahead
+                .reader(file.as_ref())
+                .read_exact(&mut buffer)
+                .unwrap();
+            assert_eq!(buffer, bytes[offset..offset + 100]);
+            readahead_sizes.push(readahead.readahead_size);
+        }
+        readahead_sizes.dedup();
+        assert_eq!(
+            readahead_sizes,
+            [8 << 10, 16 << 10, 32 << 10, MAX_READAHEAD_SIZE]
+        );
+
+        // A seek among the bytes read ahead reads nothing, while one elsewhere starts over.
+        let buffer_offset = readahead.buffer_offset;
+        readahead.seek(buffer_offset + 10);
+        readahead
+            .reader(file.as_ref())
+            .read_exact(&mut buffer)
+            .unwrap();
+        assert_eq!(readahead.buffer_offset, buffer_offset);
+        assert_eq!(readahead.readahead_size, MAX_READAHEAD_SIZE);
+        readahead.seek(4);
+        readahead
+            .reader(file.as_ref())
+            .read_exact(&mut buffer)
+            .unwrap();
+        assert_eq!(buffer, bytes[4..104]);
+        assert_eq!(readahead.readahead_size, 8 << 10);
+
+        // A large read skips the buffer, and the end of the file reads nothing.
+        let mut large_buffer = vec![0u8; 16 << 10];
+        readahead.seek(bytes.len() as u64 - (16 << 10));
+        readahead
+            .reader(file.as_ref())
+            .read_exact(&mut large_buffer)
+            .unwrap();
+        assert_eq!(large_buffer, bytes[bytes.len() - (16 << 10)..]);
+        assert_eq!(readahead.buffer_offset, 4);
+        assert_eq!(
+            readahead.reader(file.as_ref()).read(&mut buffer).unwrap(),
+            0
+        );
+    }
+}
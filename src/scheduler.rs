@@ -0,0 +1,391 @@
+use rand::{thread_rng, Rng};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::io_scheduler::{self, IoPriority};
+use crate::thread_pool::ThreadPool;
+use crate::types::{NaiveError, Result};
+
+/// The most times the interval of a job is doubled while its runs keep failing.
+const MAX_BACKOFF_DOUBLINGS: u32 = 6;
+
+/// The most errors of the failed runs kept for each job, beyond which the oldest are dropped.
+pub const MAX_KEPT_ERRORS: usize = 100;
+
+/// How often a background job runs.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct JobSchedule {
+    /// The time between the starts of two runs.
+    pub interval: Duration,
+
+    /// The most time each run is randomly delayed by beyond the interval, so that the jobs of
+    /// many instances on the same machine do not all wake up at once.
+    pub jitter: Duration,
+}
+
+impl JobSchedule {
+    pub fn every(interval: Duration) -> Self {
+        Self {
+            interval,
+            jitter: Duration::ZERO,
+        }
+    }
+
+    /// The time from now until the next run.
+    fn next_delay(&self) -> Duration {
+        if self.jitter.is_zero() {
+            return self.interval;
+        }
+        self.interval + thread_rng().gen_range(Duration::ZERO..=self.jitter)
+    }
+
+    /// The time from now until the next run after the given number of failed runs in a row, whose
+    /// interval doubles with each one up to a limit, so that a lasting error is not retried in a
+    /// tight loop.
+    fn backoff_delay(&self, num_consecutive_failures: u64) -> Duration {
+        let num_doublings = num_consecutive_failures.min(MAX_BACKOFF_DOUBLINGS as u64) as u32;
+        self.next_delay() + self.interval * (2u32.pow(num_doublings) - 1)
+    }
+}
+
+/// A snapshot of the state of a background job.
+#[derive(Clone, Debug, PartialEq)]
+pub struct JobStatus {
+    pub name: String,
+    pub schedule: JobSchedule,
+    pub is_paused: bool,
+    pub is_running: bool,
+
+    /// The runs completed so far, the failed ones included.
+    pub num_runs: u64,
+    pub num_failures: u64,
+
+    /// The runs failed since the last successful one, which push back the next run.
+    pub num_consecutive_failures: u64,
+
+    /// The runs skipped because the previous run was still in progress or the workers were busy.
+    pub num_skipped: u64,
+
+    /// How long the last completed run took, if any.
+    pub last_duration: Option<Duration>,
+
+    /// The error of the last run if it failed.
+    pub last_error: Option<String>,
+
+    /// The errors of the failed runs, the oldest first, of which the last `MAX_KEPT_ERRORS` are
+    /// kept however many runs succeed in between.
+    pub errors: Vec<String>,
+
+    /// The time until the next run, which is zero if it is overdue.
+    pub next_run_in: Duration,
+}
+
+type JobFunction = Box<dyn Fn() -> Result<()> + Send + Sync + 'static>;
+
+struct Job {
+    name: String,
+    function: JobFunction,
+    state: Mutex<JobState>,
+}
+
+struct JobState {
+    schedule: JobSchedule,
+    is_paused: bool,
+    is_running: bool,
+    next_run: Instant,
+    num_runs: u64,
+    num_failures: u64,
+    num_consecutive_failures: u64,
+    num_skipped: u64,
+    last_duration: Option<Duration>,
+    last_error: Option<String>,
+    errors: Vec<String>,
+}
+
+impl Job {
+    fn status(&self) -> Result<JobStatus> {
+        let state = self.state.lock()?;
+        Ok(JobStatus {
+            name: self.name.clone(),
+            schedule: state.schedule,
+            is_paused: state.is_paused,
+            is_running: state.is_running,
+            num_runs: state.num_runs,
+            num_failures: state.num_failures,
+            num_consecutive_failures: state.num_consecutive_failures,
+            num_skipped: state.num_skipped,
+            last_duration: state.last_duration,
+            last_error: state.last_error.clone(),
+            errors: state.errors.clone(),
+            next_run_in: state.next_run.saturating_duration_since(Instant::now()),
+        })
+    }
+
+    fn run(&self) {
+        let start_time = Instant::now();
+        let result = io_scheduler::with_priority(IoPriority::Background, || (self.function)());
+        if let Err(error) = result.as_ref() {
+            log::error!("Failed to run job {}: {:?}", self.name, error);
+        }
+        // The state is only unavailable if another run panicked while holding it.
+        if let Ok(mut state) = self.state.lock() {
+            state.is_running = false;
+            state.num_runs += 1;
+            state.last_duration = Some(start_time.elapsed());
+            state.last_error = result.err().map(|error| format!("{:?}", error));
+            if let Some(error) = state.last_error.clone() {
+                if state.errors.len() == MAX_KEPT_ERRORS {
+                    state.errors.remove(0);
+                }
+                state.errors.push(error);
+                state.num_failures += 1;
+                state.num_consecutive_failures += 1;
+                let backoff_delay = state.schedule.backoff_delay(state.num_consecutive_failures);
+                state.next_run = state.next_run.max(Instant::now() + backoff_delay);
+            } else {
+                state.num_consecutive_failures = 0;
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+struct SchedulerState {
+    jobs: Vec<Arc<Job>>,
+    is_stopped: bool,
+}
+
+/// Runs the registered jobs periodically on a dedicated thread pool, each at most one run at a
+/// time, until the scheduler is stopped or dropped.
+pub struct Scheduler {
+    state: Arc<(Mutex<SchedulerState>, Condvar)>,
+    ticker: Option<thread::JoinHandle<Result<()>>>,
+}
+
+impl Scheduler {
+    pub fn new(num_threads: usize) -> Self {
+        let state = Arc::new((Mutex::new(SchedulerState::default()), Condvar::new()));
+        let state_copy = state.clone();
+        let ticker = thread::spawn(move || {
+            // The pool is dropped, i.e. its workers are joined, when the ticker exits.
+            let workers = ThreadPool::new(num_threads.max(1));
+            let result = Self::tick(&state_copy, &workers);
+            if let Err(error) = result.as_ref() {
+                log::error!("The job scheduler stopped: {:?}", error);
+            }
+            result
+        });
+        Self {
+            state,
+            ticker: Some(ticker),
+        }
+    }
+
+    /// Start running a job periodically, first after one interval.
+    pub fn register(
+        &self,
+        name: &str,
+        schedule: JobSchedule,
+        function: impl Fn() -> Result<()> + Send + Sync + 'static,
+    ) -> Result<()> {
+        let (state, wakeup) = &*self.state;
+        let mut state = state.lock()?;
+        if state.jobs.iter().any(|job| job.name == name) {
+            log::error!("Job {} is already registered.", name);
+            return Err(NaiveError::InvalidData);
+        }
+        state.jobs.push(Arc::new(Job {
+            name: name.to_owned(),
+            function: Box::new(function),
+            state: Mutex::new(JobState {
+                schedule,
+                is_paused: false,
+                is_running: false,
+                next_run: Instant::now() + schedule.next_delay(),
+                num_runs: 0,
+                num_failures: 0,
+                num_consecutive_failures: 0,
+                num_skipped: 0,
+                last_duration: None,
+                last_error: None,
+                errors: Vec::new(),
+            }),
+        }));
+        wakeup.notify_all();
+        Ok(())
+    }
+
+    /// The jobs in the order they were registered.
+    pub fn jobs(&self) -> Result<Vec<JobStatus>> {
+        let (state, _) = &*self.state;
+        state.lock()?.jobs.iter().map(|job| job.status()).collect()
+    }
+
+    /// Stop scheduling new runs of the job, letting a run in progress finish.
+    pub fn pause(&self, name: &str) -> Result<JobStatus> {
+        self.update(name, |state| state.is_paused = true)
+    }
+
+    /// Resume a paused job, which next runs after one interval.
+    pub fn resume(&self, name: &str) -> Result<JobStatus> {
+        self.update(name, |state| {
+            if state.is_paused {
+                state.is_paused = false;
+                state.next_run = Instant::now() + state.schedule.next_delay();
+            }
+        })
+    }
+
+    /// Change how often the job runs, starting from the next run.
+    pub fn reschedule(&self, name: &str, schedule: JobSchedule) -> Result<JobStatus> {
+        self.update(name, |state| {
+            state.schedule = schedule;
+            state.next_run = Instant::now() + schedule.next_delay();
+        })
+    }
+
+    fn update(&self, name: &str, update: impl FnOnce(&mut JobState)) -> Result<JobStatus> {
+        let (state, wakeup) = &*self.state;
+        let state = state.lock()?;
+        let job = match state.jobs.iter().find(|job| job.name == name) {
+            Some(job) => job,
+            None => return Err(NaiveError::JobNotFound(name.to_owned())),
+        };
+        update(&mut *job.state.lock()?);
+        wakeup.notify_all();
+        job.status()
+    }
+
+    /// Stop scheduling the jobs and wait for the runs in progress to finish, returning the error
+    /// the scheduler has stopped with on its own, if any. The jobs can still be listed afterwards.
+    pub fn stop(&mut self) -> Result<()> {
+        let (state, wakeup) = &*self.state;
+        state.lock()?.is_stopped = true;
+        wakeup.notify_all();
+        match self.ticker.take() {
+            Some(ticker) => ticker.join().map_err(|_| NaiveError::Unknown)?,
+            None => Ok(()),
+        }
+    }
+
+    /// Start the due runs and then sleep until the next one is due, until stopped.
+    fn tick(state: &(Mutex<SchedulerState>, Condvar), workers: &ThreadPool) -> Result<()> {
+        let (state, wakeup) = state;
+        let mut state = state.lock()?;
+        while !state.is_stopped {
+            let now = Instant::now();
+            let mut next_tick = None::<Instant>;
+            for job in state.jobs.iter() {
+                let mut job_state = job.state.lock()?;
+                if job_state.is_paused {
+                    continue;
+                }
+                if job_state.next_run <= now {
+                    job_state.next_run = now + job_state.schedule.next_delay();
+                    if job_state.is_running {
+                        // Skip this run if the previous one is still in progress.
+                        job_state.num_skipped += 1;
+                    } else {
+                        let job_copy = job.clone();
+                        match workers.try_add_task(move || job_copy.run()) {
+                            Ok(()) => job_state.is_running = true,
+                            Err(NaiveError::TaskQueueFull) => job_state.num_skipped += 1,
+                            Err(error) => return Err(error),
+                        }
+                    }
+                }
+                next_tick = Some(next_tick.map_or(job_state.next_run, |next_tick| {
+                    next_tick.min(job_state.next_run)
+                }));
+            }
+            state = match next_tick {
+                Some(next_tick) => {
+                    wakeup
+                        .wait_timeout(state, next_tick.saturating_duration_since(now))
+                        .map_err(|_| NaiveError::MutexLockError)?
+                        .0
+                }
+                None => wakeup.wait(state)?,
+            };
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Scheduler {
+    fn drop(&mut self) {
+        // The error has been logged by the ticker, and can be caught by stopping explicitly.
+        let _ = self.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{JobSchedule, Scheduler};
+    use crate::types::{NaiveError, Result};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[test]
+    fn test_scheduler() {
+        let scheduler = Scheduler::new(2);
+        let num_runs = Arc::new(AtomicUsize::new(0));
+        let num_runs_copy = num_runs.clone();
+        scheduler
+            .register(
+                "count",
+                JobSchedule {
+                    interval: Duration::from_millis(20),
+                    jitter: Duration::from_millis(5),
+                },
+                move || {
+                    num_runs_copy.fetch_add(1, Ordering::SeqCst);
+                    Ok(())
+                },
+            )
+            .unwrap();
+        scheduler
+            .register(
+                "fail",
+                JobSchedule::every(Duration::from_millis(20)),
+                || Err(NaiveError::Unknown) as Result<()>,
+            )
+            .unwrap();
+        assert!(scheduler
+            .register("count", JobSchedule::every(Duration::from_secs(1)), || Ok(
+                ()
+            ))
+            .is_err());
+
+        std::thread::sleep(Duration::from_millis(300));
+        assert!(num_runs.load(Ordering::SeqCst) >= 3);
+        let jobs = scheduler.jobs().unwrap();
+        assert_eq!(jobs.len(), 2);
+        assert_eq!(jobs[0].name, "count");
+        assert_eq!(jobs[0].num_failures, 0);
+        // The failing job backs off rather than run as often.
+        assert!(jobs[1].num_runs >= 2);
+        assert!(jobs[1].num_runs < jobs[0].num_runs);
+        assert_eq!(jobs[1].num_failures, jobs[1].num_runs);
+        assert_eq!(jobs[1].num_consecutive_failures, jobs[1].num_runs);
+        assert_eq!(jobs[1].last_error, Some("Unknown".to_owned()));
+        assert_eq!(jobs[1].errors.len() as u64, jobs[1].num_failures);
+
+        // A paused job runs no more until resumed.
+        assert!(scheduler.pause("count").unwrap().is_paused);
+        std::thread::sleep(Duration::from_millis(50));
+        let num_paused_runs = num_runs.load(Ordering::SeqCst);
+        std::thread::sleep(Duration::from_millis(200));
+        assert_eq!(num_runs.load(Ordering::SeqCst), num_paused_runs);
+        assert!(!scheduler.resume("count").unwrap().is_paused);
+        std::thread::sleep(Duration::from_millis(200));
+        assert!(num_runs.load(Ordering::SeqCst) > num_paused_runs);
+
+        assert!(matches!(
+            scheduler.pause("missing"),
+            Err(NaiveError::JobNotFound(_))
+        ));
+    }
+}
@@ -0,0 +1,242 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Condvar, Mutex};
+
+use crate::types::Result;
+
+/// The sequence numbers of the writes of a catalog, shared by its successive Memtables, which
+/// take them in the order of their logs and make them visible to the readers once all the writes
+/// up to them are applied.
+pub(crate) struct Sequencer {
+    /// The sequence number of the last write taken.
+    last_sequence_no: AtomicU64,
+
+    /// The sequence numbers taken but not yet applied, which hold back the visibility of the later
+    /// ones.
+    writes_in_flight: Mutex<BTreeSet<u64>>,
+
+    /// The sequence number up to which all the writes are visible to the readers.
+    visible_sequence_no: AtomicU64,
+
+    /// Held by the readers waiting for visible_sequence_no to advance, and by the writers to
+    /// notify them, so that no notification falls in between a check and a wait.
+    visibility_lock: Mutex<()>,
+
+    /// Notified when visible_sequence_no advances.
+    visibility_changed: Condvar,
+
+    /// The sequence numbers the reads across many keys are pinned at, e.g. the frozen copies of
+    /// a Memtable, along with the number of reads at each.
+    pins: Mutex<BTreeMap<u64, usize>>,
+
+    /// The number of pins taken or being taken, checked by the writers without locking the pins.
+    num_pins: AtomicUsize,
+}
+
+impl Sequencer {
+    /// Start after the last write known, which is visible.
+    pub fn new(last_sequence_no: u64) -> Self {
+        Self {
+            last_sequence_no: AtomicU64::new(last_sequence_no),
+            writes_in_flight: Mutex::new(BTreeSet::new()),
+            visible_sequence_no: AtomicU64::new(last_sequence_no),
+            visibility_lock: Mutex::new(()),
+            visibility_changed: Condvar::new(),
+            pins: Mutex::new(BTreeMap::new()),
+            num_pins: AtomicUsize::new(0),
+        }
+    }
+
+    /// Take the sequence number of the next write, which is appended to the log before the next
+    /// one is taken, and must be finished or given back.
+    pub fn take(&self) -> Result<u64> {
+        let mut writes_in_flight = self.writes_in_flight.lock()?;
+        let sequence_no = self.last_sequence_no.fetch_add(1, Ordering::SeqCst) + 1;
+        writes_in_flight.insert(sequence_no);
+        Ok(sequence_no)
+    }
+
+    /// Give back the sequence number of a write that has failed to reach the log, which is the
+    /// last one taken, so that the sequence numbers in the logs leave no gaps.
+    pub fn give_back(&self, sequence_no: u64) -> Result<()> {
+        let mut writes_in_flight = self.writes_in_flight.lock()?;
+        writes_in_flight.remove(&sequence_no);
+        let _ = self.last_sequence_no.compare_exchange(
+            sequence_no,
+            sequence_no - 1,
+            Ordering::SeqCst,
+            Ordering::SeqCst,
+        );
+        Ok(())
+    }
+
+    /// Finish the write of the sequence number once applied, and make visible the writes up to
+    /// the first one still in flight.
+    pub fn finish(&self, sequence_no: u64) -> Result<()> {
+        let visible_sequence_no = {
+            let mut writes_in_flight = self.writes_in_flight.lock()?;
+            writes_in_flight.remove(&sequence_no);
+            match writes_in_flight.first() {
+                Some(first_sequence_no) => first_sequence_no - 1,
+                None => self.last_sequence_no.load(Ordering::SeqCst),
+            }
+        };
+        self.publish(visible_sequence_no)
+    }
+
+    /// Move the sequence number to the last write known, e.g. replayed from the primary's logs.
+    pub fn commit(&self, sequence_no: u64) {
+        self.last_sequence_no.store(sequence_no, Ordering::SeqCst);
+    }
+
+    /// Make the writes up to the sequence number visible, once they are applied to the Memtable.
+    pub fn publish(&self, sequence_no: u64) -> Result<()> {
+        self.visible_sequence_no
+            .fetch_max(sequence_no, Ordering::SeqCst);
+        let _visibility_guard = self.visibility_lock.lock()?;
+        self.visibility_changed.notify_all();
+        Ok(())
+    }
+
+    /// The sequence number of the last write visible to the reads.
+    pub fn visible_sequence_no(&self) -> u64 {
+        self.visible_sequence_no.load(Ordering::SeqCst)
+    }
+
+    /// Block until the writes up to the sequence number are visible.
+    pub fn wait_until_visible(&self, sequence_no: u64) -> Result<()> {
+        let mut visibility_guard = self.visibility_lock.lock()?;
+        while self.visible_sequence_no() < sequence_no {
+            visibility_guard = self.visibility_changed.wait(visibility_guard)?;
+        }
+        Ok(())
+    }
+
+    /// Pin the visible sequence number for a read across many keys, e.g. a frozen copy of a
+    /// Memtable, so that the writers keep the records it reads until the pin drops.
+    pub fn pin(&self) -> Result<SequencePin<'_>> {
+        // Count the pin before reading the visible sequence number, so that a writer which has
+        // found no pin read the visible sequence number before this one does.
+        self.num_pins.fetch_add(1, Ordering::SeqCst);
+        let mut pins = match self.pins.lock() {
+            Ok(pins) => pins,
+            Err(error) => {
+                self.num_pins.fetch_sub(1, Ordering::SeqCst);
+                return Err(error.into());
+            }
+        };
+        let sequence_no = self.visible_sequence_no();
+        *pins.entry(sequence_no).or_default() += 1;
+        Ok(SequencePin {
+            sequencer: self,
+            sequence_no,
+        })
+    }
+
+    /// The sequence number as of which the readers may still read, below which a writer may drop
+    /// the older records of a key as long as it keeps the newest one visible as of it.
+    pub fn oldest_readable_sequence_no(&self) -> Result<u64> {
+        let visible_sequence_no = self.visible_sequence_no();
+        if self.num_pins.load(Ordering::SeqCst) == 0 {
+            return Ok(visible_sequence_no);
+        }
+        let pins = self.pins.lock()?;
+        Ok(pins
+            .keys()
+            .next()
+            .map_or(visible_sequence_no, |sequence_no| {
+                visible_sequence_no.min(*sequence_no)
+            }))
+    }
+}
+
+/// A read pinned at a sequence number, which unpins it once dropped.
+pub(crate) struct SequencePin<'a> {
+    sequencer: &'a Sequencer,
+    sequence_no: u64,
+}
+
+impl SequencePin<'_> {
+    pub fn sequence_no(&self) -> u64 {
+        self.sequence_no
+    }
+}
+
+impl Drop for SequencePin<'_> {
+    fn drop(&mut self) {
+        if let Ok(mut pins) = self.sequencer.pins.lock() {
+            if let Some(num_pins) = pins.get_mut(&self.sequence_no) {
+                *num_pins -= 1;
+                if *num_pins == 0 {
+                    pins.remove(&self.sequence_no);
+                }
+            }
+        }
+        self.sequencer.num_pins.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicBool;
+    use std::time::Duration;
+
+    #[test]
+    fn test_sequencer() {
+        let sequencer = Sequencer::new(10);
+        assert_eq!(sequencer.visible_sequence_no(), 10);
+
+        // A write finished out of order stays invisible until the ones before it are finished.
+        assert_eq!(sequencer.take().unwrap(), 11);
+        assert_eq!(sequencer.take().unwrap(), 12);
+        sequencer.finish(12).unwrap();
+        assert_eq!(sequencer.visible_sequence_no(), 10);
+        sequencer.finish(11).unwrap();
+        assert_eq!(sequencer.visible_sequence_no(), 12);
+
+        // A sequence number given back is taken again.
+        assert_eq!(sequencer.take().unwrap(), 13);
+        sequencer.give_back(13).unwrap();
+        assert_eq!(sequencer.take().unwrap(), 13);
+
+        // The writers keep what the pinned reads need.
+        let pin = sequencer.pin().unwrap();
+        assert_eq!(pin.sequence_no(), 12);
+        sequencer.finish(13).unwrap();
+        assert_eq!(sequencer.visible_sequence_no(), 13);
+        assert_eq!(sequencer.oldest_readable_sequence_no().unwrap(), 12);
+        drop(pin);
+        assert_eq!(sequencer.oldest_readable_sequence_no().unwrap(), 13);
+    }
+
+    #[test]
+    fn test_wait_until_visible() {
+        let sequencer = Sequencer::new(10);
+        assert_eq!(sequencer.take().unwrap(), 11);
+        assert_eq!(sequencer.take().unwrap(), 12);
+        sequencer.finish(12).unwrap();
+
+        // The write of 12 is applied, but a read after it waits for the one of 11 all the same.
+        let is_11_finished = AtomicBool::new(false);
+        let (visible_sender, visible_receiver) = crossbeam::channel::bounded(1);
+        std::thread::scope(|scope| {
+            scope.spawn(|| {
+                sequencer.wait_until_visible(12).unwrap();
+                assert!(is_11_finished.load(Ordering::SeqCst));
+                visible_sender
+                    .send(sequencer.visible_sequence_no())
+                    .unwrap();
+            });
+            assert!(visible_receiver
+                .recv_timeout(Duration::from_millis(100))
+                .is_err());
+            is_11_finished.store(true, Ordering::SeqCst);
+            sequencer.finish(11).unwrap();
+            assert_eq!(visible_receiver.recv().unwrap(), 12);
+        });
+
+        // A sequence number already visible never blocks.
+        sequencer.wait_until_visible(11).unwrap();
+    }
+}
@@ -0,0 +1,583 @@
+use std::cmp::{Ordering, Reverse};
+use std::collections::BinaryHeap;
+use std::ops::{Bound, RangeBounds};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use crate::catalog::Catalog;
+use crate::comparator::{Comparator, OrderedKey};
+use crate::memtable::{FrozenMemtableData, Memtable};
+use crate::merge::{self, MergeOperator};
+use crate::sstable::{SSTable, SSTableIterator, SSTableView};
+use crate::stats::ConsistencyReport;
+use crate::types::{NaiveError, Record, Result, TimedRecord};
+use crate::utils;
+
+/// A consistent view of the data, which pins the Memtables and SSTables it is taken from so that
+/// neither later writes nor compactions can change what it observes.
+pub struct Snapshot {
+    /// A frozen copy of the read-write Memtable.
+    memtable: Arc<FrozenMemtableData>,
+
+    /// The read-only Memtable during compaction, if any.
+    ro_memtable: Option<Arc<Memtable>>,
+
+    /// The SSTables in increasing generations.
+    sstables: Vec<Arc<SSTable>>,
+
+    /// The order of keys.
+    comparator: &'static dyn Comparator,
+
+    /// What applies the merge operands to the older records, if any.
+    merge_operator: Option<Arc<dyn MergeOperator>>,
+
+    /// When the snapshot was taken, as of which the values count as expired or not.
+    taken_at_ms: u64,
+}
+
+impl Snapshot {
+    pub(crate) fn new(catalog: &Catalog) -> Result<Self> {
+        Ok(Self {
+            memtable: Arc::new(catalog.memtable.freeze()?),
+            ro_memtable: catalog.ro_memtable.clone(),
+            sstables: catalog.sstables.clone(),
+            comparator: catalog.options.comparator,
+            merge_operator: catalog.options.merge_operator.clone(),
+            taken_at_ms: utils::now_ms(),
+        })
+    }
+
+    /// A historical view made of the SSTables alone, e.g. as of an epoch, whose values count as
+    /// expired or not as of now.
+    pub(crate) fn of_sstables(catalog: &Catalog, sstables: Vec<Arc<SSTable>>) -> Self {
+        Self {
+            memtable: Arc::default(),
+            ro_memtable: None,
+            sstables,
+            comparator: catalog.options.comparator,
+            merge_operator: catalog.options.merge_operator.clone(),
+            taken_at_ms: utils::now_ms(),
+        }
+    }
+
+    /// Get the value of the key as of the snapshot.
+    pub fn get(&self, key: &str) -> Result<Option<String>> {
+        let merge_operator = self.merge_operator.as_deref();
+        let is_final = |record: &Option<TimedRecord>| {
+            record
+                .as_ref()
+                .is_some_and(|record| !matches!(record.record, Record::Merge(_)))
+        };
+        // Apply the merge operands found so far, if any, to the older record.
+        let stack = |record: Option<TimedRecord>, older_record: Option<TimedRecord>| match (
+            record,
+            older_record,
+        ) {
+            (Some(record), Some(older_record)) => {
+                merge::stack_timed(key, record, older_record, merge_operator).map(Some)
+            }
+            (record, older_record) => Ok(record.or(older_record)),
+        };
+
+        let ordered_key = OrderedKey::new(key.to_owned(), self.comparator);
+        let mut record = self.memtable.get(&ordered_key).cloned();
+        'lookup: {
+            if is_final(&record) {
+                break 'lookup;
+            }
+            if let Some(ro_memtable) = self.ro_memtable.as_ref() {
+                record = stack(record, ro_memtable.get_timed(key)?)?;
+                if is_final(&record) {
+                    break 'lookup;
+                }
+            }
+            for sstable in self.sstables.iter() {
+                if !sstable.may_contain(key) {
+                    continue;
+                }
+                record = stack(record, SSTableView::new(sstable.clone())?.get(key)?)?;
+                if is_final(&record) {
+                    break 'lookup;
+                }
+            }
+        }
+        let record = match record {
+            Some(record) => merge::resolve_timed(key, record, merge_operator)?.record,
+            None => return Ok(None),
+        };
+        match record.expire(self.taken_at_ms) {
+            Record::Value(value) | Record::ExpiringValue { value, .. } => Ok(Some(value)),
+            Record::Deleted | Record::Merge(_) => Ok(None),
+        }
+    }
+
+    /// Cross-check a full scan against the gets: the keys come in a strictly increasing order,
+    /// each value scanned is the one the get returns, and no deleted key is returned by the get.
+    ///
+    /// Since the scan and the gets share the records of every layer, each SSTable is also walked
+    /// through on its own, and every record in it must be found by its filters and its index, even
+    /// if shadowed by a newer layer. This catches a bad index or filter, which the merged view
+    /// would hide whenever the key is shadowed or both reads miss the same record.
+    ///
+    /// Every key is read at least twice, so the check costs a full scan, a walk through each
+    /// SSTable and a get per record.
+    pub fn verify(&self) -> Result<ConsistencyReport> {
+        let mut report = ConsistencyReport::default();
+        let options = ScanOptions {
+            tombstones: TombstoneVisibility::Include,
+        };
+        let mut previous_key: Option<String> = None;
+        for entry in self.scan_entries(.., &options)? {
+            let ScanEntry { key, record, .. } = entry?;
+            if let Some(previous_key) = previous_key.as_ref() {
+                if self.comparator.compare(previous_key, &key) != Ordering::Less {
+                    report.add_problem(format!(
+                        "key {:?} is scanned after key {:?}",
+                        key, previous_key
+                    ));
+                }
+            }
+            let value = self.get(&key)?;
+            match record {
+                Record::Value(scanned_value)
+                | Record::ExpiringValue {
+                    value: scanned_value,
+                    ..
+                } => {
+                    report.num_keys += 1;
+                    match value {
+                        Some(value) if value == scanned_value => (),
+                        Some(_) => report.add_problem(format!(
+                            "key {:?} gets a value other than the one scanned",
+                            key
+                        )),
+                        None => report.add_problem(format!("key {:?} is scanned but not got", key)),
+                    }
+                }
+                Record::Deleted | Record::Merge(_) => {
+                    report.num_tombstones += 1;
+                    if value.is_some() {
+                        report.add_problem(format!("key {:?} is deleted but got", key));
+                    }
+                }
+            }
+            previous_key = Some(key);
+        }
+        for sstable in self.sstables.iter() {
+            self.verify_sstable(sstable, &mut report)?;
+        }
+        Ok(report)
+    }
+
+    /// Check that every record walked through in the SSTable comes in a strictly increasing order
+    /// and is found by a lookup of its key in the SSTable alone.
+    fn verify_sstable(&self, sstable: &Arc<SSTable>, report: &mut ConsistencyReport) -> Result<()> {
+        let mut sstable_iter = sstable.pseudo_iter()?;
+        let mut sstable_view = SSTableView::new(sstable.clone())?;
+        let mut previous_key: Option<String> = None;
+        while let Some((key, record)) = sstable_iter.next_timed()? {
+            if let Some(previous_key) = previous_key.as_ref() {
+                if self.comparator.compare(previous_key, &key) != Ordering::Less {
+                    report.add_problem(format!(
+                        "key {:?} is after key {:?} in SSTable {:?}",
+                        key,
+                        previous_key,
+                        sstable.file_path()
+                    ));
+                }
+            }
+            if !sstable.may_contain(&key) {
+                report.add_problem(format!(
+                    "key {:?} is ruled out by the filters of SSTable {:?}",
+                    key,
+                    sstable.file_path()
+                ));
+            } else if sstable_view.get(&key)?.as_ref() != Some(&record) {
+                report.add_problem(format!(
+                    "key {:?} is looked up as other than the record in SSTable {:?}",
+                    key,
+                    sstable.file_path()
+                ));
+            }
+            previous_key = Some(key);
+        }
+        Ok(())
+    }
+
+    /// Iterate over the key-value pairs in the range in increasing order of keys.
+    pub fn scan<R: RangeBounds<String>>(&self, range: R) -> Result<ScanIterator> {
+        let range = (range.start_bound().cloned(), range.end_bound().cloned());
+        self.scan_with_prefix(range, None)
+    }
+
+    /// Iterate over the records in the range in increasing order of keys, including the deletions
+    /// if the options say so, e.g. for replication or debugging.
+    pub fn scan_entries<R: RangeBounds<String>>(
+        &self,
+        range: R,
+        options: &ScanOptions,
+    ) -> Result<EntryIterator> {
+        Ok(EntryIterator {
+            scan_iter: self.scan(range)?,
+            tombstones: options.tombstones,
+        })
+    }
+
+    /// Iterate over the key-value pairs whose keys start with the prefix in increasing order of
+    /// keys, skipping the SSTables whose prefix filters rule the prefix out.
+    pub fn scan_prefix(&self, prefix: &str) -> Result<ScanIterator> {
+        let start = if self.comparator.keeps_prefixes_contiguous() {
+            Bound::Included(prefix.to_owned())
+        } else {
+            Bound::Unbounded
+        };
+        self.scan_with_prefix((start, Bound::Unbounded), Some(prefix))
+    }
+
+    fn scan_with_prefix(
+        &self,
+        range: (Bound<String>, Bound<String>),
+        prefix: Option<&str>,
+    ) -> Result<ScanIterator> {
+        let prefix = prefix.map(str::to_owned);
+        let mut sources = Vec::with_capacity(self.sstables.len() + 2);
+        let mut record_sources = Vec::with_capacity(self.sstables.len() + 2);
+        if self.comparator.is_empty_range(&range) {
+            // BTreeMap::range would panic on such a range.
+            return ScanIterator::new(
+                sources,
+                record_sources,
+                range,
+                prefix,
+                self.comparator,
+                self.merge_operator.clone(),
+                self.taken_at_ms,
+            );
+        }
+
+        // Sources are ordered from the newest to the oldest.
+        let bounds = OrderedKey::bounds(&range, self.comparator);
+        sources.push(ScanSource::Records(collect_records(
+            self.memtable
+                .range(bounds)
+                .map(|(key, timed_record)| (key.as_str().to_owned(), timed_record.clone())),
+            prefix.as_deref(),
+            self.comparator,
+        )));
+        record_sources.push(RecordSource::Memtable);
+        if let Some(ro_memtable) = self.ro_memtable.as_ref() {
+            sources.push(ScanSource::Records(collect_records(
+                ro_memtable.range_timed(&range),
+                prefix.as_deref(),
+                self.comparator,
+            )));
+            record_sources.push(RecordSource::ReadOnlyMemtable);
+        }
+        for sstable in self.sstables.iter() {
+            // Skip the generations without opening their files if no key could be in the scan.
+            if !sstable.may_overlap(&range)
+                || prefix
+                    .as_ref()
+                    .is_some_and(|prefix| !sstable.may_contain_prefix(prefix))
+            {
+                continue;
+            }
+            let sstable_iter = match &range.0 {
+                Bound::Included(key) | Bound::Excluded(key) => sstable.pseudo_iter_from(key)?,
+                Bound::Unbounded => sstable.pseudo_iter()?,
+            };
+            sources.push(ScanSource::SSTable(sstable_iter));
+            record_sources.push(RecordSource::SSTable {
+                gen_no: sstable.gen_no(),
+            });
+        }
+        ScanIterator::new(
+            sources,
+            record_sources,
+            range,
+            prefix,
+            self.comparator,
+            self.merge_operator.clone(),
+            self.taken_at_ms,
+        )
+    }
+}
+
+impl IntoIterator for &Snapshot {
+    type Item = Result<(String, String)>;
+    type IntoIter = ScanIterator;
+
+    /// Iterate over all the key-value pairs in increasing order of keys like `scan(..)`, except
+    /// that a failure to start the scan comes out of the first call to next.
+    fn into_iter(self) -> ScanIterator {
+        self.scan(..)
+            .unwrap_or_else(|error| ScanIterator::failed(error, self.comparator))
+    }
+}
+
+/// How scans treat the deleted keys.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum TombstoneVisibility {
+    /// Hide the deleted keys as if they never existed.
+    #[default]
+    Skip,
+
+    /// Yield the deleted keys as Record::Deleted.
+    Include,
+}
+
+/// The options of scanning records.
+#[derive(Clone, Debug, Default)]
+pub struct ScanOptions {
+    pub tombstones: TombstoneVisibility,
+}
+
+/// Where a scanned record comes from.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RecordSource {
+    Memtable,
+    ReadOnlyMemtable,
+    SSTable { gen_no: usize },
+}
+
+/// The newest record of a key, which might be a deletion, with any merge operands applied and an
+/// expired value turned into a deletion.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ScanEntry {
+    pub key: String,
+    pub record: Record,
+    pub source: RecordSource,
+}
+
+/// The newest record of a key encoded as a `Command`, e.g. for forwarding it verbatim.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RawRecord {
+    pub bytes: Vec<u8>,
+    pub source: RecordSource,
+
+    /// The segment file and the offset of the message in it, if the record comes from an SSTable.
+    pub location: Option<(PathBuf, u64)>,
+}
+
+/// A source of key-record pairs in increasing order of keys.
+enum ScanSource {
+    Records(std::vec::IntoIter<(String, TimedRecord)>),
+    SSTable(SSTableIterator),
+}
+
+impl ScanSource {
+    fn next(&mut self) -> Result<Option<(String, TimedRecord)>> {
+        match self {
+            ScanSource::Records(records) => Ok(records.next()),
+            ScanSource::SSTable(sstable_iter) => sstable_iter.next_timed(),
+        }
+    }
+}
+
+/// An iterator over a Snapshot, merging its sources and hiding the deleted keys.
+pub struct ScanIterator {
+    /// The sources from the newest to the oldest.
+    sources: Vec<ScanSource>,
+
+    /// Where the records of each source come from.
+    record_sources: Vec<RecordSource>,
+
+    /// The pending record of each source.
+    records: Vec<Option<TimedRecord>>,
+
+    /// The pending keys, with ties broken by the source number, i.e. the newest first.
+    heap: BinaryHeap<Reverse<(OrderedKey, usize)>>,
+
+    /// The scanned range.
+    range: (Bound<String>, Bound<String>),
+
+    /// The prefix of the scanned keys, if any.
+    prefix: Option<String>,
+
+    /// The order of keys.
+    comparator: &'static dyn Comparator,
+
+    /// What applies the merge operands to the older records, if any.
+    merge_operator: Option<Arc<dyn MergeOperator>>,
+
+    /// The last key returned, for skipping its older versions.
+    last_key: Option<OrderedKey>,
+
+    /// When the snapshot was taken, as of which the values count as expired or not.
+    now_ms: u64,
+
+    /// The failure to start the scan, if any, which the first call to next returns.
+    error: Option<NaiveError>,
+}
+
+impl ScanIterator {
+    fn new(
+        sources: Vec<ScanSource>,
+        record_sources: Vec<RecordSource>,
+        range: (Bound<String>, Bound<String>),
+        prefix: Option<String>,
+        comparator: &'static dyn Comparator,
+        merge_operator: Option<Arc<dyn MergeOperator>>,
+        now_ms: u64,
+    ) -> Result<Self> {
+        let records = (0..sources.len()).map(|_| None).collect();
+        let heap = BinaryHeap::with_capacity(sources.len());
+        let mut scan_iter = Self {
+            sources,
+            record_sources,
+            records,
+            heap,
+            range,
+            prefix,
+            comparator,
+            merge_operator,
+            last_key: None,
+            now_ms,
+            error: None,
+        };
+        for source in 0..scan_iter.sources.len() {
+            scan_iter.advance(source)?;
+        }
+        Ok(scan_iter)
+    }
+
+    /// An iterator yielding nothing but the error.
+    fn failed(error: NaiveError, comparator: &'static dyn Comparator) -> Self {
+        Self {
+            sources: Vec::new(),
+            record_sources: Vec::new(),
+            records: Vec::new(),
+            heap: BinaryHeap::new(),
+            range: (Bound::Unbounded, Bound::Unbounded),
+            prefix: None,
+            comparator,
+            merge_operator: None,
+            last_key: None,
+            now_ms: 0,
+            error: Some(error),
+        }
+    }
+
+    /// Move the next in-range record of a source into the heap.
+    fn advance(&mut self, source: usize) -> Result<()> {
+        while let Some((key, record)) = self.sources[source].next()? {
+            if !self.comparator.is_before_end(self.range.1.as_ref(), &key) {
+                break;
+            }
+            if let Some(prefix) = self.prefix.as_ref() {
+                if !key.starts_with(prefix) {
+                    // Past all the keys with the prefix if they are contiguous.
+                    if self.comparator.keeps_prefixes_contiguous()
+                        && self.comparator.compare(&key, prefix) == Ordering::Greater
+                    {
+                        break;
+                    }
+                    continue;
+                }
+            }
+            // Skip the keys before the range, which SSTable iterators might yield.
+            if self.comparator.contains(&self.range, &key) {
+                self.heap
+                    .push(Reverse((OrderedKey::new(key, self.comparator), source)));
+                self.records[source] = Some(record);
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Get the newest record of the next key, which might be a deletion.
+    fn next_entry(&mut self) -> Result<Option<ScanEntry>> {
+        if let Some(error) = self.error.take() {
+            return Err(error);
+        }
+        while let Some(Reverse((key, source))) = self.heap.pop() {
+            let mut record = self.records[source].take().unwrap();
+            self.advance(source)?;
+            if self.last_key.as_ref() == Some(&key) {
+                continue;
+            }
+            // Apply the merge operands to the older records of the key, which come up next.
+            if matches!(record.record, Record::Merge(_)) {
+                let merge_operator = self.merge_operator.clone();
+                let merge_operator = merge_operator.as_deref();
+                while matches!(record.record, Record::Merge(_)) {
+                    match self.heap.peek() {
+                        Some(Reverse((next_key, _))) if *next_key == key => {}
+                        _ => break,
+                    }
+                    let Reverse((_, older_source)) = self.heap.pop().unwrap();
+                    let older_record = self.records[older_source].take().unwrap();
+                    self.advance(older_source)?;
+                    record =
+                        merge::stack_timed(key.as_str(), record, older_record, merge_operator)?;
+                }
+                record = merge::resolve_timed(key.as_str(), record, merge_operator)?;
+            }
+            self.last_key = Some(key.clone());
+            return Ok(Some(ScanEntry {
+                key: key.into_string(),
+                record: record.record.expire(self.now_ms),
+                source: self.record_sources[source],
+            }));
+        }
+        Ok(None)
+    }
+}
+
+impl Iterator for ScanIterator {
+    type Item = Result<(String, String)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.next_entry() {
+                Ok(Some(ScanEntry {
+                    key,
+                    record: Record::Value(value) | Record::ExpiringValue { value, .. },
+                    ..
+                })) => return Some(Ok((key, value))),
+                Ok(Some(_)) => continue,
+                Ok(None) => return None,
+                Err(error) => return Some(Err(error)),
+            }
+        }
+    }
+}
+
+/// An iterator over the records of a Snapshot, which yields the deletions if asked to.
+pub struct EntryIterator {
+    scan_iter: ScanIterator,
+    tombstones: TombstoneVisibility,
+}
+
+impl Iterator for EntryIterator {
+    type Item = Result<ScanEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.scan_iter.next_entry() {
+                Ok(Some(ScanEntry {
+                    record: Record::Deleted,
+                    ..
+                })) if self.tombstones == TombstoneVisibility::Skip => continue,
+                Ok(Some(entry)) => return Some(Ok(entry)),
+                Ok(None) => return None,
+                Err(error) => return Some(Err(error)),
+            }
+        }
+    }
+}
+
+/// Copy the records with the prefix, if any, out of a Memtable.
+fn collect_records(
+    records: impl Iterator<Item = (String, TimedRecord)>,
+    prefix: Option<&str>,
+    comparator: &dyn Comparator,
+) -> std::vec::IntoIter<(String, TimedRecord)> {
+    let has_prefix = |key: &str| prefix.is_none_or(|prefix| key.starts_with(prefix));
+    records
+        .take_while(|(key, _)| !comparator.keeps_prefixes_contiguous() || has_prefix(key))
+        .filter(|(key, _)| has_prefix(key))
+        .collect::<Vec<_>>()
+        .into_iter()
+}
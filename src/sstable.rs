@@ -1,14 +1,31 @@
-use std::cmp::Reverse;
-use std::collections::{BTreeMap, BinaryHeap};
-use std::fs::{File, OpenOptions};
-use std::io::{BufReader, BufWriter, Read, Seek, Write};
+use crossbeam::channel::{bounded, Receiver, Sender};
+use protobuf::Message;
+use rand::Rng;
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BTreeMap, BinaryHeap, HashMap, HashSet};
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::ops::{Bound, RangeBounds};
 use std::path::{Path, PathBuf};
-use std::sync::{Arc, Mutex};
-
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering as AtomicOrdering};
+use std::sync::{Arc, Mutex, OnceLock};
+
+use crate::backend::{Backend, BackendFile, MappedFile, PositionalReader};
+use crate::blob::{self, BlobWriter};
+use crate::bloom::BloomFilter;
+use crate::comparator::{Comparator, OrderedKey};
+use crate::index::{ChunkIndex, PartitionedIndex};
+use crate::io_scheduler;
 use crate::memtable::Memtable;
-use crate::protos::messages::{Command, CommandType};
-use crate::types::{NaiveError, Record, Result};
-use crate::utils;
+use crate::merge;
+use crate::options::Options;
+use crate::prefix::PrefixExtractor;
+use crate::protos::messages::{self, BlobReference, Command, CommandType};
+use crate::readahead::Readahead;
+use crate::stats::{RangeEstimate, SSTableProblem, SSTableProperties, SSTableReport};
+use crate::thread_pool::ThreadPool;
+use crate::trash::Trash;
+use crate::types::{NaiveError, Record, RecordKind, Result, TimedRecord};
+use crate::utils::{self, N_BYTES_CHUNK_LENGTH};
 
 /// Use an architecture-independent type to store generation numbers in files.
 type GenerationNumberType = u32;
@@ -18,8 +35,165 @@ const N_BYTES_GENERATION_NUMBER: usize = (GenerationNumberType::BITS as usize) >
 /// Write the buffered chunk into the file if its size exceeds this number.
 const SSTABLE_CHUNK_SIZE_THRESHOLD: usize = 1024;
 
-// TODO Try replacing this with the skip list.
-type SSTableIndex = BTreeMap<String, u64>;
+/// Marks the end of a segment file with a footer, whose offset comes right before this at the end
+/// of the file. The segment files written before the footers were kept end with their last chunk
+/// instead, or with a footer of the properties alone under another magic number, and are scanned
+/// on open for what the footer would tell.
+const FOOTER_MAGIC: u64 = 0x4e61_6976_654b_5632;
+
+const N_BYTES_FOOTER_TRAILER: usize = 2 * (u64::BITS as usize >> 3);
+
+/// The format of the chunks storing every key in full, as the segment files without a footer or
+/// a format version in it do.
+const FORMAT_VERSION_FULL_KEYS: u32 = 1;
+
+/// The format of the chunks storing the keys between the restart points as the suffixes after the
+/// prefixes they share with the keys before them, which the readers of the version 1 would take for
+/// the full keys.
+const FORMAT_VERSION_SHARED_KEY_PREFIXES: u32 = 2;
+
+/// The latest format version this reads, above which a segment file is refused rather than misread.
+const LATEST_FORMAT_VERSION: u32 = FORMAT_VERSION_SHARED_KEY_PREFIXES;
+
+/// The number of merged records handed over to the writing stage at a time.
+const WRITE_BATCH_SIZE: usize = 256;
+
+/// The number of batches the merge may run ahead of the writing stage.
+const WRITE_PIPELINE_DEPTH: usize = 4;
+
+/// The threads running the writing stages of the segment files being created, shared by all the
+/// merges. A writing stage only waits for its own merge, so one queued behind others starts as
+/// soon as they finish.
+static SEGMENT_WRITERS: OnceLock<ThreadPool> = OnceLock::new();
+
+/// The merged records in increasing order of keys, along with where the values separated into the
+/// blob files are, which are carried over rather than read and written again.
+type MergedBatch = Vec<(OrderedKey, TimedRecord, Option<SeparatedValue>)>;
+
+/// A value separated into a blob file, left unread by a merge, whose record holds an empty value
+/// in its place.
+struct SeparatedValue {
+    blob: BlobReference,
+
+    /// The checksum of the command, which covers the value.
+    checksum: Option<u32>,
+
+    /// The blob file holding the value, shared with the SSTable it is read from.
+    blob_file: Arc<dyn BackendFile>,
+    file_path: PathBuf,
+}
+
+impl SeparatedValue {
+    /// Read the value into the record, e.g. for the merge operands to apply to it.
+    fn resolve(self, mut timed_record: TimedRecord) -> Result<TimedRecord> {
+        let value = blob::read_blob(self.blob_file.as_ref(), &self.file_path, &self.blob)?;
+        match &mut timed_record.record {
+            Record::Value(placeholder)
+            | Record::ExpiringValue {
+                value: placeholder, ..
+            } => *placeholder = value,
+            _ => return Err(NaiveError::InvalidData),
+        }
+        Ok(timed_record)
+    }
+}
+
+type SSTableIndex = ChunkIndex;
+
+/// The index of the segment file written, the file itself and what its footer tells.
+struct WrittenSegment {
+    index: SSTableIndex,
+    segment_file: Box<dyn BackendFile>,
+    footer: SegmentFooter,
+}
+
+/// What the footer of a segment file tells about its records, enough to open the SSTable without
+/// reading its chunks, or what a scan of them finds out for the segment files without one.
+struct SegmentFooter {
+    properties: SSTableProperties,
+
+    /// The smallest and the largest keys, or None if the segment file is empty.
+    key_range: Option<(String, String)>,
+
+    /// The numbers of the blob files holding the values separated, in increasing order.
+    blob_file_nos: Vec<u64>,
+
+    /// The bytes of the values separated into each of the blob files, or none if not counted.
+    blob_file_bytes: Vec<u64>,
+
+    /// The version of the format of the chunks.
+    format_version: u32,
+}
+
+impl SegmentFooter {
+    fn to_message(&self, comparator: &dyn Comparator) -> messages::SSTableFooter {
+        let mut message = messages::SSTableFooter::new();
+        message.set_properties(self.properties.to_message());
+        if let Some((min_key, max_key)) = self.key_range.as_ref() {
+            message.set_min_key(min_key.clone());
+            message.set_max_key(max_key.clone());
+        }
+        message.set_blob_file_nos(self.blob_file_nos.clone());
+        message.set_blob_file_bytes(self.blob_file_bytes.clone());
+        message.set_comparator_name(comparator.name().to_owned());
+        if self.format_version != FORMAT_VERSION_FULL_KEYS {
+            message.set_format_version(self.format_version);
+        }
+        message
+    }
+
+    fn from_message(mut message: messages::SSTableFooter) -> Self {
+        let key_range = (message.has_min_key() && message.has_max_key())
+            .then(|| (message.take_min_key(), message.take_max_key()));
+        Self {
+            properties: SSTableProperties::from_message(message.get_properties()),
+            key_range,
+            blob_file_nos: message.take_blob_file_nos(),
+            blob_file_bytes: message.take_blob_file_bytes(),
+            format_version: message.get_format_version().max(FORMAT_VERSION_FULL_KEYS),
+        }
+    }
+}
+
+/// The options the in-memory parts of an SSTable are built with, kept to load them on demand.
+#[derive(Clone, Copy)]
+struct IndexOptions {
+    comparator: &'static dyn Comparator,
+    prefix_extractor: Option<&'static dyn PrefixExtractor>,
+    bloom_filter_bits_per_key: usize,
+    inline_value_size: usize,
+    inline_values_capacity: usize,
+    index_partition_size: usize,
+    max_resident_index_partitions: usize,
+}
+
+impl IndexOptions {
+    fn new(options: &Options) -> Self {
+        Self {
+            comparator: options.comparator,
+            prefix_extractor: options.prefix_extractor,
+            bloom_filter_bits_per_key: options.bloom_filter_bits_per_key,
+            inline_value_size: options.inline_value_size,
+            inline_values_capacity: options.inline_values_capacity,
+            index_partition_size: options.index_partition_size,
+            max_resident_index_partitions: options.max_resident_index_partitions,
+        }
+    }
+}
+
+/// The parts of an SSTable kept in memory that are built out of all its records.
+struct LoadedIndex {
+    /// The index of the chunks, whose partitions are kept in memory as far as configured.
+    index: PartitionedIndex,
+
+    /// The Bloom filter of the key prefixes.
+    prefix_filter: Option<BloomFilter>,
+
+    /// The records of the small values and of the deletions kept in memory, if configured, so
+    /// that the gets of them never read the segment file. They are keyed in the order of the
+    /// comparator, so that the keys it deems equal find the same record.
+    inline_records: BTreeMap<OrderedKey, TimedRecord>,
+}
 
 /// This structure is owned by the global storage engine.
 pub struct SSTable {
@@ -29,107 +203,328 @@ pub struct SSTable {
     /// The compaction epoch -- each compaction generates a new SSTable.
     epoch_no: u64,
 
-    /// The ordered in-memory index.
-    index: SSTableIndex,
+    /// The index, the prefix filter and the inline records, which are built by scanning the
+    /// segment file on the first query needing them, unless known from its creation or from a
+    /// scan on open, so that opening many SSTables rarely read takes a footer read apiece.
+    loaded_index: OnceLock<LoadedIndex>,
+
+    /// The options to build loaded_index with, including the extractor of the key prefixes.
+    index_options: IndexOptions,
+
+    /// Held while loaded_index is built, so that the segment file is scanned once.
+    index_loading: Mutex<()>,
+
+    /// The smallest and the largest keys, or None if the SSTable is empty.
+    key_range: Option<(String, String)>,
+
+    /// The composition of the records in the segment file, as stored in its footer.
+    properties: SSTableProperties,
+
+    /// The order of keys.
+    comparator: &'static dyn Comparator,
 
     /// The path of the segment file.
     file_path: PathBuf,
 
+    /// The numbers of the blob files holding the values separated from the segment file.
+    blob_file_nos: Vec<u64>,
+
+    /// The bytes of the values the segment file refers to in each of the blob files, or none if
+    /// the segment file predates the count.
+    blob_file_bytes: Vec<u64>,
+
+    /// The folders to look for the blob files in.
+    blob_folder_paths: Vec<PathBuf>,
+
+    /// The blob files opened on first use, in the order of blob_file_nos, which are read by
+    /// offset like shared_file.
+    blob_files: Vec<OnceLock<Arc<dyn BackendFile>>>,
+
     /// The size of the segment file in bytes.
     file_size: usize,
 
+    /// The CRC32 checksum of the whole segment file, computed on first use unless known.
+    checksum: OnceLock<u32>,
+
     /// Whether the SSTable is deprecated.
     is_deprecated: Mutex<bool>,
+
+    /// The number of reads in a row that have failed.
+    num_read_errors: AtomicUsize,
+
+    /// Whether the reads have failed often enough in a row to quarantine the SSTable.
+    is_quarantined: AtomicBool,
+
+    /// Where the segment file goes once the SSTable is deprecated, if not removed right away.
+    trash: Option<Trash>,
+
+    /// Where the segment file is stored.
+    backend: Arc<dyn Backend>,
+
+    /// Whether to read the segment file through a memory map, if the backend can map it.
+    mmap_reads: bool,
+
+    /// The segment file mapped on first use, or None if not to be read so.
+    mapped_file: OnceLock<Option<MappedFile>>,
+
+    /// The most bytes the iterators read ahead of them at once.
+    readahead_size: usize,
+
+    /// The segment file opened on first use for the views, which all read it by offset, so that
+    /// the concurrent gets of a hot SSTable neither open a file apiece nor take turns on one.
+    shared_file: OnceLock<Box<dyn BackendFile>>,
 }
 
 impl SSTable {
     /// Recover from an existing segment file.
-    pub fn open(file_path: PathBuf) -> Result<Self> {
+    pub fn open(file_path: PathBuf, options: &Options) -> Result<Self> {
+        // The epoch number is zero in the beginning.
+        Self::open_at_epoch(file_path, 0, options)
+    }
+
+    /// Open an existing segment file as of the given epoch, e.g. when a replica picks up the
+    /// SSTables written by its primary.
+    pub fn open_at_epoch(file_path: PathBuf, epoch_no: u64, options: &Options) -> Result<Self> {
         log::info!("Going to open segment file {}.", file_path.display());
 
-        // The epoch number is zero in the beginning.
-        let epoch_no = 0;
+        let comparator = options.comparator;
 
         // The file must already exist.
-        let mut segment_file = OpenOptions::new()
-            .read(true)
-            .create(false)
-            .open(file_path.as_path())?;
-        let file_size = segment_file.metadata()?.len() as usize;
+        let mut segment_file = options.backend.open(&file_path)?;
+        let file_size = segment_file.len()? as usize;
 
         // Read the generation number at the start of the file.
         let gen_no = read_sstable_gen_no(&mut segment_file)?;
 
-        let index = build_sstable_index(segment_file)?;
+        let index_options = IndexOptions::new(options);
+        let (footer, loaded_index) =
+            match read_footer(&mut segment_file, &file_path, file_size, comparator)? {
+                // The chunks are left unread until a query needs the index.
+                Some((footer, _)) => (footer, OnceLock::new()),
+                // The segment files without a footer are scanned for what it would tell.
+                None => {
+                    segment_file.seek(SeekFrom::Start(N_BYTES_GENERATION_NUMBER as u64))?;
+                    let (loaded_index, footer) = scan_segment_file(
+                        segment_file,
+                        &file_path,
+                        file_size,
+                        comparator,
+                        &index_options,
+                    )?;
+                    (footer, OnceLock::from(loaded_index))
+                }
+            };
+        let SegmentFooter {
+            properties,
+            key_range,
+            blob_file_nos,
+            blob_file_bytes,
+            ..
+        } = footer;
+        let blob_folder_paths = blob::blob_folder_paths(parent_folder_path(&file_path), options);
 
         let is_deprecated = Mutex::new(false);
 
-        Ok(SSTable {
+        let sstable = SSTable {
             gen_no,
             epoch_no,
-            index,
+            loaded_index,
+            index_options,
+            index_loading: Mutex::new(()),
+            key_range,
+            properties,
+            comparator,
             file_path,
+            blob_files: blob_file_nos.iter().map(|_| OnceLock::new()).collect(),
+            blob_file_nos,
+            blob_file_bytes,
+            blob_folder_paths,
             file_size,
+            checksum: OnceLock::new(),
             is_deprecated,
-        })
+            num_read_errors: AtomicUsize::new(0),
+            is_quarantined: AtomicBool::new(false),
+            trash: Trash::new(options),
+            backend: options.backend.clone(),
+            mmap_reads: options.mmap_reads,
+            mapped_file: OnceLock::new(),
+            readahead_size: options.readahead_size,
+            shared_file: OnceLock::new(),
+        };
+        if options.paranoid_checks {
+            if let Some(problem) = sstable.verify()?.problems.first() {
+                return Err(NaiveError::Corruption {
+                    file_path: sstable.file_path.clone(),
+                    offset: problem.offset(),
+                });
+            }
+        }
+        Ok(sstable)
+    }
+
+    /// The blob files of which the SSTables refer to less than the live ratio of the bytes, i.e.
+    /// whose other values are dead unless referred to by the SSTables not merged. The blob files
+    /// referred to by any SSTable predating the count of the bytes are left out.
+    fn sparse_blob_file_nos(sstables: &[Arc<SSTable>], options: &Options) -> Result<HashSet<u64>> {
+        if options.min_blob_live_ratio <= 0.0 {
+            return Ok(HashSet::new());
+        }
+        let mut live_bytes = HashMap::new();
+        let mut uncounted_file_nos = HashSet::new();
+        for sstable in sstables.iter() {
+            if sstable.blob_file_bytes.len() != sstable.blob_file_nos.len() {
+                uncounted_file_nos.extend(sstable.blob_file_nos.iter().copied());
+                continue;
+            }
+            for (&file_no, &num_bytes) in sstable.blob_file_nos.iter().zip(&sstable.blob_file_bytes)
+            {
+                let (_, total_bytes) = live_bytes
+                    .entry(file_no)
+                    .or_insert_with(|| (sstable.clone(), 0));
+                *total_bytes += num_bytes;
+            }
+        }
+        let mut sparse_file_nos = HashSet::new();
+        for (file_no, (sstable, num_bytes)) in live_bytes {
+            if uncounted_file_nos.contains(&file_no) {
+                continue;
+            }
+            let file_size = options
+                .backend
+                .file_size(&sstable.blob_file_path(file_no))?;
+            if (num_bytes as f64) < options.min_blob_live_ratio * file_size as f64 {
+                log::info!(
+                    "Going to store anew the {} of {} bytes still live in blob file {}.",
+                    num_bytes,
+                    file_size,
+                    file_no
+                );
+                sparse_file_nos.insert(file_no);
+            }
+        }
+        Ok(sparse_file_nos)
     }
 
     /// Create an empty segment file.
-    pub fn create_empty(file_path: PathBuf, gen_no: usize, epoch_no: u64) -> Result<Self> {
+    pub fn create_empty(
+        file_path: PathBuf,
+        gen_no: usize,
+        epoch_no: u64,
+        options: &Options,
+    ) -> Result<Self> {
         log::info!(
             "Going to create segment file {} (epoch_no = {}).",
             file_path.display(),
             epoch_no
         );
 
-        let segment_file = OpenOptions::new()
-            .append(true)
-            .create_new(true)
-            .read(false)
-            .open(file_path.as_path())?;
+        let segment_file = options.backend.create_new(&file_path)?;
         let mut file_writer = BufWriter::new(segment_file);
 
         // Write the generation number at the beginning of the file.
-        file_writer.write(&(gen_no as GenerationNumberType).to_be_bytes())?;
+        let gen_no_bytes = (gen_no as GenerationNumberType).to_be_bytes();
+        file_writer.write_all(&gen_no_bytes)?;
 
         let segment_file = file_writer.into_inner()?;
-        let file_size = segment_file.metadata()?.len() as usize;
-
-        let index = SSTableIndex::new();
+        let file_size = segment_file.len()? as usize;
+        let checksum = OnceLock::from(crc32fast::hash(&gen_no_bytes));
+
+        let index_options = IndexOptions::new(options);
+        let loaded_index = OnceLock::from(LoadedIndex {
+            index: partition_index(SSTableIndex::new(), file_size, &index_options),
+            prefix_filter: None,
+            inline_records: BTreeMap::new(),
+        });
+        let properties = SSTableProperties::default();
+        let comparator = options.comparator;
+        let blob_folder_paths = blob::blob_folder_paths(parent_folder_path(&file_path), options);
 
         let is_deprecated = Mutex::new(false);
 
         Ok(SSTable {
             gen_no,
             epoch_no,
-            index,
+            loaded_index,
+            index_options,
+            index_loading: Mutex::new(()),
+            key_range: None,
+            properties,
+            comparator,
             file_path,
+            blob_file_nos: Vec::new(),
+            blob_file_bytes: Vec::new(),
+            blob_folder_paths,
+            blob_files: Vec::new(),
             file_size,
+            checksum,
             is_deprecated,
+            num_read_errors: AtomicUsize::new(0),
+            is_quarantined: AtomicBool::new(false),
+            trash: Trash::new(options),
+            backend: options.backend.clone(),
+            mmap_reads: options.mmap_reads,
+            mapped_file: OnceLock::new(),
+            readahead_size: options.readahead_size,
+            shared_file: OnceLock::new(),
         })
     }
 
-    /// Create a new segment file by merging a Memtable with a list of SSTables.
+    /// Create a new segment file by merging a Memtable, if any, with a list of SSTables.
+    ///
+    /// If nothing is older than the SSTables, i.e. they are at the bottom, the merge operands
+    /// left are applied to nothing, so that they never pile up for the keys without a value, and
+    /// the expired values are dropped, unless a read fallback would serve the keys again. Elsewhere
+    /// the expired values become deletions, which still hide the older records.
     pub fn create(
         file_path: PathBuf,
-        memtable: &Memtable,
-        sstables: &Vec<Arc<SSTable>>,
+        memtable: Option<&Memtable>,
+        sstables: &[Arc<SSTable>],
+        is_bottom: bool,
         gen_no: usize,
         epoch_no: u64,
+        options: &Options,
     ) -> Result<Self> {
-        log::info!(
-            "Going to merge into segment file {} (epoch={}).",
-            file_path.display(),
-            epoch_no
-        );
+        let mut sstables = Self::create_partitioned(
+            || file_path.clone(),
+            memtable,
+            sstables,
+            is_bottom,
+            gen_no,
+            epoch_no,
+            0,
+            options,
+        )?;
+        Ok(sstables.pop().unwrap())
+    }
 
+    /// Like `create`, but split the records into segment files at the paths generated, each
+    /// holding about the given number of bytes of keys and values at most, or all of them if zero.
+    /// The files partition the keys by range, and at least one is created even if empty. If any
+    /// partition fails, the files of the others are removed as well.
+    #[allow(clippy::too_many_arguments)]
+    pub fn create_partitioned(
+        mut gen_file_path: impl FnMut() -> PathBuf,
+        memtable: Option<&Memtable>,
+        sstables: &[Arc<SSTable>],
+        is_bottom: bool,
+        gen_no: usize,
+        epoch_no: u64,
+        partition_size: usize,
+        options: &Options,
+    ) -> Result<Vec<Self>> {
+        // The merge heap orders keys by the comparator, and the records of the same key from the
+        // newest to the oldest by sequence number and then by source number.
+        let comparator = options.comparator;
         let mut heap = BinaryHeap::with_capacity(sstables.len() + 1);
+        let heap_entry = |key: OrderedKey, record: &TimedRecord, source: usize| {
+            Reverse((key, Reverse(record.sequence_no), source))
+        };
 
-        let mut memtable_iter = memtable.iter();
+        let mut memtable_iter = memtable.into_iter().flat_map(Memtable::iter_timed);
         let mut memtable_record = None;
         if let Some((key, record)) = memtable_iter.next() {
-            heap.push(Reverse((key.to_owned(), 0)));
-            memtable_record = Some(record.to_owned());
+            heap.push(heap_entry(OrderedKey::new(key, comparator), &record, 0));
+            memtable_record = Some(record);
         }
 
         let mut sstable_iters = Vec::with_capacity(sstables.len());
@@ -137,87 +532,180 @@ impl SSTable {
         for sstable in sstables.iter() {
             let index = sstable_iters.len();
             let mut sstable_iter = sstable.pseudo_iter()?;
-            if let Some((key, record)) = sstable_iter.next()? {
-                heap.push(Reverse((key, index + 1)));
+            if let Some((key, record, separated)) = sstable_iter.next_separated()? {
+                heap.push(heap_entry(
+                    OrderedKey::new(key, comparator),
+                    &record,
+                    index + 1,
+                ));
                 sstable_iters.push(sstable_iter);
-                sstable_records.push(Some(record));
+                sstable_records.push(Some((record, separated)));
             }
         }
 
-        let mut index = SSTableIndex::new();
-
-        // Write the generation number at the beginning of the file.
-        let segment_file = OpenOptions::new()
-            .append(true)
-            .create_new(true)
-            .read(true)
-            .open(file_path.as_path())?;
-        let mut file_writer = BufWriter::new(segment_file);
-        file_writer.write(&(gen_no as GenerationNumberType).to_be_bytes())?;
-
-        let mut buffer = Vec::new();
-        let mut last_key = None;
-        while let Some(Reverse((key, source))) = heap.pop() {
-            // With the same key, keep the record from the smallest source number.
-            // i.e. If a key exits in the Memtable or an SSTable of younger generation, ignore its
-            // existence in older generations.
-            let is_new_key = last_key.is_none() || *last_key.as_ref().unwrap() != key;
-            if is_new_key {
-                last_key = Some(key.clone());
-            }
-            if source == 0 {
-                // This comes from the Memtable.
-                if is_new_key {
+        let merge_operator = options.merge_operator.as_deref();
+        let now_ms = utils::now_ms();
+        let drops_expired = is_bottom && options.read_fallback.is_none();
+        let sparse_blob_file_nos = if is_bottom {
+            Self::sparse_blob_file_nos(sstables, options)?
+        } else {
+            HashSet::new()
+        };
+        // Get the record to write for the key, if any, out of its newest one.
+        let finish = |key: &OrderedKey,
+                      mut timed_record: TimedRecord,
+                      separated: Option<SeparatedValue>| {
+            if is_bottom {
+                timed_record = merge::resolve_timed(key.as_str(), timed_record, merge_operator)?;
+            }
+            let is_expired = matches!(
+                timed_record.record,
+                Record::ExpiringValue { expires_at_ms, .. } if expires_at_ms <= now_ms
+            );
+            Ok::<_, NaiveError>(match (is_expired, drops_expired) {
+                (true, true) => None,
+                // The deletion leaves the separated value behind.
+                (true, false) => Some((
+                    TimedRecord {
+                        record: Record::Deleted,
+                        ..timed_record
+                    },
+                    None,
+                )),
+                // The value is stored anew rather than kept in a blob file mostly dead.
+                (false, _) => match separated {
+                    Some(separated)
+                        if sparse_blob_file_nos.contains(&separated.blob.get_file_no()) =>
+                    {
+                        Some((separated.resolve(timed_record)?, None))
+                    }
+                    separated => Some((timed_record, separated)),
+                },
+            })
+        };
+        // The newest record of the last key, which takes in the older ones while it is a merge.
+        let mut pending: Option<(OrderedKey, TimedRecord, Option<SeparatedValue>)> = None;
+        let mut sstables = Vec::new();
+        // The segment file being written, started on its first record.
+        let mut segment_writer: Option<SegmentWriter> = None;
+        let mut write = |key: OrderedKey,
+                         record: TimedRecord,
+                         separated: Option<SeparatedValue>|
+         -> Result<bool> {
+            let writer = match segment_writer.as_mut() {
+                Some(writer) => writer,
+                None => segment_writer.insert(SegmentWriter::start(
+                    gen_file_path(),
+                    gen_no,
+                    epoch_no,
+                    options,
+                )?),
+            };
+            if !writer.push(key, record, separated) {
+                return Ok(false);
+            }
+            if partition_size > 0 && writer.data_size >= partition_size {
+                let writer = segment_writer.take().unwrap();
+                sstables.push(writer.finish(gen_no, epoch_no, options)?);
+            }
+            Ok(true)
+        };
+        let mut merge_all = || -> Result<()> {
+            while let Some(Reverse((key, _, source))) = heap.pop() {
+                let (record, separated) = if source == 0 {
+                    // This comes from the Memtable.
                     let record = memtable_record.take().unwrap();
-                    append_command_to_sstable(
-                        &mut index,
-                        &mut file_writer,
-                        &mut buffer,
-                        key,
-                        record,
-                    )?;
-                }
-                if let Some((key, record)) = memtable_iter.next() {
-                    heap.push(Reverse((key.clone(), 0)));
-                    memtable_record = Some(record.clone());
-                }
-            } else {
-                // This comes from an SSTable.
-                if is_new_key {
+                    if let Some((key, record)) = memtable_iter.next() {
+                        heap.push(heap_entry(OrderedKey::new(key, comparator), &record, 0));
+                        memtable_record = Some(record);
+                    }
+                    (record, None)
+                } else {
+                    // This comes from an SSTable.
                     let record = sstable_records[source - 1].take().unwrap();
-                    append_command_to_sstable(
-                        &mut index,
-                        &mut file_writer,
-                        &mut buffer,
-                        key,
-                        record,
-                    )?;
+                    let sstable_iter = &mut sstable_iters[source - 1];
+                    if let Some((key, record, separated)) = sstable_iter.next_separated()? {
+                        heap.push(heap_entry(
+                            OrderedKey::new(key, comparator),
+                            &record,
+                            source,
+                        ));
+                        sstable_records[source - 1] = Some((record, separated));
+                    }
+                    record
+                };
+
+                // With the same key, keep the record with the largest sequence number, or from the
+                // smallest source number for the records flushed before the sequence numbers were
+                // kept, i.e. If a key exits in the Memtable or an SSTable of younger generation,
+                // ignore its existence in older generations, unless it is a merge to apply to them.
+                if let Some((pending_key, pending_record, pending_separated)) = pending.take() {
+                    if pending_key == key {
+                        let pending_record = match pending_record.record {
+                            Record::Merge(_) => {
+                                // The operands apply to the value, which is read for them.
+                                let record = match separated {
+                                    Some(separated) => separated.resolve(record)?,
+                                    None => record,
+                                };
+                                merge::stack_timed(
+                                    key.as_str(),
+                                    pending_record,
+                                    record,
+                                    merge_operator,
+                                )?
+                            }
+                            _ => pending_record,
+                        };
+                        pending = Some((pending_key, pending_record, pending_separated));
+                        continue;
+                    }
+                    if let Some((pending_record, pending_separated)) =
+                        finish(&pending_key, pending_record, pending_separated)?
+                    {
+                        if !write(pending_key, pending_record, pending_separated)? {
+                            // The writing stage has failed, and its result tells why.
+                            break;
+                        }
+                    }
                 }
-                let sstable_iter = &mut sstable_iters[source - 1];
-                if let Some((key, record)) = sstable_iter.next()? {
-                    heap.push(Reverse((key, source)));
-                    sstable_records[source - 1] = Some(record);
+                pending = Some((key, record, separated));
+            }
+            if let Some((key, record, separated)) = pending.take() {
+                if let Some((record, separated)) = finish(&key, record, separated)? {
+                    write(key, record, separated)?;
                 }
             }
+            Ok(())
+        };
+        let result = merge_all().and_then(|()| {
+            let last_writer = match segment_writer.take() {
+                Some(segment_writer) => Some(segment_writer),
+                // Even an empty merge leaves a segment file behind.
+                None if sstables.is_empty() => Some(SegmentWriter::start(
+                    gen_file_path(),
+                    gen_no,
+                    epoch_no,
+                    options,
+                )?),
+                None => None,
+            };
+            if let Some(last_writer) = last_writer {
+                sstables.push(last_writer.finish(gen_no, epoch_no, options)?);
+            }
+            Ok(())
+        });
+        if let Err(error) = result {
+            // Leave no partition of a failed merge behind.
+            if let Some(segment_writer) = segment_writer {
+                segment_writer.abandon(options);
+            }
+            for sstable in sstables {
+                sstable.deprecate()?;
+            }
+            return Err(error);
         }
-        if !buffer.is_empty() {
-            // Write out the remaining buffered bytes into a chunk.
-            utils::write_chunk(&mut file_writer, &buffer)?;
-        }
-
-        let segment_file = file_writer.into_inner()?;
-        let file_size = segment_file.metadata()?.len() as usize;
-
-        let is_deprecated = Mutex::new(false);
-
-        Ok(SSTable {
-            gen_no,
-            epoch_no,
-            index,
-            file_path,
-            file_size,
-            is_deprecated,
-        })
+        Ok(sstables)
     }
 
     pub fn gen_no(&self) -> usize {
@@ -228,219 +716,1796 @@ impl SSTable {
         self.epoch_no
     }
 
-    pub fn file_size(&self) -> usize {
-        self.file_size
-    }
-
-    pub fn file_path<'a>(&'a self) -> &'a Path {
-        self.file_path.as_path()
+    /// Assign the epoch of an SSTable written but not installed yet, which takes the next one
+    /// only once it is installed, so that the epochs follow the order of the installations.
+    pub(crate) fn set_epoch_no(&mut self, epoch_no: u64) {
+        self.epoch_no = epoch_no;
     }
 
-    /// This is called by the compaction daemon when the SSTable has been merged into a new one.
-    pub fn deprecate(&self) -> Result<()> {
-        let mut is_deprecated = self.is_deprecated.lock()?;
-        *is_deprecated = true;
+    /// Rewrite the generation number at the beginning of the segment file, used for repair. The
+    /// file is rewritten into a copy renamed into place, since a checkpoint may share the original
+    /// through a hard link.
+    pub fn renumber(&mut self, gen_no: usize) -> Result<()> {
+        let temp_file_path = utils::temp_file_path(&self.file_path);
+        self.backend.remove_file(&temp_file_path)?;
+        self.backend.copy(&self.file_path, &temp_file_path)?;
+        self.backend.write_at(
+            &temp_file_path,
+            0,
+            &(gen_no as GenerationNumberType).to_be_bytes(),
+        )?;
+        // Keep the modified time, by which the repair orders the SSTables of a generation.
+        let modified_time = self.backend.modified_time(&self.file_path)?;
+        self.backend
+            .set_modified_time(&temp_file_path, modified_time)?;
+        self.backend.rename(&temp_file_path, &self.file_path)?;
+        self.gen_no = gen_no;
+        self.checksum = OnceLock::new();
+        // The file read so far is the original one.
+        self.mapped_file = OnceLock::new();
+        self.shared_file = OnceLock::new();
         Ok(())
     }
 
-    fn pseudo_iter(&self) -> Result<SSTableIterator> {
-        let mut segment_file = OpenOptions::new()
-            .read(true)
-            .create(false)
-            .open(self.file_path.as_path())?;
-        read_sstable_gen_no(&mut segment_file)?; // Skip the first few bytes.
-        let file_reader = BufReader::new(segment_file);
-        let chunk_buffer = Vec::new();
-        let chunk_offset = 0;
-        Ok(SSTableIterator {
-            file_reader,
-            chunk_buffer,
-            chunk_offset,
-        })
+    pub fn file_size(&self) -> usize {
+        self.file_size
     }
-}
 
-impl Drop for SSTable {
-    fn drop(&mut self) {
-        // If is_deprecated is set, remove the segment file on drop.
-        let is_deprecated = self
-            .is_deprecated
-            .lock()
-            .expect("Failed to lock the mutex for SSTable::is_deprecated");
-        if *is_deprecated {
-            let file_path = self.file_path.as_path();
-            utils::try_remove_file(file_path).expect(&format!(
-                "Failed to remove segment file {}",
-                file_path.display()
-            ));
+    /// The segment file mapped into memory, which is shared by all the views, or None unless the
+    /// options ask for it and the backend can map it.
+    fn mapped_file(&self) -> Result<Option<&MappedFile>> {
+        if !self.mmap_reads {
+            return Ok(None);
         }
+        if let Some(mapped_file) = self.mapped_file.get() {
+            return Ok(mapped_file.as_ref());
+        }
+        let mapped_file = self.backend.map(&self.file_path)?;
+        Ok(self.mapped_file.get_or_init(|| mapped_file).as_ref())
     }
-}
 
-/// This structure is owned by an individual service thread.
-pub struct SSTableView {
-    /// A shared pointer to
-    sstable: Arc<SSTable>,
+    /// The segment file shared by all the views for their positional reads.
+    fn shared_file(&self) -> Result<&dyn BackendFile> {
+        if let Some(shared_file) = self.shared_file.get() {
+            return Ok(shared_file.as_ref());
+        }
+        let shared_file = self.backend.open(&self.file_path)?;
+        Ok(self.shared_file.get_or_init(|| shared_file).as_ref())
+    }
 
-    /// The segment file reader, shared by multiple threads.
-    file_reader: BufReader<File>,
-}
+    /// The CRC32 checksum of the whole segment file, which is read through the first time unless
+    /// the checksum is known from its creation or the manifest.
+    pub fn checksum(&self) -> Result<u32> {
+        if let Some(&checksum) = self.checksum.get() {
+            return Ok(checksum);
+        }
+        let checksum = utils::checksum(&mut self.backend.open(&self.file_path)?)?;
+        Ok(*self.checksum.get_or_init(|| checksum))
+    }
 
-impl SSTableView {
-    pub fn new(sstable: Arc<SSTable>) -> Result<Self> {
-        let mut segment_file = OpenOptions::new()
-            .read(true)
-            .create(false)
-            .open(sstable.file_path.as_path())?;
-        read_sstable_gen_no(&mut segment_file)?; // Skip the first few bytes.
-        let file_reader = BufReader::new(segment_file);
-        Ok(SSTableView {
-            sstable,
-            file_reader,
-        })
+    /// Take the checksum recorded for the segment file instead of reading it through.
+    pub fn set_checksum(&self, checksum: u32) {
+        let _ = self.checksum.set(checksum);
     }
 
-    pub fn get(&mut self, key: &str) -> Result<Option<Record>> {
-        // Find the largest indexed key that is not greater than the query key.
-        if let Some((_, &offset)) = self.sstable.index.range(..=key.to_owned()).next_back() {
-            self.file_reader.seek(std::io::SeekFrom::Start(offset))?;
-            let mut buffer = Vec::new();
-            let num_bytes = utils::read_chunk(&mut self.file_reader, &mut buffer)?;
-            if num_bytes == 0 {
-                return Err(NaiveError::InvalidData);
+    /// Walk the segment file through and check its integrity: the chunks are framed within the
+    /// data and their records decode, the keys come in strictly increasing order, the records
+    /// stored with checksums match them, as do the values separated into the blob files, and the
+    /// index and the footer agree with the chunks.
+    ///
+    /// Reading all of the file, and loading the index unless loaded, this suits the paranoid opens
+    /// and the offline checks rather than the serving path. Only the reads that fail otherwise
+    /// than on the data found are errors.
+    pub fn verify(&self) -> Result<SSTableReport> {
+        let mut report = SSTableReport::default();
+        let mut segment_file = self.backend.open(&self.file_path)?;
+        let data_end = match read_footer(
+            &mut segment_file,
+            &self.file_path,
+            self.file_size,
+            self.comparator,
+        ) {
+            Ok(Some((_, data_end))) => data_end,
+            Ok(None) => self.file_size as u64,
+            Err(_) => {
+                report.add_problem(SSTableProblem::Footer {
+                    offset: self.file_size.saturating_sub(N_BYTES_FOOTER_TRAILER) as u64,
+                });
+                self.file_size as u64
             }
+        };
 
-            // Deserialize the messages in the chunk in order.
-            let mut buffer_reader = &buffer[..];
-            while let Some(command) = utils::read_message::<Command, &[u8]>(&mut buffer_reader)? {
-                match command.get_key().partial_cmp(&key).unwrap() {
-                    std::cmp::Ordering::Less => (),
-                    std::cmp::Ordering::Equal => {
-                        return Ok(Some(Record::from_command(&command)?));
+        segment_file.seek(SeekFrom::Start(N_BYTES_GENERATION_NUMBER as u64))?;
+        let mut data_reader = BufReader::new(segment_file)
+            .take(data_end.saturating_sub(N_BYTES_GENERATION_NUMBER as u64));
+        let mut buffer = Vec::new();
+        let mut properties = SSTableProperties::default();
+        let mut blob_file_nos = Vec::new();
+        // The first keys of the chunks along with their offsets, to look up in the index.
+        let mut chunk_keys = Vec::new();
+        let mut previous_key: Option<String> = None;
+        let mut offset = N_BYTES_GENERATION_NUMBER as u64;
+        while offset < data_end {
+            // No chunk is longer than the rest of the data, however long its length says it is.
+            let max_length = (data_end - offset).saturating_sub(N_BYTES_CHUNK_LENGTH as u64);
+            let num_bytes = match utils::read_chunk_with_limit(
+                &mut data_reader,
+                &mut buffer,
+                max_length as usize,
+            ) {
+                Ok(num_bytes) if num_bytes > 0 => num_bytes,
+                // An empty chunk, or one cut off by the end of the data, ends the chunks early.
+                Ok(_) | Err(NaiveError::FrameTooLarge { .. }) => {
+                    report.add_problem(SSTableProblem::Framing { offset });
+                    break;
+                }
+                Err(NaiveError::IoError(error))
+                    if error.kind() == std::io::ErrorKind::UnexpectedEof =>
+                {
+                    report.add_problem(SSTableProblem::Framing { offset });
+                    break;
+                }
+                Err(error) => return Err(error),
+            };
+            report.num_chunks += 1;
+
+            let mut chunk_reader = &buffer[..];
+            let mut last_key = String::new();
+            loop {
+                let message_offset =
+                    offset + (N_BYTES_CHUNK_LENGTH + num_bytes - chunk_reader.len()) as u64;
+                let mut command = match utils::read_message::<Command, &[u8]>(&mut chunk_reader) {
+                    Ok(Some(command)) => command,
+                    Ok(None) => break,
+                    Err(_) => {
+                        report.add_problem(SSTableProblem::Framing {
+                            offset: message_offset,
+                        });
+                        break;
                     }
-                    std::cmp::Ordering::Greater => {
-                        return Ok(None);
+                };
+                if restore_key(&mut command, &mut last_key).is_err() {
+                    report.add_problem(SSTableProblem::Framing {
+                        offset: message_offset,
+                    });
+                    break;
+                }
+                if let Some(previous_key) = previous_key.as_ref() {
+                    if self.comparator.compare(previous_key, command.get_key()) != Ordering::Less {
+                        report.add_problem(SSTableProblem::KeyOrder {
+                            offset: message_offset,
+                            key: command.get_key().to_owned(),
+                        });
+                    }
+                }
+                if chunk_keys.len() < report.num_chunks {
+                    // This is the first key in the chunk.
+                    chunk_keys.push((command.get_key().to_owned(), offset));
+                }
+                report.num_records += 1;
+                properties.add_command(&command);
+                if command.has_checksum() {
+                    report.num_checksums += 1;
+                }
+                if command.has_blob() {
+                    blob_file_nos.push(command.get_blob().get_file_no());
+                }
+                // A value separated into a blob file is read back, which verifies its checksum.
+                let is_separated = command.has_blob();
+                match self.resolve_blob(&mut command).and_then(|()| {
+                    TimedRecord::from_checked_command(&command, &self.file_path, message_offset)
+                }) {
+                    Ok(_) => (),
+                    Err(NaiveError::Corruption { .. }) => {
+                        report.add_problem(SSTableProblem::Checksum {
+                            offset: message_offset,
+                        })
                     }
+                    Err(_) if is_separated => report.add_problem(SSTableProblem::Checksum {
+                        offset: message_offset,
+                    }),
+                    Err(_) => report.add_problem(SSTableProblem::Framing {
+                        offset: message_offset,
+                    }),
                 }
+                previous_key = Some(command.take_key());
             }
+            offset += (N_BYTES_CHUNK_LENGTH + num_bytes) as u64;
         }
-        Ok(None)
+
+        // The index locates each chunk by its first key.
+        match self.loaded_index() {
+            Ok(loaded_index) => {
+                let index = &loaded_index.index;
+                if index.num_chunks() != chunk_keys.len() {
+                    report.add_problem(SSTableProblem::Index { offset: data_end });
+                }
+                for (key, offset) in chunk_keys.iter() {
+                    let key = OrderedKey::new(key.clone(), self.comparator);
+                    let load = |offset, end_offset| self.load_index_partition(offset, end_offset);
+                    if !matches!(index.floor(&key, load), Ok(Some(found)) if found == *offset) {
+                        report.add_problem(SSTableProblem::Index { offset: *offset });
+                    }
+                }
+            }
+            Err(_) => report.add_problem(SSTableProblem::Index {
+                offset: N_BYTES_GENERATION_NUMBER as u64,
+            }),
+        }
+
+        // What the SSTable took from the footer, or from a scan on open, agrees with the records.
+        properties.num_chunks = report.num_chunks;
+        blob_file_nos.sort_unstable();
+        blob_file_nos.dedup();
+        let key_range = chunk_keys
+            .first()
+            .map(|(min_key, _)| min_key.clone())
+            .zip(previous_key);
+        if properties != self.properties
+            || key_range != self.key_range
+            || blob_file_nos != self.blob_file_nos
+        {
+            report.add_problem(SSTableProblem::Footer { offset: data_end });
+        }
+
+        match report.problems.first() {
+            Some(problem) => log::error!(
+                "Found {} problems in segment file {}, the first being {:?}.",
+                report.num_problems,
+                self.file_path.display(),
+                problem
+            ),
+            None => log::info!(
+                "Verified {} records in segment file {}.",
+                report.num_records,
+                self.file_path.display()
+            ),
+        }
+        Ok(report)
     }
 
-    pub fn epoch_no(&self) -> u64 {
-        self.sstable.epoch_no()
+    /// The number of records, deletions included.
+    pub fn num_entries(&self) -> usize {
+        self.properties.num_entries
     }
-}
 
-/// A pseudo-iterator for SSTable, used when merging old ones into a new one.
-struct SSTableIterator {
-    /// A reader of the segment file.
-    file_reader: BufReader<File>,
+    /// The composition of the records, e.g. how many of them are deletions.
+    pub fn properties(&self) -> &SSTableProperties {
+        &self.properties
+    }
 
-    /// A buffer for holding a chunk of bytes read from file_reader.
-    chunk_buffer: Vec<u8>,
+    pub fn file_path(&self) -> &Path {
+        self.file_path.as_path()
+    }
 
-    /// The offset into chunk_buffer.
-    chunk_offset: u64,
-}
+    /// The numbers of the blob files holding the values separated from the segment file.
+    pub(crate) fn blob_file_nos(&self) -> &[u64] {
+        &self.blob_file_nos
+    }
 
-impl SSTableIterator {
-    fn next(&mut self) -> Result<Option<(String, Record)>> {
-        loop {
-            let mut chunk_cursor = std::io::Cursor::new(&self.chunk_buffer);
-            chunk_cursor.seek(std::io::SeekFrom::Start(self.chunk_offset))?;
-            if let Some(command) =
-                utils::read_message::<Command, std::io::Cursor<&Vec<u8>>>(&mut chunk_cursor)?
-            {
-                self.chunk_offset = chunk_cursor.seek(std::io::SeekFrom::Current(0))?;
-                return Ok(Some((
-                    command.get_key().to_owned(),
-                    Record::from_command(&command)?,
-                )));
-            }
+    /// The paths of the blob files holding the values separated from the segment file.
+    pub fn blob_file_paths(&self) -> Vec<PathBuf> {
+        self.blob_file_nos
+            .iter()
+            .map(|&file_no| self.blob_file_path(file_no))
+            .collect()
+    }
 
-            // Reaching the end of the old chunk, read a new chunk.
-            let num_bytes = utils::read_chunk(&mut self.file_reader, &mut self.chunk_buffer)?;
-            if num_bytes == 0 {
-                return Ok(None);
+    fn blob_file_path(&self, file_no: u64) -> PathBuf {
+        blob::locate_blob_file(self.backend.as_ref(), &self.blob_folder_paths, file_no)
+    }
+
+    /// The blob file shared by the reads of the values separated into it, along with its path.
+    fn shared_blob_file(&self, file_no: u64) -> Result<(Arc<dyn BackendFile>, PathBuf)> {
+        let file_path = self.blob_file_path(file_no);
+        let blob_file = match self.blob_file_nos.binary_search(&file_no) {
+            Ok(index) => &self.blob_files[index],
+            Err(_) => {
+                log::error!(
+                    "Found a reference to blob file {} missing from the footer of {}.",
+                    file_no,
+                    self.file_path.display()
+                );
+                return Err(NaiveError::InvalidData);
             }
-            self.chunk_offset = 0;
+        };
+        if let Some(blob_file) = blob_file.get() {
+            return Ok((blob_file.clone(), file_path));
         }
+        let opened_file = Arc::from(self.backend.open(&file_path)?);
+        Ok((blob_file.get_or_init(|| opened_file).clone(), file_path))
     }
-}
 
-/// Read the beginning first few bytes of the segment file as the generation number.
-fn read_sstable_gen_no(segment_file: &mut File) -> Result<usize> {
-    let mut gen_no_bytes = [0u8; N_BYTES_GENERATION_NUMBER];
-    segment_file.read_exact(&mut gen_no_bytes)?;
-    Ok(GenerationNumberType::from_be_bytes(gen_no_bytes) as usize)
-}
+    /// Read the value of a command separated into a blob file back into the command, so that it
+    /// decodes like the ones stored in place.
+    fn resolve_blob(&self, command: &mut Command) -> Result<()> {
+        if command.has_blob() {
+            let blob = command.take_blob();
+            let (blob_file, file_path) = self.shared_blob_file(blob.get_file_no())?;
+            command.set_value(blob::read_blob(blob_file.as_ref(), &file_path, &blob)?);
+        }
+        Ok(())
+    }
 
-/// Scan the segment file and build up the in-memory index.
-fn build_sstable_index(segment_file: File) -> Result<SSTableIndex> {
-    let mut file_reader = BufReader::new(segment_file);
+    /// Decode a command read from the given offset of the segment file, leaving the value in its
+    /// blob file if separated, in which case the checksum is not verified, since it covers the
+    /// value.
+    fn decode_separated(
+        &self,
+        mut command: Command,
+        file_offset: u64,
+    ) -> Result<(String, TimedRecord, Option<SeparatedValue>)> {
+        let separated = if command.has_blob() {
+            let blob = command.take_blob();
+            let (blob_file, file_path) = self.shared_blob_file(blob.get_file_no())?;
+            let checksum = command.has_checksum().then(|| command.get_checksum());
+            command.clear_checksum();
+            command.set_value(String::new());
+            Some(SeparatedValue {
+                blob,
+                checksum,
+                blob_file,
+                file_path,
+            })
+        } else {
+            None
+        };
+        let record = TimedRecord::from_checked_command(&command, &self.file_path, file_offset)?;
+        Ok((command.take_key(), record, separated))
+    }
 
-    let mut index = SSTableIndex::new();
-    let mut buffer = Vec::new();
-    loop {
-        let current_offset = file_reader.seek(std::io::SeekFrom::Current(0))?;
+    /// The smallest and the largest keys, or None if the SSTable is empty.
+    pub fn key_range(&self) -> Option<(&str, &str)> {
+        self.key_range
+            .as_ref()
+            .map(|(min_key, max_key)| (min_key.as_str(), max_key.as_str()))
+    }
 
-        // Read the entire chunk into the buffer.
-        let num_bytes = utils::read_chunk(&mut file_reader, &mut buffer)?;
-        if num_bytes == 0 {
-            break;
+    /// The index, the prefix filter and the inline records, which are built by scanning the
+    /// segment file the first time. The concurrent first queries wait for a single scan.
+    fn loaded_index(&self) -> Result<&LoadedIndex> {
+        if let Some(loaded_index) = self.loaded_index.get() {
+            return Ok(loaded_index);
         }
+        let _loading_guard = self.index_loading.lock()?;
+        if let Some(loaded_index) = self.loaded_index.get() {
+            return Ok(loaded_index);
+        }
+        log::info!(
+            "Going to load the index of segment file {}.",
+            self.file_path.display()
+        );
+        let (loaded_index, _) = scan_segment_file(
+            PositionalReader::new(self.shared_file()?, N_BYTES_GENERATION_NUMBER as u64),
+            &self.file_path,
+            self.file_size,
+            self.comparator,
+            &self.index_options,
+        )?;
+        Ok(self.loaded_index.get_or_init(|| loaded_index))
+    }
+
+    /// Whether the index is in memory, i.e. whether a query has needed it since the SSTable was
+    /// opened, or it was known from the start.
+    pub fn is_index_loaded(&self) -> bool {
+        self.loaded_index.get().is_some()
+    }
+
+    /// Estimate the records with keys in [start, end) at the granularity of chunks, by locating
+    /// the range in the index and assuming the records are evenly sized.
+    pub fn estimate_range(&self, start: &str, end: &str) -> RangeEstimate {
+        self.estimate(&(start.to_owned()..end.to_owned()))
+    }
+
+    /// Estimate the records with keys in the range like `estimate_range`, or none if the index
+    /// fails to load.
+    pub fn estimate<R: RangeBounds<String>>(&self, range: &R) -> RangeEstimate {
+        if self.comparator.is_empty_range(range) {
+            return RangeEstimate::default();
+        }
+        let index = match self.loaded_index() {
+            Ok(loaded_index) => &loaded_index.index,
+            Err(error) => {
+                log::warn!(
+                    "Failed to load the index of segment file {}: {:?}",
+                    self.file_path.display(),
+                    error
+                );
+                return RangeEstimate::default();
+            }
+        };
+        // The offset of the first chunk starting at or after the key, or of the next partition of
+        // the index if the one of the key is not in memory, so that no chunk is read.
+        let offset_of = |key: &String| {
+            let key = OrderedKey::new(key.clone(), self.comparator);
+            index
+                .ceiling_resident(&key, true)
+                .unwrap_or(self.file_size as u64)
+        };
+        let start = match range.start_bound() {
+            Bound::Included(key) | Bound::Excluded(key) => offset_of(key),
+            Bound::Unbounded => N_BYTES_GENERATION_NUMBER as u64,
+        };
+        let end = match range.end_bound() {
+            Bound::Included(key) | Bound::Excluded(key) => offset_of(key),
+            Bound::Unbounded => self.file_size as u64,
+        };
+        let num_bytes = end.saturating_sub(start) as usize;
+        let data_size = self
+            .file_size
+            .saturating_sub(N_BYTES_GENERATION_NUMBER)
+            .max(1);
+        RangeEstimate {
+            num_keys: (self.properties.num_entries as f64 * num_bytes as f64 / data_size as f64)
+                .round() as usize,
+            num_bytes,
+        }
+    }
+
+    /// Read the chunks holding the keys in the ranges, so that the later reads of them find the
+    /// chunks in the page cache, returning the number of bytes read. Overlapping ranges are only
+    /// read once.
+    pub fn warm_up<R: RangeBounds<String>>(&self, ranges: &[R]) -> Result<usize> {
+        let mut byte_ranges = ranges
+            .iter()
+            .map(|range| self.byte_range(range))
+            .filter(|byte_range| !matches!(byte_range, Ok((start, end)) if start >= end))
+            .collect::<Result<Vec<_>>>()?;
+        byte_ranges.sort();
+
+        let mut segment_file = self.backend.open(&self.file_path)?;
+        let mut buffer = Vec::new();
+        let mut num_bytes = 0;
+        let mut read_until = 0;
+        for (start, end) in byte_ranges {
+            let start = start.max(read_until);
+            if start >= end {
+                continue;
+            }
+            segment_file.seek(SeekFrom::Start(start))?;
+            buffer.resize((end - start) as usize, 0);
+            segment_file.read_exact(&mut buffer)?;
+            num_bytes += buffer.len();
+            read_until = end;
+        }
+        Ok(num_bytes)
+    }
+
+    /// The offsets of the chunks holding the keys in the range, from the start of the first one
+    /// to the end of the last one.
+    fn byte_range<R: RangeBounds<String>>(&self, range: &R) -> Result<(u64, u64)> {
+        let index = &self.loaded_index()?.index;
+        let ordered_key = |key: &String| OrderedKey::new(key.clone(), self.comparator);
+        let load = |offset, end_offset| self.load_index_partition(offset, end_offset);
+        let start = match range.start_bound() {
+            Bound::Included(key) | Bound::Excluded(key) => index.floor(&ordered_key(key), load)?,
+            Bound::Unbounded => None,
+        }
+        .unwrap_or(N_BYTES_GENERATION_NUMBER as u64);
+        let end = match range.end_bound() {
+            Bound::Included(key) => index.ceiling(&ordered_key(key), false, load)?,
+            Bound::Excluded(key) => index.ceiling(&ordered_key(key), true, load)?,
+            Bound::Unbounded => None,
+        }
+        .unwrap_or(self.file_size as u64);
+        Ok((start, end))
+    }
+
+    /// Read the first keys of the chunks between the offsets of the segment file, e.g. to load a
+    /// partition of the index again.
+    fn load_index_partition(&self, offset: u64, end_offset: u64) -> Result<ChunkIndex> {
+        match self.mapped_file()? {
+            Some(mapped_file) => {
+                let bytes = (**mapped_file)
+                    .as_ref()
+                    .get(offset as usize..end_offset as usize)
+                    .ok_or(NaiveError::InvalidData)?;
+                read_chunk_index(bytes, offset, self.comparator)
+            }
+            None => {
+                let file_reader = PositionalReader::new(self.shared_file()?, offset);
+                let file_reader = BufReader::new(file_reader).take(end_offset - offset);
+                read_chunk_index(file_reader, offset, self.comparator)
+            }
+        }
+    }
+
+    /// The number of partitions of the index, and how many of them are in memory, or zeros if the
+    /// index is not loaded yet.
+    pub fn index_partitions(&self) -> (usize, usize) {
+        self.loaded_index.get().map_or((0, 0), |loaded_index| {
+            (
+                loaded_index.index.num_partitions(),
+                loaded_index.index.num_resident_partitions(),
+            )
+        })
+    }
+
+    /// Whether the key falls into the key range and passes the prefix filter, i.e. whether the
+    /// SSTable may contain it.
+    pub fn may_contain(&self, key: &str) -> bool {
+        let in_key_range = match self.key_range() {
+            Some((min_key, max_key)) => {
+                self.comparator.compare(min_key, key) != Ordering::Greater
+                    && self.comparator.compare(key, max_key) != Ordering::Greater
+            }
+            None => false,
+        };
+        in_key_range && self.passes_prefix_filter(key)
+    }
+
+    /// Whether the key range overlaps the range, i.e. whether the SSTable may contain any key in
+    /// it.
+    pub fn may_overlap<R: RangeBounds<String>>(&self, range: &R) -> bool {
+        match self.key_range() {
+            Some((min_key, max_key)) => {
+                self.comparator.is_after_start(range.start_bound(), max_key)
+                    && self.comparator.is_before_end(range.end_bound(), min_key)
+            }
+            None => false,
+        }
+    }
+
+    /// Whether the SSTable may contain any key starting with the given prefix.
+    pub fn may_contain_prefix(&self, prefix: &str) -> bool {
+        self.key_range().is_some() && self.passes_prefix_filter(prefix)
+    }
+
+    /// Whether the prefix of the string may be in the prefix filter, which is vacuously true if
+    /// there is no filter or the string has no prefix, or if the filter fails to load, in which
+    /// case the reads of the segment file fail instead.
+    fn passes_prefix_filter(&self, key: &str) -> bool {
+        let prefix = match self
+            .index_options
+            .prefix_extractor
+            .and_then(|prefix_extractor| prefix_extractor.prefix(key))
+        {
+            Some(prefix) => prefix,
+            None => return true,
+        };
+        match self.loaded_index() {
+            Ok(loaded_index) => loaded_index
+                .prefix_filter
+                .as_ref()
+                .is_none_or(|prefix_filter| prefix_filter.may_contain(prefix)),
+            Err(_) => true,
+        }
+    }
+
+    /// Whether the reads have failed often enough in a row for the SSTable to be quarantined, so
+    /// that the gets needing it fail fast instead of trying again.
+    pub fn is_quarantined(&self) -> bool {
+        self.is_quarantined.load(AtomicOrdering::SeqCst)
+    }
+
+    /// Count a read that has failed, or reset the count after one that has not, and return
+    /// whether this has quarantined the SSTable, which a threshold of zero never does.
+    pub(crate) fn record_read(&self, is_error: bool, error_threshold: usize) -> bool {
+        if !is_error {
+            // Skip the store on the common path of no errors.
+            if self.num_read_errors.load(AtomicOrdering::SeqCst) > 0 {
+                self.num_read_errors.store(0, AtomicOrdering::SeqCst);
+            }
+            return false;
+        }
+        let num_errors = self.num_read_errors.fetch_add(1, AtomicOrdering::SeqCst) + 1;
+        error_threshold > 0
+            && num_errors >= error_threshold
+            && !self.is_quarantined.swap(true, AtomicOrdering::SeqCst)
+    }
+
+    /// The number of reads in a row that have failed.
+    pub fn num_read_errors(&self) -> usize {
+        self.num_read_errors.load(AtomicOrdering::SeqCst)
+    }
+
+    /// This is called by the compaction daemon when the SSTable has been merged into a new one.
+    pub fn deprecate(&self) -> Result<()> {
+        let mut is_deprecated = self.is_deprecated.lock()?;
+        *is_deprecated = true;
+        Ok(())
+    }
+
+    pub fn pseudo_iter(self: &Arc<Self>) -> Result<SSTableIterator> {
+        // Open the segment file up front, so that a missing one fails here rather than the first
+        // read, which starts past the first few bytes.
+        self.shared_file()?;
+        let readahead = Readahead::new(N_BYTES_GENERATION_NUMBER as u64, self.readahead_size);
+        let chunk_buffer = Vec::new();
+        let chunk_file_offset = 0;
+        let chunk_offset = 0;
+        Ok(SSTableIterator {
+            sstable: self.clone(),
+            readahead,
+            chunk_buffer,
+            chunk_file_offset,
+            chunk_offset,
+            last_key: String::new(),
+            is_done: false,
+        })
+    }
+
+    /// Create a pseudo-iterator starting from the chunk that may contain the key.
+    /// Note that the iterator may yield a few keys smaller than the given one.
+    pub fn pseudo_iter_from(self: &Arc<Self>, key: &str) -> Result<SSTableIterator> {
+        let mut sstable_iter = self.pseudo_iter()?;
+        let key = OrderedKey::new(key.to_owned(), self.comparator);
+        let load = |offset, end_offset| self.load_index_partition(offset, end_offset);
+        if let Some(offset) = self.loaded_index()?.index.floor(&key, load)? {
+            sstable_iter.readahead.seek(offset);
+        }
+        Ok(sstable_iter)
+    }
+}
+
+impl Drop for SSTable {
+    fn drop(&mut self) {
+        // If is_deprecated is set, remove the segment file on drop.
+        let is_deprecated = self
+            .is_deprecated
+            .lock()
+            .expect("Failed to lock the mutex for SSTable::is_deprecated");
+        if *is_deprecated {
+            let file_path = self.file_path.as_path();
+            Trash::discard(self.backend.as_ref(), self.trash.as_ref(), file_path).unwrap_or_else(
+                |_| panic!("Failed to remove segment file {}", file_path.display()),
+            );
+        }
+    }
+}
+
+/// This structure is owned by an individual service thread.
+pub struct SSTableView {
+    /// A shared pointer to the SSTable, which also pins its file until the view drops.
+    sstable: Arc<SSTable>,
+}
+
+impl SSTableView {
+    pub fn new(sstable: Arc<SSTable>) -> Result<Self> {
+        // Open the segment file up front unless mapped, so that a missing one fails the view.
+        if sstable.mapped_file()?.is_none() {
+            sstable.shared_file()?;
+        }
+        Ok(SSTableView { sstable })
+    }
+
+    /// Read the chunk at the offset of the segment file into the buffer, returning its length.
+    fn read_chunk_at(&mut self, offset: u64, buffer: &mut Vec<u8>) -> Result<usize> {
+        let num_bytes = match self.sstable.mapped_file()? {
+            Some(mapped_file) => {
+                let mut bytes = (**mapped_file)
+                    .as_ref()
+                    .get(offset as usize..)
+                    .ok_or(NaiveError::InvalidData)?;
+                utils::read_chunk(&mut bytes, buffer)?
+            }
+            None => {
+                let mut file_reader = PositionalReader::new(self.sstable.shared_file()?, offset);
+                utils::read_chunk(&mut file_reader, buffer)?
+            }
+        };
+        if num_bytes == 0 {
+            return Err(NaiveError::InvalidData);
+        }
+        Ok(num_bytes)
+    }
+
+    /// Get the record of the key along with when it was written.
+    pub fn get(&mut self, key: &str) -> Result<Option<TimedRecord>> {
+        let ordered_key = OrderedKey::new(key.to_owned(), self.sstable.comparator);
+        if let Some(record) = self
+            .sstable
+            .loaded_index()?
+            .inline_records
+            .get(&ordered_key)
+        {
+            return Ok(Some(record.clone()));
+        }
+        Ok(self.locate(key)?.map(|(record, _, _)| record))
+    }
+
+    /// Get the encoded command of the key as stored in the segment file, along with the offset
+    /// of its message in the file. The checksum, if any, is verified all the same.
+    pub fn get_raw(&mut self, key: &str) -> Result<Option<(Vec<u8>, u64)>> {
+        Ok(self
+            .locate(key)?
+            .map(|(_, bytes, file_offset)| (bytes, file_offset)))
+    }
+
+    /// Tell what the record of the key is without decoding its value, which stops at the key in
+    /// its chunk. The checksum is not verified, since it covers the value skipped.
+    pub fn get_kind(&mut self, key: &str) -> Result<Option<RecordKind>> {
+        let ordered_key = OrderedKey::new(key.to_owned(), self.sstable.comparator);
+        if let Some(record) = self
+            .sstable
+            .loaded_index()?
+            .inline_records
+            .get(&ordered_key)
+        {
+            return Ok(Some(record.record.kind()));
+        }
+        if let Some((buffer, _)) = self.read_chunk_of(key)? {
+            let mut buffer_reader = &buffer[..];
+            let mut command_key = String::new();
+            while let Some(message) = utils::split_chunk(&mut buffer_reader)? {
+                let kind = peek_command(message, &mut command_key)?;
+                match self.sstable.comparator.compare(&command_key, key) {
+                    Ordering::Less => (),
+                    Ordering::Equal => return Ok(Some(kind)),
+                    Ordering::Greater => return Ok(None),
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Read the chunk that would hold the key, along with its offset in the file, or None if the
+    /// key precedes all the indexed ones.
+    fn read_chunk_of(&mut self, key: &str) -> Result<Option<(Vec<u8>, u64)>> {
+        // Find the largest indexed key that is not greater than the query key.
+        let ordered_key = OrderedKey::new(key.to_owned(), self.sstable.comparator);
+        let sstable = &self.sstable;
+        let load = |offset, end_offset| sstable.load_index_partition(offset, end_offset);
+        let offset = match sstable.loaded_index()?.index.floor(&ordered_key, load)? {
+            Some(offset) => offset,
+            None => return Ok(None),
+        };
+        let mut buffer = Vec::new();
+        self.read_chunk_at(offset, &mut buffer)?;
+        Ok(Some((buffer, offset)))
+    }
+
+    /// Find the record of the key, its encoded command and the offset of its message in the file.
+    fn locate(&mut self, key: &str) -> Result<Option<(TimedRecord, Vec<u8>, u64)>> {
+        if let Some((buffer, offset)) = self.read_chunk_of(key)? {
+            // Deserialize the messages in the chunk in order.
+            let mut buffer_reader = &buffer[..];
+            let mut message_offset = 0;
+            let mut last_key = String::new();
+            while let Some(mut command) = utils::read_message::<Command, &[u8]>(&mut buffer_reader)?
+            {
+                let next_message_offset = buffer.len() - buffer_reader.len();
+                let is_key_shared = command.get_shared_key_length() > 0;
+                restore_key(&mut command, &mut last_key)?;
+                match self.sstable.comparator.compare(command.get_key(), key) {
+                    Ordering::Less => (),
+                    Ordering::Equal => {
+                        // Locate the message in the file in case its checksum mismatches.
+                        let file_offset = offset + (N_BYTES_CHUNK_LENGTH + message_offset) as u64;
+                        let is_separated = command.has_blob();
+                        self.sstable.resolve_blob(&mut command)?;
+                        let record = TimedRecord::from_checked_command(
+                            &command,
+                            self.sstable.file_path(),
+                            file_offset,
+                        )?;
+                        // A key stored as a suffix or a value stored in a blob file is encoded in
+                        // full again, so that the command stands on its own.
+                        let bytes = if is_key_shared || is_separated {
+                            command.write_to_bytes()?
+                        } else {
+                            buffer[message_offset + N_BYTES_CHUNK_LENGTH..next_message_offset]
+                                .to_vec()
+                        };
+                        return Ok(Some((record, bytes, file_offset)));
+                    }
+                    Ordering::Greater => {
+                        return Ok(None);
+                    }
+                }
+                message_offset = next_message_offset;
+            }
+        }
+        Ok(None)
+    }
+
+    /// Pick a key from a random chunk, or None if the picked record is a deletion or the SSTable
+    /// is empty. Keys in smaller chunks are more likely to be picked.
+    pub fn sample_key<R: Rng>(&mut self, rng: &mut R) -> Result<Option<String>> {
+        let sstable = &self.sstable;
+        let index = &sstable.loaded_index()?.index;
+        let num_chunks = index.num_chunks();
+        if num_chunks == 0 {
+            return Ok(None);
+        }
+        let load = |offset, end_offset| sstable.load_index_partition(offset, end_offset);
+        let offset = index
+            .nth(rng.gen_range(0..num_chunks), load)?
+            .ok_or(NaiveError::InvalidData)?;
+        let mut buffer = Vec::new();
+        self.read_chunk_at(offset, &mut buffer)?;
+
+        let mut buffer_reader = &buffer[..];
+        let mut commands = Vec::new();
+        let mut last_key = String::new();
+        while let Some(mut command) = utils::read_message::<Command, &[u8]>(&mut buffer_reader)? {
+            restore_key(&mut command, &mut last_key)?;
+            commands.push(command);
+        }
+        if commands.is_empty() {
+            return Err(NaiveError::InvalidData);
+        }
+        let mut command = commands.swap_remove(rng.gen_range(0..commands.len()));
+        Ok(match command.get_command_type() {
+            CommandType::SET_VALUE | CommandType::MERGE => Some(command.take_key()),
+            CommandType::DELETE => None,
+            CommandType::BATCH => return Err(NaiveError::InvalidData),
+        })
+    }
+
+    /// Whether this is a view of the SSTable rather than of any other, e.g. of one it replaced.
+    pub fn is_of(&self, sstable: &Arc<SSTable>) -> bool {
+        Arc::ptr_eq(&self.sstable, sstable)
+    }
+}
+
+/// A pseudo-iterator for SSTable, used when merging old ones into a new one or scanning.
+pub struct SSTableIterator {
+    /// Pin the SSTable so that its file is not removed during the iteration.
+    sstable: Arc<SSTable>,
+
+    /// The cursor over the shared segment file, which reads further ahead the longer the
+    /// iteration goes on.
+    readahead: Readahead,
+
+    /// A buffer for holding a chunk of bytes read from the segment file.
+    chunk_buffer: Vec<u8>,
+
+    /// The offset of the chunk in the segment file.
+    chunk_file_offset: u64,
+
+    /// The offset into chunk_buffer.
+    chunk_offset: u64,
+
+    /// The key read last from the chunk, which the next key may share a prefix with.
+    last_key: String,
+
+    /// Whether the iterator has reached the end, i.e. the empty chunk before the footer, or a read
+    /// has failed, after which it yields nothing more.
+    is_done: bool,
+}
+
+impl SSTableIterator {
+    /// Get the next record along with when it was written.
+    pub fn next_timed(&mut self) -> Result<Option<(String, TimedRecord)>> {
+        self.next_with(|sstable, mut command, file_offset| {
+            sstable.resolve_blob(&mut command)?;
+            let record =
+                TimedRecord::from_checked_command(&command, &sstable.file_path, file_offset)?;
+            Ok((command.take_key(), record))
+        })
+    }
+
+    /// Get the next record like `next_timed`, but leave its value in the blob file if separated.
+    fn next_separated(&mut self) -> Result<Option<(String, TimedRecord, Option<SeparatedValue>)>> {
+        self.next_with(|sstable, command, file_offset| {
+            sstable.decode_separated(command, file_offset)
+        })
+    }
+
+    /// Read the next command and decode it, after which the iterator yields nothing more if
+    /// there is none or either has failed.
+    fn next_with<T>(
+        &mut self,
+        decode: impl FnOnce(&SSTable, Command, u64) -> Result<T>,
+    ) -> Result<Option<T>> {
+        if self.is_done {
+            return Ok(None);
+        }
+        let result = self.read_next().and_then(|next| {
+            next.map(|(command, file_offset)| decode(&self.sstable, command, file_offset))
+                .transpose()
+        });
+        self.is_done = !matches!(result, Ok(Some(_)));
+        result
+    }
+
+    /// Read the next command with its full key, along with the offset of its message in the file.
+    fn read_next(&mut self) -> Result<Option<(Command, u64)>> {
+        loop {
+            let mut chunk_cursor = std::io::Cursor::new(&self.chunk_buffer);
+            chunk_cursor.seek(std::io::SeekFrom::Start(self.chunk_offset))?;
+            if let Some(mut command) =
+                utils::read_message::<Command, std::io::Cursor<&Vec<u8>>>(&mut chunk_cursor)?
+            {
+                restore_key(&mut command, &mut self.last_key)?;
+                let file_offset =
+                    self.chunk_file_offset + N_BYTES_CHUNK_LENGTH as u64 + self.chunk_offset;
+                self.chunk_offset = chunk_cursor.stream_position()?;
+                return Ok(Some((command, file_offset)));
+            }
+
+            // Reaching the end of the old chunk, read a new chunk.
+            self.chunk_file_offset = self.readahead.position();
+            let mut file_reader = self.readahead.reader(self.sstable.shared_file()?);
+            let num_bytes = utils::read_chunk(&mut file_reader, &mut self.chunk_buffer)?;
+            if num_bytes == 0 {
+                return Ok(None);
+            }
+            self.chunk_offset = 0;
+            self.last_key.clear();
+        }
+    }
+}
+
+impl Iterator for SSTableIterator {
+    type Item = Result<(String, Record)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_timed()
+            .transpose()
+            .map(|result| result.map(|(key, timed_record)| (key, timed_record.record)))
+    }
+}
+
+/// Decode the key and the kind of an encoded `Command`, skipping over its value and operands. The
+/// key holds the key before it in the chunk, which is replaced by the full key of the command.
+fn peek_command(message: &[u8], key: &mut String) -> Result<RecordKind> {
+    let mut input = protobuf::CodedInputStream::from_bytes(message);
+    let mut command_type = CommandType::SET_VALUE;
+    let mut expires_at_ms = None;
+    let mut key_suffix = String::new();
+    let mut shared_key_length = 0;
+    while !input.eof()? {
+        let (field_number, wire_type) = input.read_tag_unpack()?;
+        match field_number {
+            1 => command_type = input.read_enum()?,
+            2 => input.read_string_into(&mut key_suffix)?,
+            8 => expires_at_ms = Some(input.read_uint64()?),
+            9 => shared_key_length = input.read_uint32()? as usize,
+            _ => input.skip_field(wire_type)?,
+        }
+    }
+    if !key.is_char_boundary(shared_key_length) {
+        return Err(NaiveError::InvalidData);
+    }
+    key.truncate(shared_key_length);
+    key.push_str(&key_suffix);
+    Ok(match (command_type, expires_at_ms) {
+        (CommandType::SET_VALUE, None) => RecordKind::Value,
+        (CommandType::SET_VALUE, Some(expires_at_ms)) => {
+            RecordKind::ExpiringValue { expires_at_ms }
+        }
+        (CommandType::DELETE, _) => RecordKind::Deleted,
+        (CommandType::MERGE, _) => RecordKind::Merge,
+        (CommandType::BATCH, _) => return Err(NaiveError::InvalidData),
+    })
+}
+
+/// The folder holding the segment file.
+fn parent_folder_path(file_path: &Path) -> &Path {
+    file_path.parent().unwrap_or(Path::new(""))
+}
+
+/// Read the beginning first few bytes of the segment file as the generation number.
+fn read_sstable_gen_no(segment_file: &mut impl Read) -> Result<usize> {
+    let mut gen_no_bytes = [0u8; N_BYTES_GENERATION_NUMBER];
+    segment_file.read_exact(&mut gen_no_bytes)?;
+    Ok(GenerationNumberType::from_be_bytes(gen_no_bytes) as usize)
+}
+
+/// The key range of a segment file, the smallest key being the first key of its index.
+fn key_range_of(index: &SSTableIndex, max_key: Option<String>) -> Option<(String, String)> {
+    index
+        .keys()
+        .next()
+        .zip(max_key)
+        .map(|(min_key, max_key)| (min_key.as_str().to_owned(), max_key))
+}
+
+/// Split the index of the chunks of a segment file into partitions as configured.
+fn partition_index(
+    index: SSTableIndex,
+    file_size: usize,
+    index_options: &IndexOptions,
+) -> PartitionedIndex {
+    PartitionedIndex::new(
+        index,
+        file_size as u64,
+        index_options.index_partition_size,
+        index_options.max_resident_index_partitions,
+    )
+}
+
+/// Read the first keys of the chunks starting at the given offset of the segment file.
+fn read_chunk_index(
+    mut reader: impl Read,
+    mut offset: u64,
+    comparator: &'static dyn Comparator,
+) -> Result<ChunkIndex> {
+    let mut chunks = ChunkIndex::new();
+    let mut buffer = Vec::new();
+    loop {
+        let num_bytes = utils::read_chunk(&mut reader, &mut buffer)?;
+        if num_bytes == 0 {
+            break;
+        }
+        // The first key of a chunk is always stored in full.
+        let mut command = utils::read_message::<Command, &[u8]>(&mut &buffer[..])?
+            .ok_or(NaiveError::InvalidData)?;
+        restore_key(&mut command, &mut String::new())?;
+        chunks.insert(OrderedKey::new(command.take_key(), comparator), offset);
+        offset += (N_BYTES_CHUNK_LENGTH + num_bytes) as u64;
+    }
+    Ok(chunks)
+}
+
+/// Collects the distinct prefixes of keys in increasing order to build a prefix Bloom filter.
+struct PrefixFilterBuilder {
+    prefix_extractor: Option<&'static dyn PrefixExtractor>,
+    bits_per_key: usize,
+    prefixes: Vec<String>,
+}
+
+impl PrefixFilterBuilder {
+    fn new(index_options: &IndexOptions) -> Self {
+        Self {
+            prefix_extractor: index_options.prefix_extractor,
+            bits_per_key: index_options.bloom_filter_bits_per_key,
+            prefixes: Vec::new(),
+        }
+    }
+
+    fn add(&mut self, key: &str) {
+        if let Some(prefix) = self
+            .prefix_extractor
+            .and_then(|prefix_extractor| prefix_extractor.prefix(key))
+        {
+            // Keys sharing a prefix are mostly adjacent, so this dedups most of them.
+            if self.prefixes.last().map(String::as_str) != Some(prefix) {
+                self.prefixes.push(prefix.to_owned());
+            }
+        }
+    }
+
+    fn build(self) -> Option<BloomFilter> {
+        self.prefix_extractor?;
+        let mut prefix_filter = BloomFilter::new(self.prefixes.len(), self.bits_per_key);
+        for prefix in self.prefixes.iter() {
+            prefix_filter.insert(prefix);
+        }
+        Some(prefix_filter)
+    }
+}
+
+/// Collects the records of the small values and of the deletions, in increasing order of keys,
+/// until they take up the capacity.
+struct InlineRecordsBuilder {
+    comparator: &'static dyn Comparator,
+    value_size: usize,
+    capacity: usize,
+    num_bytes: usize,
+    records: BTreeMap<OrderedKey, TimedRecord>,
+}
+
+impl InlineRecordsBuilder {
+    fn new(index_options: &IndexOptions) -> Self {
+        Self {
+            comparator: index_options.comparator,
+            value_size: index_options.inline_value_size,
+            capacity: index_options.inline_values_capacity,
+            num_bytes: 0,
+            records: BTreeMap::new(),
+        }
+    }
+
+    fn add(&mut self, key: &str, timed_record: &TimedRecord) {
+        if self.value_size == 0 {
+            return;
+        }
+        let value_size = match &timed_record.record {
+            Record::Value(value) | Record::ExpiringValue { value, .. } => value.len(),
+            Record::Deleted => 0,
+            // The operands need the older records to make sense of.
+            Record::Merge(_) => return,
+        };
+        let num_bytes = key.len() + value_size;
+        if value_size > self.value_size || self.num_bytes + num_bytes > self.capacity {
+            return;
+        }
+        self.num_bytes += num_bytes;
+        self.records.insert(
+            OrderedKey::new(key.to_owned(), self.comparator),
+            timed_record.clone(),
+        );
+    }
+
+    /// Add a command read from the given location of the segment file. The ones failing their
+    /// checksums are left out, so that the gets of them fail on reading the file instead.
+    fn add_command(&mut self, command: &Command, file_path: &Path, offset: u64) {
+        // The values separated into the blob files are large enough to leave there.
+        if self.value_size == 0 || command.get_value().len() > self.value_size || command.has_blob()
+        {
+            return;
+        }
+        if let Ok(timed_record) = TimedRecord::from_checked_command(command, file_path, offset) {
+            self.add(command.get_key(), &timed_record);
+        }
+    }
+
+    fn build(self) -> BTreeMap<OrderedKey, TimedRecord> {
+        self.records
+    }
+}
+
+/// Scan the chunks of the segment file of the given size, read past its generation number, and
+/// build up the in-memory parts of the SSTable, also returning what a footer would tell about them,
+/// or fail with ComparatorMismatch if the keys are out of the order of the comparator.
+fn scan_segment_file(
+    segment_file: impl Read,
+    file_path: &Path,
+    file_size: usize,
+    comparator: &'static dyn Comparator,
+    index_options: &IndexOptions,
+) -> Result<(LoadedIndex, SegmentFooter)> {
+    let mut prefix_filter_builder = PrefixFilterBuilder::new(index_options);
+    let mut inline_records_builder = InlineRecordsBuilder::new(index_options);
+    let mut blob_file_nos = Vec::new();
+    let (index, max_key, properties) = build_sstable_index(
+        segment_file,
+        file_path,
+        comparator,
+        &mut prefix_filter_builder,
+        &mut inline_records_builder,
+        &mut blob_file_nos,
+    )
+    .map_err(|error| match error {
+        NaiveError::ComparatorMismatch(message) => {
+            NaiveError::ComparatorMismatch(format!("{} in {}", message, file_path.display()))
+        }
+        error => error,
+    })?;
+    let key_range = key_range_of(&index, max_key);
+    blob_file_nos.sort_unstable();
+    blob_file_nos.dedup();
+    let loaded_index = LoadedIndex {
+        index: partition_index(index, file_size, index_options),
+        prefix_filter: prefix_filter_builder.build(),
+        inline_records: inline_records_builder.build(),
+    };
+    let footer = SegmentFooter {
+        properties,
+        key_range,
+        blob_file_nos,
+        blob_file_bytes: Vec::new(),
+        format_version: FORMAT_VERSION_FULL_KEYS,
+    };
+    Ok((loaded_index, footer))
+}
+
+/// Scan the segment file and build up the in-memory index, also returning the largest key and the
+/// properties counted, or fail with ComparatorMismatch if the keys are out of the order of the
+/// comparator.
+fn build_sstable_index(
+    segment_file: impl Read,
+    file_path: &Path,
+    comparator: &'static dyn Comparator,
+    prefix_filter_builder: &mut PrefixFilterBuilder,
+    inline_records_builder: &mut InlineRecordsBuilder,
+    blob_file_nos: &mut Vec<u64>,
+) -> Result<(SSTableIndex, Option<String>, SSTableProperties)> {
+    let mut file_reader = BufReader::new(segment_file);
+    // The reader starts right after the generation number.
+    let mut next_offset = N_BYTES_GENERATION_NUMBER as u64;
+
+    let mut index = SSTableIndex::new();
+    let mut buffer = Vec::new();
+    let mut max_key = None;
+    let mut properties = SSTableProperties::default();
+    // Each key must come strictly after the one before it.
+    let check_order = |last_key: &Option<String>, key: &str| match last_key {
+        Some(last_key) if comparator.compare(last_key, key) != Ordering::Less => {
+            Err(NaiveError::ComparatorMismatch(format!(
+                "found key {:?} after {:?} against the order of {}",
+                key,
+                last_key,
+                comparator.name()
+            )))
+        }
+        _ => Ok(()),
+    };
+    loop {
+        let current_offset = next_offset;
+
+        // Read the entire chunk into the buffer.
+        let num_bytes = utils::read_chunk(&mut file_reader, &mut buffer)?;
+        if num_bytes == 0 {
+            break;
+        }
+        next_offset += (N_BYTES_CHUNK_LENGTH + num_bytes) as u64;
+
+        // The offset in the file of the message read next from the chunk.
+        let mut buffer_reader = &buffer[..];
+        let message_offset = |buffer_reader: &[u8]| {
+            current_offset + (N_BYTES_CHUNK_LENGTH + buffer.len() - buffer_reader.len()) as u64
+        };
 
         // Read the first message of the chunk and record its key.
-        match utils::read_message::<Command, &[u8]>(&mut &buffer[..])? {
-            Some(command) => {
-                index.insert(command.get_key().to_owned(), current_offset);
+        let mut last_key = String::new();
+        let offset = message_offset(buffer_reader);
+        match utils::read_message::<Command, &[u8]>(&mut buffer_reader)? {
+            Some(mut command) => {
+                restore_key(&mut command, &mut last_key)?;
+                check_order(&max_key, command.get_key())?;
+                prefix_filter_builder.add(command.get_key());
+                inline_records_builder.add_command(&command, file_path, offset);
+                if command.has_blob() {
+                    blob_file_nos.push(command.get_blob().get_file_no());
+                }
+                index.insert(
+                    OrderedKey::new(command.get_key().to_owned(), comparator),
+                    current_offset,
+                );
+                properties.add_command(&command);
+                max_key = Some(command.take_key());
             }
             None => {
                 return Err(NaiveError::InvalidData);
             }
         }
+
+        // Count the rest of the keys, which the prefix filter, the inline records and the blob
+        // files need as well. The largest key is the last one in the last chunk.
+        loop {
+            let offset = message_offset(buffer_reader);
+            let mut command = match utils::read_message::<Command, &[u8]>(&mut buffer_reader)? {
+                Some(command) => command,
+                None => break,
+            };
+            restore_key(&mut command, &mut last_key)?;
+            check_order(&max_key, command.get_key())?;
+            prefix_filter_builder.add(command.get_key());
+            inline_records_builder.add_command(&command, file_path, offset);
+            if command.has_blob() {
+                blob_file_nos.push(command.get_blob().get_file_no());
+            }
+            properties.add_command(&command);
+            max_key = Some(command.take_key());
+        }
+    }
+    properties.num_chunks = index.len();
+    Ok((index, max_key, properties))
+}
+
+/// Read the footer of the segment file of the given size, along with the offset where the chunks
+/// end, i.e. of the empty chunk before it, or None if it has no footer, e.g. if written before the
+/// footers were kept, or fail with ComparatorMismatch if the keys are ordered by another
+/// comparator.
+fn read_footer(
+    segment_file: &mut Box<dyn BackendFile>,
+    file_path: &Path,
+    file_size: usize,
+    comparator: &dyn Comparator,
+) -> Result<Option<(SegmentFooter, u64)>> {
+    if file_size < N_BYTES_GENERATION_NUMBER + N_BYTES_FOOTER_TRAILER {
+        return Ok(None);
+    }
+    let mut trailer = [0u8; N_BYTES_FOOTER_TRAILER];
+    segment_file.seek(SeekFrom::Start((file_size - N_BYTES_FOOTER_TRAILER) as u64))?;
+    segment_file.read_exact(&mut trailer)?;
+    let (offset_bytes, magic_bytes) = trailer.split_at(N_BYTES_FOOTER_TRAILER / 2);
+    if u64::from_be_bytes(magic_bytes.try_into().unwrap()) != FOOTER_MAGIC {
+        return Ok(None);
+    }
+    let offset = u64::from_be_bytes(offset_bytes.try_into().unwrap());
+    if offset < (N_BYTES_GENERATION_NUMBER + N_BYTES_CHUNK_LENGTH) as u64
+        || offset >= (file_size - N_BYTES_FOOTER_TRAILER) as u64
+    {
+        return Err(NaiveError::InvalidData);
+    }
+    segment_file.seek(SeekFrom::Start(offset))?;
+    let footer = utils::read_message::<messages::SSTableFooter, _>(segment_file)?
+        .ok_or(NaiveError::InvalidData)?;
+    if footer.get_comparator_name() != comparator.name() {
+        return Err(NaiveError::ComparatorMismatch(format!(
+            "found keys ordered by {} against the order of {} in {}",
+            footer.get_comparator_name(),
+            comparator.name(),
+            file_path.display()
+        )));
+    }
+    if footer.get_format_version() > LATEST_FORMAT_VERSION {
+        log::error!(
+            "Found format version {} of {} beyond the latest version {} supported.",
+            footer.get_format_version(),
+            file_path.display(),
+            LATEST_FORMAT_VERSION
+        );
+        return Err(NaiveError::InvalidData);
+    }
+    let data_end = offset - N_BYTES_CHUNK_LENGTH as u64;
+    Ok(Some((SegmentFooter::from_message(footer), data_end)))
+}
+
+/// Write the footer after the last chunk, which starts with an empty chunk, so that the readers of
+/// the chunks stop right there.
+fn write_footer(
+    file_writer: &mut (impl Write + Seek),
+    footer: &SegmentFooter,
+    comparator: &dyn Comparator,
+) -> Result<()> {
+    utils::write_chunk(file_writer, &[])?;
+    let offset = file_writer.stream_position()?;
+    utils::write_message(&footer.to_message(comparator), file_writer)?;
+    file_writer.write_all(&offset.to_be_bytes())?;
+    file_writer.write_all(&FOOTER_MAGIC.to_be_bytes())?;
+    Ok(())
+}
+
+/// Writes a segment file offline out of key-value pairs in increasing order of keys, e.g. for a
+/// bulk load that bypasses the Memtable and its log through `NaiveKV::ingest`.
+///
+/// The records carry sequence number 0, so that any write through the engine supersedes them.
+/// The keys must be ordered by the comparator of the options the data folder is opened with.
+pub struct SSTableBuilder {
+    file_writer: BufWriter<Box<dyn BackendFile>>,
+    index: SSTableIndex,
+    chunk: ChunkBuffer,
+    comparator: &'static dyn Comparator,
+    checksum_records: bool,
+    timestamp_ms: u64,
+    last_key: Option<String>,
+    properties: SSTableProperties,
+}
+
+impl SSTableBuilder {
+    /// Create the segment file, which must not exist yet.
+    pub fn new(file_path: impl Into<PathBuf>, options: &Options) -> Result<Self> {
+        let mut file_writer = BufWriter::new(options.backend.create_new(&file_path.into())?);
+        // The generation number is rewritten once the SSTable is ingested.
+        file_writer.write_all(&(0 as GenerationNumberType).to_be_bytes())?;
+        Ok(Self {
+            file_writer,
+            index: SSTableIndex::new(),
+            chunk: ChunkBuffer::new(options.key_restart_interval),
+            comparator: options.comparator,
+            checksum_records: options.checksum_records,
+            timestamp_ms: utils::now_ms(),
+            last_key: None,
+            properties: SSTableProperties::default(),
+        })
+    }
+
+    /// Append a key-value pair, whose key must come after the one appended last.
+    pub fn add(&mut self, key: String, value: String) -> Result<()> {
+        if let Some(last_key) = self.last_key.as_ref() {
+            if self.comparator.compare(last_key, &key) != Ordering::Less {
+                return Err(NaiveError::ComparatorMismatch(format!(
+                    "key {:?} added after {:?}",
+                    key, last_key
+                )));
+            }
+        }
+        let command = Record::Value(value).to_stamped_command(
+            key.clone(),
+            0,
+            Some(self.timestamp_ms),
+            self.checksum_records,
+        );
+        append_command_to_sstable(
+            &mut self.index,
+            &mut self.file_writer,
+            &mut self.chunk,
+            OrderedKey::new(key.clone(), self.comparator),
+            command,
+            &mut self.properties,
+        )?;
+        self.last_key = Some(key);
+        Ok(())
+    }
+
+    /// Write out the records buffered along with the footer and sync the segment file, returning
+    /// the number of records.
+    pub fn finish(mut self) -> Result<usize> {
+        self.chunk.flush(&mut self.file_writer)?;
+        self.properties.num_chunks = self.index.len();
+        let footer = SegmentFooter {
+            properties: self.properties,
+            key_range: key_range_of(&self.index, self.last_key),
+            blob_file_nos: Vec::new(),
+            blob_file_bytes: Vec::new(),
+            format_version: self.chunk.format_version(),
+        };
+        write_footer(&mut self.file_writer, &footer, self.comparator)?;
+        self.file_writer.into_inner()?.sync()?;
+        Ok(footer.properties.num_entries)
+    }
+}
+
+/// A segment file being written out of merged records. The merge runs ahead of a second stage,
+/// which encodes and checksums the records, writes them out and syncs the file, so that the CPU
+/// work overlaps with the I/O.
+struct SegmentWriter {
+    file_path: PathBuf,
+    batch: MergedBatch,
+    /// Dropped before the writer, which waits for the channel to disconnect.
+    batch_sender: Sender<MergedBatch>,
+    result_receiver: Receiver<Result<WrittenSegment>>,
+    prefix_filter_builder: PrefixFilterBuilder,
+    inline_records_builder: InlineRecordsBuilder,
+    /// The number of bytes of the keys and the values written.
+    data_size: usize,
+}
+
+impl SegmentWriter {
+    fn start(file_path: PathBuf, gen_no: usize, epoch_no: u64, options: &Options) -> Result<Self> {
+        log::info!(
+            "Going to merge into segment file {} (epoch={}).",
+            file_path.display(),
+            epoch_no
+        );
+        let segment_file = options.backend.create_new(&file_path)?;
+        let (batch_sender, batch_receiver) = bounded::<MergedBatch>(WRITE_PIPELINE_DEPTH);
+        let (result_sender, result_receiver) = bounded(1);
+        let checksum_records = options.checksum_records;
+        let key_restart_interval = options.key_restart_interval;
+        let min_blob_size = options.min_blob_size;
+        let comparator = options.comparator;
+        let blob_folder_path =
+            blob::blob_folder_paths(parent_folder_path(&file_path), options).swap_remove(0);
+        let backend = options.backend.clone();
+        // The writes go at the priority of the merge, e.g. in the background for a compaction.
+        let priority = io_scheduler::current_priority();
+        let writers = SEGMENT_WRITERS.get_or_init(|| {
+            ThreadPool::new(std::thread::available_parallelism().map_or(1, usize::from))
+        });
+        writers.add_task(move || {
+            io_scheduler::with_priority(priority, || {
+                let _ = result_sender.send(write_segment_file(
+                    segment_file,
+                    gen_no,
+                    batch_receiver,
+                    checksum_records,
+                    key_restart_interval,
+                    min_blob_size,
+                    comparator,
+                    || BlobWriter::create(&blob_folder_path, backend.as_ref()),
+                ));
+            })
+        })?;
+        Ok(Self {
+            file_path,
+            batch: Vec::with_capacity(WRITE_BATCH_SIZE),
+            batch_sender,
+            result_receiver,
+            prefix_filter_builder: PrefixFilterBuilder::new(&IndexOptions::new(options)),
+            inline_records_builder: InlineRecordsBuilder::new(&IndexOptions::new(options)),
+            data_size: 0,
+        })
+    }
+
+    /// Queue the record of the key, which comes after the ones before, or return false if the
+    /// writing stage has failed.
+    fn push(
+        &mut self,
+        key: OrderedKey,
+        record: TimedRecord,
+        separated: Option<SeparatedValue>,
+    ) -> bool {
+        self.prefix_filter_builder.add(key.as_str());
+        if separated.is_none() {
+            self.inline_records_builder.add(key.as_str(), &record);
+        }
+        self.data_size += key.as_str().len() + record.record.len();
+        self.batch.push((key, record, separated));
+        if self.batch.len() >= WRITE_BATCH_SIZE {
+            let full_batch =
+                std::mem::replace(&mut self.batch, Vec::with_capacity(WRITE_BATCH_SIZE));
+            return self.batch_sender.send(full_batch).is_ok();
+        }
+        true
+    }
+
+    /// Stop the writing stage of a failed merge and remove the segment file.
+    fn abandon(self, options: &Options) {
+        let Self {
+            file_path,
+            batch_sender,
+            result_receiver,
+            ..
+        } = self;
+        drop(batch_sender);
+        let _ = result_receiver.recv();
+        if let Err(error) = options.backend.remove_file(&file_path) {
+            log::error!(
+                "Failed to remove segment file {}: {:?}",
+                file_path.display(),
+                error
+            );
+        }
+    }
+
+    /// Wait for the segment file to be written and synced, and make an SSTable of it.
+    fn finish(self, gen_no: usize, epoch_no: u64, options: &Options) -> Result<SSTable> {
+        let Self {
+            file_path,
+            batch,
+            batch_sender,
+            result_receiver,
+            prefix_filter_builder,
+            inline_records_builder,
+            ..
+        } = self;
+        if !batch.is_empty() {
+            let _ = batch_sender.send(batch);
+        }
+        drop(batch_sender);
+
+        // The channel only disconnects without a result if the writing stage panics.
+        let WrittenSegment {
+            index,
+            mut segment_file,
+            footer,
+        } = result_receiver.recv().map_err(|_| NaiveError::Unknown)??;
+        let blob_folder_paths = blob::blob_folder_paths(parent_folder_path(&file_path), options);
+        let file_size = segment_file.len()? as usize;
+
+        // Read the file back while it is likely still in the page cache.
+        segment_file.seek(SeekFrom::Start(0))?;
+        let checksum = OnceLock::from(utils::checksum(&mut segment_file)?);
+
+        let index_options = IndexOptions::new(options);
+        let loaded_index = OnceLock::from(LoadedIndex {
+            index: partition_index(index, file_size, &index_options),
+            prefix_filter: prefix_filter_builder.build(),
+            inline_records: inline_records_builder.build(),
+        });
+
+        Ok(SSTable {
+            gen_no,
+            epoch_no,
+            loaded_index,
+            index_options,
+            index_loading: Mutex::new(()),
+            key_range: footer.key_range,
+            properties: footer.properties,
+            comparator: options.comparator,
+            file_path,
+            blob_files: footer
+                .blob_file_nos
+                .iter()
+                .map(|_| OnceLock::new())
+                .collect(),
+            blob_file_nos: footer.blob_file_nos,
+            blob_file_bytes: footer.blob_file_bytes,
+            blob_folder_paths,
+            file_size,
+            checksum,
+            is_deprecated: Mutex::new(false),
+            num_read_errors: AtomicUsize::new(0),
+            is_quarantined: AtomicBool::new(false),
+            trash: Trash::new(options),
+            backend: options.backend.clone(),
+            mmap_reads: options.mmap_reads,
+            mapped_file: OnceLock::new(),
+            readahead_size: options.readahead_size,
+            shared_file: OnceLock::new(),
+        })
+    }
+}
+
+/// Write the generation number followed by the batches of records into the segment file, and
+/// sync it once all are written with the footer, returning the index of the chunks and what the
+/// footer tells.
+///
+/// The values of at least the minimum blob size, unless zero, are separated into a blob file
+/// created on the first of them. The values already separated are carried over by their
+/// references.
+#[allow(clippy::too_many_arguments)]
+fn write_segment_file(
+    segment_file: Box<dyn BackendFile>,
+    gen_no: usize,
+    batches: Receiver<MergedBatch>,
+    checksum_records: bool,
+    key_restart_interval: usize,
+    min_blob_size: usize,
+    comparator: &'static dyn Comparator,
+    create_blob_writer: impl FnOnce() -> Result<BlobWriter>,
+) -> Result<WrittenSegment> {
+    let mut index = SSTableIndex::new();
+    let mut file_writer = BufWriter::new(segment_file);
+    file_writer.write_all(&(gen_no as GenerationNumberType).to_be_bytes())?;
+
+    let mut chunk = ChunkBuffer::new(key_restart_interval);
+    let mut properties = SSTableProperties::default();
+    // The keys are written in increasing order, so the last one is the largest.
+    let mut max_key = None;
+    // The bytes of the values referred to in each blob file.
+    let mut blob_file_bytes = BTreeMap::new();
+    let mut create_blob_writer = Some(create_blob_writer);
+    let mut blob_writer = None;
+    for batch in batches.iter() {
+        for (key, record, separated) in batch {
+            max_key = Some(key.as_str().to_owned());
+            // The checksum is computed over the value wherever it is stored.
+            let mut command = record.record.to_stamped_command(
+                key.as_str().to_owned(),
+                record.sequence_no,
+                record.timestamp_ms,
+                checksum_records,
+            );
+            match separated {
+                Some(separated) => {
+                    *blob_file_bytes
+                        .entry(separated.blob.get_file_no())
+                        .or_insert(0) += separated.blob.get_length();
+                    command.clear_value();
+                    command.set_blob(separated.blob);
+                    match separated.checksum {
+                        Some(checksum) => command.set_checksum(checksum),
+                        None => command.clear_checksum(),
+                    }
+                }
+                None if min_blob_size > 0
+                    && command.has_value()
+                    && command.get_value().len() >= min_blob_size =>
+                {
+                    let blob_writer = match blob_writer.as_mut() {
+                        Some(blob_writer) => blob_writer,
+                        None => blob_writer.insert(create_blob_writer.take().unwrap()()?),
+                    };
+                    let blob = blob_writer.append(command.get_value())?;
+                    *blob_file_bytes.entry(blob.get_file_no()).or_insert(0) += blob.get_length();
+                    command.clear_value();
+                    command.set_blob(blob);
+                }
+                None => (),
+            }
+            append_command_to_sstable(
+                &mut index,
+                &mut file_writer,
+                &mut chunk,
+                key,
+                command,
+                &mut properties,
+            )?;
+        }
     }
-    Ok(index)
+    // Write out the remaining buffered records into a chunk.
+    chunk.flush(&mut file_writer)?;
+
+    // The blob file is synced before the segment file referring to it.
+    blob_writer.map(BlobWriter::finish).transpose()?;
+    properties.num_chunks = index.len();
+    let footer = SegmentFooter {
+        properties,
+        key_range: key_range_of(&index, max_key),
+        blob_file_nos: blob_file_bytes.keys().copied().collect(),
+        blob_file_bytes: blob_file_bytes.into_values().collect(),
+        format_version: chunk.format_version(),
+    };
+    write_footer(&mut file_writer, &footer, comparator)?;
+    let segment_file = file_writer.into_inner()?;
+    segment_file.sync()?;
+    Ok(WrittenSegment {
+        index,
+        segment_file,
+        footer,
+    })
 }
 
+/// Encode the command of the key into the chunk, after its checksum is computed over the full key,
+/// and count it in the properties.
 fn append_command_to_sstable(
     index: &mut SSTableIndex,
-    file_writer: &mut BufWriter<File>,
-    buffer: &mut Vec<u8>,
-    key: String,
-    record: Record,
+    file_writer: &mut BufWriter<Box<dyn BackendFile>>,
+    chunk: &mut ChunkBuffer,
+    key: OrderedKey,
+    command: Command,
+    properties: &mut SSTableProperties,
 ) -> Result<()> {
-    if buffer.is_empty() {
+    properties.add_command(&command);
+    if chunk.is_empty() {
         // This is the first key in the chunk.
-        let offset = file_writer.seek(std::io::SeekFrom::Current(0))?;
-        index.insert(key.clone(), offset);
+        let offset = file_writer.stream_position()?;
+        index.insert(key, offset);
+    }
+
+    chunk.push(command)?;
+    if chunk.bytes.len() >= SSTABLE_CHUNK_SIZE_THRESHOLD {
+        // Write the chunk if its size exceeds the threshold.
+        chunk.flush(file_writer)?;
+    }
+    Ok(())
+}
+
+/// The records of the chunk being written, whose keys are stored in full at the restart points
+/// and as the suffixes after the prefixes shared with the keys before them in between.
+struct ChunkBuffer {
+    bytes: Vec<u8>,
+    last_key: String,
+    num_records: usize,
+    restart_interval: usize,
+}
+
+impl ChunkBuffer {
+    fn new(restart_interval: usize) -> Self {
+        Self {
+            bytes: Vec::new(),
+            last_key: String::new(),
+            num_records: 0,
+            restart_interval,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.num_records == 0
     }
 
-    let mut command = Command::new();
-    command.set_key(key);
-    match record {
-        Record::Value(value) => {
-            command.set_command_type(CommandType::SET_VALUE);
-            command.set_value(value);
+    /// The format version of the chunks, which store every key in full with a restart interval of
+    /// one.
+    fn format_version(&self) -> u32 {
+        if self.restart_interval > 1 {
+            FORMAT_VERSION_SHARED_KEY_PREFIXES
+        } else {
+            FORMAT_VERSION_FULL_KEYS
         }
-        Record::Deleted => {
-            command.set_command_type(CommandType::DELETE);
+    }
+
+    /// Encode the command into the chunk, after its checksum is computed over the full key.
+    fn push(&mut self, mut command: Command) -> Result<()> {
+        let key = command.take_key();
+        let shared_key_length = if self.num_records.is_multiple_of(self.restart_interval) {
+            0
+        } else {
+            shared_prefix_length(&self.last_key, &key)
+        };
+        command.set_key(key[shared_key_length..].to_owned());
+        command.set_shared_key_length(shared_key_length as u32);
+        utils::write_message(&command, &mut self.bytes)?;
+        self.last_key = key;
+        self.num_records += 1;
+        Ok(())
+    }
+
+    /// Write out the records buffered, if any, into a chunk, and start a new one.
+    fn flush(&mut self, file_writer: &mut impl Write) -> Result<()> {
+        if !self.is_empty() {
+            utils::write_chunk(file_writer, &self.bytes)?;
+            self.bytes.clear();
+            self.last_key.clear();
+            self.num_records = 0;
         }
+        Ok(())
     }
+}
 
-    utils::write_message(&command, buffer)?;
-    if buffer.len() >= SSTABLE_CHUNK_SIZE_THRESHOLD {
-        // Write the chunk if its size exceeds the threshold.
-        utils::write_chunk(file_writer, buffer)?;
-        buffer.clear();
+/// The length in bytes of the longest common prefix of the two keys that ends on a char boundary.
+fn shared_prefix_length(last_key: &str, key: &str) -> usize {
+    let mut length = last_key
+        .bytes()
+        .zip(key.bytes())
+        .take_while(|(last_byte, byte)| last_byte == byte)
+        .count();
+    while !key.is_char_boundary(length) {
+        length -= 1;
+    }
+    length
+}
+
+/// Restore the full key of a command read from a chunk out of the key before it in the chunk,
+/// which is then replaced by the full key.
+fn restore_key(command: &mut Command, last_key: &mut String) -> Result<()> {
+    let shared_key_length = command.get_shared_key_length() as usize;
+    if shared_key_length > 0 {
+        let prefix = last_key
+            .get(..shared_key_length)
+            .ok_or(NaiveError::InvalidData)?;
+        let key = prefix.to_owned() + command.get_key();
+        command.set_key(key);
+        command.clear_shared_key_length();
     }
+    last_key.clear();
+    last_key.push_str(command.get_key());
     Ok(())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::backend::LocalBackend;
+    use crate::prefix::DelimiterPrefixExtractor;
+    use std::collections::BTreeMap;
 
     #[test]
     fn test_sstable() {
@@ -448,6 +2513,7 @@ mod tests {
         const MAX_GEN_NO: usize = 2;
         const EPOCH_NO: u64 = 10086;
 
+        let options = Options::default();
         let mut expected_values = BTreeMap::new();
 
         let memtable_log_path = PathBuf::from("/tmp/test_sstable_memtable.log");
@@ -455,61 +2521,1152 @@ mod tests {
         let mut sstables = Vec::new();
         for gen_no in (0..=MAX_GEN_NO).rev() {
             utils::try_remove_file(&memtable_log_path).unwrap();
-            let mut memtable = Memtable::open(memtable_log_path.clone()).unwrap();
+            let memtable = Memtable::open(memtable_log_path.clone(), &options).unwrap();
             for num in 0..MAX_NUMBER {
                 let key = (gen_no + 2) * num;
                 let value = (gen_no + 2) * num + gen_no + 1;
                 expected_values.insert(key, value);
-                memtable.set(key.to_string(), value.to_string()).unwrap();
+                memtable.set(key.to_string(), value.to_string(), 0).unwrap();
             }
             let sstable_path = PathBuf::from(&format!("/tmp/test_gen_{}.sst", gen_no));
             utils::try_remove_file(&sstable_path).unwrap();
             let sstable = Arc::new(
-                SSTable::create(sstable_path, &memtable, &empty_sstables, gen_no, EPOCH_NO)
-                    .unwrap(),
+                SSTable::create(
+                    sstable_path,
+                    Some(&memtable),
+                    &empty_sstables,
+                    true,
+                    gen_no,
+                    EPOCH_NO,
+                    &options,
+                )
+                .unwrap(),
             );
             assert_eq!(sstable.epoch_no(), EPOCH_NO);
+            assert_eq!(sstable.num_entries(), MAX_NUMBER);
             let mut sstable_view = SSTableView::new(sstable.clone()).unwrap();
             for num in 0..MAX_NUMBER {
                 let key = ((gen_no + 2) * num).to_string();
                 let value = ((gen_no + 2) * num + gen_no + 1).to_string();
-                let record = sstable_view.get(&key).unwrap();
-                assert!(record == Some(Record::Value(value)));
+                let timed_record = sstable_view.get(&key).unwrap().unwrap();
+                assert!(timed_record.record == Record::Value(value));
+                assert!(timed_record.timestamp_ms.is_some());
             }
             sstables.push(sstable);
         }
         sstables.reverse();
 
         utils::try_remove_file(&memtable_log_path).unwrap();
-        let mut memtable = Memtable::open(memtable_log_path).unwrap();
+        let memtable = Memtable::open(memtable_log_path, &options).unwrap();
         for num in 0..MAX_NUMBER {
             expected_values.insert(num, num);
             let key = num.to_string();
             let value = key.clone();
-            memtable.set(key, value).unwrap();
+            memtable.set(key, value, 0).unwrap();
         }
 
         let sstable_path = PathBuf::from("/tmp/test_sstable.sst");
         utils::try_remove_file(&sstable_path).unwrap();
-        SSTable::create(
+        let num_entries = SSTable::create(
             sstable_path.clone(),
-            &memtable,
+            Some(&memtable),
             &sstables,
+            true,
             MAX_GEN_NO + 1,
             EPOCH_NO + 1,
+            &options,
         )
-        .unwrap();
+        .unwrap()
+        .num_entries();
+        assert_eq!(num_entries, expected_values.len());
 
-        let sstable = Arc::new(SSTable::open(sstable_path).unwrap());
-        assert_eq!(MAX_GEN_NO + 1 as usize, sstable.gen_no());
+        let sstable = Arc::new(SSTable::open(sstable_path, &options).unwrap());
+        assert_eq!(MAX_GEN_NO + 1, sstable.gen_no());
         assert_eq!(0, sstable.epoch_no());
+        assert_eq!(num_entries, sstable.num_entries());
+        let min_key = expected_values
+            .keys()
+            .map(|key| key.to_string())
+            .min()
+            .unwrap();
+        let max_key = expected_values
+            .keys()
+            .map(|key| key.to_string())
+            .max()
+            .unwrap();
+        assert_eq!(sstable.key_range(), Some((&min_key[..], &max_key[..])));
+        assert!(!sstable.may_contain(&format!("{}0", max_key)));
+        assert!(sstable.may_overlap(&(max_key.clone()..)));
+        assert!(!sstable.may_overlap(&(format!("{}0", max_key)..)));
+        assert!(sstable.may_overlap(&(..=min_key.clone())));
+        assert!(!sstable.may_overlap(&(..min_key.clone())));
+        assert!(sstable.may_overlap(&(..)));
         sstable.deprecate().unwrap();
         let mut sstable_view = SSTableView::new(sstable).unwrap();
         for (key, value) in expected_values {
             let key = key.to_string();
             let value = value.to_string();
-            let record = sstable_view.get(&key).unwrap();
+            let record = sstable_view.get(&key).unwrap().map(|timed| timed.record);
             assert!(record == Some(Record::Value(value)));
         }
     }
+
+    #[test]
+    fn test_sstable_view_pinning() {
+        let options = Options::default();
+        let memtable_log_path = PathBuf::from("/tmp/test_sstable_pinning_memtable.log");
+        utils::try_remove_file(&memtable_log_path).unwrap();
+        let memtable = Memtable::open(memtable_log_path, &options).unwrap();
+        memtable
+            .set("key".to_owned(), "value".to_owned(), 0)
+            .unwrap();
+        memtable.deprecate().unwrap();
+
+        let sstable_path = PathBuf::from("/tmp/test_sstable_pinning.sst");
+        utils::try_remove_file(&sstable_path).unwrap();
+        let sstable = Arc::new(
+            SSTable::create(
+                sstable_path.clone(),
+                Some(&memtable),
+                &[],
+                true,
+                0,
+                1,
+                &options,
+            )
+            .unwrap(),
+        );
+        let mut sstable_view = SSTableView::new(sstable.clone()).unwrap();
+
+        // The deprecated file survives as long as a view holds it.
+        sstable.deprecate().unwrap();
+        drop(sstable);
+        assert!(sstable_path.exists());
+        let record = sstable_view.get("key").unwrap().map(|timed| timed.record);
+        assert!(record == Some(Record::Value("value".to_owned())));
+
+        drop(sstable_view);
+        assert!(!sstable_path.exists());
+    }
+
+    #[test]
+    fn test_sstable_mmap_reads() {
+        const NUM_KEYS: usize = 1000;
+
+        let options = Options {
+            mmap_reads: true,
+            ..Options::default()
+        };
+        let memtable_log_path = PathBuf::from("/tmp/test_sstable_mmap_memtable.log");
+        utils::try_remove_file(&memtable_log_path).unwrap();
+        let memtable = Memtable::open(memtable_log_path, &options).unwrap();
+        for i in 0..NUM_KEYS {
+            memtable
+                .set(format!("key{:04}", i), format!("value{}", i), 0)
+                .unwrap();
+        }
+        memtable.deprecate().unwrap();
+
+        let sstable_path = PathBuf::from("/tmp/test_sstable_mmap.sst");
+        utils::try_remove_file(&sstable_path).unwrap();
+        let sstable = Arc::new(
+            SSTable::create(
+                sstable_path.clone(),
+                Some(&memtable),
+                &[],
+                true,
+                0,
+                1,
+                &options,
+            )
+            .unwrap(),
+        );
+
+        // The views share the mapped file across threads without opening it.
+        let handles = (0..4)
+            .map(|_| {
+                let mut sstable_view = SSTableView::new(sstable.clone()).unwrap();
+                assert!(sstable.shared_file.get().is_none());
+                std::thread::spawn(move || {
+                    for i in 0..NUM_KEYS {
+                        let record = sstable_view
+                            .get(&format!("key{:04}", i))
+                            .unwrap()
+                            .map(|timed| timed.record);
+                        assert!(record == Some(Record::Value(format!("value{}", i))));
+                    }
+                    assert_eq!(sstable_view.get("key").unwrap(), None);
+                    assert_eq!(
+                        sstable_view.get_kind("key0000").unwrap(),
+                        Some(RecordKind::Value)
+                    );
+                })
+            })
+            .collect::<Vec<_>>();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        // The deprecated file is removed once the map drops along with the SSTable.
+        sstable.deprecate().unwrap();
+        drop(sstable);
+        assert!(!sstable_path.exists());
+    }
+
+    #[test]
+    fn test_sstable_shared_file() {
+        const NUM_KEYS: usize = 1000;
+
+        let options = Options::default();
+        let memtable_log_path = PathBuf::from("/tmp/test_sstable_shared_file_memtable.log");
+        utils::try_remove_file(&memtable_log_path).unwrap();
+        let memtable = Memtable::open(memtable_log_path, &options).unwrap();
+        for i in 0..NUM_KEYS {
+            memtable
+                .set(format!("key{:04}", i), format!("value{}", i), 0)
+                .unwrap();
+        }
+        memtable.deprecate().unwrap();
+
+        let sstable_path = PathBuf::from("/tmp/test_sstable_shared_file.sst");
+        utils::try_remove_file(&sstable_path).unwrap();
+        let sstable = Arc::new(
+            SSTable::create(
+                sstable_path.clone(),
+                Some(&memtable),
+                &[],
+                true,
+                0,
+                1,
+                &options,
+            )
+            .unwrap(),
+        );
+        assert!(sstable.shared_file.get().is_none());
+        SSTableView::new(sstable.clone()).unwrap();
+        assert!(sstable.shared_file.get().is_some());
+
+        // The shared file keeps serving the views after it is removed.
+        std::fs::remove_file(&sstable_path).unwrap();
+
+        // The readers share the file by offset, whether through a view apiece or a view per get.
+        let handles = (0..8)
+            .map(|num| {
+                let sstable = sstable.clone();
+                std::thread::spawn(move || {
+                    let mut sstable_view = SSTableView::new(sstable.clone()).unwrap();
+                    for i in (num..NUM_KEYS).step_by(3) {
+                        let key = format!("key{:04}", i);
+                        let record = if num % 2 == 0 {
+                            sstable_view.get(&key).unwrap()
+                        } else {
+                            SSTableView::new(sstable.clone())
+                                .unwrap()
+                                .get(&key)
+                                .unwrap()
+                        };
+                        let record = record.map(|timed| timed.record);
+                        assert!(record == Some(Record::Value(format!("value{}", i))));
+                    }
+                    assert_eq!(sstable_view.get("key").unwrap(), None);
+                })
+            })
+            .collect::<Vec<_>>();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_sstable_key_compression() {
+        const NUM_KEYS: usize = 1000;
+        const KEY_PREFIX: &str = "tenant/0042/table/orders/partition/17/";
+
+        // Each pair of keys shares a prefix ending within the first byte of the last char.
+        let keys = (0..NUM_KEYS)
+            .flat_map(|i| {
+                [
+                    format!("{}{:04}/è", KEY_PREFIX, i),
+                    format!("{}{:04}/é", KEY_PREFIX, i),
+                ]
+            })
+            .collect::<Vec<_>>();
+        let mut file_sizes = Vec::new();
+        for (key_restart_interval, format_version) in [
+            (1, FORMAT_VERSION_FULL_KEYS),
+            (16, FORMAT_VERSION_SHARED_KEY_PREFIXES),
+        ] {
+            let options = Options {
+                key_restart_interval,
+                checksum_records: true,
+                ..Options::default()
+            };
+            let memtable_log_path = PathBuf::from(format!(
+                "/tmp/test_sstable_keys_{}.log",
+                key_restart_interval
+            ));
+            utils::try_remove_file(&memtable_log_path).unwrap();
+            let memtable = Memtable::open(memtable_log_path, &options).unwrap();
+            for (i, key) in keys.iter().enumerate() {
+                if i % 3 == 0 {
+                    memtable.remove(key.clone(), 0).unwrap();
+                } else {
+                    memtable.set(key.clone(), format!("v{}", i), 0).unwrap();
+                }
+            }
+            memtable.deprecate().unwrap();
+
+            let sstable_path = PathBuf::from(format!(
+                "/tmp/test_sstable_keys_{}.sst",
+                key_restart_interval
+            ));
+            utils::try_remove_file(&sstable_path).unwrap();
+            let sstable = Arc::new(
+                SSTable::create(
+                    sstable_path.clone(),
+                    Some(&memtable),
+                    &[],
+                    true,
+                    0,
+                    1,
+                    &options,
+                )
+                .unwrap(),
+            );
+            file_sizes.push(std::fs::metadata(&sstable_path).unwrap().len());
+            let file_size = sstable.file_size;
+            let (footer, _) = read_footer(
+                &mut LocalBackend.open(&sstable_path).unwrap(),
+                &sstable_path,
+                file_size,
+                options.comparator,
+            )
+            .unwrap()
+            .unwrap();
+            assert_eq!(footer.format_version, format_version);
+
+            // Reopening rebuilds the index out of the full keys.
+            drop(sstable);
+            let sstable = Arc::new(SSTable::open(sstable_path, &options).unwrap());
+            assert_eq!(sstable.num_entries(), keys.len());
+            let mut sstable_view = SSTableView::new(sstable.clone()).unwrap();
+            for (i, key) in keys.iter().enumerate() {
+                let (record, kind) = if i % 3 == 0 {
+                    (Record::Deleted, RecordKind::Deleted)
+                } else {
+                    (Record::Value(format!("v{}", i)), RecordKind::Value)
+                };
+                assert!(sstable_view.get(key).unwrap().unwrap().record == record);
+                assert_eq!(sstable_view.get_kind(key).unwrap(), Some(kind));
+
+                // The raw command carries the full key.
+                let (bytes, _) = sstable_view.get_raw(key).unwrap().unwrap();
+                let command = Command::parse_from_bytes(&bytes).unwrap();
+                assert_eq!(command.get_key(), key);
+                assert_eq!(command.get_shared_key_length(), 0);
+            }
+            assert_eq!(sstable_view.get(KEY_PREFIX).unwrap(), None);
+            assert_eq!(sstable_view.get_kind(KEY_PREFIX).unwrap(), None);
+
+            let scanned_keys = sstable
+                .pseudo_iter()
+                .unwrap()
+                .map(|result| result.map(|(key, _)| key))
+                .collect::<Result<Vec<_>>>()
+                .unwrap();
+            assert_eq!(scanned_keys, keys);
+        }
+        // Leaving out the common prefixes more than halves the file.
+        assert!(file_sizes[1] * 2 < file_sizes[0]);
+    }
+
+    #[test]
+    fn test_sstable_partitioned_index() {
+        const NUM_KEYS: usize = 2000;
+
+        for mmap_reads in [false, true] {
+            let options = Options {
+                index_partition_size: 4,
+                max_resident_index_partitions: 2,
+                mmap_reads,
+                ..Options::default()
+            };
+            let memtable_log_path = PathBuf::from("/tmp/test_sstable_partitions_memtable.log");
+            utils::try_remove_file(&memtable_log_path).unwrap();
+            let memtable = Memtable::open(memtable_log_path, &options).unwrap();
+            for i in 0..NUM_KEYS {
+                memtable
+                    .set(format!("key{:04}", i), format!("value{}", i), 0)
+                    .unwrap();
+            }
+            memtable.deprecate().unwrap();
+
+            let sstable_path = PathBuf::from("/tmp/test_sstable_partitions.sst");
+            utils::try_remove_file(&sstable_path).unwrap();
+            let sstable = Arc::new(
+                SSTable::create(
+                    sstable_path.clone(),
+                    Some(&memtable),
+                    &[],
+                    true,
+                    0,
+                    1,
+                    &options,
+                )
+                .unwrap(),
+            );
+            sstable.deprecate().unwrap();
+            let num_partitions = sstable
+                .loaded_index()
+                .unwrap()
+                .index
+                .num_chunks()
+                .div_ceil(4);
+            assert!(num_partitions > 2);
+            assert_eq!(sstable.index_partitions(), (num_partitions, 0));
+            assert_eq!(sstable.key_range(), Some(("key0000", "key1999")));
+
+            let mut sstable_view = SSTableView::new(sstable.clone()).unwrap();
+            for i in (0..NUM_KEYS).rev() {
+                let record = sstable_view
+                    .get(&format!("key{:04}", i))
+                    .unwrap()
+                    .map(|timed| timed.record);
+                assert!(record == Some(Record::Value(format!("value{}", i))));
+            }
+            assert_eq!(sstable_view.get("key").unwrap(), None);
+            assert_eq!(sstable_view.get("key2000").unwrap(), None);
+            assert_eq!(sstable.index_partitions(), (num_partitions, 2));
+
+            let mut num_keys = 0;
+            for result in sstable.pseudo_iter_from("key1000").unwrap() {
+                assert!(result.unwrap().0.as_str() >= "key0900");
+                num_keys += 1;
+            }
+            assert!(num_keys >= NUM_KEYS / 2);
+
+            let mut rng = rand::thread_rng();
+            for _ in 0..10 {
+                assert!(sstable_view.sample_key(&mut rng).unwrap().is_some());
+            }
+            let num_bytes = sstable
+                .warm_up(&["key0500".to_owned().."key1500".to_owned()])
+                .unwrap();
+            assert!(num_bytes > 0 && num_bytes < sstable.file_size());
+            assert_eq!(sstable.index_partitions(), (num_partitions, 2));
+        }
+    }
+
+    #[test]
+    fn test_sstable_partitioned_failure() {
+        const NUM_KEYS: usize = 100;
+
+        let folder_path = PathBuf::from("/tmp/naive_kv/test_sstable_partitioned_failure/");
+        let _ = std::fs::remove_dir_all(&folder_path);
+        std::fs::create_dir_all(&folder_path).unwrap();
+        let options = Options::default();
+        let memtable = Memtable::open(folder_path.join("memtable.log"), &options).unwrap();
+        for num in 0..NUM_KEYS {
+            memtable
+                .set(format!("key{:03}", num), format!("value{:03}", num), 0)
+                .unwrap();
+        }
+        memtable.deprecate().unwrap();
+
+        // The third partition cannot be created over an existing file.
+        let file_paths = (0..NUM_KEYS)
+            .map(|num| folder_path.join(format!("partition_{}.sst", num)))
+            .collect::<Vec<_>>();
+        std::fs::write(&file_paths[2], b"taken").unwrap();
+        let mut file_path_iter = file_paths.iter().cloned();
+        assert!(SSTable::create_partitioned(
+            || file_path_iter.next().unwrap(),
+            Some(&memtable),
+            &[],
+            true,
+            1,
+            1,
+            NUM_KEYS / 4 * "key000value000".len(),
+            &options,
+        )
+        .is_err());
+        assert!(!file_paths[0].exists());
+        assert!(!file_paths[1].exists());
+        assert_eq!(std::fs::read(&file_paths[2]).unwrap(), b"taken");
+
+        // All the partitions are created otherwise, one after another on the shared writers.
+        std::fs::remove_file(&file_paths[2]).unwrap();
+        let mut file_path_iter = file_paths.iter().cloned();
+        let sstables = SSTable::create_partitioned(
+            || file_path_iter.next().unwrap(),
+            Some(&memtable),
+            &[],
+            true,
+            1,
+            1,
+            NUM_KEYS / 4 * "key000value000".len(),
+            &options,
+        )
+        .unwrap();
+        assert_eq!(sstables.len(), 4);
+        let num_entries = sstables
+            .iter()
+            .map(|sstable| sstable.properties().num_entries)
+            .sum::<usize>();
+        assert_eq!(num_entries, NUM_KEYS);
+    }
+
+    #[test]
+    fn test_sstable_inline_records() {
+        const NUM_KEYS: usize = 100;
+
+        let options = Options {
+            inline_value_size: 8,
+            // Room for the first half of the small values only.
+            inline_values_capacity: NUM_KEYS / 2 * "key000value000".len(),
+            ..Options::default()
+        };
+        let memtable_log_path = PathBuf::from("/tmp/test_sstable_inline_memtable.log");
+        utils::try_remove_file(&memtable_log_path).unwrap();
+        let memtable = Memtable::open(memtable_log_path, &options).unwrap();
+        for num in 0..NUM_KEYS {
+            memtable
+                .set(format!("key{:03}", num), format!("value{:03}", num), 0)
+                .unwrap();
+        }
+        memtable
+            .set("large".to_owned(), "x".repeat(100), 0)
+            .unwrap();
+        memtable.remove("key000".to_owned(), 0).unwrap();
+        memtable.deprecate().unwrap();
+
+        let sstable_path = PathBuf::from("/tmp/test_sstable_inline.sst");
+        utils::try_remove_file(&sstable_path).unwrap();
+        let created = SSTable::create(
+            sstable_path.clone(),
+            Some(&memtable),
+            &[],
+            false,
+            0,
+            1,
+            &options,
+        )
+        .unwrap();
+
+        // The same records are kept in memory on open, taken by the smallest keys first.
+        let sstable = Arc::new(SSTable::open(sstable_path.clone(), &options).unwrap());
+        assert_eq!(
+            sstable.loaded_index().unwrap().inline_records,
+            created.loaded_index().unwrap().inline_records
+        );
+        assert_eq!(
+            sstable.loaded_index().unwrap().inline_records.len(),
+            NUM_KEYS / 2
+        );
+        assert_eq!(
+            sstable
+                .loaded_index()
+                .unwrap()
+                .inline_records
+                .get(&OrderedKey::new("key000".to_owned(), options.comparator))
+                .map(|timed| &timed.record),
+            Some(&Record::Deleted)
+        );
+        assert!(!sstable
+            .loaded_index()
+            .unwrap()
+            .inline_records
+            .contains_key(&OrderedKey::new("large".to_owned(), options.comparator)));
+        assert!(!sstable
+            .loaded_index()
+            .unwrap()
+            .inline_records
+            .contains_key(&OrderedKey::new("key099".to_owned(), options.comparator)));
+
+        // The inline records are served even once the segment file is unreadable.
+        let mut sstable_view = SSTableView::new(sstable.clone()).unwrap();
+        std::fs::write(&sstable_path, b"").unwrap();
+        let record = sstable_view
+            .get("key001")
+            .unwrap()
+            .map(|timed| timed.record);
+        assert_eq!(record, Some(Record::Value("value001".to_owned())));
+        assert!(sstable_view.get("key099").is_err());
+        assert!(sstable_view.get("large").is_err());
+    }
+
+    #[test]
+    fn test_sstable_inline_records_comparator() {
+        #[derive(Debug)]
+        struct CaseInsensitiveComparator;
+
+        impl Comparator for CaseInsensitiveComparator {
+            fn compare(&self, a: &str, b: &str) -> Ordering {
+                a.to_ascii_lowercase().cmp(&b.to_ascii_lowercase())
+            }
+
+            fn name(&self) -> &str {
+                "CaseInsensitiveComparator"
+            }
+        }
+
+        let options = Options {
+            comparator: &CaseInsensitiveComparator,
+            inline_value_size: 8,
+            ..Options::default()
+        };
+        let memtable_log_path = PathBuf::from("/tmp/test_sstable_inline_comparator_memtable.log");
+        utils::try_remove_file(&memtable_log_path).unwrap();
+        let memtable = Memtable::open(memtable_log_path, &options).unwrap();
+        memtable
+            .set("Key".to_owned(), "value".to_owned(), 0)
+            .unwrap();
+        memtable.remove("Removed".to_owned(), 0).unwrap();
+        memtable.deprecate().unwrap();
+
+        let sstable_path = PathBuf::from("/tmp/test_sstable_inline_comparator.sst");
+        utils::try_remove_file(&sstable_path).unwrap();
+        let sstable = Arc::new(
+            SSTable::create(
+                sstable_path.clone(),
+                Some(&memtable),
+                &[],
+                false,
+                0,
+                1,
+                &options,
+            )
+            .unwrap(),
+        );
+
+        // The inline records are found by the keys the comparator deems equal, without reading
+        // the segment file.
+        let mut sstable_view = SSTableView::new(sstable).unwrap();
+        std::fs::write(&sstable_path, b"").unwrap();
+        let record = sstable_view.get("KEY").unwrap().map(|timed| timed.record);
+        assert_eq!(record, Some(Record::Value("value".to_owned())));
+        assert_eq!(
+            sstable_view.get_kind("removed").unwrap(),
+            Some(RecordKind::Deleted)
+        );
+    }
+
+    #[test]
+    fn test_sstable_properties() {
+        const NUM_KEYS: usize = 1000;
+
+        let options = Options {
+            merge_operator: Some(Arc::new(merge::StringAppendOperator {
+                delimiter: ",".to_owned(),
+            })),
+            ..Options::default()
+        };
+        let memtable_log_path = PathBuf::from("/tmp/test_sstable_properties_memtable.log");
+        utils::try_remove_file(&memtable_log_path).unwrap();
+        let memtable = Memtable::open(memtable_log_path, &options).unwrap();
+        for num in 0..NUM_KEYS {
+            memtable
+                .set(format!("key{:03}", num), format!("value{:03}", num), 0)
+                .unwrap();
+        }
+        memtable.remove("key000".to_owned(), 0).unwrap();
+        memtable
+            .merge("merged".to_owned(), "operand".to_owned(), 0)
+            .unwrap();
+        memtable.deprecate().unwrap();
+
+        let sstable_path = PathBuf::from("/tmp/test_sstable_properties.sst");
+        utils::try_remove_file(&sstable_path).unwrap();
+        let created = SSTable::create(
+            sstable_path.clone(),
+            Some(&memtable),
+            &[],
+            false,
+            0,
+            1,
+            &options,
+        )
+        .unwrap();
+        let properties = created.properties().clone();
+        assert_eq!(properties.num_entries, NUM_KEYS + 1);
+        assert_eq!(properties.num_deletions, 1);
+        assert_eq!(properties.num_merges, 1);
+        assert_eq!(properties.raw_key_bytes, (NUM_KEYS + 1) * "key000".len());
+        assert_eq!(
+            properties.raw_value_bytes,
+            (NUM_KEYS - 1) * "value000".len() + "operand".len()
+        );
+        assert!(properties.num_chunks > 1);
+        assert_eq!(
+            properties.num_chunks,
+            created.loaded_index().unwrap().index.num_chunks()
+        );
+        assert_eq!(properties.deletion_ratio(), 1.0 / (NUM_KEYS + 1) as f64);
+
+        // The properties are read out of the footer on open, and the iterator stops before it.
+        let sstable = Arc::new(SSTable::open(sstable_path.clone(), &options).unwrap());
+        assert_eq!(sstable.properties(), &properties);
+        assert_eq!(sstable.pseudo_iter().unwrap().count(), NUM_KEYS + 1);
+
+        // A segment file without the footer has its records counted on open instead.
+        let mut trailer = [0u8; N_BYTES_FOOTER_TRAILER];
+        let mut segment_file = std::fs::File::open(&sstable_path).unwrap();
+        segment_file
+            .seek(SeekFrom::End(-(N_BYTES_FOOTER_TRAILER as i64)))
+            .unwrap();
+        segment_file.read_exact(&mut trailer).unwrap();
+        let footer_offset = u64::from_be_bytes(trailer[..8].try_into().unwrap());
+        std::fs::OpenOptions::new()
+            .write(true)
+            .open(&sstable_path)
+            .unwrap()
+            .set_len(footer_offset - N_BYTES_CHUNK_LENGTH as u64)
+            .unwrap();
+        let sstable = SSTable::open(sstable_path, &options).unwrap();
+        assert!(sstable.is_index_loaded());
+        assert_eq!(sstable.properties(), &properties);
+    }
+
+    #[test]
+    fn test_sstable_prefix_filter() {
+        const NUM_ENTITIES: usize = 1000;
+
+        let options = Options {
+            prefix_extractor: Some(&DelimiterPrefixExtractor { delimiter: ':' }),
+            ..Options::default()
+        };
+        let memtable_log_path = PathBuf::from("/tmp/test_sstable_prefix_memtable.log");
+        utils::try_remove_file(&memtable_log_path).unwrap();
+        let memtable = Memtable::open(memtable_log_path, &options).unwrap();
+        for num in (0..2 * NUM_ENTITIES).step_by(2) {
+            memtable
+                .set(format!("user{}:name", num), num.to_string(), 0)
+                .unwrap();
+            memtable
+                .set(format!("user{}:email", num), num.to_string(), 0)
+                .unwrap();
+        }
+        memtable.deprecate().unwrap();
+
+        let sstable_path = PathBuf::from("/tmp/test_sstable_prefix.sst");
+        utils::try_remove_file(&sstable_path).unwrap();
+        SSTable::create(
+            sstable_path.clone(),
+            Some(&memtable),
+            &[],
+            true,
+            0,
+            1,
+            &options,
+        )
+        .unwrap();
+
+        // The filter is rebuilt from the segment file on first use.
+        let sstable = SSTable::open(sstable_path, &options).unwrap();
+        sstable.deprecate().unwrap();
+        for num in (0..2 * NUM_ENTITIES).step_by(2) {
+            assert!(sstable.may_contain_prefix(&format!("user{}:", num)));
+            assert!(sstable.may_contain_prefix(&format!("user{}:em", num)));
+            assert!(sstable.may_contain(&format!("user{}:name", num)));
+        }
+        let num_false_positives = (1..2 * NUM_ENTITIES)
+            .step_by(2)
+            .filter(|num| sstable.may_contain_prefix(&format!("user{}:", num)))
+            .count();
+        assert!(num_false_positives < NUM_ENTITIES / 20);
+
+        // Strings out of the domain of the extractor cannot be ruled out.
+        assert!(sstable.may_contain_prefix("user1"));
+    }
+
+    #[test]
+    fn test_sstable_lazy_index() {
+        const NUM_KEYS: usize = 1000;
+
+        let options = Options {
+            prefix_extractor: Some(&DelimiterPrefixExtractor { delimiter: ':' }),
+            inline_value_size: 8,
+            ..Options::default()
+        };
+        let memtable_log_path = PathBuf::from("/tmp/test_sstable_lazy_memtable.log");
+        utils::try_remove_file(&memtable_log_path).unwrap();
+        let memtable = Memtable::open(memtable_log_path, &options).unwrap();
+        for num in 0..NUM_KEYS {
+            memtable
+                .set(format!("key:{:04}", num), num.to_string(), 0)
+                .unwrap();
+        }
+        memtable.deprecate().unwrap();
+
+        let sstable_path = PathBuf::from("/tmp/test_sstable_lazy.sst");
+        utils::try_remove_file(&sstable_path).unwrap();
+        let created = SSTable::create(
+            sstable_path.clone(),
+            Some(&memtable),
+            &[],
+            true,
+            0,
+            1,
+            &options,
+        )
+        .unwrap();
+        assert!(created.is_index_loaded());
+
+        // The footer tells all that opening needs, and the chunks are left unread.
+        let sstable = Arc::new(SSTable::open(sstable_path.clone(), &options).unwrap());
+        assert!(!sstable.is_index_loaded());
+        assert_eq!(sstable.key_range(), Some(("key:0000", "key:0999")));
+        assert_eq!(sstable.properties(), created.properties());
+        assert_eq!(sstable.index_partitions(), (0, 0));
+        assert!(sstable.may_overlap(&("key:0500".to_owned()..)));
+        assert!(!sstable.may_contain("other"));
+        assert!(!sstable.is_index_loaded());
+
+        // The first query needing the index loads it along with the filter and inline records.
+        let mut sstable_view = SSTableView::new(sstable.clone()).unwrap();
+        let record = sstable_view
+            .get("key:0042")
+            .unwrap()
+            .map(|timed| timed.record);
+        assert_eq!(record, Some(Record::Value("42".to_owned())));
+        assert!(sstable.is_index_loaded());
+        assert_eq!(sstable.index_partitions(), created.index_partitions());
+        let loaded_index = sstable.loaded_index().unwrap();
+        let created_index = created.loaded_index().unwrap();
+        assert_eq!(loaded_index.inline_records, created_index.inline_records);
+        assert_eq!(
+            loaded_index.index.num_chunks(),
+            created_index.index.num_chunks()
+        );
+        assert!(sstable.may_contain("key:0500"));
+
+        // The concurrent first queries share a single load of the index.
+        let sstable = Arc::new(SSTable::open(sstable_path.clone(), &options).unwrap());
+        let loaded_indexes = std::thread::scope(|scope| {
+            let handles = (0..4)
+                .map(|num| {
+                    let sstable = sstable.clone();
+                    scope.spawn(move || {
+                        let mut sstable_view = SSTableView::new(sstable.clone()).unwrap();
+                        let key = format!("key:{:04}", num);
+                        assert!(sstable_view.get(&key).unwrap().is_some());
+                        sstable.loaded_index().unwrap() as *const LoadedIndex as usize
+                    })
+                })
+                .collect::<Vec<_>>();
+            handles
+                .into_iter()
+                .map(|handle| handle.join().unwrap())
+                .collect::<Vec<_>>()
+        });
+        assert!(loaded_indexes.windows(2).all(|pair| pair[0] == pair[1]));
+
+        // A footer of another comparator fails the open right away.
+        let numeric_options = Options {
+            comparator: &crate::comparator::NumericComparator,
+            ..Options::default()
+        };
+        assert!(matches!(
+            SSTable::open(sstable_path, &numeric_options),
+            Err(NaiveError::ComparatorMismatch(_))
+        ));
+    }
+
+    #[test]
+    fn test_sstable_checksum() {
+        let options = Options {
+            checksum_records: true,
+            ..Options::default()
+        };
+        let memtable_log_path = PathBuf::from("/tmp/test_sstable_checksum_memtable.log");
+        utils::try_remove_file(&memtable_log_path).unwrap();
+        let memtable = Memtable::open(memtable_log_path, &options).unwrap();
+        for num in 0..100 {
+            memtable
+                .set(format!("key{:03}", num), format!("value{:03}", num), 0)
+                .unwrap();
+        }
+        memtable.deprecate().unwrap();
+
+        let sstable_path = PathBuf::from("/tmp/test_sstable_checksum.sst");
+        utils::try_remove_file(&sstable_path).unwrap();
+        let sstable = Arc::new(
+            SSTable::create(
+                sstable_path.clone(),
+                Some(&memtable),
+                &[],
+                true,
+                0,
+                1,
+                &options,
+            )
+            .unwrap(),
+        );
+        sstable.deprecate().unwrap();
+
+        // Flip a byte of a value without breaking the encoding.
+        let mut bytes = std::fs::read(&sstable_path).unwrap();
+        let value_offset = bytes
+            .windows(8)
+            .position(|window| window == b"value042")
+            .unwrap();
+        bytes[value_offset + 7] = b'3';
+        std::fs::write(&sstable_path, &bytes).unwrap();
+
+        let mut sstable_view = SSTableView::new(sstable.clone()).unwrap();
+        let record = sstable_view
+            .get("key041")
+            .unwrap()
+            .map(|timed| timed.record);
+        assert!(record == Some(Record::Value("value041".to_owned())));
+        let offset = match sstable_view.get("key042") {
+            Err(NaiveError::Corruption { file_path, offset }) => {
+                assert_eq!(file_path, sstable_path);
+                offset
+            }
+            _ => panic!("The corruption is not detected."),
+        };
+        assert!(offset < value_offset as u64);
+
+        // The same message is reported when merging or scanning the SSTable.
+        let mut sstable_iter = sstable.pseudo_iter().unwrap();
+        let error = sstable_iter
+            .by_ref()
+            .find_map(Result::err)
+            .expect("The corruption is not detected.");
+        assert!(matches!(
+            error,
+            NaiveError::Corruption { offset: iter_offset, .. } if iter_offset == offset
+        ));
+        // Nothing more is read after the corruption.
+        assert!(sstable_iter.next().is_none());
+    }
+
+    #[test]
+    fn test_sstable_verify() {
+        const NUM_KEYS: usize = 1000;
+
+        let options = Options {
+            checksum_records: true,
+            ..Options::default()
+        };
+        let memtable_log_path = PathBuf::from("/tmp/test_sstable_verify_memtable.log");
+        utils::try_remove_file(&memtable_log_path).unwrap();
+        let memtable = Memtable::open(memtable_log_path, &options).unwrap();
+        for num in 0..NUM_KEYS {
+            memtable
+                .set(format!("key{:04}", num), format!("value{:04}", num), 0)
+                .unwrap();
+        }
+        memtable.deprecate().unwrap();
+
+        let sstable_path = PathBuf::from("/tmp/test_sstable_verify.sst");
+        utils::try_remove_file(&sstable_path).unwrap();
+        let sstable = SSTable::create(
+            sstable_path.clone(),
+            Some(&memtable),
+            &[],
+            true,
+            0,
+            1,
+            &options,
+        )
+        .unwrap();
+        let report = sstable.verify().unwrap();
+        assert!(report.is_intact(), "{:?}", report);
+        assert_eq!(report.num_chunks, sstable.properties().num_chunks);
+        assert_eq!(report.num_records, NUM_KEYS);
+        assert_eq!(report.num_checksums, NUM_KEYS);
+
+        // A paranoid open verifies the SSTable and loads its index.
+        let paranoid_options = Options {
+            paranoid_checks: true,
+            ..options.clone()
+        };
+        let reopened = SSTable::open(sstable_path.clone(), &paranoid_options).unwrap();
+        assert!(reopened.is_index_loaded());
+
+        // A value changed without breaking the encoding fails its checksum.
+        let original_bytes = std::fs::read(&sstable_path).unwrap();
+        let mut bytes = original_bytes.clone();
+        let value_offset = bytes
+            .windows(9)
+            .position(|window| window == b"value0420")
+            .unwrap();
+        bytes[value_offset + 8] = b'1';
+        std::fs::write(&sstable_path, &bytes).unwrap();
+        let report = sstable.verify().unwrap();
+        assert_eq!(report.num_problems, 1);
+        let offset = match report.problems[0] {
+            SSTableProblem::Checksum { offset } => offset,
+            ref problem => panic!("Unexpected problem {:?}", problem),
+        };
+        assert!(offset < value_offset as u64);
+        assert!(SSTable::open(sstable_path.clone(), &options).is_ok());
+        assert!(matches!(
+            SSTable::open(sstable_path.clone(), &paranoid_options),
+            Err(NaiveError::Corruption { offset: open_offset, .. }) if open_offset == offset
+        ));
+
+        // A chunk running past the end of the data ends the walk, and the rest disagrees.
+        let mut bytes = original_bytes;
+        let first_chunk_length = u32::from_be_bytes(bytes[4..8].try_into().unwrap()) as usize;
+        let second_chunk_offset =
+            N_BYTES_GENERATION_NUMBER + N_BYTES_CHUNK_LENGTH + first_chunk_length;
+        bytes[second_chunk_offset..second_chunk_offset + N_BYTES_CHUNK_LENGTH]
+            .copy_from_slice(&u32::MAX.to_be_bytes());
+        std::fs::write(&sstable_path, &bytes).unwrap();
+        let report = sstable.verify().unwrap();
+        assert_eq!(report.num_chunks, 1);
+        assert_eq!(
+            report.problems.first(),
+            Some(&SSTableProblem::Framing {
+                offset: second_chunk_offset as u64
+            })
+        );
+        assert!(report
+            .problems
+            .iter()
+            .any(|problem| matches!(problem, SSTableProblem::Index { .. })));
+        assert!(report
+            .problems
+            .iter()
+            .any(|problem| matches!(problem, SSTableProblem::Footer { .. })));
+    }
+
+    #[test]
+    fn test_estimate_range() {
+        const NUM_KEYS: usize = 1000;
+        let options = Options::default();
+        let memtable_log_path = PathBuf::from("/tmp/test_sstable_estimate_memtable.log");
+        utils::try_remove_file(&memtable_log_path).unwrap();
+        let memtable = Memtable::open(memtable_log_path, &options).unwrap();
+        for num in 0..NUM_KEYS {
+            memtable
+                .set(format!("key{:03}", num), format!("value{:03}", num), 0)
+                .unwrap();
+        }
+        memtable.deprecate().unwrap();
+
+        let sstable_path = PathBuf::from("/tmp/test_sstable_estimate.sst");
+        utils::try_remove_file(&sstable_path).unwrap();
+        let sstable =
+            SSTable::create(sstable_path, Some(&memtable), &[], true, 0, 1, &options).unwrap();
+        sstable.deprecate().unwrap();
+
+        let estimate = sstable.estimate_range("", "key~");
+        assert_eq!(estimate.num_keys, NUM_KEYS);
+        assert_eq!(
+            estimate.num_bytes,
+            sstable.file_size() - N_BYTES_GENERATION_NUMBER
+        );
+
+        // The estimate is off by no more than a chunk at either end.
+        let num_keys_per_chunk = NUM_KEYS / sstable.loaded_index().unwrap().index.num_chunks() + 1;
+        let estimate = sstable.estimate_range("key250", "key750");
+        assert!(estimate.num_keys.abs_diff(NUM_KEYS / 2) <= 2 * num_keys_per_chunk);
+        assert!(estimate.num_bytes < sstable.file_size());
+
+        assert_eq!(
+            sstable.estimate_range("key750", "key250"),
+            RangeEstimate::default()
+        );
+    }
+
+    #[test]
+    fn test_sequence_numbers() {
+        let options = Options::default();
+        let create_sstable = |name: &str, sequence_no: u64, value: &str| {
+            let memtable_log_path = PathBuf::from(format!("/tmp/test_sstable_{}.log", name));
+            utils::try_remove_file(&memtable_log_path).unwrap();
+            let memtable = Memtable::open(memtable_log_path, &options).unwrap();
+            memtable
+                .set("key".to_owned(), value.to_owned(), sequence_no)
+                .unwrap();
+            memtable
+                .set(format!("{}_key", name), value.to_owned(), sequence_no)
+                .unwrap();
+            memtable.deprecate().unwrap();
+            let sstable_path = PathBuf::from(format!("/tmp/test_sstable_{}.sst", name));
+            utils::try_remove_file(&sstable_path).unwrap();
+            let sstable =
+                SSTable::create(sstable_path, Some(&memtable), &[], true, 0, 1, &options).unwrap();
+            sstable.deprecate().unwrap();
+            Arc::new(sstable)
+        };
+
+        // The older source holds the later write of the key, e.g. as replayed out of order.
+        let newer = create_sstable("sequence_newer", 3, "old");
+        let older = create_sstable("sequence_older", 7, "new");
+        let sstable_path = PathBuf::from("/tmp/test_sstable_sequence_merged.sst");
+        utils::try_remove_file(&sstable_path).unwrap();
+        let merged = Arc::new(
+            SSTable::create(sstable_path, None, &[newer, older], true, 1, 2, &options).unwrap(),
+        );
+        merged.deprecate().unwrap();
+
+        // The write with the larger sequence number wins, and each record keeps its own.
+        let mut sstable_view = SSTableView::new(merged).unwrap();
+        let timed_record = sstable_view.get("key").unwrap().unwrap();
+        assert_eq!(timed_record.record, Record::Value("new".to_owned()));
+        assert_eq!(timed_record.sequence_no, 7);
+        let timed_record = sstable_view.get("sequence_newer_key").unwrap().unwrap();
+        assert_eq!(timed_record.sequence_no, 3);
+    }
+
+    #[test]
+    fn test_warm_up() {
+        const NUM_KEYS: usize = 1000;
+        let options = Options::default();
+        let memtable_log_path = PathBuf::from("/tmp/test_sstable_warm_up_memtable.log");
+        utils::try_remove_file(&memtable_log_path).unwrap();
+        let memtable = Memtable::open(memtable_log_path, &options).unwrap();
+        for num in 0..NUM_KEYS {
+            memtable
+                .set(format!("key{:03}", num), format!("value{:03}", num), 0)
+                .unwrap();
+        }
+        memtable.deprecate().unwrap();
+
+        let sstable_path = PathBuf::from("/tmp/test_sstable_warm_up.sst");
+        utils::try_remove_file(&sstable_path).unwrap();
+        let sstable =
+            SSTable::create(sstable_path, Some(&memtable), &[], true, 0, 1, &options).unwrap();
+        sstable.deprecate().unwrap();
+        let data_size = sstable.file_size() - N_BYTES_GENERATION_NUMBER;
+
+        assert_eq!(sstable.warm_up(&[..]).unwrap(), data_size);
+
+        // A single key takes a single chunk, which is only read once.
+        let key = "key500".to_owned();
+        let num_chunk_bytes = sstable.warm_up(&[key.clone()..=key.clone()]).unwrap();
+        assert!(num_chunk_bytes > 0 && num_chunk_bytes < data_size);
+        assert_eq!(
+            sstable
+                .warm_up(&[key.clone()..=key.clone(), key.clone()..=key])
+                .unwrap(),
+            num_chunk_bytes
+        );
+
+        // Overlapping ranges are merged.
+        let num_bytes = sstable
+            .warm_up(&["key250".to_owned().."key750".to_owned()])
+            .unwrap();
+        assert!(num_bytes < data_size);
+        assert_eq!(
+            sstable
+                .warm_up(&[
+                    "key250".to_owned().."key500".to_owned(),
+                    "key400".to_owned().."key750".to_owned(),
+                ])
+                .unwrap(),
+            num_bytes
+        );
+        assert_eq!(
+            sstable
+                .warm_up(&["key750".to_owned().."key250".to_owned()])
+                .unwrap(),
+            0
+        );
+    }
 }
@@ -0,0 +1,540 @@
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use crate::protos::messages;
+
+/// The number of bytes of the keys and the values in the Memtables.
+pub const PROPERTY_MEMTABLE_BYTES: &str = "naivekv.memtable-bytes";
+
+/// The number of bytes of the Memtable logs, i.e. the write-ahead log.
+pub const PROPERTY_WAL_BYTES: &str = "naivekv.wal-bytes";
+
+/// The number of generations of the SSTables.
+pub const PROPERTY_NUM_GENERATIONS: &str = "naivekv.num-generations";
+
+/// The sizes in bytes of the segment files in increasing generations, separated by commas.
+pub const PROPERTY_GENERATION_BYTES: &str = "naivekv.generation-bytes";
+
+/// The number of compactions that would run if the compaction daemon woke up now.
+pub const PROPERTY_NUM_PENDING_COMPACTIONS: &str = "naivekv.num-pending-compactions";
+
+/// The number of bytes the pending compactions would write.
+pub const PROPERTY_COMPACTION_BACKLOG_BYTES: &str = "naivekv.compaction-backlog-bytes";
+
+/// The number of deletions in the live SSTables, which may hide older records of their keys.
+pub const PROPERTY_NUM_DELETIONS: &str = "naivekv.num-deletions";
+
+/// The number of bytes of all the files in the data folder and the cold folder, the trash
+/// included, but the subfolders of the column families excluded.
+pub const PROPERTY_TOTAL_DISK_BYTES: &str = "naivekv.total-disk-bytes";
+
+/// The names of all the properties, e.g. for dumping them at once.
+pub const PROPERTY_NAMES: &[&str] = &[
+    PROPERTY_MEMTABLE_BYTES,
+    PROPERTY_WAL_BYTES,
+    PROPERTY_NUM_GENERATIONS,
+    PROPERTY_GENERATION_BYTES,
+    PROPERTY_NUM_PENDING_COMPACTIONS,
+    PROPERTY_COMPACTION_BACKLOG_BYTES,
+    PROPERTY_NUM_DELETIONS,
+    PROPERTY_TOTAL_DISK_BYTES,
+];
+
+/// A point-in-time snapshot of the engine statistics.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Stats {
+    /// The number of deprecated SSTables whose files are kept alive by views or iterators.
+    pub num_pinned_obsolete_files: usize,
+
+    /// The total size in bytes of the pinned obsolete files.
+    pub pinned_obsolete_bytes: usize,
+
+    /// The replay of the Memtable log when the engine was opened.
+    pub log_replay: ReplayStats,
+
+    /// The number of layers touched by the point lookups.
+    pub read_amplification: ReadAmplification,
+
+    /// The compactions run since the engine was opened.
+    pub compaction: CompactionStats,
+
+    /// The soft limits crossed since the engine was opened.
+    pub soft_limits: SoftLimitStats,
+
+    /// The number of bytes the writes have appended to the Memtable logs since the engine was
+    /// opened.
+    pub user_bytes: usize,
+
+    /// The number of bytes the pending compactions would write.
+    pub compaction_backlog_bytes: usize,
+}
+
+impl Stats {
+    pub fn to_message(&self) -> messages::EngineStats {
+        let mut message = messages::EngineStats::new();
+        message.set_num_pinned_obsolete_files(self.num_pinned_obsolete_files as u64);
+        message.set_pinned_obsolete_bytes(self.pinned_obsolete_bytes as u64);
+        message.set_log_replay_records(self.log_replay.num_records as u64);
+        message.set_log_replay_bytes(self.log_replay.num_bytes as u64);
+        message.set_log_replay_duration_ms(self.log_replay.duration.as_millis() as u64);
+        message.set_read_amplification_counts(self.read_amplification.counts.clone());
+        message.set_num_compactions(self.compaction.num_compactions as u64);
+        message.set_compaction_bytes(self.compaction.num_bytes as u64);
+        message.set_compaction_duration_ms(self.compaction.duration.as_millis() as u64);
+        message.set_num_compaction_failures(self.compaction.num_failures as u64);
+        message.set_num_memtable_nearly_full(self.soft_limits.num_memtable_nearly_full as u64);
+        message.set_num_flush_behind(self.soft_limits.num_flush_behind as u64);
+        message.set_num_compaction_backlog(self.soft_limits.num_compaction_backlog as u64);
+        message.set_user_bytes(self.user_bytes as u64);
+        message.set_compaction_backlog_bytes(self.compaction_backlog_bytes as u64);
+        message
+    }
+
+    pub fn from_message(message: &messages::EngineStats) -> Self {
+        Self {
+            num_pinned_obsolete_files: message.get_num_pinned_obsolete_files() as usize,
+            pinned_obsolete_bytes: message.get_pinned_obsolete_bytes() as usize,
+            log_replay: ReplayStats {
+                num_records: message.get_log_replay_records() as usize,
+                num_bytes: message.get_log_replay_bytes() as usize,
+                duration: Duration::from_millis(message.get_log_replay_duration_ms()),
+            },
+            read_amplification: ReadAmplification {
+                counts: message.get_read_amplification_counts().to_vec(),
+            },
+            compaction: CompactionStats {
+                num_compactions: message.get_num_compactions() as usize,
+                num_bytes: message.get_compaction_bytes() as usize,
+                duration: Duration::from_millis(message.get_compaction_duration_ms()),
+                num_failures: message.get_num_compaction_failures() as usize,
+            },
+            soft_limits: SoftLimitStats {
+                num_memtable_nearly_full: message.get_num_memtable_nearly_full() as usize,
+                num_flush_behind: message.get_num_flush_behind() as usize,
+                num_compaction_backlog: message.get_num_compaction_backlog() as usize,
+            },
+            user_bytes: message.get_user_bytes() as usize,
+            compaction_backlog_bytes: message.get_compaction_backlog_bytes() as usize,
+        }
+    }
+
+    /// The number of bytes written to the disk, by both the writes and the compactions, per byte
+    /// written by the writes, or zero if nothing has been written since the engine was opened.
+    ///
+    /// The flushes of the logs replayed on open count as compactions without any write, so the
+    /// ratio is inflated until enough writes come in.
+    pub fn write_amplification(&self) -> f64 {
+        if self.user_bytes == 0 {
+            return 0.0;
+        }
+        (self.user_bytes + self.compaction.num_bytes) as f64 / self.user_bytes as f64
+    }
+}
+
+/// The number of times each soft limit has been crossed.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SoftLimitStats {
+    pub num_memtable_nearly_full: usize,
+    pub num_flush_behind: usize,
+    pub num_compaction_backlog: usize,
+}
+
+/// How the compactions, i.e. flushes and merges, have gone.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CompactionStats {
+    /// The number of compactions run.
+    pub num_compactions: usize,
+
+    /// The number of bytes written into the new segment files.
+    pub num_bytes: usize,
+
+    /// The total time taken by the compactions.
+    pub duration: Duration,
+
+    /// The number of compactions failed, which the compaction daemon retries later.
+    pub num_failures: usize,
+}
+
+impl CompactionStats {
+    /// The compaction throughput in bytes per second.
+    pub fn bytes_per_second(&self) -> f64 {
+        self.num_bytes as f64 / self.duration.as_secs_f64().max(f64::EPSILON)
+    }
+}
+
+/// A histogram of the number of layers, i.e. Memtables and SSTables, each point lookup touches.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ReadAmplification {
+    /// The number of lookups indexed by the number of layers they touched.
+    pub counts: Vec<u64>,
+}
+
+impl ReadAmplification {
+    /// The number of lookups recorded.
+    pub fn num_lookups(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// The average number of layers per lookup.
+    pub fn mean(&self) -> f64 {
+        let num_layers: u64 = self
+            .counts
+            .iter()
+            .enumerate()
+            .map(|(num_layers, count)| num_layers as u64 * count)
+            .sum();
+        num_layers as f64 / self.num_lookups().max(1) as f64
+    }
+
+    /// The largest number of layers a lookup has touched.
+    pub fn max(&self) -> usize {
+        self.counts
+            .iter()
+            .rposition(|&count| count > 0)
+            .unwrap_or(0)
+    }
+}
+
+/// The number of layers the read amplification recorder tells apart, the lookups touching more
+/// being counted as touching that many.
+const MAX_RECORDED_LAYERS: usize = 63;
+
+/// Records the read amplification of the point lookups of a catalog without locking, with a
+/// counter for each number of layers, so that the concurrent gets do not contend on it.
+#[derive(Debug)]
+pub(crate) struct ReadAmplificationRecorder {
+    counts: [AtomicU64; MAX_RECORDED_LAYERS + 1],
+}
+
+impl Default for ReadAmplificationRecorder {
+    fn default() -> Self {
+        Self {
+            counts: std::array::from_fn(|_| AtomicU64::new(0)),
+        }
+    }
+}
+
+impl ReadAmplificationRecorder {
+    pub fn record(&self, num_layers: usize) {
+        self.counts[num_layers.min(MAX_RECORDED_LAYERS)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The histogram recorded so far, which may miss the lookups recorded meanwhile.
+    pub fn snapshot(&self) -> ReadAmplification {
+        let mut counts = self
+            .counts
+            .iter()
+            .map(|count| count.load(Ordering::Relaxed))
+            .collect::<Vec<_>>();
+        let num_counts = counts
+            .iter()
+            .rposition(|&count| count > 0)
+            .map_or(0, |max| max + 1);
+        counts.truncate(num_counts);
+        ReadAmplification { counts }
+    }
+}
+
+/// How replaying a Memtable log went.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ReplayStats {
+    /// The number of records replayed.
+    pub num_records: usize,
+
+    /// The number of bytes replayed.
+    pub num_bytes: usize,
+
+    /// The time taken by the replay.
+    pub duration: Duration,
+}
+
+impl ReplayStats {
+    /// The replay throughput in bytes per second.
+    pub fn bytes_per_second(&self) -> f64 {
+        self.num_bytes as f64 / self.duration.as_secs_f64().max(f64::EPSILON)
+    }
+}
+
+/// The on-disk layout of a live SSTable.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SSTableDescription {
+    /// The path of the segment file.
+    pub file_path: PathBuf,
+
+    /// The generation number.
+    pub gen_no: usize,
+
+    /// The compaction epoch that generated the SSTable.
+    pub epoch_no: u64,
+
+    /// The size of the segment file in bytes.
+    pub file_size: usize,
+
+    /// The smallest and the largest keys, or None if the SSTable is empty.
+    pub key_range: Option<(String, String)>,
+
+    /// The number of records, deletions included.
+    pub num_entries: usize,
+
+    /// Whether the SSTable is quarantined after too many read errors in a row.
+    pub is_quarantined: bool,
+
+    /// The composition of the records.
+    pub properties: SSTableProperties,
+}
+
+/// The composition of the records of an SSTable, which is stored in its segment file, e.g. for
+/// telling how much of it the deletions take up.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SSTableProperties {
+    /// The number of records, deletions included.
+    pub num_entries: usize,
+
+    /// The number of deletions, i.e. tombstones, which only go away once merged at the bottom.
+    pub num_deletions: usize,
+
+    /// The number of records of merge operands.
+    pub num_merges: usize,
+
+    /// The number of bytes of the keys in full, before their common prefixes are shared.
+    pub raw_key_bytes: usize,
+
+    /// The number of bytes of the values and the merge operands, the ones separated into the blob
+    /// files included.
+    pub raw_value_bytes: usize,
+
+    /// The number of chunks of the segment file.
+    pub num_chunks: usize,
+}
+
+impl SSTableProperties {
+    /// The share of the records that are deletions, or zero if there are no records.
+    pub fn deletion_ratio(&self) -> f64 {
+        if self.num_entries == 0 {
+            return 0.0;
+        }
+        self.num_deletions as f64 / self.num_entries as f64
+    }
+
+    /// Count the record of a command as stored in a segment file, with its key in full.
+    pub(crate) fn add_command(&mut self, command: &messages::Command) {
+        self.num_entries += 1;
+        self.raw_key_bytes += command.get_key().len();
+        match command.get_command_type() {
+            messages::CommandType::SET_VALUE => {
+                self.raw_value_bytes += match command.has_blob() {
+                    true => command.get_blob().get_length() as usize,
+                    false => command.get_value().len(),
+                };
+            }
+            messages::CommandType::DELETE => self.num_deletions += 1,
+            messages::CommandType::MERGE => {
+                self.num_merges += 1;
+                self.raw_value_bytes += command
+                    .get_operands()
+                    .iter()
+                    .map(String::len)
+                    .sum::<usize>();
+            }
+            // Only the Memtable logs hold batches.
+            messages::CommandType::BATCH => {}
+        }
+    }
+
+    pub fn to_message(&self) -> messages::SSTableProperties {
+        let mut message = messages::SSTableProperties::new();
+        message.set_num_entries(self.num_entries as u64);
+        message.set_num_deletions(self.num_deletions as u64);
+        message.set_num_merges(self.num_merges as u64);
+        message.set_raw_key_bytes(self.raw_key_bytes as u64);
+        message.set_raw_value_bytes(self.raw_value_bytes as u64);
+        message.set_num_chunks(self.num_chunks as u64);
+        message
+    }
+
+    pub fn from_message(message: &messages::SSTableProperties) -> Self {
+        Self {
+            num_entries: message.get_num_entries() as usize,
+            num_deletions: message.get_num_deletions() as usize,
+            num_merges: message.get_num_merges() as usize,
+            raw_key_bytes: message.get_raw_key_bytes() as usize,
+            raw_value_bytes: message.get_raw_value_bytes() as usize,
+            num_chunks: message.get_num_chunks() as usize,
+        }
+    }
+}
+
+/// The approximate size of a key range, which counts a key once for every layer holding it.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RangeEstimate {
+    /// The number of records, deletions included.
+    pub num_keys: usize,
+
+    /// The number of bytes the records take up.
+    pub num_bytes: usize,
+}
+
+/// What a consistency check of a snapshot found, see `NaiveKV::verify`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ConsistencyReport {
+    /// The number of keys with a value in the full scan.
+    pub num_keys: usize,
+
+    /// The number of deleted keys in the full scan.
+    pub num_tombstones: usize,
+
+    /// The number of problems found, which only the first few of are described.
+    pub num_problems: usize,
+    pub problems: Vec<String>,
+}
+
+impl ConsistencyReport {
+    /// The most problems described in a report.
+    pub const MAX_PROBLEMS: usize = 100;
+
+    pub fn is_consistent(&self) -> bool {
+        self.num_problems == 0
+    }
+
+    pub(crate) fn add_problem(&mut self, problem: String) {
+        log::error!("Found an inconsistency: {}", problem);
+        self.num_problems += 1;
+        if self.problems.len() < Self::MAX_PROBLEMS {
+            self.problems.push(problem);
+        }
+    }
+
+    pub fn to_message(&self) -> messages::ConsistencyReport {
+        let mut message = messages::ConsistencyReport::new();
+        message.set_num_keys(self.num_keys as u64);
+        message.set_num_tombstones(self.num_tombstones as u64);
+        message.set_num_problems(self.num_problems as u64);
+        message.set_problems(self.problems.clone().into());
+        message
+    }
+
+    pub fn from_message(message: &messages::ConsistencyReport) -> Self {
+        Self {
+            num_keys: message.get_num_keys() as usize,
+            num_tombstones: message.get_num_tombstones() as usize,
+            num_problems: message.get_num_problems() as usize,
+            problems: message.get_problems().to_vec(),
+        }
+    }
+}
+
+/// A problem an integrity check of an SSTable found, located by an offset in its segment file.
+#[derive(Clone, Debug, PartialEq)]
+pub enum SSTableProblem {
+    /// A chunk or a record that cannot be decoded, e.g. a chunk running past the end of the data.
+    Framing { offset: u64 },
+    /// A key that does not come strictly after the one before it under the comparator.
+    KeyOrder { offset: u64, key: String },
+    /// A record that fails its checksum, or whose value separated into a blob file cannot be read
+    /// back intact.
+    Checksum { offset: u64 },
+    /// A chunk the index does not locate where it is, or an index that fails to load.
+    Index { offset: u64 },
+    /// A footer, or what the SSTable took from it on open, that disagrees with the records, e.g.
+    /// on their number.
+    Footer { offset: u64 },
+}
+
+impl SSTableProblem {
+    pub fn offset(&self) -> u64 {
+        match self {
+            SSTableProblem::Framing { offset }
+            | SSTableProblem::KeyOrder { offset, .. }
+            | SSTableProblem::Checksum { offset }
+            | SSTableProblem::Index { offset }
+            | SSTableProblem::Footer { offset } => *offset,
+        }
+    }
+}
+
+/// What an integrity check of an SSTable found, see `SSTable::verify`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SSTableReport {
+    /// The number of chunks read.
+    pub num_chunks: usize,
+
+    /// The number of records read, deletions included.
+    pub num_records: usize,
+
+    /// The number of records stored with a checksum, which have been verified.
+    pub num_checksums: usize,
+
+    /// The number of problems found, which only the first few of are listed.
+    pub num_problems: usize,
+    pub problems: Vec<SSTableProblem>,
+}
+
+impl SSTableReport {
+    /// The most problems listed in a report.
+    pub const MAX_PROBLEMS: usize = 100;
+
+    pub fn is_intact(&self) -> bool {
+        self.num_problems == 0
+    }
+
+    pub(crate) fn add_problem(&mut self, problem: SSTableProblem) {
+        self.num_problems += 1;
+        if self.problems.len() < Self::MAX_PROBLEMS {
+            self.problems.push(problem);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stats_message() {
+        let mut stats = Stats {
+            num_pinned_obsolete_files: 1,
+            pinned_obsolete_bytes: 2,
+            log_replay: ReplayStats {
+                num_records: 3,
+                num_bytes: 4,
+                duration: Duration::from_millis(5),
+            },
+            compaction: CompactionStats {
+                num_compactions: 6,
+                num_bytes: 7,
+                duration: Duration::from_millis(8),
+                num_failures: 14,
+            },
+            soft_limits: SoftLimitStats {
+                num_memtable_nearly_full: 9,
+                num_flush_behind: 10,
+                num_compaction_backlog: 11,
+            },
+            user_bytes: 12,
+            compaction_backlog_bytes: 13,
+            ..Stats::default()
+        };
+        let recorder = ReadAmplificationRecorder::default();
+        recorder.record(1);
+        recorder.record(3);
+        stats.read_amplification = recorder.snapshot();
+        assert_eq!(stats.read_amplification.counts, vec![0, 1, 0, 1]);
+        assert_eq!(Stats::from_message(&stats.to_message()), stats);
+
+        // The lookups touching too many layers are counted with the most told apart.
+        recorder.record(MAX_RECORDED_LAYERS + 10);
+        assert_eq!(recorder.snapshot().max(), MAX_RECORDED_LAYERS);
+        assert_eq!(recorder.snapshot().num_lookups(), 3);
+    }
+
+    #[test]
+    fn test_write_amplification() {
+        let mut stats = Stats::default();
+        assert_eq!(stats.write_amplification(), 0.0);
+        stats.user_bytes = 100;
+        assert_eq!(stats.write_amplification(), 1.0);
+        stats.compaction.num_bytes = 250;
+        assert_eq!(stats.write_amplification(), 3.5);
+    }
+}
@@ -1,7 +1,7 @@
-use crossbeam::channel::{bounded, Sender};
+use crossbeam::channel::{bounded, Sender, TrySendError};
 use std::thread;
 
-use crate::types::Result;
+use crate::types::{NaiveError, Result};
 
 /// The ratio of the task buffer size to the number of worker threads.
 const TASK_WORKER_RATIO: usize = 2;
@@ -38,6 +38,18 @@ impl ThreadPool {
         Ok(self.sender.as_ref().unwrap().send(Box::new(task))?)
     }
 
+    /// Add a task without blocking, which fails with TaskQueueFull if the task buffer is full.
+    pub fn try_add_task<F>(&self, task: F) -> Result<()>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        match self.sender.as_ref().unwrap().try_send(Box::new(task)) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(_)) => Err(NaiveError::TaskQueueFull),
+            Err(TrySendError::Disconnected(_)) => Err(NaiveError::ChannelSendError),
+        }
+    }
+
     pub fn worker_count(&self) -> usize {
         self.workers.len()
     }
@@ -72,7 +84,7 @@ mod tests {
                             let mut sum = sum.lock().unwrap();
                             *sum += i;
                         })
-                        .expect(&format!("Failed to add_task for {}", i));
+                        .unwrap_or_else(|_| panic!("Failed to add_task for {}", i));
                 }
                 assert_eq!(thread_pool.worker_count(), 5);
             }
@@ -80,4 +92,29 @@ mod tests {
             assert_eq!(*sum, 5050);
         }
     }
+
+    #[test]
+    fn test_try_add_task() {
+        let (sender, receiver) = crossbeam::channel::unbounded::<()>();
+        let thread_pool = ThreadPool::new(1);
+
+        // Block the only worker, and then fill up the task buffer behind it.
+        let (started_sender, started_receiver) = crossbeam::channel::unbounded();
+        thread_pool
+            .try_add_task(move || {
+                started_sender.send(()).unwrap();
+                let _ = receiver.recv();
+            })
+            .unwrap();
+        started_receiver.recv().unwrap();
+        for _ in 0..TASK_WORKER_RATIO {
+            thread_pool.try_add_task(|| {}).unwrap();
+        }
+        assert!(matches!(
+            thread_pool.try_add_task(|| {}),
+            Err(NaiveError::TaskQueueFull)
+        ));
+
+        drop(sender);
+    }
 }
@@ -0,0 +1,209 @@
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use crate::backend::Backend;
+use crate::options::Options;
+use crate::types::{NaiveError, Result};
+
+/// The name of the subdirectory for the deprecated files of a data folder.
+const TRASH_FOLDER_NAME: &str = "trash";
+
+/// Keeps deprecated files in the `trash/` subdirectory next to them for a retention period, so
+/// that operators can restore them after a bad compaction by moving them back.
+#[derive(Clone, Debug)]
+pub struct Trash {
+    /// How long a file stays in the trash.
+    retention: Duration,
+
+    /// The total size of the trash, beyond which the oldest files are removed.
+    size_cap: usize,
+
+    /// Where the files are stored.
+    backend: Arc<dyn Backend>,
+}
+
+impl Trash {
+    /// Return None if the trash is disabled.
+    pub fn new(options: &Options) -> Option<Self> {
+        if options.trash_retention_s == 0 {
+            return None;
+        }
+        Some(Self {
+            retention: Duration::from_secs(options.trash_retention_s),
+            size_cap: options.trash_size_cap,
+            backend: options.backend.clone(),
+        })
+    }
+
+    /// Move a deprecated file into the trash, or remove it right away from the backend if there
+    /// is no trash.
+    pub fn discard(backend: &dyn Backend, trash: Option<&Self>, file_path: &Path) -> Result<()> {
+        let trash = match trash {
+            Some(trash) => trash,
+            None => {
+                backend.remove_file(file_path)?;
+                return Ok(());
+            }
+        };
+        let backend = trash.backend.as_ref();
+        let (folder_path, file_name) = match (file_path.parent(), file_path.file_name()) {
+            (Some(folder_path), Some(file_name)) => (folder_path, file_name),
+            _ => {
+                backend.remove_file(file_path)?;
+                return Ok(());
+            }
+        };
+        let trash_path = Self::gen_trash_path(folder_path);
+        backend.create_dir_all(&trash_path)?;
+        let trashed_file_path = trash_path.join(file_name);
+        backend.rename(file_path, &trashed_file_path)?;
+
+        // The modification time tells when the file was moved into the trash. A concurrent purge
+        // may have removed the file already, going by the time it was last written.
+        match backend.set_modified_time(&trashed_file_path, SystemTime::now()) {
+            Err(error) if is_not_found(&error) => {}
+            result => result?,
+        }
+        log::info!("Moved {} into the trash.", file_path.display());
+
+        trash.purge(folder_path)
+    }
+
+    /// Remove the files beyond the retention period, and then the oldest ones beyond the size cap.
+    pub fn purge(&self, folder_path: &Path) -> Result<()> {
+        let trash_path = Self::gen_trash_path(folder_path);
+        if !self.backend.exists(&trash_path) {
+            return Ok(());
+        }
+        let now = SystemTime::now();
+        let mut trashed_files = Vec::new();
+        let mut total_size = 0;
+        for file_path in self.backend.list_files(&trash_path)? {
+            // Another purge of the folder, e.g. by a concurrent discard, may have removed the file
+            // since it was listed.
+            let stat = self
+                .backend
+                .modified_time(&file_path)
+                .and_then(|modified_time| {
+                    Ok((modified_time, self.backend.file_size(&file_path)? as usize))
+                });
+            let (modified_time, file_size) = match stat {
+                Ok(stat) => stat,
+                Err(error) if is_not_found(&error) => continue,
+                Err(error) => return Err(error),
+            };
+            let age = now.duration_since(modified_time).unwrap_or_default();
+            if age >= self.retention {
+                self.remove(&file_path)?;
+                continue;
+            }
+            total_size += file_size;
+            trashed_files.push((modified_time, file_size, file_path));
+        }
+        trashed_files.sort();
+        for (_, file_size, file_path) in trashed_files {
+            if total_size <= self.size_cap {
+                break;
+            }
+            self.remove(&file_path)?;
+            total_size -= file_size;
+        }
+        Ok(())
+    }
+
+    pub fn gen_trash_path(folder_path: &Path) -> PathBuf {
+        folder_path.join(TRASH_FOLDER_NAME)
+    }
+
+    /// Remove a file from the trash, unless another purge has just removed it.
+    fn remove(&self, file_path: &Path) -> Result<()> {
+        if self.backend.remove_file(file_path)? {
+            log::info!("Removed {} from the trash.", file_path.display());
+        }
+        Ok(())
+    }
+}
+
+fn is_not_found(error: &NaiveError) -> bool {
+    matches!(error, NaiveError::IoError(error) if error.kind() == std::io::ErrorKind::NotFound)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::LocalBackend;
+    use std::fs::OpenOptions;
+
+    #[test]
+    fn test_trash() {
+        const FILE_SIZE: usize = 100;
+
+        let folder_path = PathBuf::from("/tmp/naive_kv/test_trash/");
+        let _ = std::fs::remove_dir_all(&folder_path);
+        std::fs::create_dir_all(&folder_path).unwrap();
+        let options = Options {
+            trash_retention_s: 3600,
+            trash_size_cap: 3 * FILE_SIZE,
+            ..Options::default()
+        };
+        let trash = Trash::new(&options).unwrap();
+        let trash_path = Trash::gen_trash_path(&folder_path);
+
+        let mut trashed_file_paths = Vec::new();
+        for num in 0..3 {
+            let file_path = folder_path.join(format!("gen_{}.sst", num));
+            std::fs::write(&file_path, vec![0u8; FILE_SIZE]).unwrap();
+            Trash::discard(&LocalBackend, Some(&trash), &file_path).unwrap();
+            assert!(!file_path.exists());
+            let trashed_file_path = trash_path.join(format!("gen_{}.sst", num));
+            assert!(trashed_file_path.exists());
+            trashed_file_paths.push(trashed_file_path);
+        }
+
+        // Expire the first file.
+        OpenOptions::new()
+            .write(true)
+            .open(&trashed_file_paths[0])
+            .unwrap()
+            .set_modified(SystemTime::now() - Duration::from_secs(7200))
+            .unwrap();
+        trash.purge(&folder_path).unwrap();
+        assert!(!trashed_file_paths[0].exists());
+        assert!(trashed_file_paths[1].exists());
+
+        // Exceed the size cap, which evicts the oldest file.
+        std::thread::sleep(Duration::from_millis(10));
+        for num in 3..5 {
+            let file_path = folder_path.join(format!("gen_{}.sst", num));
+            std::fs::write(&file_path, vec![0u8; FILE_SIZE]).unwrap();
+            Trash::discard(&LocalBackend, Some(&trash), &file_path).unwrap();
+        }
+        assert!(!trashed_file_paths[1].exists());
+        assert!(trashed_file_paths[2].exists());
+        assert_eq!(std::fs::read_dir(&trash_path).unwrap().count(), 3);
+
+        // The concurrent discards purge the same files, each taking the others' removals.
+        std::thread::scope(|scope| {
+            for thread_no in 0..4 {
+                let (folder_path, trash) = (&folder_path, &trash);
+                scope.spawn(move || {
+                    for num in 0..20 {
+                        let file_path = folder_path.join(format!("gen_{}_{}.sst", thread_no, num));
+                        std::fs::write(&file_path, vec![0u8; FILE_SIZE]).unwrap();
+                        Trash::discard(&LocalBackend, Some(trash), &file_path).unwrap();
+                    }
+                });
+            }
+        });
+        assert!(std::fs::read_dir(&trash_path).unwrap().count() <= 3);
+        trash.remove(&trash_path.join("gen_0.sst")).unwrap();
+
+        // Without a trash, files are removed right away.
+        let file_path = folder_path.join("memtable_0.log");
+        std::fs::write(&file_path, b"log").unwrap();
+        Trash::discard(&LocalBackend, None, &file_path).unwrap();
+        assert!(!file_path.exists());
+        assert!(!trash_path.join("memtable_0.log").exists());
+    }
+}
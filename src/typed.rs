@@ -0,0 +1,173 @@
+use std::marker::PhantomData;
+use std::ops::RangeBounds;
+use std::time::Duration;
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::catalog::CatalogViewer;
+use crate::types::{NaiveError, Result, WriteReceipt};
+
+/// How a typed viewer encodes the values into the strings stored.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Encoding {
+    /// Readable by the untyped clients too, e.g. through the server.
+    Json,
+    /// Compact, and stored in base64 since the values are strings.
+    Bincode,
+}
+
+impl Encoding {
+    fn encode<T: Serialize>(&self, value: &T) -> Result<String> {
+        match self {
+            Encoding::Json => serde_json::to_string(value).map_err(invalid_value),
+            Encoding::Bincode => bincode::serialize(value)
+                .map(|bytes| BASE64.encode(bytes))
+                .map_err(invalid_value),
+        }
+    }
+
+    fn decode<T: DeserializeOwned>(&self, value: &str) -> Result<T> {
+        match self {
+            Encoding::Json => serde_json::from_str(value).map_err(invalid_value),
+            Encoding::Bincode => {
+                let bytes = BASE64.decode(value).map_err(invalid_value)?;
+                bincode::deserialize(&bytes).map_err(invalid_value)
+            }
+        }
+    }
+}
+
+fn invalid_value(error: impl std::fmt::Display) -> NaiveError {
+    NaiveError::InvalidValue(error.to_string())
+}
+
+/// A catalog viewer storing the values of a serializable type, so that the applications need not
+/// hand-roll their encoding.
+///
+/// The viewer does not tell the values of other types or encodings apart, which fail to decode
+/// with InvalidValue at best.
+pub struct TypedViewer<T> {
+    catalog_viewer: CatalogViewer,
+    encoding: Encoding,
+    _value_type: PhantomData<fn() -> T>,
+}
+
+impl<T: Serialize + DeserializeOwned> TypedViewer<T> {
+    pub fn new(catalog_viewer: CatalogViewer, encoding: Encoding) -> Self {
+        Self {
+            catalog_viewer,
+            encoding,
+            _value_type: PhantomData,
+        }
+    }
+
+    /// The untyped viewer underneath, e.g. for the operations without a typed counterpart.
+    pub fn catalog_viewer(&mut self) -> &mut CatalogViewer {
+        &mut self.catalog_viewer
+    }
+
+    pub fn into_inner(self) -> CatalogViewer {
+        self.catalog_viewer
+    }
+
+    pub fn get(&mut self, key: &str) -> Result<Option<T>> {
+        self.catalog_viewer
+            .get(key)?
+            .map(|value| self.encoding.decode(&value))
+            .transpose()
+    }
+
+    pub fn set(&mut self, key: String, value: &T) -> Result<WriteReceipt> {
+        let value = self.encoding.encode(value)?;
+        self.catalog_viewer.set(key, value)
+    }
+
+    pub fn set_with_ttl(&mut self, key: String, value: &T, ttl: Duration) -> Result<WriteReceipt> {
+        let value = self.encoding.encode(value)?;
+        self.catalog_viewer.set_with_ttl(key, value, ttl)
+    }
+
+    pub fn remove(&mut self, key: String) -> Result<WriteReceipt> {
+        self.catalog_viewer.remove(key)
+    }
+
+    /// Iterate over the decoded key-value pairs in the range as of now.
+    pub fn scan<R: RangeBounds<String>>(
+        &self,
+        range: R,
+    ) -> Result<impl Iterator<Item = Result<(String, T)>>> {
+        let encoding = self.encoding;
+        Ok(self.catalog_viewer.scan(range)?.map(move |entry| {
+            let (key, value) = entry?;
+            Ok((key, encoding.decode(&value)?))
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::options::Options;
+    use crate::NaiveKV;
+    use serde::Deserialize;
+
+    #[derive(Debug, Deserialize, PartialEq, Serialize)]
+    struct User {
+        name: String,
+        age: u32,
+        tags: Vec<String>,
+    }
+
+    #[test]
+    fn test_typed_viewer() {
+        const FOLDER_PATH: &str = "/tmp/naive_kv/test_typed_viewer/";
+
+        let _ = std::fs::remove_dir_all(FOLDER_PATH);
+        let naive_kv = NaiveKV::open(FOLDER_PATH, Options::default()).unwrap();
+        let user = |name: &str, age| User {
+            name: name.to_owned(),
+            age,
+            tags: vec!["admin".to_owned()],
+        };
+        for encoding in [Encoding::Json, Encoding::Bincode] {
+            let mut users = TypedViewer::new(naive_kv.catalog_viewer().unwrap(), encoding);
+            users.set("user1".to_owned(), &user("alice", 30)).unwrap();
+            users.set("user2".to_owned(), &user("bob", 40)).unwrap();
+            assert_eq!(users.get("user1").unwrap(), Some(user("alice", 30)));
+            assert_eq!(users.get("user3").unwrap(), None);
+            let entries = users
+                .scan("user1".to_owned()..)
+                .unwrap()
+                .collect::<Result<Vec<_>>>()
+                .unwrap();
+            assert_eq!(
+                entries,
+                vec![
+                    ("user1".to_owned(), user("alice", 30)),
+                    ("user2".to_owned(), user("bob", 40)),
+                ]
+            );
+            users.remove("user2".to_owned()).unwrap();
+            assert_eq!(users.get("user2").unwrap(), None);
+        }
+
+        // JSON is readable untyped, and a value of another type fails to decode.
+        let mut catalog_viewer = naive_kv.catalog_viewer().unwrap();
+        let mut users = TypedViewer::new(naive_kv.catalog_viewer().unwrap(), Encoding::Json);
+        users.set("user1".to_owned(), &user("alice", 30)).unwrap();
+        assert_eq!(
+            catalog_viewer.get("user1").unwrap(),
+            Some(r#"{"name":"alice","age":30,"tags":["admin"]}"#.to_owned())
+        );
+        catalog_viewer
+            .set("user1".to_owned(), "alice".to_owned())
+            .unwrap();
+        assert!(matches!(
+            users.get("user1"),
+            Err(NaiveError::InvalidValue(_))
+        ));
+    }
+}
@@ -1,42 +1,343 @@
 use crossbeam::channel;
 use log::SetLoggerError;
 use protobuf::ProtobufError;
+use std::path::{Path, PathBuf};
 use std::sync::{MutexGuard, PoisonError, RwLockReadGuard, RwLockWriteGuard};
+use std::time::Duration;
 
-use crate::protos::messages::{Command, CommandType};
+use crate::memtable;
+use crate::protos::messages::{Command, CommandType, Status};
+use crate::utils;
 
 #[derive(Clone, Debug, PartialEq)]
 pub enum Record {
     Value(String),
+    /// A value which counts as missing from the given time in milliseconds since the Unix epoch.
+    ExpiringValue {
+        value: String,
+        expires_at_ms: u64,
+    },
     Deleted,
+    /// The merge operands not yet applied to the older records of the key, from the oldest to the
+    /// newest.
+    Merge(Vec<String>),
 }
 
 impl Record {
+    #[allow(clippy::len_without_is_empty)]
     pub fn len(&self) -> usize {
         match self {
-            Record::Value(string) => string.len(),
+            Record::Value(string) | Record::ExpiringValue { value: string, .. } => string.len(),
             Record::Deleted => 2,
+            Record::Merge(operands) => operands.iter().map(String::len).sum(),
         }
     }
 
+    /// Turn the value into a deletion if it has expired by the given time, which hides the older
+    /// records of the key all the same.
+    pub fn expire(self, now_ms: u64) -> Record {
+        match self {
+            Record::ExpiringValue { expires_at_ms, .. } if expires_at_ms <= now_ms => {
+                Record::Deleted
+            }
+            record => record,
+        }
+    }
+
+    pub(crate) fn kind(&self) -> RecordKind {
+        match self {
+            Record::Value(_) => RecordKind::Value,
+            Record::ExpiringValue { expires_at_ms, .. } => RecordKind::ExpiringValue {
+                expires_at_ms: *expires_at_ms,
+            },
+            Record::Deleted => RecordKind::Deleted,
+            Record::Merge(_) => RecordKind::Merge,
+        }
+    }
+
+    /// Convert the record into a command, optionally sealed with a checksum.
+    pub fn to_command(&self, key: String, with_checksum: bool) -> Command {
+        self.to_stamped_command(key, 0, None, with_checksum)
+    }
+
+    /// Convert the record into a command stamped with a sequence number unless it is zero and
+    /// with the write timestamp if any, optionally sealed with a checksum.
+    pub fn to_stamped_command(
+        &self,
+        key: String,
+        sequence_no: u64,
+        timestamp_ms: Option<u64>,
+        with_checksum: bool,
+    ) -> Command {
+        let mut command = Command::new();
+        command.set_key(key);
+        command.set_sequence_no(sequence_no);
+        if let Some(timestamp_ms) = timestamp_ms {
+            command.set_timestamp_ms(timestamp_ms);
+        }
+        match self {
+            Record::Value(value) => {
+                command.set_command_type(CommandType::SET_VALUE);
+                command.set_value(value.clone());
+            }
+            Record::ExpiringValue {
+                value,
+                expires_at_ms,
+            } => {
+                command.set_command_type(CommandType::SET_VALUE);
+                command.set_value(value.clone());
+                command.set_expires_at_ms(*expires_at_ms);
+            }
+            Record::Deleted => {
+                command.set_command_type(CommandType::DELETE);
+            }
+            Record::Merge(operands) => {
+                command.set_command_type(CommandType::MERGE);
+                command.set_operands(operands.clone().into());
+            }
+        }
+        if with_checksum {
+            command.set_checksum(command_checksum(&command));
+        }
+        command
+    }
+
+    /// Convert a command read from the given location of a file, verifying its checksum if any.
+    pub fn from_checked_command(
+        command: &Command,
+        file_path: &Path,
+        offset: u64,
+    ) -> Result<Record> {
+        verify_command(command, file_path, offset)?;
+        Record::from_command(command)
+    }
+
     pub fn from_command(command: &Command) -> Result<Record> {
         match command.get_command_type() {
             CommandType::SET_VALUE => {
                 if !command.has_value() {
                     return Err(NaiveError::InvalidData);
                 }
-                Ok(Record::Value(command.get_value().to_owned()))
+                let value = command.get_value().to_owned();
+                Ok(match command.has_expires_at_ms() {
+                    true => Record::ExpiringValue {
+                        value,
+                        expires_at_ms: command.get_expires_at_ms(),
+                    },
+                    false => Record::Value(value),
+                })
             }
             CommandType::DELETE => {
-                if command.has_value() {
+                if command.has_value() || command.has_expires_at_ms() {
                     return Err(NaiveError::InvalidData);
                 }
                 Ok(Record::Deleted)
             }
+            CommandType::MERGE => {
+                if command.has_value()
+                    || command.has_expires_at_ms()
+                    || command.get_operands().is_empty()
+                {
+                    return Err(NaiveError::InvalidData);
+                }
+                Ok(Record::Merge(command.get_operands().to_vec()))
+            }
+            // A batch holds records rather than being one.
+            CommandType::BATCH => Err(NaiveError::InvalidData),
         }
     }
 }
 
+/// Verify the checksum of a command read from the given location of a file, if it has one.
+pub(crate) fn verify_command(command: &Command, file_path: &Path, offset: u64) -> Result<()> {
+    if command.has_checksum() && command.get_checksum() != command_checksum(command) {
+        log::error!(
+            "Checksum mismatch in {} at offset {}.",
+            file_path.display(),
+            offset
+        );
+        return Err(NaiveError::Corruption {
+            file_path: file_path.to_owned(),
+            offset,
+        });
+    }
+    Ok(())
+}
+
+/// Seal the commands of a batch, stamped with its sequence number and timestamp, into a single
+/// command, optionally with a checksum.
+pub(crate) fn batch_command(
+    commands: Vec<Command>,
+    sequence_no: u64,
+    timestamp_ms: u64,
+    with_checksum: bool,
+) -> Command {
+    let mut command = Command::new();
+    command.set_command_type(CommandType::BATCH);
+    command.set_sequence_no(sequence_no);
+    command.set_timestamp_ms(timestamp_ms);
+    command.set_batch(commands.into());
+    if with_checksum {
+        command.set_checksum(command_checksum(&command));
+    }
+    command
+}
+
+/// What a record is, told without its value, e.g. for the existence checks.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum RecordKind {
+    Value,
+    ExpiringValue { expires_at_ms: u64 },
+    Deleted,
+    Merge,
+}
+
+impl RecordKind {
+    /// Whether the key is present as of the given time, or None for merge operands, which depend
+    /// on the older records of the key.
+    pub(crate) fn is_present(self, now_ms: u64) -> Option<bool> {
+        match self {
+            RecordKind::Value => Some(true),
+            RecordKind::ExpiringValue { expires_at_ms } => Some(expires_at_ms > now_ms),
+            RecordKind::Deleted => Some(false),
+            RecordKind::Merge => None,
+        }
+    }
+}
+
+/// The CRC32 checksum over the type, the key and the value of a command.
+fn command_checksum(command: &Command) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&[command.get_command_type() as u8]);
+    hasher.update(&(command.get_key().len() as u64).to_be_bytes());
+    hasher.update(command.get_key().as_bytes());
+    if command.has_value() {
+        hasher.update(command.get_value().as_bytes());
+    }
+    for operand in command.get_operands() {
+        hasher.update(&(operand.len() as u64).to_be_bytes());
+        hasher.update(operand.as_bytes());
+    }
+    // Keep the checksums of the unstamped commands as they were.
+    if command.get_sequence_no() != 0 {
+        hasher.update(&command.get_sequence_no().to_be_bytes());
+    }
+    if command.has_timestamp_ms() {
+        hasher.update(&command.get_timestamp_ms().to_be_bytes());
+    }
+    if command.has_expires_at_ms() {
+        hasher.update(&command.get_expires_at_ms().to_be_bytes());
+    }
+    for command in command.get_batch() {
+        hasher.update(&command_checksum(command).to_be_bytes());
+    }
+    hasher.finalize()
+}
+
+/// A record along with when it was written, which is unknown for the records written before the
+/// timestamps were kept.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TimedRecord {
+    pub record: Record,
+
+    /// The wall-clock time of the write in milliseconds since the Unix epoch.
+    pub timestamp_ms: Option<u64>,
+
+    /// The sequence number of the write, or zero for the records flushed before the sequence
+    /// numbers were kept in the SSTables.
+    pub sequence_no: u64,
+}
+
+impl TimedRecord {
+    /// Convert a command read from the given location of a file, verifying its checksum if any.
+    pub fn from_checked_command(
+        command: &Command,
+        file_path: &Path,
+        offset: u64,
+    ) -> Result<TimedRecord> {
+        Ok(TimedRecord {
+            record: Record::from_checked_command(command, file_path, offset)?,
+            timestamp_ms: command
+                .has_timestamp_ms()
+                .then(|| command.get_timestamp_ms()),
+            sequence_no: command.get_sequence_no(),
+        })
+    }
+}
+
+/// A list of writes applied together while holding the shards of the Memtable their keys hash to.
+#[derive(Clone, Debug, Default)]
+pub struct WriteBatch {
+    writes: Vec<(String, Record)>,
+}
+
+impl WriteBatch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set(&mut self, key: String, value: String) {
+        self.writes.push((key, Record::Value(value)));
+    }
+
+    /// Set a value for the key which counts as missing once the TTL has passed since now.
+    pub fn set_with_ttl(&mut self, key: String, value: String, ttl: Duration) {
+        let expires_at_ms = utils::now_ms().saturating_add(ttl.as_millis() as u64);
+        self.set_expiring(key, value, expires_at_ms);
+    }
+
+    /// Set a value for the key which counts as missing from the given time.
+    pub(crate) fn set_expiring(&mut self, key: String, value: String, expires_at_ms: u64) {
+        let record = Record::ExpiringValue {
+            value,
+            expires_at_ms,
+        };
+        self.writes.push((key, record));
+    }
+
+    pub fn remove(&mut self, key: String) {
+        self.writes.push((key, Record::Deleted));
+    }
+
+    /// Write a merge operand for the key, which requires a merge operator in the options.
+    pub fn merge(&mut self, key: String, operand: String) {
+        self.writes.push((key, Record::Merge(vec![operand])));
+    }
+
+    pub fn len(&self) -> usize {
+        self.writes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.writes.is_empty()
+    }
+
+    pub fn iter(&self) -> std::slice::Iter<'_, (String, Record)> {
+        self.writes.iter()
+    }
+
+    /// The hashes of the keys written, which tell the shards of the Memtable the batch takes.
+    pub(crate) fn key_hashes(&self) -> Vec<u64> {
+        self.writes
+            .iter()
+            .map(|(key, _)| memtable::key_hash(key))
+            .collect()
+    }
+}
+
+/// What a write did, so that callers can build quotas and back-pressure on top of the engine.
+#[derive(Clone, Debug, PartialEq)]
+pub struct WriteReceipt {
+    /// The sequence number assigned to the write.
+    pub sequence_no: u64,
+
+    /// The number of bytes appended to the write-ahead log.
+    pub wal_bytes: usize,
+
+    /// Whether the write made the Memtable reach the compaction threshold.
+    pub flush_triggered: bool,
+}
+
 #[derive(Debug)]
 pub enum NaiveError {
     Unknown,
@@ -49,6 +350,73 @@ pub enum NaiveError {
     ProtobufError,
     InvalidData,
     SetLoggerError,
+    /// A record whose checksum does not match, located by the file and the offset of its message.
+    Corruption {
+        file_path: PathBuf,
+        offset: u64,
+    },
+    /// The Memtable log starts after the sequence number following the last flushed one, so
+    /// the writes in between are lost.
+    SequenceGap {
+        last_flushed_sequence_no: u64,
+        first_logged_sequence_no: u64,
+    },
+    /// The deadline of an operation passed before it completed.
+    DeadlineExceeded,
+    /// A write to a replica, which only follows the data folder of its primary.
+    ReadOnly,
+    /// A combination of options the engine cannot work with, as described.
+    InvalidOptions(String),
+    /// No background job is registered under the name.
+    JobNotFound(String),
+    /// Runs of background jobs failed, each with the name of its job and the error described.
+    JobFailed {
+        errors: Vec<(String, String)>,
+    },
+    /// The task buffer of a thread pool is full, so the task is dropped instead of waiting.
+    TaskQueueFull,
+    /// A length-prefixed frame longer than allowed, which has been skipped.
+    FrameTooLarge {
+        length: usize,
+        max_length: usize,
+    },
+    /// A read that needs an SSTable quarantined after repeated read errors, identified by its
+    /// segment file.
+    Quarantined {
+        file_path: PathBuf,
+    },
+    /// A stored value the operation cannot work with, as described, e.g. a non-integer to
+    /// increment.
+    InvalidValue(String),
+    /// Data ordered otherwise than by the configured comparator, as described, e.g. a data folder
+    /// reopened with another comparator than it was created with.
+    ComparatorMismatch(String),
+    /// No column family has been created under the name.
+    ColumnFamilyNotFound(String),
+    /// A backup or a checkpoint to restore whose files, as listed, are missing or do not match
+    /// their checksums.
+    CorruptBackup(Vec<PathBuf>),
+    /// A folder locked by another holder, e.g. a data folder some instance has open, possibly in
+    /// another process.
+    FolderInUse(PathBuf),
+    /// A folder expected to hold a data folder, e.g. on an open without create_if_missing, that
+    /// does not.
+    FolderNotFound(PathBuf),
+    /// A folder expected not to hold a data folder yet, e.g. on an open with error_if_exists,
+    /// that does.
+    FolderExists(PathBuf),
+    /// A historical view as of an epoch not in the range retained, i.e. older than the oldest one
+    /// or newer than the latest one.
+    EpochNotRetained {
+        epoch_no: u64,
+        oldest_epoch_no: u64,
+        latest_epoch_no: u64,
+    },
+    /// A request the server responded to with a status other than OK.
+    ServerError {
+        status: Status,
+        message: Option<String>,
+    },
 }
 
 impl From<std::io::Error> for NaiveError {
@@ -103,7 +471,9 @@ pub type Result<T> = std::result::Result<T, NaiveError>;
 
 impl From<Record> for Result<Option<String>> {
     fn from(record: Record) -> Self {
-        if let Record::Value(value) = record {
+        if let Record::Value(value) | Record::ExpiringValue { value, .. } =
+            record.expire(utils::now_ms())
+        {
             return Ok(Some(value));
         }
         Ok(None)
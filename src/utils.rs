@@ -1,18 +1,56 @@
-use crate::types::Result;
+use crate::types::{NaiveError, Result};
 
 /// Use an architecture-independent type to serialize the chunk size.
 type ChunkLengthType = u32;
 
-const N_BYTES_CHUNK_LENGTH: usize = (ChunkLengthType::BITS as usize) >> 3;
+pub const N_BYTES_CHUNK_LENGTH: usize = (ChunkLengthType::BITS as usize) >> 3;
 
 pub fn read_chunk(reader: &mut impl std::io::Read, buffer: &mut Vec<u8>) -> Result<usize> {
+    read_chunk_with_limit(reader, buffer, usize::MAX)
+}
+
+/// Read a chunk of at most max_length bytes. A longer chunk is read through and discarded, so that
+/// the reader is left at the start of the next chunk, and FrameTooLarge is returned.
+pub fn read_chunk_with_limit(
+    reader: &mut impl std::io::Read,
+    buffer: &mut Vec<u8>,
+    max_length: usize,
+) -> Result<usize> {
     buffer.clear();
     let chunk_length = read_chunk_length(reader)?;
+    if chunk_length > max_length {
+        let num_bytes = std::io::copy(
+            &mut std::io::Read::take(&mut *reader, chunk_length as u64),
+            &mut std::io::sink(),
+        )?;
+        if num_bytes < chunk_length as u64 {
+            return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+        }
+        return Err(NaiveError::FrameTooLarge {
+            length: chunk_length,
+            max_length,
+        });
+    }
     buffer.resize(chunk_length, 0u8);
     reader.read_exact(buffer)?;
     Ok(chunk_length)
 }
 
+/// Take the next chunk off the front of the bytes without copying it, or None at their end.
+pub fn split_chunk<'a>(bytes: &mut &'a [u8]) -> Result<Option<&'a [u8]>> {
+    if bytes.len() < N_BYTES_CHUNK_LENGTH {
+        return Ok(None);
+    }
+    let (length_bytes, rest) = bytes.split_at(N_BYTES_CHUNK_LENGTH);
+    let chunk_length = ChunkLengthType::from_be_bytes(length_bytes.try_into().unwrap()) as usize;
+    if rest.len() < chunk_length {
+        return Err(NaiveError::InvalidData);
+    }
+    let (chunk, rest) = rest.split_at(chunk_length);
+    *bytes = rest;
+    Ok(Some(chunk))
+}
+
 fn read_chunk_length(reader: &mut impl std::io::Read) -> Result<usize> {
     let mut buffer = [0u8; N_BYTES_CHUNK_LENGTH];
     match reader.read_exact(&mut buffer) {
@@ -27,34 +65,73 @@ fn read_chunk_length(reader: &mut impl std::io::Read) -> Result<usize> {
     Ok(ChunkLengthType::from_be_bytes(buffer) as usize)
 }
 
-pub fn write_chunk(writer: &mut impl std::io::Write, bytes: &[u8]) -> Result<()> {
+/// Write a chunk and return the number of bytes written, including the length prefix.
+pub fn write_chunk(writer: &mut impl std::io::Write, bytes: &[u8]) -> Result<usize> {
     // Write the message length followed by the message content.
-    writer.write(&(bytes.len() as ChunkLengthType).to_be_bytes())?;
-    writer.write(&bytes)?;
+    writer.write_all(&(bytes.len() as ChunkLengthType).to_be_bytes())?;
+    writer.write_all(bytes)?;
     writer.flush()?;
-    Ok(())
+    Ok(N_BYTES_CHUNK_LENGTH + bytes.len())
 }
 
 /// Read a chunk that consists of a single message.
 pub fn read_message<Message: protobuf::Message, Reader: std::io::Read>(
     reader: &mut Reader,
+) -> Result<Option<Message>> {
+    read_message_with_limit(reader, usize::MAX)
+}
+
+/// Read a chunk that consists of a single message of at most max_length bytes, skipping over a
+/// longer one with FrameTooLarge.
+pub fn read_message_with_limit<Message: protobuf::Message, Reader: std::io::Read>(
+    reader: &mut Reader,
+    max_length: usize,
 ) -> Result<Option<Message>> {
     let mut bytes = Vec::new();
-    let num_bytes = read_chunk(reader, &mut bytes)?;
+    let num_bytes = read_chunk_with_limit(reader, &mut bytes, max_length)?;
     if num_bytes == 0 {
         return Ok(None);
     }
     Ok(Some(Message::parse_from_bytes(&bytes)?))
 }
 
-/// Write a chunk that consists of a single message.
+/// Write a chunk that consists of a single message and return the number of bytes written.
 pub fn write_message<Message: protobuf::Message, Writer: std::io::Write>(
     message: &Message,
     writer: &mut Writer,
-) -> Result<()> {
+) -> Result<usize> {
     write_chunk(writer, &message.write_to_bytes()?)
 }
 
+/// The CRC32 checksum of everything left in the reader, e.g. of a whole file.
+pub fn checksum(reader: &mut impl std::io::Read) -> Result<u32> {
+    let mut hasher = crc32fast::Hasher::new();
+    let mut buffer = vec![0; 64 << 10];
+    loop {
+        match reader.read(&mut buffer) {
+            Ok(0) => return Ok(hasher.finalize()),
+            Ok(num_bytes) => hasher.update(&buffer[..num_bytes]),
+            Err(error) if error.kind() == std::io::ErrorKind::Interrupted => (),
+            Err(error) => return Err(error.into()),
+        }
+    }
+}
+
+/// The path of a file being written in full before it is renamed into place at the file path, so
+/// that the file there is never changed in place nor left cut short.
+pub(crate) fn temp_file_path(file_path: &std::path::Path) -> std::path::PathBuf {
+    let mut temp_file_path = file_path.as_os_str().to_owned();
+    temp_file_path.push(".tmp");
+    temp_file_path.into()
+}
+
+/// The wall-clock time in milliseconds since the Unix epoch, or zero if the clock is before it.
+pub fn now_ms() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::SystemTime::UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_millis() as u64)
+}
+
 pub fn try_remove_file(path: &std::path::Path) -> Result<bool> {
     match std::fs::remove_file(path) {
         Ok(()) => Ok(true),
@@ -66,3 +143,47 @@ pub fn try_remove_file(path: &std::path::Path) -> Result<bool> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{read_message_with_limit, write_message};
+    use crate::protos::messages::{Operation, Request};
+    use crate::types::NaiveError;
+
+    #[test]
+    fn test_read_message_with_limit() {
+        const MAX_LENGTH: usize = 64;
+
+        let mut large_request = Request::new();
+        large_request.set_id(1);
+        large_request.set_operation(Operation::SET);
+        large_request.set_key("large".to_owned());
+        large_request.set_value("x".repeat(MAX_LENGTH));
+        let mut small_request = Request::new();
+        small_request.set_id(2);
+        small_request.set_key("small".to_owned());
+
+        let mut bytes = Vec::new();
+        write_message(&large_request, &mut bytes).unwrap();
+        write_message(&small_request, &mut bytes).unwrap();
+
+        // The large message is skipped without losing track of the next one.
+        let mut reader = &bytes[..];
+        assert!(matches!(
+            read_message_with_limit::<Request, _>(&mut reader, MAX_LENGTH),
+            Err(NaiveError::FrameTooLarge {
+                max_length: MAX_LENGTH,
+                ..
+            })
+        ));
+        let request = read_message_with_limit::<Request, _>(&mut reader, MAX_LENGTH)
+            .unwrap()
+            .unwrap();
+        assert_eq!(request, small_request);
+        assert!(
+            read_message_with_limit::<Request, _>(&mut reader, MAX_LENGTH)
+                .unwrap()
+                .is_none()
+        );
+    }
+}
@@ -0,0 +1,267 @@
+use crossbeam::channel::{bounded, Receiver, Sender, TrySendError};
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::sync::RwLock;
+
+use crate::options::DEFAULT_WATCH_CAPACITY;
+use crate::types::{Record, Result};
+
+/// A write applied to a watched key, either a value set or a deletion, or the expiry of a value.
+///
+/// Only the writes through the Memtable are sent: the SSTables ingested have their keys appear
+/// without any event. An expiring value written while its key is watched is followed by the event
+/// of its expiry unless overwritten by then, which is sent by the next sweep after it expires, so
+/// some time after the reads stop seeing it. The values written before the watch, or before the
+/// engine was last opened, expire without any event.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChangeEvent {
+    pub key: String,
+
+    /// The record written, e.g. a value, an expiring one or a deletion. A merge operand is sent
+    /// as written rather than merged into the value.
+    pub record: Record,
+
+    pub sequence_no: u64,
+
+    /// Whether the value written at the sequence number has expired, rather than been written,
+    /// in which case the record is a deletion.
+    pub is_expiry: bool,
+}
+
+struct Watch {
+    prefix: String,
+    sender: Sender<ChangeEvent>,
+}
+
+/// The watches on the keys of a catalog, shared by its successive Memtables, which notify them of
+/// each write once it is applied.
+///
+/// The events pile up in the channel of a watch until received, up to its capacity. The watch is
+/// dropped with the first write to its keys after its receiver is, or that finds its channel
+/// full, so that a receiver falling behind sees its channel disconnect once drained, and knows to
+/// watch again and catch up on the writes it has missed.
+pub(crate) struct Watchers {
+    watches: RwLock<Vec<Watch>>,
+
+    /// The number of events each watch buffers.
+    capacity: usize,
+
+    expiries: RwLock<Expiries>,
+}
+
+/// The expiring values written to the watched keys, whose expiry is still to be sent.
+#[derive(Default)]
+struct Expiries {
+    /// When each value expires, along with the sequence number of its write and its key.
+    queue: BinaryHeap<Reverse<(u64, u64, String)>>,
+
+    /// The sequence number of the last write to each key with an expiry in the queue, so that a
+    /// value overwritten is never sent as expired. A merge operand keeps the expiry of the value.
+    last_sequence_nos: HashMap<String, u64>,
+}
+
+impl Default for Watchers {
+    fn default() -> Self {
+        Self::new(DEFAULT_WATCH_CAPACITY)
+    }
+}
+
+impl std::fmt::Debug for Watchers {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let num_watches = self.watches.read().map_or(0, |watches| watches.len());
+        f.debug_struct("Watchers")
+            .field("num_watches", &num_watches)
+            .finish()
+    }
+}
+
+impl Watchers {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            watches: RwLock::default(),
+            capacity,
+            expiries: RwLock::default(),
+        }
+    }
+
+    /// Watch the keys starting with the prefix, from the next write on.
+    pub fn add(&self, prefix: &str) -> Result<Receiver<ChangeEvent>> {
+        let (sender, receiver) = bounded(self.capacity);
+        self.watches.write()?.push(Watch {
+            prefix: prefix.to_owned(),
+            sender,
+        });
+        Ok(receiver)
+    }
+
+    /// Whether any watch covers the key, or its expiry is still to be sent, so that the writes of
+    /// the others need not be copied.
+    pub fn is_watching(&self, key: &str) -> bool {
+        self.watches
+            .read()
+            .is_ok_and(|watches| watches.iter().any(|watch| key.starts_with(&watch.prefix)))
+            || self
+                .expiries
+                .read()
+                .is_ok_and(|expiries| expiries.last_sequence_nos.contains_key(key))
+    }
+
+    /// Send the event to the watches covering its key, dropping those whose receiver is gone or
+    /// whose channel is full, and keep an expiring value until its expiry is sent. The write is
+    /// applied by now, so a failure here never fails it.
+    pub fn notify(&self, event: &ChangeEvent) {
+        if let Ok(mut expiries) = self.expiries.write() {
+            match &event.record {
+                Record::ExpiringValue { expires_at_ms, .. } => {
+                    expiries.queue.push(Reverse((
+                        *expires_at_ms,
+                        event.sequence_no,
+                        event.key.clone(),
+                    )));
+                    expiries
+                        .last_sequence_nos
+                        .insert(event.key.clone(), event.sequence_no);
+                }
+                Record::Merge(_) => {}
+                Record::Value(_) | Record::Deleted => {
+                    if let Some(sequence_no) = expiries.last_sequence_nos.get_mut(&event.key) {
+                        *sequence_no = event.sequence_no;
+                    }
+                }
+            }
+        }
+        self.send(event);
+    }
+
+    /// Send the expiry of the values expired by the time, unless overwritten, and return the
+    /// number of events sent.
+    pub fn expire(&self, now_ms: u64) -> Result<usize> {
+        let mut events = Vec::new();
+        {
+            let mut expiries = self.expiries.write()?;
+            while expiries
+                .queue
+                .peek()
+                .is_some_and(|Reverse((expires_at_ms, _, _))| *expires_at_ms <= now_ms)
+            {
+                let Reverse((_, sequence_no, key)) = expiries.queue.pop().unwrap();
+                if expiries.last_sequence_nos.get(&key) == Some(&sequence_no) {
+                    expiries.last_sequence_nos.remove(&key);
+                    events.push(ChangeEvent {
+                        key,
+                        record: Record::Deleted,
+                        sequence_no,
+                        is_expiry: true,
+                    });
+                }
+            }
+        }
+        for event in events.iter() {
+            self.send(event);
+        }
+        Ok(events.len())
+    }
+
+    fn send(&self, event: &ChangeEvent) {
+        if let Ok(mut watches) = self.watches.write() {
+            watches.retain(|watch| {
+                if !event.key.starts_with(&watch.prefix) {
+                    return true;
+                }
+                match watch.sender.try_send(event.clone()) {
+                    Ok(()) => true,
+                    Err(TrySendError::Full(_)) => {
+                        log::warn!(
+                            "Disconnected the watch on prefix {:?} for falling behind.",
+                            watch.prefix
+                        );
+                        false
+                    }
+                    Err(TrySendError::Disconnected(_)) => false,
+                }
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_watchers() {
+        let watchers = Watchers::default();
+        let user_receiver = watchers.add("user").unwrap();
+        let all_receiver = watchers.add("").unwrap();
+        assert!(watchers.is_watching("user1"));
+        assert!(watchers.is_watching("order1"));
+
+        let event = |key: &str, record, sequence_no| ChangeEvent {
+            key: key.to_owned(),
+            record,
+            sequence_no,
+            is_expiry: false,
+        };
+        watchers.notify(&event("user1", Record::Value("alice".to_owned()), 1));
+        watchers.notify(&event("order1", Record::Deleted, 2));
+        assert_eq!(
+            user_receiver.try_iter().collect::<Vec<_>>(),
+            vec![event("user1", Record::Value("alice".to_owned()), 1)]
+        );
+        assert_eq!(all_receiver.try_iter().count(), 2);
+
+        // A watch is dropped along with its receiver.
+        drop(all_receiver);
+        watchers.notify(&event("order2", Record::Deleted, 3));
+        assert!(!watchers.is_watching("order2"));
+        assert!(watchers.is_watching("user2"));
+
+        // A watch falling behind is disconnected once its buffered events are received.
+        let watchers = Watchers::new(2);
+        let receiver = watchers.add("").unwrap();
+        for sequence_no in 1..=3 {
+            watchers.notify(&event("key", Record::Deleted, sequence_no));
+        }
+        assert!(!watchers.is_watching("key"));
+        assert_eq!(
+            receiver
+                .iter()
+                .map(|event| event.sequence_no)
+                .collect::<Vec<_>>(),
+            vec![1, 2]
+        );
+
+        // The expiry of a value is sent once due, unless the value has been overwritten.
+        let watchers = Watchers::default();
+        let receiver = watchers.add("").unwrap();
+        let expiring = |expires_at_ms| Record::ExpiringValue {
+            value: "value".to_owned(),
+            expires_at_ms,
+        };
+        watchers.notify(&event("a", expiring(100), 1));
+        watchers.notify(&event("b", expiring(100), 2));
+        watchers.notify(&event("b", Record::Value("value".to_owned()), 3));
+        watchers.notify(&event("c", expiring(200), 4));
+        watchers.notify(&event("c", Record::Merge(vec!["operand".to_owned()]), 5));
+        assert_eq!(receiver.try_iter().count(), 5);
+        assert_eq!(watchers.expire(99).unwrap(), 0);
+        assert_eq!(watchers.expire(150).unwrap(), 1);
+        assert_eq!(
+            receiver.try_iter().collect::<Vec<_>>(),
+            vec![ChangeEvent {
+                key: "a".to_owned(),
+                record: Record::Deleted,
+                sequence_no: 1,
+                is_expiry: true,
+            }]
+        );
+
+        // The writes to a key are seen until its expiry is sent, even once no watch covers it.
+        drop(receiver);
+        watchers.notify(&event("d", Record::Deleted, 6));
+        assert!(watchers.is_watching("c"));
+        assert!(!watchers.is_watching("d"));
+        assert_eq!(watchers.expire(200).unwrap(), 1);
+        assert!(!watchers.is_watching("c"));
+    }
+}